@@ -9,6 +9,20 @@ pub(crate) async fn send_request<T: OutgoingRequest>(
     registration: serde_yaml::Value,
     request: T,
 ) -> Result<T::IncomingResponse>
+where
+    T: Debug,
+{
+    send_request_with_fields(registration, request, serde_json::Map::new()).await
+}
+
+/// Like `send_request`, but adds the fields to the JSON body of the request. Used for unstable
+/// fields Ruma doesn't know about.
+#[tracing::instrument(skip(request, fields))]
+pub(crate) async fn send_request_with_fields<T: OutgoingRequest>(
+    registration: serde_yaml::Value,
+    request: T,
+    fields: serde_json::Map<String, serde_json::Value>,
+) -> Result<T::IncomingResponse>
 where
     T: Debug,
 {
@@ -24,6 +38,15 @@ where
         .unwrap()
         .map(|body| body.freeze());
 
+    if !fields.is_empty() {
+        let mut body = serde_json::from_slice::<serde_json::Map<_, _>>(http_request.body())
+            .unwrap_or_default();
+        body.extend(fields);
+        *http_request.body_mut() = serde_json::to_vec(&body)
+            .expect("JSON object can be serialized")
+            .into();
+    }
+
     let mut parts = http_request.uri().clone().into_parts();
     let old_path_and_query = parts.path_and_query.unwrap().as_str().to_owned();
     let symbol = if old_path_and_query.contains('?') {
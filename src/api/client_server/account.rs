@@ -1,18 +1,22 @@
-use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
-use crate::{api::client_server, services, utils, Error, Result, Ruma};
+use super::{identity_server_request, DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
+use crate::{service::users::ThreepidSession, services, utils, Error, Result, Ruma};
 use ruma::{
     api::client::{
         account::{
-            change_password, deactivate, get_3pids, get_username_availability, register,
+            add_3pid, bind_3pid, change_password, check_registration_token_validity, deactivate,
+            delete_3pid, get_3pids, get_username_availability, register,
             request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn,
-            whoami, ThirdPartyIdRemovalStatus,
+            whoami, IdentityServerInfo, ThirdPartyIdRemovalStatus,
         },
         error::ErrorKind,
         uiaa::{AuthFlow, AuthType, UiaaInfo},
     },
     events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
-    push, UserId,
+    push,
+    thirdparty::Medium,
+    ClientSecret, OwnedSessionId, SessionId, UserId,
 };
+use std::time::Instant;
 use tracing::{info, warn};
 
 use register::RegistrationKind;
@@ -54,12 +58,30 @@ pub async fn get_register_available_route(
         ));
     }
 
-    // TODO add check for appservice namespaces
+    if services().appservice.is_exclusive_user_id(&user_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "Username is reserved by an appservice.",
+        ));
+    }
 
     // If no if check is true we have an username that's available to be used.
     Ok(get_username_availability::v3::Response { available: true })
 }
 
+/// # `GET /_matrix/client/v1/register/m.login.registration_token/validity`
+///
+/// Checks if the registration token can be used, without using it.
+pub async fn check_registration_token_validity_route(
+    body: Ruma<check_registration_token_validity::v1::Request>,
+) -> Result<check_registration_token_validity::v1::Response> {
+    Ok(check_registration_token_validity::v1::Response {
+        valid: services()
+            .uiaa
+            .is_valid_registration_token(body.token.trim())?,
+    })
+}
+
 /// # `POST /_matrix/client/r0/register`
 ///
 /// Register an account on this homeserver.
@@ -67,16 +89,20 @@ pub async fn get_register_available_route(
 /// You can use [`GET /_matrix/client/r0/register/available`](fn.get_register_available_route.html)
 /// to check if the user id is valid and available.
 ///
-/// - Only works if registration is enabled
-/// - If type is guest: ignores all parameters except initial_device_display_name
-/// - If sender is not appservice: Requires UIAA (but we only use a dummy stage)
+/// - Only works if registration is enabled, or with a registration token
+/// - If type is guest: Only works if guests are allowed, ignores all parameters except
+///   initial_device_display_name
+/// - If sender is not appservice: Requires UIAA with the configured stages, or a dummy stage
 /// - If type is not guest and no username is given: Always fails after UIAA check
+/// - Fails if the user id is in the exclusive namespace of an appservice other than the sender
 /// - Creates a new account and populates it with default account data
 /// - If `inhibit_login` is false: Creates a device and returns device id and access_token
 pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<register::v3::Response> {
+    let registration_token_required = services().uiaa.registration_token_required()?;
+
     if !services().globals.allow_registration()
         && !body.from_appservice
-        && services().globals.config.registration_token.is_none()
+        && !registration_token_required
     {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
@@ -86,6 +112,17 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
 
     let is_guest = body.kind == RegistrationKind::Guest;
 
+    // Registration tokens don't open guest registration
+    if is_guest
+        && !(services().globals.allow_guests() && services().globals.allow_registration())
+        && !body.from_appservice
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guest registration is disabled.",
+        ));
+    }
+
     let user_id = match (&body.username, is_guest) {
         (Some(username), false) => {
             let proposed_user_id = UserId::parse_with_server_name(
@@ -115,40 +152,71 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
                 services().globals.server_name(),
             )
             .unwrap();
-            if !services().users.exists(&proposed_user_id)? {
+            if !services().users.exists(&proposed_user_id)?
+                && !services()
+                    .appservice
+                    .is_exclusive_user_id(&proposed_user_id)
+            {
                 break proposed_user_id;
             }
         },
     };
 
+    // Users in the namespace of an appservice are registered by it
+    if let Some(info) = &body.appservice_info {
+        if !info.is_user_match(&user_id) {
+            return Err(Error::BadRequest(
+                ErrorKind::Exclusive,
+                "User is not in namespace.",
+            ));
+        }
+    } else if services().appservice.is_exclusive_user_id(&user_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "Username is reserved by an appservice.",
+        ));
+    }
+
     // UIAA
+    let stages = services()
+        .uiaa
+        .registration_stages(registration_token_required);
+    let token_stage = stages.contains(&AuthType::RegistrationToken);
     let mut uiaainfo = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: if services().globals.config.registration_token.is_some() {
-                vec![AuthType::RegistrationToken]
-            } else {
-                vec![AuthType::Dummy]
-            },
-        }],
+        params: services().uiaa.registration_params(&stages),
+        flows: vec![AuthFlow { stages }],
         completed: Vec::new(),
-        params: Default::default(),
         session: None,
         auth_error: None,
     };
 
+    let mut registration_token = None;
     if !body.from_appservice && !is_guest {
         if let Some(auth) = &body.auth {
-            let (worked, uiaainfo) = services().uiaa.try_auth(
-                &UserId::parse_with_server_name("", services().globals.server_name())
-                    .expect("we know this is valid"),
-                "".into(),
-                auth,
-                &uiaainfo,
-            )?;
+            let (worked, uiaainfo) = services()
+                .uiaa
+                .try_auth(
+                    &UserId::parse_with_server_name("", services().globals.server_name())
+                        .expect("we know this is valid"),
+                    "".into(),
+                    auth,
+                    &uiaainfo,
+                )
+                .await?;
             if !worked {
                 return Err(Error::Uiaa(uiaainfo));
             }
-        // Success!
+            // Success!
+            registration_token = uiaainfo
+                .session
+                .as_deref()
+                .and_then(|session| services().uiaa.take_session_registration_token(session));
+            if token_stage && registration_token.is_none() {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "The registration token stage expired, please register again.",
+                ));
+            }
         } else if let Some(json) = body.json_body {
             uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
             services().uiaa.create(
@@ -164,14 +232,31 @@ pub async fn register_route(body: Ruma<register::v3::Request>) -> Result<registe
         }
     }
 
-    let password = if is_guest {
-        None
-    } else {
-        body.password.as_deref()
-    };
+    // Create user, the registration token is only spent once the account exists
+    {
+        let _guard = services().uiaa.registration_token_lock.lock().unwrap();
+
+        if let Some(token) = &registration_token {
+            if !services().uiaa.is_valid_registration_token(token)? {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Invalid registration token.",
+                ));
+            }
+        }
+
+        if is_guest {
+            services().users.create_guest(&user_id)?;
+        } else {
+            services()
+                .users
+                .create(&user_id, body.password.as_deref())?;
+        }
 
-    // Create user
-    services().users.create(&user_id, password)?;
+        if let Some(token) = &registration_token {
+            services().uiaa.spend_registration_token(token)?;
+        }
+    }
 
     // Default to pretty displayname
     let mut displayname = user_id.localpart().to_owned();
@@ -277,6 +362,13 @@ pub async fn change_password_route(
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let sender_device = body.sender_device.as_ref().expect("user is authenticated");
 
+    if !services().globals.allow_password_login() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Password login is disabled on this server.",
+        ));
+    }
+
     let mut uiaainfo = UiaaInfo {
         flows: vec![AuthFlow {
             stages: vec![AuthType::Password],
@@ -288,10 +380,10 @@ pub async fn change_password_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -311,15 +403,9 @@ pub async fn change_password_route(
         .set_password(sender_user, Some(&body.new_password))?;
 
     if body.logout_devices {
-        // Logout all devices except the current one
-        for id in services()
+        services()
             .users
-            .all_device_ids(sender_user)
-            .filter_map(|id| id.ok())
-            .filter(|id| id != sender_device)
-        {
-            services().users.remove_device(sender_user, &id)?;
-        }
+            .remove_other_devices(sender_user, sender_device)?;
     }
 
     info!("User {} changed their password.", sender_user);
@@ -344,7 +430,7 @@ pub async fn whoami_route(body: Ruma<whoami::v3::Request>) -> Result<whoami::v3:
     Ok(whoami::v3::Response {
         user_id: sender_user.clone(),
         device_id,
-        is_guest: services().users.is_deactivated(sender_user)? && !body.from_appservice,
+        is_guest: services().users.is_guest(sender_user)? && !body.from_appservice,
     })
 }
 
@@ -358,6 +444,8 @@ pub async fn whoami_route(body: Ruma<whoami::v3::Request>) -> Result<whoami::v3:
 /// - Forgets all to-device events
 /// - Triggers device list updates
 /// - Removes ability to log in again
+/// - Removes the user from the user directory
+/// - Redacts all messages and removes the profile if `erase` is set
 pub async fn deactivate_route(
     body: Ruma<deactivate::v3::Request>,
 ) -> Result<deactivate::v3::Response> {
@@ -375,10 +463,10 @@ pub async fn deactivate_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -393,18 +481,28 @@ pub async fn deactivate_route(
         return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
     }
 
-    // Make the user leave all rooms before deactivation
-    client_server::leave_all_rooms(sender_user).await?;
-
-    // Remove devices and mark account as deactivated
-    services().users.deactivate_account(sender_user)?;
+    // Removes devices, leaves all rooms and erases the messages if requested
+    let failed = services()
+        .users
+        .deactivate(sender_user, body.body.erase)
+        .await?;
 
     info!("User {} deactivated their account.", sender_user);
+    let mut notice = format!("User {sender_user} deactivated their account.");
+    if !failed.is_empty() {
+        warn!(
+            "Deactivating {} failed in {} rooms",
+            sender_user,
+            failed.len()
+        );
+        notice.push_str(" Leaving or erasing these rooms failed:");
+        for (room_id, e) in failed {
+            notice.push_str(&format!("\n{room_id}: {e}"));
+        }
+    }
     services()
         .admin
-        .send_message(RoomMessageEventContent::notice_plain(format!(
-            "User {sender_user} deactivated their account."
-        )));
+        .send_message(RoomMessageEventContent::notice_plain(notice));
 
     Ok(deactivate::v3::Response {
         id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
@@ -414,27 +512,56 @@ pub async fn deactivate_route(
 /// # `GET _matrix/client/v3/account/3pid`
 ///
 /// Get a list of third party identifiers associated with this account.
-///
-/// - Currently always returns empty list
 pub async fn third_party_route(
     body: Ruma<get_3pids::v3::Request>,
 ) -> Result<get_3pids::v3::Response> {
-    let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    Ok(get_3pids::v3::Response::new(Vec::new()))
+    Ok(get_3pids::v3::Response::new(
+        services()
+            .users
+            .threepids(sender_user)
+            .collect::<Result<_>>()?,
+    ))
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/email/requestToken`
 ///
 /// "This API should be used to request validation tokens when adding an email address to an account"
 ///
+/// - The configured identity server sends the token, other identity servers are refused
 /// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
 pub async fn request_3pid_management_token_via_email_route(
-    _body: Ruma<request_3pid_management_token_via_email::v3::Request>,
+    body: Ruma<request_3pid_management_token_via_email::v3::Request>,
 ) -> Result<request_3pid_management_token_via_email::v3::Response> {
-    Err(Error::BadRequest(
-        ErrorKind::ThreepidDenied,
-        "Third party identifier is not allowed",
+    check_3pid_changes_allowed()?;
+
+    if services()
+        .users
+        .find_from_threepid(&Medium::Email, &body.email)?
+        .is_some()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "Email address is already in use.",
+        ));
+    }
+
+    let sid = request_3pid_validation(
+        body.identity_server_info.as_ref(),
+        "email",
+        &body.client_secret,
+        serde_json::json!({
+            "client_secret": body.client_secret,
+            "email": body.email,
+            "send_attempt": body.send_attempt,
+            "next_link": body.next_link,
+        }),
+    )
+    .await?;
+
+    Ok(request_3pid_management_token_via_email::v3::Response::new(
+        sid,
     ))
 }
 
@@ -442,12 +569,270 @@ pub async fn request_3pid_management_token_via_email_route(
 ///
 /// "This API should be used to request validation tokens when adding an phone number to an account"
 ///
+/// - The configured identity server sends the token, other identity servers are refused
 /// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
+/// - Numbers in international format are checked for being in use right away, the others once
+///   the identity server returns them in that format
 pub async fn request_3pid_management_token_via_msisdn_route(
-    _body: Ruma<request_3pid_management_token_via_msisdn::v3::Request>,
+    body: Ruma<request_3pid_management_token_via_msisdn::v3::Request>,
 ) -> Result<request_3pid_management_token_via_msisdn::v3::Response> {
-    Err(Error::BadRequest(
+    check_3pid_changes_allowed()?;
+
+    let msisdn = body
+        .phone_number
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    if services()
+        .users
+        .find_from_threepid(&Medium::Msisdn, &msisdn)?
+        .is_some()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "Phone number is already in use.",
+        ));
+    }
+
+    let sid = request_3pid_validation(
+        body.identity_server_info.as_ref(),
+        "msisdn",
+        &body.client_secret,
+        serde_json::json!({
+            "client_secret": body.client_secret,
+            "country": body.country,
+            "phone_number": body.phone_number,
+            "send_attempt": body.send_attempt,
+            "next_link": body.next_link,
+        }),
+    )
+    .await?;
+
+    Ok(request_3pid_management_token_via_msisdn::v3::Response::new(
+        sid,
+    ))
+}
+
+/// Fails if the server doesn't let users add or remove email addresses and phone numbers.
+fn check_3pid_changes_allowed() -> Result<()> {
+    if services().globals.allow_3pid_changes() {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "Third party identifier changes are disabled on this server.",
+        ))
+    }
+}
+
+/// Starts a validation session at the configured identity server and remembers it until the
+/// address is added to an account.
+///
+/// Sessions at identity servers the client picks are refused: anyone can run one that claims
+/// every address is validated.
+async fn request_3pid_validation(
+    identity_server_info: Option<&IdentityServerInfo>,
+    medium: &str,
+    client_secret: &ClientSecret,
+    body: serde_json::Value,
+) -> Result<OwnedSessionId> {
+    let config = &services().globals.config;
+    let id_server = config.identity_server.clone().ok_or(Error::BadRequest(
         ErrorKind::ThreepidDenied,
         "Third party identifier is not allowed",
-    ))
+    ))?;
+
+    let id_access_token = match identity_server_info {
+        Some(info) if info.id_server != id_server => {
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidDenied,
+                "Only the identity server of this homeserver can validate third party identifiers.",
+            ));
+        }
+        Some(info) => config
+            .identity_server_access_token
+            .clone()
+            .or_else(|| Some(info.id_access_token.clone())),
+        None => config.identity_server_access_token.clone(),
+    };
+
+    let response = identity_server_request(
+        &id_server,
+        id_access_token.as_deref(),
+        reqwest::Method::POST,
+        &format!("/_matrix/identity/v2/validate/{medium}/requestToken"),
+        Some(body),
+    )
+    .await?;
+
+    let sid = response
+        .get("sid")
+        .and_then(|sid| sid.as_str())
+        .and_then(|sid| SessionId::parse(sid).ok())
+        .ok_or(Error::BadServerResponse(
+            "Identity server returned no valid session id.",
+        ))?;
+
+    services().users.add_threepid_session(
+        sid.clone(),
+        ThreepidSession {
+            client_secret: client_secret.to_owned(),
+            id_server,
+            id_access_token,
+            created: Instant::now(),
+        },
+    )?;
+
+    Ok(sid)
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/add`
+///
+/// Adds an email address or phone number, once its validation session at the identity server is
+/// completed.
+pub async fn add_3pid_route(body: Ruma<add_3pid::v3::Request>) -> Result<add_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+
+    check_3pid_changes_allowed()?;
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::Password],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+
+    if let Some(auth) = &body.auth {
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
+        if !worked {
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    // Success!
+    } else if let Some(json) = body.json_body {
+        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        services()
+            .uiaa
+            .create(sender_user, sender_device, &uiaainfo, &json)?;
+        return Err(Error::Uiaa(uiaainfo));
+    } else {
+        return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+    }
+
+    let (id_server, id_access_token) = services()
+        .users
+        .threepid_session(&body.sid, &body.client_secret)
+        .filter(|(id_server, _)| {
+            services().globals.config.identity_server.as_ref() == Some(id_server)
+        })
+        .ok_or(Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "Unknown validation session.",
+        ))?;
+
+    let validated = identity_server_request(
+        &id_server,
+        id_access_token.as_deref(),
+        reqwest::Method::GET,
+        &format!(
+            "/_matrix/identity/v2/3pid/getValidated3pid?sid={}&client_secret={}",
+            body.sid, body.client_secret
+        ),
+        None,
+    )
+    .await
+    .map_err(|_| {
+        Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "The third party identifier has not been validated.",
+        )
+    })?;
+
+    let medium = validated
+        .get("medium")
+        .and_then(|medium| medium.as_str())
+        .map(Medium::from);
+    let address = validated
+        .get("address")
+        .and_then(|address| address.as_str());
+    let validated_at = validated
+        .get("validated_at")
+        .and_then(|validated_at| validated_at.as_u64());
+    let (Some(medium), Some(address), Some(validated_at)) = (medium, address, validated_at) else {
+        return Err(Error::BadServerResponse(
+            "Identity server returned an invalid validated 3pid.",
+        ));
+    };
+
+    if !services()
+        .users
+        .add_threepid(sender_user, &medium, address, validated_at)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "Third party identifier is already in use.",
+        ));
+    }
+    services()
+        .users
+        .threepid_sessions
+        .lock()
+        .unwrap()
+        .remove(&body.sid);
+
+    info!("User {} added {} {}", sender_user, medium, address);
+
+    Ok(add_3pid::v3::Response {})
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/bind`
+///
+/// Publishes the validated address at the identity server, so other users can find the user by
+/// it.
+pub async fn bind_3pid_route(
+    body: Ruma<bind_3pid::v3::Request>,
+) -> Result<bind_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    identity_server_request(
+        &body.id_server,
+        Some(&body.id_access_token),
+        reqwest::Method::POST,
+        "/_matrix/identity/v2/3pid/bind",
+        Some(serde_json::json!({
+            "sid": body.sid,
+            "client_secret": body.client_secret,
+            "mxid": sender_user,
+        })),
+    )
+    .await?;
+
+    Ok(bind_3pid::v3::Response {})
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/delete`
+///
+/// Removes an email address or phone number from the account.
+///
+/// - Bindings at identity servers are not removed
+pub async fn delete_3pid_route(
+    body: Ruma<delete_3pid::v3::Request>,
+) -> Result<delete_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    check_3pid_changes_allowed()?;
+
+    services()
+        .users
+        .remove_threepid(sender_user, &body.medium, &body.address)?;
+
+    Ok(delete_3pid::v3::Response {
+        id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
+    })
 }
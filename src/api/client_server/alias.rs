@@ -1,24 +1,27 @@
 use crate::{services, Error, Result, Ruma};
 use rand::seq::SliceRandom;
-use regex::Regex;
 use ruma::{
     api::{
-        appservice,
         client::{
             alias::{create_alias, delete_alias, get_alias},
             error::ErrorKind,
         },
         federation,
     },
-    OwnedRoomAliasId,
+    events::StateEventType,
+    OwnedRoomAliasId, OwnedServerName, RoomId,
 };
 
 /// # `PUT /_matrix/client/r0/directory/room/{roomAlias}`
 ///
 /// Creates a new room alias on this server.
+///
+/// - The room has to be known and the user joined to it, appservices only need the namespace
 pub async fn create_alias_route(
     body: Ruma<create_alias::v3::Request>,
 ) -> Result<create_alias::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
     if body.room_alias.server_name() != services().globals.server_name() {
         return Err(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -35,10 +38,40 @@ pub async fn create_alias_route(
         return Err(Error::Conflict("Alias already exists."));
     }
 
+    if let Some(info) = &body.appservice_info {
+        if !info.is_alias_match(&body.room_alias) {
+            return Err(Error::BadRequest(
+                ErrorKind::Exclusive,
+                "Room alias is not in namespace.",
+            ));
+        }
+    } else if services().appservice.is_exclusive_alias(&body.room_alias) {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "Room alias reserved by appservice.",
+        ));
+    }
+
+    if !services().rooms.metadata.exists(&body.room_id)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found."));
+    }
+
+    if body.appservice_info.is_none()
+        && !services()
+            .rooms
+            .state_cache
+            .is_joined(sender_user, &body.room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are not joined to this room.",
+        ));
+    }
+
     services()
         .rooms
         .alias
-        .set_alias(&body.room_alias, &body.room_id)?;
+        .set_alias(&body.room_alias, &body.room_id, sender_user)?;
 
     Ok(create_alias::v3::Response::new())
 }
@@ -47,11 +80,14 @@ pub async fn create_alias_route(
 ///
 /// Deletes a room alias from this server.
 ///
-/// - TODO: additional access control checks
+/// - Allowed for the creator of the alias, server admins, appservices in whose namespace the alias
+/// is, and users who may change the canonical alias of the room
 /// - TODO: Update canonical alias event
 pub async fn delete_alias_route(
     body: Ruma<delete_alias::v3::Request>,
 ) -> Result<delete_alias::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
     if body.room_alias.server_name() != services().globals.server_name() {
         return Err(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -59,6 +95,44 @@ pub async fn delete_alias_route(
         ));
     }
 
+    let room_id = services()
+        .rooms
+        .alias
+        .resolve_local_alias(&body.room_alias)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Alias does not exist.",
+        ))?;
+
+    let allowed = if let Some(info) = &body.appservice_info {
+        info.is_alias_match(&body.room_alias)
+    } else {
+        let is_creator = services()
+            .rooms
+            .alias
+            .who_created_alias(&body.room_alias)?
+            .as_ref()
+            == Some(sender_user);
+        let may_change_aliases = services()
+            .rooms
+            .state_cache
+            .is_joined(sender_user, &room_id)?
+            && services().rooms.state_accessor.user_can_send_state(
+                sender_user,
+                &room_id,
+                StateEventType::RoomCanonicalAlias,
+            )?;
+
+        is_creator || may_change_aliases || services().users.is_admin(sender_user)?
+    };
+
+    if !allowed {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to delete this alias.",
+        ));
+    }
+
     services().rooms.alias.remove_alias(&body.room_alias)?;
 
     // TODO: update alt_aliases?
@@ -70,7 +144,8 @@ pub async fn delete_alias_route(
 ///
 /// Resolve an alias locally or over federation.
 ///
-/// - TODO: Suggest more servers to join via
+/// - Aliases of other servers are queried from them
+/// - Local aliases return the servers in the room, ours first
 pub async fn get_alias_route(
     body: Ruma<get_alias::v3::Request>,
 ) -> Result<get_alias::v3::Response> {
@@ -97,49 +172,9 @@ pub(crate) async fn get_alias_helper(
         return Ok(get_alias::v3::Response::new(response.room_id, servers));
     }
 
-    let mut room_id = None;
-    match services().rooms.alias.resolve_local_alias(&room_alias)? {
-        Some(r) => room_id = Some(r),
-        None => {
-            for (_id, registration) in services().appservice.all()? {
-                let aliases = registration
-                    .get("namespaces")
-                    .and_then(|ns| ns.get("aliases"))
-                    .and_then(|aliases| aliases.as_sequence())
-                    .map_or_else(Vec::new, |aliases| {
-                        aliases
-                            .iter()
-                            .filter_map(|aliases| Regex::new(aliases.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-
-                if aliases
-                    .iter()
-                    .any(|aliases| aliases.is_match(room_alias.as_str()))
-                    && services()
-                        .sending
-                        .send_appservice_request(
-                            registration,
-                            appservice::query::query_room_alias::v1::Request {
-                                room_alias: room_alias.clone(),
-                            },
-                        )
-                        .await
-                        .is_ok()
-                {
-                    room_id = Some(
-                        services()
-                            .rooms
-                            .alias
-                            .resolve_local_alias(&room_alias)?
-                            .ok_or_else(|| {
-                                Error::bad_config("Appservice lied to us. Room does not exist.")
-                            })?,
-                    );
-                    break;
-                }
-            }
-        }
+    let room_id = match services().rooms.alias.resolve_local_alias(&room_alias)? {
+        Some(room_id) => Some(room_id),
+        None => services().appservice.query_room_alias(&room_alias).await?,
     };
 
     let room_id = match room_id {
@@ -152,8 +187,22 @@ pub(crate) async fn get_alias_helper(
         }
     };
 
-    Ok(get_alias::v3::Response::new(
-        room_id,
-        vec![services().globals.server_name().to_owned()],
-    ))
+    let servers = resident_servers(&room_id);
+
+    Ok(get_alias::v3::Response::new(room_id, servers))
+}
+
+/// Returns the servers that can be used to join the room, our own first.
+pub(crate) fn resident_servers(room_id: &RoomId) -> Vec<OwnedServerName> {
+    let mut servers = vec![services().globals.server_name().to_owned()];
+    servers.extend(
+        services()
+            .rooms
+            .state_cache
+            .room_servers(room_id)
+            .filter_map(|r| r.ok())
+            .filter(|server| &**server != services().globals.server_name()),
+    );
+
+    servers
 }
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use crate::{services, Error, Result, Ruma};
 use ruma::api::client::{
     backup::{
@@ -5,7 +7,7 @@ use ruma::api::client::{
         create_backup_version, delete_backup_keys, delete_backup_keys_for_room,
         delete_backup_keys_for_session, delete_backup_version, get_backup_info, get_backup_keys,
         get_backup_keys_for_room, get_backup_keys_for_session, get_latest_backup_info,
-        update_backup_version,
+        update_backup_version, RoomKeyBackup,
     },
     error::ErrorKind,
 };
@@ -131,26 +133,13 @@ pub async fn add_backup_keys_route(
         ));
     }
 
-    for (room_id, room) in &body.rooms {
-        for (session_id, key_data) in &room.sessions {
-            services().key_backups.add_key(
-                sender_user,
-                &body.version,
-                room_id,
-                session_id,
-                key_data,
-            )?
-        }
-    }
+    let (count, etag) = services()
+        .key_backups
+        .add_keys(sender_user, &body.version, &body.rooms)?;
 
     Ok(add_backup_keys::v3::Response {
-        count: (services()
-            .key_backups
-            .count_keys(sender_user, &body.version)? as u32)
-            .into(),
-        etag: services()
-            .key_backups
-            .get_etag(sender_user, &body.version)?,
+        count: (count as u32).into(),
+        etag,
     })
 }
 
@@ -178,24 +167,20 @@ pub async fn add_backup_keys_for_room_route(
         ));
     }
 
-    for (session_id, key_data) in &body.sessions {
-        services().key_backups.add_key(
-            sender_user,
-            &body.version,
-            &body.room_id,
-            session_id,
-            key_data,
-        )?
-    }
+    let rooms = BTreeMap::from([(
+        body.room_id.clone(),
+        RoomKeyBackup {
+            sessions: body.sessions.clone(),
+        },
+    )]);
+
+    let (count, etag) = services()
+        .key_backups
+        .add_keys(sender_user, &body.version, &rooms)?;
 
     Ok(add_backup_keys_for_room::v3::Response {
-        count: (services()
-            .key_backups
-            .count_keys(sender_user, &body.version)? as u32)
-            .into(),
-        etag: services()
-            .key_backups
-            .get_etag(sender_user, &body.version)?,
+        count: (count as u32).into(),
+        etag,
     })
 }
 
@@ -223,7 +208,7 @@ pub async fn add_backup_keys_for_session_route(
         ));
     }
 
-    services().key_backups.add_key(
+    let etag = services().key_backups.add_key(
         sender_user,
         &body.version,
         &body.room_id,
@@ -236,9 +221,7 @@ pub async fn add_backup_keys_for_session_route(
             .key_backups
             .count_keys(sender_user, &body.version)? as u32)
             .into(),
-        etag: services()
-            .key_backups
-            .get_etag(sender_user, &body.version)?,
+        etag,
     })
 }
 
@@ -297,7 +280,7 @@ pub async fn delete_backup_keys_route(
 ) -> Result<delete_backup_keys::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    services()
+    let etag = services()
         .key_backups
         .delete_all_keys(sender_user, &body.version)?;
 
@@ -306,9 +289,7 @@ pub async fn delete_backup_keys_route(
             .key_backups
             .count_keys(sender_user, &body.version)? as u32)
             .into(),
-        etag: services()
-            .key_backups
-            .get_etag(sender_user, &body.version)?,
+        etag,
     })
 }
 
@@ -320,18 +301,17 @@ pub async fn delete_backup_keys_for_room_route(
 ) -> Result<delete_backup_keys_for_room::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    services()
-        .key_backups
-        .delete_room_keys(sender_user, &body.version, &body.room_id)?;
+    let etag =
+        services()
+            .key_backups
+            .delete_room_keys(sender_user, &body.version, &body.room_id)?;
 
     Ok(delete_backup_keys_for_room::v3::Response {
         count: (services()
             .key_backups
             .count_keys(sender_user, &body.version)? as u32)
             .into(),
-        etag: services()
-            .key_backups
-            .get_etag(sender_user, &body.version)?,
+        etag,
     })
 }
 
@@ -343,7 +323,7 @@ pub async fn delete_backup_keys_for_session_route(
 ) -> Result<delete_backup_keys_for_session::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    services().key_backups.delete_room_key(
+    let etag = services().key_backups.delete_room_key(
         sender_user,
         &body.version,
         &body.room_id,
@@ -355,8 +335,6 @@ pub async fn delete_backup_keys_for_session_route(
             .key_backups
             .count_keys(sender_user, &body.version)? as u32)
             .into(),
-        etag: services()
-            .key_backups
-            .get_etag(sender_user, &body.version)?,
+        etag,
     })
 }
@@ -1,18 +1,23 @@
 use crate::{services, Result, Ruma};
 use ruma::api::client::discovery::get_capabilities::{
-    self, Capabilities, RoomVersionStability, RoomVersionsCapability,
+    self, Capabilities, ChangePasswordCapability, RoomVersionStability, RoomVersionsCapability,
+    SetAvatarUrlCapability, SetDisplayNameCapability, ThirdPartyIdChangesCapability,
 };
 use std::collections::BTreeMap;
 
 /// # `GET /_matrix/client/r0/capabilities`
 ///
 /// Get information on the supported feature set and other relevent capabilities of this server.
+///
+/// - Unstable room versions are only listed if they are allowed in the config
 pub async fn get_capabilities_route(
     _body: Ruma<get_capabilities::v3::Request>,
 ) -> Result<get_capabilities::v3::Response> {
     let mut available = BTreeMap::new();
-    for room_version in &services().globals.unstable_room_versions {
-        available.insert(room_version.clone(), RoomVersionStability::Unstable);
+    if services().globals.allow_unstable_room_versions() {
+        for room_version in &services().globals.unstable_room_versions {
+            available.insert(room_version.clone(), RoomVersionStability::Unstable);
+        }
     }
     for room_version in &services().globals.stable_room_versions {
         available.insert(room_version.clone(), RoomVersionStability::Stable);
@@ -23,6 +28,14 @@ pub async fn get_capabilities_route(
         default: services().globals.default_room_version(),
         available,
     };
+    capabilities.change_password =
+        ChangePasswordCapability::new(services().globals.allow_password_login());
+    capabilities.set_displayname =
+        SetDisplayNameCapability::new(services().globals.allow_profile_changes());
+    capabilities.set_avatar_url =
+        SetAvatarUrlCapability::new(services().globals.allow_profile_changes());
+    capabilities.thirdparty_id_changes =
+        ThirdPartyIdChangesCapability::new(services().globals.allow_3pid_changes());
 
     Ok(get_capabilities::v3::Response { capabilities })
 }
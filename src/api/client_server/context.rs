@@ -1,3 +1,4 @@
+use super::filter::{event_allowed, sender_not_ignored};
 use crate::{services, Error, Result, Ruma};
 use ruma::{
     api::client::{context::get_context, error::ErrorKind, filter::LazyLoadOptions},
@@ -10,8 +11,10 @@ use tracing::error;
 ///
 /// Allows loading room history around an event.
 ///
-/// - Only works if the user is joined (TODO: always allow, but only show events if the user was
-/// joined, depending on history_visibility)
+/// - Returns `limit / 2` events before and after the event that the user is allowed to see
+/// - The state is the state after the last returned event, member events are lazy loaded if the
+/// filter asks for it
+/// - Unknown events and events the user can't see are both not found
 pub async fn get_context_route(
     body: Ruma<get_context::v3::Request>,
 ) -> Result<get_context::v3::Response> {
@@ -25,61 +28,45 @@ pub async fn get_context_route(
         _ => (false, false),
     };
 
-    let mut lazy_loaded = HashSet::new();
+    let not_found = || Error::BadRequest(ErrorKind::NotFound, "Event not found.");
 
     let base_token = services()
         .rooms
         .timeline
         .get_pdu_count(&body.event_id)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "Base event id not found.",
-        ))?;
+        .ok_or_else(not_found)?;
 
-    let base_event =
-        services()
-            .rooms
-            .timeline
-            .get_pdu(&body.event_id)?
-            .ok_or(Error::BadRequest(
-                ErrorKind::NotFound,
-                "Base event not found.",
-            ))?;
+    let base_event = services()
+        .rooms
+        .timeline
+        .get_pdu(&body.event_id)?
+        .filter(|pdu| pdu.room_id == body.room_id)
+        .ok_or_else(not_found)?;
 
     let room_id = base_event.room_id.clone();
 
+    // Don't reveal whether the event exists to users who can't see it
     if !services()
         .rooms
         .state_accessor
         .user_can_see_event(sender_user, &room_id, &body.event_id)?
     {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view this event.",
-        ));
-    }
-
-    if !services().rooms.lazy_loading.lazy_load_was_sent_before(
-        sender_user,
-        sender_device,
-        &room_id,
-        &base_event.sender,
-    )? || lazy_load_send_redundant
-    {
-        lazy_loaded.insert(base_event.sender.as_str().to_owned());
+        return Err(not_found());
     }
 
     // Use limit with maximum 100
     let limit = u64::from(body.limit).min(100) as usize;
 
-    let base_event = base_event.to_room_event();
+    let ignored_users = services().users.ignored_users(sender_user)?;
 
-    let events_before: Vec<_> = services()
+    let mut events_before: Vec<_> = services()
         .rooms
         .timeline
         .pdus_until(sender_user, &room_id, base_token)?
-        .take(limit / 2)
         .filter_map(|r| r.ok()) // Remove buggy events
+        .filter(|(_, pdu)| event_allowed(&body.filter, pdu))
+        .filter(|(_, pdu)| sender_not_ignored(&ignored_users, pdu))
+        .take(limit / 2)
         .filter(|(_, pdu)| {
             services()
                 .rooms
@@ -89,34 +76,14 @@ pub async fn get_context_route(
         })
         .collect();
 
-    for (_, event) in &events_before {
-        if !services().rooms.lazy_loading.lazy_load_was_sent_before(
-            sender_user,
-            sender_device,
-            &room_id,
-            &event.sender,
-        )? || lazy_load_send_redundant
-        {
-            lazy_loaded.insert(event.sender.as_str().to_owned());
-        }
-    }
-
-    let start_token = events_before
-        .last()
-        .map(|(count, _)| count.stringify())
-        .unwrap_or_else(|| base_token.stringify());
-
-    let events_before: Vec<_> = events_before
-        .into_iter()
-        .map(|(_, pdu)| pdu.to_room_event())
-        .collect();
-
-    let events_after: Vec<_> = services()
+    let mut events_after: Vec<_> = services()
         .rooms
         .timeline
         .pdus_after(sender_user, &room_id, base_token)?
-        .take(limit / 2)
         .filter_map(|r| r.ok()) // Remove buggy events
+        .filter(|(_, pdu)| event_allowed(&body.filter, pdu))
+        .filter(|(_, pdu)| sender_not_ignored(&ignored_users, pdu))
+        .take(limit / 2)
         .filter(|(_, pdu)| {
             services()
                 .rooms
@@ -126,18 +93,44 @@ pub async fn get_context_route(
         })
         .collect();
 
-    for (_, event) in &events_after {
-        if !services().rooms.lazy_loading.lazy_load_was_sent_before(
+    let lazy_loaded: HashSet<String> = services()
+        .rooms
+        .state_accessor
+        .lazy_loading_members(
             sender_user,
             sender_device,
             &room_id,
-            &event.sender,
-        )? || lazy_load_send_redundant
-        {
-            lazy_loaded.insert(event.sender.as_str().to_owned());
-        }
+            std::iter::once(&*base_event)
+                .chain(events_before.iter().map(|(_, pdu)| pdu))
+                .chain(events_after.iter().map(|(_, pdu)| pdu)),
+            &HashSet::new(),
+            lazy_load_send_redundant,
+        )?
+        .into_iter()
+        .map(|(user_id, _)| user_id.to_string())
+        .collect();
+
+    let mut base_event = (*base_event).clone();
+    services()
+        .rooms
+        .pdu_metadata
+        .add_bundled_aggregations(sender_user, &mut base_event)?;
+    for (_, pdu) in events_before.iter_mut().chain(events_after.iter_mut()) {
+        services()
+            .rooms
+            .pdu_metadata
+            .add_bundled_aggregations(sender_user, pdu)?;
     }
 
+    let start_token = events_before
+        .last()
+        .map_or(base_token, |(count, _)| *count)
+        .stringify();
+    let end_token = events_after
+        .last()
+        .map_or(base_token, |(count, _)| *count)
+        .stringify();
+
     let shortstatehash = match services().rooms.state_accessor.pdu_shortstatehash(
         events_after
             .last()
@@ -157,16 +150,6 @@ pub async fn get_context_route(
         .state_full_ids(shortstatehash)
         .await?;
 
-    let end_token = events_after
-        .last()
-        .map(|(count, _)| count.stringify())
-        .unwrap_or_else(|| base_token.stringify());
-
-    let events_after: Vec<_> = events_after
-        .into_iter()
-        .map(|(_, pdu)| pdu.to_room_event())
-        .collect();
-
     let mut state = Vec::new();
 
     for (shortstatekey, id) in state_ids {
@@ -175,33 +158,35 @@ pub async fn get_context_route(
             .short
             .get_statekey_from_short(shortstatekey)?;
 
-        if event_type != StateEventType::RoomMember {
-            let pdu = match services().rooms.timeline.get_pdu(&id)? {
-                Some(pdu) => pdu,
-                None => {
-                    error!("Pdu in state not found: {}", id);
-                    continue;
-                }
-            };
-            state.push(pdu.to_state_event());
-        } else if !lazy_load_enabled || lazy_loaded.contains(&state_key) {
-            let pdu = match services().rooms.timeline.get_pdu(&id)? {
-                Some(pdu) => pdu,
-                None => {
-                    error!("Pdu in state not found: {}", id);
-                    continue;
-                }
-            };
-            state.push(pdu.to_state_event());
+        if lazy_load_enabled
+            && event_type == StateEventType::RoomMember
+            && !lazy_loaded.contains(&state_key)
+        {
+            continue;
         }
+
+        let pdu = match services().rooms.timeline.get_pdu(&id)? {
+            Some(pdu) => pdu,
+            None => {
+                error!("Pdu in state not found: {}", id);
+                continue;
+            }
+        };
+        state.push(pdu.to_state_event());
     }
 
     let resp = get_context::v3::Response {
         start: Some(start_token),
         end: Some(end_token),
-        events_before,
-        event: Some(base_event),
-        events_after,
+        events_before: events_before
+            .into_iter()
+            .map(|(_, pdu)| pdu.to_room_event())
+            .collect(),
+        event: Some(base_event.to_room_event()),
+        events_after: events_after
+            .into_iter()
+            .map(|(_, pdu)| pdu.to_room_event())
+            .collect(),
         state,
     };
 
@@ -1,8 +1,11 @@
 use crate::{services, utils, Error, Result, Ruma};
-use ruma::api::client::{
-    device::{self, delete_device, delete_devices, get_device, get_devices, update_device},
-    error::ErrorKind,
-    uiaa::{AuthFlow, AuthType, UiaaInfo},
+use ruma::{
+    api::client::{
+        device::{self, delete_device, delete_devices, get_device, get_devices, update_device},
+        error::ErrorKind,
+        uiaa::{AuthFlow, AuthType, UiaaInfo},
+    },
+    UserId,
 };
 
 use super::SESSION_ID_LENGTH;
@@ -19,6 +22,7 @@ pub async fn get_devices_route(
         .users
         .all_devices_metadata(sender_user)
         .filter_map(|r| r.ok()) // Filter out buggy devices
+        .map(|device| with_last_seen(sender_user, device))
         .collect();
 
     Ok(get_devices::v3::Response { devices })
@@ -37,7 +41,22 @@ pub async fn get_device_route(
         .get_device_metadata(sender_user, &body.body.device_id)?
         .ok_or(Error::BadRequest(ErrorKind::NotFound, "Device not found."))?;
 
-    Ok(get_device::v3::Response { device })
+    Ok(get_device::v3::Response {
+        device: with_last_seen(sender_user, device),
+    })
+}
+
+/// Fills in where and when the device was last used.
+fn with_last_seen(user_id: &UserId, mut device: device::Device) -> device::Device {
+    if let Ok(Some(last_seen)) = services()
+        .users
+        .device_last_seen(user_id, &device.device_id)
+    {
+        device.last_seen_ts = Some(last_seen.ts);
+        device.last_seen_ip = last_seen.ip;
+    }
+
+    device
 }
 
 /// # `PUT /_matrix/client/r0/devices/{deviceId}`
@@ -89,10 +108,10 @@ pub async fn delete_device_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -143,10 +162,10 @@ pub async fn delete_devices_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -2,6 +2,7 @@ use crate::{services, Error, Result, Ruma};
 use ruma::{
     api::{
         client::{
+            appservice::set_room_visibility as set_appservice_room_visibility,
             directory::{
                 get_public_rooms, get_public_rooms_filtered, get_room_visibility,
                 set_room_visibility,
@@ -11,7 +12,7 @@ use ruma::{
         },
         federation,
     },
-    directory::{Filter, PublicRoomJoinRule, PublicRoomsChunk, RoomNetwork},
+    directory::{Filter, PublicRoomJoinRule, PublicRoomsChunk, RoomNetwork, RoomTypeFilter},
     events::{
         room::{
             avatar::RoomAvatarEventContent,
@@ -26,13 +27,15 @@ use ruma::{
     },
     ServerName, UInt,
 };
-use tracing::{error, info, warn};
+use tracing::{error, info};
 
 /// # `POST /_matrix/client/r0/publicRooms`
 ///
 /// Lists the public rooms on this server.
 ///
 /// - Rooms are ordered by the number of joined members
+/// - The search term matches the name, topic and aliases of rooms
+/// - Rooms of other servers are requested from that server
 pub async fn get_public_rooms_filtered_route(
     body: Ruma<get_public_rooms_filtered::v3::Request>,
 ) -> Result<get_public_rooms_filtered::v3::Response> {
@@ -75,7 +78,7 @@ pub async fn get_public_rooms_route(
 ///
 /// Sets the visibility of a given room in the room directory.
 ///
-/// - TODO: Access control checks
+/// - Only server admins and joined users who may change the canonical alias can do this
 pub async fn set_room_visibility_route(
     body: Ruma<set_room_visibility::v3::Request>,
 ) -> Result<set_room_visibility::v3::Response> {
@@ -86,6 +89,23 @@ pub async fn set_room_visibility_route(
         return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found"));
     }
 
+    if !services().users.is_admin(sender_user)?
+        && !(services()
+            .rooms
+            .state_cache
+            .is_joined(sender_user, &body.room_id)?
+            && services().rooms.state_accessor.user_can_send_state(
+                sender_user,
+                &body.room_id,
+                StateEventType::RoomCanonicalAlias,
+            )?)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to change the visibility of this room.",
+        ));
+    }
+
     match &body.visibility {
         room::Visibility::Public => {
             services().rooms.directory.set_public(&body.room_id)?;
@@ -103,6 +123,46 @@ pub async fn set_room_visibility_route(
     Ok(set_room_visibility::v3::Response {})
 }
 
+/// # `PUT /_matrix/client/r0/directory/list/appservice/{networkId}/{roomId}`
+///
+/// Sets the visibility of a room in the directory of a third party network, e.g. of a bridge.
+///
+/// - Only appservices can do this
+pub async fn set_appservice_room_visibility_route(
+    body: Ruma<set_appservice_room_visibility::v3::Request>,
+) -> Result<set_appservice_room_visibility::v3::Response> {
+    if !body.from_appservice {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Only appservices can change the directory of a network.",
+        ));
+    }
+
+    if !services().rooms.metadata.exists(&body.room_id)? {
+        // Return 404 if the room doesn't exist
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found"));
+    }
+
+    match &body.visibility {
+        room::Visibility::Public => services()
+            .rooms
+            .directory
+            .set_public_in_network(&body.network_id, &body.room_id)?,
+        room::Visibility::Private => services()
+            .rooms
+            .directory
+            .set_not_public_in_network(&body.network_id, &body.room_id)?,
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Room visibility type is not supported.",
+            ));
+        }
+    }
+
+    Ok(set_appservice_room_visibility::v3::Response {})
+}
+
 /// # `GET /_matrix/client/r0/directory/list/room/{roomId}`
 ///
 /// Gets the visibility of a given room in the room directory.
@@ -128,7 +188,7 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     limit: Option<UInt>,
     since: Option<&str>,
     filter: &Filter,
-    _network: &RoomNetwork,
+    network: &RoomNetwork,
 ) -> Result<get_public_rooms_filtered::v3::Response> {
     if let Some(other_server) =
         server.filter(|server| *server != services().globals.server_name().as_str())
@@ -144,7 +204,7 @@ pub(crate) async fn get_public_rooms_filtered_helper(
                         generic_search_term: filter.generic_search_term.clone(),
                         room_types: filter.room_types.clone(),
                     },
-                    room_network: RoomNetwork::Matrix,
+                    room_network: network.clone(),
                 },
             )
             .await?;
@@ -186,10 +246,9 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     let mut all_rooms: Vec<_> = services()
         .rooms
         .directory
-        .public_rooms()
+        .public_rooms_in(network)?
+        .into_iter()
         .map(|room_id| {
-            let room_id = room_id?;
-
             let chunk = PublicRoomsChunk {
                 canonical_alias: services()
                     .rooms
@@ -206,11 +265,8 @@ pub(crate) async fn get_public_rooms_filtered_helper(
                 num_joined_members: services()
                     .rooms
                     .state_cache
-                    .room_joined_count(&room_id)?
-                    .unwrap_or_else(|| {
-                        warn!("Room {} has no member count", room_id);
-                        0
-                    })
+                    .get_member_count(&room_id)?
+                    .0
                     .try_into()
                     .expect("user count should not be that big"),
                 topic: services()
@@ -305,6 +361,12 @@ pub(crate) async fn get_public_rooms_filtered_helper(
             Ok(chunk)
         })
         .filter_map(|r: Result<_>| r.ok()) // Filter out buggy rooms
+        .filter(|chunk| {
+            filter.room_types.is_empty()
+                || filter
+                    .room_types
+                    .contains(&RoomTypeFilter::from(chunk.room_type.clone()))
+        })
         .filter(|chunk| {
             if let Some(query) = filter
                 .generic_search_term
@@ -329,7 +391,12 @@ pub(crate) async fn get_public_rooms_filtered_helper(
                     }
                 }
 
-                false
+                services()
+                    .rooms
+                    .alias
+                    .local_aliases_for_room(&chunk.room_id)
+                    .filter_map(|r| r.ok())
+                    .any(|alias| alias.as_str().to_lowercase().contains(&query))
             } else {
                 // No search term
                 true
@@ -338,9 +405,15 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         // We need to collect all, so we can sort by member count
         .collect();
 
-    all_rooms.sort_by(|l, r| r.num_joined_members.cmp(&l.num_joined_members));
+    // Rooms with the same member count are ordered by id, so the pages don't change between
+    // requests
+    all_rooms.sort_by(|l, r| {
+        r.num_joined_members
+            .cmp(&l.num_joined_members)
+            .then_with(|| l.room_id.cmp(&r.room_id))
+    });
 
-    let total_room_count_estimate = (all_rooms.len() as u32).into();
+    let total_room_count_estimate = all_rooms.len() as u64;
 
     let chunk: Vec<_> = all_rooms
         .into_iter()
@@ -354,16 +427,21 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         Some(format!("p{num_since}"))
     };
 
-    let next_batch = if chunk.len() < limit as usize {
-        None
+    let next_since = num_since.saturating_add(limit);
+    let next_batch = if next_since < total_room_count_estimate {
+        Some(format!("n{next_since}"))
     } else {
-        Some(format!("n{}", num_since + limit))
+        None
     };
 
     Ok(get_public_rooms_filtered::v3::Response {
         chunk,
         prev_batch,
         next_batch,
-        total_room_count_estimate: Some(total_room_count_estimate),
+        total_room_count_estimate: Some(
+            total_room_count_estimate
+                .try_into()
+                .expect("room count should not be that big"),
+        ),
     })
 }
@@ -1,8 +1,13 @@
-use crate::{services, Error, Result, Ruma};
-use ruma::api::client::{
-    error::ErrorKind,
-    filter::{create_filter, get_filter},
+use crate::{services, Error, PduEvent, Result, Ruma};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        filter::{create_filter, get_filter, RoomEventFilter, UrlFilter},
+    },
+    OwnedRoomId, OwnedUserId, RoomId,
 };
+use serde::Deserialize;
+use std::collections::HashSet;
 
 /// # `GET /_matrix/client/r0/user/{userId}/filter/{filterId}`
 ///
@@ -21,7 +26,7 @@ pub async fn get_filter_route(
     Ok(get_filter::v3::Response::new(filter))
 }
 
-/// # `PUT /_matrix/client/r0/user/{userId}/filter`
+/// # `POST /_matrix/client/r0/user/{userId}/filter`
 ///
 /// Creates a new filter to be used by other endpoints.
 pub async fn create_filter_route(
@@ -32,3 +37,106 @@ pub async fn create_filter_route(
         services().users.create_filter(sender_user, &body.filter)?,
     ))
 }
+
+/// Whether the `rooms` and `not_rooms` fields of a filter include the room.
+pub(crate) fn room_allowed(
+    room_id: &RoomId,
+    rooms: Option<&[OwnedRoomId]>,
+    not_rooms: &[OwnedRoomId],
+) -> bool {
+    rooms.map_or(true, |rooms| rooms.iter().any(|r| r == room_id))
+        && !not_rooms.iter().any(|r| r == room_id)
+}
+
+/// Whether the `types` and `not_types` fields of a filter include the event type. A `*` in them
+/// matches any sequence of characters.
+pub(crate) fn type_allowed(filter: &RoomEventFilter, event_type: &str) -> bool {
+    filter.types.as_ref().map_or(true, |types| {
+        types
+            .iter()
+            .any(|pattern| type_matches(pattern, event_type))
+    }) && !filter
+        .not_types
+        .iter()
+        .any(|pattern| type_matches(pattern, event_type))
+}
+
+/// Whether the filter includes the event, the limit and lazy loading are up to the caller.
+pub(crate) fn event_allowed(filter: &RoomEventFilter, pdu: &PduEvent) -> bool {
+    if !room_allowed(&pdu.room_id, filter.rooms.as_deref(), &filter.not_rooms)
+        || !type_allowed(filter, &pdu.kind.to_string())
+    {
+        return false;
+    }
+
+    if filter
+        .senders
+        .as_ref()
+        .map_or(false, |senders| !senders.contains(&pdu.sender))
+        || filter.not_senders.contains(&pdu.sender)
+    {
+        return false;
+    }
+
+    match &filter.url_filter {
+        Some(url_filter) => {
+            #[derive(Deserialize)]
+            struct ExtractUrl {
+                url: Option<serde::de::IgnoredAny>,
+            }
+
+            let has_url = serde_json::from_str::<ExtractUrl>(pdu.content.get())
+                .map_or(false, |content| content.url.is_some());
+
+            has_url == matches!(url_filter, UrlFilter::EventsWithUrl)
+        }
+        None => true,
+    }
+}
+
+/// Whether the event may be shown to a user who ignores these users. State events of ignored
+/// users are still shown, they change the room.
+pub(crate) fn sender_not_ignored(ignored_users: &HashSet<OwnedUserId>, pdu: &PduEvent) -> bool {
+    pdu.state_key.is_some() || !ignored_users.contains(&pdu.sender)
+}
+
+fn type_matches(pattern: &str, event_type: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().expect("split always returns an element");
+
+    let Some(mut rest) = event_type.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        // No wildcard
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::type_matches;
+
+    #[test]
+    fn type_wildcards() {
+        assert!(type_matches("m.room.message", "m.room.message"));
+        assert!(!type_matches("m.room.message", "m.room.message.feedback"));
+        assert!(type_matches("m.room.*", "m.room.member"));
+        assert!(!type_matches("m.room.*", "m.reaction"));
+        assert!(type_matches("*", "org.example.custom"));
+        assert!(type_matches("m.*.member", "m.room.member"));
+        assert!(type_matches("m.room*", "m.room"));
+        assert!(!type_matches("m.*.m*r", "m.room.member.extra"));
+    }
+}
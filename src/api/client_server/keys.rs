@@ -28,6 +28,7 @@ use tracing::debug;
 /// Publish end-to-end encryption keys for the sender device.
 ///
 /// - Adds one time keys
+/// - Replaces the fallback keys, they are claimed when there are no one time keys left
 /// - If there are no device keys yet: Adds device keys (TODO: merge with existing keys?)
 pub async fn upload_keys_route(
     body: Ruma<upload_keys::v3::Request>,
@@ -41,6 +42,12 @@ pub async fn upload_keys_route(
             .add_one_time_key(sender_user, sender_device, key_key, key_value)?;
     }
 
+    for (key_key, key_value) in &body.fallback_keys {
+        services()
+            .users
+            .add_fallback_key(sender_user, sender_device, key_key, key_value)?;
+    }
+
     if let Some(device_keys) = &body.device_keys {
         // TODO: merge this and the existing event?
         // This check is needed to assure that signatures are kept
@@ -94,6 +101,8 @@ pub async fn claim_keys_route(
 /// Uploads end-to-end key information for the sender user.
 ///
 /// - Requires UIAA to verify password
+/// - The master key can be left out to replace the other keys
+/// - Sends the public keys to the servers that share a room with the user
 pub async fn upload_signing_keys_route(
     body: Ruma<upload_signing_keys::v3::Request>,
 ) -> Result<upload_signing_keys::v3::Response> {
@@ -112,10 +121,10 @@ pub async fn upload_signing_keys_route(
     };
 
     if let Some(auth) = &body.auth {
-        let (worked, uiaainfo) =
-            services()
-                .uiaa
-                .try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+        let (worked, uiaainfo) = services()
+            .uiaa
+            .try_auth(sender_user, sender_device, auth, &uiaainfo)
+            .await?;
         if !worked {
             return Err(Error::Uiaa(uiaainfo));
         }
@@ -130,14 +139,18 @@ pub async fn upload_signing_keys_route(
         return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
     }
 
-    if let Some(master_key) = &body.master_key {
+    if body.master_key.is_some()
+        || body.self_signing_key.is_some()
+        || body.user_signing_key.is_some()
+    {
         services().users.add_cross_signing_keys(
             sender_user,
-            master_key,
+            &body.master_key,
             &body.self_signing_key,
             &body.user_signing_key,
             true, // notify so that other users see the new keys
         )?;
+        services().users.federate_cross_signing_keys(sender_user)?;
     }
 
     Ok(upload_signing_keys::v3::Response {})
@@ -150,6 +163,7 @@ pub async fn upload_signatures_route(
     body: Ruma<upload_signatures::v3::Request>,
 ) -> Result<upload_signatures::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let mut own_keys_signed = false;
 
     for (user_id, keys) in &body.signed_keys {
         for (key_id, key) in keys {
@@ -190,10 +204,16 @@ pub async fn upload_signatures_route(
                 services()
                     .users
                     .sign_key(user_id, key_id, signature, sender_user)?;
+                own_keys_signed |= user_id == sender_user;
             }
         }
     }
 
+    // Other servers need the signatures of devices on the master key to trust it
+    if own_keys_signed {
+        services().users.federate_cross_signing_keys(sender_user)?;
+    }
+
     Ok(upload_signatures::v3::Response {
         failures: BTreeMap::new(), // TODO: integrate
     })
@@ -418,7 +438,10 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
                     let json = serde_json::to_value(master_key).expect("to_value always works");
                     let raw = serde_json::from_value(json).expect("Raw::from_value always works");
                     services().users.add_cross_signing_keys(
-                        &user, &raw, &None, &None,
+                        &user,
+                        &Some(raw.clone()),
+                        &None,
+                        &None,
                         false, // Dont notify. A notification would trigger another key request resulting in an endless loop
                     )?;
                     master_keys.insert(user, raw);
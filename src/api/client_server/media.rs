@@ -4,7 +4,8 @@ use crate::{service::media::FileMeta, services, utils, Error, Result, Ruma};
 use ruma::api::client::{
     error::ErrorKind,
     media::{
-        create_content, get_content, get_content_as_filename, get_content_thumbnail,
+        create_content, get_content, get_content_as_filename,
+        get_content_thumbnail::{self, v3::Method},
         get_media_config,
     },
 };
@@ -18,7 +19,7 @@ pub async fn get_media_config_route(
     _body: Ruma<get_media_config::v3::Request>,
 ) -> Result<get_media_config::v3::Response> {
     Ok(get_media_config::v3::Response {
-        upload_size: services().globals.max_request_size().into(),
+        upload_size: services().globals.config.body_limit.media_upload.into(),
     })
 }
 
@@ -31,6 +32,8 @@ pub async fn get_media_config_route(
 pub async fn create_content_route(
     body: Ruma<create_content::v3::Request>,
 ) -> Result<create_content::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
     let mxc = format!(
         "mxc://{}/{}",
         services().globals.server_name(),
@@ -40,6 +43,7 @@ pub async fn create_content_route(
     services()
         .media
         .create(
+            Some(sender_user),
             mxc.clone(),
             body.filename
                 .as_ref()
@@ -78,6 +82,7 @@ pub async fn get_remote_content(
     services()
         .media
         .create(
+            None,
             mxc.to_owned(),
             content_response.content_disposition.as_deref(),
             content_response.content_type.as_deref(),
@@ -166,19 +171,20 @@ pub async fn get_content_thumbnail_route(
 ) -> Result<get_content_thumbnail::v3::Response> {
     let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
 
+    let width = body
+        .width
+        .try_into()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?;
+    let height = body
+        .height
+        .try_into()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Height is invalid."))?;
+
     if let Some(FileMeta {
         content_type, file, ..
     }) = services()
         .media
-        .get_thumbnail(
-            mxc.clone(),
-            body.width
-                .try_into()
-                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?,
-            body.height
-                .try_into()
-                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?,
-        )
+        .get_thumbnail(mxc.clone(), width, height)
         .await?
     {
         Ok(get_content_thumbnail::v3::Response {
@@ -187,15 +193,21 @@ pub async fn get_content_thumbnail_route(
             cross_origin_resource_policy: Some("cross-origin".to_owned()),
         })
     } else if &*body.server_name != services().globals.server_name() && body.allow_remote {
+        // Ask for the same sizes we would generate ourselves, so the cached thumbnail is found
+        // by get_thumbnail next time and odd sizes don't pile up
+        let properties = services().media.thumbnail_properties(width, height);
+
         let get_thumbnail_response = services()
             .sending
             .send_federation_request(
                 &body.server_name,
                 get_content_thumbnail::v3::Request {
                     allow_remote: false,
-                    height: body.height,
-                    width: body.width,
-                    method: body.method.clone(),
+                    height: properties.map_or(body.height, |(_, height, _)| height.into()),
+                    width: properties.map_or(body.width, |(width, _, _)| width.into()),
+                    method: properties
+                        .map(|(_, _, crop)| if crop { Method::Crop } else { Method::Scale })
+                        .or_else(|| body.method.clone()),
                     server_name: body.server_name.clone(),
                     media_id: body.media_id.clone(),
                     timeout_ms: Duration::from_secs(20),
@@ -204,20 +216,176 @@ pub async fn get_content_thumbnail_route(
             )
             .await?;
 
-        services()
-            .media
-            .upload_thumbnail(
-                mxc,
-                None,
-                get_thumbnail_response.content_type.as_deref(),
-                body.width.try_into().expect("all UInts are valid u32s"),
-                body.height.try_into().expect("all UInts are valid u32s"),
-                &get_thumbnail_response.file,
-            )
-            .await?;
+        if let Some((width, height, _)) = properties {
+            services()
+                .media
+                .upload_thumbnail(
+                    mxc,
+                    None,
+                    get_thumbnail_response.content_type.as_deref(),
+                    width,
+                    height,
+                    &get_thumbnail_response.file,
+                )
+                .await?;
+        }
 
         Ok(get_thumbnail_response)
     } else {
         Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
     }
 }
+
+/// Byte ranges requested with a `Range` header, see RFC 7233.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRanges {
+    /// Inclusive first and last byte of each range, clamped to the file, sorted and with
+    /// overlapping or adjacent ranges merged
+    Satisfiable(Vec<(u64, u64)>),
+    /// None of the ranges overlap the file
+    Unsatisfiable,
+}
+
+/// Requests asking for more ranges than this are treated like invalid headers.
+const MAX_RANGES: usize = 16;
+
+/// Parses a `Range` header for a file of `len` bytes.
+///
+/// Returns None if the header is invalid or uses another unit than bytes, in which case it has
+/// to be ignored and the whole file sent. Ranges that together ask for more bytes than the file
+/// has are treated the same way, they can only be overlapping.
+pub fn parse_range_header(value: &str, len: u64) -> Option<ByteRanges> {
+    let specs = value.trim().strip_prefix("bytes=")?;
+
+    let mut ranges = Vec::new();
+    for (i, spec) in specs.split(',').enumerate() {
+        if i >= MAX_RANGES {
+            return None;
+        }
+
+        let (first, last) = spec.trim().split_once('-')?;
+        if first.is_empty() {
+            // The last n bytes of the file
+            let suffix = last.parse::<u64>().ok()?;
+            if suffix > 0 && len > 0 {
+                ranges.push((len.saturating_sub(suffix), len - 1));
+            }
+        } else {
+            let first = first.parse::<u64>().ok()?;
+            let last = if last.is_empty() {
+                u64::MAX
+            } else {
+                last.parse::<u64>().ok()?
+            };
+            if last < first {
+                return None;
+            }
+            if first < len {
+                ranges.push((first, last.min(len - 1)));
+            }
+        }
+    }
+
+    // Overlapping ranges would make us read the same bytes many times (RFC 7233, section 6.1)
+    let requested = ranges
+        .iter()
+        .map(|(first, last)| last - first + 1)
+        .fold(0_u64, u64::saturating_add);
+    if requested > len {
+        return None;
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some((_, previous_last)) if first <= previous_last.saturating_add(1) => {
+                *previous_last = (*previous_last).max(last);
+            }
+            _ => merged.push((first, last)),
+        }
+    }
+
+    Some(if merged.is_empty() {
+        ByteRanges::Unsatisfiable
+    } else {
+        ByteRanges::Satisfiable(merged)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_range_header, ByteRanges};
+
+    #[test]
+    fn ranges_are_clamped_to_the_file() {
+        assert_eq!(
+            parse_range_header("bytes=0-99", 1000),
+            Some(ByteRanges::Satisfiable(vec![(0, 99)]))
+        );
+        assert_eq!(
+            parse_range_header("bytes=900-", 1000),
+            Some(ByteRanges::Satisfiable(vec![(900, 999)]))
+        );
+        assert_eq!(
+            parse_range_header("bytes=900-2000", 1000),
+            Some(ByteRanges::Satisfiable(vec![(900, 999)]))
+        );
+        assert_eq!(
+            parse_range_header("bytes=-100", 1000),
+            Some(ByteRanges::Satisfiable(vec![(900, 999)]))
+        );
+        assert_eq!(
+            parse_range_header("bytes=-2000", 1000),
+            Some(ByteRanges::Satisfiable(vec![(0, 999)]))
+        );
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        assert_eq!(
+            parse_range_header("bytes=0-9, 20-29, 5000-", 1000),
+            Some(ByteRanges::Satisfiable(vec![(0, 9), (20, 29)]))
+        );
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        assert_eq!(
+            parse_range_header("bytes=20-29, 0-9, 5-14", 1000),
+            Some(ByteRanges::Satisfiable(vec![(0, 14), (20, 29)]))
+        );
+        // Adjacent ranges become one
+        assert_eq!(
+            parse_range_header("bytes=0-9, 10-19, -10", 1000),
+            Some(ByteRanges::Satisfiable(vec![(0, 19), (990, 999)]))
+        );
+        // Asking for more bytes than the file has can only be overlaps, the header is ignored
+        assert_eq!(parse_range_header("bytes=0-, 0-", 1000), None);
+        assert_eq!(parse_range_header("bytes=0-599, 400-999", 1000), None);
+    }
+
+    #[test]
+    fn ranges_outside_the_file_are_unsatisfiable() {
+        assert_eq!(
+            parse_range_header("bytes=1000-", 1000),
+            Some(ByteRanges::Unsatisfiable)
+        );
+        assert_eq!(
+            parse_range_header("bytes=-0", 1000),
+            Some(ByteRanges::Unsatisfiable)
+        );
+        assert_eq!(
+            parse_range_header("bytes=0-", 0),
+            Some(ByteRanges::Unsatisfiable)
+        );
+    }
+
+    #[test]
+    fn invalid_headers_are_ignored() {
+        assert_eq!(parse_range_header("items=0-9", 1000), None);
+        assert_eq!(parse_range_header("bytes=9-0", 1000), None);
+        assert_eq!(parse_range_header("bytes=a-b", 1000), None);
+        assert_eq!(parse_range_header("bytes=", 1000), None);
+    }
+}
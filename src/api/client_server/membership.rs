@@ -1,11 +1,15 @@
+use base64::{engine::general_purpose, Engine as _};
+use http::header::CONTENT_TYPE;
+use ring::digest;
 use ruma::{
     api::{
         client::{
             error::ErrorKind,
+            knock::knock_room,
             membership::{
                 ban_user, forget_room, get_member_events, invite_user, join_room_by_id,
                 join_room_by_id_or_alias, joined_members, joined_rooms, kick_user, leave_room,
-                unban_user, ThirdPartySigned,
+                unban_user, Invite3pid, ThirdPartySigned,
             },
         },
         federation::{self, membership::create_invite},
@@ -13,9 +17,9 @@ use ruma::{
     canonical_json::to_canonical_value,
     events::{
         room::{
-            join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
-            member::{MembershipState, RoomMemberEventContent},
-            power_levels::RoomPowerLevelsEventContent,
+            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            member::{MembershipState, RoomMemberEventContent, ThirdPartyInvite},
+            third_party_invite::RoomThirdPartyInviteEventContent,
         },
         StateEventType, TimelineEventType,
     },
@@ -140,6 +144,43 @@ pub async fn join_room_by_id_or_alias_route(
     })
 }
 
+/// # `POST /_matrix/client/v3/knock/{roomIdOrAlias}`
+///
+/// Tries to knock on a room, asking its members to let the sender user in.
+///
+/// - Only works if the join rule of the room is `knock` or `knock_restricted`
+/// - If the server knowns about this room: creates the knock event and does auth rules locally
+/// - If the server does not know about the room: asks other servers over federation
+pub async fn knock_room_route(
+    body: Ruma<knock_room::v3::Request>,
+) -> Result<knock_room::v3::Response> {
+    let sender_user = body.sender_user.as_deref().expect("user is authenticated");
+    let body = body.body;
+
+    let (servers, room_id) = match OwnedRoomId::try_from(body.room_id_or_alias) {
+        Ok(room_id) => {
+            let mut servers = body.server_name.clone();
+            servers.push(
+                room_id
+                    .server_name()
+                    .expect("Room IDs should always have a server name")
+                    .into(),
+            );
+
+            (servers, room_id)
+        }
+        Err(room_alias) => {
+            let response = get_alias_helper(room_alias).await?;
+
+            (response.servers, response.room_id)
+        }
+    };
+
+    knock_room_helper(sender_user, &room_id, body.reason.clone(), &servers).await?;
+
+    Ok(knock_room::v3::Response::new(room_id))
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/leave`
 ///
 /// Tries to leave the sender user from a room.
@@ -163,19 +204,24 @@ pub async fn invite_user_route(
 ) -> Result<invite_user::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    if let invite_user::v3::InvitationRecipient::UserId { user_id } = &body.recipient {
-        invite_helper(
-            sender_user,
-            user_id,
-            &body.room_id,
-            body.reason.clone(),
-            false,
-        )
-        .await?;
-        Ok(invite_user::v3::Response {})
-    } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "User not found."))
+    match &body.recipient {
+        invite_user::v3::InvitationRecipient::UserId { user_id } => {
+            invite_helper(
+                sender_user,
+                user_id,
+                &body.room_id,
+                body.reason.clone(),
+                false,
+                None,
+            )
+            .await?;
+        }
+        invite_user::v3::InvitationRecipient::ThirdPartyId(invite_3pid) => {
+            invite_3pid_helper(sender_user, &body.room_id, invite_3pid).await?;
+        }
     }
+
+    Ok(invite_user::v3::Response {})
 }
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/kick`
@@ -399,9 +445,12 @@ pub async fn joined_rooms_route(
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/members`
 ///
-/// Lists all joined users in a room (TODO: at a specific point in time, with a specific membership).
+/// Lists the member events of a room.
 ///
 /// - Only works if the user is currently joined
+/// - With `at`, the members at this event or sync token are returned instead of the current ones,
+///   if the user could see the room at that point
+/// - `membership` and `not_membership` filter by the membership of the events
 pub async fn get_member_events_route(
     body: Ruma<get_member_events::v3::Request>,
 ) -> Result<get_member_events::v3::Response> {
@@ -418,14 +467,48 @@ pub async fn get_member_events_route(
         ));
     }
 
+    let state = match &body.at {
+        Some(at) => {
+            let shortstatehash = services().rooms.state_accessor.shortstatehash_at_token(
+                sender_user,
+                &body.room_id,
+                at,
+            )?;
+
+            services()
+                .rooms
+                .state_accessor
+                .state_full(shortstatehash)
+                .await?
+        }
+        None => {
+            services()
+                .rooms
+                .state_accessor
+                .room_state_full(&body.room_id)
+                .await?
+        }
+    };
+
     Ok(get_member_events::v3::Response {
-        chunk: services()
-            .rooms
-            .state_accessor
-            .room_state_full(&body.room_id)
-            .await?
+        chunk: state
             .iter()
             .filter(|(key, _)| key.0 == StateEventType::RoomMember)
+            .filter(|(_, pdu)| {
+                let Ok(content) = serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+                else {
+                    return false;
+                };
+                let membership = content.membership.as_str();
+
+                body.membership
+                    .as_ref()
+                    .map_or(true, |filter| filter.as_str() == membership)
+                    && body
+                        .not_membership
+                        .as_ref()
+                        .map_or(true, |filter| filter.as_str() != membership)
+            })
             .map(|(_, pdu)| pdu.to_member_event())
             .collect(),
     })
@@ -436,6 +519,7 @@ pub async fn get_member_events_route(
 /// Lists all members of a room.
 ///
 /// - The sender user must be in the room
+/// - The display names and avatars are the ones of the member events, not the global profiles
 /// - TODO: An appservice just needs a puppet joined
 pub async fn joined_members_route(
     body: Ruma<joined_members::v3::Request>,
@@ -453,6 +537,12 @@ pub async fn joined_members_route(
         ));
     }
 
+    let state = services()
+        .rooms
+        .state_accessor
+        .room_state_full(&body.room_id)
+        .await?;
+
     let mut joined = BTreeMap::new();
     for user_id in services()
         .rooms
@@ -460,14 +550,15 @@ pub async fn joined_members_route(
         .room_members(&body.room_id)
         .filter_map(|r| r.ok())
     {
-        let display_name = services().users.displayname(&user_id)?;
-        let avatar_url = services().users.avatar_url(&user_id)?;
+        let content = state
+            .get(&(StateEventType::RoomMember, user_id.to_string()))
+            .and_then(|pdu| serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()).ok());
 
         joined.insert(
             user_id,
             joined_members::v3::RoomMember {
-                display_name,
-                avatar_url,
+                display_name: content.as_ref().and_then(|c| c.displayname.clone()),
+                avatar_url: content.and_then(|c| c.avatar_url),
             },
         );
     }
@@ -480,10 +571,61 @@ async fn join_room_by_id_helper(
     room_id: &RoomId,
     reason: Option<String>,
     servers: &[OwnedServerName],
-    _third_party_signed: Option<&ThirdPartySigned>,
+    third_party_signed: Option<&ThirdPartySigned>,
 ) -> Result<join_room_by_id::v3::Response> {
     let sender_user = sender_user.expect("user is authenticated");
 
+    if services().rooms.metadata.is_blocked(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is blocked on this server.",
+        ));
+    }
+
+    if services().users.is_blocked(sender_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are blocked on this server.",
+        ));
+    }
+
+    // A third party invite has to become an invite membership before we can join with it
+    if let Some(third_party_signed) = third_party_signed {
+        if *third_party_signed.mxid != *sender_user {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "The third party invite is for another user.",
+            ));
+        }
+
+        if !services()
+            .rooms
+            .state_cache
+            .is_invited(sender_user, room_id)?
+        {
+            let signed = serde_json::from_value(serde_json::json!({
+                "mxid": third_party_signed.mxid,
+                "token": third_party_signed.token,
+                "signatures": third_party_signed.signatures,
+            }))
+            .map_err(|_| {
+                Error::BadRequest(ErrorKind::InvalidParam, "Invalid third_party_signed.")
+            })?;
+
+            exchange_third_party_invite_helper(
+                &third_party_signed.sender,
+                sender_user,
+                room_id,
+                ThirdPartyInvite {
+                    // The server of the inviter uses the display name of the invite
+                    display_name: sender_user.to_string(),
+                    signed,
+                },
+            )
+            .await?;
+        }
+    }
+
     let mutex_state = Arc::clone(
         services()
             .globals
@@ -801,95 +943,29 @@ async fn join_room_by_id_helper(
     } else {
         info!("We can join locally");
 
-        let join_rules_event = services().rooms.state_accessor.room_state_get(
-            room_id,
-            &StateEventType::RoomJoinRules,
-            "",
-        )?;
-        let power_levels_event = services().rooms.state_accessor.room_state_get(
-            room_id,
-            &StateEventType::RoomPowerLevels,
-            "",
-        )?;
+        let restriction_rooms = services()
+            .rooms
+            .state_accessor
+            .join_restriction_rooms(room_id)?
+            .unwrap_or_default();
 
-        let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-            .as_ref()
-            .map(|join_rules_event| {
-                serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                    warn!("Invalid join rules event: {}", e);
-                    Error::bad_database("Invalid join rules event in db.")
-                })
-            })
-            .transpose()?;
-        let power_levels_event_content: Option<RoomPowerLevelsEventContent> = power_levels_event
-            .as_ref()
-            .map(|power_levels_event| {
-                serde_json::from_str(power_levels_event.content.get()).map_err(|e| {
-                    warn!("Invalid power levels event: {}", e);
-                    Error::bad_database("Invalid power levels event in db.")
-                })
-            })
-            .transpose()?;
+        let in_restriction_room = restriction_rooms.iter().any(|restriction_room_id| {
+            services()
+                .rooms
+                .state_cache
+                .is_joined(sender_user, restriction_room_id)
+                .unwrap_or(false)
+        });
 
-        let restriction_rooms = match join_rules_event_content {
-            Some(RoomJoinRulesEventContent {
-                join_rule: JoinRule::Restricted(restricted),
-            })
-            | Some(RoomJoinRulesEventContent {
-                join_rule: JoinRule::KnockRestricted(restricted),
-            }) => restricted
-                .allow
-                .into_iter()
-                .filter_map(|a| match a {
-                    AllowRule::RoomMembership(r) => Some(r.room_id),
-                    _ => None,
-                })
-                .collect(),
-            _ => Vec::new(),
+        let authorized_user = if in_restriction_room {
+            services()
+                .rooms
+                .state_accessor
+                .restricted_join_authorizer(room_id)?
+        } else {
+            None
         };
 
-        let authorized_user = restriction_rooms
-            .iter()
-            .find_map(|restriction_room_id| {
-                if !services()
-                    .rooms
-                    .state_cache
-                    .is_joined(sender_user, restriction_room_id)
-                    .ok()?
-                {
-                    return None;
-                }
-                let authorized_user = power_levels_event_content
-                    .as_ref()
-                    .and_then(|c| {
-                        c.users
-                            .iter()
-                            .filter(|(uid, i)| {
-                                uid.server_name() == services().globals.server_name()
-                                    && **i > ruma::int!(0)
-                                    && services()
-                                        .rooms
-                                        .state_cache
-                                        .is_joined(uid, restriction_room_id)
-                                        .unwrap_or(false)
-                            })
-                            .max_by_key(|(_, i)| *i)
-                            .map(|(u, _)| u.to_owned())
-                    })
-                    .or_else(|| {
-                        // TODO: Check here if user is actually allowed to invite. Currently the auth
-                        // check will just fail in this case.
-                        services()
-                            .rooms
-                            .state_cache
-                            .room_members(restriction_room_id)
-                            .filter_map(|r| r.ok())
-                            .find(|uid| uid.server_name() == services().globals.server_name())
-                    });
-                Some(authorized_user)
-            })
-            .flatten();
-
         let event = RoomMemberEventContent {
             membership: MembershipState::Join,
             displayname: services().users.displayname(sender_user)?,
@@ -918,6 +994,26 @@ async fn join_room_by_id_helper(
             Err(e) => e,
         };
 
+        if !restriction_rooms.is_empty()
+            && !in_restriction_room
+            && !services()
+                .rooms
+                .state_cache
+                .is_invited(sender_user, room_id)?
+        {
+            return Err(Error::BadRequestString(
+                ErrorKind::Forbidden,
+                format!(
+                    "This room can only be joined by members of these rooms: {}",
+                    restriction_rooms
+                        .iter()
+                        .map(|room_id| room_id.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            ));
+        }
+
         if !restriction_rooms.is_empty()
             && servers
                 .iter()
@@ -1070,6 +1166,226 @@ async fn join_room_by_id_helper(
     Ok(join_room_by_id::v3::Response::new(room_id.to_owned()))
 }
 
+async fn knock_room_helper(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    reason: Option<String>,
+    servers: &[OwnedServerName],
+) -> Result<()> {
+    if services().rooms.metadata.is_blocked(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is blocked on this server.",
+        ));
+    }
+
+    if services().users.is_blocked(sender_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are blocked on this server.",
+        ));
+    }
+
+    if services()
+        .rooms
+        .state_cache
+        .is_joined(sender_user, room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are already joined to this room.",
+        ));
+    }
+
+    let mutex_state = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    let content = RoomMemberEventContent {
+        membership: MembershipState::Knock,
+        displayname: services().users.displayname(sender_user)?,
+        avatar_url: services().users.avatar_url(sender_user)?,
+        is_direct: None,
+        third_party_invite: None,
+        blurhash: services().users.blurhash(sender_user)?,
+        reason,
+        join_authorized_via_users_server: None,
+    };
+
+    if services()
+        .rooms
+        .state_cache
+        .server_in_room(services().globals.server_name(), room_id)?
+    {
+        info!("We can knock locally");
+
+        let join_rule = services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+            .map(|join_rules_event| {
+                serde_json::from_str(join_rules_event.content.get())
+                    .map(|c: RoomJoinRulesEventContent| c.join_rule)
+                    .map_err(|e| {
+                        warn!("Invalid join rules event: {}", e);
+                        Error::bad_database("Invalid join rules event in db.")
+                    })
+            })
+            .transpose()?
+            .unwrap_or(JoinRule::Invite);
+
+        if !matches!(join_rule, JoinRule::Knock | JoinRule::KnockRestricted(_)) {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "This room does not allow knocking.",
+            ));
+        }
+
+        services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content: to_raw_value(&content).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(sender_user.to_string()),
+                redacts: None,
+            },
+            sender_user,
+            room_id,
+            &state_lock,
+        )?;
+
+        return Ok(());
+    }
+
+    info!("Knocking on {room_id} over federation.");
+
+    let mut make_knock_response_and_server = Err(Error::BadServerResponse(
+        "No server available to assist in knocking.",
+    ));
+
+    for remote_server in servers {
+        if remote_server == services().globals.server_name() {
+            continue;
+        }
+        info!("Asking {remote_server} for make_knock");
+        let make_knock_response = services()
+            .sending
+            .send_federation_request(
+                remote_server,
+                federation::knock::create_knock_event_template::v1::Request {
+                    room_id: room_id.to_owned(),
+                    user_id: sender_user.to_owned(),
+                    ver: services().globals.supported_room_versions(),
+                },
+            )
+            .await;
+
+        make_knock_response_and_server = make_knock_response.map(|r| (r, remote_server.clone()));
+
+        if make_knock_response_and_server.is_ok() {
+            break;
+        }
+    }
+
+    let (make_knock_response, remote_server) = make_knock_response_and_server?;
+
+    let room_version_id = make_knock_response.room_version;
+    if !services()
+        .globals
+        .supported_room_versions()
+        .contains(&room_version_id)
+    {
+        return Err(Error::BadServerResponse("Room version is not supported"));
+    }
+
+    let mut knock_event_stub: CanonicalJsonObject =
+        serde_json::from_str(make_knock_response.event.get()).map_err(|_| {
+            Error::BadServerResponse("Invalid make_knock event json received from server.")
+        })?;
+
+    // TODO: Is origin needed?
+    knock_event_stub.insert(
+        "origin".to_owned(),
+        CanonicalJsonValue::String(services().globals.server_name().as_str().to_owned()),
+    );
+    knock_event_stub.insert(
+        "origin_server_ts".to_owned(),
+        CanonicalJsonValue::Integer(
+            utils::millis_since_unix_epoch()
+                .try_into()
+                .expect("Timestamp is valid js_int value"),
+        ),
+    );
+    knock_event_stub.insert(
+        "content".to_owned(),
+        to_canonical_value(content).expect("event is valid, we just created it"),
+    );
+
+    // We don't leave the event id in the pdu because that's only allowed in v1 or v2 rooms
+    knock_event_stub.remove("event_id");
+
+    // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
+    ruma::signatures::hash_and_sign_event(
+        services().globals.server_name().as_str(),
+        services().globals.keypair(),
+        &mut knock_event_stub,
+        &room_version_id,
+    )
+    .expect("event is valid, we just created it");
+
+    // Generate event id
+    let event_id = EventId::parse(format!(
+        "${}",
+        ruma::signatures::reference_hash(&knock_event_stub, &room_version_id)
+            .expect("ruma can calculate reference hashes")
+    ))
+    .expect("ruma's reference hashes are valid event ids");
+
+    // Add event_id back
+    knock_event_stub.insert(
+        "event_id".to_owned(),
+        CanonicalJsonValue::String(event_id.as_str().to_owned()),
+    );
+
+    // It has enough fields to be called a proper event now
+    let knock_event = knock_event_stub;
+
+    info!("Asking {remote_server} for send_knock");
+    let send_knock_response = services()
+        .sending
+        .send_federation_request(
+            &remote_server,
+            federation::knock::send_knock::v1::Request {
+                room_id: room_id.to_owned(),
+                event_id,
+                pdu: PduEvent::convert_to_outgoing_federation_event(knock_event),
+            },
+        )
+        .await?;
+
+    info!("send_knock finished");
+
+    // We are not in the room, so this is the only place that remembers the knock
+    services().rooms.state_cache.update_membership(
+        room_id,
+        sender_user,
+        MembershipState::Knock,
+        Some(send_knock_response.knock_room_state),
+        true,
+    )?;
+
+    drop(state_lock);
+
+    Ok(())
+}
+
 async fn make_join_request(
     sender_user: &UserId,
     room_id: &RoomId,
@@ -1183,7 +1499,22 @@ pub(crate) async fn invite_helper<'a>(
     room_id: &RoomId,
     reason: Option<String>,
     is_direct: bool,
+    third_party_invite: Option<ThirdPartyInvite>,
 ) -> Result<()> {
+    if services().rooms.metadata.is_blocked(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is blocked on this server.",
+        ));
+    }
+
+    if services().users.is_blocked(sender_user)? || services().users.is_blocked(user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The user is blocked on this server.",
+        ));
+    }
+
     if user_id.server_name() != services().globals.server_name() {
         let (pdu, pdu_json, invite_room_state) = {
             let mutex_state = Arc::clone(
@@ -1202,7 +1533,7 @@ pub(crate) async fn invite_helper<'a>(
                 displayname: None,
                 is_direct: Some(is_direct),
                 membership: MembershipState::Invite,
-                third_party_invite: None,
+                third_party_invite,
                 blurhash: None,
                 reason,
                 join_authorized_via_users_server: None,
@@ -1291,7 +1622,7 @@ pub(crate) async fn invite_helper<'a>(
             .filter_map(|r| r.ok())
             .filter(|server| &**server != services().globals.server_name());
 
-        services().sending.send_pdu(servers, &pdu_id)?;
+        services().sending.send_pdu(room_id, servers, &pdu_id)?;
 
         return Ok(());
     }
@@ -1307,6 +1638,10 @@ pub(crate) async fn invite_helper<'a>(
         ));
     }
 
+    if !services().appservice.user_exists(user_id).await? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "User not found."));
+    }
+
     let mutex_state = Arc::clone(
         services()
             .globals
@@ -1326,7 +1661,7 @@ pub(crate) async fn invite_helper<'a>(
                 displayname: services().users.displayname(user_id)?,
                 avatar_url: services().users.avatar_url(user_id)?,
                 is_direct: Some(is_direct),
-                third_party_invite: None,
+                third_party_invite,
                 blurhash: services().users.blurhash(user_id)?,
                 reason,
                 join_authorized_via_users_server: None,
@@ -1346,8 +1681,297 @@ pub(crate) async fn invite_helper<'a>(
     Ok(())
 }
 
-// Make a user leave all their joined rooms
-pub async fn leave_all_rooms(user_id: &UserId) -> Result<()> {
+/// Invites the owner of an email address or phone number.
+///
+/// - If the identity server knows their Matrix ID, they are invited directly
+/// - Otherwise the identity server stores the invite until they bind the address, and an
+///   `m.room.third_party_invite` event holds the keys their invite will be signed with
+async fn invite_3pid_helper(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    invite_3pid: &Invite3pid,
+) -> Result<()> {
+    if !services()
+        .rooms
+        .state_cache
+        .is_joined(sender_user, room_id)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this room.",
+        ));
+    }
+
+    if let Some(user_id) = lookup_3pid(invite_3pid).await? {
+        return invite_helper(sender_user, &user_id, room_id, None, false, None).await;
+    }
+
+    let stored_invite = identity_server_request(
+        &invite_3pid.id_server,
+        Some(&invite_3pid.id_access_token),
+        reqwest::Method::POST,
+        "/_matrix/identity/v2/store-invite",
+        Some(serde_json::json!({
+            "medium": invite_3pid.medium.as_str(),
+            "address": invite_3pid.address,
+            "room_id": room_id,
+            "sender": sender_user,
+            "sender_display_name": services().users.displayname(sender_user)?,
+            "room_name": services().rooms.state_accessor.get_name(room_id)?,
+        })),
+    )
+    .await?;
+
+    let token = stored_invite
+        .get("token")
+        .and_then(|token| token.as_str())
+        .ok_or(Error::BadServerResponse(
+            "Identity server returned no invite token.",
+        ))?;
+    let public_keys = stored_invite
+        .get("public_keys")
+        .and_then(|public_keys| public_keys.as_array())
+        .filter(|public_keys| !public_keys.is_empty())
+        .ok_or(Error::BadServerResponse(
+            "Identity server returned no public keys.",
+        ))?;
+
+    let content = to_raw_value(&serde_json::json!({
+        "display_name": stored_invite.get("display_name"),
+        "key_validity_url": public_keys[0].get("key_validity_url"),
+        "public_key": public_keys[0].get("public_key"),
+        "public_keys": public_keys,
+    }))
+    .expect("json is valid raw value");
+    serde_json::from_str::<RoomThirdPartyInviteEventContent>(content.get()).map_err(|_| {
+        Error::BadServerResponse("Identity server returned an invalid third party invite.")
+    })?;
+
+    let mutex_state = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    services().rooms.timeline.build_and_append_pdu(
+        PduBuilder {
+            event_type: TimelineEventType::RoomThirdPartyInvite,
+            content,
+            unsigned: None,
+            state_key: Some(token.to_owned()),
+            redacts: None,
+        },
+        sender_user,
+        room_id,
+        &state_lock,
+    )?;
+
+    drop(state_lock);
+
+    Ok(())
+}
+
+/// Asks the identity server which user bound the address, using the sha256 lookup.
+async fn lookup_3pid(invite_3pid: &Invite3pid) -> Result<Option<OwnedUserId>> {
+    let hash_details = identity_server_request(
+        &invite_3pid.id_server,
+        Some(&invite_3pid.id_access_token),
+        reqwest::Method::GET,
+        "/_matrix/identity/v2/hash_details",
+        None,
+    )
+    .await?;
+
+    let supports_sha256 = hash_details
+        .get("algorithms")
+        .and_then(|algorithms| algorithms.as_array())
+        .map_or(false, |algorithms| {
+            algorithms.iter().any(|algorithm| algorithm == "sha256")
+        });
+    let Some(pepper) = hash_details
+        .get("lookup_pepper")
+        .and_then(|pepper| pepper.as_str())
+        .filter(|_| supports_sha256)
+    else {
+        // We don't send plain addresses to identity servers, they will get the invite instead
+        return Ok(None);
+    };
+
+    let hash = general_purpose::URL_SAFE_NO_PAD.encode(digest::digest(
+        &digest::SHA256,
+        format!(
+            "{} {} {}",
+            invite_3pid.address,
+            invite_3pid.medium.as_str(),
+            pepper
+        )
+        .as_bytes(),
+    ));
+
+    let lookup = identity_server_request(
+        &invite_3pid.id_server,
+        Some(&invite_3pid.id_access_token),
+        reqwest::Method::POST,
+        "/_matrix/identity/v2/lookup",
+        Some(serde_json::json!({
+            "addresses": [hash],
+            "algorithm": "sha256",
+            "pepper": pepper,
+        })),
+    )
+    .await?;
+
+    Ok(lookup
+        .get("mappings")
+        .and_then(|mappings| mappings.get(&hash))
+        .and_then(|user_id| user_id.as_str())
+        .and_then(|user_id| UserId::parse(user_id).ok()))
+}
+
+/// Sends a request to the identity server and returns the JSON it responds with.
+pub(crate) async fn identity_server_request(
+    id_server: &str,
+    id_access_token: Option<&str>,
+    method: reqwest::Method,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> Result<serde_json::Value> {
+    let mut request = services()
+        .globals
+        .default_client()
+        .request(method, format!("https://{id_server}{path}"));
+
+    if let Some(id_access_token) = id_access_token {
+        request = request.bearer_auth(id_access_token);
+    }
+
+    if let Some(body) = body {
+        request = request
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&body).expect("json can be serialized"));
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        warn!(
+            "Identity server {} returned {} for {}",
+            id_server,
+            response.status(),
+            path
+        );
+        return Err(Error::BadServerResponse(
+            "Identity server rejected the request.",
+        ));
+    }
+
+    serde_json::from_slice(&response.bytes().await?)
+        .map_err(|_| Error::BadServerResponse("Identity server returned invalid JSON."))
+}
+
+/// Turns the signed third party invite into an invite membership event for the user who bound the
+/// address. The server of the user who sent the `m.room.third_party_invite` event does that, see
+/// `accept_third_party_invite`.
+pub(crate) async fn exchange_third_party_invite_helper(
+    sender: &UserId,
+    user_id: &UserId,
+    room_id: &RoomId,
+    third_party_invite: ThirdPartyInvite,
+) -> Result<()> {
+    if sender.server_name() == services().globals.server_name() {
+        return accept_third_party_invite(sender, user_id, room_id, third_party_invite).await;
+    }
+
+    services()
+        .sending
+        .send_federation_request(
+            sender.server_name(),
+            federation::thirdparty::exchange_invite::v1::Request {
+                room_id: room_id.to_owned(),
+                kind: StateEventType::RoomMember,
+                sender: sender.to_owned(),
+                state_key: user_id.to_owned(),
+                content: RoomMemberEventContent {
+                    third_party_invite: Some(third_party_invite),
+                    ..RoomMemberEventContent::new(MembershipState::Invite)
+                },
+            },
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Invites the user in the name of our user who sent the `m.room.third_party_invite` event with
+/// the token, if the identity server signed the invite.
+pub(crate) async fn accept_third_party_invite(
+    sender: &UserId,
+    user_id: &UserId,
+    room_id: &RoomId,
+    mut third_party_invite: ThirdPartyInvite,
+) -> Result<()> {
+    if *third_party_invite.signed.mxid != *user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The third party invite is for another user.",
+        ));
+    }
+
+    let invite_event = services()
+        .rooms
+        .state_accessor
+        .room_state_get(
+            room_id,
+            &StateEventType::RoomThirdPartyInvite,
+            &third_party_invite.signed.token,
+        )?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "No third party invite with this token in the room.",
+        ))?;
+
+    if *invite_event.sender != *sender {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The third party invite was sent by another user.",
+        ));
+    }
+
+    let member_content = to_raw_value(&RoomMemberEventContent {
+        third_party_invite: Some(third_party_invite.clone()),
+        ..RoomMemberEventContent::new(MembershipState::Invite)
+    })
+    .expect("member event is valid value");
+    if !invite_event.verifies_third_party_signed(&member_content) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The third party invite is not signed by the identity server.",
+        ));
+    }
+
+    third_party_invite.display_name =
+        serde_json::from_str::<RoomThirdPartyInviteEventContent>(invite_event.content.get())
+            .map_err(|_| Error::bad_database("Invalid third party invite event in database."))?
+            .display_name;
+
+    invite_helper(
+        sender,
+        user_id,
+        room_id,
+        None,
+        false,
+        Some(third_party_invite),
+    )
+    .await
+}
+
+/// Makes the user leave, reject or retract their knock in every room. Returns the rooms that
+/// failed.
+pub async fn leave_all_rooms(user_id: &UserId) -> Result<Vec<(OwnedRoomId, Error)>> {
     let all_rooms = services()
         .rooms
         .state_cache
@@ -1359,28 +1983,55 @@ pub async fn leave_all_rooms(user_id: &UserId) -> Result<()> {
                 .rooms_invited(user_id)
                 .map(|t| t.map(|(r, _)| r)),
         )
+        .chain(
+            services()
+                .rooms
+                .state_cache
+                .rooms_knocked(user_id)
+                .map(|t| t.map(|(r, _)| r)),
+        )
         .collect::<Vec<_>>();
 
+    let mut failed = Vec::new();
+
     for room_id in all_rooms {
         let room_id = match room_id {
             Ok(room_id) => room_id,
             Err(_) => continue,
         };
 
-        let _ = leave_room(user_id, &room_id, None).await;
+        match leave_room_reporting(user_id, &room_id, None).await {
+            Ok(None) => {}
+            Ok(Some(e)) | Err(e) => failed.push((room_id, e)),
+        }
     }
 
-    Ok(())
+    Ok(failed)
 }
 
 pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<String>) -> Result<()> {
+    // Don't tell the client about remote errors, the invite is dropped anyway
+    leave_room_reporting(user_id, room_id, reason)
+        .await
+        .map(|_| ())
+}
+
+/// Leaves the room like `leave_room`, but also returns the error of asking a remote server to
+/// leave a room we don't have.
+async fn leave_room_reporting(
+    user_id: &UserId,
+    room_id: &RoomId,
+    reason: Option<String>,
+) -> Result<Option<Error>> {
+    let mut remote_error = None;
+
     // Ask a remote server if we don't have this room
     if !services().rooms.metadata.exists(room_id)?
         && room_id.server_name() != Some(services().globals.server_name())
     {
         if let Err(e) = remote_leave_room(user_id, room_id).await {
             warn!("Failed to leave room {} remotely: {}", user_id, e);
-            // Don't tell the client about this error
+            remote_error = Some(e);
         }
 
         let last_state = services()
@@ -1397,7 +2048,6 @@ pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<Strin
             room_id,
             user_id,
             MembershipState::Leave,
-            user_id,
             last_state,
             true,
         )?;
@@ -1428,11 +2078,10 @@ pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<Strin
                     room_id,
                     user_id,
                     MembershipState::Leave,
-                    user_id,
                     None,
                     true,
                 )?;
-                return Ok(());
+                return Ok(remote_error);
             }
             Some(e) => e,
         };
@@ -1457,7 +2106,7 @@ pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<Strin
         )?;
     }
 
-    Ok(())
+    Ok(remote_error)
 }
 
 async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
@@ -1465,16 +2114,24 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
         "No server available to assist in leaving.",
     ));
 
-    let invite_state = services()
+    let invite_state = match services()
         .rooms
         .state_cache
         .invite_state(user_id, room_id)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::BadState,
-            "User is not invited.",
-        ))?;
+    {
+        Some(invite_state) => invite_state,
+        // Knocks can be withdrawn the same way
+        None => services()
+            .rooms
+            .state_cache
+            .knock_state(user_id, room_id)?
+            .ok_or(Error::BadRequest(
+                ErrorKind::BadState,
+                "User is not invited or knocking.",
+            ))?,
+    };
 
-    let servers: HashSet<_> = invite_state
+    let mut servers: HashSet<_> = invite_state
         .iter()
         .filter_map(|event| serde_json::from_str(event.json().get()).ok())
         .filter_map(|event: serde_json::Value| event.get("sender").cloned())
@@ -1482,6 +2139,8 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
         .filter_map(|sender| UserId::parse(sender).ok())
         .map(|user| user.server_name().to_owned())
         .collect();
+    servers.extend(room_id.server_name().map(ToOwned::to_owned));
+    servers.remove(services().globals.server_name());
 
     for remote_server in servers {
         let make_leave_response = services()
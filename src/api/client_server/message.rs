@@ -1,18 +1,21 @@
+use super::filter::{event_allowed, sender_not_ignored};
 use crate::{
     service::{pdu::PduBuilder, rooms::timeline::PduCount},
-    services, utils, Error, Result, Ruma,
+    services, Error, Result, Ruma,
 };
 use ruma::{
     api::client::{
         error::ErrorKind,
+        filter::LazyLoadOptions,
         message::{get_message_events, send_message_event},
     },
-    events::{StateEventType, TimelineEventType},
+    events::TimelineEventType,
 };
 use std::{
     collections::{BTreeMap, HashSet},
     sync::Arc,
 };
+use tracing::warn;
 
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/send/{eventType}/{txnId}`
 ///
@@ -49,24 +52,11 @@ pub async fn send_message_event_route(
     }
 
     // Check if this is a new transaction id
-    if let Some(response) =
+    if let Some(event_id) =
         services()
             .transaction_ids
-            .existing_txnid(sender_user, sender_device, &body.txn_id)?
+            .existing_event_id(sender_user, sender_device, &body.txn_id)?
     {
-        // The client might have sent a txnid of the /sendToDevice endpoint
-        // This txnid has no response associated with it
-        if response.is_empty() {
-            return Err(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Tried to use txn id already used for an incompatible endpoint.",
-            ));
-        }
-
-        let event_id = utils::string_from_bytes(&response)
-            .map_err(|_| Error::bad_database("Invalid txnid bytes in database."))?
-            .try_into()
-            .map_err(|_| Error::bad_database("Invalid event id in txnid data."))?;
         return Ok(send_message_event::v3::Response { event_id });
     }
 
@@ -107,6 +97,7 @@ pub async fn send_message_event_route(
 ///
 /// - Only works if the user is joined (TODO: always allow, but only show events where the user was
 /// joined, depending on history_visibility)
+/// - Only returns events the filter includes, the limit counts those
 pub async fn get_message_events_route(
     body: Ruma<get_message_events::v3::Request>,
 ) -> Result<get_message_events::v3::Response> {
@@ -133,22 +124,39 @@ pub async fn get_message_events_route(
         from,
     )?;
 
-    let limit = u64::from(body.limit).min(100) as usize;
+    let limit = body
+        .filter
+        .limit
+        .map_or(body.limit, |filter_limit| filter_limit.min(body.limit));
+    let limit = u64::from(limit).min(100) as usize;
 
     let next_token;
 
     let mut resp = get_message_events::v3::Response::new();
 
-    let mut lazy_loaded = HashSet::new();
+    // Without lazy loading the member events of all senders are sent
+    let (lazy_load_enabled, lazy_load_send_redundant) = match body.filter.lazy_load_options {
+        LazyLoadOptions::Enabled {
+            include_redundant_members,
+        } => (true, include_redundant_members),
+        _ => (false, false),
+    };
+
+    let lazy_loaded;
+
+    let ignored_users = services().users.ignored_users(sender_user)?;
 
     match body.dir {
         ruma::api::Direction::Forward => {
-            let events_after: Vec<_> = services()
+            let mut events_after: Vec<_> = services()
                 .rooms
                 .timeline
                 .pdus_after(sender_user, &body.room_id, from)?
-                .take(limit)
                 .filter_map(|r| r.ok()) // Filter out buggy events
+                .take_while(|&(k, _)| Some(k) != to) // Stop at `to`
+                .filter(|(_, pdu)| event_allowed(&body.filter, pdu))
+                .filter(|(_, pdu)| sender_not_ignored(&ignored_users, pdu))
+                .take(limit)
                 .filter(|(_, pdu)| {
                     services()
                         .rooms
@@ -156,27 +164,26 @@ pub async fn get_message_events_route(
                         .user_can_see_event(sender_user, &body.room_id, &pdu.event_id)
                         .unwrap_or(false)
                 })
-                .take_while(|&(k, _)| Some(k) != to) // Stop at `to`
                 .collect();
 
-            for (_, event) in &events_after {
-                /* TODO: Remove this when these are resolved:
-                 * https://github.com/vector-im/element-android/issues/3417
-                 * https://github.com/vector-im/element-web/issues/21034
-                if !services().rooms.lazy_loading.lazy_load_was_sent_before(
-                    sender_user,
-                    sender_device,
-                    &body.room_id,
-                    &event.sender,
-                )? {
-                    lazy_loaded.insert(event.sender.clone());
-                }
-                */
-                lazy_loaded.insert(event.sender.clone());
-            }
+            lazy_loaded = services().rooms.state_accessor.lazy_loading_members(
+                sender_user,
+                sender_device,
+                &body.room_id,
+                events_after.iter().map(|(_, pdu)| pdu),
+                &HashSet::new(),
+                !lazy_load_enabled || lazy_load_send_redundant,
+            )?;
 
             next_token = events_after.last().map(|(count, _)| count).copied();
 
+            for (_, pdu) in &mut events_after {
+                services()
+                    .rooms
+                    .pdu_metadata
+                    .add_bundled_aggregations(sender_user, pdu)?;
+            }
+
             let events_after: Vec<_> = events_after
                 .into_iter()
                 .map(|(_, pdu)| pdu.to_room_event())
@@ -187,17 +194,25 @@ pub async fn get_message_events_route(
             resp.chunk = events_after;
         }
         ruma::api::Direction::Backward => {
-            services()
+            // Errors are not fatal, we can still return the events we have
+            if let Err(e) = services()
                 .rooms
                 .timeline
-                .backfill_if_required(&body.room_id, from)
-                .await?;
-            let events_before: Vec<_> = services()
+                .backfill_if_required(&body.room_id, from, limit)
+                .await
+            {
+                warn!("Failed to backfill {}: {e}", body.room_id);
+            }
+
+            let mut events_before: Vec<_> = services()
                 .rooms
                 .timeline
                 .pdus_until(sender_user, &body.room_id, from)?
-                .take(limit)
                 .filter_map(|r| r.ok()) // Filter out buggy events
+                .take_while(|&(k, _)| Some(k) != to) // Stop at `to`
+                .filter(|(_, pdu)| event_allowed(&body.filter, pdu))
+                .filter(|(_, pdu)| sender_not_ignored(&ignored_users, pdu))
+                .take(limit)
                 .filter(|(_, pdu)| {
                     services()
                         .rooms
@@ -205,61 +220,58 @@ pub async fn get_message_events_route(
                         .user_can_see_event(sender_user, &body.room_id, &pdu.event_id)
                         .unwrap_or(false)
                 })
-                .take_while(|&(k, _)| Some(k) != to) // Stop at `to`
                 .collect();
 
-            for (_, event) in &events_before {
-                /* TODO: Remove this when these are resolved:
-                 * https://github.com/vector-im/element-android/issues/3417
-                 * https://github.com/vector-im/element-web/issues/21034
-                if !services().rooms.lazy_loading.lazy_load_was_sent_before(
-                    sender_user,
-                    sender_device,
-                    &body.room_id,
-                    &event.sender,
-                )? {
-                    lazy_loaded.insert(event.sender.clone());
-                }
-                */
-                lazy_loaded.insert(event.sender.clone());
-            }
+            lazy_loaded = services().rooms.state_accessor.lazy_loading_members(
+                sender_user,
+                sender_device,
+                &body.room_id,
+                events_before.iter().map(|(_, pdu)| pdu),
+                &HashSet::new(),
+                !lazy_load_enabled || lazy_load_send_redundant,
+            )?;
 
             next_token = events_before.last().map(|(count, _)| count).copied();
 
+            for (_, pdu) in &mut events_before {
+                services()
+                    .rooms
+                    .pdu_metadata
+                    .add_bundled_aggregations(sender_user, pdu)?;
+            }
+
             let events_before: Vec<_> = events_before
                 .into_iter()
                 .map(|(_, pdu)| pdu.to_room_event())
                 .collect();
 
+            // Without any events before `from`, even after backfilling, there is no end token and
+            // the client knows it reached the start of the room
             resp.start = from.stringify();
             resp.end = next_token.map(|count| count.stringify());
             resp.chunk = events_before;
         }
     }
 
-    resp.state = Vec::new();
-    for ll_id in &lazy_loaded {
-        if let Some(member_event) = services().rooms.state_accessor.room_state_get(
-            &body.room_id,
-            &StateEventType::RoomMember,
-            ll_id.as_str(),
-        )? {
-            resp.state.push(member_event.to_state_event());
+    if lazy_load_enabled {
+        if let Some(next_token) = next_token {
+            services().rooms.lazy_loading.lazy_load_mark_sent(
+                sender_user,
+                sender_device,
+                &body.room_id,
+                lazy_loaded
+                    .iter()
+                    .map(|(user_id, _)| user_id.clone())
+                    .collect(),
+                next_token,
+            );
         }
     }
 
-    // TODO: enable again when we are sure clients can handle it
-    /*
-    if let Some(next_token) = next_token {
-        services().rooms.lazy_loading.lazy_load_mark_sent(
-            sender_user,
-            sender_device,
-            &body.room_id,
-            lazy_loaded,
-            next_token,
-        );
-    }
-    */
+    resp.state = lazy_loaded
+        .into_iter()
+        .map(|(_, member_event)| member_event.to_state_event())
+        .collect();
 
     Ok(resp)
 }
@@ -0,0 +1,29 @@
+use crate::{service::users::OPENID_TOKEN_LIFETIME, services, Error, Result, Ruma};
+use ruma::{
+    api::client::{account::request_openid_token, error::ErrorKind},
+    authentication::TokenType,
+};
+
+/// # `POST /_matrix/client/v3/user/{userId}/openid/request_token`
+///
+/// Creates a token other services, like integration managers, can use to verify the identity of
+/// the user with `GET /_matrix/federation/v1/openid/userinfo`.
+pub async fn create_openid_token_route(
+    body: Ruma<request_openid_token::v3::Request>,
+) -> Result<request_openid_token::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    if *sender_user != body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Not allowed to request OpenID tokens for other users.",
+        ));
+    }
+
+    Ok(request_openid_token::v3::Response {
+        access_token: services().users.create_openid_token(sender_user)?,
+        token_type: TokenType::Bearer,
+        matrix_server_name: services().globals.server_name().to_owned(),
+        expires_in: OPENID_TOKEN_LIFETIME,
+    })
+}
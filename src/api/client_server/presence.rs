@@ -1,4 +1,4 @@
-use crate::{services, utils, Error, Result, Ruma};
+use crate::{services, Error, Result, Ruma};
 use ruma::api::client::{
     error::ErrorKind,
     presence::{get_presence, set_presence},
@@ -8,32 +8,25 @@ use std::time::Duration;
 /// # `PUT /_matrix/client/r0/presence/{userId}/status`
 ///
 /// Sets the presence state of the sender user.
+///
+/// - Does nothing if presence is disabled
 pub async fn set_presence_route(
     body: Ruma<set_presence::v3::Request>,
 ) -> Result<set_presence::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    for room_id in services().rooms.state_cache.rooms_joined(sender_user) {
-        let room_id = room_id?;
+    if sender_user != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can only set your own presence.",
+        ));
+    }
 
-        services().rooms.edus.presence.update_presence(
+    if services().globals.allow_presence() {
+        services().rooms.edus.presence.set_presence(
             sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: services().users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: services().users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: body.presence.clone(),
-                    status_msg: body.status_msg.clone(),
-                },
-                sender: sender_user.clone(),
-            },
+            body.presence.clone(),
+            body.status_msg.clone(),
         )?;
     }
 
@@ -50,25 +43,19 @@ pub async fn get_presence_route(
 ) -> Result<get_presence::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let mut presence_event = None;
-
-    for room_id in services()
-        .rooms
-        .user
-        .get_shared_rooms(vec![sender_user.clone(), body.user_id.clone()])?
-    {
-        let room_id = room_id?;
-
-        if let Some(presence) = services()
+    let shares_room = sender_user == &body.user_id
+        || services()
             .rooms
-            .edus
-            .presence
-            .get_last_presence_event(sender_user, &room_id)?
-        {
-            presence_event = Some(presence);
-            break;
-        }
-    }
+            .user
+            .get_shared_rooms(vec![sender_user.clone(), body.user_id.clone()])?
+            .next()
+            .is_some();
+
+    let presence_event = if services().globals.allow_presence() && shares_room {
+        services().rooms.edus.presence.get_presence(&body.user_id)?
+    } else {
+        None
+    };
 
     if let Some(presence) = presence_event {
         Ok(get_presence::v3::Response {
@@ -1,4 +1,4 @@
-use crate::{service::pdu::PduBuilder, services, utils, Error, Result, Ruma};
+use crate::{service::pdu::PduBuilder, services, Error, Result, Ruma};
 use ruma::{
     api::{
         client::{
@@ -24,6 +24,13 @@ pub async fn set_displayname_route(
 ) -> Result<set_display_name::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    if !services().globals.allow_profile_changes() && !body.from_appservice {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Profile changes are disabled on this server.",
+        ));
+    }
+
     services()
         .users
         .set_displayname(sender_user, body.displayname.clone())?;
@@ -89,29 +96,15 @@ pub async fn set_displayname_route(
             &room_id,
             &state_lock,
         );
-
-        // Presence update
-        services().rooms.edus.presence.update_presence(
-            sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: services().users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: services().users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_user.clone(),
-            },
-        )?;
     }
 
+    // The presence contains the profile
+    services()
+        .rooms
+        .edus
+        .presence
+        .resend_presence(sender_user)?;
+
     Ok(set_display_name::v3::Response {})
 }
 
@@ -155,6 +148,13 @@ pub async fn set_avatar_url_route(
 ) -> Result<set_avatar_url::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    if !services().globals.allow_profile_changes() && !body.from_appservice {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Profile changes are disabled on this server.",
+        ));
+    }
+
     services()
         .users
         .set_avatar_url(sender_user, body.avatar_url.clone())?;
@@ -224,29 +224,15 @@ pub async fn set_avatar_url_route(
             &room_id,
             &state_lock,
         );
-
-        // Presence update
-        services().rooms.edus.presence.update_presence(
-            sender_user,
-            &room_id,
-            ruma::events::presence::PresenceEvent {
-                content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: services().users.avatar_url(sender_user)?,
-                    currently_active: None,
-                    displayname: services().users.displayname(sender_user)?,
-                    last_active_ago: Some(
-                        utils::millis_since_unix_epoch()
-                            .try_into()
-                            .expect("time is valid"),
-                    ),
-                    presence: ruma::presence::PresenceState::Online,
-                    status_msg: None,
-                },
-                sender: sender_user.clone(),
-            },
-        )?;
     }
 
+    // The presence contains the profile
+    services()
+        .rooms
+        .edus
+        .presence
+        .resend_presence(sender_user)?;
+
     Ok(set_avatar_url::v3::Response {})
 }
 
@@ -287,6 +273,7 @@ pub async fn get_avatar_url_route(
 /// Returns the displayname, avatar_url and blurhash of the user.
 ///
 /// - If user is on another server: Fetches profile over federation
+/// - If the user is unknown: Asks the appservice that claimed it exclusively to create it
 pub async fn get_profile_route(
     body: Ruma<get_profile::v3::Request>,
 ) -> Result<get_profile::v3::Response> {
@@ -309,7 +296,7 @@ pub async fn get_profile_route(
         });
     }
 
-    if !services().users.exists(&body.user_id)? {
+    if !services().appservice.user_exists(&body.user_id).await? {
         // Return 404 if this user doesn't exist
         return Err(Error::BadRequest(
             ErrorKind::NotFound,
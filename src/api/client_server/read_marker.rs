@@ -2,94 +2,70 @@ use crate::{service::rooms::timeline::PduCount, services, Error, Result, Ruma};
 use ruma::{
     api::client::{error::ErrorKind, read_marker::set_read_marker, receipt::create_receipt},
     events::{
-        receipt::{ReceiptThread, ReceiptType},
+        fully_read::{FullyReadEvent, FullyReadEventContent},
+        receipt::{Receipt, ReceiptEvent, ReceiptEventContent, ReceiptThread, ReceiptType},
         RoomAccountDataEventType,
     },
-    MilliSecondsSinceUnixEpoch,
+    EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
 };
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::MutexGuard;
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/read_markers`
 ///
 /// Sets different types of read markers.
 ///
 /// - Updates fully-read account data event to `fully_read`
-/// - If `read_receipt` is set: Update private marker and public read receipt EDU
+/// - If `read_receipt` is set: Update public read receipt EDU
+/// - If `private_read_receipt` is set: Update private marker and private read receipt
+/// - Unread counts only include events after the furthest read receipt
 pub async fn set_read_marker_route(
     body: Ruma<set_read_marker::v3::Request>,
 ) -> Result<set_read_marker::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    if let Some(fully_read) = &body.fully_read {
-        let fully_read_event = ruma::events::fully_read::FullyReadEvent {
-            content: ruma::events::fully_read::FullyReadEventContent {
-                event_id: fully_read.clone(),
-            },
-        };
-        services().account_data.update(
-            Some(&body.room_id),
-            sender_user,
-            RoomAccountDataEventType::FullyRead,
-            &serde_json::to_value(fully_read_event).expect("to json value always works"),
-        )?;
+    // Check all events before changing anything
+    for event_id in [
+        &body.fully_read,
+        &body.read_receipt,
+        &body.private_read_receipt,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        check_event_in_room(&body.room_id, event_id)?;
+    }
+    if let Some(event) = &body.private_read_receipt {
+        check_not_backfilled(event)?;
     }
 
-    if body.private_read_receipt.is_some() || body.read_receipt.is_some() {
+    let mutex_state = Arc::clone(
         services()
-            .rooms
-            .user
-            .reset_notification_counts(sender_user, &body.room_id)?;
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(body.room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    if let Some(fully_read) = &body.fully_read {
+        set_fully_read(sender_user, &body.room_id, fully_read)?;
     }
 
     if let Some(event) = &body.private_read_receipt {
-        let count = services()
-            .rooms
-            .timeline
-            .get_pdu_count(event)?
-            .ok_or(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Event does not exist.",
-            ))?;
-        let count = match count {
-            PduCount::Backfilled(_) => {
-                return Err(Error::BadRequest(
-                    ErrorKind::InvalidParam,
-                    "Read receipt is in backfilled timeline",
-                ))
-            }
-            PduCount::Normal(c) => c,
-        };
-        services()
-            .rooms
-            .edus
-            .read_receipt
-            .private_read_set(&body.room_id, sender_user, count)?;
+        set_private_read(sender_user, &body.room_id, event)?;
+        update_notification_counts(sender_user, &body.room_id, event, &state_lock)?;
     }
 
     if let Some(event) = &body.read_receipt {
-        let mut user_receipts = BTreeMap::new();
-        user_receipts.insert(
-            sender_user.clone(),
-            ruma::events::receipt::Receipt {
-                ts: Some(MilliSecondsSinceUnixEpoch::now()),
-                thread: ReceiptThread::Unthreaded,
-            },
-        );
-
-        let mut receipts = BTreeMap::new();
-        receipts.insert(ReceiptType::Read, user_receipts);
-
-        let mut receipt_content = BTreeMap::new();
-        receipt_content.insert(event.to_owned(), receipts);
-
         services().rooms.edus.read_receipt.readreceipt_update(
             sender_user,
             &body.room_id,
-            ruma::events::receipt::ReceiptEvent {
-                content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-                room_id: body.room_id.clone(),
-            },
+            receipt_event(sender_user, &body.room_id, event, ReceiptType::Read),
         )?;
+        update_notification_counts(sender_user, &body.room_id, event, &state_lock)?;
     }
 
     Ok(set_read_marker::v3::Response {})
@@ -98,85 +74,163 @@ pub async fn set_read_marker_route(
 /// # `POST /_matrix/client/r0/rooms/{roomId}/receipt/{receiptType}/{eventId}`
 ///
 /// Sets private read marker and public read receipt EDU.
+///
+/// - Public receipts are sent to the other servers in the room
+/// - Private receipts are only visible to the sender user
 pub async fn create_receipt_route(
     body: Ruma<create_receipt::v3::Request>,
 ) -> Result<create_receipt::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    if matches!(
-        &body.receipt_type,
-        create_receipt::v3::ReceiptType::Read | create_receipt::v3::ReceiptType::ReadPrivate
-    ) {
+    check_event_in_room(&body.room_id, &body.event_id)?;
+
+    let mutex_state = Arc::clone(
         services()
-            .rooms
-            .user
-            .reset_notification_counts(sender_user, &body.room_id)?;
-    }
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(body.room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
 
     match body.receipt_type {
         create_receipt::v3::ReceiptType::FullyRead => {
-            let fully_read_event = ruma::events::fully_read::FullyReadEvent {
-                content: ruma::events::fully_read::FullyReadEventContent {
-                    event_id: body.event_id.clone(),
-                },
-            };
-            services().account_data.update(
-                Some(&body.room_id),
-                sender_user,
-                RoomAccountDataEventType::FullyRead,
-                &serde_json::to_value(fully_read_event).expect("to json value always works"),
-            )?;
+            set_fully_read(sender_user, &body.room_id, &body.event_id)?;
         }
         create_receipt::v3::ReceiptType::Read => {
-            let mut user_receipts = BTreeMap::new();
-            user_receipts.insert(
-                sender_user.clone(),
-                ruma::events::receipt::Receipt {
-                    ts: Some(MilliSecondsSinceUnixEpoch::now()),
-                    thread: ReceiptThread::Unthreaded,
-                },
-            );
-            let mut receipts = BTreeMap::new();
-            receipts.insert(ReceiptType::Read, user_receipts);
-
-            let mut receipt_content = BTreeMap::new();
-            receipt_content.insert(body.event_id.to_owned(), receipts);
-
             services().rooms.edus.read_receipt.readreceipt_update(
                 sender_user,
                 &body.room_id,
-                ruma::events::receipt::ReceiptEvent {
-                    content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-                    room_id: body.room_id.clone(),
-                },
+                receipt_event(
+                    sender_user,
+                    &body.room_id,
+                    &body.event_id,
+                    ReceiptType::Read,
+                ),
             )?;
+            update_notification_counts(sender_user, &body.room_id, &body.event_id, &state_lock)?;
         }
         create_receipt::v3::ReceiptType::ReadPrivate => {
-            let count = services()
-                .rooms
-                .timeline
-                .get_pdu_count(&body.event_id)?
-                .ok_or(Error::BadRequest(
-                    ErrorKind::InvalidParam,
-                    "Event does not exist.",
-                ))?;
-            let count = match count {
-                PduCount::Backfilled(_) => {
-                    return Err(Error::BadRequest(
-                        ErrorKind::InvalidParam,
-                        "Read receipt is in backfilled timeline",
-                    ))
-                }
-                PduCount::Normal(c) => c,
-            };
-            services().rooms.edus.read_receipt.private_read_set(
-                &body.room_id,
-                sender_user,
-                count,
-            )?;
+            check_not_backfilled(&body.event_id)?;
+            set_private_read(sender_user, &body.room_id, &body.event_id)?;
+            update_notification_counts(sender_user, &body.room_id, &body.event_id, &state_lock)?;
         }
         _ => return Err(Error::bad_database("Unsupported receipt type")),
     }
 
     Ok(create_receipt::v3::Response {})
 }
+
+/// Makes sure markers only point to events of their room.
+fn check_event_in_room(room_id: &RoomId, event_id: &EventId) -> Result<()> {
+    if services()
+        .rooms
+        .timeline
+        .get_pdu(event_id)?
+        .map_or(true, |pdu| pdu.room_id != room_id)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event does not exist.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Private read markers are stored as counts of the timeline, which backfilled events are not in.
+fn check_not_backfilled(event_id: &EventId) -> Result<()> {
+    let count = services()
+        .rooms
+        .timeline
+        .get_pdu_count(event_id)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event does not exist.",
+        ))?;
+    match count {
+        PduCount::Backfilled(_) => Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Read receipt is in backfilled timeline",
+        )),
+        PduCount::Normal(_) => Ok(()),
+    }
+}
+
+/// Sets the m.read.private receipt only the user can see. The private read marker moves with the
+/// unread counts, see `update_notification_counts`.
+fn set_private_read(user_id: &UserId, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+    services().rooms.edus.read_receipt.readreceipt_update(
+        user_id,
+        room_id,
+        receipt_event(user_id, room_id, event_id, ReceiptType::ReadPrivate),
+    )
+}
+
+/// Recounts the unread notifications of the user after they read up to the event.
+fn update_notification_counts(
+    user_id: &UserId,
+    room_id: &RoomId,
+    event_id: &EventId,
+    state_lock: &MutexGuard<'_, ()>,
+) -> Result<()> {
+    let count = services()
+        .rooms
+        .timeline
+        .get_pdu_count(event_id)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event does not exist.",
+        ))?;
+
+    services()
+        .rooms
+        .timeline
+        .update_notification_counts(user_id, room_id, count, state_lock)
+}
+
+/// Stores the fully read marker as room account data.
+fn set_fully_read(user_id: &UserId, room_id: &RoomId, event_id: &EventId) -> Result<()> {
+    let fully_read_event = FullyReadEvent {
+        content: FullyReadEventContent {
+            event_id: event_id.to_owned(),
+        },
+    };
+
+    services().account_data.update(
+        Some(room_id),
+        user_id,
+        RoomAccountDataEventType::FullyRead,
+        &serde_json::to_value(fully_read_event).expect("to json value always works"),
+    )
+}
+
+/// Creates a receipt event of the user for a single event.
+fn receipt_event(
+    user_id: &UserId,
+    room_id: &RoomId,
+    event_id: &EventId,
+    receipt_type: ReceiptType,
+) -> ReceiptEvent {
+    let mut user_receipts = BTreeMap::new();
+    user_receipts.insert(
+        user_id.to_owned(),
+        Receipt {
+            ts: Some(MilliSecondsSinceUnixEpoch::now()),
+            thread: ReceiptThread::Unthreaded,
+        },
+    );
+
+    let mut receipts = BTreeMap::new();
+    receipts.insert(receipt_type, user_receipts);
+
+    let mut receipt_content = BTreeMap::new();
+    receipt_content.insert(event_id.to_owned(), receipts);
+
+    ReceiptEvent {
+        content: ReceiptEventContent(receipt_content),
+        room_id: room_id.to_owned(),
+    }
+}
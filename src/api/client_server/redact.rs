@@ -12,11 +12,12 @@ use serde_json::value::to_raw_value;
 ///
 /// Tries to send a redaction event into the room.
 ///
-/// - TODO: Handle txn id
+/// - Is a NOOP if the txn id was already used before and returns the same event id again
 pub async fn redact_event_route(
     body: Ruma<redact_event::v3::Request>,
 ) -> Result<redact_event::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_device = body.sender_device.as_deref();
     let body = body.body;
 
     let mutex_state = Arc::clone(
@@ -30,6 +31,15 @@ pub async fn redact_event_route(
     );
     let state_lock = mutex_state.lock().await;
 
+    // Check if this is a new transaction id
+    if let Some(event_id) =
+        services()
+            .transaction_ids
+            .existing_event_id(sender_user, sender_device, &body.txn_id)?
+    {
+        return Ok(redact_event::v3::Response { event_id });
+    }
+
     let event_id = services().rooms.timeline.build_and_append_pdu(
         PduBuilder {
             event_type: TimelineEventType::RoomRedaction,
@@ -47,6 +57,13 @@ pub async fn redact_event_route(
         &state_lock,
     )?;
 
+    services().transaction_ids.add_txnid(
+        sender_user,
+        sender_device,
+        &body.txn_id,
+        event_id.as_bytes(),
+    )?;
+
     drop(state_lock);
 
     let event_id = (*event_id).to_owned();
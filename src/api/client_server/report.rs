@@ -1,4 +1,9 @@
-use crate::{services, utils::HtmlEscape, Error, Result, Ruma};
+use crate::{
+    service::rooms::reports::Report,
+    services,
+    utils::{self, HtmlEscape},
+    Error, Result, Ruma,
+};
 use ruma::{
     api::client::{error::ErrorKind, room::report_content},
     events::room::message,
@@ -9,21 +14,27 @@ use ruma::{
 ///
 /// Reports an inappropriate event to homeserver admins
 ///
+/// - The event has to be visible to the reporter
+/// - The report is stored for the `list-reports` admin command
 pub async fn report_event_route(
     body: Ruma<report_content::v3::Request>,
 ) -> Result<report_content::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
     let pdu = match services().rooms.timeline.get_pdu(&body.event_id)? {
-        Some(pdu) => pdu,
-        _ => {
-            return Err(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Invalid Event ID",
-            ))
-        }
+        Some(pdu) if pdu.room_id == body.room_id => pdu,
+        _ => return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found.")),
     };
 
+    // Users must not learn about events they can't see
+    if !services().rooms.state_accessor.user_can_see_event(
+        sender_user,
+        &body.room_id,
+        &body.event_id,
+    )? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
     if let Some(true) = body.score.map(|s| s > int!(0) || s < int!(-100)) {
         return Err(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -38,6 +49,15 @@ pub async fn report_event_route(
         ));
     };
 
+    services().rooms.reports.add_report(&Report {
+        reporter: sender_user.clone(),
+        room_id: pdu.room_id.clone(),
+        event_id: (*pdu.event_id).to_owned(),
+        reason: body.reason.clone(),
+        score: body.score,
+        timestamp: utils::millis_since_unix_epoch(),
+    })?;
+
     services().admin
         .send_message(message::RoomMessageEventContent::text_html(
             format!(
@@ -1,5 +1,9 @@
 use crate::{
-    api::client_server::invite_helper, service::pdu::PduBuilder, services, Error, Result, Ruma,
+    api::client_server::{invite_helper, leave_room},
+    service::pdu::PduBuilder,
+    services,
+    utils::has_creator_field,
+    Error, Result, Ruma,
 };
 use ruma::{
     api::client::{
@@ -15,7 +19,7 @@ use ruma::{
             join_rules::{JoinRule, RoomJoinRulesEventContent},
             member::{MembershipState, RoomMemberEventContent},
             name::RoomNameEventContent,
-            power_levels::RoomPowerLevelsEventContent,
+            power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
             tombstone::RoomTombstoneEventContent,
             topic::RoomTopicEventContent,
         },
@@ -23,7 +27,7 @@ use ruma::{
     },
     int,
     serde::JsonObject,
-    CanonicalJsonObject, OwnedRoomAliasId, RoomAliasId, RoomId,
+    CanonicalJsonObject, OwnedRoomAliasId, RoomAliasId, RoomId, RoomVersionId, UserId,
 };
 use serde_json::{json, value::to_raw_value};
 use std::{cmp::max, collections::BTreeMap, sync::Arc};
@@ -127,12 +131,14 @@ pub async fn create_room_route(
             let mut content = content
                 .deserialize_as::<CanonicalJsonObject>()
                 .expect("Invalid creation content");
-            content.insert(
-                "creator".into(),
-                json!(&sender_user).try_into().map_err(|_| {
-                    Error::BadRequest(ErrorKind::BadJson, "Invalid creation content")
-                })?,
-            );
+            if has_creator_field(&room_version) {
+                content.insert(
+                    "creator".into(),
+                    json!(&sender_user).try_into().map_err(|_| {
+                        Error::BadRequest(ErrorKind::BadJson, "Invalid creation content")
+                    })?,
+                );
+            }
             content.insert(
                 "room_version".into(),
                 json!(room_version.as_str()).try_into().map_err(|_| {
@@ -142,9 +148,13 @@ pub async fn create_room_route(
             content
         }
         None => {
-            // TODO: Add correct value for v11
+            let create_event_content = if has_creator_field(&room_version) {
+                RoomCreateEventContent::new_v1(sender_user.clone())
+            } else {
+                RoomCreateEventContent::new_v11()
+            };
             let mut content = serde_json::from_str::<CanonicalJsonObject>(
-                to_raw_value(&RoomCreateEventContent::new_v1(sender_user.clone()))
+                to_raw_value(&create_event_content)
                     .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid creation content"))?
                     .get(),
             )
@@ -399,12 +409,15 @@ pub async fn create_room_route(
     // 8. Events implied by invite (and TODO: invite_3pid)
     drop(state_lock);
     for user_id in &body.invite {
-        let _ = invite_helper(sender_user, user_id, &room_id, None, body.is_direct).await;
+        let _ = invite_helper(sender_user, user_id, &room_id, None, body.is_direct, None).await;
     }
 
     // Homeserver specific stuff
     if let Some(alias) = alias {
-        services().rooms.alias.set_alias(&alias, &room_id)?;
+        services()
+            .rooms
+            .alias
+            .set_alias(&alias, &room_id, sender_user)?;
     }
 
     if body.visibility == room::Visibility::Public {
@@ -448,6 +461,10 @@ pub async fn get_room_event_route(
 
     let mut event = (*event).clone();
     event.add_age()?;
+    services()
+        .rooms
+        .pdu_metadata
+        .add_bundled_aggregations(sender_user, &mut event)?;
 
     Ok(get_room_event::v3::Response {
         event: event.to_room_event(),
@@ -490,11 +507,13 @@ pub async fn get_room_aliases_route(
 /// Upgrades the room.
 ///
 /// - Creates a replacement room
-/// - Sends a tombstone event into the current room
 /// - Sender user joins the room
 /// - Transfers some state events
+/// - Sends a tombstone event into the current room once the replacement room is complete
 /// - Moves local aliases
 /// - Modifies old room power levels to prevent users from speaking
+/// - Invites the members of the current room to the replacement room in the background
+/// - Purges the replacement room again if the upgrade fails before the tombstone
 pub async fn upgrade_room_route(
     body: Ruma<upgrade_room::v3::Request>,
 ) -> Result<upgrade_room::v3::Response> {
@@ -511,6 +530,28 @@ pub async fn upgrade_room_route(
         ));
     }
 
+    // Get the old room power levels
+    let mut power_levels_event_content: RoomPowerLevelsEventContent = serde_json::from_str(
+        services()
+            .rooms
+            .state_accessor
+            .room_state_get(&body.room_id, &StateEventType::RoomPowerLevels, "")?
+            .ok_or_else(|| Error::bad_database("Found room without m.room.power_levels event."))?
+            .content
+            .get(),
+    )
+    .map_err(|_| Error::bad_database("Invalid room event in database."))?;
+
+    // Check this before creating anything, the tombstone is sent last
+    if !RoomPowerLevels::from(power_levels_event_content.clone())
+        .user_can_send_state(sender_user, StateEventType::RoomTombstone)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to upgrade this room.",
+        ));
+    }
+
     // Create a replacement room
     let replacement_room = RoomId::new(services().globals.server_name());
     services()
@@ -518,6 +559,18 @@ pub async fn upgrade_room_route(
         .short
         .get_or_create_shortroomid(&replacement_room)?;
 
+    if let Err(e) = create_replacement_room(
+        sender_user,
+        &body.room_id,
+        &replacement_room,
+        &body.new_version,
+    )
+    .await
+    {
+        abandon_replacement_room(sender_user, &replacement_room).await;
+        return Err(e);
+    }
+
     let mutex_state = Arc::clone(
         services()
             .globals
@@ -530,8 +583,7 @@ pub async fn upgrade_room_route(
     let state_lock = mutex_state.lock().await;
 
     // Send a m.room.tombstone event to the old room to indicate that it is not intended to be used any further
-    // Fail if the sender does not have the required permissions
-    let tombstone_event_id = services().rooms.timeline.build_and_append_pdu(
+    let tombstone = services().rooms.timeline.build_and_append_pdu(
         PduBuilder {
             event_type: TimelineEventType::RoomTombstone,
             content: to_raw_value(&RoomTombstoneEventContent {
@@ -546,17 +598,74 @@ pub async fn upgrade_room_route(
         sender_user,
         &body.room_id,
         &state_lock,
+    );
+    if let Err(e) = tombstone {
+        drop(state_lock);
+        abandon_replacement_room(sender_user, &replacement_room).await;
+        return Err(e);
+    }
+
+    // Moves any local aliases to the new room
+    for alias in services()
+        .rooms
+        .alias
+        .local_aliases_for_room(&body.room_id)
+        .filter_map(|r| r.ok())
+    {
+        services()
+            .rooms
+            .alias
+            .set_alias(&alias, &replacement_room, sender_user)?;
+    }
+
+    // Setting events_default and invite to the greater of 50 and users_default + 1
+    let new_level = max(int!(50), power_levels_event_content.users_default + int!(1));
+    power_levels_event_content.events_default = new_level;
+    power_levels_event_content.invite = new_level;
+
+    // Modify the power levels in the old room to prevent sending of events and inviting new users
+    let _ = services().rooms.timeline.build_and_append_pdu(
+        PduBuilder {
+            event_type: TimelineEventType::RoomPowerLevels,
+            content: to_raw_value(&power_levels_event_content)
+                .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some("".to_owned()),
+            redacts: None,
+        },
+        sender_user,
+        &body.room_id,
+        &state_lock,
     )?;
 
-    // Change lock to replacement room
     drop(state_lock);
+
+    // Inviting every member takes a while in big rooms, the client doesn't have to wait for it
+    let sender_user = sender_user.to_owned();
+    let room_id = body.room_id.clone();
+    let invite_room = replacement_room.clone();
+    tokio::spawn(async move {
+        migrate_members(&sender_user, &room_id, &invite_room).await;
+    });
+
+    // Return the replacement room id
+    Ok(upgrade_room::v3::Response { replacement_room })
+}
+
+/// Creates the replacement room with the predecessor and the transferable state of the old room.
+async fn create_replacement_room(
+    sender_user: &UserId,
+    room_id: &RoomId,
+    replacement_room: &RoomId,
+    new_version: &RoomVersionId,
+) -> Result<()> {
     let mutex_state = Arc::clone(
         services()
             .globals
             .roomid_mutex_state
             .write()
             .unwrap()
-            .entry(replacement_room.clone())
+            .entry(replacement_room.to_owned())
             .or_default(),
     );
     let state_lock = mutex_state.lock().await;
@@ -566,29 +675,41 @@ pub async fn upgrade_room_route(
         services()
             .rooms
             .state_accessor
-            .room_state_get(&body.room_id, &StateEventType::RoomCreate, "")?
+            .room_state_get(room_id, &StateEventType::RoomCreate, "")?
             .ok_or_else(|| Error::bad_database("Found room without m.room.create event."))?
             .content
             .get(),
     )
     .map_err(|_| Error::bad_database("Invalid room event in database."))?;
 
-    // Use the m.room.tombstone event as the predecessor
+    // The tombstone doesn't exist yet, so the predecessor is the last known event of the old room
+    let last_event_id = services()
+        .rooms
+        .state
+        .get_forward_extremities(room_id)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::bad_database("Room has no forward extremities."))?;
     let predecessor = Some(ruma::events::room::create::PreviousRoom::new(
-        body.room_id.clone(),
-        (*tombstone_event_id).to_owned(),
+        room_id.to_owned(),
+        (*last_event_id).to_owned(),
     ));
 
     // Send a m.room.create event containing a predecessor field and the applicable room_version
-    create_event_content.insert(
-        "creator".into(),
-        json!(&sender_user)
-            .try_into()
-            .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Error forming creation event"))?,
-    );
+    if has_creator_field(new_version) {
+        create_event_content.insert(
+            "creator".into(),
+            json!(&sender_user).try_into().map_err(|_| {
+                Error::BadRequest(ErrorKind::BadJson, "Error forming creation event")
+            })?,
+        );
+    } else {
+        // The sender of the create event is the creator
+        create_event_content.remove("creator");
+    }
     create_event_content.insert(
         "room_version".into(),
-        json!(&body.new_version)
+        json!(new_version)
             .try_into()
             .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Error forming creation event"))?,
     );
@@ -623,7 +744,7 @@ pub async fn upgrade_room_route(
             redacts: None,
         },
         sender_user,
-        &replacement_room,
+        replacement_room,
         &state_lock,
     )?;
 
@@ -647,11 +768,12 @@ pub async fn upgrade_room_route(
             redacts: None,
         },
         sender_user,
-        &replacement_room,
+        replacement_room,
         &state_lock,
     )?;
 
-    // Recommended transferable state events list from the specs
+    // Recommended transferable state events list from the specs. The power levels come last, as
+    // the creator can send anything before there are any.
     let transferable_state_events = vec![
         StateEventType::RoomServerAcl,
         StateEventType::RoomEncryption,
@@ -670,7 +792,7 @@ pub async fn upgrade_room_route(
             match services()
                 .rooms
                 .state_accessor
-                .room_state_get(&body.room_id, &event_type, "")?
+                .room_state_get(room_id, &event_type, "")?
             {
                 Some(v) => v.content.clone(),
                 None => continue, // Skipping missing events.
@@ -685,58 +807,52 @@ pub async fn upgrade_room_route(
                 redacts: None,
             },
             sender_user,
-            &replacement_room,
+            replacement_room,
             &state_lock,
         )?;
     }
 
-    // Moves any local aliases to the new room
-    for alias in services()
-        .rooms
-        .alias
-        .local_aliases_for_room(&body.room_id)
-        .filter_map(|r| r.ok())
-    {
-        services()
-            .rooms
-            .alias
-            .set_alias(&alias, &replacement_room)?;
-    }
-
-    // Get the old room power levels
-    let mut power_levels_event_content: RoomPowerLevelsEventContent = serde_json::from_str(
-        services()
-            .rooms
-            .state_accessor
-            .room_state_get(&body.room_id, &StateEventType::RoomPowerLevels, "")?
-            .ok_or_else(|| Error::bad_database("Found room without m.room.create event."))?
-            .content
-            .get(),
-    )
-    .map_err(|_| Error::bad_database("Invalid room event in database."))?;
+    Ok(())
+}
 
-    // Setting events_default and invite to the greater of 50 and users_default + 1
-    let new_level = max(int!(50), power_levels_event_content.users_default + int!(1));
-    power_levels_event_content.events_default = new_level;
-    power_levels_event_content.invite = new_level;
+/// Gets rid of a replacement room that could not be completed. Nobody else knows about it yet, so
+/// the sender leaves it and it is purged.
+async fn abandon_replacement_room(sender_user: &UserId, replacement_room: &RoomId) {
+    if let Err(e) = leave_room(sender_user, replacement_room, None).await {
+        warn!(
+            "Failed to leave abandoned replacement room {}: {}",
+            replacement_room, e
+        );
+    }
 
-    // Modify the power levels in the old room to prevent sending of events and inviting new users
-    let _ = services().rooms.timeline.build_and_append_pdu(
-        PduBuilder {
-            event_type: TimelineEventType::RoomPowerLevels,
-            content: to_raw_value(&power_levels_event_content)
-                .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        sender_user,
-        &body.room_id,
-        &state_lock,
-    )?;
+    if let Err(e) = services().rooms.metadata.purge_room(replacement_room).await {
+        warn!(
+            "Failed to purge abandoned replacement room {}: {}",
+            replacement_room, e
+        );
+    }
+}
 
-    drop(state_lock);
+/// Invites the joined and invited members of the old room, so they can follow the tombstone even
+/// if the replacement room is not public.
+async fn migrate_members(sender_user: &UserId, room_id: &RoomId, replacement_room: &RoomId) {
+    let members = services()
+        .rooms
+        .state_cache
+        .room_members(room_id)
+        .chain(services().rooms.state_cache.room_members_invited(room_id))
+        .filter_map(|r| r.ok())
+        .filter(|user_id| user_id != sender_user)
+        .collect::<Vec<_>>();
 
-    // Return the replacement room id
-    Ok(upgrade_room::v3::Response { replacement_room })
+    for user_id in members {
+        if let Err(e) =
+            invite_helper(sender_user, &user_id, replacement_room, None, false, None).await
+        {
+            warn!(
+                "Failed to invite {} to replacement room {}: {}",
+                user_id, replacement_room, e
+            );
+        }
+    }
 }
@@ -1,25 +1,41 @@
-use crate::{services, Error, Result, Ruma};
+use super::filter::event_allowed;
+use crate::{service::rooms::search::rank, services, Error, PduEvent, Result, Ruma};
 use ruma::api::client::{
     error::ErrorKind,
     search::search_events::{
         self,
-        v3::{EventContextResult, ResultCategories, ResultRoomEvents, SearchResult},
+        v3::{EventContextResult, OrderBy, ResultCategories, ResultRoomEvents, SearchResult},
     },
 };
+use serde::Deserialize;
 
-use std::collections::BTreeMap;
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// How many matches per room are considered before ranking and pagination.
+const MAX_CANDIDATES_PER_ROOM: usize = 1000;
+
+/// How many matches per room are loaded and checked against the filter and visibility at most,
+/// so a filter that rejects almost everything can't make one query scan the whole index.
+const MAX_SCANNED_PER_ROOM: usize = 10 * MAX_CANDIDATES_PER_ROOM;
 
 /// # `POST /_matrix/client/r0/search`
 ///
 /// Searches rooms for messages.
 ///
-/// - Only works if the user is currently joined to the room (TODO: Respect history visibility)
+/// - Only searches rooms the user is currently joined to, and only returns events they can see
+/// - Results are filtered by the room event filter
+/// - Results are ordered by rank unless `order_by` is `recent`
 pub async fn search_events_route(
     body: Ruma<search_events::v3::Request>,
 ) -> Result<search_events::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let search_criteria = body.search_categories.room_events.as_ref().unwrap();
+    let search_criteria = body.search_categories.room_events.as_ref().ok_or_else(|| {
+        Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Only room_events search is supported.",
+        )
+    })?;
     let filter = &search_criteria.filter;
 
     let room_ids = filter.rooms.clone().unwrap_or_else(|| {
@@ -34,9 +50,16 @@ pub async fn search_events_route(
     // Use limit or else 10, with maximum 100
     let limit = filter.limit.map_or(10, u64::from).min(100) as usize;
 
-    let mut searches = Vec::new();
+    let by_rank = !matches!(search_criteria.order_by, Some(OrderBy::Recent));
+
+    let mut candidates = Vec::new();
+    let mut highlights = Vec::new();
 
     for room_id in room_ids {
+        if filter.not_rooms.contains(&room_id) {
+            continue;
+        }
+
         if !services()
             .rooms
             .state_cache
@@ -48,15 +71,57 @@ pub async fn search_events_route(
             ));
         }
 
-        if let Some(search) = services()
+        let (pdu_ids, words) = match services()
             .rooms
             .search
             .search_pdus(&room_id, &search_criteria.search_term)?
         {
-            searches.push(search.0.peekable());
+            Some(search) => search,
+            None => continue,
+        };
+
+        let matches = pdu_ids
+            .take(MAX_SCANNED_PER_ROOM)
+            .filter_map(|pdu_id| {
+                let pdu = services().rooms.timeline.get_pdu_from_id(&pdu_id).ok()??;
+                Some((pdu_id, pdu))
+            })
+            .filter(|(_, pdu)| event_allowed(filter, pdu))
+            .filter(|(_, pdu)| {
+                services()
+                    .rooms
+                    .state_accessor
+                    .user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
+                    .unwrap_or(false)
+            })
+            .take(MAX_CANDIDATES_PER_ROOM)
+            .map(|(pdu_id, pdu)| {
+                let rank = by_rank.then(|| rank(&words, &message_body(&pdu)));
+                (pdu_id, rank, pdu)
+            });
+
+        candidates.extend(matches);
+
+        for word in words {
+            if !highlights.contains(&word) {
+                highlights.push(word);
+            }
         }
     }
 
+    if by_rank {
+        candidates.sort_by(|(a_id, a_rank, _), (b_id, b_rank, _)| {
+            b_rank
+                .partial_cmp(a_rank)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| b_id[8..].cmp(&a_id[8..]))
+        });
+    } else {
+        // The part after the shortroomid is a global count, so it orders events of
+        // different rooms too
+        candidates.sort_by(|(a_id, _, _), (b_id, _, _)| b_id[8..].cmp(&a_id[8..]));
+    }
+
     let skip = match body.next_batch.as_ref().map(|s| s.parse()) {
         Some(Ok(s)) => s,
         Some(Err(_)) => {
@@ -68,37 +133,19 @@ pub async fn search_events_route(
         None => 0, // Default to the start
     };
 
-    let mut results = Vec::new();
-    for _ in 0..skip + limit {
-        if let Some(s) = searches
-            .iter_mut()
-            .map(|s| (s.peek().cloned(), s))
-            .max_by_key(|(peek, _)| peek.clone())
-            .and_then(|(_, i)| i.next())
-        {
-            results.push(s);
-        }
-    }
+    let count = candidates.len();
 
-    let results: Vec<_> = results
-        .iter()
-        .filter_map(|result| {
+    let results: Vec<_> = candidates
+        .into_iter()
+        .skip(skip)
+        .take(limit)
+        .map(|(_, rank, mut pdu)| {
             services()
                 .rooms
-                .timeline
-                .get_pdu_from_id(result)
-                .ok()?
-                .filter(|pdu| {
-                    services()
-                        .rooms
-                        .state_accessor
-                        .user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
-                        .unwrap_or(false)
-                })
-                .map(|pdu| pdu.to_room_event())
-        })
-        .map(|result| {
-            Ok::<_, Error>(SearchResult {
+                .pdu_metadata
+                .add_bundled_aggregations(sender_user, &mut pdu)?;
+
+            Ok(SearchResult {
                 context: EventContextResult {
                     end: None,
                     events_after: Vec::new(),
@@ -106,33 +153,38 @@ pub async fn search_events_route(
                     profile_info: BTreeMap::new(),
                     start: None,
                 },
-                rank: None,
-                result: Some(result),
+                rank,
+                result: Some(pdu.to_room_event()),
             })
         })
-        .filter_map(|r| r.ok())
-        .skip(skip)
-        .take(limit)
-        .collect();
+        .collect::<Result<_>>()?;
 
-    let next_batch = if results.len() < limit {
-        None
-    } else {
+    let next_batch = if skip + limit < count {
         Some((skip + limit).to_string())
+    } else {
+        None
     };
 
     Ok(search_events::v3::Response::new(ResultCategories {
         room_events: ResultRoomEvents {
-            count: Some((results.len() as u32).into()), // TODO: set this to none. Element shouldn't depend on it
-            groups: BTreeMap::new(),                    // TODO
+            count: Some((count as u32).into()),
+            groups: BTreeMap::new(), // TODO
             next_batch,
             results,
             state: BTreeMap::new(), // TODO
-            highlights: search_criteria
-                .search_term
-                .split_terminator(|c: char| !c.is_alphanumeric())
-                .map(str::to_lowercase)
-                .collect(),
+            highlights,
         },
     }))
 }
+
+fn message_body(pdu: &PduEvent) -> String {
+    #[derive(Deserialize)]
+    struct ExtractBody {
+        body: Option<String>,
+    }
+
+    serde_json::from_str::<ExtractBody>(pdu.content.get())
+        .ok()
+        .and_then(|content| content.body)
+        .unwrap_or_default()
+}
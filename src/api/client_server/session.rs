@@ -1,9 +1,25 @@
 use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
-use crate::{services, utils, Error, Result, Ruma};
+use crate::{
+    service::sso::{SsoRedirect, SESSION_COOKIE},
+    services,
+    utils::{self, HtmlEscape},
+    Error, Result, Ruma,
+};
+use axum::{
+    headers::Cookie,
+    response::{Html, IntoResponse, Redirect, Response},
+    Form, TypedHeader,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
-        session::{get_login_types, login, logout, logout_all},
+        session::{
+            get_login_types::{
+                self,
+                v3::{IdentityProvider, LoginType, SsoLoginType},
+            },
+            login, logout, logout_all, sso_login, sso_login_with_provider,
+        },
         uiaa::UserIdentifier,
     },
     UserId,
@@ -24,17 +40,126 @@ struct Claims {
 pub async fn get_login_types_route(
     _body: Ruma<get_login_types::v3::Request>,
 ) -> Result<get_login_types::v3::Response> {
-    Ok(get_login_types::v3::Response::new(vec![
-        get_login_types::v3::LoginType::Password(Default::default()),
-        get_login_types::v3::LoginType::ApplicationService(Default::default()),
-    ]))
+    let mut types = vec![LoginType::ApplicationService(Default::default())];
+    if services().globals.allow_password_login() {
+        types.insert(0, LoginType::Password(Default::default()));
+    }
+
+    let providers = services().sso.providers();
+    if !providers.is_empty() {
+        types.push(LoginType::Sso(SsoLoginType {
+            identity_providers: providers
+                .iter()
+                .map(|provider| IdentityProvider {
+                    id: provider.id.clone(),
+                    name: provider.name.clone(),
+                    icon: None,
+                    brand: None,
+                })
+                .collect(),
+        }));
+    }
+
+    if !providers.is_empty() || services().globals.jwt_decoding_key().is_some() {
+        types.push(LoginType::Token(Default::default()));
+    }
+
+    Ok(get_login_types::v3::Response::new(types))
+}
+
+/// # `GET /_matrix/client/v3/login/sso/redirect`
+///
+/// Redirects the user to the first configured identity provider to log in.
+pub async fn sso_login_route(
+    body: Ruma<sso_login::v3::Request>,
+) -> Result<sso_login::v3::Response> {
+    let (location, cookie) = services()
+        .sso
+        .authorization_url(None, &body.redirect_url)
+        .await?;
+
+    Ok(sso_login::v3::Response {
+        location,
+        cookie: Some(cookie),
+    })
+}
+
+/// # `GET /_matrix/client/v3/login/sso/redirect/{idpId}`
+///
+/// Redirects the user to the identity provider to log in.
+pub async fn sso_login_with_provider_route(
+    body: Ruma<sso_login_with_provider::v3::Request>,
+) -> Result<sso_login_with_provider::v3::Response> {
+    let (location, cookie) = services()
+        .sso
+        .authorization_url(Some(&body.idp_id), &body.redirect_url)
+        .await?;
+
+    Ok(sso_login_with_provider::v3::Response {
+        location,
+        cookie: Some(cookie),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct SsoCallback {
+    code: Option<String>,
+    state: String,
+    error: Option<String>,
+}
+
+/// # `GET /_conduit/client/oidc/callback`
+///
+/// Where the identity provider sends the user after they logged in. Redirects the user back to
+/// the client with a login token for `m.login.token`.
+///
+/// - Only works in the browser the login was started in
+/// - Clients that aren't in `sso_client_whitelist` get a page where the user confirms which host
+///   the login token goes to
+pub async fn sso_callback_route(
+    cookie: Option<TypedHeader<Cookie>>,
+    Form(callback): Form<SsoCallback>,
+) -> Result<Response> {
+    let Some(code) = callback.code.filter(|_| callback.error.is_none()) else {
+        warn!("Login at identity provider failed: {:?}", callback.error);
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Login at the identity provider failed.",
+        ));
+    };
+
+    let browser_secret = cookie
+        .as_ref()
+        .and_then(|TypedHeader(cookie)| cookie.get(SESSION_COOKIE));
+
+    let redirect = services()
+        .sso
+        .finish_login(&code, &callback.state, browser_secret)
+        .await?;
+
+    Ok(match redirect {
+        SsoRedirect::Client(url) => Redirect::to(&url).into_response(),
+        SsoRedirect::Confirm { url, host } => Html(format!(
+            "<!DOCTYPE html>\n\
+             <html><head><meta charset=\"utf-8\"><title>Continue to your client</title></head>\n\
+             <body><p>You are about to log in to {} on <b>{}</b>.</p>\n\
+             <p>Only continue if you trust this client.</p>\n\
+             <p><a href=\"{}\">Continue to {}</a></p></body></html>",
+            HtmlEscape(services().globals.server_name().as_str()),
+            HtmlEscape(&host),
+            HtmlEscape(&url),
+            HtmlEscape(&host),
+        ))
+        .into_response(),
+    })
 }
 
 /// # `POST /_matrix/client/r0/login`
 ///
 /// Authenticates the user and returns an access token it can use in subsequent requests.
 ///
-/// - The user needs to authenticate using their password (or if enabled using a json web token)
+/// - The user needs to authenticate using their password, a login token from single sign-on or
+///   if enabled a json web token
 /// - If `device_id` is known: invalidates old access token of that device
 /// - If `device_id` is unknown: creates a new device
 /// - Returns access token that is associated with the user and device
@@ -67,6 +192,17 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
             }
             .map_err(|_| Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid."))?;
 
+            // The server user keeps working with the emergency password
+            if !services().globals.allow_password_login()
+                && !(user_id.server_name() == services().globals.server_name()
+                    && user_id.localpart() == "conduit")
+            {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Password login is disabled on this server.",
+                ));
+            }
+
             let hash = services()
                 .users
                 .password_hash(&user_id)?
@@ -94,7 +230,15 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
             user_id
         }
         login::v3::LoginInfo::Token(login::v3::Token { token }) => {
-            if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
+            if let Some(user_id) = services().sso.redeem_login_token(token) {
+                if services().users.is_deactivated(&user_id)? {
+                    return Err(Error::BadRequest(
+                        ErrorKind::UserDeactivated,
+                        "The user has been deactivated",
+                    ));
+                }
+                user_id
+            } else if let Some(jwt_decoding_key) = services().globals.jwt_decoding_key() {
                 let token = jsonwebtoken::decode::<Claims>(
                     token,
                     jwt_decoding_key,
@@ -105,6 +249,8 @@ pub async fn login_route(body: Ruma<login::v3::Request>) -> Result<login::v3::Re
                 UserId::parse_with_server_name(username, services().globals.server_name()).map_err(
                     |_| Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid."),
                 )?
+            } else if !services().sso.providers().is_empty() {
+                return Err(Error::BadRequest(ErrorKind::Forbidden, "Token is invalid."));
             } else {
                 return Err(Error::BadRequest(
                     ErrorKind::Unknown,
@@ -1,6 +1,8 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
-use crate::{service::pdu::PduBuilder, services, Error, Result, Ruma, RumaResponse};
+use super::get_alias_helper;
+use crate::{service::pdu::PduBuilder, services, Error, PduEvent, Result, Ruma, RumaResponse};
+use axum::extract::RawQuery;
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -10,17 +12,29 @@ use ruma::{
         room::canonical_alias::RoomCanonicalAliasEventContent, AnyStateEventContent, StateEventType,
     },
     serde::Raw,
-    EventId, RoomId, UserId,
+    EventId, OwnedRoomAliasId, RoomId, UserId,
 };
+use serde::Deserialize;
 use tracing::log::warn;
 
+/// How long other servers have to resolve the aliases of a new canonical alias event.
+const REMOTE_ALIAS_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The `at` query parameter, an event id or sync token. The state endpoints accept it beyond the
+/// spec to return the state at this point instead of the current one.
+#[derive(Deserialize)]
+struct StateAtQuery {
+    at: Option<String>,
+}
+
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/state/{eventType}/{stateKey}`
 ///
 /// Sends a state event into the room.
 ///
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is allowed
-/// - If event is new canonical_alias: Rejects if alias is incorrect
+/// - If event is new canonical_alias: Rejects if an alias that wasn't in the previous event doesn't
+/// point to the room
 pub async fn send_state_event_for_key_route(
     body: Ruma<send_state_event::v3::Request>,
 ) -> Result<send_state_event::v3::Response> {
@@ -77,7 +91,10 @@ pub async fn send_state_event_for_empty_key_route(
 /// Get all state events for a room.
 ///
 /// - If not joined: Only works if current room history visibility is world readable
+/// - With `at`, the state at this event or sync token is returned instead of the current one, if
+///   the user could see the room at that point
 pub async fn get_state_events_route(
+    RawQuery(query): RawQuery,
     body: Ruma<get_state_events::v3::Request>,
 ) -> Result<get_state_events::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -93,15 +110,31 @@ pub async fn get_state_events_route(
         ));
     }
 
+    let state = match state_at(query)? {
+        Some(at) => {
+            let shortstatehash = services().rooms.state_accessor.shortstatehash_at_token(
+                sender_user,
+                &body.room_id,
+                &at,
+            )?;
+
+            services()
+                .rooms
+                .state_accessor
+                .state_full(shortstatehash)
+                .await?
+        }
+        None => {
+            services()
+                .rooms
+                .state_accessor
+                .room_state_full(&body.room_id)
+                .await?
+        }
+    };
+
     Ok(get_state_events::v3::Response {
-        room_state: services()
-            .rooms
-            .state_accessor
-            .room_state_full(&body.room_id)
-            .await?
-            .values()
-            .map(|pdu| pdu.to_state_event())
-            .collect(),
+        room_state: state.values().map(|pdu| pdu.to_state_event()).collect(),
     })
 }
 
@@ -110,7 +143,10 @@ pub async fn get_state_events_route(
 /// Get single state event of a room.
 ///
 /// - If not joined: Only works if current room history visibility is world readable
+/// - With `at`, the state event at this event or sync token is returned instead of the current
+///   one, if the user could see the room at that point
 pub async fn get_state_events_for_key_route(
+    RawQuery(query): RawQuery,
     body: Ruma<get_state_events_for_key::v3::Request>,
 ) -> Result<get_state_events_for_key::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -126,17 +162,20 @@ pub async fn get_state_events_for_key_route(
         ));
     }
 
-    let event = services()
-        .rooms
-        .state_accessor
-        .room_state_get(&body.room_id, &body.event_type, &body.state_key)?
-        .ok_or_else(|| {
-            warn!(
-                "State event {:?} not found in room {:?}",
-                &body.event_type, &body.room_id
-            );
-            Error::BadRequest(ErrorKind::NotFound, "State event not found.")
-        })?;
+    let event = state_event_at(
+        sender_user,
+        &body.room_id,
+        &body.event_type,
+        &body.state_key,
+        state_at(query)?.as_deref(),
+    )?
+    .ok_or_else(|| {
+        warn!(
+            "State event {:?} not found in room {:?}",
+            &body.event_type, &body.room_id
+        );
+        Error::BadRequest(ErrorKind::NotFound, "State event not found.")
+    })?;
 
     Ok(get_state_events_for_key::v3::Response {
         content: serde_json::from_str(event.content.get())
@@ -149,7 +188,10 @@ pub async fn get_state_events_for_key_route(
 /// Get single state event of a room.
 ///
 /// - If not joined: Only works if current room history visibility is world readable
+/// - With `at`, the state event at this event or sync token is returned instead of the current
+///   one, if the user could see the room at that point
 pub async fn get_state_events_for_empty_key_route(
+    RawQuery(query): RawQuery,
     body: Ruma<get_state_events_for_key::v3::Request>,
 ) -> Result<RumaResponse<get_state_events_for_key::v3::Response>> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -165,17 +207,20 @@ pub async fn get_state_events_for_empty_key_route(
         ));
     }
 
-    let event = services()
-        .rooms
-        .state_accessor
-        .room_state_get(&body.room_id, &body.event_type, "")?
-        .ok_or_else(|| {
-            warn!(
-                "State event {:?} not found in room {:?}",
-                &body.event_type, &body.room_id
-            );
-            Error::BadRequest(ErrorKind::NotFound, "State event not found.")
-        })?;
+    let event = state_event_at(
+        sender_user,
+        &body.room_id,
+        &body.event_type,
+        "",
+        state_at(query)?.as_deref(),
+    )?
+    .ok_or_else(|| {
+        warn!(
+            "State event {:?} not found in room {:?}",
+            &body.event_type, &body.room_id
+        );
+        Error::BadRequest(ErrorKind::NotFound, "State event not found.")
+    })?;
 
     Ok(get_state_events_for_key::v3::Response {
         content: serde_json::from_str(event.content.get())
@@ -184,6 +229,44 @@ pub async fn get_state_events_for_empty_key_route(
     .into())
 }
 
+/// Returns the `at` query parameter of a state request.
+fn state_at(query: Option<String>) -> Result<Option<String>> {
+    let Some(query) = query else {
+        return Ok(None);
+    };
+
+    serde_html_form::from_str::<StateAtQuery>(&query)
+        .map(|query| query.at)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid at parameter."))
+}
+
+/// Returns the state event at the `at` parameter, or the current one without it.
+fn state_event_at(
+    user_id: &UserId,
+    room_id: &RoomId,
+    event_type: &StateEventType,
+    state_key: &str,
+    at: Option<&str>,
+) -> Result<Option<Arc<PduEvent>>> {
+    match at {
+        Some(at) => {
+            let shortstatehash = services()
+                .rooms
+                .state_accessor
+                .shortstatehash_at_token(user_id, room_id, at)?;
+
+            services()
+                .rooms
+                .state_accessor
+                .state_get(shortstatehash, event_type, state_key)
+        }
+        None => services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, event_type, state_key),
+    }
+}
+
 async fn send_state_event_for_key_helper(
     sender: &UserId,
     room_id: &RoomId,
@@ -193,33 +276,8 @@ async fn send_state_event_for_key_helper(
 ) -> Result<Arc<EventId>> {
     let sender_user = sender;
 
-    // TODO: Review this check, error if event is unparsable, use event type, allow alias if it
-    // previously existed
-    if let Ok(canonical_alias) =
-        serde_json::from_str::<RoomCanonicalAliasEventContent>(json.json().get())
-    {
-        let mut aliases = canonical_alias.alt_aliases.clone();
-
-        if let Some(alias) = canonical_alias.alias {
-            aliases.push(alias);
-        }
-
-        for alias in aliases {
-            if alias.server_name() != services().globals.server_name()
-                || services()
-                    .rooms
-                    .alias
-                    .resolve_local_alias(&alias)?
-                    .filter(|room| room == room_id) // Make sure it's the right room
-                    .is_none()
-            {
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "You are only allowed to send canonical_alias \
-                    events when it's aliases already exists",
-                ));
-            }
-        }
+    if *event_type == StateEventType::RoomCanonicalAlias {
+        validate_canonical_alias(room_id, json).await?;
     }
 
     let mutex_state = Arc::clone(
@@ -248,3 +306,75 @@ async fn send_state_event_for_key_helper(
 
     Ok(event_id)
 }
+
+/// Makes sure the aliases of a canonical alias event point to the room. Aliases that were in the
+/// previous event are not checked again, so they don't block changes after they were removed.
+async fn validate_canonical_alias(
+    room_id: &RoomId,
+    json: &Raw<AnyStateEventContent>,
+) -> Result<()> {
+    let content = serde_json::from_str::<RoomCanonicalAliasEventContent>(json.json().get())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid canonical alias event."))?;
+
+    let previous = services()
+        .rooms
+        .state_accessor
+        .room_state_get(room_id, &StateEventType::RoomCanonicalAlias, "")?
+        .and_then(|pdu| {
+            serde_json::from_str::<RoomCanonicalAliasEventContent>(pdu.content.get()).ok()
+        })
+        .map(|previous| {
+            previous
+                .alias
+                .into_iter()
+                .chain(previous.alt_aliases)
+                .collect::<HashSet<_>>()
+        })
+        .unwrap_or_default();
+
+    let new_aliases = content
+        .alias
+        .into_iter()
+        .chain(content.alt_aliases)
+        .filter(|alias| !previous.contains(alias))
+        .collect::<HashSet<_>>();
+
+    for alias in new_aliases {
+        if !alias_points_to(&alias, room_id).await? {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "One of the aliases doesn't point to this room.",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the alias points to the room, which is asked for over federation for aliases of other
+/// servers.
+async fn alias_points_to(alias: &OwnedRoomAliasId, room_id: &RoomId) -> Result<bool> {
+    if alias.server_name() == services().globals.server_name() {
+        return Ok(services()
+            .rooms
+            .alias
+            .resolve_local_alias(alias)?
+            .map_or(false, |alias_room| &*alias_room == room_id));
+    }
+
+    if !services().globals.check_remote_canonical_aliases() {
+        return Ok(true);
+    }
+
+    match tokio::time::timeout(REMOTE_ALIAS_CHECK_TIMEOUT, get_alias_helper(alias.clone())).await {
+        Ok(Ok(response)) => Ok(&*response.room_id == room_id),
+        Ok(Err(e)) => {
+            warn!("Failed to resolve remote alias {alias}: {e}");
+            Ok(false)
+        }
+        Err(_) => {
+            warn!("Timed out resolving remote alias {alias}");
+            Ok(false)
+        }
+    }
+}
@@ -1,14 +1,16 @@
+use super::filter::{event_allowed, room_allowed, sender_not_ignored, type_allowed};
 use crate::{
     service::rooms::timeline::PduCount, services, Error, PduEvent, Result, Ruma, RumaResponse,
 };
 use ruma::{
     api::client::{
-        filter::{FilterDefinition, LazyLoadOptions},
+        filter::{FilterDefinition, LazyLoadOptions, RoomEventFilter, RoomFilter},
         sync::sync_events::{
             self,
             v3::{
                 Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom, JoinedRoom,
-                LeftRoom, Presence, RoomAccountData, RoomSummary, Rooms, State, Timeline, ToDevice,
+                KnockState, KnockedRoom, LeftRoom, Presence, RoomAccountData, RoomSummary, Rooms,
+                State, Timeline, ToDevice,
             },
             v4::SlidingOp,
             DeviceLists, UnreadNotificationsCount,
@@ -17,6 +19,7 @@ use ruma::{
     },
     events::{
         room::member::{MembershipState, RoomMemberEventContent},
+        AnyStrippedStateEvent, GlobalAccountDataEventType, RoomAccountDataEventType,
         StateEventType, TimelineEventType,
     },
     serde::Raw,
@@ -36,6 +39,8 @@ use tracing::error;
 ///
 /// - This endpoint takes a `since` parameter which should be the `next_batch` value from a
 /// previous request for incremental syncs.
+/// - The room filter decides which rooms, timeline and state events, EDUs and room account data
+/// are returned, and how long the timelines are
 ///
 /// Calling this endpoint without a `since` parameter returns:
 /// - Some of the most recent events of each timeline
@@ -59,6 +64,9 @@ use tracing::error;
 /// For invited rooms:
 /// - If the user was invited after `since`: A subset of the state of the room at the point of the invite
 ///
+/// For knocked rooms:
+/// - If the user knocked after `since`: A subset of the state of the room at the point of the knock
+///
 /// For left rooms:
 /// - If the user left after `since`: prev_batch token, empty state (TODO: subset of the state at the point of the leave)
 ///
@@ -170,14 +178,19 @@ async fn sync_helper(
     body: sync_events::v3::Request,
     // bool = caching allowed
 ) -> Result<(sync_events::v3::Response, bool), Error> {
-    // TODO: match body.set_presence {
-    services().rooms.edus.presence.ping_presence(&sender_user)?;
+    services()
+        .rooms
+        .edus
+        .presence
+        .ping_presence(&sender_user, &body.set_presence)?;
 
     // Setup watchers, so if there's no response, we can wait for them
     let watcher = services().globals.watch(&sender_user, &sender_device);
 
     let next_batch = services().globals.current_count()?;
     let next_batchcount = PduCount::Normal(next_batch);
+    // Account data with a count up to next_batch has to be stored before we read it
+    services().account_data.wait_for_updates(&sender_user);
     let next_batch_string = next_batch.to_string();
 
     // Load filter
@@ -208,17 +221,19 @@ async fn sync_helper(
     let sincecount = PduCount::Normal(since);
 
     let mut presence_updates = HashMap::new();
-    let mut left_encrypted_users = HashSet::new(); // Users that have left any encrypted rooms the sender was in
-    let mut device_list_updates = HashSet::new();
-    let mut device_list_left = HashSet::new();
 
-    // Look for device list updates of this account
-    device_list_updates.extend(
-        services()
-            .users
-            .keys_changed(sender_user.as_ref(), since, None)
-            .filter_map(|r| r.ok()),
-    );
+    // Device list changes of users we share encrypted rooms with, including our own devices.
+    // An initial sync has nothing to compare to, the client queries all keys instead.
+    let (device_list_updates, device_list_left) = if since == 0 {
+        (HashSet::new(), HashSet::new())
+    } else {
+        services().users.device_list_changes(&sender_user, since)?
+    };
+    services().users.acknowledge_device_list_changes(
+        &sender_user,
+        &sender_device,
+        if since == 0 { next_batch } else { since },
+    )?;
 
     let all_joined_rooms = services()
         .rooms
@@ -227,6 +242,14 @@ async fn sync_helper(
         .collect::<Vec<_>>();
     for room_id in all_joined_rooms {
         let room_id = room_id?;
+        if !room_allowed(
+            &room_id,
+            filter.room.rooms.as_deref(),
+            &filter.room.not_rooms,
+        ) {
+            continue;
+        }
+
         if let Ok(joined_room) = load_joined_room(
             &sender_user,
             &sender_device,
@@ -238,8 +261,7 @@ async fn sync_helper(
             lazy_load_enabled,
             lazy_load_send_redundant,
             full_state,
-            &mut device_list_updates,
-            &mut left_encrypted_users,
+            &filter.room,
         )
         .await
         {
@@ -247,6 +269,10 @@ async fn sync_helper(
                 joined_rooms.insert(room_id.clone(), joined_room);
             }
 
+            if !services().globals.allow_presence() {
+                continue;
+            }
+
             // Take presence updates from this room
             for (user_id, presence) in services()
                 .rooms
@@ -292,6 +318,13 @@ async fn sync_helper(
         .collect();
     for result in all_left_rooms {
         let (room_id, _) = result?;
+        if !room_allowed(
+            &room_id,
+            filter.room.rooms.as_deref(),
+            &filter.room.not_rooms,
+        ) {
+            continue;
+        }
 
         let mut left_state_events = Vec::new();
 
@@ -392,6 +425,10 @@ async fn sync_helper(
                         }
                     };
 
+                    if !event_allowed(&filter.room.state, &pdu) {
+                        continue;
+                    }
+
                     left_state_events.push(pdu.to_sync_state_event());
 
                     i += 1;
@@ -419,6 +456,14 @@ async fn sync_helper(
     }
 
     let mut invited_rooms = BTreeMap::new();
+    let ignored_users = services().users.ignored_users(&sender_user)?;
+    // Invites of users who were unignored since the last sync are sent again
+    let ignore_list_changed = services()
+        .account_data
+        .changes_since(None, &sender_user, since)?
+        .contains_key(&RoomAccountDataEventType::from(
+            GlobalAccountDataEventType::IgnoredUserList.to_string(),
+        ));
     let all_invited_rooms: Vec<_> = services()
         .rooms
         .state_cache
@@ -426,6 +471,13 @@ async fn sync_helper(
         .collect();
     for result in all_invited_rooms {
         let (room_id, invite_state_events) = result?;
+        if !room_allowed(
+            &room_id,
+            filter.room.rooms.as_deref(),
+            &filter.room.not_rooms,
+        ) {
+            continue;
+        }
 
         {
             // Get and drop the lock to wait for remaining operations to finish
@@ -448,7 +500,13 @@ async fn sync_helper(
             .get_invite_count(&room_id, &sender_user)?;
 
         // Invited before last sync
-        if Some(since) >= invite_count {
+        if Some(since) >= invite_count && !ignore_list_changed {
+            continue;
+        }
+
+        if invite_sender(&invite_state_events, &sender_user)
+            .map_or(false, |inviter| ignored_users.contains(&inviter))
+        {
             continue;
         }
 
@@ -462,28 +520,40 @@ async fn sync_helper(
         );
     }
 
-    for user_id in left_encrypted_users {
-        let dont_share_encrypted_room = services()
+    let mut knocked_rooms = BTreeMap::new();
+    let all_knocked_rooms: Vec<_> = services()
+        .rooms
+        .state_cache
+        .rooms_knocked(&sender_user)
+        .collect();
+    for result in all_knocked_rooms {
+        let (room_id, knock_state_events) = result?;
+        if !room_allowed(
+            &room_id,
+            filter.room.rooms.as_deref(),
+            &filter.room.not_rooms,
+        ) {
+            continue;
+        }
+
+        let knock_count = services()
             .rooms
-            .user
-            .get_shared_rooms(vec![sender_user.clone(), user_id.clone()])?
-            .filter_map(|r| r.ok())
-            .filter_map(|other_room_id| {
-                Some(
-                    services()
-                        .rooms
-                        .state_accessor
-                        .room_state_get(&other_room_id, &StateEventType::RoomEncryption, "")
-                        .ok()?
-                        .is_some(),
-                )
-            })
-            .all(|encrypted| !encrypted);
-        // If the user doesn't share an encrypted room with the target anymore, we need to tell
-        // them
-        if dont_share_encrypted_room {
-            device_list_left.insert(user_id);
+            .state_cache
+            .get_knock_count(&room_id, &sender_user)?;
+
+        // Knocked before last sync
+        if Some(since) >= knock_count {
+            continue;
         }
+
+        knocked_rooms.insert(
+            room_id.clone(),
+            KnockedRoom {
+                knock_state: KnockState {
+                    events: knock_state_events,
+                },
+            },
+        );
     }
 
     // Remove all to-device events the device received *last time*
@@ -497,7 +567,7 @@ async fn sync_helper(
             leave: left_rooms,
             join: joined_rooms,
             invite: invited_rooms,
-            knock: BTreeMap::new(), // TODO
+            knock: knocked_rooms,
         },
         presence: Presence {
             events: presence_updates
@@ -525,12 +595,17 @@ async fn sync_helper(
             .users
             .count_one_time_keys(&sender_user, &sender_device)?,
         to_device: ToDevice {
-            events: services()
-                .users
-                .get_to_device_events(&sender_user, &sender_device)?,
+            events: services().users.get_to_device_events(
+                &sender_user,
+                &sender_device,
+                next_batch,
+            )?,
         },
-        // Fallback keys are not yet supported
-        device_unused_fallback_key_types: None,
+        device_unused_fallback_key_types: Some(
+            services()
+                .users
+                .unused_fallback_key_types(&sender_user, &sender_device)?,
+        ),
     };
 
     // TODO: Retry the endpoint instead of returning (waiting for #118)
@@ -566,8 +641,7 @@ async fn load_joined_room(
     lazy_load_enabled: bool,
     lazy_load_send_redundant: bool,
     full_state: bool,
-    device_list_updates: &mut HashSet<OwnedUserId>,
-    left_encrypted_users: &mut HashSet<OwnedUserId>,
+    filter: &RoomFilter,
 ) -> Result<JoinedRoom> {
     {
         // Get and drop the lock to wait for remaining operations to finish
@@ -585,7 +659,14 @@ async fn load_joined_room(
         drop(insert_lock);
     }
 
-    let (timeline_pdus, limited) = load_timeline(sender_user, room_id, sincecount, 10)?;
+    let timeline_limit = filter.timeline.limit.map_or(10, u64::from).min(100);
+    let (timeline_pdus, limited) = load_timeline(
+        sender_user,
+        room_id,
+        sincecount,
+        timeline_limit,
+        &filter.timeline,
+    )?;
 
     let send_notification_counts = !timeline_pdus.is_empty()
         || services()
@@ -628,16 +709,8 @@ async fn load_joined_room(
         } else {
             // Calculates joined_member_count, invited_member_count and heroes
             let calculate_counts = || {
-                let joined_member_count = services()
-                    .rooms
-                    .state_cache
-                    .room_joined_count(room_id)?
-                    .unwrap_or(0);
-                let invited_member_count = services()
-                    .rooms
-                    .state_cache
-                    .room_invited_count(room_id)?
-                    .unwrap_or(0);
+                let (joined_member_count, invited_member_count) =
+                    services().rooms.state_cache.get_member_count(room_id)?;
 
                 // Recalculate heroes (first 5 members)
                 let mut heroes = Vec::new();
@@ -861,27 +934,18 @@ async fn load_joined_room(
                     }
                 }
 
-                for (_, event) in &timeline_pdus {
-                    if lazy_loaded.contains(&event.sender) {
-                        continue;
-                    }
-
-                    if !services().rooms.lazy_loading.lazy_load_was_sent_before(
+                for (user_id, member_event) in
+                    services().rooms.state_accessor.lazy_loading_members(
                         sender_user,
                         sender_device,
                         room_id,
-                        &event.sender,
-                    )? || lazy_load_send_redundant
-                    {
-                        if let Some(member_event) = services().rooms.state_accessor.room_state_get(
-                            room_id,
-                            &StateEventType::RoomMember,
-                            event.sender.as_str(),
-                        )? {
-                            lazy_loaded.insert(event.sender.clone());
-                            state_events.push(member_event);
-                        }
-                    }
+                        timeline_pdus.iter().map(|(_, pdu)| pdu),
+                        &lazy_loaded,
+                        lazy_load_send_redundant,
+                    )?
+                {
+                    lazy_loaded.insert(user_id);
+                    state_events.push(member_event);
                 }
 
                 services().rooms.lazy_loading.lazy_load_mark_sent(
@@ -892,83 +956,10 @@ async fn load_joined_room(
                     next_batchcount,
                 );
 
-                let encrypted_room = services()
-                    .rooms
-                    .state_accessor
-                    .state_get(current_shortstatehash, &StateEventType::RoomEncryption, "")?
-                    .is_some();
-
-                let since_encryption = services().rooms.state_accessor.state_get(
-                    since_shortstatehash,
-                    &StateEventType::RoomEncryption,
-                    "",
-                )?;
-
-                // Calculations:
-                let new_encrypted_room = encrypted_room && since_encryption.is_none();
-
                 let send_member_count = state_events
                     .iter()
                     .any(|event| event.kind == TimelineEventType::RoomMember);
 
-                if encrypted_room {
-                    for state_event in &state_events {
-                        if state_event.kind != TimelineEventType::RoomMember {
-                            continue;
-                        }
-
-                        if let Some(state_key) = &state_event.state_key {
-                            let user_id = UserId::parse(state_key.clone()).map_err(|_| {
-                                Error::bad_database("Invalid UserId in member PDU.")
-                            })?;
-
-                            if user_id == sender_user {
-                                continue;
-                            }
-
-                            let new_membership = serde_json::from_str::<RoomMemberEventContent>(
-                                state_event.content.get(),
-                            )
-                            .map_err(|_| Error::bad_database("Invalid PDU in database."))?
-                            .membership;
-
-                            match new_membership {
-                                MembershipState::Join => {
-                                    // A new user joined an encrypted room
-                                    if !share_encrypted_room(sender_user, &user_id, room_id)? {
-                                        device_list_updates.insert(user_id);
-                                    }
-                                }
-                                MembershipState::Leave => {
-                                    // Write down users that have left encrypted rooms we are in
-                                    left_encrypted_users.insert(user_id);
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                }
-
-                if joined_since_last_sync && encrypted_room || new_encrypted_room {
-                    // If the user is in a new encrypted room, give them all joined users
-                    device_list_updates.extend(
-                        services()
-                            .rooms
-                            .state_cache
-                            .room_members(room_id)
-                            .flatten()
-                            .filter(|user_id| {
-                                // Don't send key updates from the sender to the sender
-                                sender_user != user_id
-                            })
-                            .filter(|user_id| {
-                                // Only send keys if the sender doesn't share an encrypted room with the target already
-                                !share_encrypted_room(sender_user, user_id, room_id)
-                                    .unwrap_or(false)
-                            }),
-                    );
-                }
-
                 let (joined_member_count, invited_member_count, heroes) = if send_member_count {
                     calculate_counts()?
                 } else {
@@ -985,14 +976,6 @@ async fn load_joined_room(
             }
         };
 
-    // Look for device list updates in this room
-    device_list_updates.extend(
-        services()
-            .users
-            .keys_changed(room_id.as_ref(), since, None)
-            .filter_map(|r| r.ok()),
-    );
-
     let notification_count = if send_notification_counts {
         Some(
             services()
@@ -1036,16 +1019,30 @@ async fn load_joined_room(
         .map(|(_, pdu)| pdu.to_sync_room_event())
         .collect();
 
-    let mut edus: Vec<_> = services()
-        .rooms
-        .edus
-        .read_receipt
-        .readreceipts_since(room_id, since)
-        .filter_map(|r| r.ok()) // Filter out buggy events
-        .map(|(_, _, v)| v)
-        .collect();
+    let ephemeral_allowed = |event_type| {
+        room_allowed(
+            room_id,
+            filter.ephemeral.rooms.as_deref(),
+            &filter.ephemeral.not_rooms,
+        ) && type_allowed(&filter.ephemeral, event_type)
+    };
 
-    if services().rooms.edus.typing.last_typing_update(room_id)? > since {
+    let mut edus: Vec<_> = if ephemeral_allowed("m.receipt") {
+        services()
+            .rooms
+            .edus
+            .read_receipt
+            .readreceipts_since(room_id, sender_user, since)
+            .filter_map(|r| r.ok()) // Filter out buggy events
+            .map(|(_, _, v)| v)
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    if ephemeral_allowed("m.typing")
+        && services().rooms.edus.typing.last_typing_update(room_id)? > since
+    {
         edus.push(
             serde_json::from_str(
                 &serde_json::to_string(&services().rooms.edus.typing.typings_all(room_id)?)
@@ -1068,6 +1065,13 @@ async fn load_joined_room(
                 .account_data
                 .changes_since(Some(room_id), sender_user, since)?
                 .into_iter()
+                .filter(|(event_type, _)| {
+                    room_allowed(
+                        room_id,
+                        filter.account_data.rooms.as_deref(),
+                        &filter.account_data.not_rooms,
+                    ) && type_allowed(&filter.account_data, &event_type.to_string())
+                })
                 .filter_map(|(_, v)| {
                     serde_json::from_str(v.json().get())
                         .map_err(|_| Error::bad_database("Invalid account event in database."))
@@ -1092,6 +1096,7 @@ async fn load_joined_room(
         state: State {
             events: state_events
                 .iter()
+                .filter(|pdu| event_allowed(&filter.state, pdu))
                 .map(|pdu| pdu.to_sync_state_event())
                 .collect(),
         },
@@ -1100,14 +1105,32 @@ async fn load_joined_room(
     })
 }
 
+/// Returns who invited the user, from the member event in the stripped state of the invite.
+fn invite_sender(
+    invite_state: &[Raw<AnyStrippedStateEvent>],
+    user_id: &UserId,
+) -> Option<OwnedUserId> {
+    invite_state
+        .iter()
+        .filter_map(|event| event.deserialize().ok())
+        .find_map(|event| match event {
+            AnyStrippedStateEvent::RoomMember(member) if *member.state_key == *user_id => {
+                Some(member.sender)
+            }
+            _ => None,
+        })
+}
+
 fn load_timeline(
     sender_user: &UserId,
     room_id: &RoomId,
     roomsincecount: PduCount,
     limit: u64,
+    filter: &RoomEventFilter,
 ) -> Result<(Vec<(PduCount, PduEvent)>, bool), Error> {
-    let timeline_pdus;
+    let mut timeline_pdus;
     let limited;
+    let ignored_users = services().users.ignored_users(sender_user)?;
     if services()
         .rooms
         .timeline
@@ -1125,20 +1148,40 @@ fn load_timeline(
                 }
                 r.ok()
             })
-            .take_while(|(pducount, _)| pducount > &roomsincecount);
+            .take_while(|(pducount, _)| pducount > &roomsincecount)
+            .filter(|(_, pdu)| event_allowed(filter, pdu))
+            .filter(|(_, pdu)| sender_not_ignored(&ignored_users, pdu))
+            .filter_map(|(pducount, pdu)| {
+                match services().rooms.state_accessor.user_can_see_event(
+                    sender_user,
+                    room_id,
+                    &pdu.event_id,
+                ) {
+                    Ok(true) => Some(Ok((pducount, pdu))),
+                    Ok(false) => None,
+                    Err(e) => Some(Err(e)),
+                }
+            });
 
         // Take the last events for the timeline
         timeline_pdus = non_timeline_pdus
             .by_ref()
             .take(limit as usize)
-            .collect::<Vec<_>>()
+            .collect::<Result<Vec<_>>>()?
             .into_iter()
             .rev()
             .collect::<Vec<_>>();
 
         // They /sync response doesn't always return all messages, so we say the output is
         // limited unless there are events in non_timeline_pdus
-        limited = non_timeline_pdus.next().is_some();
+        limited = non_timeline_pdus.next().transpose()?.is_some();
+
+        for (_, pdu) in &mut timeline_pdus {
+            services()
+                .rooms
+                .pdu_metadata
+                .add_bundled_aggregations(sender_user, pdu)?;
+        }
     } else {
         timeline_pdus = Vec::new();
         limited = false;
@@ -1146,30 +1189,6 @@ fn load_timeline(
     Ok((timeline_pdus, limited))
 }
 
-fn share_encrypted_room(
-    sender_user: &UserId,
-    user_id: &UserId,
-    ignore_room: &RoomId,
-) -> Result<bool> {
-    Ok(services()
-        .rooms
-        .user
-        .get_shared_rooms(vec![sender_user.to_owned(), user_id.to_owned()])?
-        .filter_map(|r| r.ok())
-        .filter(|room_id| room_id != ignore_room)
-        .filter_map(|other_room_id| {
-            Some(
-                services()
-                    .rooms
-                    .state_accessor
-                    .room_state_get(&other_room_id, &StateEventType::RoomEncryption, "")
-                    .ok()?
-                    .is_some(),
-            )
-        })
-        .any(|encrypted| encrypted))
-}
-
 pub async fn sync_events_v4_route(
     body: Ruma<sync_events::v4::Request>,
 ) -> Result<sync_events::v4::Response, RumaResponse<UiaaResponse>> {
@@ -1180,6 +1199,7 @@ pub async fn sync_events_v4_route(
     let watcher = services().globals.watch(&sender_user, &sender_device);
 
     let next_batch = services().globals.next_count()?;
+    services().account_data.wait_for_updates(&sender_user);
 
     let globalsince = body
         .pos
@@ -1328,7 +1348,7 @@ pub async fn sync_events_v4_route(
                                     match new_membership {
                                         MembershipState::Join => {
                                             // A new user joined an encrypted room
-                                            if !share_encrypted_room(
+                                            if !services().users.share_encrypted_room(
                                                 &sender_user,
                                                 &user_id,
                                                 room_id,
@@ -1360,7 +1380,9 @@ pub async fn sync_events_v4_route(
                                 })
                                 .filter(|user_id| {
                                     // Only send keys if the sender doesn't share an encrypted room with the target already
-                                    !share_encrypted_room(&sender_user, user_id, room_id)
+                                    !services()
+                                        .users
+                                        .share_encrypted_room(&sender_user, user_id, room_id)
                                         .unwrap_or(false)
                                 }),
                         );
@@ -1525,8 +1547,13 @@ pub async fn sync_events_v4_route(
     for (room_id, (required_state_request, timeline_limit, roomsince)) in &todo_rooms {
         let roomsincecount = PduCount::Normal(*roomsince);
 
-        let (timeline_pdus, limited) =
-            load_timeline(&sender_user, room_id, roomsincecount, *timeline_limit)?;
+        let (timeline_pdus, limited) = load_timeline(
+            &sender_user,
+            room_id,
+            roomsincecount,
+            *timeline_limit,
+            &RoomEventFilter::default(),
+        )?;
 
         if roomsince != &0 && timeline_pdus.is_empty() {
             continue;
@@ -1696,9 +1723,11 @@ pub async fn sync_events_v4_route(
         extensions: sync_events::v4::Extensions {
             to_device: if body.extensions.to_device.enabled.unwrap_or(false) {
                 Some(sync_events::v4::ToDevice {
-                    events: services()
-                        .users
-                        .get_to_device_events(&sender_user, &sender_device)?,
+                    events: services().users.get_to_device_events(
+                        &sender_user,
+                        &sender_device,
+                        next_batch,
+                    )?,
                     next_batch: next_batch.to_string(),
                 })
             } else {
@@ -1712,8 +1741,11 @@ pub async fn sync_events_v4_route(
                 device_one_time_keys_count: services()
                     .users
                     .count_one_time_keys(&sender_user, &sender_device)?,
-                // Fallback keys are not yet supported
-                device_unused_fallback_key_types: None,
+                device_unused_fallback_key_types: Some(
+                    services()
+                        .users
+                        .unused_fallback_key_types(&sender_user, &sender_device)?,
+                ),
             },
             account_data: sync_events::v4::AccountData {
                 global: if body.extensions.account_data.enabled.unwrap_or(false) {
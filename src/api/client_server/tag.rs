@@ -1,10 +1,14 @@
 use crate::{services, Error, Result, Ruma};
 use ruma::{
-    api::client::tag::{create_tag, delete_tag, get_tags},
+    api::client::{
+        error::ErrorKind,
+        tag::{create_tag, delete_tag, get_tags},
+    },
     events::{
         tag::{TagEvent, TagEventContent},
         RoomAccountDataEventType,
     },
+    RoomId, UserId,
 };
 use std::collections::BTreeMap;
 
@@ -13,29 +17,31 @@ use std::collections::BTreeMap;
 /// Adds a tag to the room.
 ///
 /// - Inserts the tag into the tag event of the room account data.
+/// - The order has to be between 0 and 1
 pub async fn update_tag_route(
     body: Ruma<create_tag::v3::Request>,
 ) -> Result<create_tag::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let event = services().account_data.get(
-        Some(&body.room_id),
-        sender_user,
-        RoomAccountDataEventType::Tag,
-    )?;
-
-    let mut tags_event = event
-        .map(|e| {
-            serde_json::from_str(e.get())
-                .map_err(|_| Error::bad_database("Invalid account data event in db."))
-        })
-        .unwrap_or_else(|| {
-            Ok(TagEvent {
-                content: TagEventContent {
-                    tags: BTreeMap::new(),
-                },
-            })
-        })?;
+    if sender_user != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You cannot change the tags of another user.",
+        ));
+    }
+
+    if body
+        .tag_info
+        .order
+        .map_or(false, |order| !(0.0..=1.0).contains(&order))
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Tag order must be between 0 and 1.",
+        ));
+    }
+
+    let mut tags_event = tags_event(sender_user, &body.room_id)?;
 
     tags_event
         .content
@@ -62,24 +68,14 @@ pub async fn delete_tag_route(
 ) -> Result<delete_tag::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let event = services().account_data.get(
-        Some(&body.room_id),
-        sender_user,
-        RoomAccountDataEventType::Tag,
-    )?;
+    if sender_user != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You cannot change the tags of another user.",
+        ));
+    }
 
-    let mut tags_event = event
-        .map(|e| {
-            serde_json::from_str(e.get())
-                .map_err(|_| Error::bad_database("Invalid account data event in db."))
-        })
-        .unwrap_or_else(|| {
-            Ok(TagEvent {
-                content: TagEventContent {
-                    tags: BTreeMap::new(),
-                },
-            })
-        })?;
+    let mut tags_event = tags_event(sender_user, &body.room_id)?;
 
     tags_event.content.tags.remove(&body.tag.clone().into());
 
@@ -101,13 +97,24 @@ pub async fn delete_tag_route(
 pub async fn get_tags_route(body: Ruma<get_tags::v3::Request>) -> Result<get_tags::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let event = services().account_data.get(
-        Some(&body.room_id),
-        sender_user,
-        RoomAccountDataEventType::Tag,
-    )?;
+    if sender_user != &body.user_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You cannot see the tags of another user.",
+        ));
+    }
+
+    Ok(get_tags::v3::Response {
+        tags: tags_event(sender_user, &body.room_id)?.content.tags,
+    })
+}
 
-    let tags_event = event
+/// Loads the tag event of the room account data, which is empty if the user never tagged the
+/// room.
+fn tags_event(user_id: &UserId, room_id: &RoomId) -> Result<TagEvent> {
+    services()
+        .account_data
+        .get(Some(room_id), user_id, RoomAccountDataEventType::Tag)?
         .map(|e| {
             serde_json::from_str(e.get())
                 .map_err(|_| Error::bad_database("Invalid account data event in db."))
@@ -118,9 +125,5 @@ pub async fn get_tags_route(body: Ruma<get_tags::v3::Request>) -> Result<get_tag
                     tags: BTreeMap::new(),
                 },
             })
-        })?;
-
-    Ok(get_tags::v3::Response {
-        tags: tags_event.content.tags,
-    })
+        })
 }
@@ -22,7 +22,7 @@ pub async fn get_threads_route(
         u64::MAX
     };
 
-    let threads = services()
+    let mut threads = services()
         .rooms
         .threads
         .threads_until(sender_user, &body.room_id, from, &body.include)?
@@ -39,6 +39,13 @@ pub async fn get_threads_route(
 
     let next_batch = threads.last().map(|(count, _)| count.to_string());
 
+    for (_, pdu) in &mut threads {
+        services()
+            .rooms
+            .pdu_metadata
+            .add_bundled_aggregations(sender_user, pdu)?;
+    }
+
     Ok(get_threads::v1::Response {
         chunk: threads
             .into_iter()
@@ -12,6 +12,10 @@ use ruma::{
 /// # `PUT /_matrix/client/r0/sendToDevice/{eventType}/{txnId}`
 ///
 /// Send a to-device event to a set of client devices.
+///
+/// - Local messages are queued for each target device until it syncs past them
+/// - A device id of `*` sends the message to all devices of the user
+/// - Messages for remote users are sent with one EDU per server
 pub async fn send_event_to_device_route(
     body: Ruma<send_event_to_device::v3::Request>,
 ) -> Result<send_event_to_device::v3::Response> {
@@ -27,32 +31,19 @@ pub async fn send_event_to_device_route(
         return Ok(send_event_to_device::v3::Response {});
     }
 
-    for (target_user_id, map) in &body.messages {
-        for (target_device_id_maybe, event) in map {
-            if target_user_id.server_name() != services().globals.server_name() {
-                let mut map = BTreeMap::new();
-                map.insert(target_device_id_maybe.clone(), event.clone());
-                let mut messages = BTreeMap::new();
-                messages.insert(target_user_id.clone(), map);
-                let count = services().globals.next_count()?;
-
-                services().sending.send_reliable_edu(
-                    target_user_id.server_name(),
-                    serde_json::to_vec(&federation::transactions::edu::Edu::DirectToDevice(
-                        DirectDeviceContent {
-                            sender: sender_user.clone(),
-                            ev_type: body.event_type.clone(),
-                            message_id: count.to_string().into(),
-                            messages,
-                        },
-                    ))
-                    .expect("DirectToDevice EDU can be serialized"),
-                    count,
-                )?;
+    // Messages for remote users, one EDU per server
+    let mut remote_messages = BTreeMap::new();
 
-                continue;
-            }
+    for (target_user_id, map) in &body.messages {
+        if target_user_id.server_name() != services().globals.server_name() {
+            remote_messages
+                .entry(target_user_id.server_name())
+                .or_insert_with(BTreeMap::new)
+                .insert(target_user_id.clone(), map.clone());
+            continue;
+        }
 
+        for (target_device_id_maybe, event) in map {
             match target_device_id_maybe {
                 DeviceIdOrAllDevices::DeviceId(target_device_id) => {
                     services().users.add_to_device_event(
@@ -83,6 +74,24 @@ pub async fn send_event_to_device_route(
         }
     }
 
+    for (server, messages) in remote_messages {
+        let count = services().globals.next_count()?;
+
+        services().sending.send_reliable_edu(
+            server,
+            serde_json::to_vec(&federation::transactions::edu::Edu::DirectToDevice(
+                DirectDeviceContent {
+                    sender: sender_user.clone(),
+                    ev_type: body.event_type.clone(),
+                    message_id: count.to_string().into(),
+                    messages,
+                },
+            ))
+            .expect("DirectToDevice EDU can be serialized"),
+            count,
+        )?;
+    }
+
     // Save transaction id with empty data
     services()
         .transaction_ids
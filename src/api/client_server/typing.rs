@@ -4,6 +4,8 @@ use ruma::api::client::{error::ErrorKind, typing::create_typing_event};
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/typing/{userId}`
 ///
 /// Sets the typing state of the sender user.
+///
+/// - The timeout can't be longer than `max_typing_timeout_seconds`
 pub async fn create_typing_event_route(
     body: Ruma<create_typing_event::v3::Request>,
 ) -> Result<create_typing_event::v3::Response> {
@@ -23,6 +25,13 @@ pub async fn create_typing_event_route(
     }
 
     if let Typing::Yes(duration) = body.state {
+        if duration.as_secs() > services().globals.config.max_typing_timeout_seconds.into() {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Typing timeout is too long.",
+            ));
+        }
+
         services().rooms.edus.typing.typing_add(
             sender_user,
             &body.room_id,
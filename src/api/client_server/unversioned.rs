@@ -1,10 +1,57 @@
-use std::{collections::BTreeMap, iter::FromIterator};
+use std::collections::BTreeMap;
 
 use axum::{response::IntoResponse, Json};
-use ruma::api::client::{discovery::get_supported_versions, error::ErrorKind};
+use ruma::api::{
+    client::{
+        directory::get_public_rooms_filtered, discovery::get_supported_versions, error::ErrorKind,
+        keys::upload_signing_keys, receipt::create_receipt, relations::get_relating_events,
+        space::get_hierarchy, threads::get_threads,
+    },
+    IncomingRequest, Metadata,
+};
 
 use crate::{services, Error, Result, Ruma};
 
+/// Spec versions with the endpoints they introduced. A version is only advertised if all of its
+/// endpoints are in the router.
+const SPEC_VERSIONS: &[(&str, &[Metadata])] = &[
+    ("r0.5.0", &[]),
+    ("r0.6.0", &[]),
+    ("v1.1", &[upload_signing_keys::v3::Request::METADATA]),
+    ("v1.2", &[get_hierarchy::v1::Request::METADATA]),
+    ("v1.3", &[get_relating_events::v1::Request::METADATA]),
+    ("v1.4", &[get_threads::v1::Request::METADATA]),
+    ("v1.5", &[]),
+];
+
+/// Unstable features with the endpoints that implement them, advertised like the spec versions.
+const UNSTABLE_FEATURES: &[(&str, &[Metadata])] = &[
+    (
+        "org.matrix.e2e_cross_signing",
+        &[upload_signing_keys::v3::Request::METADATA],
+    ),
+    // Private read receipts
+    (
+        "org.matrix.msc2285.stable",
+        &[create_receipt::v3::Request::METADATA],
+    ),
+    // Spaces
+    (
+        "org.matrix.msc2946",
+        &[get_hierarchy::v1::Request::METADATA],
+    ),
+    // Threads
+    (
+        "org.matrix.msc3440.stable",
+        &[get_threads::v1::Request::METADATA],
+    ),
+    // Filtering public rooms by room type
+    (
+        "org.matrix.msc3827.stable",
+        &[get_public_rooms_filtered::v3::Request::METADATA],
+    ),
+];
+
 /// # `GET /_matrix/client/versions`
 ///
 /// Get the versions of the specification and unstable features supported by this server.
@@ -12,23 +59,29 @@ use crate::{services, Error, Result, Ruma};
 /// - Versions take the form MAJOR.MINOR.PATCH
 /// - Only the latest PATCH release will be reported for each MAJOR.MINOR value
 /// - Unstable features are namespaced and may include version information in their name
+/// - Both are derived from the endpoints the router serves
 ///
 /// Note: Unstable features are used while developing new features. Clients should avoid using
 /// unstable features in their stable releases
 pub async fn get_supported_versions_route(
     _body: Ruma<get_supported_versions::Request>,
 ) -> Result<get_supported_versions::Response> {
+    let served = |endpoints: &[Metadata]| {
+        endpoints
+            .iter()
+            .all(|endpoint| services().globals.serves(endpoint))
+    };
+
     let resp = get_supported_versions::Response {
-        versions: vec![
-            "r0.5.0".to_owned(),
-            "r0.6.0".to_owned(),
-            "v1.1".to_owned(),
-            "v1.2".to_owned(),
-            "v1.3".to_owned(),
-            "v1.4".to_owned(),
-            "v1.5".to_owned(),
-        ],
-        unstable_features: BTreeMap::from_iter([("org.matrix.e2e_cross_signing".to_owned(), true)]),
+        versions: SPEC_VERSIONS
+            .iter()
+            .filter(|(_, endpoints)| served(endpoints))
+            .map(|(version, _)| (*version).to_owned())
+            .collect(),
+        unstable_features: UNSTABLE_FEATURES
+            .iter()
+            .map(|(feature, endpoints)| ((*feature).to_owned(), served(endpoints)))
+            .collect::<BTreeMap<_, _>>(),
     };
 
     Ok(resp)
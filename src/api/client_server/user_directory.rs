@@ -5,90 +5,85 @@ use ruma::{
         room::join_rules::{JoinRule, RoomJoinRulesEventContent},
         StateEventType,
     },
+    UserId,
 };
 
 /// # `POST /_matrix/client/r0/user_directory/search`
 ///
 /// Searches all known users for a match.
 ///
+/// - Matches words of the search term against the start or the middle of words in the localpart
+/// or displayname
 /// - Hides any local users that aren't in any public rooms (i.e. those that have the join rule set to public)
 /// and don't share a room with the sender
+/// - Users sharing a room with the sender come first, then localpart matches before displayname
+/// matches and prefix matches before substring matches
 pub async fn search_users_route(
     body: Ruma<search_users::v3::Request>,
 ) -> Result<search_users::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let limit = u64::from(body.limit) as usize;
 
-    let mut users = services().users.iter().filter_map(|user_id| {
-        // Filter out buggy users (they should not exist, but you never know...)
-        let user_id = user_id.ok()?;
+    let mut users: Vec<_> = services()
+        .users
+        .search_directory(&body.search_term)?
+        .into_iter()
+        .filter_map(|(user_id, directory_match)| {
+            let user_is_in_shared_rooms = services()
+                .rooms
+                .user
+                .get_shared_rooms(vec![sender_user.clone(), user_id.clone()])
+                .ok()?
+                .next()
+                .is_some();
 
-        let user = search_users::v3::User {
-            user_id: user_id.clone(),
-            display_name: services().users.displayname(&user_id).ok()?,
-            avatar_url: services().users.avatar_url(&user_id).ok()?,
-        };
+            if !user_is_in_shared_rooms && !user_is_in_public_rooms(&user_id) {
+                return None;
+            }
 
-        let user_id_matches = user
-            .user_id
-            .to_string()
-            .to_lowercase()
-            .contains(&body.search_term.to_lowercase());
+            Some((!user_is_in_shared_rooms, directory_match, user_id))
+        })
+        .collect();
 
-        let user_displayname_matches = user
-            .display_name
-            .as_ref()
-            .filter(|name| {
-                name.to_lowercase()
-                    .contains(&body.search_term.to_lowercase())
-            })
-            .is_some();
-
-        if !user_id_matches && !user_displayname_matches {
-            return None;
-        }
-
-        let user_is_in_public_rooms = services()
-            .rooms
-            .state_cache
-            .rooms_joined(&user_id)
-            .filter_map(|r| r.ok())
-            .any(|room| {
-                services()
-                    .rooms
-                    .state_accessor
-                    .room_state_get(&room, &StateEventType::RoomJoinRules, "")
-                    .map_or(false, |event| {
-                        event.map_or(false, |event| {
-                            serde_json::from_str(event.content.get())
-                                .map_or(false, |r: RoomJoinRulesEventContent| {
-                                    r.join_rule == JoinRule::Public
-                                })
-                        })
-                    })
-            });
-
-        if user_is_in_public_rooms {
-            return Some(user);
-        }
-
-        let user_is_in_shared_rooms = services()
-            .rooms
-            .user
-            .get_shared_rooms(vec![sender_user.clone(), user_id])
-            .ok()?
-            .next()
-            .is_some();
+    // The directory already sorted by match and user id, keep that order for equal rooms
+    users.sort_by_key(|(not_shared, _, _)| *not_shared);
 
-        if user_is_in_shared_rooms {
-            return Some(user);
-        }
+    let limited = users.len() > limit;
 
-        None
-    });
-
-    let results = users.by_ref().take(limit).collect();
-    let limited = users.next().is_some();
+    let results = users
+        .into_iter()
+        .take(limit)
+        .filter_map(|(_, _, user_id)| {
+            // Filter out buggy users (they should not exist, but you never know...)
+            Some(search_users::v3::User {
+                display_name: services().users.displayname(&user_id).ok()?,
+                avatar_url: services().users.avatar_url(&user_id).ok()?,
+                user_id,
+            })
+        })
+        .collect();
 
     Ok(search_users::v3::Response { results, limited })
 }
+
+fn user_is_in_public_rooms(user_id: &UserId) -> bool {
+    services()
+        .rooms
+        .state_cache
+        .rooms_joined(user_id)
+        .filter_map(|r| r.ok())
+        .any(|room| {
+            services()
+                .rooms
+                .state_accessor
+                .room_state_get(&room, &StateEventType::RoomJoinRules, "")
+                .map_or(false, |event| {
+                    event.map_or(false, |event| {
+                        serde_json::from_str(event.content.get())
+                            .map_or(false, |r: RoomJoinRulesEventContent| {
+                                r.join_rule == JoinRule::Public
+                            })
+                    })
+                })
+        })
+}
@@ -1,18 +1,23 @@
-use std::{collections::BTreeMap, iter::FromIterator, str};
+use std::{
+    collections::BTreeMap,
+    iter::FromIterator,
+    net::{IpAddr, SocketAddr},
+    str,
+};
 
 use axum::{
     async_trait,
     body::{Full, HttpBody},
-    extract::{rejection::TypedHeaderRejectionReason, FromRequest, Path, TypedHeader},
+    extract::{rejection::TypedHeaderRejectionReason, ConnectInfo, FromRequest, Path, TypedHeader},
     headers::{
         authorization::{Bearer, Credentials},
         Authorization,
     },
     response::{IntoResponse, Response},
-    BoxError, RequestExt, RequestPartsExt,
+    BoxError, RequestPartsExt,
 };
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use http::{Request, StatusCode};
+use http::{header, request::Parts, Request, StatusCode};
 use ruma::{
     api::{client::error::ErrorKind, AuthScheme, IncomingRequest, OutgoingResponse},
     CanonicalJsonValue, OwnedDeviceId, OwnedServerName, UserId,
@@ -21,7 +26,7 @@ use serde::Deserialize;
 use tracing::{debug, error, warn};
 
 use super::{Ruma, RumaResponse};
-use crate::{services, Error, Result};
+use crate::{service::rate_limiter::Category, services, Error, Result};
 
 #[async_trait]
 impl<T, S, B> FromRequest<S, B> for Ruma<T>
@@ -38,24 +43,30 @@ where
         struct QueryParams {
             access_token: Option<String>,
             user_id: Option<String>,
+            #[serde(rename = "org.matrix.msc3202.device_id")]
+            device_id: Option<String>,
         }
 
-        let (mut parts, mut body) = match req.with_limited_body() {
-            Ok(limited_req) => {
-                let (parts, body) = limited_req.into_parts();
-                let body = to_bytes(body)
-                    .await
-                    .map_err(|_| Error::BadRequest(ErrorKind::MissingToken, "Missing token."))?;
-                (parts, body)
-            }
-            Err(original_req) => {
-                let (parts, body) = original_req.into_parts();
-                let body = to_bytes(body)
-                    .await
-                    .map_err(|_| Error::BadRequest(ErrorKind::MissingToken, "Missing token."))?;
-                (parts, body)
-            }
-        };
+        let (mut parts, body) = req.into_parts();
+        let limit = services()
+            .globals
+            .config
+            .body_limit
+            .limit(&parts.method, parts.uri.path()) as usize;
+
+        // Don't even start reading bodies that announce they are too big
+        if parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+            .map_or(false, |length| length > limit)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::TooLarge,
+                "Request body is too large.",
+            ));
+        }
+        let mut body = read_body(body, limit).await?;
 
         let metadata = T::METADATA;
         let auth_header: Option<TypedHeader<Authorization<Bearer>>> = parts.extract().await?;
@@ -80,32 +91,41 @@ where
 
         let mut json_body = serde_json::from_slice::<CanonicalJsonValue>(&body).ok();
 
-        let appservices = services().appservice.all().unwrap();
-        let appservice_registration = appservices.iter().find(|(_id, registration)| {
-            registration
-                .get("as_token")
-                .and_then(|as_token| as_token.as_str())
-                .map_or(false, |as_token| token == Some(as_token))
-        });
+        let appservice_info = token.and_then(|token| services().appservice.find_from_token(token));
 
         let (sender_user, sender_device, sender_servername, from_appservice) =
-            if let Some((_id, registration)) = appservice_registration {
+            if let Some(info) = &appservice_info {
                 match metadata.authentication {
                     AuthScheme::AccessToken => {
-                        let user_id = query_params.user_id.map_or_else(
-                            || {
-                                UserId::parse_with_server_name(
-                                    registration
-                                        .get("sender_localpart")
-                                        .unwrap()
-                                        .as_str()
-                                        .unwrap(),
-                                    services().globals.server_name(),
-                                )
-                                .unwrap()
-                            },
-                            |s| UserId::parse(s).unwrap(),
-                        );
+                        let user_id = match query_params.user_id {
+                            Some(user_id) => UserId::parse(user_id).map_err(|_| {
+                                Error::BadRequest(ErrorKind::InvalidUsername, "Invalid user_id.")
+                            })?,
+                            // Typing, receipts and the like are always about one of the users
+                            None if is_ephemeral_send(&parts.method, parts.uri.path()) => {
+                                return Err(Error::BadRequest(
+                                    ErrorKind::MissingParam,
+                                    "Appservices have to pass user_id for ephemeral events.",
+                                ));
+                            }
+                            None => UserId::parse_with_server_name(
+                                info.sender_localpart.as_str(),
+                                services().globals.server_name(),
+                            )
+                            .map_err(|_| {
+                                Error::bad_config("Appservice has an invalid sender_localpart.")
+                            })?,
+                        };
+
+                        // Appservices may only act as their own users
+                        if user_id.server_name() != services().globals.server_name()
+                            || !info.is_user_match(&user_id)
+                        {
+                            return Err(Error::BadRequest(
+                                ErrorKind::Forbidden,
+                                "User is not in namespace of the appservice.",
+                            ));
+                        }
 
                         if !services().users.exists(&user_id).unwrap() {
                             return Err(Error::BadRequest(
@@ -114,8 +134,27 @@ where
                             ));
                         }
 
-                        // TODO: Check if appservice is allowed to be that user
-                        (Some(user_id), None, None, true)
+                        // Appservices that manage the encryption of their users act as one of
+                        // their devices (MSC3202)
+                        let sender_device = match query_params.device_id {
+                            Some(device_id) if info.device_masquerading => {
+                                let device_id = OwnedDeviceId::from(device_id);
+                                if services()
+                                    .users
+                                    .get_device_metadata(&user_id, &device_id)?
+                                    .is_none()
+                                {
+                                    return Err(Error::BadRequest(
+                                        ErrorKind::Forbidden,
+                                        "Device does not exist for this user.",
+                                    ));
+                                }
+                                Some(device_id)
+                            }
+                            _ => None,
+                        };
+
+                        (Some(user_id), sender_device, None, true)
                     }
                     AuthScheme::ServerSignatures => (None, None, None, true),
                     AuthScheme::None => (None, None, None, true),
@@ -140,12 +179,34 @@ where
                                     "Unknown access token.",
                                 ))
                             }
-                            Some((user_id, device_id)) => (
-                                Some(user_id),
-                                Some(OwnedDeviceId::from(device_id)),
-                                None,
-                                false,
-                            ),
+                            Some((user_id, device_id)) => {
+                                let device_id = OwnedDeviceId::from(device_id);
+
+                                if services().users.is_soft_logged_out(&user_id, &device_id)? {
+                                    return Err(Error::BadRequest(
+                                        ErrorKind::UnknownToken { soft_logout: true },
+                                        "Access token was soft logged out, please log in again.",
+                                    ));
+                                }
+
+                                services()
+                                    .users
+                                    .update_device_last_seen(
+                                        &user_id,
+                                        &device_id,
+                                        client_ip(&parts),
+                                        parts
+                                            .headers
+                                            .get(header::USER_AGENT)
+                                            .and_then(|ua| ua.to_str().ok())
+                                            .map(ToOwned::to_owned),
+                                    )
+                                    .unwrap_or_else(|e| {
+                                        warn!("Failed to update last seen of device: {e}")
+                                    });
+
+                                (Some(user_id), Some(device_id), None, false)
+                            }
                         }
                     }
                     AuthScheme::ServerSignatures => {
@@ -168,6 +229,13 @@ where
                                 Error::BadRequest(ErrorKind::Forbidden, msg)
                             })?;
 
+                        if !services().globals.is_federation_allowed(&x_matrix.origin) {
+                            return Err(Error::BadRequest(
+                                ErrorKind::Forbidden,
+                                "Federation with this server is not allowed.",
+                            ));
+                        }
+
                         let origin_signatures = BTreeMap::from_iter([(
                             x_matrix.key.clone(),
                             CanonicalJsonValue::String(x_matrix.sig),
@@ -254,6 +322,25 @@ where
                 }
             };
 
+        let path = parts.uri.path();
+        // Appservices and admins are trusted not to flood the server
+        if (path.starts_with("/_matrix/client/") || path.starts_with("/_matrix/media/"))
+            && !from_appservice
+        {
+            let requester = match &sender_user {
+                Some(user_id) if services().users.is_admin(user_id).unwrap_or(false) => None,
+                Some(user_id) => Some(user_id.to_string()),
+                None => client_ip(&parts),
+            };
+
+            if let Some(requester) = requester {
+                services().rate_limiter.check(
+                    requester,
+                    Category::of(&parts.method, path, parts.uri.query()),
+                )?;
+            }
+        }
+
         let mut http_request = http::Request::builder().uri(parts.uri).method(parts.method);
         *http_request.headers_mut().unwrap() = parts.headers;
 
@@ -303,11 +390,56 @@ where
             sender_device,
             sender_servername,
             from_appservice,
+            appservice_info,
             json_body,
         })
     }
 }
 
+/// Whether the request sends typing notifications, receipts, read markers, presence or to-device
+/// events.
+fn is_ephemeral_send(method: &http::Method, path: &str) -> bool {
+    match *method {
+        http::Method::PUT => {
+            path.contains("/typing/")
+                || path.contains("/sendToDevice/")
+                || (path.contains("/presence/") && path.ends_with("/status"))
+        }
+        http::Method::POST => path.contains("/receipt/") || path.ends_with("/read_markers"),
+        _ => false,
+    }
+}
+
+/// The address of the client, as seen by a reverse proxy in front of Conduit if there is one.
+///
+/// A proxy appends the address it received the request from to `X-Forwarded-For`, so only the
+/// last entry is used. Earlier entries are sent by the client and can be made up, and so can the
+/// whole header if the request doesn't come from one of the `trusted_proxies`.
+fn client_ip(parts: &Parts) -> Option<String> {
+    let peer =
+        parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| match addr.ip() {
+                // Listeners on [::] see IPv4 clients as mapped addresses
+                IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+                ip => ip,
+            })?;
+
+    if !services().globals.trusted_proxies().contains(&peer) {
+        return Some(peer.to_string());
+    }
+
+    parts
+        .headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .map(|ip| ip.trim().to_owned())
+        .filter(|ip| !ip.is_empty())
+        .or_else(|| Some(peer.to_string()))
+}
+
 struct XMatrix {
     origin: OwnedServerName,
     key: String, // KeyName?
@@ -374,54 +506,27 @@ impl<T: OutgoingResponse> IntoResponse for RumaResponse<T> {
     }
 }
 
-// copied from hyper under the following license:
-// Copyright (c) 2014-2021 Sean McArthur
-
-// Permission is hereby granted, free of charge, to any person obtaining a copy
-// of this software and associated documentation files (the "Software"), to deal
-// in the Software without restriction, including without limitation the rights
-// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
-// copies of the Software, and to permit persons to whom the Software is
-// furnished to do so, subject to the following conditions:
-
-// The above copyright notice and this permission notice shall be included in
-// all copies or substantial portions of the Software.
-
-// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
-// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
-// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
-// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
-// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
-// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
-// THE SOFTWARE.
-pub(crate) async fn to_bytes<T>(body: T) -> Result<Bytes, T::Error>
+/// Reads the whole body, but stops with `M_TOO_LARGE` as soon as it gets bigger than the limit.
+async fn read_body<B>(body: B, limit: usize) -> Result<Bytes>
 where
-    T: HttpBody,
+    B: HttpBody,
 {
     futures_util::pin_mut!(body);
 
-    // If there's only 1 chunk, we can just return Buf::to_bytes()
-    let mut first = if let Some(buf) = body.data().await {
-        buf?
-    } else {
-        return Ok(Bytes::new());
-    };
-
-    let second = if let Some(buf) = body.data().await {
-        buf?
-    } else {
-        return Ok(first.copy_to_bytes(first.remaining()));
-    };
-
-    // With more than 1 buf, we gotta flatten into a Vec first.
-    let cap = first.remaining() + second.remaining() + body.size_hint().lower() as usize;
-    let mut vec = Vec::with_capacity(cap);
-    vec.put(first);
-    vec.put(second);
-
-    while let Some(buf) = body.data().await {
-        vec.put(buf?);
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| {
+            Error::BadRequest(ErrorKind::Unknown, "Failed to read the request body.")
+        })?;
+
+        if bytes.len() + chunk.remaining() > limit {
+            return Err(Error::BadRequest(
+                ErrorKind::TooLarge,
+                "Request body is too large.",
+            ));
+        }
+        bytes.put(chunk);
     }
 
-    Ok(vec.into())
+    Ok(bytes.freeze())
 }
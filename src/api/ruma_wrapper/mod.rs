@@ -1,9 +1,9 @@
-use crate::Error;
+use crate::{service::appservice::RegistrationInfo, Error};
 use ruma::{
     api::client::uiaa::UiaaResponse, CanonicalJsonValue, OwnedDeviceId, OwnedServerName,
     OwnedUserId,
 };
-use std::ops::Deref;
+use std::{ops::Deref, sync::Arc};
 
 #[cfg(feature = "conduit_bin")]
 mod axum;
@@ -17,6 +17,8 @@ pub struct Ruma<T> {
     // This is None when body is not a valid string
     pub json_body: Option<CanonicalJsonValue>,
     pub from_appservice: bool,
+    /// The registration of the appservice that sent the request
+    pub appservice_info: Option<Arc<RegistrationInfo>>,
 }
 
 impl<T> Deref for Ruma<T> {
@@ -3,7 +3,7 @@
 use crate::{
     api::client_server::{self, claim_keys_helper, get_keys_helper},
     service::pdu::{gen_event_id_canonical_json, PduBuilder},
-    services, utils, Error, PduEvent, Result, Ruma,
+    services, utils, Config, Error, PduEvent, Result, Ruma,
 };
 use axum::{response::IntoResponse, Json};
 use get_profile_information::v1::ProfileField;
@@ -21,7 +21,9 @@ use ruma::{
             event::{get_event, get_missing_events, get_room_state, get_room_state_ids},
             keys::{claim_keys, get_keys},
             membership::{create_invite, create_join_event, prepare_join_event},
+            openid::get_openid_userinfo,
             query::{get_profile_information, get_room_information},
+            thirdparty::{bind_callback, exchange_invite},
             transactions::{
                 edu::{DeviceListUpdateContent, DirectDeviceContent, Edu, SigningKeyUpdateContent},
                 send_transaction_message,
@@ -33,17 +35,14 @@ use ruma::{
     directory::{Filter, RoomNetwork},
     events::{
         receipt::{ReceiptEvent, ReceiptEventContent, ReceiptType},
-        room::{
-            join_rules::{JoinRule, RoomJoinRulesEventContent},
-            member::{MembershipState, RoomMemberEventContent},
-        },
+        room::member::{MembershipState, RoomMemberEventContent, ThirdPartyInvite},
         StateEventType, TimelineEventType,
     },
     serde::{Base64, JsonObject, Raw},
     to_device::DeviceIdOrAllDevices,
     uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
     OwnedEventId, OwnedRoomId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId, RoomId,
-    ServerName,
+    ServerName, UserId,
 };
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use std::{
@@ -51,12 +50,27 @@ use std::{
     fmt::Debug,
     mem,
     net::{IpAddr, SocketAddr},
-    sync::{Arc, RwLock},
+    sync::{atomic::Ordering, Arc, RwLock},
     time::{Duration, Instant, SystemTime},
 };
 
 use tracing::{debug, error, warn};
 
+/// How long users of other servers are typing after their last typing EDU in millis
+const REMOTE_TYPING_TIMEOUT: u64 = 30 * 1000;
+
+/// How long a resolved destination is cached if the .well-known response has no max-age.
+const WELL_KNOWN_DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Bounds for the max-age of .well-known responses. Destinations that don't need a .well-known
+/// lookup are cached for the maximum.
+const WELL_KNOWN_MIN_TTL: Duration = Duration::from_secs(5 * 60);
+const WELL_KNOWN_MAX_TTL: Duration = Duration::from_secs(48 * 60 * 60);
+/// How long we use the fallback without delegation if the server has no valid .well-known.
+const WELL_KNOWN_INVALID_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long we use the fallback without delegation if the .well-known request failed, so an
+/// unreachable server is not resolved again for every request.
+const WELL_KNOWN_FAILED_TTL: Duration = Duration::from_secs(2 * 60);
+
 /// Wraps either an literal IP address plus port, or a hostname plus complement
 /// (colon-plus-port if it was specified).
 ///
@@ -111,6 +125,51 @@ impl FedDest {
     }
 }
 
+/// Requests to other servers that get their own timeout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RequestKind {
+    Transaction,
+    Join,
+    Media,
+    KeyQuery,
+    Other,
+}
+
+impl RequestKind {
+    fn from_path(path: &str) -> Self {
+        if path.starts_with("/_matrix/federation/v1/send/") {
+            Self::Transaction
+        } else if ["/make_join/", "/send_join/", "/make_knock/", "/send_knock/"]
+            .iter()
+            .any(|segment| path.contains(segment))
+        {
+            Self::Join
+        } else if path.starts_with("/_matrix/media/")
+            || path.starts_with("/_matrix/federation/v1/media/")
+        {
+            Self::Media
+        } else if path.starts_with("/_matrix/key/")
+            || path.starts_with("/_matrix/federation/v1/user/keys/")
+        {
+            Self::KeyQuery
+        } else {
+            Self::Other
+        }
+    }
+
+    fn timeout(self, config: &Config) -> Duration {
+        let seconds = match self {
+            Self::Transaction => config.federation_transaction_timeout_seconds,
+            Self::Join => config.federation_join_timeout_seconds,
+            Self::Media => config.federation_media_timeout_seconds,
+            Self::KeyQuery => config.federation_key_query_timeout_seconds,
+            Self::Other => config.federation_timeout_seconds,
+        };
+
+        Duration::from_secs(seconds.into())
+    }
+}
+
 #[tracing::instrument(skip(request))]
 pub(crate) async fn send_request<T: OutgoingRequest>(
     destination: &ServerName,
@@ -129,9 +188,14 @@ where
         ));
     }
 
-    debug!("Preparing to send request to {destination}");
+    if !services().globals.is_federation_allowed(destination) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Federation with this server is not allowed.",
+        ));
+    }
 
-    let mut write_destination_to_cache = false;
+    debug!("Preparing to send request to {destination}");
 
     let cached_result = services()
         .globals
@@ -139,16 +203,30 @@ where
         .read()
         .unwrap()
         .get(destination)
+        .filter(|(_, _, valid_until)| *valid_until > Instant::now())
         .cloned();
 
-    let (actual_destination, host) = if let Some(result) = cached_result {
-        result
+    let (actual_destination, host) = if let Some((actual_destination, host, _)) = cached_result {
+        (actual_destination, host)
     } else {
-        write_destination_to_cache = true;
+        let (actual_destination, host, ttl) = find_actual_destination(destination).await;
+        let host = host.into_uri_string();
 
-        let result = find_actual_destination(destination).await;
+        services()
+            .globals
+            .actual_destination_cache
+            .write()
+            .unwrap()
+            .insert(
+                OwnedServerName::from(destination),
+                (
+                    actual_destination.clone(),
+                    host.clone(),
+                    Instant::now() + ttl,
+                ),
+            );
 
-        (result.0, result.1.into_uri_string())
+        (actual_destination, host)
     };
 
     let actual_destination_str = actual_destination.clone().into_https_string();
@@ -232,12 +310,20 @@ where
         }
     }
 
-    let reqwest_request = reqwest::Request::try_from(http_request)
+    let mut reqwest_request = reqwest::Request::try_from(http_request)
         .expect("all http requests are valid reqwest requests");
+    *reqwest_request.timeout_mut() = Some(
+        RequestKind::from_path(reqwest_request.url().path()).timeout(&services().globals.config),
+    );
 
     let url = reqwest_request.url().clone();
 
     debug!("Sending request to {destination} at {url}");
+    services()
+        .globals
+        .federation_pool_stats
+        .requests
+        .fetch_add(1, Ordering::Relaxed);
     let response = services()
         .globals
         .federation_client()
@@ -285,17 +371,6 @@ where
             if status == 200 {
                 debug!("Parsing response bytes from {destination}");
                 let response = T::IncomingResponse::try_from_http_response(http_response);
-                if response.is_ok() && write_destination_to_cache {
-                    services()
-                        .globals
-                        .actual_destination_cache
-                        .write()
-                        .unwrap()
-                        .insert(
-                            OwnedServerName::from(destination),
-                            (actual_destination, host),
-                        );
-                }
 
                 response.map_err(|e| {
                     warn!(
@@ -317,6 +392,11 @@ where
                 "Could not send request to {} at {}: {}",
                 destination, actual_destination_str, e
             );
+            if e.is_timeout() {
+                return Err(Error::BadServerResponse(
+                    "Timeout waiting for server response",
+                ));
+            }
             Err(e.into())
         }
     }
@@ -340,13 +420,15 @@ fn add_port_to_hostname(destination_str: &str) -> FedDest {
     FedDest::Named(host.to_owned(), port.to_owned())
 }
 
-/// Returns: actual_destination, host header
+/// Returns: actual_destination, host header, how long the result may be cached
 /// Implemented according to the specification at <https://matrix.org/docs/spec/server_server/r0.1.4#resolving-server-names>
 /// Numbers in comments below refer to bullet points in linked section of specification
-async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDest) {
+async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDest, Duration) {
     debug!("Finding actual destination for {destination}");
     let destination_str = destination.as_str().to_owned();
     let mut hostname = destination_str.clone();
+    // SRV records are cached along with the rest of the result
+    let mut ttl = WELL_KNOWN_MAX_TTL;
     let actual_destination = match get_ip_with_port(&destination_str) {
         Some(host_port) => {
             debug!("1: IP literal with provided or default port");
@@ -359,7 +441,10 @@ async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDe
                 FedDest::Named(host.to_owned(), port.to_owned())
             } else {
                 debug!("Requesting well known for {destination}");
-                match request_well_known(destination.as_str()).await {
+                let (delegated_hostname, well_known_ttl) =
+                    request_well_known(destination.as_str()).await;
+                ttl = well_known_ttl;
+                match delegated_hostname {
                     Some(delegated_hostname) => {
                         debug!("3: A .well-known file is available");
                         hostname = add_port_to_hostname(&delegated_hostname).into_uri_string();
@@ -472,7 +557,7 @@ async fn find_actual_destination(destination: &'_ ServerName) -> (FedDest, FedDe
     } else {
         FedDest::Named(hostname, ":8448".to_owned())
     };
-    (actual_destination, hostname)
+    (actual_destination, hostname, ttl)
 }
 
 async fn query_srv_record(hostname: &'_ str) -> Option<FedDest> {
@@ -497,22 +582,62 @@ async fn query_srv_record(hostname: &'_ str) -> Option<FedDest> {
     }
 }
 
-async fn request_well_known(destination: &str) -> Option<String> {
-    let response = services()
+/// Returns the delegated server name and how long the answer may be cached.
+async fn request_well_known(destination: &str) -> (Option<String>, Duration) {
+    let response = match services()
         .globals
         .default_client()
         .get(&format!("https://{destination}/.well-known/matrix/server"))
         .send()
-        .await;
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            debug!("Well known error: {e:?}");
+            return (None, WELL_KNOWN_FAILED_TTL);
+        }
+    };
     debug!("Got well known response");
-    if let Err(e) = &response {
-        debug!("Well known error: {e:?}");
-        return None;
-    }
-    let text = response.ok()?.text().await;
+
+    let ttl = well_known_ttl(response.headers());
+    let Ok(text) = response.text().await else {
+        return (None, WELL_KNOWN_FAILED_TTL);
+    };
     debug!("Got well known response text");
-    let body: serde_json::Value = serde_json::from_str(&text.ok()?).ok()?;
-    Some(body.get("m.server")?.as_str()?.to_owned())
+
+    let delegated_hostname = serde_json::from_str::<serde_json::Value>(&text)
+        .ok()
+        .and_then(|body| Some(body.get("m.server")?.as_str()?.to_owned()));
+
+    match delegated_hostname {
+        Some(delegated_hostname) => (Some(delegated_hostname), ttl),
+        None => (None, WELL_KNOWN_INVALID_TTL),
+    }
+}
+
+/// The max-age of the Cache-Control header, within sane bounds.
+fn well_known_ttl(headers: &http::HeaderMap) -> Duration {
+    headers
+        .get(http::header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|cache_control| {
+            cache_control.split(',').find_map(|directive| {
+                let directive = directive.trim();
+                if directive.eq_ignore_ascii_case("no-cache")
+                    || directive.eq_ignore_ascii_case("no-store")
+                {
+                    return Some(Duration::ZERO);
+                }
+
+                directive
+                    .strip_prefix("max-age=")?
+                    .parse()
+                    .ok()
+                    .map(Duration::from_secs)
+            })
+        })
+        .unwrap_or(WELL_KNOWN_DEFAULT_TTL)
+        .clamp(WELL_KNOWN_MIN_TTL, WELL_KNOWN_MAX_TTL)
 }
 
 /// # `GET /_matrix/federation/v1/version`
@@ -682,6 +807,9 @@ pub fn parse_incoming_pdu(
 /// # `PUT /_matrix/federation/v1/send/{txnId}`
 ///
 /// Push EDUs and PDUs to this server.
+///
+/// - PDUs that were already handled successfully are skipped and reported as successes
+/// - EDUs are always handled, they have no event id to recognize them by
 pub async fn send_transaction_message_route(
     body: Ruma<send_transaction_message::v1::Request>,
 ) -> Result<send_transaction_message::v1::Response> {
@@ -735,6 +863,44 @@ pub async fn send_transaction_message_route(
         };
         // We do not add the event_id field to the pdu here because of signature and hashes checks
 
+        // Events in blocked rooms are dropped without storing them. Events of blocked users are
+        // kept, the room state would differ from the other servers in the room otherwise.
+        if services().rooms.metadata.is_blocked(&room_id)? {
+            debug!("Dropping event {event_id} of a blocked room");
+            resolved_map.insert(
+                event_id,
+                Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "The room is blocked on this server.",
+                )),
+            );
+            continue;
+        }
+
+        let sender = value
+            .get("sender")
+            .and_then(|sender| UserId::parse(sender.as_str()?).ok());
+
+        // Servers denied by the room ACL can't send events through other servers either. The
+        // origin is checked when the event is handled.
+        if let Some(sender) = &sender {
+            if let Err(e) = services()
+                .rooms
+                .event_handler
+                .acl_check(sender.server_name(), &room_id)
+            {
+                resolved_map.insert(event_id, Err(e));
+                continue;
+            }
+        }
+
+        // Retransmitted transactions contain events we already handled
+        if services().rooms.event_handler.was_handled(&event_id) {
+            debug!("Skipping already handled event {event_id}");
+            resolved_map.insert(event_id, Ok(()));
+            continue;
+        }
+
         let mutex = Arc::clone(
             services()
                 .globals
@@ -746,23 +912,29 @@ pub async fn send_transaction_message_route(
         );
         let mutex_lock = mutex.lock().await;
         let start_time = Instant::now();
-        resolved_map.insert(
-            event_id.clone(),
+        let result = services()
+            .rooms
+            .event_handler
+            .handle_incoming_pdu(
+                sender_servername,
+                &event_id,
+                &room_id,
+                value,
+                true,
+                &pub_key_map,
+            )
+            .await
+            .map(|_| ());
+        drop(mutex_lock);
+
+        // Failures may be temporary, so only successes are remembered
+        if result.is_ok() {
             services()
                 .rooms
                 .event_handler
-                .handle_incoming_pdu(
-                    sender_servername,
-                    &event_id,
-                    &room_id,
-                    value,
-                    true,
-                    &pub_key_map,
-                )
-                .await
-                .map(|_| ()),
-        );
-        drop(mutex_lock);
+                .mark_handled(event_id.clone());
+        }
+        resolved_map.insert(event_id.clone(), result);
 
         let elapsed = start_time.elapsed();
         debug!(
@@ -787,10 +959,48 @@ pub async fn send_transaction_message_route(
         .filter_map(|edu| serde_json::from_str::<Edu>(edu.json().get()).ok())
     {
         match edu {
-            Edu::Presence(_) => {}
+            Edu::Presence(presence) => {
+                if !services().globals.allow_presence() {
+                    continue;
+                }
+
+                for update in presence.push {
+                    // Servers can only send the presence of their own users
+                    if update.user_id.server_name() != sender_servername
+                        || services().users.is_blocked(&update.user_id)?
+                    {
+                        continue;
+                    }
+
+                    services().rooms.edus.presence.set_remote_presence(
+                        &update.user_id,
+                        update.presence,
+                        update.status_msg,
+                        update.last_active_ago,
+                        update.currently_active,
+                    )?;
+                }
+            }
             Edu::Receipt(receipt) => {
                 for (room_id, room_updates) in receipt.receipts {
+                    if services().rooms.metadata.is_blocked(&room_id)?
+                        || services()
+                            .rooms
+                            .event_handler
+                            .acl_check(sender_servername, &room_id)
+                            .is_err()
+                    {
+                        continue;
+                    }
+
                     for (user_id, user_updates) in room_updates.read {
+                        // Servers can only send the receipts of their own users
+                        if user_id.server_name() != sender_servername
+                            || services().users.is_blocked(&user_id)?
+                        {
+                            continue;
+                        }
+
                         if let Some((event_id, _)) = user_updates
                             .event_ids
                             .iter()
@@ -831,16 +1041,28 @@ pub async fn send_transaction_message_route(
                 }
             }
             Edu::Typing(typing) => {
+                if typing.user_id.server_name() != sender_servername
+                    || services().users.is_blocked(&typing.user_id)?
+                    || services()
+                        .rooms
+                        .event_handler
+                        .acl_check(sender_servername, &typing.room_id)
+                        .is_err()
+                {
+                    continue;
+                }
+
                 if services()
                     .rooms
                     .state_cache
                     .is_joined(&typing.user_id, &typing.room_id)?
                 {
                     if typing.typing {
+                        // The other server sends the update again if the user keeps typing
                         services().rooms.edus.typing.typing_add(
                             &typing.user_id,
                             &typing.room_id,
-                            3000 + utils::millis_since_unix_epoch(),
+                            REMOTE_TYPING_TIMEOUT + utils::millis_since_unix_epoch(),
                         )?;
                     } else {
                         services()
@@ -860,6 +1082,12 @@ pub async fn send_transaction_message_route(
                 message_id,
                 messages,
             }) => {
+                if sender.server_name() != sender_servername
+                    || services().users.is_blocked(&sender)?
+                {
+                    continue;
+                }
+
                 // Check if this is a new transaction id
                 if services()
                     .transaction_ids
@@ -923,14 +1151,18 @@ pub async fn send_transaction_message_route(
                 if user_id.server_name() != sender_servername {
                     continue;
                 }
-                if let Some(master_key) = master_key {
-                    services().users.add_cross_signing_keys(
-                        &user_id,
-                        &master_key,
-                        &self_signing_key,
-                        &None,
-                        true,
-                    )?;
+                if master_key.is_none() && self_signing_key.is_none() {
+                    continue;
+                }
+                // Invalid keys of one user shouldn't fail the whole transaction
+                if let Err(e) = services().users.add_cross_signing_keys(
+                    &user_id,
+                    &master_key,
+                    &self_signing_key,
+                    &None,
+                    true,
+                ) {
+                    warn!("Invalid signing key update for {}: {}", user_id, e);
                 }
             }
             Edu::_Custom(_) => {}
@@ -949,7 +1181,8 @@ pub async fn send_transaction_message_route(
 ///
 /// Retrieves a single event from the server.
 ///
-/// - Only works if a user of this server is currently invited or joined the room
+/// - Only works if the sender is in the room and not denied by the server ACL
+/// - The history visibility at the event decides whether the sender may see it
 pub async fn get_event_route(
     body: Ruma<get_event::v1::Request>,
 ) -> Result<get_event::v1::Response> {
@@ -990,6 +1223,11 @@ pub async fn get_event_route(
         ));
     }
 
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, room_id)?;
+
     if !services().rooms.state_accessor.server_can_see_event(
         sender_servername,
         room_id,
@@ -1236,9 +1474,55 @@ pub async fn get_event_authorization_route(
     })
 }
 
+/// Returns the ids of the state events at the event, checking that the event is in the room and
+/// the server is allowed to see it.
+async fn state_ids_at_event(
+    sender_servername: &ServerName,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> Result<Vec<Arc<EventId>>> {
+    // The state of events in other rooms must not leak
+    if services()
+        .rooms
+        .timeline
+        .get_pdu(event_id)?
+        .map_or(true, |pdu| pdu.room_id != room_id)
+    {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
+    if !services().rooms.state_accessor.server_can_see_event(
+        sender_servername,
+        room_id,
+        event_id,
+    )? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Server is not allowed to see event.",
+        ));
+    }
+
+    let shortstatehash = services()
+        .rooms
+        .state_accessor
+        .pdu_shortstatehash(event_id)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Pdu state not found.",
+        ))?;
+
+    Ok(services()
+        .rooms
+        .state_accessor
+        .state_full_ids(shortstatehash)
+        .await?
+        .into_values()
+        .collect())
+}
+
 /// # `GET /_matrix/federation/v1/state/{roomId}`
 ///
-/// Retrieves the current state of the room.
+/// Retrieves the state of the room at an event and the auth chain of that state.
 pub async fn get_room_state_route(
     body: Ruma<get_room_state::v1::Request>,
 ) -> Result<get_room_state::v1::Response> {
@@ -1267,50 +1551,31 @@ pub async fn get_room_state_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
-    let shortstatehash = services()
-        .rooms
-        .state_accessor
-        .pdu_shortstatehash(&body.event_id)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "Pdu state not found.",
-        ))?;
+    let state_ids = state_ids_at_event(sender_servername, &body.room_id, &body.event_id).await?;
 
-    let pdus = services()
-        .rooms
-        .state_accessor
-        .state_full_ids(shortstatehash)
-        .await?
-        .into_values()
-        .map(|id| {
-            PduEvent::convert_to_outgoing_federation_event(
-                services()
-                    .rooms
-                    .timeline
-                    .get_pdu_json(&id)
-                    .unwrap()
-                    .unwrap(),
-            )
-        })
+    let outgoing_event = |id: &EventId| match services().rooms.timeline.get_pdu_json(id).ok()? {
+        Some(json) => Some(PduEvent::convert_to_outgoing_federation_event(json)),
+        None => {
+            error!("Could not find event json for {id} in db.");
+            None
+        }
+    };
+
+    let pdus = state_ids
+        .iter()
+        .filter_map(|id| outgoing_event(id))
         .collect();
 
+    // The auth chain of the whole state, so the other server can authorize every state event
     let auth_chain_ids = services()
         .rooms
         .auth_chain
-        .get_auth_chain(&body.room_id, vec![Arc::from(&*body.event_id)])
+        .get_auth_chain(&body.room_id, state_ids)
         .await?;
 
     Ok(get_room_state::v1::Response {
         auth_chain: auth_chain_ids
-            .filter_map(
-                |id| match services().rooms.timeline.get_pdu_json(&id).ok()? {
-                    Some(json) => Some(PduEvent::convert_to_outgoing_federation_event(json)),
-                    None => {
-                        error!("Could not find event json for {id} in db.");
-                        None
-                    }
-                },
-            )
+            .filter_map(|id| outgoing_event(&id))
             .collect(),
         pdus,
     })
@@ -1318,7 +1583,7 @@ pub async fn get_room_state_route(
 
 /// # `GET /_matrix/federation/v1/state_ids/{roomId}`
 ///
-/// Retrieves the current state of the room.
+/// Retrieves the ids of the state of the room at an event and of the auth chain of that state.
 pub async fn get_room_state_ids_route(
     body: Ruma<get_room_state_ids::v1::Request>,
 ) -> Result<get_room_state_ids::v1::Response> {
@@ -1347,28 +1612,13 @@ pub async fn get_room_state_ids_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
-    let shortstatehash = services()
-        .rooms
-        .state_accessor
-        .pdu_shortstatehash(&body.event_id)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "Pdu state not found.",
-        ))?;
-
-    let pdu_ids = services()
-        .rooms
-        .state_accessor
-        .state_full_ids(shortstatehash)
-        .await?
-        .into_values()
-        .map(|id| (*id).to_owned())
-        .collect();
+    let state_ids = state_ids_at_event(sender_servername, &body.room_id, &body.event_id).await?;
+    let pdu_ids = state_ids.iter().map(|id| (**id).to_owned()).collect();
 
     let auth_chain_ids = services()
         .rooms
         .auth_chain
-        .get_auth_chain(&body.room_id, vec![Arc::from(&*body.event_id)])
+        .get_auth_chain(&body.room_id, state_ids)
         .await?;
 
     Ok(get_room_state_ids::v1::Response {
@@ -1404,6 +1654,20 @@ pub async fn create_join_event_template_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
+    if services().rooms.metadata.is_blocked(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is blocked on this server.",
+        ));
+    }
+
+    if services().users.is_blocked(&body.user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The user is blocked on this server.",
+        ));
+    }
+
     let mutex_state = Arc::clone(
         services()
             .globals
@@ -1415,34 +1679,42 @@ pub async fn create_join_event_template_route(
     );
     let state_lock = mutex_state.lock().await;
 
-    // TODO: Conduit does not implement restricted join rules yet, we always reject
-    let join_rules_event = services().rooms.state_accessor.room_state_get(
-        &body.room_id,
-        &StateEventType::RoomJoinRules,
-        "",
-    )?;
-
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-        .as_ref()
-        .map(|join_rules_event| {
-            serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                warn!("Invalid join rules event: {}", e);
-                Error::bad_database("Invalid join rules event in db.")
-            })
-        })
-        .transpose()?;
+    let join_authorized_via_users_server = match services()
+        .rooms
+        .state_accessor
+        .join_restriction_rooms(&body.room_id)?
+    {
+        // Invited users and members don't need to be authorised
+        Some(restriction_rooms)
+            if !services()
+                .rooms
+                .state_cache
+                .is_joined(&body.user_id, &body.room_id)?
+                && !services()
+                    .rooms
+                    .state_cache
+                    .is_invited(&body.user_id, &body.room_id)? =>
+        {
+            if !user_in_restriction_room(&body.user_id, &restriction_rooms) {
+                return Err(Error::BadRequest(
+                    ErrorKind::UnableToAuthorizeJoin,
+                    "The user is not in any of the rooms that allow joining.",
+                ));
+            }
 
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. } | JoinRule::KnockRestricted { .. }
-        ) {
-            return Err(Error::BadRequest(
-                ErrorKind::UnableToAuthorizeJoin,
-                "Conduit does not support restricted rooms yet.",
-            ));
+            Some(
+                services()
+                    .rooms
+                    .state_accessor
+                    .restricted_join_authorizer(&body.room_id)?
+                    .ok_or(Error::BadRequest(
+                        ErrorKind::UnableToGrantJoin,
+                        "No user on this server can authorise the join.",
+                    ))?,
+            )
         }
-    }
+        _ => None,
+    };
 
     let room_version_id = services().rooms.state.get_room_version(&body.room_id)?;
     if !body.ver.contains(&room_version_id) {
@@ -1462,7 +1734,7 @@ pub async fn create_join_event_template_route(
         membership: MembershipState::Join,
         third_party_invite: None,
         reason: None,
-        join_authorized_via_users_server: None,
+        join_authorized_via_users_server,
     })
     .expect("member event is valid value");
 
@@ -1510,33 +1782,11 @@ async fn create_join_event(
         .event_handler
         .acl_check(sender_servername, room_id)?;
 
-    // TODO: Conduit does not implement restricted join rules yet, we always reject
-    let join_rules_event = services().rooms.state_accessor.room_state_get(
-        room_id,
-        &StateEventType::RoomJoinRules,
-        "",
-    )?;
-
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-        .as_ref()
-        .map(|join_rules_event| {
-            serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                warn!("Invalid join rules event: {}", e);
-                Error::bad_database("Invalid join rules event in db.")
-            })
-        })
-        .transpose()?;
-
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. } | JoinRule::KnockRestricted { .. }
-        ) {
-            return Err(Error::BadRequest(
-                ErrorKind::UnableToAuthorizeJoin,
-                "Conduit does not support restricted rooms yet.",
-            ));
-        }
+    if services().rooms.metadata.is_blocked(room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is blocked on this server.",
+        ));
     }
 
     // We need to return the state prior to joining, let's keep a reference to that here
@@ -1554,7 +1804,7 @@ async fn create_join_event(
 
     // We do not add the event_id field to the pdu here because of signature and hashes checks
     let room_version_id = services().rooms.state.get_room_version(room_id)?;
-    let (event_id, value) = match gen_event_id_canonical_json(pdu, &room_version_id) {
+    let (event_id, mut value) = match gen_event_id_canonical_json(pdu, &room_version_id) {
         Ok(t) => t,
         Err(_) => {
             // Event could not be converted to canonical json
@@ -1565,6 +1815,79 @@ async fn create_join_event(
         }
     };
 
+    // Checked here as well, the server might not have asked for a template first
+    for field in ["sender", "state_key"] {
+        if let Some(user_id) = value
+            .get(field)
+            .and_then(|user_id| user_id.as_str())
+            .and_then(|user_id| UserId::parse(user_id).ok())
+        {
+            if services().users.is_blocked(&user_id)? {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "The user is blocked on this server.",
+                ));
+            }
+        }
+    }
+
+    // Restricted joins are authorised by one of our users, which means we have to sign them
+    let authorizing_user = value
+        .get("content")
+        .and_then(|content| content.as_object()?.get("join_authorised_via_users_server"))
+        .and_then(|user_id| user_id.as_str())
+        .map(UserId::parse)
+        .transpose()
+        .map_err(|_| {
+            Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Invalid join_authorised_via_users_server.",
+            )
+        })?;
+
+    let signed_event = match authorizing_user {
+        Some(authorizing_user)
+            if authorizing_user.server_name() == services().globals.server_name() =>
+        {
+            let user_id = value
+                .get("state_key")
+                .and_then(|state_key| state_key.as_str())
+                .and_then(|state_key| UserId::parse(state_key).ok())
+                .ok_or(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Join event has an invalid state key.",
+                ))?;
+
+            let restriction_rooms = services()
+                .rooms
+                .state_accessor
+                .join_restriction_rooms(room_id)?
+                .unwrap_or_default();
+
+            if !user_in_restriction_room(&user_id, &restriction_rooms) {
+                return Err(Error::BadRequest(
+                    ErrorKind::UnableToAuthorizeJoin,
+                    "The user is not in any of the rooms that allow joining.",
+                ));
+            }
+
+            ruma::signatures::hash_and_sign_event(
+                services().globals.server_name().as_str(),
+                services().globals.keypair(),
+                &mut value,
+                &room_version_id,
+            )
+            .map_err(|_| {
+                Error::BadRequest(ErrorKind::InvalidParam, "Could not sign the join event.")
+            })?;
+
+            Some(PduEvent::convert_to_outgoing_federation_event(
+                value.clone(),
+            ))
+        }
+        _ => None,
+    };
+
     let origin: OwnedServerName = serde_json::from_value(
         serde_json::to_value(value.get("origin").ok_or(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -1613,7 +1936,7 @@ async fn create_join_event(
         .filter_map(|r| r.ok())
         .filter(|server| &**server != services().globals.server_name());
 
-    services().sending.send_pdu(servers, &pdu_id)?;
+    services().sending.send_pdu(room_id, servers, &pdu_id)?;
 
     Ok(create_join_event::v1::RoomState {
         auth_chain: auth_chain_ids
@@ -1625,7 +1948,18 @@ async fn create_join_event(
             .filter_map(|(_, id)| services().rooms.timeline.get_pdu_json(id).ok().flatten())
             .map(PduEvent::convert_to_outgoing_federation_event)
             .collect(),
-        event: None, // TODO: handle restricted joins
+        event: signed_event,
+    })
+}
+
+/// Whether we know the user to be joined to one of the rooms of a restricted join rule.
+fn user_in_restriction_room(user_id: &UserId, restriction_rooms: &[OwnedRoomId]) -> bool {
+    restriction_rooms.iter().any(|room_id| {
+        services()
+            .rooms
+            .state_cache
+            .is_joined(user_id, room_id)
+            .unwrap_or(false)
     })
 }
 
@@ -1692,6 +2026,13 @@ pub async fn create_invite_route(
         .event_handler
         .acl_check(sender_servername, &body.room_id)?;
 
+    if services().rooms.metadata.is_blocked(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room is blocked on this server.",
+        ));
+    }
+
     if !services()
         .globals
         .supported_room_versions()
@@ -1754,6 +2095,13 @@ pub async fn create_invite_route(
     )
     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "state_key is not a user id."))?;
 
+    if services().users.is_blocked(&sender)? || services().users.is_blocked(&invited_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The user is blocked on this server.",
+        ));
+    }
+
     let mut invite_state = body.invite_room_state.clone();
 
     let mut event: JsonObject = serde_json::from_str(body.event.get())
@@ -1778,7 +2126,6 @@ pub async fn create_invite_route(
             &body.room_id,
             &invited_user,
             MembershipState::Invite,
-            &sender,
             Some(invite_state),
             true,
         )?;
@@ -1789,6 +2136,117 @@ pub async fn create_invite_route(
     })
 }
 
+/// # `PUT /_matrix/federation/v1/exchange_third_party_invite/{roomId}`
+///
+/// Invites a user who bound the address of a third party invite one of our users sent.
+///
+/// - The identity server must have signed the invite with a key of the `m.room.third_party_invite`
+///   event
+pub async fn exchange_third_party_invite_route(
+    body: Ruma<exchange_invite::v1::Request>,
+) -> Result<exchange_invite::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    services()
+        .rooms
+        .event_handler
+        .acl_check(sender_servername, &body.room_id)?;
+
+    if body.kind != StateEventType::RoomMember || body.content.membership != MembershipState::Invite
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Only invite membership events can be exchanged.",
+        ));
+    }
+
+    if body.sender.server_name() != services().globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "The third party invite was not sent by a user of this server.",
+        ));
+    }
+
+    let third_party_invite = body
+        .content
+        .third_party_invite
+        .clone()
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Membership event has no third party invite.",
+        ))?;
+
+    client_server::accept_third_party_invite(
+        &body.sender,
+        &body.state_key,
+        &body.room_id,
+        third_party_invite,
+    )
+    .await?;
+
+    Ok(exchange_invite::v1::Response {})
+}
+
+/// # `PUT /_matrix/federation/v1/3pid/onbind`
+///
+/// Called by identity servers when one of our users bound an address that has pending third
+/// party invites.
+///
+/// - Each invite is exchanged for an invite membership event with the server of the inviter
+pub async fn third_party_invite_onbind_route(
+    body: Ruma<bind_callback::v1::Request>,
+) -> Result<bind_callback::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    if body.mxid.server_name() != services().globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "User does not belong to this server.",
+        ));
+    }
+
+    for invite in &body.invites {
+        if invite.mxid != body.mxid {
+            warn!(
+                "Identity server sent invite for {} in onbind of {}",
+                invite.mxid, body.mxid
+            );
+            continue;
+        }
+
+        let third_party_invite = ThirdPartyInvite {
+            // The server of the inviter uses the display name of the invite
+            display_name: invite.address.clone(),
+            signed: invite.signed.clone(),
+        };
+
+        if let Err(e) = client_server::exchange_third_party_invite_helper(
+            &invite.sender,
+            &invite.mxid,
+            &invite.room_id,
+            third_party_invite,
+        )
+        .await
+        {
+            warn!(
+                "Failed to exchange third party invite of {} in {}: {}",
+                invite.mxid, invite.room_id, e
+            );
+        }
+    }
+
+    Ok(bind_callback::v1::Response {})
+}
+
 /// # `GET /_matrix/federation/v1/user/devices/{userId}`
 ///
 /// Gets information on all devices of the user.
@@ -1806,6 +2264,10 @@ pub async fn get_devices_route(
         ));
     }
 
+    if !services().appservice.user_exists(&body.user_id).await? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "User not found."));
+    }
+
     let sender_servername = body
         .sender_servername
         .as_ref()
@@ -1848,6 +2310,9 @@ pub async fn get_devices_route(
 /// # `GET /_matrix/federation/v1/query/directory`
 ///
 /// Resolve a room alias to a room id.
+///
+/// - Only our own aliases are resolved, including the ones of appservices
+/// - Returns the servers in the room, ours first
 pub async fn get_room_information_route(
     body: Ruma<get_room_information::v1::Request>,
 ) -> Result<get_room_information::v1::Response> {
@@ -1855,19 +2320,49 @@ pub async fn get_room_information_route(
         return Err(Error::bad_config("Federation is disabled."));
     }
 
-    let room_id = services()
+    let not_found = || Error::BadRequest(ErrorKind::NotFound, "Room alias not found.");
+
+    if body.room_alias.server_name() != services().globals.server_name() {
+        return Err(not_found());
+    }
+
+    let room_id = match services()
         .rooms
         .alias
         .resolve_local_alias(&body.room_alias)?
+    {
+        Some(room_id) => room_id,
+        None => services()
+            .appservice
+            .query_room_alias(&body.room_alias)
+            .await?
+            .ok_or_else(not_found)?,
+    };
+
+    let servers = client_server::resident_servers(&room_id);
+
+    Ok(get_room_information::v1::Response { room_id, servers })
+}
+
+/// # `GET /_matrix/federation/v1/openid/userinfo`
+///
+/// Returns the user an OpenID token was created for.
+pub async fn get_openid_userinfo_route(
+    body: Ruma<get_openid_userinfo::v1::Request>,
+) -> Result<get_openid_userinfo::v1::Response> {
+    if !services().globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    let sub = services()
+        .users
+        .find_from_openid_token(&body.access_token)?
         .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "Room alias not found.",
+            ErrorKind::UnknownToken { soft_logout: false },
+            "OpenID token is unknown or expired.",
         ))?;
 
-    Ok(get_room_information::v1::Response {
-        room_id,
-        servers: vec![services().globals.server_name().to_owned()],
-    })
+    Ok(get_openid_userinfo::v1::Response { sub })
 }
 
 /// # `GET /_matrix/federation/v1/query/profile`
@@ -1976,7 +2471,14 @@ pub async fn claim_keys_route(
 
 #[cfg(test)]
 mod tests {
-    use super::{add_port_to_hostname, get_ip_with_port, FedDest};
+    use std::time::Duration;
+
+    use http::{header::CACHE_CONTROL, HeaderMap, HeaderValue};
+
+    use super::{
+        add_port_to_hostname, get_ip_with_port, well_known_ttl, FedDest, RequestKind,
+        WELL_KNOWN_DEFAULT_TTL, WELL_KNOWN_MAX_TTL, WELL_KNOWN_MIN_TTL,
+    };
 
     #[test]
     fn ips_get_default_ports() {
@@ -2017,4 +2519,51 @@ mod tests {
             FedDest::Named(String::from("example.com"), String::from(":1337"))
         )
     }
+
+    #[test]
+    fn well_known_ttl_is_bounded_max_age() {
+        let ttl = |cache_control: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CACHE_CONTROL, HeaderValue::from_str(cache_control).unwrap());
+            well_known_ttl(&headers)
+        };
+
+        assert_eq!(well_known_ttl(&HeaderMap::new()), WELL_KNOWN_DEFAULT_TTL);
+        assert_eq!(ttl("public, max-age=3600"), Duration::from_secs(3600));
+        assert_eq!(ttl("max-age=1"), WELL_KNOWN_MIN_TTL);
+        assert_eq!(ttl("no-store"), WELL_KNOWN_MIN_TTL);
+        assert_eq!(ttl("max-age=31536000"), WELL_KNOWN_MAX_TTL);
+    }
+
+    #[test]
+    fn request_kind_from_path() {
+        let kind = RequestKind::from_path;
+
+        assert_eq!(
+            kind("/_matrix/federation/v1/send/1234"),
+            RequestKind::Transaction
+        );
+        assert_eq!(
+            kind("/_matrix/federation/v2/send_join/!room:a/$event"),
+            RequestKind::Join
+        );
+        assert_eq!(
+            kind("/_matrix/federation/v1/make_join/!room:a/@user:b"),
+            RequestKind::Join
+        );
+        assert_eq!(kind("/_matrix/media/v3/download/a/b"), RequestKind::Media);
+        assert_eq!(kind("/_matrix/key/v2/server"), RequestKind::KeyQuery);
+        assert_eq!(
+            kind("/_matrix/federation/v1/user/keys/query"),
+            RequestKind::KeyQuery
+        );
+        assert_eq!(
+            kind("/_matrix/federation/v1/send_leave/a/b"),
+            RequestKind::Other
+        );
+        assert_eq!(
+            kind("/_matrix/federation/v1/state/!room:a"),
+            RequestKind::Other
+        );
+    }
 }
@@ -0,0 +1,117 @@
+use http::Method;
+use serde::Deserialize;
+
+/// How many bytes the body of a request may have, by the kind of endpoint. Bigger requests are
+/// rejected with `M_TOO_LARGE` before they are read completely.
+///
+/// ## Example:
+/// ```toml
+/// [global.body_limit]
+/// message = 65_536
+/// media_upload = 50_000_000
+/// ```
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct BodyLimitConfig {
+    /// Sending and redacting events
+    #[serde(default = "default_event")]
+    pub message: u32,
+    /// Sending state events
+    #[serde(default = "default_event")]
+    pub state_event: u32,
+    /// Media uploads, this is also the upload size clients see in the media config
+    #[serde(default = "default_media_upload")]
+    pub media_upload: u32,
+    /// Every other client and federation endpoint
+    #[serde(default = "default_general")]
+    pub general: u32,
+}
+
+impl BodyLimitConfig {
+    /// Returns the limit for a request to this path.
+    pub fn limit(&self, method: &Method, path: &str) -> u32 {
+        let is_client = path.starts_with("/_matrix/client/");
+
+        if path.starts_with("/_matrix/media/") && path.contains("/upload") {
+            self.media_upload
+        } else if is_client && method == Method::PUT && path.contains("/state/") {
+            self.state_event
+        } else if is_client
+            && method == Method::PUT
+            && (path.contains("/send/") || path.contains("/redact/"))
+        {
+            self.message
+        } else {
+            self.general
+        }
+    }
+
+    /// The biggest of all limits, for requests that are not sorted into a category.
+    pub fn largest(&self) -> u32 {
+        self.message
+            .max(self.state_event)
+            .max(self.media_upload)
+            .max(self.general)
+    }
+}
+
+impl Default for BodyLimitConfig {
+    fn default() -> Self {
+        Self {
+            message: default_event(),
+            state_event: default_event(),
+            media_upload: default_media_upload(),
+            general: default_general(),
+        }
+    }
+}
+
+/// Events can't be bigger than 64 KiB anyway.
+fn default_event() -> u32 {
+    65_536
+}
+
+fn default_media_upload() -> u32 {
+    20 * 1024 * 1024 // 20 MB
+}
+
+/// Federation transactions can contain 50 PDUs and 100 EDUs, so this has to fit them.
+fn default_general() -> u32 {
+    10_000_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_by_endpoint() {
+        let config = BodyLimitConfig::default();
+
+        assert_eq!(
+            config.limit(
+                &Method::PUT,
+                "/_matrix/client/v3/rooms/!a:b/send/m.room.message/1"
+            ),
+            config.message
+        );
+        assert_eq!(
+            config.limit(
+                &Method::PUT,
+                "/_matrix/client/v3/rooms/!a:b/state/m.room.name/"
+            ),
+            config.state_event
+        );
+        assert_eq!(
+            config.limit(&Method::POST, "/_matrix/media/v3/upload"),
+            config.media_upload
+        );
+        assert_eq!(
+            config.limit(&Method::PUT, "/_matrix/federation/v1/send/1"),
+            config.general
+        );
+        assert_eq!(
+            config.limit(&Method::PUT, "/_matrix/client/v3/sendToDevice/m.room_key/1"),
+            config.general
+        );
+    }
+}
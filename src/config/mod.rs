@@ -1,16 +1,23 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashSet},
     fmt,
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
 };
 
-use ruma::{OwnedServerName, RoomVersionId};
+use figment::Figment;
+use ruma::{OwnedServerName, OwnedUserId, RoomVersionId};
 use serde::{de::IgnoredAny, Deserialize};
 use tracing::warn;
 
+mod body_limit;
+mod oidc;
 mod proxy;
+mod rate_limit;
 
+pub use self::body_limit::BodyLimitConfig;
+pub use self::oidc::OidcProvider;
 use self::proxy::ProxyConfig;
+pub use self::rate_limit::{RateLimit, RateLimitConfig};
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
@@ -19,11 +26,20 @@ pub struct Config {
     #[serde(default = "default_port")]
     pub port: u16,
     pub tls: Option<TlsConfig>,
+    #[serde(default = "false_fn")]
+    pub enable_metrics: bool,
+    #[serde(default = "default_address")]
+    pub metrics_address: IpAddr,
+    #[serde(default = "default_metrics_port")]
+    pub metrics_port: u16,
+    #[serde(default = "default_trusted_proxies")]
+    pub trusted_proxies: Vec<IpAddr>,
 
     pub server_name: OwnedServerName,
     #[serde(default = "default_database_backend")]
     pub database_backend: String,
     pub database_path: String,
+    pub database_url: Option<String>,
     #[serde(default = "default_db_cache_capacity_mb")]
     pub db_cache_capacity_mb: f64,
     #[serde(default = "true_fn")]
@@ -38,22 +54,94 @@ pub struct Config {
     pub pdu_cache_capacity: u32,
     #[serde(default = "default_cleanup_second_interval")]
     pub cleanup_second_interval: u32,
-    #[serde(default = "default_max_request_size")]
-    pub max_request_size: u32,
+    #[serde(default)]
+    pub flush_strategy: FlushStrategy,
+    #[serde(default = "default_flush_second_interval")]
+    pub flush_second_interval: u32,
+    #[serde(default)]
+    pub media_quota_per_user: u64,
+    #[serde(default)]
+    pub media_retention_days: u32,
+    #[serde(default = "default_media_purge_second_interval")]
+    pub media_purge_second_interval: u32,
+    #[serde(default = "false_fn")]
+    pub allow_room_retention: bool,
+    #[serde(default)]
+    pub room_retention_default_days: u32,
+    #[serde(default)]
+    pub room_retention_max_days: u32,
+    #[serde(default = "default_room_retention_purge_second_interval")]
+    pub room_retention_purge_second_interval: u32,
+    #[serde(default = "default_device_last_seen_second_interval")]
+    pub device_last_seen_second_interval: u32,
     #[serde(default = "default_max_concurrent_requests")]
     pub max_concurrent_requests: u16,
+    #[serde(default = "default_max_concurrent_requests_per_destination")]
+    pub max_concurrent_requests_per_destination: u16,
+    #[serde(default = "default_federation_timeout_seconds")]
+    pub federation_timeout_seconds: u32,
+    #[serde(default = "default_federation_transaction_timeout_seconds")]
+    pub federation_transaction_timeout_seconds: u32,
+    #[serde(default = "default_federation_join_timeout_seconds")]
+    pub federation_join_timeout_seconds: u32,
+    #[serde(default = "default_federation_media_timeout_seconds")]
+    pub federation_media_timeout_seconds: u32,
+    #[serde(default = "default_federation_key_query_timeout_seconds")]
+    pub federation_key_query_timeout_seconds: u32,
+    #[serde(default = "default_federation_pool_max_idle_per_host")]
+    pub federation_pool_max_idle_per_host: u16,
+    #[serde(default = "default_federation_pool_idle_timeout_seconds")]
+    pub federation_pool_idle_timeout_seconds: u32,
+    pub tls_ca_file: Option<String>,
+    #[serde(default = "default_sending_backoff_max_seconds")]
+    pub sending_backoff_max_seconds: u32,
+    #[serde(default = "default_sending_dead_after_failures")]
+    pub sending_dead_after_failures: u32,
+    #[serde(default = "default_sending_dead_probe_second_interval")]
+    pub sending_dead_probe_second_interval: u32,
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: u32,
     #[serde(default = "default_max_fetch_prev_events")]
     pub max_fetch_prev_events: u16,
+    #[serde(default = "default_max_backups_per_user")]
+    pub max_backups_per_user: u32,
     #[serde(default = "false_fn")]
     pub allow_registration: bool,
+    #[serde(default = "false_fn")]
+    pub allow_guests: bool,
+    #[serde(default = "true_fn")]
+    pub allow_password_login: bool,
+    #[serde(default = "true_fn")]
+    pub allow_profile_changes: bool,
+    #[serde(default = "true_fn")]
+    pub allow_3pid_changes: bool,
     pub registration_token: Option<String>,
+    pub recaptcha_public_key: Option<String>,
+    pub recaptcha_private_key: Option<String>,
+    #[serde(default)]
+    pub registration_stages: Vec<RegistrationStage>,
+    pub identity_server: Option<String>,
+    pub identity_server_access_token: Option<String>,
     #[serde(default = "true_fn")]
     pub allow_encryption: bool,
     #[serde(default = "false_fn")]
     pub allow_federation: bool,
+    pub federation_allowlist: Option<HashSet<OwnedServerName>>,
+    #[serde(default = "true_fn")]
+    pub check_remote_canonical_aliases: bool,
     #[serde(default = "true_fn")]
     pub allow_room_creation: bool,
     #[serde(default = "true_fn")]
+    pub allow_presence: bool,
+    #[serde(default = "default_presence_idle_timeout_seconds")]
+    pub presence_idle_timeout_seconds: u32,
+    #[serde(default = "default_presence_offline_timeout_seconds")]
+    pub presence_offline_timeout_seconds: u32,
+    #[serde(default = "default_max_typing_timeout_seconds")]
+    pub max_typing_timeout_seconds: u32,
+    #[serde(default = "default_transaction_id_retention_days")]
+    pub transaction_id_retention_days: u32,
+    #[serde(default = "true_fn")]
     pub allow_unstable_room_versions: bool,
     #[serde(default = "default_default_room_version")]
     pub default_room_version: RoomVersionId,
@@ -64,7 +152,16 @@ pub struct Config {
     pub tracing_flame: bool,
     #[serde(default)]
     pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub body_limit: BodyLimitConfig,
     pub jwt_secret: Option<String>,
+    #[serde(default)]
+    pub oidc_providers: Vec<OidcProvider>,
+    pub sso_base_url: Option<String>,
+    #[serde(default)]
+    pub sso_client_whitelist: Vec<String>,
     #[serde(default = "default_trusted_servers")]
     pub trusted_servers: Vec<OwnedServerName>,
     #[serde(default = "default_log")]
@@ -82,6 +179,8 @@ pub struct Config {
 
     pub emergency_password: Option<String>,
 
+    pub moderation_user: Option<OwnedUserId>,
+
     #[serde(flatten)]
     pub catchall: BTreeMap<String, IgnoredAny>,
 }
@@ -92,9 +191,61 @@ pub struct TlsConfig {
     pub key: String,
 }
 
-const DEPRECATED_KEYS: &[&str] = &["cache_capacity"];
+/// When the database is asked to flush its writes to disk.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlushStrategy {
+    /// After every key backup mutation and counter reservation
+    Immediate,
+    /// Every `flush_second_interval` seconds
+    #[default]
+    Periodic,
+    /// Once no new counts were handed out for `flush_second_interval` seconds
+    OnIdle,
+}
+
+impl fmt::Display for FlushStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlushStrategy::Immediate => write!(f, "immediate"),
+            FlushStrategy::Periodic => write!(f, "periodic"),
+            FlushStrategy::OnIdle => write!(f, "on_idle"),
+        }
+    }
+}
+
+/// A stage of the user-interactive authentication when registering.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationStage {
+    /// `m.login.recaptcha`, needs the recaptcha keys
+    Recaptcha,
+    /// `m.login.registration_token`
+    Token,
+}
+
+impl fmt::Display for RegistrationStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationStage::Recaptcha => write!(f, "recaptcha"),
+            RegistrationStage::Token => write!(f, "token"),
+        }
+    }
+}
+
+// max_request_size is media_upload in body_limit now, and used for it if that isn't set
+const DEPRECATED_KEYS: &[&str] = &["cache_capacity", "max_request_size"];
 
 impl Config {
+    /// Takes over the values of deprecated keys that have a replacement which isn't set.
+    pub fn apply_deprecated(&mut self, raw_config: &Figment) {
+        if raw_config.find_value("body_limit.media_upload").is_err() {
+            if let Ok(max_request_size) = raw_config.extract_inner::<u32>("max_request_size") {
+                self.body_limit.media_upload = max_request_size;
+            }
+        }
+    }
+
     pub fn warn_deprecated(&self) {
         let mut was_deprecated = false;
         for key in self
@@ -114,11 +265,40 @@ impl Config {
 
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let federation_allowlist = match &self.federation_allowlist {
+            Some(allowlist) => {
+                let mut lst: Vec<_> = allowlist.iter().map(|server| server.host()).collect();
+                lst.sort_unstable();
+                lst.join(", ")
+            }
+            None => "not set".to_owned(),
+        };
+
+        let trusted_proxies = self
+            .trusted_proxies
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+
         // Prepare a list of config values to show
         let lines = [
             ("Server name", self.server_name.host()),
             ("Database backend", &self.database_backend),
+            ("Enable metrics", &self.enable_metrics.to_string()),
+            (
+                "Metrics address",
+                &format!("{}:{}", self.metrics_address, self.metrics_port),
+            ),
+            ("Trusted proxies", &trusted_proxies),
             ("Database path", &self.database_path),
+            (
+                "Database URL",
+                match self.database_url {
+                    Some(_) => "set",
+                    None => "not set",
+                },
+            ),
             (
                 "Database cache capacity (MB)",
                 &self.db_cache_capacity_mb.to_string(),
@@ -137,19 +317,207 @@ impl fmt::Display for Config {
                 "Cleanup interval in seconds",
                 &self.cleanup_second_interval.to_string(),
             ),
-            ("Maximum request size", &self.max_request_size.to_string()),
+            ("Flush strategy", &self.flush_strategy.to_string()),
+            (
+                "Flush interval in seconds",
+                &self.flush_second_interval.to_string(),
+            ),
+            (
+                "Media quota per user",
+                &self.media_quota_per_user.to_string(),
+            ),
+            (
+                "Media retention in days",
+                &self.media_retention_days.to_string(),
+            ),
+            (
+                "Media purge interval in seconds",
+                &self.media_purge_second_interval.to_string(),
+            ),
+            (
+                "Allow room retention",
+                &self.allow_room_retention.to_string(),
+            ),
+            (
+                "Default room retention in days",
+                &self.room_retention_default_days.to_string(),
+            ),
+            (
+                "Maximum room retention in days",
+                &self.room_retention_max_days.to_string(),
+            ),
+            (
+                "Room retention purge interval in seconds",
+                &self.room_retention_purge_second_interval.to_string(),
+            ),
+            (
+                "Device last seen update interval in seconds",
+                &self.device_last_seen_second_interval.to_string(),
+            ),
             (
                 "Maximum concurrent requests",
                 &self.max_concurrent_requests.to_string(),
             ),
+            (
+                "Maximum concurrent requests per destination",
+                &self.max_concurrent_requests_per_destination.to_string(),
+            ),
+            (
+                "Federation request timeout in seconds",
+                &self.federation_timeout_seconds.to_string(),
+            ),
+            (
+                "Federation transaction timeout in seconds",
+                &self.federation_transaction_timeout_seconds.to_string(),
+            ),
+            (
+                "Federation join timeout in seconds",
+                &self.federation_join_timeout_seconds.to_string(),
+            ),
+            (
+                "Federation media timeout in seconds",
+                &self.federation_media_timeout_seconds.to_string(),
+            ),
+            (
+                "Federation key query timeout in seconds",
+                &self.federation_key_query_timeout_seconds.to_string(),
+            ),
+            (
+                "Idle federation connections per server",
+                &self.federation_pool_max_idle_per_host.to_string(),
+            ),
+            (
+                "Federation connection idle timeout in seconds",
+                &self.federation_pool_idle_timeout_seconds.to_string(),
+            ),
+            (
+                "TLS CA file",
+                match &self.tls_ca_file {
+                    Some(path) => path,
+                    None => "not set",
+                },
+            ),
+            (
+                "Maximum sending backoff in seconds",
+                &self.sending_backoff_max_seconds.to_string(),
+            ),
+            (
+                "Failures until a destination is dead",
+                &self.sending_dead_after_failures.to_string(),
+            ),
+            (
+                "Dead destination probe interval in seconds",
+                &self.sending_dead_probe_second_interval.to_string(),
+            ),
+            (
+                "Shutdown timeout in seconds",
+                &self.shutdown_timeout_seconds.to_string(),
+            ),
+            (
+                "Maximum key backups per user",
+                &self.max_backups_per_user.to_string(),
+            ),
             ("Allow registration", &self.allow_registration.to_string()),
+            ("Allow guests", &self.allow_guests.to_string()),
+            (
+                "Allow password login",
+                &self.allow_password_login.to_string(),
+            ),
+            (
+                "Allow profile changes",
+                &self.allow_profile_changes.to_string(),
+            ),
+            ("Allow 3pid changes", &self.allow_3pid_changes.to_string()),
+            ("Registration stages", {
+                if self.registration_stages.is_empty() {
+                    "automatic"
+                } else {
+                    &self
+                        .registration_stages
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            }),
+            (
+                "reCAPTCHA",
+                if self.recaptcha_private_key.is_some() {
+                    "enabled"
+                } else {
+                    "disabled"
+                },
+            ),
+            (
+                "Identity server",
+                match &self.identity_server {
+                    Some(id_server) => id_server,
+                    None => "not set",
+                },
+            ),
             (
                 "Enabled lightning bolt",
                 &self.enable_lightning_bolt.to_string(),
             ),
             ("Allow encryption", &self.allow_encryption.to_string()),
             ("Allow federation", &self.allow_federation.to_string()),
+            ("Federation allowlist", &federation_allowlist),
+            (
+                "Check remote canonical aliases",
+                &self.check_remote_canonical_aliases.to_string(),
+            ),
             ("Allow room creation", &self.allow_room_creation.to_string()),
+            ("Allow presence", &self.allow_presence.to_string()),
+            (
+                "Presence idle timeout in seconds",
+                &self.presence_idle_timeout_seconds.to_string(),
+            ),
+            (
+                "Presence offline timeout in seconds",
+                &self.presence_offline_timeout_seconds.to_string(),
+            ),
+            (
+                "Maximum typing timeout in seconds",
+                &self.max_typing_timeout_seconds.to_string(),
+            ),
+            (
+                "Transaction id retention in days",
+                &self.transaction_id_retention_days.to_string(),
+            ),
+            ("Login rate limit", &rate_limit_line(self.rate_limit.login)),
+            (
+                "Guest registration rate limit",
+                &rate_limit_line(self.rate_limit.guest_registration),
+            ),
+            (
+                "Message rate limit",
+                &rate_limit_line(self.rate_limit.message),
+            ),
+            (
+                "Media upload rate limit",
+                &rate_limit_line(self.rate_limit.media_upload),
+            ),
+            (
+                "General rate limit",
+                &rate_limit_line(self.rate_limit.general),
+            ),
+            ("Message body limit", &self.body_limit.message.to_string()),
+            (
+                "State event body limit",
+                &self.body_limit.state_event.to_string(),
+            ),
+            (
+                "Media upload body limit",
+                &self.body_limit.media_upload.to_string(),
+            ),
+            ("General body limit", &self.body_limit.general.to_string()),
+            (
+                "Moderation user",
+                match &self.moderation_user {
+                    Some(user_id) => user_id.as_str(),
+                    None => "not set",
+                },
+            ),
             (
                 "JWT secret",
                 match self.jwt_secret {
@@ -157,6 +525,18 @@ impl fmt::Display for Config {
                     None => "not set",
                 },
             ),
+            ("OIDC providers", {
+                if self.oidc_providers.is_empty() {
+                    "none"
+                } else {
+                    &self
+                        .oidc_providers
+                        .iter()
+                        .map(|provider| provider.id.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            }),
             ("Trusted servers", {
                 let mut lst = vec![];
                 for server in &self.trusted_servers {
@@ -207,6 +587,17 @@ impl fmt::Display for Config {
     }
 }
 
+fn rate_limit_line(limit: RateLimit) -> String {
+    if limit.is_enabled() {
+        format!(
+            "{} per second, bursts of {}",
+            limit.per_second, limit.burst_count
+        )
+    } else {
+        "disabled".to_owned()
+    }
+}
+
 fn false_fn() -> bool {
     false
 }
@@ -223,6 +614,10 @@ fn default_port() -> u16 {
     8000
 }
 
+fn default_metrics_port() -> u16 {
+    6168
+}
+
 fn default_database_backend() -> String {
     "sqlite".to_owned()
 }
@@ -247,18 +642,102 @@ fn default_cleanup_second_interval() -> u32 {
     60 // every minute
 }
 
-fn default_max_request_size() -> u32 {
-    20 * 1024 * 1024 // Default to 20 MB
+fn default_flush_second_interval() -> u32 {
+    10
+}
+
+fn default_media_purge_second_interval() -> u32 {
+    60 * 60 // every hour
+}
+
+fn default_room_retention_purge_second_interval() -> u32 {
+    60 * 60 // every hour
+}
+
+fn default_trusted_proxies() -> Vec<IpAddr> {
+    vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()]
+}
+
+fn default_device_last_seen_second_interval() -> u32 {
+    5 * 60 // every 5 minutes
 }
 
 fn default_max_concurrent_requests() -> u16 {
     100
 }
 
+fn default_max_concurrent_requests_per_destination() -> u16 {
+    4
+}
+
+fn default_federation_timeout_seconds() -> u32 {
+    2 * 60
+}
+
+fn default_federation_transaction_timeout_seconds() -> u32 {
+    60
+}
+
+fn default_federation_join_timeout_seconds() -> u32 {
+    10 * 60
+}
+
+fn default_federation_media_timeout_seconds() -> u32 {
+    5 * 60
+}
+
+fn default_federation_key_query_timeout_seconds() -> u32 {
+    30
+}
+
+fn default_federation_pool_max_idle_per_host() -> u16 {
+    4
+}
+
+fn default_federation_pool_idle_timeout_seconds() -> u32 {
+    90
+}
+
+fn default_sending_backoff_max_seconds() -> u32 {
+    4 * 60 * 60 // 4 hours
+}
+
+fn default_sending_dead_after_failures() -> u32 {
+    10
+}
+
+fn default_sending_dead_probe_second_interval() -> u32 {
+    12 * 60 * 60 // every 12 hours
+}
+
+fn default_shutdown_timeout_seconds() -> u32 {
+    30
+}
+
+fn default_presence_idle_timeout_seconds() -> u32 {
+    5 * 60
+}
+
+fn default_presence_offline_timeout_seconds() -> u32 {
+    30 * 60
+}
+
+fn default_max_typing_timeout_seconds() -> u32 {
+    120
+}
+
+fn default_transaction_id_retention_days() -> u32 {
+    7
+}
+
 fn default_max_fetch_prev_events() -> u16 {
     100_u16
 }
 
+fn default_max_backups_per_user() -> u32 {
+    10
+}
+
 fn default_trusted_servers() -> Vec<OwnedServerName> {
     vec![OwnedServerName::try_from("matrix.org").unwrap()]
 }
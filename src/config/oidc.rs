@@ -0,0 +1,48 @@
+use serde::Deserialize;
+
+/// An OpenID Connect identity provider users can log in with. The endpoints are discovered from
+/// the issuer.
+///
+/// ## Example:
+/// ```toml
+/// [[global.oidc_providers]]
+/// id = "company"
+/// name = "Company SSO"
+/// issuer = "https://sso.example.org"
+/// client_id = "conduit"
+/// client_secret = "secret"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct OidcProvider {
+    /// The id clients use to pick this provider, shown in `GET /login`
+    pub id: String,
+    /// The name clients show to users
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// The claim the localpart of new users is taken from
+    #[serde(default = "default_localpart_claim")]
+    pub localpart_claim: String,
+    /// Creates accounts for users of the provider who don't have one yet
+    #[serde(default = "super::true_fn")]
+    pub auto_provision: bool,
+    /// Logs users of the provider into the account that has their verified email address. Only
+    /// enable this if the provider verifies every email address it reports.
+    #[serde(default = "super::false_fn")]
+    pub link_by_email: bool,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec![
+        "openid".to_owned(),
+        "profile".to_owned(),
+        "email".to_owned(),
+    ]
+}
+
+fn default_localpart_claim() -> String {
+    "preferred_username".to_owned()
+}
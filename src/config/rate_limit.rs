@@ -0,0 +1,84 @@
+use serde::Deserialize;
+
+/// How many requests of each category a user, or an IP address if there is no user, may send.
+///
+/// ## Example:
+/// ```toml
+/// [global.rate_limit]
+/// login = { per_second = 0.17, burst_count = 3 }
+/// general = { per_second = 0.0, burst_count = 0 } # No limit
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_login")]
+    pub login: RateLimit,
+    #[serde(default = "default_guest_registration")]
+    pub guest_registration: RateLimit,
+    #[serde(default = "default_message")]
+    pub message: RateLimit,
+    #[serde(default = "default_media_upload")]
+    pub media_upload: RateLimit,
+    #[serde(default = "default_general")]
+    pub general: RateLimit,
+}
+
+/// A token bucket: it holds up to `burst_count` requests and refills `per_second` requests every
+/// second. A `per_second` of 0 disables the limit.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst_count: u32,
+}
+
+impl RateLimit {
+    pub fn is_enabled(&self) -> bool {
+        self.per_second > 0.0
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            login: default_login(),
+            guest_registration: default_guest_registration(),
+            message: default_message(),
+            media_upload: default_media_upload(),
+            general: default_general(),
+        }
+    }
+}
+
+fn default_login() -> RateLimit {
+    RateLimit {
+        per_second: 0.17,
+        burst_count: 3,
+    }
+}
+
+fn default_guest_registration() -> RateLimit {
+    RateLimit {
+        per_second: 0.017,
+        burst_count: 3,
+    }
+}
+
+fn default_message() -> RateLimit {
+    RateLimit {
+        per_second: 0.2,
+        burst_count: 10,
+    }
+}
+
+fn default_media_upload() -> RateLimit {
+    RateLimit {
+        per_second: 0.2,
+        burst_count: 10,
+    }
+}
+
+fn default_general() -> RateLimit {
+    RateLimit {
+        per_second: 10.0,
+        burst_count: 50,
+    }
+}
@@ -18,11 +18,18 @@ pub mod rocksdb;
 #[cfg(feature = "persy")]
 pub mod persy;
 
+#[cfg(feature = "postgres")]
+pub mod postgres;
+
+#[cfg(test)]
+pub mod memory;
+
 #[cfg(any(
     feature = "sqlite",
     feature = "rocksdb",
     feature = "heed",
-    feature = "persy"
+    feature = "persy",
+    feature = "postgres"
 ))]
 pub mod watchers;
 
@@ -31,6 +38,8 @@ pub trait KeyValueDatabaseEngine: Send + Sync {
     where
         Self: Sized;
     fn open_tree(&self, name: &'static str) -> Result<Arc<dyn KvTree>>;
+    /// Returns the names of all trees in the database, also the ones that were not opened yet.
+    fn tree_names(&self) -> Result<Vec<String>>;
     fn flush(&self) -> Result<()>;
     fn cleanup(&self) -> Result<()> {
         Ok(())
@@ -65,8 +74,15 @@ pub trait KvTree: Send + Sync {
         prefix: Vec<u8>,
     ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>;
 
+    /// Resolves after the next `insert` or `insert_batch` of a key that starts with the prefix.
+    /// Removals and increments don't wake watchers.
     fn watch_prefix<'a>(&'a self, prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
 
+    /// Returns the number of entries. Backends that keep statistics may return an estimate.
+    fn count(&self) -> Result<u64> {
+        Ok(self.iter().count() as u64)
+    }
+
     fn clear(&self) -> Result<()> {
         for (key, _) in self.iter() {
             self.remove(&key)?;
@@ -0,0 +1,74 @@
+use std::{collections::BTreeMap, future::Future, pin::Pin, sync::RwLock};
+
+use super::KvTree;
+use crate::{utils, Result};
+
+/// A tree that only lives in memory, for tests of code that works on trees.
+#[derive(Default)]
+pub struct MemoryTree(RwLock<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+impl KvTree for MemoryTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.read().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.0.write().unwrap().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn insert_batch(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.0.write().unwrap().extend(iter);
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.0.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        let entries = self.0.read().unwrap().clone();
+        Box::new(entries.into_iter())
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        from: &[u8],
+        backwards: bool,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        let entries = self.0.read().unwrap().clone().into_iter();
+        let from = from.to_vec();
+        if backwards {
+            Box::new(entries.rev().filter(move |(k, _)| *k <= from))
+        } else {
+            Box::new(entries.filter(move |(k, _)| *k >= from))
+        }
+    }
+
+    fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let mut entries = self.0.write().unwrap();
+        let value = utils::increment(entries.get(key).map(Vec::as_slice))
+            .expect("utils::increment always returns Some");
+        entries.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+        for key in iter {
+            self.increment(&key)?;
+        }
+        Ok(())
+    }
+
+    fn scan_prefix<'a>(
+        &'a self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+        Box::new(self.iter().filter(move |(k, _)| k.starts_with(&prefix)))
+    }
+
+    fn watch_prefix<'a>(&'a self, _prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(std::future::pending())
+    }
+}
@@ -42,6 +42,15 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
         }))
     }
 
+    fn tree_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .persy
+            .list_indexes()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
+
     fn flush(&self) -> Result<()> {
         Ok(())
     }
@@ -72,21 +81,26 @@ impl KvTree for PersyTree {
     }
 
     fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        self.insert_batch(&mut Some((key.to_owned(), value.to_owned())).into_iter())?;
-        self.watchers.wake(key);
-        Ok(())
+        self.insert_batch(&mut Some((key.to_owned(), value.to_owned())).into_iter())
     }
 
     fn insert_batch<'a>(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
         let mut tx = self.begin()?;
+        let mut keys = Vec::new();
         for (key, value) in iter {
             tx.put::<ByteVec, ByteVec>(
                 &self.name,
                 ByteVec::from(key.clone()),
                 ByteVec::from(value),
             )?;
+            keys.push(key);
         }
         tx.prepare()?.commit()?;
+
+        for key in keys {
+            self.watchers.wake(&key);
+        }
+
         Ok(())
     }
 
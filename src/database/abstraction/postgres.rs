@@ -0,0 +1,353 @@
+use super::{watchers::Watchers, KeyValueDatabaseEngine, KvTree};
+use crate::{database::Config, Error, Result};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    vec,
+};
+use tokio::runtime::{Handle, Runtime};
+use tokio_postgres::{types::ToSql, Client, NoTls, Row};
+use tracing::error;
+
+/// How many rows iterators fetch with one query
+const PAGE_SIZE: usize = 1000;
+
+type TupleOfBytes = (Vec<u8>, Vec<u8>);
+type Params = Vec<Box<dyn ToSql + Send + Sync>>;
+
+pub struct Engine {
+    client: Arc<Client>,
+    /// Drives the connection and the queries. Trees are used from sync code running on the main
+    /// runtime, which can't block on futures itself.
+    runtime: Runtime,
+    /// Held for read-modify-write operations like increment
+    write_lock: Mutex<()>,
+}
+
+/// Runs the future on the runtime and blocks the current thread until it is done.
+fn block_on<T: Send + 'static>(
+    handle: &Handle,
+    future: impl Future<Output = T> + Send + 'static,
+) -> T {
+    let (tx, rx) = mpsc::sync_channel(1);
+    handle.spawn(async move {
+        let _ = tx.send(future.await);
+    });
+    rx.recv().expect("the postgres runtime is never shut down")
+}
+
+impl Engine {
+    fn query(&self, statement: String, params: Params) -> Result<Vec<Row>> {
+        let client = Arc::clone(&self.client);
+
+        Ok(block_on(self.runtime.handle(), async move {
+            let params = params
+                .iter()
+                .map(|p| &**p as &(dyn ToSql + Sync))
+                .collect::<Vec<_>>();
+            client.query(statement.as_str(), &params).await
+        })?)
+    }
+}
+
+impl KeyValueDatabaseEngine for Arc<Engine> {
+    fn open(config: &Config) -> Result<Self> {
+        let url = config.database_url.clone().ok_or(Error::BadConfig(
+            "The postgres database backend needs a database_url.",
+        ))?;
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("postgres")
+            .enable_all()
+            .build()?;
+
+        let connected = block_on(runtime.handle(), async move {
+            tokio_postgres::connect(&url, NoTls).await
+        });
+        let (client, connection) = match connected {
+            Ok(connected) => connected,
+            Err(e) => {
+                // Dropping a runtime blocks, which is not allowed on the main runtime
+                runtime.shutdown_background();
+                return Err(e.into());
+            }
+        };
+
+        runtime.spawn(async move {
+            if let Err(e) = connection.await {
+                error!("Connection to the postgres database failed: {}", e);
+            }
+        });
+
+        Ok(Arc::new(Engine {
+            client: Arc::new(client),
+            runtime,
+            write_lock: Mutex::new(()),
+        }))
+    }
+
+    fn open_tree(&self, name: &'static str) -> Result<Arc<dyn KvTree>> {
+        self.query(
+            format!("CREATE TABLE IF NOT EXISTS \"{name}\" ( key BYTEA PRIMARY KEY, value BYTEA NOT NULL )"),
+            Vec::new(),
+        )?;
+
+        Ok(Arc::new(PostgresTable {
+            engine: Arc::clone(self),
+            name,
+            watchers: Watchers::default(),
+        }))
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .query(
+                "SELECT table_name FROM information_schema.tables WHERE table_schema = current_schema()"
+                    .to_owned(),
+                Vec::new(),
+            )?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Every statement is committed on its own
+        Ok(())
+    }
+}
+
+pub struct PostgresTable {
+    engine: Arc<Engine>,
+    name: &'static str,
+    watchers: Watchers,
+}
+
+impl PostgresTable {
+    fn insert_without_wake(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.engine.query(
+            format!(
+                "INSERT INTO \"{}\" (key, value) VALUES ($1, $2) ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
+                self.name
+            ),
+            vec![Box::new(key.to_vec()), Box::new(value.to_vec())],
+        )?;
+
+        Ok(())
+    }
+
+    fn iter_pages<'a>(
+        &'a self,
+        from: Option<Vec<u8>>,
+        backwards: bool,
+    ) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        Box::new(PageIterator {
+            table: self,
+            from,
+            last: None,
+            backwards,
+            page: Vec::new().into_iter(),
+            done: false,
+        })
+    }
+}
+
+/// Iterates over a table one page at a time, so big tables are not loaded at once.
+struct PageIterator<'a> {
+    table: &'a PostgresTable,
+    /// Where the first page starts, including this key
+    from: Option<Vec<u8>>,
+    /// The last key we returned, the next page starts after it
+    last: Option<Vec<u8>>,
+    backwards: bool,
+    page: vec::IntoIter<TupleOfBytes>,
+    done: bool,
+}
+
+impl PageIterator<'_> {
+    fn next_page(&mut self) -> Result<Vec<TupleOfBytes>> {
+        let (compare, order) = match (&self.last, self.backwards) {
+            (Some(_), false) => (">", "ASC"),
+            (Some(_), true) => ("<", "DESC"),
+            (None, false) => (">=", "ASC"),
+            (None, true) => ("<=", "DESC"),
+        };
+
+        let (condition, params): (_, Params) = match self.last.as_ref().or(self.from.as_ref()) {
+            Some(key) => (
+                format!("WHERE key {compare} $1"),
+                vec![Box::new(key.clone())],
+            ),
+            None => (String::new(), Vec::new()),
+        };
+
+        let rows = self.table.engine.query(
+            format!(
+                "SELECT key, value FROM \"{}\" {condition} ORDER BY key {order} LIMIT {PAGE_SIZE}",
+                self.table.name
+            ),
+            params,
+        )?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect())
+    }
+}
+
+impl Iterator for PageIterator<'_> {
+    type Item = TupleOfBytes;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((key, value)) = self.page.next() {
+            self.last = Some(key.clone());
+            return Some((key, value));
+        }
+
+        if self.done {
+            return None;
+        }
+
+        match self.next_page() {
+            Ok(page) => {
+                self.done = page.len() < PAGE_SIZE;
+                self.page = page.into_iter();
+            }
+            Err(e) => {
+                error!("Error iterating over {}: {}", self.table.name, e);
+                self.done = true;
+            }
+        }
+
+        let (key, value) = self.page.next()?;
+        self.last = Some(key.clone());
+        Some((key, value))
+    }
+}
+
+impl KvTree for PostgresTable {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .engine
+            .query(
+                format!("SELECT value FROM \"{}\" WHERE key = $1", self.name),
+                vec![Box::new(key.to_vec())],
+            )?
+            .first()
+            .map(|row| row.get(0)))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.insert_without_wake(key, value)?;
+        self.watchers.wake(key);
+        Ok(())
+    }
+
+    fn insert_batch(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        // One statement can't update the same row twice, the last value of a key wins like with
+        // separate inserts
+        let batch = iter.collect::<BTreeMap<_, _>>();
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let (keys, values): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        self.engine.query(
+            format!(
+                "INSERT INTO \"{}\" (key, value) SELECT * FROM UNNEST($1::BYTEA[], $2::BYTEA[]) ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
+                self.name
+            ),
+            vec![Box::new(keys.clone()), Box::new(values)],
+        )?;
+
+        for key in keys {
+            self.watchers.wake(&key);
+        }
+
+        Ok(())
+    }
+
+    fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+        let guard = self.engine.write_lock.lock().unwrap();
+
+        for key in iter {
+            let old = self.get(&key)?;
+            let new = crate::utils::increment(old.as_deref())
+                .expect("utils::increment always returns Some");
+            self.insert_without_wake(&key, &new)?;
+        }
+
+        drop(guard);
+
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        self.engine.query(
+            format!("DELETE FROM \"{}\" WHERE key = $1", self.name),
+            vec![Box::new(key.to_vec())],
+        )?;
+
+        Ok(())
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        self.iter_pages(None, false)
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        from: &[u8],
+        backwards: bool,
+    ) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        self.iter_pages(Some(from.to_vec()), backwards)
+    }
+
+    fn increment(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let guard = self.engine.write_lock.lock().unwrap();
+
+        let old = self.get(key)?;
+        let new =
+            crate::utils::increment(old.as_deref()).expect("utils::increment always returns Some");
+        self.insert_without_wake(key, &new)?;
+
+        drop(guard);
+
+        Ok(new)
+    }
+
+    fn scan_prefix<'a>(&'a self, prefix: Vec<u8>) -> Box<dyn Iterator<Item = TupleOfBytes> + 'a> {
+        Box::new(
+            self.iter_from(&prefix, false)
+                .take_while(move |(key, _)| key.starts_with(&prefix)),
+        )
+    }
+
+    fn watch_prefix<'a>(&'a self, prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        self.watchers.watch(prefix)
+    }
+
+    fn count(&self) -> Result<u64> {
+        let count: i64 = self
+            .engine
+            .query(
+                format!("SELECT count(*) FROM \"{}\"", self.name),
+                Vec::new(),
+            )?
+            .first()
+            .map_or(0, |row| row.get(0));
+
+        Ok(count as u64)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.engine
+            .query(format!("DELETE FROM \"{}\"", self.name), Vec::new())?;
+
+        Ok(())
+    }
+}
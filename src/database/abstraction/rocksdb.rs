@@ -110,6 +110,16 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
         }))
     }
 
+    fn tree_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .old_cfs
+            .iter()
+            // RocksDB always has this column family, Conduit doesn't use it
+            .filter(|name| *name != "default")
+            .cloned()
+            .collect())
+    }
+
     fn flush(&self) -> Result<()> {
         // TODO?
         Ok(())
@@ -158,8 +168,20 @@ impl KvTree for RocksDbEngineTree<'_> {
     }
 
     fn insert_batch<'a>(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let cf = self.cf();
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut keys = Vec::new();
         for (key, value) in iter {
-            self.db.rocks.put_cf(&self.cf(), key, value)?;
+            batch.put_cf(&cf, &key, value);
+            keys.push(key);
+        }
+
+        let lock = self.write_lock.read().unwrap();
+        self.db.rocks.write(batch)?;
+        drop(lock);
+
+        for key in keys {
+            self.watchers.wake(&key);
         }
 
         Ok(())
@@ -248,4 +270,13 @@ impl KvTree for RocksDbEngineTree<'_> {
     fn watch_prefix<'a>(&'a self, prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
         self.watchers.watch(prefix)
     }
+
+    fn count(&self) -> Result<u64> {
+        // Counting every key would read the whole column family
+        Ok(self
+            .db
+            .rocks
+            .property_int_value_cf(&self.cf(), "rocksdb.estimate-num-keys")?
+            .unwrap_or_default())
+    }
 }
@@ -1,16 +1,14 @@
-use super::super::Config;
+use super::{super::Config, KeyValueDatabaseEngine, KvTree};
 use crate::{utils, Result};
 use std::{future::Future, pin::Pin, sync::Arc};
 use tracing::warn;
 
-use super::{DatabaseEngine, Tree};
-
 pub struct Engine(sled::Db);
 
 pub struct SledEngineTree(sled::Tree);
 
-impl DatabaseEngine for Engine {
-    fn open(config: &Config) -> Result<Arc<Self>> {
+impl KeyValueDatabaseEngine for Arc<Engine> {
+    fn open(config: &Config) -> Result<Self> {
         Ok(Arc::new(Engine(
             sled::Config::default()
                 .path(&config.database_path)
@@ -20,16 +18,28 @@ impl DatabaseEngine for Engine {
         )))
     }
 
-    fn open_tree(self: &Arc<Self>, name: &'static str) -> Result<Arc<dyn Tree>> {
+    fn open_tree(&self, name: &'static str) -> Result<Arc<dyn KvTree>> {
         Ok(Arc::new(SledEngineTree(self.0.open_tree(name)?)))
     }
 
-    fn flush(self: &Arc<Self>) -> Result<()> {
-        Ok(()) // noop
+    fn tree_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .0
+            .tree_names()
+            .into_iter()
+            // Sled always has this tree, Conduit doesn't use it
+            .filter(|name| &**name != b"__sled__default")
+            .filter_map(|name| String::from_utf8(name.to_vec()).ok())
+            .collect())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
     }
 }
 
-impl Tree for SledEngineTree {
+impl KvTree for SledEngineTree {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         Ok(self.0.get(key)?.map(|v| v.to_vec()))
     }
@@ -39,10 +49,12 @@ impl Tree for SledEngineTree {
         Ok(())
     }
 
-    fn insert_batch<'a>(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+    fn insert_batch(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let mut batch = sled::Batch::default();
         for (key, value) in iter {
-            self.0.insert(key, value)?;
+            batch.insert(key, value);
         }
+        self.0.apply_batch(batch)?;
 
         Ok(())
     }
@@ -62,15 +74,15 @@ impl Tree for SledEngineTree {
                     }
                     r.ok()
                 })
-                .map(|(k, v)| (k.to_vec().into(), v.to_vec().into())),
+                .map(|(k, v)| (k.to_vec(), v.to_vec())),
         )
     }
 
-    fn iter_from(
-        &self,
+    fn iter_from<'a>(
+        &'a self,
         from: &[u8],
         backwards: bool,
-    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)>> {
+    ) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
         let iter = if backwards {
             self.0.range(..=from)
         } else {
@@ -84,7 +96,7 @@ impl Tree for SledEngineTree {
                 }
                 r.ok()
             })
-            .map(|(k, v)| (k.to_vec().into(), v.to_vec().into()));
+            .map(|(k, v)| (k.to_vec(), v.to_vec()));
 
         if backwards {
             Box::new(iter.rev())
@@ -100,6 +112,14 @@ impl Tree for SledEngineTree {
             .map(|o| o.expect("increment always sets a value").to_vec())?)
     }
 
+    fn increment_batch(&self, iter: &mut dyn Iterator<Item = Vec<u8>>) -> Result<()> {
+        for key in iter {
+            self.increment(&key)?;
+        }
+
+        Ok(())
+    }
+
     fn scan_prefix<'a>(
         &'a self,
         prefix: Vec<u8>,
@@ -113,7 +133,7 @@ impl Tree for SledEngineTree {
                 }
                 r.ok()
             })
-            .map(|(k, v)| (k.to_vec().into(), v.to_vec().into()));
+            .map(|(k, v)| (k.to_vec(), v.to_vec()));
 
         Box::new(iter)
     }
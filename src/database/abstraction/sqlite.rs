@@ -115,6 +115,16 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
         }))
     }
 
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let guard = self.read_lock();
+        let mut statement = guard.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?;
+        let names = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<_, _>>()?;
+
+        Ok(names)
+    }
+
     fn flush(&self) -> Result<()> {
         // we enabled PRAGMA synchronous=normal, so this should not be necessary
         Ok(())
@@ -200,14 +210,20 @@ impl KvTree for SqliteTable {
     fn insert_batch<'a>(&self, iter: &mut dyn Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<()> {
         let guard = self.engine.write_lock();
 
+        let mut keys = Vec::new();
         guard.execute("BEGIN", [])?;
         for (key, value) in iter {
             self.insert_with_guard(&guard, &key, &value)?;
+            keys.push(key);
         }
         guard.execute("COMMIT", [])?;
 
         drop(guard);
 
+        for key in keys {
+            self.watchers.wake(&key);
+        }
+
         Ok(())
     }
 
@@ -327,6 +343,14 @@ impl KvTree for SqliteTable {
         self.watchers.watch(prefix)
     }
 
+    fn count(&self) -> Result<u64> {
+        Ok(self
+            .engine
+            .read_lock()
+            .prepare(format!("SELECT count(*) FROM {}", self.name).as_str())?
+            .query_row([], |row| row.get(0))?)
+    }
+
     fn clear(&self) -> Result<()> {
         debug!("clear: running");
         self.engine
@@ -16,9 +16,12 @@ pub const LAST_CHECK_FOR_UPDATES_COUNT: &[u8] = b"u";
 
 #[async_trait]
 impl service::globals::Data for KeyValueDatabase {
-    fn next_count(&self) -> Result<u64> {
-        utils::u64_from_bytes(&self.global.increment(COUNTER)?)
-            .map_err(|_| Error::bad_database("Count has invalid bytes."))
+    fn reserve_counts(&self, amount: u64) -> Result<u64> {
+        let reserved = self.current_count()? + amount;
+        self.global.insert(COUNTER, &reserved.to_be_bytes())?;
+        self.flush_if_immediate()?;
+
+        Ok(reserved)
     }
 
     fn current_count(&self) -> Result<u64> {
@@ -63,6 +66,7 @@ impl service::globals::Data for KeyValueDatabase {
         futures.push(self.userroomid_joined.watch_prefix(&userid_prefix));
         futures.push(self.userroomid_invitestate.watch_prefix(&userid_prefix));
         futures.push(self.userroomid_leftstate.watch_prefix(&userid_prefix));
+        futures.push(self.userroomid_knockstate.watch_prefix(&userid_prefix));
         futures.push(
             self.userroomid_notificationcount
                 .watch_prefix(&userid_prefix),
@@ -97,6 +101,7 @@ impl service::globals::Data for KeyValueDatabase {
             futures.push(self.roomid_lasttypingupdate.watch_prefix(&roomid_bytes));
 
             futures.push(self.readreceiptid_readreceipt.watch_prefix(&roomid_prefix));
+            futures.push(self.presenceid_presence.watch_prefix(&roomid_prefix));
 
             // Key changes
             futures.push(self.keychangeid_userid.watch_prefix(&roomid_prefix));
@@ -122,9 +127,15 @@ impl service::globals::Data for KeyValueDatabase {
         // More key changes (used when user is not joined to any rooms)
         futures.push(self.keychangeid_userid.watch_prefix(&userid_prefix));
 
+        // Device list changes of users we share encrypted rooms with
+        futures.push(self.observerchangeid_userid.watch_prefix(&userid_prefix));
+
         // One time keys
         futures.push(self.userid_lastonetimekeyupdate.watch_prefix(&userid_bytes));
 
+        // Deleted key backup versions, clients should stop uploading to them
+        futures.push(self.userid_lastbackupdeletion.watch_prefix(&userid_bytes));
+
         futures.push(Box::pin(services().globals.rotate.watch()));
 
         // Wait until one of them finds something
@@ -137,6 +148,10 @@ impl service::globals::Data for KeyValueDatabase {
         self._db.cleanup()
     }
 
+    fn flush(&self) -> Result<()> {
+        self._db.flush()
+    }
+
     fn memory_usage(&self) -> String {
         let pdu_cache = self.pdu_cache.lock().unwrap().len();
         let shorteventid_cache = self.shorteventid_cache.lock().unwrap().len();
@@ -9,6 +9,10 @@ use ruma::{
     OwnedRoomId, RoomId, UserId,
 };
 
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
+use tracing::error;
+
 use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
 
 impl service::key_backups::Data for KeyValueDatabase {
@@ -17,10 +21,41 @@ impl service::key_backups::Data for KeyValueDatabase {
         user_id: &UserId,
         backup_metadata: &Raw<BackupAlgorithm>,
     ) -> Result<String> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        // Make room for the new version by pruning the oldest ones
+        let max_backups = services().globals.max_backups_per_user() as usize;
+        if max_backups > 0 {
+            let mut versions = self
+                .backupid_algorithm
+                .scan_prefix(prefix.clone())
+                .map(|(key, _)| {
+                    utils::string_from_bytes(&key[prefix.len()..])
+                        .map_err(|_| Error::bad_database("backupid_algorithm key is invalid."))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            // Versions are stored as decimal strings, so they don't sort numerically
+            versions.sort_by_key(|version| version.parse::<u64>().unwrap_or(0));
+
+            let excess = (versions.len() + 1).saturating_sub(max_backups);
+            for outdated_version in versions.into_iter().take(excess) {
+                if let Err(e) = self.delete_backup(user_id, &outdated_version) {
+                    error!(
+                        "Failed to prune key backup version {} of {}: {}",
+                        outdated_version, user_id, e
+                    );
+                    return Err(Error::bad_database(
+                        "Failed to prune the oldest key backup version.",
+                    ));
+                }
+            }
+        }
+
         let version = services().globals.next_count()?.to_string();
 
-        let mut key = user_id.as_bytes().to_vec();
-        key.push(0xff);
+        let mut key = prefix;
         key.extend_from_slice(version.as_bytes());
 
         self.backupid_algorithm.insert(
@@ -29,6 +64,7 @@ impl service::key_backups::Data for KeyValueDatabase {
         )?;
         self.backupid_etag
             .insert(&key, &services().globals.next_count()?.to_be_bytes())?;
+        self.flush_if_immediate()?;
         Ok(version)
     }
 
@@ -46,6 +82,13 @@ impl service::key_backups::Data for KeyValueDatabase {
             self.backupkeyid_backup.remove(&outdated_key)?;
         }
 
+        // Removals don't wake watchers, so wake the user's sync with an insert
+        self.userid_lastbackupdeletion.insert(
+            user_id.as_bytes(),
+            &services().globals.next_count()?.to_be_bytes(),
+        )?;
+        self.flush_if_immediate()?;
+
         Ok(())
     }
 
@@ -70,6 +113,7 @@ impl service::key_backups::Data for KeyValueDatabase {
             .insert(&key, backup_metadata.json().get().as_bytes())?;
         self.backupid_etag
             .insert(&key, &services().globals.next_count()?.to_be_bytes())?;
+        self.flush_if_immediate()?;
         Ok(version.to_owned())
     }
 
@@ -125,6 +169,13 @@ impl service::key_backups::Data for KeyValueDatabase {
             .transpose()
     }
 
+    fn get_backup_count(&self, user_id: &UserId) -> Result<usize> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Ok(self.backupid_algorithm.scan_prefix(prefix).count())
+    }
+
     fn get_backup(&self, user_id: &UserId, version: &str) -> Result<Option<Raw<BackupAlgorithm>>> {
         let mut key = user_id.as_bytes().to_vec();
         key.push(0xff);
@@ -145,7 +196,7 @@ impl service::key_backups::Data for KeyValueDatabase {
         room_id: &RoomId,
         session_id: &str,
         key_data: &Raw<KeyBackupData>,
-    ) -> Result<()> {
+    ) -> Result<String> {
         let mut key = user_id.as_bytes().to_vec();
         key.push(0xff);
         key.extend_from_slice(version.as_bytes());
@@ -157,18 +208,89 @@ impl service::key_backups::Data for KeyValueDatabase {
             ));
         }
 
-        self.backupid_etag
-            .insert(&key, &services().globals.next_count()?.to_be_bytes())?;
+        let mut session_key = key.clone();
+        session_key.push(0xff);
+        session_key.extend_from_slice(room_id.as_bytes());
+        session_key.push(0xff);
+        session_key.extend_from_slice(session_id.as_bytes());
+
+        // Keep the existing key if it is at least as good, the etag must not change then
+        if let Some(existing) = self.backupkeyid_backup.get(&session_key)? {
+            if !is_better_key(key_data, &existing) {
+                return self.get_etag(user_id, version);
+            }
+        }
 
+        let etag = services().globals.next_count()?;
+        self.backupid_etag.insert(&key, &etag.to_be_bytes())?;
+
+        self.backupkeyid_backup.insert(
+            &session_key,
+            &encode_key_data(key_data.json().get().as_bytes()),
+        )?;
+
+        self.flush_if_immediate()?;
+
+        Ok(etag.to_string())
+    }
+
+    fn add_keys(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        rooms: &BTreeMap<OwnedRoomId, RoomKeyBackup>,
+    ) -> Result<(usize, String)> {
+        let mut key = user_id.as_bytes().to_vec();
         key.push(0xff);
-        key.extend_from_slice(room_id.as_bytes());
-        key.push(0xff);
-        key.extend_from_slice(session_id.as_bytes());
+        key.extend_from_slice(version.as_bytes());
 
-        self.backupkeyid_backup
-            .insert(&key, key_data.json().get().as_bytes())?;
+        if self.backupid_algorithm.get(&key)?.is_none() {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Tried to update nonexistent backup.",
+            ));
+        }
 
-        Ok(())
+        let mut prefix = key.clone();
+        prefix.push(0xff);
+
+        let mut batch = Vec::new();
+        for (room_id, room) in rooms {
+            let mut room_prefix = prefix.clone();
+            room_prefix.extend_from_slice(room_id.as_bytes());
+            room_prefix.push(0xff);
+
+            for (session_id, key_data) in &room.sessions {
+                let mut session_key = room_prefix.clone();
+                session_key.extend_from_slice(session_id.as_bytes());
+
+                if let Some(existing) = self.backupkeyid_backup.get(&session_key)? {
+                    if !is_better_key(key_data, &existing) {
+                        continue;
+                    }
+                }
+
+                batch.push((
+                    session_key,
+                    encode_key_data(key_data.json().get().as_bytes()),
+                ));
+            }
+        }
+
+        let etag = if batch.is_empty() {
+            self.get_etag(user_id, version)?
+        } else {
+            let etag = services().globals.next_count()?;
+            self.backupid_etag.insert(&key, &etag.to_be_bytes())?;
+
+            self.backupkeyid_backup
+                .insert_batch(&mut batch.into_iter())?;
+            self.flush_if_immediate()?;
+
+            etag.to_string()
+        };
+
+        Ok((self.count_keys(user_id, version)?, etag))
     }
 
     fn count_keys(&self, user_id: &UserId, version: &str) -> Result<usize> {
@@ -194,60 +316,46 @@ impl service::key_backups::Data for KeyValueDatabase {
         .to_string())
     }
 
-    fn get_all(
-        &self,
+    fn iter_all<'a>(
+        &'a self,
         user_id: &UserId,
         version: &str,
-    ) -> Result<BTreeMap<OwnedRoomId, RoomKeyBackup>> {
+    ) -> Box<dyn Iterator<Item = Result<(OwnedRoomId, String, Raw<KeyBackupData>)>> + 'a> {
         let mut prefix = user_id.as_bytes().to_vec();
         prefix.push(0xff);
         prefix.extend_from_slice(version.as_bytes());
         prefix.push(0xff);
 
-        let mut rooms = BTreeMap::<OwnedRoomId, RoomKeyBackup>::new();
+        Box::new(
+            self.backupkeyid_backup
+                .scan_prefix(prefix)
+                .map(|(key, value)| {
+                    let mut parts = key.rsplit(|&b| b == 0xff);
 
-        for result in self
-            .backupkeyid_backup
-            .scan_prefix(prefix)
-            .map(|(key, value)| {
-                let mut parts = key.rsplit(|&b| b == 0xff);
-
-                let session_id =
-                    utils::string_from_bytes(parts.next().ok_or_else(|| {
+                    let session_id = utils::string_from_bytes(parts.next().ok_or_else(|| {
                         Error::bad_database("backupkeyid_backup key is invalid.")
                     })?)
                     .map_err(|_| {
                         Error::bad_database("backupkeyid_backup session_id is invalid.")
                     })?;
 
-                let room_id = RoomId::parse(
-                    utils::string_from_bytes(parts.next().ok_or_else(|| {
-                        Error::bad_database("backupkeyid_backup key is invalid.")
-                    })?)
-                    .map_err(|_| Error::bad_database("backupkeyid_backup room_id is invalid."))?,
-                )
-                .map_err(|_| {
-                    Error::bad_database("backupkeyid_backup room_id is invalid room id.")
-                })?;
-
-                let key_data = serde_json::from_slice(&value).map_err(|_| {
-                    Error::bad_database("KeyBackupData in backupkeyid_backup is invalid.")
-                })?;
+                    let room_id = RoomId::parse(
+                        utils::string_from_bytes(parts.next().ok_or_else(|| {
+                            Error::bad_database("backupkeyid_backup key is invalid.")
+                        })?)
+                        .map_err(|_| {
+                            Error::bad_database("backupkeyid_backup room_id is invalid.")
+                        })?,
+                    )
+                    .map_err(|_| {
+                        Error::bad_database("backupkeyid_backup room_id is invalid room id.")
+                    })?;
 
-                Ok::<_, Error>((room_id, session_id, key_data))
-            })
-        {
-            let (room_id, session_id, key_data) = result?;
-            rooms
-                .entry(room_id)
-                .or_insert_with(|| RoomKeyBackup {
-                    sessions: BTreeMap::new(),
-                })
-                .sessions
-                .insert(session_id, key_data);
-        }
+                    let key_data = decode_key_data(&value)?;
 
-        Ok(rooms)
+                    Ok::<_, Error>((room_id, session_id, key_data))
+                }),
+        )
     }
 
     fn get_room(
@@ -277,9 +385,7 @@ impl service::key_backups::Data for KeyValueDatabase {
                         Error::bad_database("backupkeyid_backup session_id is invalid.")
                     })?;
 
-                let key_data = serde_json::from_slice(&value).map_err(|_| {
-                    Error::bad_database("KeyBackupData in backupkeyid_backup is invalid.")
-                })?;
+                let key_data = decode_key_data(&value)?;
 
                 Ok::<_, Error>((session_id, key_data))
             })
@@ -304,40 +410,69 @@ impl service::key_backups::Data for KeyValueDatabase {
 
         self.backupkeyid_backup
             .get(&key)?
-            .map(|value| {
-                serde_json::from_slice(&value).map_err(|_| {
-                    Error::bad_database("KeyBackupData in backupkeyid_backup is invalid.")
-                })
-            })
+            .map(|value| decode_key_data(&value))
             .transpose()
     }
 
-    fn delete_all_keys(&self, user_id: &UserId, version: &str) -> Result<()> {
+    fn delete_all_keys(&self, user_id: &UserId, version: &str) -> Result<String> {
         let mut key = user_id.as_bytes().to_vec();
         key.push(0xff);
         key.extend_from_slice(version.as_bytes());
-        key.push(0xff);
 
-        for (outdated_key, _) in self.backupkeyid_backup.scan_prefix(key) {
+        if self.backupid_algorithm.get(&key)?.is_none() {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Tried to update nonexistent backup.",
+            ));
+        }
+
+        let mut prefix = key.clone();
+        prefix.push(0xff);
+
+        for (outdated_key, _) in self.backupkeyid_backup.scan_prefix(prefix) {
             self.backupkeyid_backup.remove(&outdated_key)?;
         }
 
-        Ok(())
+        let etag = services().globals.next_count()?;
+        self.backupid_etag.insert(&key, &etag.to_be_bytes())?;
+
+        self.flush_if_immediate()?;
+
+        Ok(etag.to_string())
     }
 
-    fn delete_room_keys(&self, user_id: &UserId, version: &str, room_id: &RoomId) -> Result<()> {
+    fn delete_room_keys(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        room_id: &RoomId,
+    ) -> Result<String> {
         let mut key = user_id.as_bytes().to_vec();
         key.push(0xff);
         key.extend_from_slice(version.as_bytes());
-        key.push(0xff);
-        key.extend_from_slice(room_id.as_bytes());
-        key.push(0xff);
 
-        for (outdated_key, _) in self.backupkeyid_backup.scan_prefix(key) {
+        if self.backupid_algorithm.get(&key)?.is_none() {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Tried to update nonexistent backup.",
+            ));
+        }
+
+        let mut prefix = key.clone();
+        prefix.push(0xff);
+        prefix.extend_from_slice(room_id.as_bytes());
+        prefix.push(0xff);
+
+        for (outdated_key, _) in self.backupkeyid_backup.scan_prefix(prefix) {
             self.backupkeyid_backup.remove(&outdated_key)?;
         }
 
-        Ok(())
+        let etag = services().globals.next_count()?;
+        self.backupid_etag.insert(&key, &etag.to_be_bytes())?;
+
+        self.flush_if_immediate()?;
+
+        Ok(etag.to_string())
     }
 
     fn delete_room_key(
@@ -346,19 +481,200 @@ impl service::key_backups::Data for KeyValueDatabase {
         version: &str,
         room_id: &RoomId,
         session_id: &str,
-    ) -> Result<()> {
+    ) -> Result<String> {
         let mut key = user_id.as_bytes().to_vec();
         key.push(0xff);
         key.extend_from_slice(version.as_bytes());
-        key.push(0xff);
-        key.extend_from_slice(room_id.as_bytes());
-        key.push(0xff);
-        key.extend_from_slice(session_id.as_bytes());
 
-        for (outdated_key, _) in self.backupkeyid_backup.scan_prefix(key) {
-            self.backupkeyid_backup.remove(&outdated_key)?;
+        if self.backupid_algorithm.get(&key)?.is_none() {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Tried to update nonexistent backup.",
+            ));
         }
 
-        Ok(())
+        let mut session_key = key.clone();
+        session_key.push(0xff);
+        session_key.extend_from_slice(room_id.as_bytes());
+        session_key.push(0xff);
+        session_key.extend_from_slice(session_id.as_bytes());
+
+        self.backupkeyid_backup.remove(&session_key)?;
+
+        let etag = services().globals.next_count()?;
+        self.backupid_etag.insert(&key, &etag.to_be_bytes())?;
+
+        self.flush_if_immediate()?;
+
+        Ok(etag.to_string())
+    }
+}
+
+/// Returns whether the uploaded key should replace the stored one.
+///
+/// Following the spec, a verified key beats an unverified one, then the key with the lower
+/// `first_message_index` wins and after that the one with the lower `forwarded_count`.
+fn is_better_key(new: &Raw<KeyBackupData>, existing: &[u8]) -> bool {
+    let existing = match key_data_rank(existing) {
+        Some(existing) => existing,
+        // Replace whatever we can't read anymore
+        None => return true,
+    };
+    let new = match key_data_rank(new.json().get().as_bytes()) {
+        Some(new) => new,
+        None => return false,
+    };
+
+    new < existing
+}
+
+/// The part of a stored key that decides which key wins, lower is better.
+fn key_data_rank(bytes: &[u8]) -> Option<(bool, u64, u64)> {
+    if let Some((is_verified, first_message_index, forwarded_count, _)) =
+        split_compact_key_data(bytes)
+    {
+        return Some((!is_verified, first_message_index, forwarded_count));
+    }
+
+    let fields = serde_json::from_slice::<KeyBackupDataFields<'_>>(bytes).ok()?;
+    Some((
+        !fields.is_verified,
+        fields.first_message_index,
+        fields.forwarded_count,
+    ))
+}
+
+/// Values in `backupkeyid_backup` starting with this byte use the compact encoding. Older values
+/// are plain JSON, which always starts with `{` for a KeyBackupData.
+const COMPACT_KEY_DATA: u8 = 0x01;
+
+#[derive(Deserialize)]
+struct KeyBackupDataFields<'a> {
+    first_message_index: u64,
+    forwarded_count: u64,
+    is_verified: bool,
+    #[serde(borrow)]
+    session_data: &'a RawJsonValue,
+}
+
+/// Encodes a KeyBackupData for `backupkeyid_backup`: the marker byte, `is_verified` as one byte,
+/// `first_message_index` and `forwarded_count` as big endian u64s and then the `session_data`
+/// JSON, which is encrypted and has to be returned to the client as is.
+///
+/// Values that can't be parsed (including values that are already compact) are kept unchanged.
+pub(in crate::database) fn encode_key_data(json: &[u8]) -> Vec<u8> {
+    let fields = match serde_json::from_slice::<KeyBackupDataFields<'_>>(json) {
+        Ok(fields) => fields,
+        Err(_) => return json.to_vec(),
+    };
+    let session_data = fields.session_data.get().as_bytes();
+
+    let mut bytes = Vec::with_capacity(1 + 1 + 8 + 8 + session_data.len());
+    bytes.push(COMPACT_KEY_DATA);
+    bytes.push(u8::from(fields.is_verified));
+    bytes.extend_from_slice(&fields.first_message_index.to_be_bytes());
+    bytes.extend_from_slice(&fields.forwarded_count.to_be_bytes());
+    bytes.extend_from_slice(session_data);
+    bytes
+}
+
+/// Splits a compact value into `is_verified`, `first_message_index`, `forwarded_count` and the
+/// `session_data` JSON. Returns None for JSON values.
+fn split_compact_key_data(bytes: &[u8]) -> Option<(bool, u64, u64, &[u8])> {
+    let rest = bytes.strip_prefix(&[COMPACT_KEY_DATA])?;
+    if rest.len() < 17 {
+        return None;
+    }
+    let (header, session_data) = rest.split_at(17);
+
+    Some((
+        header[0] != 0,
+        u64::from_be_bytes(header[1..9].try_into().ok()?),
+        u64::from_be_bytes(header[9..17].try_into().ok()?),
+        session_data,
+    ))
+}
+
+/// Reads a value of `backupkeyid_backup` in either the compact or the old JSON encoding.
+fn decode_key_data(bytes: &[u8]) -> Result<Raw<KeyBackupData>> {
+    let json = match split_compact_key_data(bytes) {
+        Some((is_verified, first_message_index, forwarded_count, session_data)) => format!(
+            r#"{{"first_message_index":{first_message_index},"forwarded_count":{forwarded_count},"is_verified":{is_verified},"session_data":{}}}"#,
+            utils::string_from_bytes(session_data).map_err(|_| {
+                Error::bad_database("KeyBackupData in backupkeyid_backup is invalid.")
+            })?
+        ),
+        None => utils::string_from_bytes(bytes)
+            .map_err(|_| Error::bad_database("KeyBackupData in backupkeyid_backup is invalid."))?,
+    };
+
+    RawJsonValue::from_string(json)
+        .map(Raw::from_json)
+        .map_err(|_| Error::bad_database("KeyBackupData in backupkeyid_backup is invalid."))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn key_data(index: u64) -> Vec<u8> {
+        format!(
+            r#"{{"first_message_index":{index},"forwarded_count":0,"is_verified":true,"session_data":{{"ciphertext":"{}","ephemeral":"base64+ephemeral+key","mac":"base64+mac"}}}}"#,
+            "a".repeat(200)
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn compact_key_data_round_trips() {
+        let json = key_data(7);
+        let compact = encode_key_data(&json);
+
+        assert_eq!(compact[0], COMPACT_KEY_DATA);
+        assert_eq!(encode_key_data(&compact), compact);
+        assert_eq!(
+            decode_key_data(&compact)
+                .unwrap()
+                .deserialize()
+                .unwrap()
+                .first_message_index,
+            7_u32.into()
+        );
+        assert_eq!(
+            decode_key_data(&json).unwrap().json().get().as_bytes(),
+            &json[..]
+        );
+    }
+
+    /// Decodes the values of a backup with 50k sessions like `get_all` does, in both encodings.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture get_all_decoding`.
+    #[test]
+    #[ignore]
+    fn bench_get_all_decoding() {
+        let json = (0..50_000).map(key_data).collect::<Vec<_>>();
+        let compact = json.iter().map(|v| encode_key_data(v)).collect::<Vec<_>>();
+
+        let start = Instant::now();
+        for value in &json {
+            serde_json::from_slice::<Raw<KeyBackupData>>(value).unwrap();
+        }
+        let json_time = start.elapsed();
+
+        let start = Instant::now();
+        for value in &compact {
+            decode_key_data(value).unwrap();
+        }
+        let compact_time = start.elapsed();
+
+        println!(
+            "JSON: {:?} for {} bytes, compact: {:?} for {} bytes",
+            json_time,
+            json.iter().map(Vec::len).sum::<usize>(),
+            compact_time,
+            compact.iter().map(Vec::len).sum::<usize>(),
+        );
     }
 }
@@ -1,4 +1,6 @@
-use ruma::api::client::error::ErrorKind;
+use std::mem;
+
+use ruma::{api::client::error::ErrorKind, OwnedUserId, UserId};
 
 use crate::{database::KeyValueDatabase, service, utils, Error, Result};
 
@@ -79,4 +81,123 @@ impl service::media::Data for KeyValueDatabase {
         };
         Ok((content_disposition, content_type, key))
     }
+
+    fn count_thumbnails(&self, mxc: String) -> Result<usize> {
+        let mut prefix = mxc.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut original = prefix.clone();
+        original.extend_from_slice(&0_u32.to_be_bytes());
+        original.extend_from_slice(&0_u32.to_be_bytes());
+        original.push(0xff);
+
+        Ok(self
+            .mediaid_file
+            .scan_prefix(prefix)
+            .filter(|(key, _)| !key.starts_with(&original))
+            .count())
+    }
+
+    fn iter_file_metadata<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(String, u32, u32, Vec<u8>)>> + 'a> {
+        Box::new(self.mediaid_file.iter().map(|(key, _)| {
+            let mxc_bytes = key
+                .split(|&b| b == 0xff)
+                .next()
+                .expect("split always returns one element");
+            let mxc = utils::string_from_bytes(mxc_bytes)
+                .map_err(|_| Error::bad_database("MXC in mediaid_file is invalid unicode."))?;
+
+            let dimensions = key
+                .get(mxc_bytes.len() + 1..mxc_bytes.len() + 9)
+                .ok_or_else(|| Error::bad_database("Media ID in db is invalid."))?;
+            let width = u32::from_be_bytes(dimensions[..4].try_into().expect("4 bytes"));
+            let height = u32::from_be_bytes(dimensions[4..].try_into().expect("4 bytes"));
+
+            Ok::<_, Error>((mxc, width, height, key))
+        }))
+    }
+
+    fn delete_file_metadata(&self, key: &[u8]) -> Result<()> {
+        self.mediaid_file.remove(key)
+    }
+
+    fn add_media_usage(&self, user_id: &UserId, mxc: &str, size: u64) -> Result<()> {
+        let mut value = user_id.as_bytes().to_vec();
+        value.push(0xff);
+        value.extend_from_slice(&size.to_be_bytes());
+        self.mxc_uploader.insert(mxc.as_bytes(), &value)?;
+
+        let usage = self.media_usage(user_id)?.saturating_add(size);
+        self.userid_mediausage
+            .insert(user_id.as_bytes(), &usage.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    fn media_uploader(&self, mxc: &str) -> Result<Option<OwnedUserId>> {
+        self.mxc_uploader
+            .get(mxc.as_bytes())?
+            .map(|value| parse_uploader(&value).map(|(user_id, _)| user_id))
+            .transpose()
+    }
+
+    fn remove_media_usage(&self, mxc: &str) -> Result<()> {
+        let value = match self.mxc_uploader.get(mxc.as_bytes())? {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        let (user_id, size) = parse_uploader(&value)?;
+
+        let usage = self.media_usage(&user_id)?.saturating_sub(size);
+        self.userid_mediausage
+            .insert(user_id.as_bytes(), &usage.to_be_bytes())?;
+        self.mxc_uploader.remove(mxc.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn media_usage(&self, user_id: &UserId) -> Result<u64> {
+        self.userid_mediausage
+            .get(user_id.as_bytes())?
+            .map_or(Ok(0), |bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Media usage in userid_mediausage is invalid.")
+                })
+            })
+    }
+
+    fn media_usages<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedUserId, u64)>> + 'a> {
+        Box::new(self.userid_mediausage.iter().map(|(user_id, usage)| {
+            Ok::<_, Error>((
+                UserId::parse(utils::string_from_bytes(&user_id).map_err(|_| {
+                    Error::bad_database("User ID in userid_mediausage is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in userid_mediausage is invalid."))?,
+                utils::u64_from_bytes(&usage).map_err(|_| {
+                    Error::bad_database("Media usage in userid_mediausage is invalid.")
+                })?,
+            ))
+        }))
+    }
+}
+
+/// Parses an mxc_uploader value into the uploader and the size of the file.
+fn parse_uploader(value: &[u8]) -> Result<(OwnedUserId, u64)> {
+    // The size is a fixed 8 bytes at the end, it can contain 0xff itself
+    let (user_id, size) = value
+        .len()
+        .checked_sub(mem::size_of::<u64>() + 1)
+        .map(|separator| (&value[..separator], &value[separator + 1..]))
+        .ok_or_else(|| Error::bad_database("Size in mxc_uploader is invalid."))?;
+    let size = utils::u64_from_bytes(size)
+        .map_err(|_| Error::bad_database("Size in mxc_uploader is invalid."))?;
+    let user_id = UserId::parse(
+        utils::string_from_bytes(user_id)
+            .map_err(|_| Error::bad_database("User ID in mxc_uploader is invalid unicode."))?,
+    )
+    .map_err(|_| Error::bad_database("User ID in mxc_uploader is invalid."))?;
+
+    Ok((user_id, size))
 }
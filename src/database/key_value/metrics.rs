@@ -0,0 +1,10 @@
+use crate::{database::KeyValueDatabase, service, Result};
+
+impl service::metrics::Data for KeyValueDatabase {
+    fn tree_sizes(&self) -> Result<Vec<(&'static str, u64)>> {
+        self.trees
+            .iter()
+            .map(|(name, tree)| Ok((*name, tree.count()?)))
+            .collect()
+    }
+}
@@ -2,12 +2,15 @@ mod account_data;
 //mod admin;
 mod appservice;
 mod globals;
-mod key_backups;
+pub(super) mod key_backups;
 mod media;
+mod metrics;
 //mod pdu;
 mod pusher;
 mod rooms;
 mod sending;
+mod server_notices;
+mod sso;
 mod transaction_ids;
 mod uiaa;
 mod users;
@@ -1,11 +1,19 @@
-use ruma::{api::client::error::ErrorKind, OwnedRoomAliasId, OwnedRoomId, RoomAliasId, RoomId};
+use ruma::{
+    api::client::error::ErrorKind, OwnedRoomAliasId, OwnedRoomId, OwnedUserId, RoomAliasId, RoomId,
+    UserId,
+};
 
 use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
 
 impl service::rooms::alias::Data for KeyValueDatabase {
-    fn set_alias(&self, alias: &RoomAliasId, room_id: &RoomId) -> Result<()> {
+    fn set_alias(&self, alias: &RoomAliasId, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+        // The alias may have pointed to another room before
+        self.remove_aliasid(alias)?;
+
         self.alias_roomid
             .insert(alias.alias().as_bytes(), room_id.as_bytes())?;
+        self.alias_userid
+            .insert(alias.alias().as_bytes(), user_id.as_bytes())?;
         let mut aliasid = room_id.as_bytes().to_vec();
         aliasid.push(0xff);
         aliasid.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
@@ -14,14 +22,10 @@ impl service::rooms::alias::Data for KeyValueDatabase {
     }
 
     fn remove_alias(&self, alias: &RoomAliasId) -> Result<()> {
-        if let Some(room_id) = self.alias_roomid.get(alias.alias().as_bytes())? {
-            let mut prefix = room_id.to_vec();
-            prefix.push(0xff);
-
-            for (key, _) in self.aliasid_alias.scan_prefix(prefix) {
-                self.aliasid_alias.remove(&key)?;
-            }
+        if self.alias_roomid.get(alias.alias().as_bytes())?.is_some() {
+            self.remove_aliasid(alias)?;
             self.alias_roomid.remove(alias.alias().as_bytes())?;
+            self.alias_userid.remove(alias.alias().as_bytes())?;
         } else {
             return Err(Error::BadRequest(
                 ErrorKind::NotFound,
@@ -43,6 +47,18 @@ impl service::rooms::alias::Data for KeyValueDatabase {
             .transpose()
     }
 
+    fn who_created_alias(&self, alias: &RoomAliasId) -> Result<Option<OwnedUserId>> {
+        self.alias_userid
+            .get(alias.alias().as_bytes())?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in alias_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in alias_userid is invalid."))
+            })
+            .transpose()
+    }
+
     fn local_aliases_for_room<'a>(
         &'a self,
         room_id: &RoomId,
@@ -58,3 +74,25 @@ impl service::rooms::alias::Data for KeyValueDatabase {
         }))
     }
 }
+
+impl KeyValueDatabase {
+    /// Removes the alias from the aliases of the room it points to, other aliases of the room
+    /// stay.
+    fn remove_aliasid(&self, alias: &RoomAliasId) -> Result<()> {
+        if let Some(room_id) = self.alias_roomid.get(alias.alias().as_bytes())? {
+            let mut prefix = room_id;
+            prefix.push(0xff);
+
+            for (key, _) in self
+                .aliasid_alias
+                .scan_prefix(prefix)
+                .filter(|(_, value)| value == alias.as_bytes())
+                .collect::<Vec<_>>()
+            {
+                self.aliasid_alias.remove(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -25,4 +25,53 @@ impl service::rooms::directory::Data for KeyValueDatabase {
             .map_err(|_| Error::bad_database("Room ID in publicroomids is invalid."))
         }))
     }
+
+    fn set_public_in_network(&self, network_id: &str, room_id: &RoomId) -> Result<()> {
+        let mut key = network_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(room_id.as_bytes());
+
+        self.networkid_publicroomid.insert(&key, &[])
+    }
+
+    fn set_not_public_in_network(&self, network_id: &str, room_id: &RoomId) -> Result<()> {
+        let mut key = network_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(room_id.as_bytes());
+
+        self.networkid_publicroomid.remove(&key)
+    }
+
+    fn network_public_rooms<'a>(
+        &'a self,
+        network_id: Option<&str>,
+    ) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
+        let prefix = network_id.map_or_else(Vec::new, |network_id| {
+            let mut prefix = network_id.as_bytes().to_vec();
+            prefix.push(0xff);
+            prefix
+        });
+
+        Box::new(
+            self.networkid_publicroomid
+                .scan_prefix(prefix)
+                .map(|(key, _)| {
+                    RoomId::parse(
+                        utils::string_from_bytes(
+                            key.rsplit(|&b| b == 0xff)
+                                .next()
+                                .expect("rsplit always returns an element"),
+                        )
+                        .map_err(|_| {
+                            Error::bad_database(
+                                "Room ID in networkid_publicroomid is invalid unicode.",
+                            )
+                        })?,
+                    )
+                    .map_err(|_| {
+                        Error::bad_database("Room ID in networkid_publicroomid is invalid.")
+                    })
+                }),
+        )
+    }
 }
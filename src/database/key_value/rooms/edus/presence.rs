@@ -4,15 +4,14 @@ use ruma::{
     events::presence::PresenceEvent, presence::PresenceState, OwnedUserId, RoomId, UInt, UserId,
 };
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, rooms::edus::presence::Presence},
+    services, utils, Error, Result,
+};
 
 impl service::rooms::edus::presence::Data for KeyValueDatabase {
-    fn update_presence(
-        &self,
-        user_id: &UserId,
-        room_id: &RoomId,
-        presence: PresenceEvent,
-    ) -> Result<()> {
+    fn update_presence(&self, room_id: &RoomId, presence: PresenceEvent) -> Result<()> {
         // TODO: Remove old entry? Or maybe just wipe completely from time to time?
 
         let count = services().globals.next_count()?.to_be_bytes();
@@ -28,50 +27,39 @@ impl service::rooms::edus::presence::Data for KeyValueDatabase {
             &serde_json::to_vec(&presence).expect("PresenceEvent can be serialized"),
         )?;
 
-        self.userid_lastpresenceupdate.insert(
-            user_id.as_bytes(),
-            &utils::millis_since_unix_epoch().to_be_bytes(),
-        )?;
-
-        Ok(())
-    }
-
-    fn ping_presence(&self, user_id: &UserId) -> Result<()> {
-        self.userid_lastpresenceupdate.insert(
-            user_id.as_bytes(),
-            &utils::millis_since_unix_epoch().to_be_bytes(),
-        )?;
-
         Ok(())
     }
 
-    fn last_presence_update(&self, user_id: &UserId) -> Result<Option<u64>> {
-        self.userid_lastpresenceupdate
+    fn get_presence(&self, user_id: &UserId) -> Result<Option<Presence>> {
+        self.userid_presence
             .get(user_id.as_bytes())?
             .map(|bytes| {
-                utils::u64_from_bytes(&bytes).map_err(|_| {
-                    Error::bad_database("Invalid timestamp in userid_lastpresenceupdate.")
-                })
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid presence in userid_presence."))
             })
             .transpose()
     }
 
-    fn get_presence_event(
-        &self,
-        room_id: &RoomId,
-        user_id: &UserId,
-        count: u64,
-    ) -> Result<Option<PresenceEvent>> {
-        let mut presence_id = room_id.as_bytes().to_vec();
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(&count.to_be_bytes());
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(user_id.as_bytes());
+    fn set_presence(&self, user_id: &UserId, presence: &Presence) -> Result<()> {
+        self.userid_presence.insert(
+            user_id.as_bytes(),
+            &serde_json::to_vec(presence).expect("Presence can be serialized"),
+        )
+    }
 
-        self.presenceid_presence
-            .get(&presence_id)?
-            .map(|value| parse_presence_event(&value))
-            .transpose()
+    fn presences<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedUserId, Presence)>> + 'a> {
+        Box::new(self.userid_presence.iter().map(|(key, value)| {
+            let user_id =
+                UserId::parse(utils::string_from_bytes(&key).map_err(|_| {
+                    Error::bad_database("Invalid UserId bytes in userid_presence.")
+                })?)
+                .map_err(|_| Error::bad_database("Invalid UserId in userid_presence."))?;
+
+            let presence = serde_json::from_slice(&value)
+                .map_err(|_| Error::bad_database("Invalid presence in userid_presence."))?;
+
+            Ok((user_id, presence))
+        }))
     }
 
     fn presence_since(
@@ -108,25 +96,6 @@ impl service::rooms::edus::presence::Data for KeyValueDatabase {
 
         Ok(hashmap)
     }
-
-    /*
-    fn presence_maintain(&self, db: Arc<TokioRwLock<Database>>) {
-        // TODO @M0dEx: move this to a timed tasks module
-        tokio::spawn(async move {
-            loop {
-                select! {
-                    Some(user_id) = self.presence_timers.next() {
-                        // TODO @M0dEx: would it be better to acquire the lock outside the loop?
-                        let guard = db.read().await;
-
-                        // TODO @M0dEx: add self.presence_timers
-                        // TODO @M0dEx: maintain presence
-                    }
-                }
-            }
-        });
-    }
-    */
 }
 
 fn parse_presence_event(bytes: &[u8]) -> Result<PresenceEvent> {
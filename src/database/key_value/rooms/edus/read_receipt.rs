@@ -1,7 +1,9 @@
-use std::mem;
+use std::{collections::HashSet, mem};
 
 use ruma::{
-    events::receipt::ReceiptEvent, serde::Raw, CanonicalJsonObject, OwnedUserId, RoomId, UserId,
+    events::receipt::{ReceiptEvent, ReceiptType},
+    serde::Raw,
+    CanonicalJsonObject, OwnedUserId, RoomId, UserId,
 };
 
 use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
@@ -16,22 +18,24 @@ impl service::rooms::edus::read_receipt::Data for KeyValueDatabase {
         let mut prefix = room_id.as_bytes().to_vec();
         prefix.push(0xff);
 
-        let mut last_possible_key = prefix.clone();
-        last_possible_key.extend_from_slice(&u64::MAX.to_be_bytes());
+        let new_types = receipt_types(&event);
 
-        // Remove old entry
-        if let Some((old, _)) = self
+        // Remove the old receipts of the user with the same type
+        for (old, _) in self
             .readreceiptid_readreceipt
-            .iter_from(&last_possible_key, true)
-            .take_while(|(key, _)| key.starts_with(&prefix))
-            .find(|(key, _)| {
+            .scan_prefix(prefix.clone())
+            .filter(|(key, _)| {
                 key.rsplit(|&b| b == 0xff)
                     .next()
                     .expect("rsplit always returns an element")
                     == user_id.as_bytes()
             })
+            .filter(|(_, value)| {
+                // Invalid receipts are replaced as well
+                serde_json::from_slice::<ReceiptEvent>(value)
+                    .map_or(true, |old| !new_types.is_disjoint(&receipt_types(&old)))
+            })
         {
-            // This is the old room_latest
             self.readreceiptid_readreceipt.remove(&old)?;
         }
 
@@ -148,3 +152,12 @@ impl service::rooms::edus::read_receipt::Data for KeyValueDatabase {
             .unwrap_or(0))
     }
 }
+
+fn receipt_types(event: &ReceiptEvent) -> HashSet<ReceiptType> {
+    event
+        .content
+        .0
+        .values()
+        .flat_map(|receipts| receipts.keys().cloned())
+        .collect()
+}
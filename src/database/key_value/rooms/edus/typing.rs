@@ -1,6 +1,6 @@
 use std::{collections::HashSet, mem};
 
-use ruma::{OwnedUserId, RoomId, UserId};
+use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
 
@@ -9,6 +9,15 @@ impl service::rooms::edus::typing::Data for KeyValueDatabase {
         let mut prefix = room_id.as_bytes().to_vec();
         prefix.push(0xff);
 
+        // The new timeout replaces the old one
+        for (key, _) in self
+            .typingid_userid
+            .scan_prefix(prefix.clone())
+            .filter(|(_, v)| &**v == user_id.as_bytes())
+        {
+            self.typingid_userid.remove(&key)?;
+        }
+
         let count = services().globals.next_count()?.to_be_bytes();
 
         let mut room_typing_id = prefix;
@@ -53,21 +62,22 @@ impl service::rooms::edus::typing::Data for KeyValueDatabase {
         Ok(())
     }
 
-    fn typings_maintain(&self, room_id: &RoomId) -> Result<()> {
+    fn typings_maintain(&self, room_id: &RoomId) -> Result<Vec<OwnedUserId>> {
         let mut prefix = room_id.as_bytes().to_vec();
         prefix.push(0xff);
 
         let current_timestamp = utils::millis_since_unix_epoch();
 
-        let mut found_outdated = false;
+        let mut stopped = Vec::new();
 
         // Find all outdated edus before inserting a new one
-        for outdated_edu in self
+        for (key, user_id, _) in self
             .typingid_userid
             .scan_prefix(prefix)
-            .map(|(key, _)| {
+            .map(|(key, user_id)| {
                 Ok::<_, Error>((
                     key.clone(),
+                    user_id,
                     utils::u64_from_bytes(
                         &key.splitn(2, |&b| b == 0xff).nth(1).ok_or_else(|| {
                             Error::bad_database("RoomTyping has invalid timestamp or delimiters.")
@@ -77,21 +87,47 @@ impl service::rooms::edus::typing::Data for KeyValueDatabase {
                 ))
             })
             .filter_map(|r| r.ok())
-            .take_while(|&(_, timestamp)| timestamp < current_timestamp)
+            .take_while(|&(_, _, timestamp)| timestamp < current_timestamp)
         {
             // This is an outdated edu (time > timestamp)
-            self.typingid_userid.remove(&outdated_edu.0)?;
-            found_outdated = true;
+            self.typingid_userid.remove(&key)?;
+
+            if let Some(user_id) = utils::string_from_bytes(&user_id)
+                .ok()
+                .and_then(|user_id| UserId::parse(user_id).ok())
+            {
+                stopped.push(user_id);
+            }
         }
 
-        if found_outdated {
+        if !stopped.is_empty() {
             self.roomid_lasttypingupdate.insert(
                 room_id.as_bytes(),
                 &services().globals.next_count()?.to_be_bytes(),
             )?;
         }
 
-        Ok(())
+        Ok(stopped)
+    }
+
+    fn typing_rooms(&self) -> Result<HashSet<OwnedRoomId>> {
+        let mut room_ids = HashSet::new();
+
+        for (key, _) in self.typingid_userid.iter() {
+            let room_id = key
+                .split(|&b| b == 0xff)
+                .next()
+                .expect("split always returns one element");
+
+            let room_id = RoomId::parse(utils::string_from_bytes(room_id).map_err(|_| {
+                Error::bad_database("Room ID in typingid_userid is invalid unicode.")
+            })?)
+            .map_err(|_| Error::bad_database("Room ID in typingid_userid is invalid."))?;
+
+            room_ids.insert(room_id);
+        }
+
+        Ok(room_ids)
     }
 
     fn last_typing_update(&self, room_id: &RoomId) -> Result<u64> {
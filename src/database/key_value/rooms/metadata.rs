@@ -1,6 +1,11 @@
+use std::{collections::HashSet, mem};
+
 use ruma::{OwnedRoomId, RoomId};
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use crate::{
+    database::{abstraction::KvTree, key_value::sending, KeyValueDatabase},
+    service, services, utils, Error, PduEvent, Result,
+};
 
 impl service::rooms::metadata::Data for KeyValueDatabase {
     fn exists(&self, room_id: &RoomId) -> Result<bool> {
@@ -42,4 +47,314 @@ impl service::rooms::metadata::Data for KeyValueDatabase {
 
         Ok(())
     }
+
+    fn is_blocked(&self, room_id: &RoomId) -> Result<bool> {
+        Ok(self.blockedroomids.get(room_id.as_bytes())?.is_some())
+    }
+
+    fn block_room(&self, room_id: &RoomId, blocked: bool) -> Result<()> {
+        if blocked {
+            self.blockedroomids.insert(room_id.as_bytes(), &[])?;
+        } else {
+            self.blockedroomids.remove(room_id.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn purge_room(&self, room_id: &RoomId) -> Result<u64> {
+        let room = room_id.as_bytes();
+        let mut prefix = room.to_vec();
+        prefix.push(0xff);
+
+        let mut freed = 0;
+
+        // Aliases, the local directory and server notices point to the room
+        let aliases = self
+            .alias_roomid
+            .iter()
+            .filter(|(_, v)| v == room)
+            .collect::<Vec<_>>();
+        for (alias, _) in &aliases {
+            freed += remove_key(&*self.alias_userid, alias)?;
+        }
+        freed += remove_entries(&*self.alias_roomid, aliases.into_iter())?;
+        for tree in [&self.networkid_publicroomid, &self.serverroomids] {
+            freed += remove_room_entries(&**tree, room, 1)?;
+        }
+        freed += remove_entries(
+            &*self.userid_servernoticeroomid,
+            self.userid_servernoticeroomid
+                .iter()
+                .filter(|(_, v)| v == room),
+        )?;
+
+        // The room lists of the users, even the ones whose membership in the room got lost
+        for tree in [
+            &self.userroomid_joined,
+            &self.userroomid_invitestate,
+            &self.userroomid_knockstate,
+            &self.userroomid_leftstate,
+            &self.userroomid_notificationcount,
+            &self.userroomid_highlightcount,
+        ] {
+            freed += remove_room_entries(&**tree, room, 1)?;
+        }
+        freed += remove_room_entries(&*self.lazyloadedids, room, 2)?;
+
+        for tree in [
+            &self.roomid_pduleaves,
+            &self.aliasid_alias,
+            &self.roomserverids,
+            &self.roomuserid_joined,
+            &self.roomuseroncejoinedids,
+            &self.roomuserid_invitecount,
+            &self.roomuserid_knockcount,
+            &self.roomuserid_leftcount,
+            &self.roomuserid_lastnotificationread,
+            &self.readreceiptid_readreceipt,
+            &self.roomuserid_privateread,
+            &self.roomuserid_lastprivatereadupdate,
+            &self.typingid_userid,
+            &self.presenceid_presence,
+            &self.roomuserdataid_accountdata,
+            &self.roomusertype_roomuserdataid,
+            &self.keychangeid_userid,
+            &self.reportid_report,
+        ] {
+            freed += remove_entries(&**tree, tree.scan_prefix(prefix.clone()))?;
+        }
+
+        // Event ids start with a $, so this doesn't match rooms whose id starts with this one
+        let mut references_prefix = room.to_vec();
+        references_prefix.push(b'$');
+        freed += remove_entries(
+            &*self.referencedevents,
+            self.referencedevents.scan_prefix(references_prefix),
+        )?;
+
+        let mut event_ids = HashSet::new();
+        let mut shortstatehashes = HashSet::new();
+
+        if let Some(shortstatehash) = self.roomid_shortstatehash.get(room)? {
+            shortstatehashes.insert(shortstatehash);
+        }
+
+        if let Some(shortroomid) = services().rooms.short.get_shortroomid(room_id)? {
+            let shortroomid = shortroomid.to_be_bytes();
+
+            let pdus = self
+                .pduid_pdu
+                .scan_prefix(shortroomid.to_vec())
+                .collect::<Vec<_>>();
+            for (pdu_id, pdu) in &pdus {
+                if let Ok(pdu) = serde_json::from_slice::<PduEvent>(pdu) {
+                    event_ids.insert(pdu.event_id.as_bytes().to_vec());
+                }
+
+                // Relations are keyed by the count of the target
+                let count = &pdu_id[pdu_id.len().saturating_sub(mem::size_of::<u64>())..];
+                freed += remove_entries(
+                    &*self.tofrom_relation,
+                    self.tofrom_relation.scan_prefix(count.to_vec()),
+                )?;
+            }
+            freed += remove_entries(&*self.pduid_pdu, pdus.into_iter())?;
+
+            shortstatehashes.extend(
+                self.roomsynctoken_shortstatehash
+                    .scan_prefix(shortroomid.to_vec())
+                    .map(|(_, v)| v),
+            );
+
+            for tree in [
+                &self.relationids,
+                &self.tokenids,
+                &self.threadid_userids,
+                &self.roomsynctoken_shortstatehash,
+            ] {
+                freed += remove_entries(&**tree, tree.scan_prefix(shortroomid.to_vec()))?;
+            }
+
+            for tree in [&self.servernameevent_data, &self.servercurrentevent_data] {
+                freed += remove_entries(
+                    &**tree,
+                    tree.iter()
+                        .filter(|(k, v)| sending::is_queued_pdu_of(k, v, &shortroomid)),
+                )?;
+            }
+
+            freed += remove_key(&*self.roomid_shortroomid, room)?;
+        }
+
+        let outliers = self
+            .eventid_outlierpdu
+            .iter()
+            .filter(|(_, pdu)| {
+                serde_json::from_slice::<PduEvent>(pdu)
+                    .map_or(false, |pdu| &*pdu.room_id == room_id)
+            })
+            .collect::<Vec<_>>();
+        event_ids.extend(outliers.iter().map(|(event_id, _)| event_id.clone()));
+        freed += remove_entries(&*self.eventid_outlierpdu, outliers.into_iter())?;
+
+        for event_id in &event_ids {
+            freed += remove_key(&*self.eventid_pduid, event_id)?;
+            freed += remove_key(&*self.softfailedeventids, event_id)?;
+
+            if let Some(shorteventid) = self.eventid_shorteventid.get(event_id)? {
+                if let Some(shortstatehash) = self.shorteventid_shortstatehash.get(&shorteventid)? {
+                    shortstatehashes.insert(shortstatehash);
+                }
+
+                freed += remove_key(&*self.shorteventid_shortstatehash, &shorteventid)?;
+                freed += remove_key(&*self.shorteventid_authchain, &shorteventid)?;
+                freed += remove_key(&*self.shorteventid_eventid, &shorteventid)?;
+                freed += remove_key(&*self.eventid_shorteventid, event_id)?;
+            }
+        }
+
+        // The state layers of the room, including the ones other layers build on. The empty
+        // state before the create events is shared by all rooms, so it is kept.
+        let mut room_layers = HashSet::new();
+        for shortstatehash in shortstatehashes {
+            let Ok(shortstatehash) = utils::u64_from_bytes(&shortstatehash) else {
+                continue;
+            };
+            let Ok(layers) = services()
+                .rooms
+                .state_compressor
+                .load_shortstatehash_info(shortstatehash)
+            else {
+                continue;
+            };
+
+            room_layers.extend(
+                layers
+                    .into_iter()
+                    .filter(|(_, full_state, _, _)| !full_state.is_empty())
+                    .map(|(shortstatehash, _, _, _)| shortstatehash.to_be_bytes().to_vec()),
+            );
+        }
+        freed += remove_entries(
+            &*self.statehash_shortstatehash,
+            self.statehash_shortstatehash
+                .iter()
+                .filter(|(_, v)| room_layers.contains(v)),
+        )?;
+        for shortstatehash in &room_layers {
+            freed += remove_key(&*self.shortstatehash_statediff, shortstatehash)?;
+        }
+
+        for tree in [
+            &self.roomid_shortstatehash,
+            &self.roomid_joinedcount,
+            &self.roomid_invitedcount,
+            &self.roomid_lasttypingupdate,
+            &self.publicroomids,
+            &self.disabledroomids,
+        ] {
+            freed += remove_key(&**tree, room)?;
+        }
+
+        // Cached events and short ids of the room would point to removed entries
+        self.pdu_cache.lock().unwrap().clear();
+        self.shorteventid_cache.lock().unwrap().clear();
+        self.eventidshort_cache.lock().unwrap().clear();
+        self.auth_chain_cache.lock().unwrap().clear();
+        self.our_real_users_cache.write().unwrap().remove(room_id);
+        self.appservice_in_room_cache
+            .write()
+            .unwrap()
+            .remove(room_id);
+        self.lasttimelinecount_cache.lock().unwrap().remove(room_id);
+
+        Ok(freed)
+    }
+}
+
+/// Returns the part of the key with this index, when splitting it at every 0xff.
+fn key_part(key: &[u8], index: usize) -> Option<&[u8]> {
+    key.split(|&b| b == 0xff).nth(index)
+}
+
+/// Removes the entries whose key has the room id as the part with this index and returns how many
+/// bytes they took up.
+fn remove_room_entries(tree: &dyn KvTree, room: &[u8], index: usize) -> Result<u64> {
+    remove_entries(
+        tree,
+        tree.iter()
+            .filter(|(k, _)| key_part(k, index) == Some(room)),
+    )
+}
+
+/// Removes the entries from the tree and returns how many bytes they took up.
+fn remove_entries(
+    tree: &dyn KvTree,
+    entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>,
+) -> Result<u64> {
+    // Collect first, some backends don't allow writing while iterating
+    let entries = entries.collect::<Vec<_>>();
+
+    let mut freed = 0;
+    for (key, value) in entries {
+        tree.remove(&key)?;
+        freed += (key.len() + value.len()) as u64;
+    }
+
+    Ok(freed)
+}
+
+/// Removes the key from the tree and returns how many bytes the entry took up.
+fn remove_key(tree: &dyn KvTree, key: &[u8]) -> Result<u64> {
+    remove_entries(
+        tree,
+        tree.get(key)?
+            .map(|value| (key.to_vec(), value))
+            .into_iter(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::abstraction::memory::MemoryTree;
+
+    fn user_room_key(user: &str, room: &str) -> Vec<u8> {
+        let mut key = user.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(room.as_bytes());
+        key
+    }
+
+    #[test]
+    fn purged_room_leaves_user_room_lists() {
+        let userroomid_joined = MemoryTree::default();
+        for (user, room) in [
+            ("@alice:a", "!purged:a"),
+            ("@alice:a", "!other:a"),
+            ("@bob:a", "!purged:a"),
+            // Starts like the purged room, but is another one
+            ("@bob:a", "!purged:ab"),
+        ] {
+            userroomid_joined
+                .insert(&user_room_key(user, room), &[])
+                .unwrap();
+        }
+
+        let freed = remove_room_entries(&userroomid_joined, b"!purged:a", 1).unwrap();
+
+        assert_eq!(
+            freed,
+            (user_room_key("@alice:a", "!purged:a").len()
+                + user_room_key("@bob:a", "!purged:a").len()) as u64
+        );
+        assert_eq!(
+            userroomid_joined.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+            vec![
+                user_room_key("@alice:a", "!other:a"),
+                user_room_key("@bob:a", "!purged:ab"),
+            ]
+        );
+    }
 }
@@ -16,6 +16,98 @@ impl service::rooms::pdu_metadata::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn add_typed_relation(
+        &self,
+        shortroomid: u64,
+        target: u64,
+        rel_type: &str,
+        from: u64,
+    ) -> Result<()> {
+        let mut key = shortroomid.to_be_bytes().to_vec();
+        key.extend_from_slice(&target.to_be_bytes());
+        key.extend_from_slice(rel_type.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(&from.to_be_bytes());
+        self.relationids.insert(&key, &[])
+    }
+
+    fn typed_relations<'a>(
+        &'a self,
+        shortroomid: u64,
+        target: u64,
+        rel_type: Option<&str>,
+    ) -> Box<dyn Iterator<Item = Result<(String, u64)>> + 'a> {
+        let mut prefix = shortroomid.to_be_bytes().to_vec();
+        prefix.extend_from_slice(&target.to_be_bytes());
+        let prefix_len = prefix.len();
+
+        if let Some(rel_type) = rel_type {
+            prefix.extend_from_slice(rel_type.as_bytes());
+            prefix.push(0xff);
+        }
+
+        Box::new(self.relationids.scan_prefix(prefix).map(move |(key, _)| {
+            // The count can contain 0xff, so we can't split on it
+            let count_start = key
+                .len()
+                .checked_sub(mem::size_of::<u64>())
+                .filter(|&start| start > prefix_len)
+                .ok_or_else(|| Error::bad_database("Invalid key in relationids."))?;
+
+            let rel_type = utils::string_from_bytes(&key[prefix_len..count_start - 1])
+                .map_err(|_| Error::bad_database("Invalid relation type in relationids."))?;
+            let from = utils::u64_from_bytes(&key[count_start..])
+                .map_err(|_| Error::bad_database("Invalid count in relationids."))?;
+
+            Ok((rel_type, from))
+        }))
+    }
+
+    fn remove_relations(
+        &self,
+        shortroomid: u64,
+        count: u64,
+        targets: &[(u64, Option<String>)],
+    ) -> Result<()> {
+        // Collect first, we can't remove entries while iterating over the tree
+        let relating = self
+            .tofrom_relation
+            .scan_prefix(count.to_be_bytes().to_vec())
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        for key in relating {
+            self.tofrom_relation.remove(&key)?;
+        }
+
+        let mut prefix = shortroomid.to_be_bytes().to_vec();
+        prefix.extend_from_slice(&count.to_be_bytes());
+        let relating = self
+            .relationids
+            .scan_prefix(prefix)
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        for key in relating {
+            self.relationids.remove(&key)?;
+        }
+
+        for (target, rel_type) in targets {
+            let mut key = target.to_be_bytes().to_vec();
+            key.extend_from_slice(&count.to_be_bytes());
+            self.tofrom_relation.remove(&key)?;
+
+            if let Some(rel_type) = rel_type {
+                let mut key = shortroomid.to_be_bytes().to_vec();
+                key.extend_from_slice(&target.to_be_bytes());
+                key.extend_from_slice(rel_type.as_bytes());
+                key.push(0xff);
+                key.extend_from_slice(&count.to_be_bytes());
+                self.relationids.remove(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn relations_until<'a>(
         &'a self,
         user_id: &'a UserId,
@@ -0,0 +1,39 @@
+use ruma::RoomId;
+
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, rooms::reports::Report},
+    services, Error, Result,
+};
+
+impl service::rooms::reports::Data for KeyValueDatabase {
+    fn add_report(&self, report: &Report) -> Result<()> {
+        let mut report_id = report.room_id.as_bytes().to_vec();
+        report_id.push(0xff);
+        report_id.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
+
+        self.reportid_report.insert(
+            &report_id,
+            &serde_json::to_vec(report).expect("Report can be serialized"),
+        )
+    }
+
+    fn reports<'a>(
+        &'a self,
+        room_id: Option<&RoomId>,
+    ) -> Box<dyn Iterator<Item = Result<Report>> + 'a> {
+        let iter = match room_id {
+            Some(room_id) => {
+                let mut prefix = room_id.as_bytes().to_vec();
+                prefix.push(0xff);
+                self.reportid_report.scan_prefix(prefix)
+            }
+            None => self.reportid_report.iter(),
+        };
+
+        Box::new(iter.map(|(_, value)| {
+            serde_json::from_slice(&value)
+                .map_err(|_| Error::bad_database("Invalid report in reportid_report."))
+        }))
+    }
+}
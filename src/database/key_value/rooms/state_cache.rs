@@ -1,13 +1,17 @@
 use std::{collections::HashSet, sync::Arc};
 
-use regex::Regex;
 use ruma::{
     events::{AnyStrippedStateEvent, AnySyncStateEvent},
     serde::Raw,
     OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
 
-use crate::{database::KeyValueDatabase, service, services, utils, Error, Result};
+use crate::{
+    database::{abstraction::KvTree, KeyValueDatabase},
+    service,
+    service::appservice::RegistrationInfo,
+    services, utils, Error, Result,
+};
 
 impl service::rooms::state_cache::Data for KeyValueDatabase {
     fn mark_as_once_joined(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
@@ -26,12 +30,19 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
         userroom_id.push(0xff);
         userroom_id.extend_from_slice(room_id.as_bytes());
 
-        self.userroomid_joined.insert(&userroom_id, &[])?;
-        self.roomuserid_joined.insert(&roomuser_id, &[])?;
+        set_joined(
+            &*self.userroomid_joined,
+            &*self.roomuserid_joined,
+            user_id,
+            room_id,
+            true,
+        )?;
         self.userroomid_invitestate.remove(&userroom_id)?;
         self.roomuserid_invitecount.remove(&roomuser_id)?;
         self.userroomid_leftstate.remove(&userroom_id)?;
         self.roomuserid_leftcount.remove(&roomuser_id)?;
+        self.userroomid_knockstate.remove(&userroom_id)?;
+        self.roomuserid_knockcount.remove(&roomuser_id)?;
 
         Ok(())
     }
@@ -59,8 +70,53 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
             &roomuser_id,
             &services().globals.next_count()?.to_be_bytes(),
         )?;
-        self.userroomid_joined.remove(&userroom_id)?;
-        self.roomuserid_joined.remove(&roomuser_id)?;
+        set_joined(
+            &*self.userroomid_joined,
+            &*self.roomuserid_joined,
+            user_id,
+            room_id,
+            false,
+        )?;
+        self.userroomid_leftstate.remove(&userroom_id)?;
+        self.roomuserid_leftcount.remove(&roomuser_id)?;
+        self.userroomid_knockstate.remove(&userroom_id)?;
+        self.roomuserid_knockcount.remove(&roomuser_id)?;
+
+        Ok(())
+    }
+
+    fn mark_as_knocked(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        last_state: Option<Vec<Raw<AnyStrippedStateEvent>>>,
+    ) -> Result<()> {
+        let mut roomuser_id = room_id.as_bytes().to_vec();
+        roomuser_id.push(0xff);
+        roomuser_id.extend_from_slice(user_id.as_bytes());
+
+        let mut userroom_id = user_id.as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.as_bytes());
+
+        self.userroomid_knockstate.insert(
+            &userroom_id,
+            &serde_json::to_vec(&last_state.unwrap_or_default())
+                .expect("state to bytes always works"),
+        )?;
+        self.roomuserid_knockcount.insert(
+            &roomuser_id,
+            &services().globals.next_count()?.to_be_bytes(),
+        )?;
+        set_joined(
+            &*self.userroomid_joined,
+            &*self.roomuserid_joined,
+            user_id,
+            room_id,
+            false,
+        )?;
+        self.userroomid_invitestate.remove(&userroom_id)?;
+        self.roomuserid_invitecount.remove(&roomuser_id)?;
         self.userroomid_leftstate.remove(&userroom_id)?;
         self.roomuserid_leftcount.remove(&roomuser_id)?;
 
@@ -84,10 +140,17 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
             &roomuser_id,
             &services().globals.next_count()?.to_be_bytes(),
         )?;
-        self.userroomid_joined.remove(&userroom_id)?;
-        self.roomuserid_joined.remove(&roomuser_id)?;
+        set_joined(
+            &*self.userroomid_joined,
+            &*self.roomuserid_joined,
+            user_id,
+            room_id,
+            false,
+        )?;
         self.userroomid_invitestate.remove(&userroom_id)?;
         self.roomuserid_invitecount.remove(&roomuser_id)?;
+        self.userroomid_knockstate.remove(&userroom_id)?;
+        self.roomuserid_knockcount.remove(&roomuser_id)?;
 
         Ok(())
     }
@@ -184,46 +247,28 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
     }
 
     #[tracing::instrument(skip(self, room_id, appservice))]
-    fn appservice_in_room(
-        &self,
-        room_id: &RoomId,
-        appservice: &(String, serde_yaml::Value),
-    ) -> Result<bool> {
+    fn appservice_in_room(&self, room_id: &RoomId, appservice: &RegistrationInfo) -> Result<bool> {
         let maybe = self
             .appservice_in_room_cache
             .read()
             .unwrap()
             .get(room_id)
-            .and_then(|map| map.get(&appservice.0))
+            .and_then(|map| map.get(&appservice.id))
             .copied();
 
         if let Some(b) = maybe {
             Ok(b)
-        } else if let Some(namespaces) = appservice.1.get("namespaces") {
-            let users = namespaces
-                .get("users")
-                .and_then(|users| users.as_sequence())
-                .map_or_else(Vec::new, |users| {
-                    users
-                        .iter()
-                        .filter_map(|users| Regex::new(users.get("regex")?.as_str()?).ok())
-                        .collect::<Vec<_>>()
-                });
-
-            let bridge_user_id = appservice
-                .1
-                .get("sender_localpart")
-                .and_then(|string| string.as_str())
-                .and_then(|string| {
-                    UserId::parse_with_server_name(string, services().globals.server_name()).ok()
-                });
+        } else {
+            let bridge_user_id = UserId::parse_with_server_name(
+                appservice.sender_localpart.as_str(),
+                services().globals.server_name(),
+            )
+            .ok();
 
             let in_room = bridge_user_id
                 .map_or(false, |id| self.is_joined(&id, room_id).unwrap_or(false))
                 || self.room_members(room_id).any(|userid| {
-                    userid.map_or(false, |userid| {
-                        users.iter().any(|r| r.is_match(userid.as_str()))
-                    })
+                    userid.map_or(false, |userid| appservice.users.is_match(userid.as_str()))
                 });
 
             self.appservice_in_room_cache
@@ -231,11 +276,9 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
                 .unwrap()
                 .entry(room_id.to_owned())
                 .or_default()
-                .insert(appservice.0.clone(), in_room);
+                .insert(appservice.id.clone(), in_room);
 
             Ok(in_room)
-        } else {
-            Ok(false)
         }
     }
 
@@ -430,6 +473,21 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
             })
     }
 
+    #[tracing::instrument(skip(self))]
+    fn get_knock_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+
+        self.roomuserid_knockcount
+            .get(&key)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid knockcount in db."))
+            })
+            .transpose()
+    }
+
     #[tracing::instrument(skip(self))]
     fn get_left_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
         let mut key = room_id.as_bytes().to_vec();
@@ -528,6 +586,64 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
             .transpose()
     }
 
+    /// Returns an iterator over all rooms a user knocked on.
+    #[allow(clippy::type_complexity)]
+    #[tracing::instrument(skip(self))]
+    fn rooms_knocked<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedRoomId, Vec<Raw<AnyStrippedStateEvent>>)>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Box::new(
+            self.userroomid_knockstate
+                .scan_prefix(prefix)
+                .map(|(key, state)| {
+                    let room_id = RoomId::parse(
+                        utils::string_from_bytes(
+                            key.rsplit(|&b| b == 0xff)
+                                .next()
+                                .expect("rsplit always returns an element"),
+                        )
+                        .map_err(|_| {
+                            Error::bad_database(
+                                "Room ID in userroomid_knockstate is invalid unicode.",
+                            )
+                        })?,
+                    )
+                    .map_err(|_| {
+                        Error::bad_database("Room ID in userroomid_knockstate is invalid.")
+                    })?;
+
+                    let state = serde_json::from_slice(&state).map_err(|_| {
+                        Error::bad_database("Invalid state in userroomid_knockstate.")
+                    })?;
+
+                    Ok((room_id, state))
+                }),
+        )
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn knock_state(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(room_id.as_bytes());
+
+        self.userroomid_knockstate
+            .get(&key)?
+            .map(|state| {
+                serde_json::from_slice(&state)
+                    .map_err(|_| Error::bad_database("Invalid state in userroomid_knockstate."))
+            })
+            .transpose()
+    }
+
     #[tracing::instrument(skip(self))]
     fn left_state(
         &self,
@@ -613,6 +729,15 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
         Ok(self.userroomid_invitestate.get(&userroom_id)?.is_some())
     }
 
+    #[tracing::instrument(skip(self))]
+    fn is_knocked(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
+        let mut userroom_id = user_id.as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.as_bytes());
+
+        Ok(self.userroomid_knockstate.get(&userroom_id)?.is_some())
+    }
+
     #[tracing::instrument(skip(self))]
     fn is_left(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
         let mut userroom_id = user_id.as_bytes().to_vec();
@@ -622,3 +747,149 @@ impl service::rooms::state_cache::Data for KeyValueDatabase {
         Ok(self.userroomid_leftstate.get(&userroom_id)?.is_some())
     }
 }
+
+/// Adds the user to or removes them from the joined members index of the room and the joined
+/// rooms index of the user.
+fn set_joined(
+    userroomid_joined: &dyn KvTree,
+    roomuserid_joined: &dyn KvTree,
+    user_id: &UserId,
+    room_id: &RoomId,
+    joined: bool,
+) -> Result<()> {
+    let mut roomuser_id = room_id.as_bytes().to_vec();
+    roomuser_id.push(0xff);
+    roomuser_id.extend_from_slice(user_id.as_bytes());
+
+    let mut userroom_id = user_id.as_bytes().to_vec();
+    userroom_id.push(0xff);
+    userroom_id.extend_from_slice(room_id.as_bytes());
+
+    if joined {
+        userroomid_joined.insert(&userroom_id, &[])?;
+        roomuserid_joined.insert(&roomuser_id, &[])?;
+    } else {
+        userroomid_joined.remove(&userroom_id)?;
+        roomuserid_joined.remove(&roomuser_id)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use ruma::{events::room::member::MembershipState, room_id, user_id, OwnedRoomId};
+
+    use super::*;
+    use crate::database::abstraction::memory::MemoryTree;
+
+    fn keys(tree: &MemoryTree) -> Vec<Vec<u8>> {
+        tree.iter().map(|(k, _)| k).collect()
+    }
+
+    fn key(a: &str, b: &str) -> Vec<u8> {
+        let mut key = a.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(b.as_bytes());
+        key
+    }
+
+    #[test]
+    fn set_joined_updates_both_indexes() {
+        let userroomid_joined = MemoryTree::default();
+        let roomuserid_joined = MemoryTree::default();
+        let (room, other) = (room_id!("!room:a"), room_id!("!other:a"));
+        let (alice, bob) = (user_id!("@alice:a"), user_id!("@bob:a"));
+
+        set_joined(&userroomid_joined, &roomuserid_joined, alice, room, true).unwrap();
+        set_joined(&userroomid_joined, &roomuserid_joined, alice, other, true).unwrap();
+        set_joined(&userroomid_joined, &roomuserid_joined, bob, room, true).unwrap();
+        set_joined(&userroomid_joined, &roomuserid_joined, bob, room, false).unwrap();
+
+        assert_eq!(
+            keys(&userroomid_joined),
+            vec![key("@alice:a", "!other:a"), key("@alice:a", "!room:a")]
+        );
+        assert_eq!(
+            keys(&roomuserid_joined),
+            vec![key("!other:a", "@alice:a"), key("!room:a", "@alice:a")]
+        );
+
+        // Removing a user that isn't joined changes nothing
+        set_joined(&userroomid_joined, &roomuserid_joined, bob, other, false).unwrap();
+        assert_eq!(keys(&userroomid_joined).len(), 2);
+        assert_eq!(keys(&roomuserid_joined).len(), 2);
+    }
+
+    #[cfg(feature = "persy")]
+    #[tokio::test]
+    async fn joined_indexes_follow_membership_and_purge() {
+        crate::database::load_temporary_database().await;
+
+        let state_cache = &services().rooms.state_cache;
+        let room = room_id!("!joined-indexes:test.local");
+        let other = room_id!("!joined-indexes-other:test.local");
+        let (alice, bob, carol) = (
+            user_id!("@alice:test.local"),
+            user_id!("@bob:test.local"),
+            user_id!("@carol:test.local"),
+        );
+
+        let members = |room_id| {
+            state_cache
+                .room_members(room_id)
+                .map(|r| r.unwrap())
+                .collect::<BTreeSet<_>>()
+        };
+        let rooms = |user_id| {
+            state_cache
+                .rooms_joined(user_id)
+                .map(|r| r.unwrap())
+                .collect::<BTreeSet<OwnedRoomId>>()
+        };
+
+        for user_id in [alice, bob, carol] {
+            state_cache
+                .update_membership(room, user_id, MembershipState::Join, None, true)
+                .unwrap();
+        }
+        state_cache
+            .update_membership(other, alice, MembershipState::Join, None, true)
+            .unwrap();
+
+        // A kick is a leave sent by someone else, this layer doesn't tell them apart
+        state_cache
+            .update_membership(room, bob, MembershipState::Leave, None, true)
+            .unwrap();
+        state_cache
+            .update_membership(room, carol, MembershipState::Ban, None, true)
+            .unwrap();
+
+        assert_eq!(members(room), BTreeSet::from([alice.to_owned()]));
+        assert_eq!(
+            rooms(alice),
+            BTreeSet::from([room.to_owned(), other.to_owned()])
+        );
+        assert!(rooms(bob).is_empty());
+        assert!(rooms(carol).is_empty());
+        assert_eq!(state_cache.room_joined_count(room).unwrap(), Some(1));
+
+        state_cache
+            .update_membership(room, bob, MembershipState::Join, None, true)
+            .unwrap();
+        assert_eq!(
+            members(room),
+            BTreeSet::from([alice.to_owned(), bob.to_owned()])
+        );
+        assert_eq!(rooms(bob), BTreeSet::from([room.to_owned()]));
+
+        services().rooms.metadata.purge_room(room).await.unwrap();
+
+        assert!(members(room).is_empty());
+        assert_eq!(rooms(alice), BTreeSet::from([other.to_owned()]));
+        assert!(rooms(bob).is_empty());
+        assert_eq!(members(other), BTreeSet::from([alice.to_owned()]));
+    }
+}
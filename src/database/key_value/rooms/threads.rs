@@ -75,4 +75,8 @@ impl service::rooms::threads::Data for KeyValueDatabase {
             Ok(None)
         }
     }
+
+    fn remove_thread(&self, root_id: &[u8]) -> Result<()> {
+        self.threadid_userids.remove(root_id)
+    }
 }
@@ -221,6 +221,40 @@ impl service::rooms::timeline::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn purge_pdu(
+        &self,
+        pdu_id: &[u8],
+        event_id: &EventId,
+        redacted_json: &CanonicalJsonObject,
+    ) -> Result<()> {
+        self.eventid_outlierpdu.insert(
+            event_id.as_bytes(),
+            &serde_json::to_vec(redacted_json).expect("CanonicalJsonObject is always a valid"),
+        )?;
+        self.eventid_pduid.remove(event_id.as_bytes())?;
+        self.pduid_pdu.remove(pdu_id)?;
+
+        self.pdu_cache.lock().unwrap().remove(event_id);
+
+        Ok(())
+    }
+
+    fn add_count_checkpoint(&self, ts: u64, count: u64) -> Result<()> {
+        self.timestamp_count
+            .insert(&ts.to_be_bytes(), &count.to_be_bytes())
+    }
+
+    fn count_at(&self, ts: u64) -> Result<Option<u64>> {
+        self.timestamp_count
+            .iter_from(&ts.to_be_bytes(), true)
+            .next()
+            .map(|(_, count)| {
+                utils::u64_from_bytes(&count)
+                    .map_err(|_| Error::bad_database("Invalid count in timestamp_count."))
+            })
+            .transpose()
+    }
+
     /// Returns an iterator over all events and their tokens in a room that happened before the
     /// event with id `until` in reverse-chronological order.
     fn pdus_until<'a>(
@@ -1,10 +1,12 @@
+use std::mem;
+
 use ruma::{ServerName, UserId};
 
 use crate::{
     database::KeyValueDatabase,
     service::{
         self,
-        sending::{OutgoingKind, SendingEventType},
+        sending::{Backoff, OutgoingKind, SendingEventType},
     },
     services, utils, Error, Result,
 };
@@ -45,6 +47,16 @@ impl service::sending::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn has_queued_pdus(&self, shortroomid: u64) -> Result<bool> {
+        let shortroomid = shortroomid.to_be_bytes();
+
+        Ok(self
+            .servernameevent_data
+            .iter()
+            .chain(self.servercurrentevent_data.iter())
+            .any(|(key, value)| is_queued_pdu_of(&key, &value, &shortroomid)))
+    }
+
     fn delete_all_requests_for(&self, outgoing_kind: &OutgoingKind) -> Result<()> {
         let prefix = outgoing_kind.get_prefix();
         for (key, _) in self.servercurrentevent_data.scan_prefix(prefix.clone()) {
@@ -110,21 +122,59 @@ impl service::sending::Data for KeyValueDatabase {
         Ok(())
     }
 
-    fn set_latest_educount(&self, server_name: &ServerName, last_count: u64) -> Result<()> {
-        self.servername_educount
-            .insert(server_name.as_bytes(), &last_count.to_be_bytes())
+    fn backoffs<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OutgoingKind, Backoff)>> + 'a> {
+        Box::new(self.outgoingkind_backoff.iter().map(|(key, value)| {
+            // The key is the prefix of the destination, which parses like an event key without
+            // the event
+            let (outgoing_kind, _) = parse_servercurrentevent(&key, Vec::new())?;
+
+            if value.len() != 20 {
+                return Err(Error::bad_database(
+                    "Invalid backoff in outgoingkind_backoff.",
+                ));
+            }
+            let failures = u32::from_be_bytes(value[0..4].try_into().expect("we checked the size"));
+            let last_failure = utils::u64_from_bytes(&value[4..12])
+                .map_err(|_| Error::bad_database("Invalid backoff in outgoingkind_backoff."))?;
+            let next_attempt = utils::u64_from_bytes(&value[12..20])
+                .map_err(|_| Error::bad_database("Invalid backoff in outgoingkind_backoff."))?;
+
+            Ok((
+                outgoing_kind,
+                Backoff {
+                    failures,
+                    last_failure,
+                    next_attempt,
+                },
+            ))
+        }))
     }
 
-    fn get_latest_educount(&self, server_name: &ServerName) -> Result<u64> {
-        self.servername_educount
-            .get(server_name.as_bytes())?
-            .map_or(Ok(0), |bytes| {
-                utils::u64_from_bytes(&bytes)
-                    .map_err(|_| Error::bad_database("Invalid u64 in servername_educount."))
-            })
+    fn set_backoff(&self, outgoing_kind: &OutgoingKind, backoff: &Backoff) -> Result<()> {
+        let mut value = backoff.failures.to_be_bytes().to_vec();
+        value.extend_from_slice(&backoff.last_failure.to_be_bytes());
+        value.extend_from_slice(&backoff.next_attempt.to_be_bytes());
+
+        self.outgoingkind_backoff
+            .insert(&outgoing_kind.get_prefix(), &value)
+    }
+
+    fn remove_backoff(&self, outgoing_kind: &OutgoingKind) -> Result<()> {
+        self.outgoingkind_backoff
+            .remove(&outgoing_kind.get_prefix())
     }
 }
 
+/// Checks if the queued request is a PDU of the room. PDU requests end with the pdu id, EDUs are
+/// not bound to a room.
+pub(super) fn is_queued_pdu_of(key: &[u8], value: &[u8], shortroomid: &[u8]) -> bool {
+    let pdu_id_start = key.len().saturating_sub(2 * mem::size_of::<u64>());
+    value.is_empty()
+        && pdu_id_start > 0
+        && key[pdu_id_start - 1] == 0xff
+        && key[pdu_id_start..].starts_with(shortroomid)
+}
+
 #[tracing::instrument(skip(key))]
 fn parse_servercurrentevent(
     key: &[u8],
@@ -160,18 +210,18 @@ fn parse_servercurrentevent(
         let user_id = UserId::parse(user_string)
             .map_err(|_| Error::bad_database("Invalid user id in servercurrentevent"))?;
 
-        let pushkey = parts
+        let gateway = parts
             .next()
             .ok_or_else(|| Error::bad_database("Invalid bytes in servercurrentpdus."))?;
-        let pushkey_string = utils::string_from_bytes(pushkey)
-            .map_err(|_| Error::bad_database("Invalid pushkey in servercurrentevent"))?;
+        let gateway_string = utils::string_from_bytes(gateway)
+            .map_err(|_| Error::bad_database("Invalid push gateway in servercurrentevent"))?;
 
         let event = parts
             .next()
             .ok_or_else(|| Error::bad_database("Invalid bytes in servercurrentpdus."))?;
 
         (
-            OutgoingKind::Push(user_id, pushkey_string),
+            OutgoingKind::Push(user_id, gateway_string),
             if value.is_empty() {
                 SendingEventType::Pdu(event.to_vec())
             } else {
@@ -1,6 +1,8 @@
+use std::mem::size_of;
+
 use ruma::{DeviceId, TransactionId, UserId};
 
-use crate::{database::KeyValueDatabase, service, Result};
+use crate::{database::KeyValueDatabase, service, utils, Error, Result};
 
 impl service::transaction_ids::Data for KeyValueDatabase {
     fn add_txnid(
@@ -18,6 +20,11 @@ impl service::transaction_ids::Data for KeyValueDatabase {
 
         self.userdevicetxnid_response.insert(&key, data)?;
 
+        let mut timestamp_key = utils::millis_since_unix_epoch().to_be_bytes().to_vec();
+        timestamp_key.extend_from_slice(&key);
+        self.timestamptxnid_userdevicetxnid
+            .insert(&timestamp_key, &[])?;
+
         Ok(())
     }
 
@@ -36,4 +43,27 @@ impl service::transaction_ids::Data for KeyValueDatabase {
         // If there's no entry, this is a new transaction
         self.userdevicetxnid_response.get(&key)
     }
+
+    fn remove_txnids_older_than(&self, ts: u64) -> Result<u64> {
+        let mut removed = 0;
+
+        // Sorted by timestamp, so the old ones come first
+        for (timestamp_key, _) in self.timestamptxnid_userdevicetxnid.iter() {
+            let timestamp = timestamp_key
+                .get(..size_of::<u64>())
+                .map(utils::u64_from_bytes)
+                .ok_or_else(|| Error::bad_database("Invalid timestamp in timestamptxnid."))?
+                .map_err(|_| Error::bad_database("Invalid timestamp in timestamptxnid."))?;
+            if timestamp >= ts {
+                break;
+            }
+
+            self.userdevicetxnid_response
+                .remove(&timestamp_key[size_of::<u64>()..])?;
+            self.timestamptxnid_userdevicetxnid.remove(&timestamp_key)?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
 }
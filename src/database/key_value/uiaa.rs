@@ -3,7 +3,11 @@ use ruma::{
     CanonicalJsonValue, DeviceId, UserId,
 };
 
-use crate::{database::KeyValueDatabase, service, Error, Result};
+use crate::{
+    database::KeyValueDatabase,
+    service::{self, uiaa::RegistrationTokenInfo},
+    utils, Error, Result,
+};
 
 impl service::uiaa::Data for KeyValueDatabase {
     fn set_uiaa_request(
@@ -86,4 +90,40 @@ impl service::uiaa::Data for KeyValueDatabase {
         )
         .map_err(|_| Error::bad_database("UiaaInfo in userdeviceid_uiaainfo is invalid."))
     }
+
+    fn set_registration_token(&self, token: &str, info: &RegistrationTokenInfo) -> Result<()> {
+        self.registrationtoken_info.insert(
+            token.as_bytes(),
+            &serde_json::to_vec(info).expect("RegistrationTokenInfo::to_vec always works"),
+        )
+    }
+
+    fn registration_token(&self, token: &str) -> Result<Option<RegistrationTokenInfo>> {
+        self.registrationtoken_info
+            .get(token.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Info in registrationtoken_info is invalid."))
+            })
+            .transpose()
+    }
+
+    fn remove_registration_token(&self, token: &str) -> Result<()> {
+        self.registrationtoken_info.remove(token.as_bytes())
+    }
+
+    fn registration_tokens<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(String, RegistrationTokenInfo)>> + 'a> {
+        Box::new(self.registrationtoken_info.iter().map(|(token, bytes)| {
+            Ok((
+                utils::string_from_bytes(&token).map_err(|_| {
+                    Error::bad_database("Token in registrationtoken_info is invalid unicode.")
+                })?,
+                serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Info in registrationtoken_info is invalid.")
+                })?,
+            ))
+        }))
+    }
 }
@@ -1,10 +1,14 @@
-use std::{collections::BTreeMap, mem::size_of};
+use std::{
+    collections::{BTreeMap, HashSet},
+    mem::size_of,
+};
 
 use ruma::{
     api::client::{device::Device, error::ErrorKind, filter::FilterDefinition},
-    encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
+    encryption::{CrossSigningKey, DeviceKeys, KeyUsage, OneTimeKey},
     events::{AnyToDeviceEvent, StateEventType},
     serde::Raw,
+    thirdparty::{Medium, ThirdPartyIdentifier},
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
     OwnedDeviceKeyId, OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
@@ -12,7 +16,10 @@ use tracing::warn;
 
 use crate::{
     database::KeyValueDatabase,
-    service::{self, users::clean_signatures},
+    service::{
+        self,
+        users::{self, clean_signatures, DeviceLastSeen, DeviceListChange, DirectoryMatch},
+    },
     services, utils, Error, Result,
 };
 
@@ -101,21 +108,52 @@ impl service::users::Data for KeyValueDatabase {
 
     /// Hash and set the user's password to the Argon2 hash
     fn set_password(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
+        let new_user = self.userid_password.get(user_id.as_bytes())?.is_none();
+
         if let Some(password) = password {
             if let Ok(hash) = utils::calculate_password_hash(password) {
                 self.userid_password
                     .insert(user_id.as_bytes(), hash.as_bytes())?;
-                Ok(())
             } else {
-                Err(Error::BadRequest(
+                return Err(Error::BadRequest(
                     ErrorKind::InvalidParam,
                     "Password does not meet the requirements.",
-                ))
+                ));
             }
         } else {
             self.userid_password.insert(user_id.as_bytes(), b"")?;
-            Ok(())
         }
+
+        // New users can be found by their localpart right away
+        if new_user {
+            self.update_user_directory(user_id, None, None)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_guest(&self, user_id: &UserId, is_guest: bool) -> Result<()> {
+        if is_guest {
+            self.userid_guest.insert(user_id.as_bytes(), &[])
+        } else {
+            self.userid_guest.remove(user_id.as_bytes())
+        }
+    }
+
+    fn is_guest(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.userid_guest.get(user_id.as_bytes())?.is_some())
+    }
+
+    fn block_user(&self, user_id: &UserId, blocked: bool) -> Result<()> {
+        if blocked {
+            self.blockeduserids.insert(user_id.as_bytes(), &[])
+        } else {
+            self.blockeduserids.remove(user_id.as_bytes())
+        }
+    }
+
+    fn is_blocked(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.blockeduserids.get(user_id.as_bytes())?.is_some())
     }
 
     /// Returns the displayname of a user on this homeserver.
@@ -131,6 +169,9 @@ impl service::users::Data for KeyValueDatabase {
 
     /// Sets a new displayname or removes it if displayname is None. You still need to nofify all rooms of this change.
     fn set_displayname(&self, user_id: &UserId, displayname: Option<String>) -> Result<()> {
+        let old_displayname = self.displayname(user_id)?;
+        self.update_user_directory(user_id, old_displayname.as_deref(), displayname.as_deref())?;
+
         if let Some(displayname) = displayname {
             self.userid_displayname
                 .insert(user_id.as_bytes(), displayname.as_bytes())?;
@@ -141,6 +182,44 @@ impl service::users::Data for KeyValueDatabase {
         Ok(())
     }
 
+    fn remove_from_user_directory(&self, user_id: &UserId) -> Result<()> {
+        let displayname = self.displayname(user_id)?;
+
+        for suffix in directory_entries(user_id, displayname.as_deref()).keys() {
+            self.directorytoken_userid
+                .remove(&directory_key(suffix, user_id))?;
+        }
+
+        Ok(())
+    }
+
+    fn search_directory<'a>(
+        &'a self,
+        word: &str,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, u8)>> + 'a> {
+        Box::new(
+            self.directorytoken_userid
+                .scan_prefix(word.as_bytes().to_vec())
+                .map(|(key, flags)| {
+                    let user_bytes = key
+                        .splitn(2, |&b| b == 0xff)
+                        .nth(1)
+                        .ok_or_else(|| Error::bad_database("Invalid directorytoken_userid key."))?;
+                    let user_id =
+                        UserId::parse(utils::string_from_bytes(user_bytes).map_err(|_| {
+                            Error::bad_database(
+                                "User ID in directorytoken_userid is invalid unicode.",
+                            )
+                        })?)
+                        .map_err(|_| {
+                            Error::bad_database("User ID in directorytoken_userid is invalid.")
+                        })?;
+
+                    Ok::<_, Error>((user_id, flags.first().copied().unwrap_or_default()))
+                }),
+        )
+    }
+
     /// Get the avatar_url of a user.
     fn avatar_url(&self, user_id: &UserId) -> Result<Option<OwnedMxcUri>> {
         self.userid_avatarurl
@@ -240,15 +319,23 @@ impl service::users::Data for KeyValueDatabase {
         let mut prefix = userdeviceid.clone();
         prefix.push(0xff);
 
-        for (key, _) in self.todeviceid_events.scan_prefix(prefix) {
+        for (key, _) in self.todeviceid_events.scan_prefix(prefix.clone()) {
             self.todeviceid_events.remove(&key)?;
         }
 
+        for (key, _) in self.userdevicealgorithm_fallbackkey.scan_prefix(prefix) {
+            self.userdevicealgorithm_fallbackkey.remove(&key)?;
+        }
+
         // TODO: Remove onetimekeys
 
         self.userid_devicelistversion
             .increment(user_id.as_bytes())?;
 
+        self.userdeviceid_devicelistsince.remove(&userdeviceid)?;
+        self.userdeviceid_softlogout.remove(&userdeviceid)?;
+        self.userdeviceid_lastseen.remove(&userdeviceid)?;
+
         self.userdeviceid_metadata.remove(&userdeviceid)?;
 
         Ok(())
@@ -300,9 +387,96 @@ impl service::users::Data for KeyValueDatabase {
         self.token_userdeviceid
             .insert(token.as_bytes(), &userdeviceid)?;
 
+        // The new token is valid again
+        self.userdeviceid_softlogout.remove(&userdeviceid)?;
+
         Ok(())
     }
 
+    fn soft_logout_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_softlogout.insert(&userdeviceid, &[])
+    }
+
+    fn set_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        last_seen: &DeviceLastSeen,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        let mut value = u64::from(last_seen.ts.get()).to_be_bytes().to_vec();
+        value.extend_from_slice(last_seen.ip.as_deref().unwrap_or_default().as_bytes());
+        value.push(0xff);
+        value.extend_from_slice(
+            last_seen
+                .user_agent
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+
+        self.userdeviceid_lastseen.insert(&userdeviceid, &value)
+    }
+
+    fn device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<DeviceLastSeen>> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_lastseen
+            .get(&userdeviceid)?
+            .map(|bytes| {
+                let ts = bytes
+                    .get(..size_of::<u64>())
+                    .and_then(|ts| utils::u64_from_bytes(ts).ok())
+                    .and_then(UInt::new)
+                    .ok_or_else(|| {
+                        Error::bad_database("Timestamp in userdeviceid_lastseen is invalid.")
+                    })?;
+
+                let mut parts = bytes[size_of::<u64>()..].splitn(2, |&b| b == 0xff);
+                let mut next_string = || {
+                    parts
+                        .next()
+                        .filter(|part| !part.is_empty())
+                        .map(|part| {
+                            utils::string_from_bytes(part).map_err(|_| {
+                                Error::bad_database(
+                                    "String in userdeviceid_lastseen is invalid unicode.",
+                                )
+                            })
+                        })
+                        .transpose()
+                };
+
+                Ok(DeviceLastSeen {
+                    ts: MilliSecondsSinceUnixEpoch(ts),
+                    ip: next_string()?,
+                    user_agent: next_string()?,
+                })
+            })
+            .transpose()
+    }
+
+    fn is_soft_logged_out(&self, user_id: &UserId, device_id: &DeviceId) -> Result<bool> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        Ok(self.userdeviceid_softlogout.get(&userdeviceid)?.is_some())
+    }
+
     fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -422,6 +596,109 @@ impl service::users::Data for KeyValueDatabase {
         Ok(counts)
     }
 
+    fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        fallback_key_key: &DeviceKeyId,
+        fallback_key_value: &Raw<OneTimeKey>,
+    ) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+
+        // Only existing devices should be able to call this.
+        assert!(self.userdeviceid_metadata.get(&key)?.is_some());
+
+        key.push(0xff);
+        key.extend_from_slice(fallback_key_key.algorithm().as_ref().as_bytes());
+
+        // A new fallback key is unused
+        let mut value = vec![0];
+        value.extend_from_slice(
+            serde_json::to_string(fallback_key_key)
+                .expect("DeviceKeyId::to_string always works")
+                .as_bytes(),
+        );
+        value.push(0xff);
+        value.extend_from_slice(
+            &serde_json::to_vec(&fallback_key_value).expect("OneTimeKey::to_vec always works"),
+        );
+
+        self.userdevicealgorithm_fallbackkey.insert(&key, &value)?;
+
+        self.userid_lastonetimekeyupdate.insert(
+            user_id.as_bytes(),
+            &services().globals.next_count()?.to_be_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    fn take_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_algorithm: &DeviceKeyAlgorithm,
+    ) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(key_algorithm.as_ref().as_bytes());
+
+        let Some(mut value) = self.userdevicealgorithm_fallbackkey.get(&key)? else {
+            return Ok(None);
+        };
+
+        let mut parts = value
+            .get(1..)
+            .ok_or_else(|| Error::bad_database("Fallback key in db is invalid."))?
+            .splitn(2, |&b| b == 0xff);
+        let fallback_key_key =
+            serde_json::from_slice(parts.next().expect("splitn always returns one element"))
+                .map_err(|_| Error::bad_database("Fallback key id in db is invalid."))?;
+        let fallback_key_value = serde_json::from_slice(
+            parts
+                .next()
+                .ok_or_else(|| Error::bad_database("Fallback key in db is invalid."))?,
+        )
+        .map_err(|_| Error::bad_database("Fallback key in db is invalid."))?;
+
+        if value[0] == 0 {
+            value[0] = 1;
+            self.userdevicealgorithm_fallbackkey.insert(&key, &value)?;
+
+            self.userid_lastonetimekeyupdate.insert(
+                user_id.as_bytes(),
+                &services().globals.next_count()?.to_be_bytes(),
+            )?;
+        }
+
+        Ok(Some((fallback_key_key, fallback_key_value)))
+    }
+
+    fn unused_fallback_key_types(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Vec<DeviceKeyAlgorithm>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+
+        self.userdevicealgorithm_fallbackkey
+            .scan_prefix(prefix.clone())
+            .filter(|(_, value)| value.first() == Some(&0))
+            .map(|(key, _)| {
+                utils::string_from_bytes(&key[prefix.len()..])
+                    .map(Into::into)
+                    .map_err(|_| Error::bad_database("Fallback key algorithm in db is invalid."))
+            })
+            .collect()
+    }
+
     fn add_device_keys(
         &self,
         user_id: &UserId,
@@ -445,7 +722,7 @@ impl service::users::Data for KeyValueDatabase {
     fn add_cross_signing_keys(
         &self,
         user_id: &UserId,
-        master_key: &Raw<CrossSigningKey>,
+        master_key: &Option<Raw<CrossSigningKey>>,
         self_signing_key: &Option<Raw<CrossSigningKey>>,
         user_signing_key: &Option<Raw<CrossSigningKey>>,
         notify: bool,
@@ -454,23 +731,30 @@ impl service::users::Data for KeyValueDatabase {
         let mut prefix = user_id.as_bytes().to_vec();
         prefix.push(0xff);
 
-        let (master_key_key, _) = self.parse_master_key(user_id, master_key)?;
-
-        self.keyid_key
-            .insert(&master_key_key, master_key.json().get().as_bytes())?;
-
-        self.userid_masterkeyid
-            .insert(user_id.as_bytes(), &master_key_key)?;
+        // Validate all keys before writing any of them
+        let master_key_key = match master_key {
+            Some(master_key) => Some(self.parse_master_key(user_id, master_key)?.0),
+            None if self.userid_masterkeyid.get(user_id.as_bytes())?.is_none() => {
+                return Err(Error::BadRequest(
+                    ErrorKind::MissingParam,
+                    "User has no master key, it has to be uploaded first.",
+                ));
+            }
+            None => None,
+        };
 
         // Self-signing key
-        if let Some(self_signing_key) = self_signing_key {
-            let mut self_signing_key_ids = self_signing_key
-                .deserialize()
-                .map_err(|_| {
-                    Error::BadRequest(ErrorKind::InvalidParam, "Invalid self signing key")
-                })?
-                .keys
-                .into_values();
+        let self_signing_key_key = if let Some(self_signing_key) = self_signing_key {
+            let parsed = self_signing_key.deserialize().map_err(|_| {
+                Error::BadRequest(ErrorKind::InvalidParam, "Invalid self signing key")
+            })?;
+            if parsed.user_id != user_id || !parsed.usage.contains(&KeyUsage::SelfSigning) {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Self signing key is not a self signing key of this user.",
+                ));
+            }
+            let mut self_signing_key_ids = parsed.keys.into_values();
 
             let self_signing_key_id = self_signing_key_ids.next().ok_or(Error::BadRequest(
                 ErrorKind::InvalidParam,
@@ -486,25 +770,23 @@ impl service::users::Data for KeyValueDatabase {
 
             let mut self_signing_key_key = prefix.clone();
             self_signing_key_key.extend_from_slice(self_signing_key_id.as_bytes());
-
-            self.keyid_key.insert(
-                &self_signing_key_key,
-                self_signing_key.json().get().as_bytes(),
-            )?;
-
-            self.userid_selfsigningkeyid
-                .insert(user_id.as_bytes(), &self_signing_key_key)?;
-        }
+            Some(self_signing_key_key)
+        } else {
+            None
+        };
 
         // User-signing key
-        if let Some(user_signing_key) = user_signing_key {
-            let mut user_signing_key_ids = user_signing_key
-                .deserialize()
-                .map_err(|_| {
-                    Error::BadRequest(ErrorKind::InvalidParam, "Invalid user signing key")
-                })?
-                .keys
-                .into_values();
+        let user_signing_key_key = if let Some(user_signing_key) = user_signing_key {
+            let parsed = user_signing_key.deserialize().map_err(|_| {
+                Error::BadRequest(ErrorKind::InvalidParam, "Invalid user signing key")
+            })?;
+            if parsed.user_id != user_id || !parsed.usage.contains(&KeyUsage::UserSigning) {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "User signing key is not a user signing key of this user.",
+                ));
+            }
+            let mut user_signing_key_ids = parsed.keys.into_values();
 
             let user_signing_key_id = user_signing_key_ids.next().ok_or(Error::BadRequest(
                 ErrorKind::InvalidParam,
@@ -520,7 +802,34 @@ impl service::users::Data for KeyValueDatabase {
 
             let mut user_signing_key_key = prefix;
             user_signing_key_key.extend_from_slice(user_signing_key_id.as_bytes());
+            Some(user_signing_key_key)
+        } else {
+            None
+        };
+
+        if let (Some(master_key), Some(master_key_key)) = (master_key, master_key_key) {
+            self.keyid_key
+                .insert(&master_key_key, master_key.json().get().as_bytes())?;
+
+            self.userid_masterkeyid
+                .insert(user_id.as_bytes(), &master_key_key)?;
+        }
+
+        if let (Some(self_signing_key), Some(self_signing_key_key)) =
+            (self_signing_key, self_signing_key_key)
+        {
+            self.keyid_key.insert(
+                &self_signing_key_key,
+                self_signing_key.json().get().as_bytes(),
+            )?;
+
+            self.userid_selfsigningkeyid
+                .insert(user_id.as_bytes(), &self_signing_key_key)?;
+        }
 
+        if let (Some(user_signing_key), Some(user_signing_key_key)) =
+            (user_signing_key, user_signing_key_key)
+        {
             self.keyid_key.insert(
                 &user_signing_key_key,
                 user_signing_key.json().get().as_bytes(),
@@ -624,6 +933,17 @@ impl service::users::Data for KeyValueDatabase {
 
     fn mark_device_key_update(&self, user_id: &UserId) -> Result<()> {
         let count = services().globals.next_count()?.to_be_bytes();
+
+        // Local users who share an encrypted room with the user, and the user itself
+        let mut observers = HashSet::new();
+        if user_id.server_name() == services().globals.server_name() {
+            observers.insert(user_id.to_owned());
+
+            // Other servers see the new keys once the version changes
+            self.userid_devicelistversion
+                .increment(user_id.as_bytes())?;
+        }
+
         for room_id in services()
             .rooms
             .state_cache
@@ -645,6 +965,15 @@ impl service::users::Data for KeyValueDatabase {
             key.extend_from_slice(&count);
 
             self.keychangeid_userid.insert(&key, user_id.as_bytes())?;
+
+            observers.extend(
+                services()
+                    .rooms
+                    .state_cache
+                    .room_members(&room_id)
+                    .filter_map(|r| r.ok())
+                    .filter(|member| member.server_name() == services().globals.server_name()),
+            );
         }
 
         let mut key = user_id.as_bytes().to_vec();
@@ -652,9 +981,127 @@ impl service::users::Data for KeyValueDatabase {
         key.extend_from_slice(&count);
         self.keychangeid_userid.insert(&key, user_id.as_bytes())?;
 
+        let mut value = user_id.as_bytes().to_vec();
+        value.push(0xff);
+        value.push(DeviceListChange::Changed as u8);
+
+        self.observerchangeid_userid
+            .insert_batch(&mut observers.into_iter().map(|observer| {
+                let mut key = observer.as_bytes().to_vec();
+                key.push(0xff);
+                key.extend_from_slice(&count);
+                (key, value.clone())
+            }))?;
+
+        Ok(())
+    }
+
+    fn add_device_list_change(
+        &self,
+        observer: &UserId,
+        user_id: &UserId,
+        change: DeviceListChange,
+    ) -> Result<()> {
+        let mut key = observer.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&services().globals.next_count()?.to_be_bytes());
+
+        let mut value = user_id.as_bytes().to_vec();
+        value.push(0xff);
+        value.push(change as u8);
+
+        self.observerchangeid_userid.insert(&key, &value)
+    }
+
+    fn device_list_changes<'a>(
+        &'a self,
+        observer: &UserId,
+        since: u64,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, DeviceListChange)>> + 'a> {
+        let mut prefix = observer.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut start = prefix.clone();
+        start.extend_from_slice(&(since + 1).to_be_bytes());
+
+        Box::new(
+            self.observerchangeid_userid
+                .iter_from(&start, false)
+                .take_while(move |(k, _)| k.starts_with(&prefix))
+                .map(|(_, value)| {
+                    let (change, user_bytes) = value
+                        .split_last()
+                        .and_then(|(change, rest)| Some((change, rest.strip_suffix(&[0xff])?)))
+                        .ok_or_else(|| {
+                            Error::bad_database("Invalid value in observerchangeid_userid.")
+                        })?;
+
+                    let user_id =
+                        UserId::parse(utils::string_from_bytes(user_bytes).map_err(|_| {
+                            Error::bad_database(
+                                "User ID in observerchangeid_userid is invalid unicode.",
+                            )
+                        })?)
+                        .map_err(|_| {
+                            Error::bad_database("User ID in observerchangeid_userid is invalid.")
+                        })?;
+
+                    let change = DeviceListChange::from_byte(*change).ok_or_else(|| {
+                        Error::bad_database("Change in observerchangeid_userid is invalid.")
+                    })?;
+
+                    Ok::<_, Error>((user_id, change))
+                }),
+        )
+    }
+
+    fn prune_device_list_changes(&self, observer: &UserId, until: u64) -> Result<()> {
+        let mut prefix = observer.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut end = prefix.clone();
+        end.extend_from_slice(&until.to_be_bytes());
+
+        for (key, _) in self
+            .observerchangeid_userid
+            .scan_prefix(prefix)
+            .take_while(|(key, _)| key <= &end)
+        {
+            self.observerchangeid_userid.remove(&key)?;
+        }
+
         Ok(())
     }
 
+    fn set_device_list_since(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        count: u64,
+    ) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_devicelistsince
+            .insert(&key, &count.to_be_bytes())
+    }
+
+    fn device_list_since(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<u64>> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(device_id.as_bytes());
+
+        self.userdeviceid_devicelistsince
+            .get(&key)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Count in userdeviceid_devicelistsince is invalid.")
+                })
+            })
+            .transpose()
+    }
+
     fn get_device_keys(
         &self,
         user_id: &UserId,
@@ -682,6 +1129,12 @@ impl service::users::Data for KeyValueDatabase {
         let master_key = master_key
             .deserialize()
             .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid master key"))?;
+        if master_key.user_id != user_id || !master_key.usage.contains(&KeyUsage::Master) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Master key is not a master key of this user.",
+            ));
+        }
         let mut master_key_ids = master_key.keys.values();
         let master_key_id = master_key_ids.next().ok_or(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -790,6 +1243,7 @@ impl service::users::Data for KeyValueDatabase {
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
+        until: u64,
     ) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
         let mut events = Vec::new();
 
@@ -798,7 +1252,16 @@ impl service::users::Data for KeyValueDatabase {
         prefix.extend_from_slice(device_id.as_bytes());
         prefix.push(0xff);
 
-        for (_, value) in self.todeviceid_events.scan_prefix(prefix) {
+        // Newer events are sent with the next sync, they would be removed only after that one
+        for (_, value) in self
+            .todeviceid_events
+            .scan_prefix(prefix)
+            .take_while(|(key, _)| {
+                key.len() >= size_of::<u64>()
+                    && utils::u64_from_bytes(&key[key.len() - size_of::<u64>()..])
+                        .map_or(false, |count| count <= until)
+            })
+        {
             events.push(
                 serde_json::from_slice(&value)
                     .map_err(|_| Error::bad_database("Event in todeviceid_events is invalid."))?,
@@ -895,6 +1358,22 @@ impl service::users::Data for KeyValueDatabase {
             })
     }
 
+    fn last_device_list_update(&self, user_id: &UserId) -> Result<Option<u64>> {
+        self.userid_lastdevicelistupdate
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid stream id in userid_lastdevicelistupdate.")
+                })
+            })
+            .transpose()
+    }
+
+    fn set_last_device_list_update(&self, user_id: &UserId, stream_id: u64) -> Result<()> {
+        self.userid_lastdevicelistupdate
+            .insert(user_id.as_bytes(), &stream_id.to_be_bytes())
+    }
+
     fn all_devices_metadata<'a>(
         &'a self,
         user_id: &UserId,
@@ -943,9 +1422,237 @@ impl service::users::Data for KeyValueDatabase {
             Ok(None)
         }
     }
+
+    fn add_threepid(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+        validated_at: u64,
+        added_at: u64,
+    ) -> Result<()> {
+        let threepid = threepid_key(medium, address);
+
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&threepid);
+
+        let mut timestamps = validated_at.to_be_bytes().to_vec();
+        timestamps.extend_from_slice(&added_at.to_be_bytes());
+
+        self.threepid_userid.insert(&threepid, user_id.as_bytes())?;
+        self.userthreepid_timestamps.insert(&key, &timestamps)
+    }
+
+    fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+        let threepid = threepid_key(medium, address);
+
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&threepid);
+
+        // Another user might have added the address since
+        if self.find_from_threepid(medium, address)?.as_deref() == Some(user_id) {
+            self.threepid_userid.remove(&threepid)?;
+        }
+        self.userthreepid_timestamps.remove(&key)
+    }
+
+    fn find_from_threepid(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>> {
+        self.threepid_userid
+            .get(&threepid_key(medium, address))?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in threepid_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in threepid_userid is invalid."))
+            })
+            .transpose()
+    }
+
+    fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<ThirdPartyIdentifier>> + 'a> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Box::new(
+            self.userthreepid_timestamps
+                .scan_prefix(prefix)
+                .map(|(key, timestamps)| {
+                    let mut parts = key.splitn(3, |&b| b == 0xff).skip(1);
+                    let medium = parts.next().ok_or_else(|| {
+                        Error::bad_database("Medium in userthreepid_timestamps is invalid.")
+                    })?;
+                    let address = parts.next().ok_or_else(|| {
+                        Error::bad_database("Address in userthreepid_timestamps is invalid.")
+                    })?;
+
+                    if timestamps.len() != 2 * size_of::<u64>() {
+                        return Err(Error::bad_database(
+                            "Timestamps in userthreepid_timestamps are invalid.",
+                        ));
+                    }
+                    let (validated_at, added_at) = timestamps.split_at(size_of::<u64>());
+                    let timestamp = |bytes: &[u8]| {
+                        MilliSecondsSinceUnixEpoch(
+                            utils::u64_from_bytes(bytes)
+                                .expect("length was checked")
+                                .try_into()
+                                .unwrap_or_default(),
+                        )
+                    };
+
+                    Ok(ThirdPartyIdentifier {
+                        address: utils::string_from_bytes(address).map_err(|_| {
+                            Error::bad_database(
+                                "Address in userthreepid_timestamps is invalid unicode.",
+                            )
+                        })?,
+                        medium: utils::string_from_bytes(medium)
+                            .map_err(|_| {
+                                Error::bad_database(
+                                    "Medium in userthreepid_timestamps is invalid unicode.",
+                                )
+                            })?
+                            .into(),
+                        validated_at: timestamp(validated_at),
+                        added_at: timestamp(added_at),
+                    })
+                }),
+        )
+    }
+
+    fn create_openid_token(&self, user_id: &UserId, token: &str, expires_at: u64) -> Result<()> {
+        let mut value = expires_at.to_be_bytes().to_vec();
+        value.extend_from_slice(user_id.as_bytes());
+
+        self.openidtoken_expiresatuserid
+            .insert(token.as_bytes(), &value)
+    }
+
+    fn find_from_openid_token(&self, token: &str) -> Result<Option<(OwnedUserId, u64)>> {
+        self.openidtoken_expiresatuserid
+            .get(token.as_bytes())?
+            .map(|value| {
+                if value.len() < size_of::<u64>() {
+                    return Err(Error::bad_database(
+                        "Expiry in openidtoken_expiresatuserid is invalid.",
+                    ));
+                }
+                let (expires_at, user_id) = value.split_at(size_of::<u64>());
+
+                Ok((
+                    UserId::parse(utils::string_from_bytes(user_id).map_err(|_| {
+                        Error::bad_database(
+                            "User ID in openidtoken_expiresatuserid is invalid unicode.",
+                        )
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("User ID in openidtoken_expiresatuserid is invalid.")
+                    })?,
+                    utils::u64_from_bytes(expires_at).expect("length was checked"),
+                ))
+            })
+            .transpose()
+    }
+
+    fn remove_openid_token(&self, token: &str) -> Result<()> {
+        self.openidtoken_expiresatuserid.remove(token.as_bytes())
+    }
+
+    fn remove_expired_openid_tokens(&self, ts: u64) -> Result<u64> {
+        let mut removed = 0;
+
+        for (token, value) in self.openidtoken_expiresatuserid.iter() {
+            let expires_at = value
+                .get(..size_of::<u64>())
+                .map(utils::u64_from_bytes)
+                .ok_or_else(|| {
+                    Error::bad_database("Expiry in openidtoken_expiresatuserid is invalid.")
+                })?
+                .map_err(|_| {
+                    Error::bad_database("Expiry in openidtoken_expiresatuserid is invalid.")
+                })?;
+            if expires_at < ts {
+                self.openidtoken_expiresatuserid.remove(&token)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
 }
 
-impl KeyValueDatabase {}
+fn threepid_key(medium: &Medium, address: &str) -> Vec<u8> {
+    let mut key = medium.as_str().as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(address.as_bytes());
+    key
+}
+
+impl KeyValueDatabase {
+    /// Replaces the user directory entries of a user for the old displayname with the ones for the
+    /// new displayname. The localpart entries are always (re)inserted.
+    pub(in crate::database) fn update_user_directory(
+        &self,
+        user_id: &UserId,
+        old_displayname: Option<&str>,
+        displayname: Option<&str>,
+    ) -> Result<()> {
+        let old_entries = directory_entries(user_id, old_displayname);
+        let entries = directory_entries(user_id, displayname);
+
+        for suffix in old_entries.keys().filter(|s| !entries.contains_key(*s)) {
+            self.directorytoken_userid
+                .remove(&directory_key(suffix, user_id))?;
+        }
+
+        self.directorytoken_userid.insert_batch(
+            &mut entries
+                .into_iter()
+                .map(|(suffix, flags)| (directory_key(&suffix, user_id), vec![flags])),
+        )
+    }
+}
+
+fn directory_key(suffix: &str, user_id: &UserId) -> Vec<u8> {
+    let mut key = suffix.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(user_id.as_bytes());
+    key
+}
+
+/// Every suffix of every word in the localpart and displayname, so that a prefix scan finds
+/// substrings, with the kinds of matches each suffix stands for.
+fn directory_entries(user_id: &UserId, displayname: Option<&str>) -> BTreeMap<String, u8> {
+    let mut entries = BTreeMap::new();
+
+    let mut add = |text: &str, prefix: DirectoryMatch, substring: DirectoryMatch| {
+        for word in users::directory_words(text) {
+            for (i, _) in word.char_indices() {
+                let flag = if i == 0 { prefix } else { substring }.flag();
+                *entries.entry(word[i..].to_owned()).or_default() |= flag;
+            }
+        }
+    };
+
+    add(
+        user_id.localpart(),
+        DirectoryMatch::LocalpartPrefix,
+        DirectoryMatch::LocalpartSubstring,
+    );
+    if let Some(displayname) = displayname {
+        add(
+            displayname,
+            DirectoryMatch::DisplaynamePrefix,
+            DirectoryMatch::DisplaynameSubstring,
+        );
+    }
+
+    entries
+}
 
 /// Will only return with Some(username) if the password was not empty and the
 /// username could be successfully parsed.
@@ -2,8 +2,9 @@ pub mod abstraction;
 pub mod key_value;
 
 use crate::{
-    service::rooms::timeline::PduCount, services, utils, Config, Error, PduEvent, Result, Services,
-    SERVICES,
+    config::{FlushStrategy, RegistrationStage},
+    service::rooms::timeline::PduCount,
+    services, utils, Config, Error, PduEvent, Result, Services, SERVICES,
 };
 use abstraction::{KeyValueDatabaseEngine, KvTree};
 use directories::ProjectDirs;
@@ -44,20 +45,32 @@ pub struct KeyValueDatabase {
     pub(super) userid_displayname: Arc<dyn KvTree>,
     pub(super) userid_avatarurl: Arc<dyn KvTree>,
     pub(super) userid_blurhash: Arc<dyn KvTree>,
+    pub(super) userid_guest: Arc<dyn KvTree>, // Marks users who registered as guests
+    pub(super) blockeduserids: Arc<dyn KvTree>, // Users who may not join, invite or send over federation
+    pub(super) directorytoken_userid: Arc<dyn KvTree>, // DirectoryToken = Suffix of a localpart or displayname word + UserId
     pub(super) userdeviceid_token: Arc<dyn KvTree>,
     pub(super) userdeviceid_metadata: Arc<dyn KvTree>, // This is also used to check if a device exists
     pub(super) userid_devicelistversion: Arc<dyn KvTree>, // DevicelistVersion = u64
+    pub(super) userid_lastdevicelistupdate: Arc<dyn KvTree>, // StreamId of the last device list update EDU = u64
     pub(super) token_userdeviceid: Arc<dyn KvTree>,
+    pub(super) userdeviceid_softlogout: Arc<dyn KvTree>, // Devices that have to log in again to keep their state
+    pub(super) userdeviceid_lastseen: Arc<dyn KvTree>,   // LastSeen = Timestamp + Ip + UserAgent
 
     pub(super) onetimekeyid_onetimekeys: Arc<dyn KvTree>, // OneTimeKeyId = UserId + DeviceKeyId
     pub(super) userid_lastonetimekeyupdate: Arc<dyn KvTree>, // LastOneTimeKeyUpdate = Count
-    pub(super) keychangeid_userid: Arc<dyn KvTree>,       // KeyChangeId = UserId/RoomId + Count
+    pub(super) userdevicealgorithm_fallbackkey: Arc<dyn KvTree>, // FallbackKey = Used + DeviceKeyId + OneTimeKey
+    pub(super) keychangeid_userid: Arc<dyn KvTree>, // KeyChangeId = UserId/RoomId + Count
+    pub(super) observerchangeid_userid: Arc<dyn KvTree>, // ObserverChangeId = UserId + Count, value = UserId + DeviceListChange
+    pub(super) userdeviceid_devicelistsince: Arc<dyn KvTree>, // DeviceListSince = Count
     pub(super) keyid_key: Arc<dyn KvTree>, // KeyId = UserId + KeyId (depends on key type)
     pub(super) userid_masterkeyid: Arc<dyn KvTree>,
     pub(super) userid_selfsigningkeyid: Arc<dyn KvTree>,
     pub(super) userid_usersigningkeyid: Arc<dyn KvTree>,
 
     pub(super) userfilterid_filter: Arc<dyn KvTree>, // UserFilterId = UserId + FilterId
+    pub(super) threepid_userid: Arc<dyn KvTree>,     // Threepid = Medium + Address
+    pub(super) userthreepid_timestamps: Arc<dyn KvTree>, // Timestamps = ValidatedAt + AddedAt
+    pub(super) openidtoken_expiresatuserid: Arc<dyn KvTree>, // ExpiresAtUserId = ExpiresAt + UserId
 
     pub(super) todeviceid_events: Arc<dyn KvTree>, // ToDeviceId = UserId + DeviceId + Count
 
@@ -65,6 +78,8 @@ pub struct KeyValueDatabase {
     pub(super) userdevicesessionid_uiaainfo: Arc<dyn KvTree>, // User-interactive authentication
     pub(super) userdevicesessionid_uiaarequest:
         RwLock<BTreeMap<(OwnedUserId, OwnedDeviceId, String), CanonicalJsonValue>>,
+    pub(super) registrationtoken_info: Arc<dyn KvTree>, // Info = Remaining uses and expiry
+    pub(super) idpsubject_userid: Arc<dyn KvTree>,      // IdpSubject = IdpId + Subject
 
     //pub edus: RoomEdus,
     pub(super) readreceiptid_readreceipt: Arc<dyn KvTree>, // ReadReceiptId = RoomId + Count + UserId
@@ -73,15 +88,18 @@ pub struct KeyValueDatabase {
     pub(super) typingid_userid: Arc<dyn KvTree>,        // TypingId = RoomId + TimeoutTime + Count
     pub(super) roomid_lasttypingupdate: Arc<dyn KvTree>, // LastRoomTypingUpdate = Count
     pub(super) presenceid_presence: Arc<dyn KvTree>,    // PresenceId = RoomId + Count + UserId
-    pub(super) userid_lastpresenceupdate: Arc<dyn KvTree>, // LastPresenceUpdate = Count
+    pub(super) userid_presence: Arc<dyn KvTree>, // Presence = current presence of the user as json
 
     //pub rooms: rooms::Rooms,
     pub(super) pduid_pdu: Arc<dyn KvTree>, // PduId = ShortRoomId + Count
     pub(super) eventid_pduid: Arc<dyn KvTree>,
     pub(super) roomid_pduleaves: Arc<dyn KvTree>,
+    pub(super) timestamp_count: Arc<dyn KvTree>, // Count = the global count at that time
     pub(super) alias_roomid: Arc<dyn KvTree>,
     pub(super) aliasid_alias: Arc<dyn KvTree>, // AliasId = RoomId + Count
+    pub(super) alias_userid: Arc<dyn KvTree>,  // The creator of the alias
     pub(super) publicroomids: Arc<dyn KvTree>,
+    pub(super) networkid_publicroomid: Arc<dyn KvTree>, // NetworkId = appservice network, RoomId
 
     pub(super) threadid_userids: Arc<dyn KvTree>, // ThreadId = RoomId + Count
 
@@ -98,10 +116,14 @@ pub struct KeyValueDatabase {
     pub(super) roomuseroncejoinedids: Arc<dyn KvTree>,
     pub(super) userroomid_invitestate: Arc<dyn KvTree>, // InviteState = Vec<Raw<Pdu>>
     pub(super) roomuserid_invitecount: Arc<dyn KvTree>, // InviteCount = Count
+    pub(super) userroomid_knockstate: Arc<dyn KvTree>,  // KnockState = Vec<Raw<Pdu>>
+    pub(super) roomuserid_knockcount: Arc<dyn KvTree>,  // KnockCount = Count
     pub(super) userroomid_leftstate: Arc<dyn KvTree>,
     pub(super) roomuserid_leftcount: Arc<dyn KvTree>,
 
     pub(super) disabledroomids: Arc<dyn KvTree>, // Rooms where incoming federation handling is disabled
+    pub(super) blockedroomids: Arc<dyn KvTree>, // Rooms nobody on this server may join or be invited to
+    pub(super) reportid_report: Arc<dyn KvTree>, // ReportId = RoomId + Count
 
     pub(super) lazyloadedids: Arc<dyn KvTree>, // LazyLoadedIds = UserId + DeviceId + RoomId + LazyLoadedUserId
 
@@ -135,6 +157,7 @@ pub struct KeyValueDatabase {
 
     /// ShortEventId + ShortEventId -> ().
     pub(super) tofrom_relation: Arc<dyn KvTree>,
+    pub(super) relationids: Arc<dyn KvTree>, // RelationId = ShortRoomId + TargetCount + RelType + 0xff + FromCount
     /// RoomId + EventId -> Parent PDU EventId.
     pub(super) referencedevents: Arc<dyn KvTree>,
 
@@ -144,17 +167,24 @@ pub struct KeyValueDatabase {
 
     //pub media: media::Media,
     pub(super) mediaid_file: Arc<dyn KvTree>, // MediaId = MXC + WidthHeight + ContentDisposition + ContentType
+    pub(super) mxc_uploader: Arc<dyn KvTree>, // Uploader = UserId + Size
+    pub(super) userid_mediausage: Arc<dyn KvTree>, // MediaUsage = Bytes
     //pub key_backups: key_backups::KeyBackups,
     pub(super) backupid_algorithm: Arc<dyn KvTree>, // BackupId = UserId + Version(Count)
     pub(super) backupid_etag: Arc<dyn KvTree>,      // BackupId = UserId + Version(Count)
     pub(super) backupkeyid_backup: Arc<dyn KvTree>, // BackupKeyId = UserId + Version + RoomId + SessionId
+    pub(super) userid_lastbackupdeletion: Arc<dyn KvTree>, // LastBackupDeletion = Count
 
     //pub transaction_ids: transaction_ids::TransactionIds,
     pub(super) userdevicetxnid_response: Arc<dyn KvTree>, // Response can be empty (/sendToDevice) or the event id (/send)
+    pub(super) timestamptxnid_userdevicetxnid: Arc<dyn KvTree>, // TimestampTxnId = Timestamp + UserDeviceTxnId, for pruning
     //pub sending: sending::Sending,
-    pub(super) servername_educount: Arc<dyn KvTree>, // EduCount: Count of last EDU sync
     pub(super) servernameevent_data: Arc<dyn KvTree>, // ServernameEvent = (+ / $)SenderKey / ServerName / UserId + PduId / Id (for edus), Data = EDU content
     pub(super) servercurrentevent_data: Arc<dyn KvTree>, // ServerCurrentEvents = (+ / $)ServerName / UserId + PduId / Id (for edus), Data = EDU content
+    pub(super) outgoingkind_backoff: Arc<dyn KvTree>, // Backoff = Failures + LastFailure + NextAttempt
+
+    //pub server_notices: server_notices::ServerNotices,
+    pub(super) userid_servernoticeroomid: Arc<dyn KvTree>, // ServerNoticeRoomId = RoomId
 
     //pub appservice: appservice::Appservice,
     pub(super) id_appserviceregistrations: Arc<dyn KvTree>,
@@ -172,6 +202,8 @@ pub struct KeyValueDatabase {
     pub(super) our_real_users_cache: RwLock<HashMap<OwnedRoomId, Arc<HashSet<OwnedUserId>>>>,
     pub(super) appservice_in_room_cache: RwLock<HashMap<OwnedRoomId, HashMap<String, bool>>>,
     pub(super) lasttimelinecount_cache: Mutex<HashMap<OwnedRoomId, PduCount>>,
+
+    pub(super) trees: Vec<(&'static str, Arc<dyn KvTree>)>, // All trees above with their names
 }
 
 impl KeyValueDatabase {
@@ -234,143 +266,299 @@ impl KeyValueDatabase {
         Ok(())
     }
 
-    /// Load an existing database or create a new one.
-    pub async fn load_or_create(config: Config) -> Result<()> {
-        Self::check_db_setup(&config)?;
-
-        if !Path::new(&config.database_path).exists() {
-            std::fs::create_dir_all(&config.database_path)
-                .map_err(|_| Error::BadConfig("Database folder doesn't exists and couldn't be created (e.g. due to missing permissions). Please create the database folder yourself."))?;
-        }
-
-        let builder: Arc<dyn KeyValueDatabaseEngine> = match &*config.database_backend {
+    fn open_engine(config: &Config) -> Result<Arc<dyn KeyValueDatabaseEngine>> {
+        Ok(match &*config.database_backend {
+            "sled" => {
+                #[cfg(not(feature = "sled"))]
+                return Err(Error::BadConfig("Database backend not found."));
+                #[cfg(feature = "sled")]
+                Arc::new(Arc::<abstraction::sled::Engine>::open(config)?)
+            }
             "sqlite" => {
                 #[cfg(not(feature = "sqlite"))]
                 return Err(Error::BadConfig("Database backend not found."));
                 #[cfg(feature = "sqlite")]
-                Arc::new(Arc::<abstraction::sqlite::Engine>::open(&config)?)
+                Arc::new(Arc::<abstraction::sqlite::Engine>::open(config)?)
             }
             "rocksdb" => {
                 #[cfg(not(feature = "rocksdb"))]
                 return Err(Error::BadConfig("Database backend not found."));
                 #[cfg(feature = "rocksdb")]
-                Arc::new(Arc::<abstraction::rocksdb::Engine>::open(&config)?)
+                Arc::new(Arc::<abstraction::rocksdb::Engine>::open(config)?)
             }
             "persy" => {
                 #[cfg(not(feature = "persy"))]
                 return Err(Error::BadConfig("Database backend not found."));
                 #[cfg(feature = "persy")]
-                Arc::new(Arc::<abstraction::persy::Engine>::open(&config)?)
+                Arc::new(Arc::<abstraction::persy::Engine>::open(config)?)
+            }
+            "postgres" => {
+                #[cfg(not(feature = "postgres"))]
+                return Err(Error::BadConfig("Database backend not found."));
+                #[cfg(feature = "postgres")]
+                Arc::new(Arc::<abstraction::postgres::Engine>::open(config)?)
             }
             _ => {
                 return Err(Error::BadConfig("Database backend not found."));
             }
-        };
+        })
+    }
+
+    /// Copies every tree of another database into the configured one, e.g. to move from sled to
+    /// RocksDB. Conduit must not be running on either of them.
+    pub fn copy_database(config: &Config, from_backend: &str, from_path: &str) -> Result<()> {
+        if from_backend == config.database_backend && from_path == config.database_path {
+            return Err(Error::bad_config(
+                "Can't copy the configured database into itself.",
+            ));
+        }
+
+        let mut from_config = config.clone();
+        from_config.database_backend = from_backend.to_owned();
+        from_config.database_path = from_path.to_owned();
+        let from = Self::open_engine(&from_config)?;
+
+        Self::check_db_setup(config)?;
+        if !Path::new(&config.database_path).exists() {
+            std::fs::create_dir_all(&config.database_path)?;
+        }
+        let to = Self::open_engine(config)?;
+
+        if to.open_tree("global")?.iter().next().is_some() {
+            return Err(Error::bad_config(
+                "The configured database is not empty, copy into a new one.",
+            ));
+        }
+
+        for name in from.tree_names()? {
+            // Trees are only opened with static names otherwise
+            let name: &'static str = Box::leak(name.into_boxed_str());
+            let source = from.open_tree(name)?;
+            let target = to.open_tree(name)?;
+
+            let mut entries = source.iter();
+            let mut count = 0;
+            loop {
+                let batch = entries.by_ref().take(1000).collect::<Vec<_>>();
+                if batch.is_empty() {
+                    break;
+                }
+                count += batch.len();
+                target.insert_batch(&mut batch.into_iter())?;
+            }
+
+            info!("Copied {} entries of {}", count, name);
+        }
+
+        to.flush()?;
+
+        Ok(())
+    }
+
+    /// Load an existing database or create a new one.
+    pub async fn load_or_create(config: Config) -> Result<()> {
+        Self::check_db_setup(&config)?;
+
+        if !Path::new(&config.database_path).exists() {
+            std::fs::create_dir_all(&config.database_path)
+                .map_err(|_| Error::BadConfig("Database folder doesn't exists and couldn't be created (e.g. due to missing permissions). Please create the database folder yourself."))?;
+        }
+
+        let builder = Self::open_engine(&config)?;
 
         if config.registration_token == Some(String::new()) {
             return Err(Error::bad_config("Registration token is empty"));
         }
 
-        if config.max_request_size < 1024 {
-            error!(?config.max_request_size, "Max request size is less than 1KB. Please increase it.");
+        if config.recaptcha_public_key.is_some() != config.recaptcha_private_key.is_some() {
+            return Err(Error::bad_config(
+                "reCAPTCHA needs both recaptcha_public_key and recaptcha_private_key",
+            ));
         }
 
+        if config
+            .registration_stages
+            .contains(&RegistrationStage::Recaptcha)
+            && config.recaptcha_private_key.is_none()
+        {
+            return Err(Error::bad_config(
+                "The recaptcha registration stage needs the reCAPTCHA keys",
+            ));
+        }
+
+        // With open registration only the configured token can be used, admins can't hand out
+        // their own
+        if config
+            .registration_stages
+            .contains(&RegistrationStage::Token)
+            && config.registration_token.is_none()
+            && config.allow_registration
+        {
+            return Err(Error::bad_config(
+                "The token registration stage needs a registration_token or allow_registration = false",
+            ));
+        }
+
+        if !config.oidc_providers.is_empty() && config.sso_base_url.is_none() {
+            return Err(Error::bad_config("OIDC providers need the sso_base_url"));
+        }
+
+        let mut oidc_provider_ids = HashSet::new();
+        for provider in &config.oidc_providers {
+            if provider.id.is_empty()
+                || provider.id.len() > 255
+                || !provider
+                    .id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._~-".contains(c))
+            {
+                return Err(Error::bad_config(
+                    "OIDC provider ids may only have up to 255 letters, digits and the characters ._~-",
+                ));
+            }
+            if !oidc_provider_ids.insert(&provider.id) {
+                return Err(Error::bad_config("OIDC provider ids have to be unique"));
+            }
+        }
+
+        if config.body_limit.media_upload < 1024 {
+            error!(?config.body_limit.media_upload, "Media upload limit is less than 1KB. Please increase it.");
+        }
+
+        // Remember every tree, so the metrics can report their sizes
+        let opened_trees = Mutex::new(Vec::new());
+        let open_tree = |name: &'static str| -> Result<Arc<dyn KvTree>> {
+            let tree = builder.open_tree(name)?;
+            opened_trees.lock().unwrap().push((name, Arc::clone(&tree)));
+            Ok(tree)
+        };
+
+        // The last notification read counts have always lived in the highlight count tree, so
+        // share it instead of registering the same tree twice
+        let userroomid_highlightcount = open_tree("userroomid_highlightcount")?;
+
         let db_raw = Box::new(Self {
             _db: builder.clone(),
-            userid_password: builder.open_tree("userid_password")?,
-            userid_displayname: builder.open_tree("userid_displayname")?,
-            userid_avatarurl: builder.open_tree("userid_avatarurl")?,
-            userid_blurhash: builder.open_tree("userid_blurhash")?,
-            userdeviceid_token: builder.open_tree("userdeviceid_token")?,
-            userdeviceid_metadata: builder.open_tree("userdeviceid_metadata")?,
-            userid_devicelistversion: builder.open_tree("userid_devicelistversion")?,
-            token_userdeviceid: builder.open_tree("token_userdeviceid")?,
-            onetimekeyid_onetimekeys: builder.open_tree("onetimekeyid_onetimekeys")?,
-            userid_lastonetimekeyupdate: builder.open_tree("userid_lastonetimekeyupdate")?,
-            keychangeid_userid: builder.open_tree("keychangeid_userid")?,
-            keyid_key: builder.open_tree("keyid_key")?,
-            userid_masterkeyid: builder.open_tree("userid_masterkeyid")?,
-            userid_selfsigningkeyid: builder.open_tree("userid_selfsigningkeyid")?,
-            userid_usersigningkeyid: builder.open_tree("userid_usersigningkeyid")?,
-            userfilterid_filter: builder.open_tree("userfilterid_filter")?,
-            todeviceid_events: builder.open_tree("todeviceid_events")?,
-
-            userdevicesessionid_uiaainfo: builder.open_tree("userdevicesessionid_uiaainfo")?,
+            userid_password: open_tree("userid_password")?,
+            userid_displayname: open_tree("userid_displayname")?,
+            userid_avatarurl: open_tree("userid_avatarurl")?,
+            userid_blurhash: open_tree("userid_blurhash")?,
+            userid_guest: open_tree("userid_guest")?,
+            blockeduserids: open_tree("blockeduserids")?,
+            directorytoken_userid: open_tree("directorytoken_userid")?,
+            userdeviceid_token: open_tree("userdeviceid_token")?,
+            userdeviceid_metadata: open_tree("userdeviceid_metadata")?,
+            userid_devicelistversion: open_tree("userid_devicelistversion")?,
+            userid_lastdevicelistupdate: open_tree("userid_lastdevicelistupdate")?,
+            token_userdeviceid: open_tree("token_userdeviceid")?,
+            userdeviceid_softlogout: open_tree("userdeviceid_softlogout")?,
+            userdeviceid_lastseen: open_tree("userdeviceid_lastseen")?,
+            onetimekeyid_onetimekeys: open_tree("onetimekeyid_onetimekeys")?,
+            userid_lastonetimekeyupdate: open_tree("userid_lastonetimekeyupdate")?,
+            userdevicealgorithm_fallbackkey: open_tree("userdevicealgorithm_fallbackkey")?,
+            keychangeid_userid: open_tree("keychangeid_userid")?,
+            observerchangeid_userid: open_tree("observerchangeid_userid")?,
+            userdeviceid_devicelistsince: open_tree("userdeviceid_devicelistsince")?,
+            keyid_key: open_tree("keyid_key")?,
+            userid_masterkeyid: open_tree("userid_masterkeyid")?,
+            userid_selfsigningkeyid: open_tree("userid_selfsigningkeyid")?,
+            userid_usersigningkeyid: open_tree("userid_usersigningkeyid")?,
+            userfilterid_filter: open_tree("userfilterid_filter")?,
+            threepid_userid: open_tree("threepid_userid")?,
+            userthreepid_timestamps: open_tree("userthreepid_timestamps")?,
+            openidtoken_expiresatuserid: open_tree("openidtoken_expiresatuserid")?,
+            todeviceid_events: open_tree("todeviceid_events")?,
+
+            userdevicesessionid_uiaainfo: open_tree("userdevicesessionid_uiaainfo")?,
             userdevicesessionid_uiaarequest: RwLock::new(BTreeMap::new()),
-            readreceiptid_readreceipt: builder.open_tree("readreceiptid_readreceipt")?,
-            roomuserid_privateread: builder.open_tree("roomuserid_privateread")?, // "Private" read receipt
-            roomuserid_lastprivatereadupdate: builder
-                .open_tree("roomuserid_lastprivatereadupdate")?,
-            typingid_userid: builder.open_tree("typingid_userid")?,
-            roomid_lasttypingupdate: builder.open_tree("roomid_lasttypingupdate")?,
-            presenceid_presence: builder.open_tree("presenceid_presence")?,
-            userid_lastpresenceupdate: builder.open_tree("userid_lastpresenceupdate")?,
-            pduid_pdu: builder.open_tree("pduid_pdu")?,
-            eventid_pduid: builder.open_tree("eventid_pduid")?,
-            roomid_pduleaves: builder.open_tree("roomid_pduleaves")?,
-
-            alias_roomid: builder.open_tree("alias_roomid")?,
-            aliasid_alias: builder.open_tree("aliasid_alias")?,
-            publicroomids: builder.open_tree("publicroomids")?,
-
-            threadid_userids: builder.open_tree("threadid_userids")?,
-
-            tokenids: builder.open_tree("tokenids")?,
-
-            roomserverids: builder.open_tree("roomserverids")?,
-            serverroomids: builder.open_tree("serverroomids")?,
-            userroomid_joined: builder.open_tree("userroomid_joined")?,
-            roomuserid_joined: builder.open_tree("roomuserid_joined")?,
-            roomid_joinedcount: builder.open_tree("roomid_joinedcount")?,
-            roomid_invitedcount: builder.open_tree("roomid_invitedcount")?,
-            roomuseroncejoinedids: builder.open_tree("roomuseroncejoinedids")?,
-            userroomid_invitestate: builder.open_tree("userroomid_invitestate")?,
-            roomuserid_invitecount: builder.open_tree("roomuserid_invitecount")?,
-            userroomid_leftstate: builder.open_tree("userroomid_leftstate")?,
-            roomuserid_leftcount: builder.open_tree("roomuserid_leftcount")?,
-
-            disabledroomids: builder.open_tree("disabledroomids")?,
-
-            lazyloadedids: builder.open_tree("lazyloadedids")?,
-
-            userroomid_notificationcount: builder.open_tree("userroomid_notificationcount")?,
-            userroomid_highlightcount: builder.open_tree("userroomid_highlightcount")?,
-            roomuserid_lastnotificationread: builder.open_tree("userroomid_highlightcount")?,
-
-            statekey_shortstatekey: builder.open_tree("statekey_shortstatekey")?,
-            shortstatekey_statekey: builder.open_tree("shortstatekey_statekey")?,
-
-            shorteventid_authchain: builder.open_tree("shorteventid_authchain")?,
-
-            roomid_shortroomid: builder.open_tree("roomid_shortroomid")?,
-
-            shortstatehash_statediff: builder.open_tree("shortstatehash_statediff")?,
-            eventid_shorteventid: builder.open_tree("eventid_shorteventid")?,
-            shorteventid_eventid: builder.open_tree("shorteventid_eventid")?,
-            shorteventid_shortstatehash: builder.open_tree("shorteventid_shortstatehash")?,
-            roomid_shortstatehash: builder.open_tree("roomid_shortstatehash")?,
-            roomsynctoken_shortstatehash: builder.open_tree("roomsynctoken_shortstatehash")?,
-            statehash_shortstatehash: builder.open_tree("statehash_shortstatehash")?,
-
-            eventid_outlierpdu: builder.open_tree("eventid_outlierpdu")?,
-            softfailedeventids: builder.open_tree("softfailedeventids")?,
-
-            tofrom_relation: builder.open_tree("tofrom_relation")?,
-            referencedevents: builder.open_tree("referencedevents")?,
-            roomuserdataid_accountdata: builder.open_tree("roomuserdataid_accountdata")?,
-            roomusertype_roomuserdataid: builder.open_tree("roomusertype_roomuserdataid")?,
-            mediaid_file: builder.open_tree("mediaid_file")?,
-            backupid_algorithm: builder.open_tree("backupid_algorithm")?,
-            backupid_etag: builder.open_tree("backupid_etag")?,
-            backupkeyid_backup: builder.open_tree("backupkeyid_backup")?,
-            userdevicetxnid_response: builder.open_tree("userdevicetxnid_response")?,
-            servername_educount: builder.open_tree("servername_educount")?,
-            servernameevent_data: builder.open_tree("servernameevent_data")?,
-            servercurrentevent_data: builder.open_tree("servercurrentevent_data")?,
-            id_appserviceregistrations: builder.open_tree("id_appserviceregistrations")?,
-            senderkey_pusher: builder.open_tree("senderkey_pusher")?,
-            global: builder.open_tree("global")?,
-            server_signingkeys: builder.open_tree("server_signingkeys")?,
+            registrationtoken_info: open_tree("registrationtoken_info")?,
+            idpsubject_userid: open_tree("idpsubject_userid")?,
+            readreceiptid_readreceipt: open_tree("readreceiptid_readreceipt")?,
+            roomuserid_privateread: open_tree("roomuserid_privateread")?, // "Private" read receipt
+            roomuserid_lastprivatereadupdate: open_tree("roomuserid_lastprivatereadupdate")?,
+            typingid_userid: open_tree("typingid_userid")?,
+            roomid_lasttypingupdate: open_tree("roomid_lasttypingupdate")?,
+            presenceid_presence: open_tree("presenceid_presence")?,
+            userid_presence: open_tree("userid_presence")?,
+            pduid_pdu: open_tree("pduid_pdu")?,
+            eventid_pduid: open_tree("eventid_pduid")?,
+            roomid_pduleaves: open_tree("roomid_pduleaves")?,
+            timestamp_count: open_tree("timestamp_count")?,
+
+            alias_roomid: open_tree("alias_roomid")?,
+            aliasid_alias: open_tree("aliasid_alias")?,
+            alias_userid: open_tree("alias_userid")?,
+            publicroomids: open_tree("publicroomids")?,
+            networkid_publicroomid: open_tree("networkid_publicroomid")?,
+
+            threadid_userids: open_tree("threadid_userids")?,
+
+            tokenids: open_tree("tokenids")?,
+
+            roomserverids: open_tree("roomserverids")?,
+            serverroomids: open_tree("serverroomids")?,
+            userroomid_joined: open_tree("userroomid_joined")?,
+            roomuserid_joined: open_tree("roomuserid_joined")?,
+            roomid_joinedcount: open_tree("roomid_joinedcount")?,
+            roomid_invitedcount: open_tree("roomid_invitedcount")?,
+            roomuseroncejoinedids: open_tree("roomuseroncejoinedids")?,
+            userroomid_invitestate: open_tree("userroomid_invitestate")?,
+            roomuserid_invitecount: open_tree("roomuserid_invitecount")?,
+            userroomid_knockstate: open_tree("userroomid_knockstate")?,
+            roomuserid_knockcount: open_tree("roomuserid_knockcount")?,
+            userroomid_leftstate: open_tree("userroomid_leftstate")?,
+            roomuserid_leftcount: open_tree("roomuserid_leftcount")?,
+
+            disabledroomids: open_tree("disabledroomids")?,
+            blockedroomids: open_tree("blockedroomids")?,
+            reportid_report: open_tree("reportid_report")?,
+
+            lazyloadedids: open_tree("lazyloadedids")?,
+
+            userroomid_notificationcount: open_tree("userroomid_notificationcount")?,
+            roomuserid_lastnotificationread: Arc::clone(&userroomid_highlightcount),
+            userroomid_highlightcount,
+
+            statekey_shortstatekey: open_tree("statekey_shortstatekey")?,
+            shortstatekey_statekey: open_tree("shortstatekey_statekey")?,
+
+            shorteventid_authchain: open_tree("shorteventid_authchain")?,
+
+            roomid_shortroomid: open_tree("roomid_shortroomid")?,
+
+            shortstatehash_statediff: open_tree("shortstatehash_statediff")?,
+            eventid_shorteventid: open_tree("eventid_shorteventid")?,
+            shorteventid_eventid: open_tree("shorteventid_eventid")?,
+            shorteventid_shortstatehash: open_tree("shorteventid_shortstatehash")?,
+            roomid_shortstatehash: open_tree("roomid_shortstatehash")?,
+            roomsynctoken_shortstatehash: open_tree("roomsynctoken_shortstatehash")?,
+            statehash_shortstatehash: open_tree("statehash_shortstatehash")?,
+
+            eventid_outlierpdu: open_tree("eventid_outlierpdu")?,
+            softfailedeventids: open_tree("softfailedeventids")?,
+
+            tofrom_relation: open_tree("tofrom_relation")?,
+            relationids: open_tree("relationids")?,
+            referencedevents: open_tree("referencedevents")?,
+            roomuserdataid_accountdata: open_tree("roomuserdataid_accountdata")?,
+            roomusertype_roomuserdataid: open_tree("roomusertype_roomuserdataid")?,
+            mediaid_file: open_tree("mediaid_file")?,
+            mxc_uploader: open_tree("mxc_uploader")?,
+            userid_mediausage: open_tree("userid_mediausage")?,
+            backupid_algorithm: open_tree("backupid_algorithm")?,
+            backupid_etag: open_tree("backupid_etag")?,
+            backupkeyid_backup: open_tree("backupkeyid_backup")?,
+            userid_lastbackupdeletion: open_tree("userid_lastbackupdeletion")?,
+            userdevicetxnid_response: open_tree("userdevicetxnid_response")?,
+            timestamptxnid_userdevicetxnid: open_tree("timestamptxnid_userdevicetxnid")?,
+            servernameevent_data: open_tree("servernameevent_data")?,
+            servercurrentevent_data: open_tree("servercurrentevent_data")?,
+            outgoingkind_backoff: open_tree("outgoingkind_backoff")?,
+            userid_servernoticeroomid: open_tree("userid_servernoticeroomid")?,
+            id_appserviceregistrations: open_tree("id_appserviceregistrations")?,
+            senderkey_pusher: open_tree("senderkey_pusher")?,
+            global: open_tree("global")?,
+            server_signingkeys: open_tree("server_signingkeys")?,
 
             cached_registrations: Arc::new(RwLock::new(HashMap::new())),
             pdu_cache: Mutex::new(LruCache::new(
@@ -397,6 +585,7 @@ impl KeyValueDatabase {
             our_real_users_cache: RwLock::new(HashMap::new()),
             appservice_in_room_cache: RwLock::new(HashMap::new()),
             lasttimelinecount_cache: Mutex::new(HashMap::new()),
+            trees: opened_trees.into_inner().unwrap(),
         });
 
         let db = Box::leak(db_raw);
@@ -406,6 +595,18 @@ impl KeyValueDatabase {
         // This is the first and only time we initialize the SERVICE static
         *SERVICES.write().unwrap() = Some(Box::leak(services_raw));
 
+        if services()
+            .globals
+            .config
+            .registration_stages
+            .contains(&RegistrationStage::Token)
+            && !services().uiaa.registration_token_available()?
+        {
+            warn!(
+                "No registration token can be used, nobody can register until an admin creates one"
+            );
+        }
+
         // Matrix resource ownership is based on the server name; changing it
         // requires recreating the database from scratch.
         if services().users.count()? > 0 {
@@ -425,7 +626,7 @@ impl KeyValueDatabase {
         }
 
         // If the database has any data, perform data migrations before starting
-        let latest_database_version = 13;
+        let latest_database_version = 17;
 
         if services().users.count()? > 0 {
             // MIGRATIONS
@@ -942,6 +1143,142 @@ impl KeyValueDatabase {
                 warn!("Migration: 12 -> 13 finished");
             }
 
+            if services().globals.database_version()? < 14 {
+                // Store backed up room keys in the compact encoding. Collect first, some backends
+                // don't allow writing while iterating.
+                let reencoded = db
+                    .backupkeyid_backup
+                    .iter()
+                    .filter_map(|(key, value)| {
+                        let encoded = key_value::key_backups::encode_key_data(&value);
+                        (encoded != value).then_some((key, encoded))
+                    })
+                    .collect::<Vec<_>>();
+
+                for batch in reencoded.chunks(1000) {
+                    db.backupkeyid_backup
+                        .insert_batch(&mut batch.iter().cloned())?;
+                    debug!("Re-encoded smaller batch of backup keys");
+                }
+
+                services().globals.bump_database_version(14)?;
+
+                warn!("Migration: 13 -> 14 finished");
+            }
+
+            if services().globals.database_version()? < 15 {
+                // Build the user directory index for existing users
+                for (userid, _) in db.userid_password.iter() {
+                    let user_id = match utils::string_from_bytes(&userid)
+                        .ok()
+                        .and_then(|u| UserId::parse(u).ok())
+                    {
+                        Some(user_id) => user_id,
+                        None => {
+                            warn!("Skipping invalid user id {:?} in userid_password", userid);
+                            continue;
+                        }
+                    };
+
+                    let displayname = services().users.displayname(&user_id)?;
+                    db.update_user_directory(&user_id, None, displayname.as_deref())?;
+                }
+
+                services().globals.bump_database_version(15)?;
+
+                warn!("Migration: 14 -> 15 finished");
+            }
+
+            if services().globals.database_version()? < 16 {
+                // Index the relations of existing events by type for bundled aggregations
+                #[derive(Deserialize)]
+                struct ExtractRelation {
+                    event_id: OwnedEventId,
+                    rel_type: Option<String>,
+                }
+                #[derive(Deserialize)]
+                struct ExtractContent {
+                    #[serde(rename = "m.relates_to")]
+                    relates_to: ExtractRelation,
+                }
+                #[derive(Deserialize)]
+                struct ExtractPdu {
+                    room_id: OwnedRoomId,
+                    content: ExtractContent,
+                }
+
+                for (pdu_id, value) in db.pduid_pdu.iter() {
+                    // Relations with backfilled pdus are not indexed
+                    if pdu_id.len() != 2 * size_of::<u64>() {
+                        continue;
+                    }
+
+                    let pdu = match serde_json::from_slice::<ExtractPdu>(&value) {
+                        Ok(pdu) => pdu,
+                        Err(_) => continue,
+                    };
+                    let rel_type = match pdu.content.relates_to.rel_type {
+                        Some(rel_type) => rel_type,
+                        None => continue,
+                    };
+
+                    let from = utils::u64_from_bytes(&pdu_id[size_of::<u64>()..])
+                        .map_err(|_| Error::bad_database("Invalid pdu id in pduid_pdu."))?;
+                    if let Some(target) = services()
+                        .rooms
+                        .timeline
+                        .get_pdu_count(&pdu.content.relates_to.event_id)?
+                    {
+                        services().rooms.pdu_metadata.add_typed_relation(
+                            &pdu.room_id,
+                            PduCount::Normal(from),
+                            target,
+                            &rel_type,
+                        )?;
+                    }
+                }
+
+                services().globals.bump_database_version(16)?;
+
+                warn!("Migration: 15 -> 16 finished");
+            }
+
+            if services().globals.database_version()? < 17 {
+                // Thread summaries are computed when sending events now, remove the stored ones
+                for (root_id, _) in db.threadid_userids.iter() {
+                    let mut pdu_json =
+                        match services().rooms.timeline.get_pdu_json_from_id(&root_id)? {
+                            Some(pdu_json) => pdu_json,
+                            None => continue,
+                        };
+
+                    if let Some(CanonicalJsonValue::Object(unsigned)) = pdu_json.get_mut("unsigned")
+                    {
+                        if let Some(CanonicalJsonValue::Object(relations)) =
+                            unsigned.get_mut("m.relations")
+                        {
+                            relations.remove("m.thread");
+                            if relations.is_empty() {
+                                unsigned.remove("m.relations");
+                            }
+                        }
+                    }
+
+                    let pdu = serde_json::from_value(
+                        serde_json::to_value(&pdu_json).expect("canonical json is valid json"),
+                    )
+                    .map_err(|_| Error::bad_database("Invalid PDU in db."))?;
+                    services()
+                        .rooms
+                        .timeline
+                        .replace_pdu(&root_id, &pdu_json, &pdu)?;
+                }
+
+                services().globals.bump_database_version(17)?;
+
+                warn!("Migration: 16 -> 17 finished");
+            }
+
             assert_eq!(
                 services().globals.database_version().unwrap(),
                 latest_database_version
@@ -991,6 +1328,24 @@ impl KeyValueDatabase {
         services().sending.start_handler();
 
         Self::start_cleanup_task().await;
+        Self::start_flush_task();
+        if services().globals.media_retention_days() > 0 {
+            Self::start_media_purge_task();
+        }
+        if services().globals.allow_room_retention() {
+            Self::start_room_retention_task();
+        }
+        if services().globals.allow_presence() {
+            services().rooms.edus.presence.start_timeout_task();
+        }
+        services().rooms.edus.typing.start_sweep_task();
+        services().users.start_openid_token_sweep_task();
+        if services().globals.config.transaction_id_retention_days != 0 {
+            services().transaction_ids.start_prune_task();
+        }
+        if services().globals.config.enable_metrics {
+            services().metrics.start_tree_sizes_task();
+        }
         if services().globals.allow_check_for_updates() {
             Self::start_check_for_updates_task();
         }
@@ -1009,6 +1364,16 @@ impl KeyValueDatabase {
         res
     }
 
+    /// Flushes right away if the flush strategy is `immediate`, otherwise the flush task picks
+    /// up the write later.
+    fn flush_if_immediate(&self) -> Result<()> {
+        if services().globals.flush_strategy() == FlushStrategy::Immediate {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
     #[tracing::instrument]
     pub fn start_check_for_updates_task() {
         tokio::spawn(async move {
@@ -1063,6 +1428,134 @@ impl KeyValueDatabase {
         Ok(())
     }
 
+    #[tracing::instrument]
+    pub fn start_media_purge_task() {
+        let timer_interval =
+            Duration::from_secs(services().globals.config.media_purge_second_interval as u64);
+
+        tokio::spawn(async move {
+            let mut i = interval(timer_interval);
+            loop {
+                i.tick().await;
+
+                let retention =
+                    u64::from(services().globals.media_retention_days()) * 24 * 60 * 60 * 1000;
+                let ts = utils::millis_since_unix_epoch().saturating_sub(retention);
+
+                match services().media.purge_media_older_than(ts).await {
+                    Ok((files, bytes)) => {
+                        debug!("media purge: Deleted {} files, {} bytes", files, bytes)
+                    }
+                    Err(e) => error!("media purge: Errored: {}", e),
+                }
+            }
+        });
+    }
+
+    #[tracing::instrument]
+    pub fn start_room_retention_task() {
+        let timer_interval = Duration::from_secs(
+            services()
+                .globals
+                .config
+                .room_retention_purge_second_interval as u64,
+        );
+
+        tokio::spawn(async move {
+            let mut i = interval(timer_interval);
+            loop {
+                i.tick().await;
+
+                // Ages are measured against these checkpoints, events received before the first
+                // one count as received at that point
+                if let Err(e) = services().rooms.timeline.add_count_checkpoint() {
+                    error!("room retention: Failed to add a count checkpoint: {}", e);
+                    continue;
+                }
+
+                // Rooms can set their own policy, so this runs even without a configured default
+                for room_id in services().rooms.metadata.iter_ids().filter_map(|r| r.ok()) {
+                    let lifetime = match services().rooms.timeline.retention_max_lifetime(&room_id)
+                    {
+                        Ok(Some(lifetime)) => lifetime,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            error!("room retention: Failed to get policy of {}: {}", room_id, e);
+                            continue;
+                        }
+                    };
+                    let ts = utils::millis_since_unix_epoch().saturating_sub(lifetime);
+                    let count = match services().rooms.timeline.count_at(ts) {
+                        Ok(Some(count)) => count,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            error!("room retention: Failed to get the count at {}: {}", ts, e);
+                            continue;
+                        }
+                    };
+
+                    match services()
+                        .rooms
+                        .timeline
+                        .purge_pdus_received_before(&room_id, count)
+                    {
+                        Ok(0) => {}
+                        Ok(count) => {
+                            debug!("room retention: Purged {} events in {}", count, room_id)
+                        }
+                        Err(e) => error!("room retention: Errored in {}: {}", room_id, e),
+                    }
+                }
+            }
+        });
+    }
+
+    #[tracing::instrument]
+    pub fn start_flush_task() {
+        let strategy = services().globals.flush_strategy();
+        if strategy == FlushStrategy::Immediate {
+            return;
+        }
+
+        let timer_interval =
+            Duration::from_secs(services().globals.config.flush_second_interval as u64);
+
+        tokio::spawn(async move {
+            let mut i = interval(timer_interval);
+            // The global counter moves with almost every write, so it tells us if we are idle
+            let mut last_count = services().globals.current_count().unwrap_or_default();
+            let mut flushed_count = last_count;
+
+            loop {
+                i.tick().await;
+
+                let count = match services().globals.current_count() {
+                    Ok(count) => count,
+                    Err(e) => {
+                        error!("flush: Failed to read the counter: {}", e);
+                        continue;
+                    }
+                };
+
+                let should_flush = match strategy {
+                    FlushStrategy::OnIdle => count == last_count && count != flushed_count,
+                    _ => true,
+                };
+                last_count = count;
+
+                if !should_flush {
+                    continue;
+                }
+
+                if let Err(e) = services().globals.flush() {
+                    error!("flush: Errored: {}", e);
+                } else {
+                    flushed_count = count;
+                }
+            }
+        });
+    }
+
     #[tracing::instrument]
     pub async fn start_cleanup_task() {
         #[cfg(unix)]
@@ -1095,6 +1588,7 @@ impl KeyValueDatabase {
                 }
 
                 let start = Instant::now();
+                services().rate_limiter.cleanup();
                 if let Err(e) = services().globals.cleanup() {
                     error!("cleanup: Errored: {}", e);
                 } else {
@@ -1132,3 +1626,33 @@ fn set_emergency_access() -> Result<bool> {
 
     res
 }
+
+/// Loads a new database in a temporary directory, for tests that need the services.
+///
+/// The services can only be set up once per process, so all tests using this share one database.
+/// Background tasks only run as long as the runtime of the first test.
+#[cfg(all(test, feature = "persy"))]
+pub(crate) async fn load_temporary_database() {
+    lazy_static::lazy_static! {
+        static ref LOADED: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::new();
+    }
+
+    LOADED
+        .get_or_init(|| async {
+            let path = std::env::temp_dir().join(format!("conduit-test-{}", std::process::id()));
+            let _ = remove_dir_all(&path);
+
+            let config = figment::Figment::new()
+                .merge(figment::providers::Toml::string(&format!(
+                    "server_name = \"test.local\"\ndatabase_backend = \"persy\"\ndatabase_path = {:?}\n",
+                    path.display().to_string()
+                )))
+                .extract::<Config>()
+                .expect("test config is valid");
+
+            KeyValueDatabase::load_or_create(config)
+                .await
+                .expect("test database can be created");
+        })
+        .await;
+}
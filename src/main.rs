@@ -1,4 +1,10 @@
-use std::{future::Future, io, net::SocketAddr, sync::atomic, time::Duration};
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::atomic,
+    time::{Duration, Instant},
+};
 
 use axum::{
     extract::{DefaultBodyLimit, FromRequestParts, MatchedPath},
@@ -55,7 +61,7 @@ async fn main() {
             )
             .merge(Env::prefixed("CONDUIT_").global());
 
-    let config = match raw_config.extract::<Config>() {
+    let mut config = match raw_config.extract::<Config>() {
         Ok(s) => s,
         Err(e) => {
             eprintln!("It looks like your config is invalid. The following error occurred: {e}");
@@ -63,6 +69,7 @@ async fn main() {
         }
     };
 
+    config.apply_deprecated(&raw_config);
     config.warn_deprecated();
 
     let log = format!("{},ruma_state_res=error,_=off,sled=off", config.log);
@@ -125,6 +132,26 @@ async fn main() {
     #[cfg(unix)]
     maximize_fd_limit().expect("should be able to increase the soft limit to the hard limit");
 
+    // `conduit copy-database <backend> <path>` copies another database into the configured one
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("copy-database") {
+        let (from_backend, from_path) = match &args[..] {
+            [_, backend, path] => (backend, path),
+            _ => {
+                eprintln!("Usage: conduit copy-database <backend> <path>");
+                std::process::exit(1);
+            }
+        };
+
+        info!("Copying the {from_backend} database at {from_path}");
+        if let Err(error) = KeyValueDatabase::copy_database(&config, from_backend, from_path) {
+            error!(?error, "The database couldn't be copied");
+            std::process::exit(1);
+        }
+        info!("Copied the database, Conduit can be started with it now");
+        return;
+    }
+
     info!("Loading database");
     if let Err(error) = KeyValueDatabase::load_or_create(config).await {
         error!(?error, "The database couldn't be loaded or created");
@@ -136,6 +163,8 @@ async fn main() {
     info!("Starting server");
     run_server().await.unwrap();
 
+    shutdown().await;
+
     if config.allow_jaeger {
         opentelemetry::global::shutdown_tracer_provider();
     }
@@ -161,7 +190,9 @@ async fn run_server() -> io::Result<()> {
                 tracing::info_span!("http_request", %path)
             }),
         )
+        .layer(axum::middleware::from_fn(record_metrics))
         .layer(axum::middleware::from_fn(unrecognized_method))
+        .layer(axum::middleware::from_fn(media_range_requests))
         .layer(
             CorsLayer::new()
                 .allow_origin(cors::Any)
@@ -178,21 +209,42 @@ async fn run_server() -> io::Result<()> {
                     header::CONTENT_TYPE,
                     header::ACCEPT,
                     header::AUTHORIZATION,
+                    header::RANGE,
                 ])
                 .max_age(Duration::from_secs(86400)),
         )
+        // Requests for Ruma endpoints have lower limits by endpoint
         .layer(DefaultBodyLimit::max(
             config
-                .max_request_size
+                .body_limit
+                .largest()
                 .try_into()
                 .expect("failed to convert max request size"),
         ));
 
-    let app = routes().layer(middlewares).into_make_service();
+    // Both the plain and the TLS listener serve this, so requests always know the address of the
+    // connection for rate limits and last seen IPs
+    let app = routes()
+        .layer(middlewares)
+        .into_make_service_with_connect_info::<SocketAddr>();
     let handle = ServerHandle::new();
 
     tokio::spawn(shutdown_signal(handle.clone()));
 
+    if config.enable_metrics {
+        let metrics_addr = SocketAddr::from((config.metrics_address, config.metrics_port));
+        let metrics_app = Router::new()
+            .route("/metrics", get(metrics))
+            .into_make_service();
+
+        info!("Serving metrics on {metrics_addr}");
+        tokio::spawn(async move {
+            if let Err(e) = bind(metrics_addr).serve(metrics_app).await {
+                error!("Metrics listener stopped: {e}");
+            }
+        });
+    }
+
     match &config.tls {
         Some(tls) => {
             let conf = RustlsConfig::from_pem_file(&tls.certs, &tls.key).await?;
@@ -228,6 +280,48 @@ async fn spawn_task<B: Send + 'static>(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+/// Times every request by its route, and counts the sync requests that wait for events.
+async fn record_metrics<B: Send>(
+    req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> std::result::Result<axum::response::Response, StatusCode> {
+    if !services().globals.config.enable_metrics {
+        return Ok(next.run(req).await);
+    }
+
+    let method = req.method().clone();
+    // Unmatched paths are not recorded one by one, anyone can make up new ones
+    let endpoint = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unknown", |path| path.as_str())
+        .to_owned();
+
+    let sync_connection = endpoint
+        .ends_with("/sync")
+        .then(|| services().metrics.sync_connection());
+    let start = Instant::now();
+
+    let response = next.run(req).await;
+
+    drop(sync_connection);
+    services().metrics.record_request(
+        method.as_str(),
+        &endpoint,
+        response.status().as_u16(),
+        start.elapsed(),
+    );
+
+    Ok(response)
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        services().metrics.render(),
+    )
+}
+
 async fn unrecognized_method<B: Send>(
     req: axum::http::Request<B>,
     next: axum::middleware::Next<B>,
@@ -249,12 +343,135 @@ async fn unrecognized_method<B: Send>(
     Ok(inner)
 }
 
+/// Answers `Range` requests for media downloads with `206 Partial Content`.
+///
+/// Files that aren't stored on this server yet are sent whole by the download routes, which
+/// fetch them over federation.
+async fn media_range_requests<B: Send>(
+    req: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> std::result::Result<axum::response::Response, StatusCode> {
+    let path = req.uri().path().to_owned();
+    let media = path
+        .strip_prefix("/_matrix/media/")
+        .and_then(|path| path.split_once("/download/"))
+        .map(|(_, path)| path.split('/').collect::<Vec<_>>())
+        .filter(|segments| segments.len() == 2 || segments.len() == 3);
+
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .filter(|_| req.method() == Method::GET)
+        .map(|range| range.to_str().map(ToOwned::to_owned).unwrap_or_default());
+
+    let (segments, range) = match (media, range) {
+        (Some(segments), Some(range)) => (segments, range),
+        (Some(_), None) => {
+            let mut response = next.run(req).await;
+            if response.status() == StatusCode::OK {
+                response.headers_mut().insert(
+                    header::ACCEPT_RANGES,
+                    header::HeaderValue::from_static("bytes"),
+                );
+            }
+            return Ok(response);
+        }
+        _ => return Ok(next.run(req).await),
+    };
+
+    let mxc = format!("mxc://{}/{}", segments[0], segments[1]);
+    let (content_disposition, content_type, len) =
+        match services().media.get_file_info(mxc.clone()).await {
+            Ok(Some(info)) => info,
+            _ => return Ok(next.run(req).await),
+        };
+    let content_disposition = match segments.get(2) {
+        Some(filename) => Some(format!("inline; filename={filename}")),
+        None => content_disposition,
+    };
+    let content_type = content_type.unwrap_or_else(|| "application/octet-stream".to_owned());
+
+    let ranges = match client_server::parse_range_header(&range, len) {
+        Some(client_server::ByteRanges::Satisfiable(ranges)) => ranges,
+        Some(client_server::ByteRanges::Unsatisfiable) => {
+            return http::Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+                .body(axum::body::boxed(axum::body::Full::default()))
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        None => return Ok(next.run(req).await),
+    };
+
+    let mut response = http::Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header("Cross-Origin-Resource-Policy", "cross-origin");
+    if let Some(content_disposition) = content_disposition {
+        response = response.header(header::CONTENT_DISPOSITION, content_disposition);
+    }
+
+    let body = if let [(first, last)] = ranges[..] {
+        response = response
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_RANGE, format!("bytes {first}-{last}/{len}"));
+
+        match services()
+            .media
+            .read_range(mxc, first, last - first + 1)
+            .await
+        {
+            Ok(Some(bytes)) => bytes,
+            _ => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    } else {
+        let boundary = utils::random_string(32);
+        response = response.header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={boundary}"),
+        );
+
+        let mut body = Vec::new();
+        for (first, last) in ranges {
+            let bytes = match services()
+                .media
+                .read_range(mxc.clone(), first, last - first + 1)
+                .await
+            {
+                Ok(Some(bytes)) => bytes,
+                _ => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+            };
+
+            body.extend_from_slice(
+                format!(
+                    "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {first}-{last}/{len}\r\n\r\n"
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(&bytes);
+        }
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        body
+    };
+
+    response
+        .body(axum::body::boxed(axum::body::Full::from(body)))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
 fn routes() -> Router {
     Router::new()
         .ruma_route(client_server::get_supported_versions_route)
         .ruma_route(client_server::get_register_available_route)
+        .ruma_route(client_server::check_registration_token_validity_route)
         .ruma_route(client_server::register_route)
         .ruma_route(client_server::get_login_types_route)
+        .ruma_route(client_server::sso_login_route)
+        .ruma_route(client_server::sso_login_with_provider_route)
+        .route(
+            "/_conduit/client/oidc/callback",
+            get(client_server::sso_callback_route),
+        )
         .ruma_route(client_server::login_route)
         .ruma_route(client_server::whoami_route)
         .ruma_route(client_server::logout_route)
@@ -264,6 +481,10 @@ fn routes() -> Router {
         .ruma_route(client_server::third_party_route)
         .ruma_route(client_server::request_3pid_management_token_via_email_route)
         .ruma_route(client_server::request_3pid_management_token_via_msisdn_route)
+        .ruma_route(client_server::add_3pid_route)
+        .ruma_route(client_server::create_openid_token_route)
+        .ruma_route(client_server::bind_3pid_route)
+        .ruma_route(client_server::delete_3pid_route)
         .ruma_route(client_server::get_capabilities_route)
         .ruma_route(client_server::get_pushrules_all_route)
         .ruma_route(client_server::set_pushrule_route)
@@ -316,6 +537,7 @@ fn routes() -> Router {
         .ruma_route(client_server::get_alias_route)
         .ruma_route(client_server::join_room_by_id_route)
         .ruma_route(client_server::join_room_by_id_or_alias_route)
+        .ruma_route(client_server::knock_room_route)
         .ruma_route(client_server::joined_members_route)
         .ruma_route(client_server::leave_room_route)
         .ruma_route(client_server::forget_room_route)
@@ -325,6 +547,7 @@ fn routes() -> Router {
         .ruma_route(client_server::unban_user_route)
         .ruma_route(client_server::invite_user_route)
         .ruma_route(client_server::set_room_visibility_route)
+        .ruma_route(client_server::set_appservice_room_visibility_route)
         .ruma_route(client_server::get_room_visibility_route)
         .ruma_route(client_server::get_public_rooms_route)
         .ruma_route(client_server::get_public_rooms_filtered_route)
@@ -412,9 +635,12 @@ fn routes() -> Router {
         .ruma_route(server_server::create_join_event_v1_route)
         .ruma_route(server_server::create_join_event_v2_route)
         .ruma_route(server_server::create_invite_route)
+        .ruma_route(server_server::exchange_third_party_invite_route)
+        .ruma_route(server_server::third_party_invite_onbind_route)
         .ruma_route(server_server::get_devices_route)
         .ruma_route(server_server::get_room_information_route)
         .ruma_route(server_server::get_profile_information_route)
+        .ruma_route(server_server::get_openid_userinfo_route)
         .ruma_route(server_server::get_keys_route)
         .ruma_route(server_server::claim_keys_route)
         .route(
@@ -455,14 +681,51 @@ async fn shutdown_signal(handle: ServerHandle) {
     }
 
     warn!("Received {}, shutting down...", sig);
-    handle.graceful_shutdown(Some(Duration::from_secs(30)));
-
+    let timeout = Duration::from_secs(services().globals.config.shutdown_timeout_seconds.into());
+    info!(
+        "Waiting up to {:?} for {} open connections",
+        timeout,
+        handle.connection_count()
+    );
+    handle.graceful_shutdown(Some(timeout));
+
+    // Lets sync requests return now instead of when they time out
     services().globals.shutdown();
 
     #[cfg(feature = "systemd")]
     let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Stopping]);
 }
 
+/// Finishes the work that doesn't belong to a request once the server stopped accepting them.
+async fn shutdown() {
+    let start = Instant::now();
+    let timeout = Duration::from_secs(services().globals.config.shutdown_timeout_seconds.into());
+
+    let sending = match tokio::time::timeout(timeout, services().sending.drain()).await {
+        Ok(Some(drained)) => format!(
+            "{} transactions finished, {} failed and {} events are sent at the next start",
+            drained.finished, drained.failed, drained.kept
+        ),
+        Ok(None) => "the sending handler was not running".to_owned(),
+        Err(_) => "transactions that didn't finish in time are sent at the next start".to_owned(),
+    };
+
+    let database = match services().globals.flush() {
+        Ok(()) => "the database was flushed",
+        Err(e) => {
+            error!("Failed to flush the database: {}", e);
+            "the database couldn't be flushed"
+        }
+    };
+
+    info!(
+        "Shut down in {:?}: {}, {}",
+        start.elapsed(),
+        sending,
+        database
+    );
+}
+
 async fn not_found(uri: Uri) -> impl IntoResponse {
     warn!("Not found: {uri}");
     Error::BadRequest(ErrorKind::Unrecognized, "Unrecognized request")
@@ -523,6 +786,13 @@ macro_rules! impl_ruma_handler {
                 for path in meta.history.all_paths() {
                     let handler = self.clone();
 
+                    services()
+                        .globals
+                        .served_endpoints
+                        .write()
+                        .unwrap()
+                        .insert((meta.method.clone(), path));
+
                     router = router.route(path, on(method_filter, |$( $ty: $ty, )* req| async move {
                         handler($($ty,)* req).await.map(RumaResponse)
                     }))
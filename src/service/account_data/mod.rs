@@ -8,9 +8,12 @@ use ruma::{
     RoomId, UserId,
 };
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
-use crate::Result;
+use crate::{services, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -18,6 +21,9 @@ pub struct Service {
 
 impl Service {
     /// Places one event in the account data of the user and removes the previous entry.
+    ///
+    /// The change is stored with a new count and wakes the syncs of the user, which include it in
+    /// their next response.
     #[tracing::instrument(skip(self, room_id, user_id, event_type, data))]
     pub fn update(
         &self,
@@ -26,9 +32,31 @@ impl Service {
         event_type: RoomAccountDataEventType,
         data: &serde_json::Value,
     ) -> Result<()> {
+        // Syncs wait for this lock after they read the current count, so they can't miss a change
+        // whose count was handed out before but which wasn't stored yet
+        let mutex = Self::mutex(user_id);
+        let _lock = mutex.lock().unwrap();
+
         self.db.update(room_id, user_id, event_type, data)
     }
 
+    /// Waits for account data changes of the user that are in progress to be stored.
+    pub fn wait_for_updates(&self, user_id: &UserId) {
+        drop(Self::mutex(user_id).lock().unwrap());
+    }
+
+    fn mutex(user_id: &UserId) -> Arc<Mutex<()>> {
+        Arc::clone(
+            services()
+                .globals
+                .userid_mutex_account_data
+                .write()
+                .unwrap()
+                .entry(user_id.to_owned())
+                .or_default(),
+        )
+    }
+
     /// Searches the account data for a specific kind.
     #[tracing::instrument(skip(self, room_id, user_id, event_type))]
     pub fn get(
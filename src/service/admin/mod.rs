@@ -1,8 +1,8 @@
 use std::{
     collections::BTreeMap,
     convert::{TryFrom, TryInto},
-    sync::{Arc, RwLock},
-    time::Instant,
+    sync::{atomic::Ordering, Arc, RwLock},
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
@@ -19,23 +19,30 @@ use ruma::{
             message::RoomMessageEventContent,
             name::RoomNameEventContent,
             power_levels::RoomPowerLevelsEventContent,
+            redaction::RoomRedactionEventContent,
             topic::RoomTopicEventContent,
         },
         TimelineEventType,
     },
-    EventId, OwnedRoomAliasId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
+    EventId, MilliSecondsSinceUnixEpoch, OwnedRoomAliasId, OwnedUserId, RoomAliasId, RoomId,
+    RoomVersionId, ServerName, UserId,
 };
 use serde_json::value::to_raw_value;
 use tokio::sync::{mpsc, Mutex, MutexGuard};
 
 use crate::{
-    api::client_server::{leave_all_rooms, AUTO_GEN_PASSWORD_LENGTH},
+    api::client_server::{leave_all_rooms, leave_room, AUTO_GEN_PASSWORD_LENGTH},
     services,
     utils::{self, HtmlEscape},
     Error, PduEvent, Result,
 };
 
-use super::pdu::PduBuilder;
+use super::{pdu::PduBuilder, sending::OutgoingKind, uiaa::RegistrationTokenInfo};
+
+const REGISTRATION_TOKEN_LENGTH: usize = 16;
+
+/// How long purge-room waits for the leave events to be sent to the other servers.
+const PURGE_LEAVE_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[cfg_attr(test, derive(Debug))]
 #[derive(Parser)]
@@ -73,9 +80,39 @@ enum AdminCommand {
     /// List users in the database
     ListLocalUsers,
 
+    /// List users with many key backup versions, most versions first
+    ListKeyBackupCounts {
+        /// Only list users with at least this many versions
+        #[arg(default_value_t = 2)]
+        min_versions: usize,
+    },
+
+    /// List the users storing the most media, compared to the media quota
+    ListMediaUsage {
+        /// How many users to list
+        #[arg(default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Delete media older than the given number of days, except for avatars
+    ///
+    /// Uses the configured media_retention_days if no number is given.
+    PurgeOldMedia { older_than_days: Option<u32> },
+
     /// List all rooms we are currently handling an incoming pdu from
     IncomingFederation,
 
+    /// List the servers, appservices and push gateways we wait for before sending to them again
+    ///
+    /// Dead destinations failed too often in a row and are only tried again on new events or
+    /// every sending_dead_probe_second_interval seconds.
+    ListBackoffs,
+
+    /// List the servers we are sending requests to, with the requests in flight and waiting
+    ///
+    /// Also shows how many requests reused a pooled connection since the start.
+    FederationQueues,
+
     /// Deactivate a user
     ///
     /// User will not be removed from all rooms by default.
@@ -109,6 +146,31 @@ enum AdminCommand {
         force: bool,
     },
 
+    /// Deactivate all guest accounts and make them leave their rooms
+    ///
+    /// Set allow_guests to false first, so no new guests register in the meantime.
+    DeactivateGuests,
+
+    /// List the devices of a user with when and where they were last used
+    ListSessions {
+        /// The user, e.g. @alice:example.org
+        user_id: Box<UserId>,
+    },
+
+    /// Log out all devices of local users that were not used in the given number of days
+    EvictInactive { days: u64 },
+
+    /// Resolve the state of a room again from its forward extremities
+    ///
+    /// Shows what would change in the current state. Run it again with --confirm to replace the
+    /// current state, which can be repeated safely.
+    RecomputeState {
+        room_id: Box<RoomId>,
+        #[arg(long)]
+        /// Replace the current state of the room
+        confirm: bool,
+    },
+
     /// Get the auth_chain of a PDU
     GetAuthChain {
         /// An event ID (the $ character followed by the base64 reference hash)
@@ -133,6 +195,29 @@ enum AdminCommand {
         event_id: Box<EventId>,
     },
 
+    /// Redact an event in any room the server is in
+    ///
+    /// The redaction is sent by the configured moderation user, or @conduit if there is none.
+    /// That user has to be joined to the room with enough power to redact.
+    Redact {
+        /// The event to redact
+        event_id: Box<EventId>,
+        /// Why the event is redacted
+        reason: Vec<String>,
+    },
+
+    /// Send a server notice to a local user, or to all local users with *
+    ///
+    /// Each user gets a dedicated notice room, which is created on the first notice and reused
+    /// for later ones.
+    SendNotice {
+        /// The user to notify, or * for everyone
+        user_id: String,
+        /// The message to send
+        #[arg(required = true)]
+        message: Vec<String>,
+    },
+
     /// Print database memory usage statistics
     MemoryUsage,
 
@@ -151,6 +236,21 @@ enum AdminCommand {
         username: String,
     },
 
+    /// List the devices of a user with where and when they were last used
+    ListDevices { user_id: Box<UserId> },
+
+    /// Soft logout all devices of a user
+    ///
+    /// The clients have to log in again, but keep their local state like encryption keys.
+    /// Use --all to soft logout every local user, e.g. after a forced password reset.
+    SoftLogout {
+        #[arg(short, long)]
+        /// Soft logout all local users
+        all: bool,
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        user_id: Option<Box<UserId>>,
+    },
+
     /// Create a new user
     CreateUser {
         /// Username of the new user
@@ -159,11 +259,63 @@ enum AdminCommand {
         password: Option<String>,
     },
 
+    /// Create a token that allows registering, even if registration is disabled
+    ///
+    /// As long as there are valid tokens, registering requires one.
+    CreateRegistrationToken {
+        #[arg(short, long)]
+        /// How many registrations may use the token, unlimited by default
+        uses: Option<u64>,
+        #[arg(short, long)]
+        /// After how many days the token expires, never by default
+        expires_in_days: Option<u32>,
+        /// The token, if unspecified one is generated
+        token: Option<String>,
+    },
+
+    /// List the registration tokens with their remaining uses and expiry
+    ListRegistrationTokens,
+
+    /// Delete a registration token
+    DeleteRegistrationToken { token: String },
+
     /// Disables incoming federation handling for a room.
     DisableRoom { room_id: Box<RoomId> },
     /// Enables incoming federation handling for a room again.
     EnableRoom { room_id: Box<RoomId> },
 
+    /// Remove everything this server stored about a room
+    ///
+    /// Other servers are not told about this, use --leave-rooms to make the local users leave
+    /// the room first. With --block, nobody on this server can join or be invited to the room
+    /// afterwards.
+    PurgeRoom {
+        #[arg(short, long)]
+        leave_rooms: bool,
+        #[arg(short, long)]
+        block: bool,
+        room_id: Box<RoomId>,
+    },
+
+    /// Block a room, nobody on this server can join or be invited to it
+    ///
+    /// Events other servers send in the room are dropped. Local users who are in the room
+    /// already stay, use purge-room to remove it completely.
+    BlockRoom { room_id: Box<RoomId> },
+    /// Unblock a room again
+    UnblockRoom { room_id: Box<RoomId> },
+
+    /// Block a local or remote user
+    ///
+    /// Blocked users can't join rooms, invite or be invited. Their room events from other
+    /// servers are still accepted, so the rooms don't diverge from other servers.
+    BlockUser { user_id: Box<UserId> },
+    /// Unblock a user again
+    UnblockUser { user_id: Box<UserId> },
+
+    /// List the events users reported, in all rooms or only in the given room
+    ListReports { room_id: Option<Box<RoomId>> },
+
     /// Verify json signatures
     /// [commandbody]()
     /// # ```
@@ -433,6 +585,70 @@ impl Service {
                 }
                 Err(e) => RoomMessageEventContent::text_plain(e.to_string()),
             },
+            AdminCommand::ListKeyBackupCounts { min_versions } => {
+                let mut counts = Vec::new();
+                for user_id in services().users.iter().filter_map(|r| r.ok()) {
+                    let count = services().key_backups.get_backup_count(&user_id)?;
+                    if count >= min_versions {
+                        counts.push((count, user_id));
+                    }
+                }
+                counts.sort_unstable_by(|a, b| b.cmp(a));
+
+                let mut msg = format!(
+                    "Found {} user(s) with at least {} key backup version(s):\n",
+                    counts.len(),
+                    min_versions
+                );
+                for (count, user_id) in counts {
+                    msg += &format!("{user_id}: {count}\n");
+                }
+                RoomMessageEventContent::text_plain(&msg)
+            }
+            AdminCommand::ListMediaUsage { limit } => {
+                let mut usages = services()
+                    .media
+                    .media_usages()
+                    .filter_map(|r| r.ok())
+                    .filter(|(_, usage)| *usage > 0)
+                    .collect::<Vec<_>>();
+                usages.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+
+                let quota = services().globals.media_quota_per_user();
+                let mut msg = if quota > 0 {
+                    format!("Media usage of the top {limit} user(s), the quota is {quota} bytes:\n")
+                } else {
+                    format!("Media usage of the top {limit} user(s), there is no quota:\n")
+                };
+                for (user_id, usage) in usages.into_iter().take(limit) {
+                    if quota > 0 {
+                        msg += &format!(
+                            "{user_id}: {usage} bytes ({}%)\n",
+                            usage.saturating_mul(100) / quota
+                        );
+                    } else {
+                        msg += &format!("{user_id}: {usage} bytes\n");
+                    }
+                }
+                RoomMessageEventContent::text_plain(&msg)
+            }
+            AdminCommand::PurgeOldMedia { older_than_days } => {
+                let days =
+                    older_than_days.unwrap_or_else(|| services().globals.media_retention_days());
+                if days == 0 {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "No media retention is configured, please specify a number of days.",
+                    ));
+                }
+
+                let ts = utils::millis_since_unix_epoch()
+                    .saturating_sub(u64::from(days) * 24 * 60 * 60 * 1000);
+                let (files, bytes) = services().media.purge_media_older_than(ts).await?;
+
+                RoomMessageEventContent::text_plain(format!(
+                    "Deleted {files} files ({bytes} bytes) older than {days} days."
+                ))
+            }
             AdminCommand::IncomingFederation => {
                 let map = services()
                     .globals
@@ -453,6 +669,60 @@ impl Service {
                 }
                 RoomMessageEventContent::text_plain(&msg)
             }
+            AdminCommand::FederationQueues => {
+                let queues = services().sending.destination_queues();
+
+                let stats = &services().globals.federation_pool_stats;
+                let requests = stats.requests.load(Ordering::Relaxed);
+                let connections = stats.connections.load(Ordering::Relaxed);
+
+                let mut msg = format!(
+                    "{} request(s) sent on {} connection(s), {} reused a pooled connection\n",
+                    requests,
+                    connections,
+                    requests.saturating_sub(connections)
+                );
+                msg += &format!(
+                    "{} request(s) in flight, sending to {} server(s):\n",
+                    services().sending.requests_in_flight(),
+                    queues.len()
+                );
+                for (server, in_flight, waiting) in queues {
+                    msg += &format!("{server}: {in_flight} in flight, {waiting} waiting\n");
+                }
+                RoomMessageEventContent::text_plain(&msg)
+            }
+            AdminCommand::ListBackoffs => {
+                let mut backoffs = services()
+                    .sending
+                    .backoffs()
+                    .filter_map(|r| r.ok())
+                    .collect::<Vec<_>>();
+                backoffs.sort_unstable_by_key(|(_, backoff)| backoff.next_attempt);
+
+                let now = utils::millis_since_unix_epoch();
+                let minutes = |millis: u64| millis / 1000 / 60;
+
+                let mut msg = format!("Backing off from {} destination(s):\n", backoffs.len());
+                for (outgoing_kind, backoff) in backoffs {
+                    let destination = match outgoing_kind {
+                        OutgoingKind::Normal(server) => server.to_string(),
+                        OutgoingKind::Appservice(id) => format!("appservice {id}"),
+                        OutgoingKind::Push(user_id, gateway) => {
+                            format!("push gateway {gateway} of {user_id}")
+                        }
+                    };
+                    msg += &format!(
+                        "{}{}: {} failure(s), last {}m ago, next attempt in {}m\n",
+                        destination,
+                        if backoff.is_dead() { " (dead)" } else { "" },
+                        backoff.failures,
+                        minutes(now.saturating_sub(backoff.last_failure)),
+                        minutes(backoff.next_attempt.saturating_sub(now)),
+                    );
+                }
+                RoomMessageEventContent::text_plain(&msg)
+            }
             AdminCommand::GetAuthChain { event_id } => {
                 let event_id = Arc::<EventId>::from(event_id);
                 if let Some(event) = services().rooms.timeline.get_pdu_json(&event_id)? {
@@ -551,6 +821,162 @@ impl Service {
                     None => RoomMessageEventContent::text_plain("PDU not found."),
                 }
             }
+            AdminCommand::Redact { event_id, reason } => {
+                let pdu = match services().rooms.timeline.get_pdu(&event_id)? {
+                    Some(pdu) => pdu,
+                    None => return Ok(RoomMessageEventContent::text_plain("Event not found.")),
+                };
+
+                let already_redacted = pdu
+                    .unsigned
+                    .as_ref()
+                    .and_then(|unsigned| {
+                        serde_json::from_str::<serde_json::Value>(unsigned.get()).ok()
+                    })
+                    .map_or(false, |unsigned| unsigned.get("redacted_because").is_some());
+                if already_redacted {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "Event is already redacted.",
+                    ));
+                }
+
+                if !services()
+                    .rooms
+                    .state_cache
+                    .server_in_room(services().globals.server_name(), &pdu.room_id)?
+                {
+                    return Ok(RoomMessageEventContent::text_plain(format!(
+                        "This server is not in room {}.",
+                        pdu.room_id
+                    )));
+                }
+
+                let conduit_user =
+                    UserId::parse(format!("@conduit:{}", services().globals.server_name()))
+                        .expect("@conduit:server_name is valid");
+                let sender_user = services()
+                    .globals
+                    .moderation_user()
+                    .unwrap_or(&conduit_user);
+                if sender_user.server_name() != services().globals.server_name() {
+                    return Ok(RoomMessageEventContent::text_plain(format!(
+                        "Moderation user {sender_user} is not a local user."
+                    )));
+                }
+                if !services()
+                    .rooms
+                    .state_cache
+                    .is_joined(sender_user, &pdu.room_id)?
+                {
+                    return Ok(RoomMessageEventContent::text_plain(format!(
+                        "{sender_user} is not joined to room {}.",
+                        pdu.room_id
+                    )));
+                }
+
+                let reason = if reason.is_empty() {
+                    None
+                } else {
+                    Some(reason.join(" "))
+                };
+
+                let mutex_state = Arc::clone(
+                    services()
+                        .globals
+                        .roomid_mutex_state
+                        .write()
+                        .unwrap()
+                        .entry(pdu.room_id.clone())
+                        .or_default(),
+                );
+                let state_lock = mutex_state.lock().await;
+
+                let result = services().rooms.timeline.build_and_append_pdu(
+                    PduBuilder {
+                        event_type: TimelineEventType::RoomRedaction,
+                        content: to_raw_value(&RoomRedactionEventContent {
+                            redacts: Some(event_id.clone().into()),
+                            reason,
+                        })
+                        .expect("event is valid, we just created it"),
+                        unsigned: None,
+                        state_key: None,
+                        redacts: Some(event_id.clone().into()),
+                    },
+                    sender_user,
+                    &pdu.room_id,
+                    &state_lock,
+                );
+
+                drop(state_lock);
+
+                match result {
+                    Ok(redaction_id) => RoomMessageEventContent::text_plain(format!(
+                        "Redacted {event_id} in {} with {redaction_id}.",
+                        pdu.room_id
+                    )),
+                    Err(e) => RoomMessageEventContent::text_plain(format!(
+                        "Failed to redact {event_id}: {e}"
+                    )),
+                }
+            }
+            AdminCommand::SendNotice { user_id, message } => {
+                let content = RoomMessageEventContent::notice_plain(message.join(" "));
+
+                let user_ids = if user_id == "*" {
+                    let conduit_user =
+                        UserId::parse(format!("@conduit:{}", services().globals.server_name()))
+                            .expect("@conduit:server_name is valid");
+
+                    services()
+                        .users
+                        .iter()
+                        .filter_map(|r| r.ok())
+                        .filter(|user_id| user_id != &conduit_user)
+                        .filter(|user_id| !services().users.is_deactivated(user_id).unwrap_or(true))
+                        .collect()
+                } else {
+                    let user_id = match UserId::parse(user_id) {
+                        Ok(user_id) => user_id,
+                        Err(e) => {
+                            return Ok(RoomMessageEventContent::text_plain(format!(
+                                "The supplied user ID is not valid: {e}"
+                            )))
+                        }
+                    };
+                    if user_id.server_name() != services().globals.server_name()
+                        || !services().users.exists(&user_id)?
+                    {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "The supplied user is not a local user.",
+                        ));
+                    }
+                    vec![user_id]
+                };
+
+                let mut sent = 0;
+                let mut failed = Vec::new();
+                for user_id in &user_ids {
+                    match services()
+                        .server_notices
+                        .send_notice(user_id, &content)
+                        .await
+                    {
+                        Ok(_) => sent += 1,
+                        Err(e) => failed.push(format!("{user_id}: {e}")),
+                    }
+                }
+
+                if failed.is_empty() {
+                    RoomMessageEventContent::text_plain(format!("Sent notice to {sent} user(s)."))
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Sent notice to {sent} user(s), failed for {}:\n{}",
+                        failed.len(),
+                        failed.join("\n")
+                    ))
+                }
+            }
             AdminCommand::MemoryUsage => {
                 let response1 = services().memory_usage();
                 let response2 = services().globals.db.memory_usage();
@@ -614,6 +1040,79 @@ impl Service {
                     )),
                 }
             }
+            AdminCommand::ListDevices { user_id } => {
+                if !services().users.exists(&user_id)? {
+                    return Ok(RoomMessageEventContent::text_plain(format!(
+                        "User {user_id} doesn't exist on this server"
+                    )));
+                }
+
+                let mut devices = Vec::new();
+                for device in services().users.all_devices_metadata(&user_id) {
+                    let device = device?;
+                    let last_seen = services()
+                        .users
+                        .device_last_seen(&user_id, &device.device_id)?;
+
+                    devices.push(format!(
+                        "{} ({}): last seen {} from {} with {}",
+                        device.device_id,
+                        device.display_name.as_deref().unwrap_or("no name"),
+                        last_seen
+                            .as_ref()
+                            .map(|l| l.ts)
+                            .or(device.last_seen_ts)
+                            .map_or("never".to_owned(), |ts| ts.get().to_string()),
+                        last_seen
+                            .as_ref()
+                            .and_then(|l| l.ip.as_deref())
+                            .unwrap_or("unknown IP"),
+                        last_seen
+                            .as_ref()
+                            .and_then(|l| l.user_agent.as_deref())
+                            .unwrap_or("unknown user agent"),
+                    ));
+                }
+
+                RoomMessageEventContent::text_plain(format!(
+                    "Devices of {user_id} ({}), timestamps in milliseconds since the unix epoch:\n{}",
+                    devices.len(),
+                    devices.join("\n")
+                ))
+            }
+            AdminCommand::SoftLogout { all, user_id } => {
+                let user_ids = match user_id {
+                    Some(user_id) => {
+                        if !services().users.exists(&user_id)? {
+                            return Ok(RoomMessageEventContent::text_plain(format!(
+                                "User {user_id} doesn't exist on this server"
+                            )));
+                        }
+                        vec![OwnedUserId::from(user_id)]
+                    }
+                    None if all => services()
+                        .users
+                        .iter()
+                        .filter_map(|r| r.ok())
+                        .filter(|user_id| user_id.server_name() == services().globals.server_name())
+                        .collect(),
+                    None => {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "Either a user id or --all is required",
+                        ))
+                    }
+                };
+
+                let mut devices = 0;
+                for user_id in &user_ids {
+                    devices += services().users.soft_logout_user(user_id)?;
+                }
+
+                RoomMessageEventContent::text_plain(format!(
+                    "Soft logged out {devices} devices of {} users",
+                    user_ids.len()
+                ))
+            }
             AdminCommand::CreateUser { username, password } => {
                 let password =
                     password.unwrap_or_else(|| utils::random_string(AUTO_GEN_PASSWORD_LENGTH));
@@ -676,6 +1175,78 @@ impl Service {
                     "Created user with user_id: {user_id} and password: {password}"
                 ))
             }
+            AdminCommand::CreateRegistrationToken {
+                uses,
+                expires_in_days,
+                token,
+            } => {
+                let token =
+                    token.unwrap_or_else(|| utils::random_string(REGISTRATION_TOKEN_LENGTH));
+                if token.is_empty()
+                    || token.len() > 64
+                    || !token
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "._~-".contains(c))
+                {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "Tokens may only have up to 64 letters, digits and the characters ._~-",
+                    ));
+                }
+
+                if uses == Some(0) {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "A token has to allow at least one registration.",
+                    ));
+                }
+
+                let expires_at = expires_in_days.map(|days| {
+                    utils::millis_since_unix_epoch() + u64::from(days) * 24 * 60 * 60 * 1000
+                });
+                services().uiaa.create_registration_token(
+                    &token,
+                    &RegistrationTokenInfo {
+                        uses_remaining: uses,
+                        expires_at,
+                    },
+                )?;
+
+                RoomMessageEventContent::text_plain(format!("Created registration token: {token}"))
+            }
+            AdminCommand::ListRegistrationTokens => {
+                let tokens = services()
+                    .uiaa
+                    .registration_tokens()
+                    .filter_map(|r| r.ok())
+                    .collect::<Vec<_>>();
+
+                let mut msg = format!("Found {} registration token(s):\n", tokens.len());
+                for (token, info) in tokens {
+                    let uses = match info.uses_remaining {
+                        Some(uses) => format!("{uses} use(s) left"),
+                        None => "unlimited uses".to_owned(),
+                    };
+                    let expiry = match info.expires_at {
+                        Some(expires_at) => {
+                            let now = utils::millis_since_unix_epoch();
+                            if expires_at > now {
+                                format!(
+                                    "expires in {} hour(s)",
+                                    (expires_at - now) / (60 * 60 * 1000)
+                                )
+                            } else {
+                                "expired".to_owned()
+                            }
+                        }
+                        None => "never expires".to_owned(),
+                    };
+                    msg += &format!("{token}: {uses}, {expiry}\n");
+                }
+                RoomMessageEventContent::text_plain(&msg)
+            }
+            AdminCommand::DeleteRegistrationToken { token } => {
+                services().uiaa.remove_registration_token(&token)?;
+                RoomMessageEventContent::text_plain(format!("Deleted registration token: {token}"))
+            }
             AdminCommand::DisableRoom { room_id } => {
                 services().rooms.metadata.disable_room(&room_id, true)?;
                 RoomMessageEventContent::text_plain("Room disabled.")
@@ -684,6 +1255,126 @@ impl Service {
                 services().rooms.metadata.disable_room(&room_id, false)?;
                 RoomMessageEventContent::text_plain("Room enabled.")
             }
+            AdminCommand::PurgeRoom {
+                leave_rooms,
+                block,
+                room_id,
+            } => {
+                let admin_room_alias: Box<RoomAliasId> =
+                    format!("#admins:{}", services().globals.server_name())
+                        .try_into()
+                        .expect("#admins:server_name is a valid alias name");
+                if services()
+                    .rooms
+                    .alias
+                    .resolve_local_alias(&admin_room_alias)?
+                    .map_or(false, |admin_room| *admin_room == *room_id)
+                {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "The admin room can't be purged.",
+                    ));
+                }
+
+                let mut failed = Vec::new();
+                if leave_rooms {
+                    let local_users = services()
+                        .rooms
+                        .state_cache
+                        .room_members(&room_id)
+                        .chain(services().rooms.state_cache.room_members_invited(&room_id))
+                        .filter_map(|r| r.ok())
+                        .filter(|user_id| user_id.server_name() == services().globals.server_name())
+                        .collect::<Vec<_>>();
+
+                    for user_id in local_users {
+                        if let Err(e) = leave_room(&user_id, &room_id, None).await {
+                            failed.push(format!("{user_id}: {e}"));
+                        }
+                    }
+
+                    // The leave events are sent from the database, they have to go out first
+                    let start = Instant::now();
+                    while services().sending.has_queued_pdus(&room_id)?
+                        && start.elapsed() < PURGE_LEAVE_TIMEOUT
+                    {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+
+                // Blocking first keeps anyone from joining again while the room is purged
+                if block {
+                    services().rooms.metadata.block_room(&room_id, true)?;
+                }
+                let freed = services().rooms.metadata.purge_room(&room_id).await?;
+
+                let mut msg = format!("Purged {room_id}, freeing {freed} bytes.");
+                if block {
+                    msg.push_str(" The room is blocked now.");
+                }
+                if !failed.is_empty() {
+                    msg.push_str(&format!(
+                        "\nThese users failed to leave the room first:\n{}",
+                        failed.join("\n")
+                    ));
+                }
+
+                RoomMessageEventContent::text_plain(msg)
+            }
+            AdminCommand::BlockRoom { room_id } => {
+                services().rooms.metadata.block_room(&room_id, true)?;
+                RoomMessageEventContent::text_plain("Room blocked.")
+            }
+            AdminCommand::UnblockRoom { room_id } => {
+                services().rooms.metadata.block_room(&room_id, false)?;
+                RoomMessageEventContent::text_plain("Room unblocked.")
+            }
+            AdminCommand::BlockUser { user_id } => {
+                if user_id.server_name() == services().globals.server_name()
+                    && user_id.localpart() == "conduit"
+                {
+                    return Ok(RoomMessageEventContent::text_plain(
+                        "The server user can't be blocked.",
+                    ));
+                }
+
+                services().users.block_user(&user_id, true)?;
+                RoomMessageEventContent::text_plain(format!("User {user_id} blocked."))
+            }
+            AdminCommand::UnblockUser { user_id } => {
+                services().users.block_user(&user_id, false)?;
+                RoomMessageEventContent::text_plain(format!("User {user_id} unblocked."))
+            }
+            AdminCommand::ListReports { room_id } => {
+                let reports = services()
+                    .rooms
+                    .reports
+                    .reports(room_id.as_deref())
+                    .filter_map(|r| r.ok())
+                    .map(|report| {
+                        format!(
+                            "{} in {} reported by {} at {} with score {}: {}",
+                            report.event_id,
+                            report.room_id,
+                            report.reporter,
+                            report.timestamp,
+                            report
+                                .score
+                                .map_or("none".to_owned(), |score| score.to_string()),
+                            report.reason.as_deref().unwrap_or("no reason given"),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                if reports.is_empty() {
+                    RoomMessageEventContent::text_plain("No events were reported.")
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Reports ({}), timestamps in milliseconds since the unix epoch:\n{}",
+                        reports.len(),
+                        reports.join("\n")
+                    ))
+                }
+            }
             AdminCommand::DeactivateUser {
                 leave_rooms,
                 user_id,
@@ -694,15 +1385,27 @@ impl Service {
                         "Making {user_id} leave all rooms before deactivation..."
                     ));
 
-                    services().users.deactivate_account(&user_id)?;
+                    let failed = if leave_rooms {
+                        services().users.deactivate(&user_id, false).await?
+                    } else {
+                        services().users.deactivate_account(&user_id)?;
+                        Vec::new()
+                    };
 
-                    if leave_rooms {
-                        leave_all_rooms(&user_id).await?;
+                    if failed.is_empty() {
+                        RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} has been deactivated"
+                        ))
+                    } else {
+                        RoomMessageEventContent::text_plain(format!(
+                            "User {user_id} has been deactivated, but leaving these rooms failed:\n{}",
+                            failed
+                                .iter()
+                                .map(|(room_id, e)| format!("{room_id}: {e}"))
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                        ))
                     }
-
-                    RoomMessageEventContent::text_plain(format!(
-                        "User {user_id} has been deactivated"
-                    ))
                 } else {
                     RoomMessageEventContent::text_plain(format!(
                         "User {user_id} doesn't exist on this server"
@@ -768,6 +1471,151 @@ impl Service {
                     )
                 }
             }
+            AdminCommand::DeactivateGuests => {
+                let mut deactivated = 0;
+                let mut failed = Vec::new();
+
+                for user_id in services().users.iter().collect::<Vec<_>>() {
+                    let user_id = user_id?;
+                    if user_id.server_name() != services().globals.server_name()
+                        || !services().users.is_guest(&user_id)?
+                    {
+                        continue;
+                    }
+
+                    match services().users.deactivate(&user_id, false).await {
+                        Ok(failed_rooms) if failed_rooms.is_empty() => deactivated += 1,
+                        Ok(_) => {
+                            deactivated += 1;
+                            failed.push(format!("{user_id}: could not leave some rooms"));
+                        }
+                        Err(e) => failed.push(format!("{user_id}: {e}")),
+                    }
+                }
+
+                if failed.is_empty() {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Deactivated {deactivated} guest accounts."
+                    ))
+                } else {
+                    RoomMessageEventContent::text_plain(format!(
+                        "Deactivated {deactivated} guest accounts. These failed:\n{}",
+                        failed.join("\n")
+                    ))
+                }
+            }
+            AdminCommand::ListSessions { user_id } => {
+                if !services().users.exists(&user_id)? {
+                    return Ok(RoomMessageEventContent::text_plain(format!(
+                        "User {user_id} doesn't exist on this server"
+                    )));
+                }
+
+                let now = utils::millis_since_unix_epoch();
+                let ago = |millis: u64| {
+                    let minutes = now.saturating_sub(millis) / 1000 / 60;
+                    if minutes < 60 {
+                        format!("{minutes}m ago")
+                    } else if minutes < 48 * 60 {
+                        format!("{}h ago", minutes / 60)
+                    } else {
+                        format!("{}d ago", minutes / 60 / 24)
+                    }
+                };
+
+                let devices = services()
+                    .users
+                    .all_devices_metadata(&user_id)
+                    .filter_map(|r| r.ok())
+                    .collect::<Vec<_>>();
+
+                let mut msg = format!("{user_id} has {} device(s):\n", devices.len());
+                for device in devices {
+                    let last_seen = services()
+                        .users
+                        .device_last_seen(&user_id, &device.device_id)?;
+                    let last_active = services()
+                        .users
+                        .device_last_active(&user_id, &device)?
+                        .map_or_else(
+                            || "never used".to_owned(),
+                            |ts| format!("last seen {}", ago(ts.get().into())),
+                        );
+
+                    msg += &format!(
+                        "{} \"{}\": {}",
+                        device.device_id,
+                        device.display_name.as_deref().unwrap_or_default(),
+                        last_active
+                    );
+                    if let Some(ip) = last_seen.as_ref().and_then(|l| l.ip.as_deref()) {
+                        msg += &format!(" from {ip}");
+                    }
+                    if let Some(user_agent) =
+                        last_seen.as_ref().and_then(|l| l.user_agent.as_deref())
+                    {
+                        msg += &format!(" ({user_agent})");
+                    }
+                    msg += "\n";
+                }
+                RoomMessageEventContent::text_plain(&msg)
+            }
+            AdminCommand::EvictInactive { days } => {
+                let before = MilliSecondsSinceUnixEpoch(
+                    utils::millis_since_unix_epoch()
+                        .saturating_sub(days.saturating_mul(24 * 60 * 60 * 1000))
+                        .try_into()
+                        .expect("time is valid"),
+                );
+                let removed = services().users.remove_inactive_devices(before)?;
+
+                RoomMessageEventContent::text_plain(format!(
+                    "Logged out {removed} device(s) not seen in {days} day(s), their access tokens are invalid now."
+                ))
+            }
+            AdminCommand::RecomputeState { room_id, confirm } => {
+                let (added, removed) = services()
+                    .rooms
+                    .event_handler
+                    .recompute_state(&room_id, confirm)
+                    .await?;
+
+                let describe = |compressed| {
+                    let (shortstatekey, event_id) = services()
+                        .rooms
+                        .state_compressor
+                        .parse_compressed_state_event(compressed)?;
+                    let (event_type, state_key) = services()
+                        .rooms
+                        .short
+                        .get_statekey_from_short(shortstatekey)?;
+                    Ok::<_, Error>(format!("{event_type} \"{state_key}\": {event_id}"))
+                };
+
+                let mut msg = if added.is_empty() && removed.is_empty() {
+                    "The current state already matches the resolved state.\n".to_owned()
+                } else if confirm {
+                    format!(
+                        "Replaced the current state, {} event(s) added and {} removed:\n",
+                        added.len(),
+                        removed.len()
+                    )
+                } else {
+                    format!(
+                        "Recomputing would add {} and remove {} state event(s). Run again with --confirm to apply this:\n",
+                        added.len(),
+                        removed.len()
+                    )
+                };
+                for compressed in &added {
+                    msg += &format!("+ {}\n", describe(compressed)?);
+                }
+                for compressed in &removed {
+                    msg += &format!("- {}\n", describe(compressed)?);
+                }
+
+                RoomMessageEventContent::text_plain(&msg)
+            }
             AdminCommand::SignJson => {
                 if body.len() > 2 && body[0].trim() == "```" && body.last().unwrap().trim() == "```"
                 {
@@ -935,10 +1783,15 @@ impl Service {
 
         services().users.create(&conduit_user, None)?;
 
-        let mut content = RoomCreateEventContent::new_v1(conduit_user.clone());
+        let room_version = services().globals.default_room_version();
+        let mut content = if utils::has_creator_field(&room_version) {
+            RoomCreateEventContent::new_v1(conduit_user.clone())
+        } else {
+            RoomCreateEventContent::new_v11()
+        };
         content.federate = true;
         content.predecessor = None;
-        content.room_version = services().globals.default_room_version();
+        content.room_version = room_version;
 
         // 1. The room create event
         services().rooms.timeline.build_and_append_pdu(
@@ -1100,7 +1953,10 @@ impl Service {
             &state_lock,
         )?;
 
-        services().rooms.alias.set_alias(&alias, &room_id)?;
+        services()
+            .rooms
+            .alias
+            .set_alias(&alias, &room_id, &conduit_user)?;
 
         Ok(())
     }
@@ -1258,4 +2114,51 @@ mod test {
         assert!(error.contains("Commands:"));
         assert!(error.contains("Options:"));
     }
+
+    #[test]
+    fn registration_token_options() {
+        match AdminCommand::try_parse_from([
+            "argv[0]",
+            "create-registration-token",
+            "--uses",
+            "5",
+            "-e",
+            "7",
+            "invite-only",
+        ])
+        .unwrap()
+        {
+            AdminCommand::CreateRegistrationToken {
+                uses,
+                expires_in_days,
+                token,
+            } => {
+                assert_eq!(uses, Some(5));
+                assert_eq!(expires_in_days, Some(7));
+                assert_eq!(token.as_deref(), Some("invite-only"));
+            }
+            command => panic!("parsed as {command:?}"),
+        }
+    }
+
+    #[test]
+    fn redact_reason_is_optional() {
+        match AdminCommand::try_parse_from(["argv[0]", "redact", "$event:example.org"]).unwrap() {
+            AdminCommand::Redact { reason, .. } => assert!(reason.is_empty()),
+            command => panic!("parsed as {command:?}"),
+        }
+
+        match AdminCommand::try_parse_from([
+            "argv[0]",
+            "redact",
+            "$event:example.org",
+            "spam",
+            "links",
+        ])
+        .unwrap()
+        {
+            AdminCommand::Redact { reason, .. } => assert_eq!(reason, ["spam", "links"]),
+            command => panic!("parsed as {command:?}"),
+        }
+    }
 }
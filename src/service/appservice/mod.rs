@@ -1,17 +1,276 @@
 mod data;
 
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
 pub use data::Data;
+use regex::RegexSet;
+use ruma::{
+    api::{appservice::query, client::error::ErrorKind},
+    OwnedRoomId, RoomAliasId, RoomId, UserId,
+};
+use tokio::time::timeout;
+use tracing::warn;
+
+use crate::{services, Error, Result};
+
+/// How long we wait for an appservice to create a user or room we asked about.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The regexes of one kind of namespace of an appservice, compiled once so they can be matched
+/// against every event.
+#[derive(Clone, Debug, Default)]
+pub struct NamespaceRegex {
+    pub exclusive: Option<RegexSet>,
+    pub non_exclusive: Option<RegexSet>,
+}
+
+impl NamespaceRegex {
+    /// Compiles the namespaces of the kind (users, aliases or rooms) of a registration.
+    fn parse(namespaces: Option<&serde_yaml::Value>, kind: &str) -> Result<Self> {
+        let mut exclusive = Vec::new();
+        let mut non_exclusive = Vec::new();
+
+        let entries = namespaces
+            .and_then(|namespaces| namespaces.get(kind))
+            .and_then(|entries| entries.as_sequence())
+            .map_or(&[][..], |entries| entries.as_slice());
+        for entry in entries {
+            let regex =
+                entry
+                    .get("regex")
+                    .and_then(|regex| regex.as_str())
+                    .ok_or(Error::BadRequest(
+                        ErrorKind::InvalidParam,
+                        "Appservice namespace has no regex.",
+                    ))?;
+
+            if entry
+                .get("exclusive")
+                .and_then(|exclusive| exclusive.as_bool())
+                .unwrap_or(false)
+            {
+                exclusive.push(regex.to_owned());
+            } else {
+                non_exclusive.push(regex.to_owned());
+            }
+        }
+
+        let compile = |patterns: Vec<String>| -> Result<Option<RegexSet>> {
+            if patterns.is_empty() {
+                return Ok(None);
+            }
+            RegexSet::new(patterns).map(Some).map_err(|_| {
+                Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Appservice namespace has an invalid regex.",
+                )
+            })
+        };
+
+        Ok(Self {
+            exclusive: compile(exclusive)?,
+            non_exclusive: compile(non_exclusive)?,
+        })
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        self.is_exclusive_match(s)
+            || self
+                .non_exclusive
+                .as_ref()
+                .map_or(false, |set| set.is_match(s))
+    }
+
+    pub fn is_exclusive_match(&self, s: &str) -> bool {
+        self.exclusive.as_ref().map_or(false, |set| set.is_match(s))
+    }
+
+    fn patterns(&self) -> impl Iterator<Item = &String> {
+        self.exclusive
+            .iter()
+            .chain(self.non_exclusive.iter())
+            .flat_map(|set| set.patterns())
+    }
+
+    /// Whether the exclusive namespaces of one could contain names of the other.
+    ///
+    /// Regexes can't be compared in general, so this conservatively compares the literal
+    /// prefixes: `@irc_.*` and `@irc_freenode_.*` overlap, `@irc_.*` and `@slack_.*` don't.
+    fn overlaps_exclusive(&self, other: &Self) -> bool {
+        let overlaps = |exclusive: &Option<RegexSet>, patterns: Vec<&String>| {
+            exclusive.iter().flat_map(|set| set.patterns()).any(|a| {
+                patterns.iter().any(|b| {
+                    let (a, b) = (literal_prefix(a), literal_prefix(b));
+                    a.starts_with(&b) || b.starts_with(&a)
+                })
+            })
+        };
 
-use crate::Result;
+        overlaps(&self.exclusive, other.patterns().collect())
+            || overlaps(&other.exclusive, self.patterns().collect())
+    }
+}
+
+/// The part of the regex every match starts with.
+fn literal_prefix(regex: &str) -> String {
+    let mut prefix = String::new();
+    let mut chars = regex.strip_prefix('^').unwrap_or(regex).chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) if escaped.is_ascii_punctuation() => prefix.push(escaped),
+                _ => break,
+            },
+            '.' | '*' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '$' => break,
+            _ => {
+                // The character is optional or repeated
+                if matches!(chars.peek(), Some('*' | '?' | '{')) {
+                    break;
+                }
+                prefix.push(c);
+            }
+        }
+    }
+
+    prefix
+}
+
+/// An appservice registration with its compiled namespaces.
+#[derive(Clone, Debug)]
+pub struct RegistrationInfo {
+    pub id: String,
+    pub registration: serde_yaml::Value,
+    pub as_token: String,
+    pub sender_localpart: String,
+    pub users: NamespaceRegex,
+    pub aliases: NamespaceRegex,
+    pub rooms: NamespaceRegex,
+    /// Whether the appservice wants typing, receipts and presence (MSC2409)
+    pub push_ephemeral: bool,
+    /// Whether the appservice may act as the devices of its users (MSC3202)
+    pub device_masquerading: bool,
+}
+
+impl RegistrationInfo {
+    fn parse(registration: serde_yaml::Value) -> Result<Self> {
+        let field = |name: &str, error: &'static str| {
+            registration
+                .get(name)
+                .and_then(|value| value.as_str())
+                .map(ToOwned::to_owned)
+                .ok_or(Error::BadRequest(ErrorKind::InvalidParam, error))
+        };
+        let id = field("id", "Appservice registration has no id.")?;
+        let as_token = field("as_token", "Appservice registration has no as_token.")?;
+        let sender_localpart = field(
+            "sender_localpart",
+            "Appservice registration has no sender_localpart.",
+        )?;
+
+        let push_ephemeral = registration
+            .get("de.sorunome.msc2409.push_ephemeral")
+            .and_then(|push_ephemeral| push_ephemeral.as_bool())
+            .unwrap_or(false);
+
+        let device_masquerading = registration
+            .get("org.matrix.msc3202")
+            .and_then(|device_masquerading| device_masquerading.as_bool())
+            .unwrap_or(false);
+
+        let namespaces = registration.get("namespaces");
+        Ok(Self {
+            users: NamespaceRegex::parse(namespaces, "users")?,
+            aliases: NamespaceRegex::parse(namespaces, "aliases")?,
+            rooms: NamespaceRegex::parse(namespaces, "rooms")?,
+            id,
+            registration,
+            as_token,
+            sender_localpart,
+            push_ephemeral,
+            device_masquerading,
+        })
+    }
+
+    /// Whether the user is the sender of the appservice.
+    pub fn is_sender(&self, user_id: &UserId) -> bool {
+        user_id.localpart() == self.sender_localpart
+            && user_id.server_name() == services().globals.server_name()
+    }
+
+    /// Whether the user is the sender of the appservice or in its namespaces.
+    pub fn is_user_match(&self, user_id: &UserId) -> bool {
+        self.is_sender(user_id) || self.users.is_match(user_id.as_str())
+    }
+
+    pub fn is_alias_match(&self, alias: &RoomAliasId) -> bool {
+        self.aliases.is_match(alias.as_str())
+    }
+
+    pub fn is_room_match(&self, room_id: &RoomId) -> bool {
+        self.rooms.is_match(room_id.as_str())
+    }
+}
 
 pub struct Service {
     pub db: &'static dyn Data,
+    registration_info: RwLock<BTreeMap<String, Arc<RegistrationInfo>>>,
 }
 
 impl Service {
+    /// Loads and compiles the stored registrations. Invalid ones are ignored.
+    pub fn build(db: &'static dyn Data) -> Result<Self> {
+        let mut registration_info = BTreeMap::new();
+        for (id, registration) in db.all()? {
+            match RegistrationInfo::parse(registration) {
+                Ok(info) => {
+                    registration_info.insert(id, Arc::new(info));
+                }
+                Err(e) => warn!("Ignoring invalid registration of appservice {}: {}", id, e),
+            }
+        }
+
+        Ok(Self {
+            db,
+            registration_info: RwLock::new(registration_info),
+        })
+    }
+
     /// Registers an appservice and returns the ID to the caller
+    ///
+    /// Fails if the namespaces are invalid or the exclusive namespaces overlap with the ones of
+    /// another appservice.
     pub fn register_appservice(&self, yaml: serde_yaml::Value) -> Result<String> {
-        self.db.register_appservice(yaml)
+        let info = RegistrationInfo::parse(yaml.clone())?;
+
+        let mut registration_info = self.registration_info.write().unwrap();
+        for other in registration_info
+            .values()
+            .filter(|other| other.id != info.id)
+        {
+            if info.users.overlaps_exclusive(&other.users)
+                || info.aliases.overlaps_exclusive(&other.aliases)
+                || info.rooms.overlaps_exclusive(&other.rooms)
+            {
+                warn!(
+                    "Namespaces of appservice {} overlap with appservice {}",
+                    info.id, other.id
+                );
+                return Err(Error::BadRequest(
+                    ErrorKind::Exclusive,
+                    "The exclusive namespaces overlap with the ones of another appservice.",
+                ));
+            }
+        }
+
+        let id = self.db.register_appservice(yaml)?;
+        registration_info.insert(id.clone(), Arc::new(info));
+
+        Ok(id)
     }
 
     /// Remove an appservice registration
@@ -20,7 +279,10 @@ impl Service {
     ///
     /// * `service_name` - the name you send to register the service previously
     pub fn unregister_appservice(&self, service_name: &str) -> Result<()> {
-        self.db.unregister_appservice(service_name)
+        self.db.unregister_appservice(service_name)?;
+        self.registration_info.write().unwrap().remove(service_name);
+
+        Ok(())
     }
 
     pub fn get_registration(&self, id: &str) -> Result<Option<serde_yaml::Value>> {
@@ -34,4 +296,140 @@ impl Service {
     pub fn all(&self) -> Result<Vec<(String, serde_yaml::Value)>> {
         self.db.all()
     }
+
+    /// Returns the compiled registrations of all appservices.
+    pub fn all_info(&self) -> Vec<Arc<RegistrationInfo>> {
+        self.registration_info
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn find_from_token(&self, token: &str) -> Option<Arc<RegistrationInfo>> {
+        self.registration_info
+            .read()
+            .unwrap()
+            .values()
+            .find(|info| info.as_token == token)
+            .cloned()
+    }
+
+    /// Whether an appservice claimed the user exclusively, so nobody else may register it.
+    pub fn is_exclusive_user_id(&self, user_id: &UserId) -> bool {
+        self.registration_info
+            .read()
+            .unwrap()
+            .values()
+            .any(|info| info.users.is_exclusive_match(user_id.as_str()))
+    }
+
+    /// Whether an appservice claimed the alias exclusively, so nobody else may create it.
+    pub fn is_exclusive_alias(&self, alias: &RoomAliasId) -> bool {
+        self.registration_info
+            .read()
+            .unwrap()
+            .values()
+            .any(|info| info.aliases.is_exclusive_match(alias.as_str()))
+    }
+
+    /// Whether the local user exists. Unknown users in the exclusive namespace of an appservice
+    /// are created by it when we ask for them, so bridges only register users when they are
+    /// needed.
+    pub async fn user_exists(&self, user_id: &UserId) -> Result<bool> {
+        if services().users.exists(user_id)? {
+            return Ok(true);
+        }
+
+        let owner = self
+            .all_info()
+            .into_iter()
+            .find(|info| info.users.is_exclusive_match(user_id.as_str()));
+        let Some(owner) = owner else {
+            return Ok(false);
+        };
+
+        let request = services().sending.send_appservice_request(
+            owner.registration.clone(),
+            query::query_user_id::v1::Request {
+                user_id: user_id.to_owned(),
+            },
+        );
+
+        match timeout(QUERY_TIMEOUT, request).await {
+            // The appservice registered the user before answering
+            Ok(Ok(_)) => services().users.exists(user_id),
+            // It doesn't know the user either
+            Ok(Err(_)) => Ok(false),
+            Err(_) => {
+                warn!(
+                    "Appservice {} did not answer in time whether {} exists",
+                    owner.id, user_id
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    /// Asks the appservices in whose namespace the alias is to create the room.
+    pub async fn query_room_alias(&self, room_alias: &RoomAliasId) -> Result<Option<OwnedRoomId>> {
+        for appservice in self.all_info() {
+            if !appservice.is_alias_match(room_alias) {
+                continue;
+            }
+
+            let request = services().sending.send_appservice_request(
+                appservice.registration.clone(),
+                query::query_room_alias::v1::Request {
+                    room_alias: room_alias.to_owned(),
+                },
+            );
+
+            match timeout(QUERY_TIMEOUT, request).await {
+                Ok(Ok(_)) => {
+                    return Ok(Some(
+                        services()
+                            .rooms
+                            .alias
+                            .resolve_local_alias(room_alias)?
+                            .ok_or_else(|| {
+                                Error::bad_config("Appservice lied to us. Room does not exist.")
+                            })?,
+                    ));
+                }
+                Ok(Err(_)) => {}
+                Err(_) => warn!(
+                    "Appservice {} did not answer in time whether {} exists",
+                    appservice.id, room_alias
+                ),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespace(exclusive: &[&str], non_exclusive: &[&str]) -> NamespaceRegex {
+        NamespaceRegex {
+            exclusive: (!exclusive.is_empty()).then(|| RegexSet::new(exclusive).unwrap()),
+            non_exclusive: (!non_exclusive.is_empty())
+                .then(|| RegexSet::new(non_exclusive).unwrap()),
+        }
+    }
+
+    #[test]
+    fn exclusive_namespaces_overlap_by_prefix() {
+        let irc = namespace(&["^@irc_.*:example\\.org$"], &[]);
+
+        assert!(irc.overlaps_exclusive(&namespace(&[], &["@irc_freenode_.*"])));
+        assert!(namespace(&["@irc_freenode_.*"], &[]).overlaps_exclusive(&irc));
+        assert!(!irc.overlaps_exclusive(&namespace(&["@slack_.*"], &[])));
+        // Non-exclusive namespaces may be shared
+        assert!(!namespace(&[], &["@bot.*"]).overlaps_exclusive(&namespace(&[], &["@bot.*"])));
+    }
 }
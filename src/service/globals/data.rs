@@ -11,12 +11,32 @@ use crate::Result;
 
 #[async_trait]
 pub trait Data: Send + Sync {
-    fn next_count(&self) -> Result<u64>;
+    /// Persists a counter value `amount` higher than the current one and returns it. Callers must
+    /// make sure there are no concurrent reservations.
+    fn reserve_counts(&self, amount: u64) -> Result<u64>;
+    /// Returns the highest persisted counter value.
     fn current_count(&self) -> Result<u64>;
     fn last_check_for_updates_id(&self) -> Result<u64>;
     fn update_check_for_updates_id(&self, id: u64) -> Result<()>;
+    /// Resolves when there might be something new for the sync of the device. These trees are
+    /// watched:
+    /// - To-device events of the device
+    /// - The user's memberships, notification and highlight counts: `userroomid_joined`,
+    ///   `userroomid_invitestate`, `userroomid_leftstate`, `userroomid_knockstate`,
+    ///   `userroomid_notificationcount` and `userroomid_highlightcount`
+    /// - For every joined room: `pduid_pdu`, `roomid_lasttypingupdate`,
+    ///   `readreceiptid_readreceipt`, `presenceid_presence`, `keychangeid_userid` and the room
+    ///   account data of the user
+    /// - The user's global account data, `keychangeid_userid`, `observerchangeid_userid`,
+    ///   `userid_lastonetimekeyupdate` and `userid_lastbackupdeletion`
+    ///
+    /// Only inserts wake watchers. Changes that only remove entries have to insert into one of
+    /// these trees too, like deleting a key backup version does with `userid_lastbackupdeletion`.
+    /// Changes that sync doesn't need to see, like backed up room keys, fire
+    /// `services().globals.user_watchers` instead.
     async fn watch(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()>;
     fn cleanup(&self) -> Result<()>;
+    fn flush(&self) -> Result<()>;
     fn memory_usage(&self) -> String;
     fn clear_caches(&self, amount: u32);
     fn load_keypair(&self) -> Result<Ed25519KeyPair>;
@@ -7,8 +7,9 @@ use ruma::{
 
 use crate::api::server_server::FedDest;
 
-use crate::{services, Config, Error, Result};
+use crate::{config::FlushStrategy, services, Config, Error, Result};
 use futures_util::FutureExt;
+use http::Method;
 use hyper::{
     client::connect::dns::{GaiResolver, Name},
     service::Service as HyperService,
@@ -18,11 +19,12 @@ use ruma::{
     api::{
         client::sync::sync_events,
         federation::discovery::{ServerSigningKeys, VerifyKey},
+        Metadata,
     },
     DeviceId, RoomVersionId, ServerName, UserId,
 };
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     error::Error as StdError,
     fs,
     future::{self, Future},
@@ -30,7 +32,7 @@ use std::{
     net::{IpAddr, SocketAddr},
     path::PathBuf,
     sync::{
-        atomic::{self, AtomicBool},
+        atomic::{self, AtomicBool, AtomicU64},
         Arc, Mutex, RwLock,
     },
     time::{Duration, Instant},
@@ -41,19 +43,25 @@ use trust_dns_resolver::TokioAsyncResolver;
 
 use base64::{engine::general_purpose, Engine as _};
 
-type WellKnownMap = HashMap<OwnedServerName, (FedDest, String)>;
+type WellKnownMap = HashMap<OwnedServerName, (FedDest, String, Instant)>; // actual_destination, host, valid until
 type TlsNameMap = HashMap<String, (Vec<IpAddr>, u16)>;
 type RateLimitState = (Instant, u32); // Time if last failed try, number of failed tries
+
 type SyncHandle = (
     Option<String>,                                      // since
     Receiver<Option<Result<sync_events::v3::Response>>>, // rx
 );
 
+/// How many counts are reserved on disk at once. Counts of a reservation that weren't handed out
+/// before a restart are skipped, so no count is ever used twice.
+const COUNTER_RESERVATION: u64 = 1000;
+
 pub struct Service {
     pub db: &'static dyn Data,
 
-    pub actual_destination_cache: Arc<RwLock<WellKnownMap>>, // actual_destination, host
+    pub actual_destination_cache: Arc<RwLock<WellKnownMap>>,
     pub tls_name_override: Arc<RwLock<TlsNameMap>>,
+    pub federation_pool_stats: Arc<FederationPoolStats>,
     pub config: Config,
     keypair: Arc<ruma::signatures::Ed25519KeyPair>,
     dns_resolver: TokioAsyncResolver,
@@ -70,9 +78,14 @@ pub struct Service {
     pub roomid_mutex_insert: RwLock<HashMap<OwnedRoomId, Arc<Mutex<()>>>>,
     pub roomid_mutex_state: RwLock<HashMap<OwnedRoomId, Arc<TokioMutex<()>>>>,
     pub roomid_mutex_federation: RwLock<HashMap<OwnedRoomId, Arc<TokioMutex<()>>>>, // this lock will be held longer
+    pub userid_mutex_account_data: RwLock<HashMap<OwnedUserId, Arc<Mutex<()>>>>,
     pub roomid_federationhandletime: RwLock<HashMap<OwnedRoomId, (OwnedEventId, Instant)>>,
     pub stateres_mutex: Arc<Mutex<()>>,
+    /// Method and paths of the Ruma endpoints in the router, so /versions can tell what we serve
+    pub served_endpoints: RwLock<HashSet<(Method, &'static str)>>,
     pub rotate: RotationHandler,
+    pub user_watchers: UserWatchers,
+    counter: Mutex<Option<(u64, u64)>>, // last handed out count, end of the reserved range
 
     pub shutdown: AtomicBool,
 }
@@ -107,22 +120,69 @@ impl Default for RotationHandler {
     }
 }
 
+/// Wakes tasks waiting for changes of a user's data that doesn't live in a watched tree.
+///
+/// Services fire into this after they modified a user's data, so long-polling requests can await
+/// the next change without every backend having to watch the trees involved.
+#[derive(Default)]
+pub struct UserWatchers(Mutex<HashMap<OwnedUserId, broadcast::Sender<()>>>);
+
+impl UserWatchers {
+    /// Resolves on the next `wake` for this user after this was called.
+    pub fn watch(&self, user_id: &UserId) -> impl Future<Output = ()> {
+        let mut r = self
+            .0
+            .lock()
+            .unwrap()
+            .entry(user_id.to_owned())
+            .or_insert_with(|| broadcast::channel(1).0)
+            .subscribe();
+
+        async move {
+            let _ = r.recv().await;
+        }
+    }
+
+    pub fn wake(&self, user_id: &UserId) {
+        // Every watcher resolves on the first wake, later watchers subscribe to a new sender
+        if let Some(s) = self.0.lock().unwrap().remove(user_id) {
+            let _ = s.send(());
+        }
+    }
+}
+
+/// Counts the requests of the federation client and the connections it opened for them.
+///
+/// Every new connection resolves the destination first, requests that didn't need a resolve
+/// reused a pooled connection.
+#[derive(Default)]
+pub struct FederationPoolStats {
+    pub requests: AtomicU64,
+    pub connections: AtomicU64,
+}
+
 pub struct Resolver {
     inner: GaiResolver,
     overrides: Arc<RwLock<TlsNameMap>>,
+    stats: Arc<FederationPoolStats>,
 }
 
 impl Resolver {
-    pub fn new(overrides: Arc<RwLock<TlsNameMap>>) -> Self {
+    pub fn new(overrides: Arc<RwLock<TlsNameMap>>, stats: Arc<FederationPoolStats>) -> Self {
         Resolver {
             inner: GaiResolver::new(),
             overrides,
+            stats,
         }
     }
 }
 
 impl Resolve for Resolver {
     fn resolve(&self, name: Name) -> Resolving {
+        self.stats
+            .connections
+            .fetch_add(1, atomic::Ordering::Relaxed);
+
         self.overrides
             .read()
             .expect("lock should not be poisoned")
@@ -160,6 +220,7 @@ impl Service {
         };
 
         let tls_name_override = Arc::new(RwLock::new(TlsNameMap::new()));
+        let federation_pool_stats = Arc::new(FederationPoolStats::default());
 
         let jwt_decoding_key = config
             .jwt_secret
@@ -168,7 +229,17 @@ impl Service {
 
         let default_client = reqwest_client_builder(&config)?.build()?;
         let federation_client = reqwest_client_builder(&config)?
-            .dns_resolver(Arc::new(Resolver::new(tls_name_override.clone())))
+            .dns_resolver(Arc::new(Resolver::new(
+                tls_name_override.clone(),
+                federation_pool_stats.clone(),
+            )))
+            .pool_max_idle_per_host(config.federation_pool_max_idle_per_host.into())
+            .pool_idle_timeout(Duration::from_secs(
+                config.federation_pool_idle_timeout_seconds.into(),
+            ))
+            .timeout(Duration::from_secs(
+                config.federation_timeout_seconds.into(),
+            ))
             .build()?;
 
         // Supported and stable room versions
@@ -178,6 +249,7 @@ impl Service {
             RoomVersionId::V8,
             RoomVersionId::V9,
             RoomVersionId::V10,
+            RoomVersionId::V11,
         ];
         // Experimental, partially supported room versions
         let unstable_room_versions = vec![RoomVersionId::V3, RoomVersionId::V4, RoomVersionId::V5];
@@ -195,6 +267,7 @@ impl Service {
             })?,
             actual_destination_cache: Arc::new(RwLock::new(WellKnownMap::new())),
             tls_name_override,
+            federation_pool_stats,
             federation_client,
             default_client,
             jwt_decoding_key,
@@ -207,10 +280,14 @@ impl Service {
             roomid_mutex_state: RwLock::new(HashMap::new()),
             roomid_mutex_insert: RwLock::new(HashMap::new()),
             roomid_mutex_federation: RwLock::new(HashMap::new()),
+            userid_mutex_account_data: RwLock::new(HashMap::new()),
             roomid_federationhandletime: RwLock::new(HashMap::new()),
             stateres_mutex: Arc::new(Mutex::new(())),
+            served_endpoints: RwLock::new(HashSet::new()),
             sync_receivers: RwLock::new(HashMap::new()),
             rotate: RotationHandler::new(),
+            user_watchers: UserWatchers::default(),
+            counter: Mutex::new(None),
             shutdown: AtomicBool::new(false),
         };
 
@@ -238,7 +315,7 @@ impl Service {
         self.default_client.clone()
     }
 
-    /// Returns a client used for resolving .well-knowns
+    /// Returns the client for requests to other servers, which keeps connections open for reuse
     pub fn federation_client(&self) -> reqwest::Client {
         // Client is cheap to clone (Arc wrapper) and avoids lifetime issues
         self.federation_client.clone()
@@ -246,12 +323,29 @@ impl Service {
 
     #[tracing::instrument(skip(self))]
     pub fn next_count(&self) -> Result<u64> {
-        self.db.next_count()
+        let mut counter = self.counter.lock().unwrap();
+
+        let (last, reserved) = match *counter {
+            Some((last, reserved)) if last < reserved => (last, reserved),
+            Some((last, _)) => (last, self.db.reserve_counts(COUNTER_RESERVATION)?),
+            None => {
+                let reserved = self.db.reserve_counts(COUNTER_RESERVATION)?;
+                (reserved - COUNTER_RESERVATION, reserved)
+            }
+        };
+
+        *counter = Some((last + 1, reserved));
+        Ok(last + 1)
     }
 
+    /// Returns the last count that was handed out. This is lower than the persisted counter while
+    /// a reservation isn't used up.
     #[tracing::instrument(skip(self))]
     pub fn current_count(&self) -> Result<u64> {
-        self.db.current_count()
+        match *self.counter.lock().unwrap() {
+            Some((last, _)) => Ok(last),
+            None => self.db.current_count(),
+        }
     }
 
     #[tracing::instrument(skip(self))]
@@ -272,22 +366,74 @@ impl Service {
         self.db.cleanup()
     }
 
-    pub fn server_name(&self) -> &ServerName {
-        self.config.server_name.as_ref()
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()
+    }
+
+    pub fn flush_strategy(&self) -> FlushStrategy {
+        self.config.flush_strategy
     }
 
-    pub fn max_request_size(&self) -> u32 {
-        self.config.max_request_size
+    pub fn server_name(&self) -> &ServerName {
+        self.config.server_name.as_ref()
     }
 
     pub fn max_fetch_prev_events(&self) -> u16 {
         self.config.max_fetch_prev_events
     }
 
+    pub fn media_quota_per_user(&self) -> u64 {
+        self.config.media_quota_per_user
+    }
+
+    pub fn media_retention_days(&self) -> u32 {
+        self.config.media_retention_days
+    }
+
+    pub fn allow_room_retention(&self) -> bool {
+        self.config.allow_room_retention
+    }
+
+    pub fn room_retention_default_days(&self) -> u32 {
+        self.config.room_retention_default_days
+    }
+
+    pub fn room_retention_max_days(&self) -> u32 {
+        self.config.room_retention_max_days
+    }
+
+    pub fn trusted_proxies(&self) -> &[IpAddr] {
+        &self.config.trusted_proxies
+    }
+
+    pub fn device_last_seen_second_interval(&self) -> u32 {
+        self.config.device_last_seen_second_interval
+    }
+
+    pub fn max_backups_per_user(&self) -> u32 {
+        self.config.max_backups_per_user
+    }
+
     pub fn allow_registration(&self) -> bool {
         self.config.allow_registration
     }
 
+    pub fn allow_guests(&self) -> bool {
+        self.config.allow_guests
+    }
+
+    pub fn allow_password_login(&self) -> bool {
+        self.config.allow_password_login
+    }
+
+    pub fn allow_profile_changes(&self) -> bool {
+        self.config.allow_profile_changes
+    }
+
+    pub fn allow_3pid_changes(&self) -> bool {
+        self.config.allow_3pid_changes
+    }
+
     pub fn allow_encryption(&self) -> bool {
         self.config.allow_encryption
     }
@@ -296,10 +442,27 @@ impl Service {
         self.config.allow_federation
     }
 
+    pub fn check_remote_canonical_aliases(&self) -> bool {
+        self.config.check_remote_canonical_aliases
+    }
+
+    /// Returns whether we may federate with this server. Without an allowlist every server is
+    /// allowed, and our own server always is.
+    pub fn is_federation_allowed(&self, server: &ServerName) -> bool {
+        match &self.config.federation_allowlist {
+            Some(allowlist) => server == self.server_name() || allowlist.contains(server),
+            None => true,
+        }
+    }
+
     pub fn allow_room_creation(&self) -> bool {
         self.config.allow_room_creation
     }
 
+    pub fn allow_presence(&self) -> bool {
+        self.config.allow_presence
+    }
+
     pub fn allow_unstable_room_versions(&self) -> bool {
         self.config.allow_unstable_room_versions
     }
@@ -352,6 +515,19 @@ impl Service {
         &self.config.emergency_password
     }
 
+    pub fn moderation_user(&self) -> Option<&UserId> {
+        self.config.moderation_user.as_deref()
+    }
+
+    /// Checks if the router serves the endpoint under any of its paths.
+    pub fn serves(&self, metadata: &Metadata) -> bool {
+        let served = self.served_endpoints.read().unwrap();
+        metadata
+            .history
+            .all_paths()
+            .any(|path| served.contains(&(metadata.method.clone(), path)))
+    }
+
     pub fn supported_room_versions(&self) -> Vec<RoomVersionId> {
         let mut room_versions: Vec<RoomVersionId> = vec![];
         room_versions.extend(self.stable_room_versions.clone());
@@ -438,5 +614,16 @@ fn reqwest_client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
         reqwest_client_builder = reqwest_client_builder.proxy(proxy);
     }
 
+    if let Some(path) = &config.tls_ca_file {
+        let certificate = fs::read(path)
+            .ok()
+            .and_then(|pem| reqwest::Certificate::from_pem(&pem).ok())
+            .ok_or_else(|| {
+                error!("Failed to load CA certificates from {}", path);
+                Error::bad_config("Failed to load the tls_ca_file.")
+            })?;
+        reqwest_client_builder = reqwest_client_builder.add_root_certificate(certificate);
+    }
+
     Ok(reqwest_client_builder)
 }
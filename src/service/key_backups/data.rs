@@ -14,6 +14,8 @@ pub trait Data: Send + Sync {
         backup_metadata: &Raw<BackupAlgorithm>,
     ) -> Result<String>;
 
+    /// Deletes a backup version with all of its keys and wakes up the user's sync, so clients
+    /// learn that they have to stop uploading to this version.
     fn delete_backup(&self, user_id: &UserId, version: &str) -> Result<()>;
 
     fn update_backup(
@@ -28,6 +30,9 @@ pub trait Data: Send + Sync {
     fn get_latest_backup(&self, user_id: &UserId)
         -> Result<Option<(String, Raw<BackupAlgorithm>)>>;
 
+    /// Returns how many backup versions the user has, without reading the algorithms.
+    fn get_backup_count(&self, user_id: &UserId) -> Result<usize>;
+
     fn get_backup(&self, user_id: &UserId, version: &str) -> Result<Option<Raw<BackupAlgorithm>>>;
 
     fn add_key(
@@ -37,17 +42,27 @@ pub trait Data: Send + Sync {
         room_id: &RoomId,
         session_id: &str,
         key_data: &Raw<KeyBackupData>,
-    ) -> Result<()>;
+    ) -> Result<String>;
+
+    /// Adds all sessions of all rooms with a single etag bump and returns the new key count
+    /// together with the new etag.
+    fn add_keys(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        rooms: &BTreeMap<OwnedRoomId, RoomKeyBackup>,
+    ) -> Result<(usize, String)>;
 
     fn count_keys(&self, user_id: &UserId, version: &str) -> Result<usize>;
 
     fn get_etag(&self, user_id: &UserId, version: &str) -> Result<String>;
 
-    fn get_all(
-        &self,
+    /// Returns an iterator over all keys of a backup, ordered by room.
+    fn iter_all<'a>(
+        &'a self,
         user_id: &UserId,
         version: &str,
-    ) -> Result<BTreeMap<OwnedRoomId, RoomKeyBackup>>;
+    ) -> Box<dyn Iterator<Item = Result<(OwnedRoomId, String, Raw<KeyBackupData>)>> + 'a>;
 
     fn get_room(
         &self,
@@ -64,9 +79,10 @@ pub trait Data: Send + Sync {
         session_id: &str,
     ) -> Result<Option<Raw<KeyBackupData>>>;
 
-    fn delete_all_keys(&self, user_id: &UserId, version: &str) -> Result<()>;
+    fn delete_all_keys(&self, user_id: &UserId, version: &str) -> Result<String>;
 
-    fn delete_room_keys(&self, user_id: &UserId, version: &str, room_id: &RoomId) -> Result<()>;
+    fn delete_room_keys(&self, user_id: &UserId, version: &str, room_id: &RoomId)
+        -> Result<String>;
 
     fn delete_room_key(
         &self,
@@ -74,5 +90,5 @@ pub trait Data: Send + Sync {
         version: &str,
         room_id: &RoomId,
         session_id: &str,
-    ) -> Result<()>;
+    ) -> Result<String>;
 }
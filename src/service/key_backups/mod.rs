@@ -1,29 +1,45 @@
 mod data;
 pub use data::Data;
 
-use crate::Result;
+use crate::{services, Error, Result};
+use base64::{engine::general_purpose, Engine as _};
 use ruma::{
-    api::client::backup::{BackupAlgorithm, KeyBackupData, RoomKeyBackup},
+    api::client::{
+        backup::{BackupAlgorithm, KeyBackupData, RoomKeyBackup},
+        error::ErrorKind,
+    },
     serde::Raw,
     OwnedRoomId, RoomId, UserId,
 };
-use std::collections::BTreeMap;
+use serde::Deserialize;
+use std::{collections::BTreeMap, future::Future};
 
 pub struct Service {
     pub db: &'static dyn Data,
 }
 
 impl Service {
+    /// Resolves after the next change of one of the user's backups or their keys.
+    pub fn watch(&self, user_id: &UserId) -> impl Future<Output = ()> {
+        services().globals.user_watchers.watch(user_id)
+    }
+
     pub fn create_backup(
         &self,
         user_id: &UserId,
         backup_metadata: &Raw<BackupAlgorithm>,
     ) -> Result<String> {
-        self.db.create_backup(user_id, backup_metadata)
+        validate_algorithm(backup_metadata)?;
+        let result = self.db.create_backup(user_id, backup_metadata)?;
+        services().globals.user_watchers.wake(user_id);
+        Ok(result)
     }
 
     pub fn delete_backup(&self, user_id: &UserId, version: &str) -> Result<()> {
-        self.db.delete_backup(user_id, version)
+        validate_version(version)?;
+        self.db.delete_backup(user_id, version)?;
+        services().globals.user_watchers.wake(user_id);
+        Ok(())
     }
 
     pub fn update_backup(
@@ -32,7 +48,11 @@ impl Service {
         version: &str,
         backup_metadata: &Raw<BackupAlgorithm>,
     ) -> Result<String> {
-        self.db.update_backup(user_id, version, backup_metadata)
+        validate_version(version)?;
+        validate_algorithm(backup_metadata)?;
+        let result = self.db.update_backup(user_id, version, backup_metadata)?;
+        services().globals.user_watchers.wake(user_id);
+        Ok(result)
     }
 
     pub fn get_latest_backup_version(&self, user_id: &UserId) -> Result<Option<String>> {
@@ -46,11 +66,16 @@ impl Service {
         self.db.get_latest_backup(user_id)
     }
 
+    pub fn get_backup_count(&self, user_id: &UserId) -> Result<usize> {
+        self.db.get_backup_count(user_id)
+    }
+
     pub fn get_backup(
         &self,
         user_id: &UserId,
         version: &str,
     ) -> Result<Option<Raw<BackupAlgorithm>>> {
+        validate_version(version)?;
         self.db.get_backup(user_id, version)
     }
 
@@ -61,25 +86,68 @@ impl Service {
         room_id: &RoomId,
         session_id: &str,
         key_data: &Raw<KeyBackupData>,
-    ) -> Result<()> {
-        self.db
-            .add_key(user_id, version, room_id, session_id, key_data)
+    ) -> Result<String> {
+        validate_version(version)?;
+        let result = self
+            .db
+            .add_key(user_id, version, room_id, session_id, key_data)?;
+        services().globals.user_watchers.wake(user_id);
+        Ok(result)
+    }
+
+    /// Adds the keys of many rooms at once and returns the new number of keys in the backup
+    /// and the new etag.
+    pub fn add_keys(
+        &self,
+        user_id: &UserId,
+        version: &str,
+        rooms: &BTreeMap<OwnedRoomId, RoomKeyBackup>,
+    ) -> Result<(usize, String)> {
+        validate_version(version)?;
+        let result = self.db.add_keys(user_id, version, rooms)?;
+        services().globals.user_watchers.wake(user_id);
+        Ok(result)
     }
 
     pub fn count_keys(&self, user_id: &UserId, version: &str) -> Result<usize> {
+        validate_version(version)?;
         self.db.count_keys(user_id, version)
     }
 
     pub fn get_etag(&self, user_id: &UserId, version: &str) -> Result<String> {
+        validate_version(version)?;
         self.db.get_etag(user_id, version)
     }
 
+    /// Returns an iterator over all keys of a backup without loading the whole backup into memory.
+    pub fn iter_all<'a>(
+        &'a self,
+        user_id: &UserId,
+        version: &str,
+    ) -> Result<impl Iterator<Item = Result<(OwnedRoomId, String, Raw<KeyBackupData>)>> + 'a> {
+        validate_version(version)?;
+        Ok(self.db.iter_all(user_id, version))
+    }
+
     pub fn get_all(
         &self,
         user_id: &UserId,
         version: &str,
     ) -> Result<BTreeMap<OwnedRoomId, RoomKeyBackup>> {
-        self.db.get_all(user_id, version)
+        let mut rooms = BTreeMap::<OwnedRoomId, RoomKeyBackup>::new();
+
+        for result in self.iter_all(user_id, version)? {
+            let (room_id, session_id, key_data) = result?;
+            rooms
+                .entry(room_id)
+                .or_insert_with(|| RoomKeyBackup {
+                    sessions: BTreeMap::new(),
+                })
+                .sessions
+                .insert(session_id, key_data);
+        }
+
+        Ok(rooms)
     }
 
     pub fn get_room(
@@ -88,6 +156,7 @@ impl Service {
         version: &str,
         room_id: &RoomId,
     ) -> Result<BTreeMap<String, Raw<KeyBackupData>>> {
+        validate_version(version)?;
         self.db.get_room(user_id, version, room_id)
     }
 
@@ -98,11 +167,15 @@ impl Service {
         room_id: &RoomId,
         session_id: &str,
     ) -> Result<Option<Raw<KeyBackupData>>> {
+        validate_version(version)?;
         self.db.get_session(user_id, version, room_id, session_id)
     }
 
-    pub fn delete_all_keys(&self, user_id: &UserId, version: &str) -> Result<()> {
-        self.db.delete_all_keys(user_id, version)
+    pub fn delete_all_keys(&self, user_id: &UserId, version: &str) -> Result<String> {
+        validate_version(version)?;
+        let result = self.db.delete_all_keys(user_id, version)?;
+        services().globals.user_watchers.wake(user_id);
+        Ok(result)
     }
 
     pub fn delete_room_keys(
@@ -110,8 +183,11 @@ impl Service {
         user_id: &UserId,
         version: &str,
         room_id: &RoomId,
-    ) -> Result<()> {
-        self.db.delete_room_keys(user_id, version, room_id)
+    ) -> Result<String> {
+        validate_version(version)?;
+        let result = self.db.delete_room_keys(user_id, version, room_id)?;
+        services().globals.user_watchers.wake(user_id);
+        Ok(result)
     }
 
     pub fn delete_room_key(
@@ -120,8 +196,66 @@ impl Service {
         version: &str,
         room_id: &RoomId,
         session_id: &str,
-    ) -> Result<()> {
-        self.db
-            .delete_room_key(user_id, version, room_id, session_id)
+    ) -> Result<String> {
+        validate_version(version)?;
+        let result = self
+            .db
+            .delete_room_key(user_id, version, room_id, session_id)?;
+        services().globals.user_watchers.wake(user_id);
+        Ok(result)
+    }
+}
+
+/// Versions are always created from the global counter, so anything that is not a plain
+/// decimal number can't refer to an existing backup. Rejecting everything else also makes
+/// sure no `0xff` separator can sneak into the keys of the backup trees.
+fn validate_version(version: &str) -> Result<()> {
+    if version.is_empty() || !version.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Backup version is invalid.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the auth data of the algorithms we know. Unknown algorithms are stored as they are, so
+/// clients can use newer algorithms before we know about them.
+fn validate_algorithm(backup_metadata: &Raw<BackupAlgorithm>) -> Result<()> {
+    #[derive(Deserialize)]
+    struct Algorithm {
+        algorithm: String,
+        #[serde(default)]
+        auth_data: serde_json::Value,
+    }
+
+    let algorithm = serde_json::from_str::<Algorithm>(backup_metadata.json().get())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Backup algorithm is invalid."))?;
+
+    if algorithm.algorithm != "m.megolm_backup.v1.curve25519-aes-sha2" {
+        return Ok(());
+    }
+
+    let public_key = algorithm
+        .auth_data
+        .get("public_key")
+        .and_then(|public_key| public_key.as_str())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "auth_data.public_key is missing.",
+        ))?;
+
+    // The spec uses unpadded base64, but some clients send padding
+    match general_purpose::STANDARD_NO_PAD.decode(public_key.trim_end_matches('=')) {
+        Ok(key) if key.len() == 32 => Ok(()),
+        Ok(_) => Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "auth_data.public_key must be a 32 byte Curve25519 key.",
+        )),
+        Err(_) => Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "auth_data.public_key is not valid base64.",
+        )),
     }
 }
@@ -1,4 +1,5 @@
 use crate::Result;
+use ruma::{OwnedUserId, UserId};
 
 pub trait Data: Send + Sync {
     fn create_file_metadata(
@@ -17,4 +18,30 @@ pub trait Data: Send + Sync {
         width: u32,
         height: u32,
     ) -> Result<(Option<String>, Option<String>, Vec<u8>)>;
+
+    /// Returns how many thumbnails of the file are saved, not counting the original.
+    fn count_thumbnails(&self, mxc: String) -> Result<usize>;
+
+    /// Returns an iterator over the mxc, width, height and metadata key of all stored files.
+    fn iter_file_metadata<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(String, u32, u32, Vec<u8>)>> + 'a>;
+
+    fn delete_file_metadata(&self, key: &[u8]) -> Result<()>;
+
+    /// Remembers who uploaded the file and adds its size to their media usage.
+    fn add_media_usage(&self, user_id: &UserId, mxc: &str, size: u64) -> Result<()>;
+
+    /// Returns who uploaded the file, if it counts towards someone's media usage.
+    fn media_uploader(&self, mxc: &str) -> Result<Option<OwnedUserId>>;
+
+    /// Subtracts the size of the file from its uploader's media usage. Does nothing for files
+    /// without an uploader, like remote media.
+    fn remove_media_usage(&self, mxc: &str) -> Result<()>;
+
+    /// Returns how many bytes of media the user has stored.
+    fn media_usage(&self, user_id: &UserId) -> Result<u64>;
+
+    /// Returns an iterator over the media usage of all users that uploaded something.
+    fn media_usages<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OwnedUserId, u64)>> + 'a>;
 }
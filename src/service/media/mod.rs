@@ -1,15 +1,27 @@
 mod data;
-use std::io::Cursor;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{Cursor, ErrorKind as IoErrorKind, SeekFrom},
+    sync::{Arc, RwLock},
+    time::UNIX_EPOCH,
+};
 
 pub use data::Data;
 
-use crate::{services, Result};
+use crate::{services, Error, Result};
 use image::imageops::FilterType;
+use ruma::{api::client::error::ErrorKind, JsOption, OwnedUserId, UserId};
 
 use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    sync::Mutex as TokioMutex,
 };
+use tracing::warn;
+
+/// How many thumbnails of one file are saved at most. The sizes from `thumbnail_properties`
+/// already stay below this, so it only guards against files thumbnailed elsewhere.
+const MAX_THUMBNAILS_PER_MEDIA: usize = 5;
 
 pub struct FileMeta {
     pub content_disposition: Option<String>,
@@ -19,29 +31,158 @@ pub struct FileMeta {
 
 pub struct Service {
     pub db: &'static dyn Data,
+    /// Held while checking or changing the media usage of a user, so concurrent uploads can't
+    /// all pass the quota check
+    pub userid_mutex_usage: RwLock<HashMap<OwnedUserId, Arc<TokioMutex<()>>>>,
 }
 
 impl Service {
     /// Uploads a file.
+    ///
+    /// Files with an uploader count towards their media quota, uploads exceeding it are rejected.
     pub async fn create(
         &self,
+        uploader: Option<&UserId>,
         mxc: String,
         content_disposition: Option<&str>,
         content_type: Option<&str>,
         file: &[u8],
     ) -> Result<()> {
+        let usage_mutex = uploader.map(|uploader| self.usage_mutex(uploader));
+        let _usage_lock = match &usage_mutex {
+            Some(mutex) => Some(mutex.lock().await),
+            None => None,
+        };
+
+        if let Some(uploader) = uploader {
+            let quota = services().globals.media_quota_per_user();
+            if quota > 0 && self.db.media_usage(uploader)? + file.len() as u64 > quota {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Uploading this file would exceed your media quota.",
+                ));
+            }
+        }
+
         // Width, Height = 0 if it's not a thumbnail
-        let key = self
-            .db
-            .create_file_metadata(mxc, 0, 0, content_disposition, content_type)?;
+        let key =
+            self.db
+                .create_file_metadata(mxc.clone(), 0, 0, content_disposition, content_type)?;
 
         let path = services().globals.get_media_file(&key);
         let mut f = File::create(path).await?;
         f.write_all(file).await?;
+
+        if let Some(uploader) = uploader {
+            self.db.add_media_usage(uploader, &mxc, file.len() as u64)?;
+        }
+
         Ok(())
     }
 
+    fn usage_mutex(&self, user_id: &UserId) -> Arc<TokioMutex<()>> {
+        Arc::clone(
+            self.userid_mutex_usage
+                .write()
+                .unwrap()
+                .entry(user_id.to_owned())
+                .or_default(),
+        )
+    }
+
+    /// Returns how many bytes of media the user has stored.
+    pub fn media_usage(&self, user_id: &UserId) -> Result<u64> {
+        self.db.media_usage(user_id)
+    }
+
+    /// Returns an iterator over the media usage of all users that uploaded something.
+    pub fn media_usages(&self) -> impl Iterator<Item = Result<(OwnedUserId, u64)>> + '_ {
+        self.db.media_usages()
+    }
+
+    /// Deletes all media whose original file is older than `ts` (milliseconds since the unix
+    /// epoch), together with its thumbnails. User and room avatars are kept, references from
+    /// messages are not checked.
+    ///
+    /// Returns how many files and bytes were freed.
+    pub async fn purge_media_older_than(&self, ts: u64) -> Result<(u64, u64)> {
+        let avatars = avatar_mxcs()?;
+
+        // Group the files by mxc, so thumbnails go together with their original
+        let mut media = BTreeMap::<String, Vec<(bool, Vec<u8>)>>::new();
+        for result in self.db.iter_file_metadata() {
+            let (mxc, width, height, key) = result?;
+            media
+                .entry(mxc)
+                .or_default()
+                .push((width == 0 && height == 0, key));
+        }
+
+        let mut freed_files = 0;
+        let mut freed_bytes = 0;
+
+        for (mxc, keys) in media {
+            if avatars.contains(&mxc) {
+                continue;
+            }
+
+            // Remote thumbnails can exist without an original, they are judged by their own age
+            let reference = keys
+                .iter()
+                .find(|(original, _)| *original)
+                .or_else(|| keys.first())
+                .map(|(_, key)| services().globals.get_media_file(key))
+                .expect("every group has at least one file");
+
+            // Metadata without a file is always outdated
+            let modified = match fs::metadata(&reference).await.and_then(|m| m.modified()) {
+                Ok(modified) => modified
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64),
+                Err(_) => 0,
+            };
+            if modified >= ts {
+                continue;
+            }
+
+            let mut failed = false;
+            for (_, key) in keys {
+                let path = services().globals.get_media_file(&key);
+                let size = fs::metadata(&path).await.map_or(0, |m| m.len());
+
+                match fs::remove_file(&path).await {
+                    Ok(()) => {
+                        freed_files += 1;
+                        freed_bytes += size;
+                    }
+                    Err(e) if e.kind() == IoErrorKind::NotFound => {}
+                    Err(e) => {
+                        warn!("Failed to delete media file {}: {}", path.display(), e);
+                        failed = true;
+                        continue;
+                    }
+                }
+
+                self.db.delete_file_metadata(&key)?;
+            }
+
+            // The upload still counts for the uploader until all of its files are gone, the next
+            // purge tries the rest again
+            if !failed {
+                if let Some(uploader) = self.db.media_uploader(&mxc)? {
+                    let usage_mutex = self.usage_mutex(&uploader);
+                    let _usage_lock = usage_mutex.lock().await;
+                    self.db.remove_media_usage(&mxc)?;
+                }
+            }
+        }
+
+        Ok((freed_files, freed_bytes))
+    }
+
     /// Uploads or replaces a file thumbnail.
+    ///
+    /// Once a file has `MAX_THUMBNAILS_PER_MEDIA` thumbnails, new sizes are not saved anymore.
     #[allow(clippy::too_many_arguments)]
     pub async fn upload_thumbnail(
         &self,
@@ -52,6 +193,15 @@ impl Service {
         height: u32,
         file: &[u8],
     ) -> Result<()> {
+        if self
+            .db
+            .search_file_metadata(mxc.clone(), width, height)
+            .is_err()
+            && self.db.count_thumbnails(mxc.clone())? >= MAX_THUMBNAILS_PER_MEDIA
+        {
+            return Ok(());
+        }
+
         let key =
             self.db
                 .create_file_metadata(mxc, width, height, content_disposition, content_type)?;
@@ -84,6 +234,39 @@ impl Service {
         }
     }
 
+    /// Returns content_disposition, content_type and the size of a file without reading it.
+    pub async fn get_file_info(
+        &self,
+        mxc: String,
+    ) -> Result<Option<(Option<String>, Option<String>, u64)>> {
+        if let Ok((content_disposition, content_type, key)) =
+            self.db.search_file_metadata(mxc, 0, 0)
+        {
+            let path = services().globals.get_media_file(&key);
+            let len = fs::metadata(path).await?.len();
+
+            Ok(Some((content_disposition, content_type, len)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Reads `len` bytes of a file starting at `start`, without reading the rest of the file.
+    pub async fn read_range(&self, mxc: String, start: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        if let Ok((_, _, key)) = self.db.search_file_metadata(mxc, 0, 0) {
+            let path = services().globals.get_media_file(&key);
+            let mut file = File::open(path).await?;
+            file.seek(SeekFrom::Start(start)).await?;
+
+            let mut bytes = Vec::new();
+            file.take(len).read_to_end(&mut bytes).await?;
+
+            Ok(Some(bytes))
+        } else {
+            Ok(None)
+        }
+    }
+
     /// Returns width, height of the thumbnail and whether it should be cropped. Returns None when
     /// the server should send the original file.
     pub fn thumbnail_properties(&self, width: u32, height: u32) -> Option<(u32, u32, bool)> {
@@ -196,17 +379,15 @@ impl Service {
                 )?;
 
                 // Save thumbnail in database so we don't have to generate it again next time
-                let thumbnail_key = self.db.create_file_metadata(
+                self.upload_thumbnail(
                     mxc,
-                    width,
-                    height,
                     content_disposition.as_deref(),
                     content_type.as_deref(),
-                )?;
-
-                let path = services().globals.get_media_file(&thumbnail_key);
-                let mut f = File::create(path).await?;
-                f.write_all(&thumbnail_bytes).await?;
+                    width,
+                    height,
+                    &thumbnail_bytes,
+                )
+                .await?;
 
                 Ok(Some(FileMeta {
                     content_disposition,
@@ -226,3 +407,24 @@ impl Service {
         }
     }
 }
+
+/// Returns the mxcs of all user and room avatars.
+fn avatar_mxcs() -> Result<HashSet<String>> {
+    let mut avatars = HashSet::new();
+
+    for user_id in services().users.iter().filter_map(|r| r.ok()) {
+        if let Some(avatar_url) = services().users.avatar_url(&user_id)? {
+            avatars.insert(avatar_url.to_string());
+        }
+    }
+
+    for room_id in services().rooms.metadata.iter_ids().filter_map(|r| r.ok()) {
+        if let JsOption::Some(avatar) = services().rooms.state_accessor.get_avatar(&room_id)? {
+            if let Some(url) = avatar.url {
+                avatars.insert(url.to_string());
+            }
+        }
+    }
+
+    Ok(avatars)
+}
@@ -0,0 +1,6 @@
+use crate::Result;
+
+pub trait Data: Send + Sync {
+    /// Returns the name and number of entries of every database tree.
+    fn tree_sizes(&self) -> Result<Vec<(&'static str, u64)>>;
+}
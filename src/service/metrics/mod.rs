@@ -0,0 +1,307 @@
+mod data;
+
+pub use data::Data;
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::interval;
+use tracing::error;
+
+use crate::services;
+
+use super::sending::OutgoingKind;
+
+/// Upper bounds of the request duration histogram buckets in seconds
+const DURATION_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Counting the entries of every tree reads the whole database, so it is not done on every scrape.
+const TREE_SIZES_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The handled requests of one endpoint.
+#[derive(Default)]
+pub struct RequestStats {
+    /// Requests by the first bucket of `DURATION_BUCKETS` they fit in, the last one is for slower
+    /// requests
+    buckets: [u64; DURATION_BUCKETS.len() + 1],
+    seconds: f64,
+    statuses: BTreeMap<u16, u64>,
+}
+
+impl RequestStats {
+    fn record(&mut self, status: u16, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+
+        self.buckets[bucket] += 1;
+        self.seconds += seconds;
+        *self.statuses.entry(status).or_default() += 1;
+    }
+}
+
+pub struct Service {
+    pub db: &'static dyn Data,
+
+    /// Stats by method and route
+    pub requests: Mutex<BTreeMap<(String, String), RequestStats>>,
+    pub sync_connections: AtomicUsize,
+    /// Sent transactions by destination kind and result
+    pub transactions: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    pub tree_sizes: Mutex<Vec<(&'static str, u64)>>,
+}
+
+/// Counts a sync request as active until it is dropped.
+pub struct SyncConnection<'a>(&'a AtomicUsize);
+
+impl Drop for SyncConnection<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Service {
+    /// Records a handled request. The endpoint is the route, not the path, so there is a bounded
+    /// number of them.
+    pub fn record_request(&self, method: &str, endpoint: &str, status: u16, duration: Duration) {
+        self.requests
+            .lock()
+            .unwrap()
+            .entry((method.to_owned(), endpoint.to_owned()))
+            .or_default()
+            .record(status, duration);
+    }
+
+    pub fn sync_connection(&self) -> SyncConnection<'_> {
+        self.sync_connections.fetch_add(1, Ordering::Relaxed);
+        SyncConnection(&self.sync_connections)
+    }
+
+    pub fn record_transaction(&self, outgoing_kind: &OutgoingKind, success: bool) {
+        let kind = match outgoing_kind {
+            OutgoingKind::Normal(_) => "federation",
+            OutgoingKind::Appservice(_) => "appservice",
+            OutgoingKind::Push(_, _) => "push",
+        };
+        let result = if success { "success" } else { "failure" };
+
+        *self
+            .transactions
+            .lock()
+            .unwrap()
+            .entry((kind, result))
+            .or_default() += 1;
+    }
+
+    pub fn start_tree_sizes_task(&'static self) {
+        tokio::spawn(async move {
+            let mut i = interval(TREE_SIZES_INTERVAL);
+
+            loop {
+                i.tick().await;
+
+                match tokio::task::spawn_blocking(|| self.db.tree_sizes()).await {
+                    Ok(Ok(tree_sizes)) => *self.tree_sizes.lock().unwrap() = tree_sizes,
+                    Ok(Err(e)) => error!("Failed to count the entries of the trees: {}", e),
+                    Err(e) => error!("Failed to count the entries of the trees: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Returns all metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = render_requests(&self.requests.lock().unwrap());
+
+        gauge(
+            &mut out,
+            "conduit_sync_connections",
+            "Sync requests that are waiting for new events.",
+            self.sync_connections.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            "conduit_sliding_sync_connections",
+            "Sliding sync connections we keep the state of.",
+            services().users.connections.lock().unwrap().len(),
+        );
+
+        let queues = services().sending.destination_queues();
+        gauge(
+            &mut out,
+            "conduit_sending_requests_in_flight",
+            "Requests to other servers, appservices and push gateways that are being sent.",
+            services().sending.requests_in_flight(),
+        );
+        gauge(
+            &mut out,
+            "conduit_sending_requests_waiting",
+            "Requests to other servers that wait for their destination's queue.",
+            queues.iter().map(|(_, _, waiting)| waiting).sum::<usize>(),
+        );
+
+        let backoffs = services()
+            .sending
+            .backoffs()
+            .filter_map(|r| r.ok())
+            .collect::<Vec<_>>();
+        gauge(
+            &mut out,
+            "conduit_sending_destinations_backing_off",
+            "Destinations we wait for before sending to them again, including dead ones.",
+            backoffs.len(),
+        );
+        gauge(
+            &mut out,
+            "conduit_sending_destinations_dead",
+            "Destinations that failed too often in a row.",
+            backoffs
+                .iter()
+                .filter(|(_, backoff)| backoff.is_dead())
+                .count(),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP conduit_sending_transactions_total Sent transactions.\n\
+             # TYPE conduit_sending_transactions_total counter"
+        );
+        for ((kind, result), count) in self.transactions.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "conduit_sending_transactions_total{{kind=\"{kind}\",result=\"{result}\"}} {count}"
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP conduit_database_tree_entries Entries of a database tree, updated every {} minutes.\n\
+             # TYPE conduit_database_tree_entries gauge",
+            TREE_SIZES_INTERVAL.as_secs() / 60
+        );
+        for (tree, entries) in self.tree_sizes.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "conduit_database_tree_entries{{tree=\"{tree}\"}} {entries}"
+            );
+        }
+
+        if let Ok(count) = services().globals.current_count() {
+            let _ = writeln!(
+                out,
+                "# HELP conduit_global_count The counter that orders events, EDUs and other changes.\n\
+                 # TYPE conduit_global_count counter\n\
+                 conduit_global_count {count}"
+            );
+        }
+
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+    );
+}
+
+fn render_requests(requests: &BTreeMap<(String, String), RequestStats>) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# HELP conduit_http_requests_total Handled requests.\n\
+         # TYPE conduit_http_requests_total counter"
+    );
+    for ((method, endpoint), stats) in requests {
+        for (status, count) in &stats.statuses {
+            let _ = writeln!(
+                out,
+                "conduit_http_requests_total{{method=\"{method}\",endpoint=\"{endpoint}\",status=\"{status}\"}} {count}"
+            );
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP conduit_http_request_duration_seconds How long handling requests took.\n\
+         # TYPE conduit_http_request_duration_seconds histogram"
+    );
+    for ((method, endpoint), stats) in requests {
+        let labels = format!("method=\"{method}\",endpoint=\"{endpoint}\"");
+
+        // Prometheus buckets count every request up to their bound
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(&stats.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "conduit_http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        cumulative += stats.buckets[DURATION_BUCKETS.len()];
+        let _ = writeln!(
+            out,
+            "conduit_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {cumulative}\n\
+             conduit_http_request_duration_seconds_sum{{{labels}}} {}\n\
+             conduit_http_request_duration_seconds_count{{{labels}}} {cumulative}",
+            stats.seconds
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::{render_requests, RequestStats};
+
+    #[test]
+    fn request_histogram_is_cumulative() {
+        let mut stats = RequestStats::default();
+        stats.record(200, Duration::from_millis(3));
+        stats.record(200, Duration::from_millis(40));
+        stats.record(500, Duration::from_secs(60));
+
+        let mut requests = BTreeMap::new();
+        requests.insert(
+            ("GET".to_owned(), "/_matrix/client/v3/sync".to_owned()),
+            stats,
+        );
+        let out = render_requests(&requests);
+
+        let labels = "method=\"GET\",endpoint=\"/_matrix/client/v3/sync\"";
+        assert!(out.contains(&format!(
+            "conduit_http_requests_total{{{labels},status=\"200\"}} 2\n"
+        )));
+        assert!(out.contains(&format!(
+            "conduit_http_request_duration_seconds_bucket{{{labels},le=\"0.005\"}} 1\n"
+        )));
+        assert!(out.contains(&format!(
+            "conduit_http_request_duration_seconds_bucket{{{labels},le=\"0.05\"}} 2\n"
+        )));
+        assert!(out.contains(&format!(
+            "conduit_http_request_duration_seconds_bucket{{{labels},le=\"30\"}} 2\n"
+        )));
+        assert!(out.contains(&format!(
+            "conduit_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} 3\n"
+        )));
+        assert!(out.contains(&format!(
+            "conduit_http_request_duration_seconds_count{{{labels}}} 3\n"
+        )));
+    }
+}
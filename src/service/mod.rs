@@ -1,6 +1,6 @@
 use std::{
     collections::{BTreeMap, HashMap},
-    sync::{Arc, Mutex},
+    sync::{atomic::AtomicUsize, Arc, Mutex, RwLock},
 };
 
 use lru_cache::LruCache;
@@ -13,10 +13,14 @@ pub mod appservice;
 pub mod globals;
 pub mod key_backups;
 pub mod media;
+pub mod metrics;
 pub mod pdu;
 pub mod pusher;
+pub mod rate_limiter;
 pub mod rooms;
 pub mod sending;
+pub mod server_notices;
+pub mod sso;
 pub mod transaction_ids;
 pub mod uiaa;
 pub mod users;
@@ -24,6 +28,7 @@ pub mod users;
 pub struct Services {
     pub appservice: appservice::Service,
     pub pusher: pusher::Service,
+    pub rate_limiter: rate_limiter::Service,
     pub rooms: rooms::Service,
     pub transaction_ids: transaction_ids::Service,
     pub uiaa: uiaa::Service,
@@ -33,7 +38,10 @@ pub struct Services {
     pub globals: globals::Service,
     pub key_backups: key_backups::Service,
     pub media: media::Service,
+    pub metrics: metrics::Service,
     pub sending: Arc<sending::Service>,
+    pub server_notices: server_notices::Service,
+    pub sso: sso::Service,
 }
 
 impl Services {
@@ -48,14 +56,17 @@ impl Services {
             + globals::Data
             + key_backups::Data
             + media::Data
+            + metrics::Data
             + sending::Data
+            + server_notices::Data
+            + sso::Data
             + 'static,
     >(
         db: &'static D,
         config: Config,
     ) -> Result<Self> {
         Ok(Self {
-            appservice: appservice::Service { db },
+            appservice: appservice::Service::build(db)?,
             pusher: pusher::Service { db },
             rooms: rooms::Service {
                 alias: rooms::alias::Service { db },
@@ -66,7 +77,11 @@ impl Services {
                     read_receipt: rooms::edus::read_receipt::Service { db },
                     typing: rooms::edus::typing::Service { db },
                 },
-                event_handler: rooms::event_handler::Service,
+                event_handler: rooms::event_handler::Service {
+                    handled_pdu_cache: Mutex::new(LruCache::new(
+                        (1000.0 * config.conduit_cache_capacity_modifier) as usize,
+                    )),
+                },
                 lazy_loading: rooms::lazy_loading::Service {
                     db,
                     lazy_load_waiting: Mutex::new(HashMap::new()),
@@ -74,6 +89,7 @@ impl Services {
                 metadata: rooms::metadata::Service { db },
                 outlier: rooms::outlier::Service { db },
                 pdu_metadata: rooms::pdu_metadata::Service { db },
+                reports: rooms::reports::Service { db },
                 search: rooms::search::Service { db },
                 short: rooms::short::Service { db },
                 state: rooms::state::Service { db },
@@ -85,6 +101,9 @@ impl Services {
                     user_visibility_cache: Mutex::new(LruCache::new(
                         (100.0 * config.conduit_cache_capacity_modifier) as usize,
                     )),
+                    server_acl_cache: Mutex::new(LruCache::new(
+                        (100.0 * config.conduit_cache_capacity_modifier) as usize,
+                    )),
                 },
                 state_cache: rooms::state_cache::Service { db },
                 state_compressor: rooms::state_compressor::Service {
@@ -104,16 +123,46 @@ impl Services {
                 user: rooms::user::Service { db },
             },
             transaction_ids: transaction_ids::Service { db },
-            uiaa: uiaa::Service { db },
+            uiaa: uiaa::Service {
+                db,
+                registration_token_lock: Mutex::new(()),
+                session_registration_tokens: Mutex::new(HashMap::new()),
+            },
             users: users::Service {
                 db,
                 connections: Mutex::new(BTreeMap::new()),
+                last_seen_updates: Mutex::new(LruCache::new(
+                    (10000.0 * config.conduit_cache_capacity_modifier) as usize,
+                )),
+                threepid_sessions: Mutex::new(HashMap::new()),
+                threepid_lock: Mutex::new(()),
+                openid_token_lock: Mutex::new(()),
             },
             account_data: account_data::Service { db },
             admin: admin::Service::build(),
             key_backups: key_backups::Service { db },
-            media: media::Service { db },
+            media: media::Service {
+                db,
+                userid_mutex_usage: RwLock::new(HashMap::new()),
+            },
+            metrics: metrics::Service {
+                db,
+                requests: Mutex::new(BTreeMap::new()),
+                sync_connections: AtomicUsize::new(0),
+                transactions: Mutex::new(BTreeMap::new()),
+                tree_sizes: Mutex::new(Vec::new()),
+            },
             sending: sending::Service::build(db, &config),
+            server_notices: server_notices::Service { db },
+            sso: sso::Service {
+                db,
+                provider_metadata: RwLock::new(HashMap::new()),
+                sessions: Mutex::new(HashMap::new()),
+                login_tokens: Mutex::new(HashMap::new()),
+            },
+            rate_limiter: rate_limiter::Service {
+                buckets: Mutex::new(LruCache::new(rate_limiter::MAX_BUCKETS)),
+            },
 
             globals: globals::Service::load(db, config)?,
         })
@@ -161,6 +210,20 @@ impl Services {
             .lock()
             .unwrap()
             .len();
+        let handled_pdu_cache = self
+            .rooms
+            .event_handler
+            .handled_pdu_cache
+            .lock()
+            .unwrap()
+            .len();
+        let server_acl_cache = self
+            .rooms
+            .state_accessor
+            .server_acl_cache
+            .lock()
+            .unwrap()
+            .len();
 
         format!(
             "\
@@ -169,7 +232,9 @@ server_visibility_cache: {server_visibility_cache}
 user_visibility_cache: {user_visibility_cache}
 stateinfo_cache: {stateinfo_cache}
 lasttimelinecount_cache: {lasttimelinecount_cache}
-roomid_spacechunk_cache: {roomid_spacechunk_cache}\
+roomid_spacechunk_cache: {roomid_spacechunk_cache}
+handled_pdu_cache: {handled_pdu_cache}
+server_acl_cache: {server_acl_cache}\
             "
         )
     }
@@ -222,5 +287,21 @@ roomid_spacechunk_cache: {roomid_spacechunk_cache}\
                 .unwrap()
                 .clear();
         }
+        if amount > 6 {
+            self.rooms
+                .event_handler
+                .handled_pdu_cache
+                .lock()
+                .unwrap()
+                .clear();
+        }
+        if amount > 7 {
+            self.rooms
+                .state_accessor
+                .server_acl_cache
+                .lock()
+                .unwrap()
+                .clear();
+        }
     }
 }
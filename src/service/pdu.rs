@@ -1,7 +1,11 @@
 use crate::Error;
 use ruma::{
+    canonical_json::redact_content_in_place,
     events::{
-        room::member::RoomMemberEventContent, space::child::HierarchySpaceChildEvent,
+        room::{
+            member::RoomMemberEventContent, third_party_invite::RoomThirdPartyInviteEventContent,
+        },
+        space::child::HierarchySpaceChildEvent,
         AnyEphemeralRoomEvent, AnyMessageLikeEvent, AnyStateEvent, AnyStrippedStateEvent,
         AnySyncStateEvent, AnySyncTimelineEvent, AnyTimelineEvent, StateEvent, TimelineEventType,
     },
@@ -9,12 +13,12 @@ use ruma::{
     state_res, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch,
     OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, RoomVersionId, UInt, UserId,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
 use serde_json::{
     json,
     value::{to_raw_value, RawValue as RawJsonValue},
 };
-use std::{cmp::Ordering, collections::BTreeMap, sync::Arc};
+use std::{cmp::Ordering, collections::BTreeMap, iter, sync::Arc};
 use tracing::warn;
 
 /// Content hashes of a PDU.
@@ -48,47 +52,117 @@ pub struct PduEvent {
 }
 
 impl PduEvent {
-    #[tracing::instrument(skip(self))]
-    pub fn redact(&mut self, reason: &PduEvent) -> crate::Result<()> {
+    #[tracing::instrument(skip(self, reason))]
+    pub fn redact(
+        &mut self,
+        room_version_id: &RoomVersionId,
+        reason: &PduEvent,
+    ) -> crate::Result<()> {
         self.unsigned = None;
 
-        let allowed: &[&str] = match self.kind {
-            TimelineEventType::RoomMember => &["join_authorised_via_users_server", "membership"],
-            TimelineEventType::RoomCreate => &["creator"],
-            TimelineEventType::RoomJoinRules => &["join_rule"],
-            TimelineEventType::RoomPowerLevels => &[
-                "ban",
-                "events",
-                "events_default",
-                "kick",
-                "redact",
-                "state_default",
-                "users",
-                "users_default",
-            ],
-            TimelineEventType::RoomHistoryVisibility => &["history_visibility"],
-            _ => &[],
-        };
+        let mut content: CanonicalJsonObject = serde_json::from_str(self.content.get())
+            .map_err(|_| Error::bad_database("PDU in db has invalid content."))?;
+        redact_content_in_place(&mut content, room_version_id, self.kind.to_string())
+            .map_err(|_| Error::bad_database("Failed to redact PDU content."))?;
 
-        let mut old_content: BTreeMap<String, serde_json::Value> =
-            serde_json::from_str(self.content.get())
-                .map_err(|_| Error::bad_database("PDU in db has invalid content."))?;
+        self.unsigned = Some(to_raw_value(&json!({
+            "redacted_because": serde_json::to_value(reason).expect("to_value(PduEvent) always works")
+        })).expect("to string always works"));
 
-        let mut new_content = serde_json::Map::new();
+        self.content = to_raw_value(&content).expect("to string always works");
 
-        for key in allowed {
-            if let Some(value) = old_content.remove(*key) {
-                new_content.insert((*key).to_owned(), value);
+        Ok(())
+    }
+
+    /// The event a redaction redacts. Since room version 11 it is part of the content, our own
+    /// redactions have it in both places.
+    pub fn redacts_id(&self, room_version_id: &RoomVersionId) -> Option<Arc<EventId>> {
+        use RoomVersionId::*;
+
+        match room_version_id {
+            V1 | V2 | V3 | V4 | V5 | V6 | V7 | V8 | V9 | V10 => self.redacts.clone(),
+            _ => {
+                #[derive(Deserialize)]
+                struct ExtractRedacts {
+                    redacts: Option<Arc<EventId>>,
+                }
+
+                serde_json::from_str::<ExtractRedacts>(self.content.get())
+                    .ok()
+                    .and_then(|content| content.redacts)
+                    .or_else(|| self.redacts.clone())
             }
         }
+    }
+
+    /// The token of the `m.room.third_party_invite` event a membership event refers to.
+    pub fn third_party_invite_token(&self) -> Option<String> {
+        if self.kind != TimelineEventType::RoomMember {
+            return None;
+        }
 
-        self.unsigned = Some(to_raw_value(&json!({
-            "redacted_because": serde_json::to_value(reason).expect("to_value(PduEvent) always works")
-        })).expect("to string always works"));
+        serde_json::from_str::<RoomMemberEventContent>(self.content.get())
+            .ok()?
+            .third_party_invite
+            .map(|third_party_invite| third_party_invite.signed.token)
+    }
 
-        self.content = to_raw_value(&new_content).expect("to string always works");
+    /// Whether the `signed` block in the content of a membership event has a signature made with
+    /// one of the public keys of this `m.room.third_party_invite` event.
+    pub fn verifies_third_party_signed(&self, member_content: &RawJsonValue) -> bool {
+        #[derive(Deserialize)]
+        struct ExtractSigned {
+            third_party_invite: Option<ExtractThirdPartyInvite>,
+        }
 
-        Ok(())
+        #[derive(Deserialize)]
+        struct ExtractThirdPartyInvite {
+            signed: CanonicalJsonObject,
+        }
+
+        let Some(signed) = serde_json::from_str::<ExtractSigned>(member_content.get())
+            .ok()
+            .and_then(|content| content.third_party_invite)
+            .map(|third_party_invite| third_party_invite.signed)
+        else {
+            return false;
+        };
+
+        let Ok(content) =
+            serde_json::from_str::<RoomThirdPartyInviteEventContent>(self.content.get())
+        else {
+            return false;
+        };
+        let public_keys = iter::once(content.public_key)
+            .chain(
+                content
+                    .public_keys
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|key| key.public_key),
+            )
+            .collect::<Vec<_>>();
+
+        let Some(CanonicalJsonValue::Object(signatures)) = signed.get("signatures") else {
+            return false;
+        };
+
+        // We don't know which identity server and key made the signature, so we try them all
+        signatures.iter().any(|(entity, entity_signatures)| {
+            let CanonicalJsonValue::Object(entity_signatures) = entity_signatures else {
+                return false;
+            };
+
+            entity_signatures.keys().any(|key_id| {
+                public_keys.iter().any(|public_key| {
+                    let public_key_map = BTreeMap::from([(
+                        entity.clone(),
+                        BTreeMap::from([(key_id.clone(), public_key.clone())]),
+                    )]);
+                    ruma::signatures::verify_json(&public_key_map, &signed).is_ok()
+                })
+            })
+        })
     }
 
     pub fn remove_transaction_id(&mut self) -> crate::Result<()> {
@@ -103,6 +177,46 @@ impl PduEvent {
         Ok(())
     }
 
+    /// Whether this event was redacted, see `redact`.
+    pub fn is_redacted(&self) -> bool {
+        #[derive(Deserialize)]
+        struct ExtractRedactedBecause {
+            redacted_because: Option<IgnoredAny>,
+        }
+
+        self.unsigned
+            .as_ref()
+            .and_then(|unsigned| {
+                serde_json::from_str::<ExtractRedactedBecause>(unsigned.get()).ok()
+            })
+            .map_or(false, |unsigned| unsigned.redacted_because.is_some())
+    }
+
+    /// Adds bundled aggregations to `unsigned.m.relations`, keeping the ones already there.
+    pub fn add_relations(
+        &mut self,
+        relations: serde_json::Map<String, serde_json::Value>,
+    ) -> crate::Result<()> {
+        let mut unsigned: BTreeMap<String, serde_json::Value> = self
+            .unsigned
+            .as_ref()
+            .map_or_else(|| Ok(BTreeMap::new()), |u| serde_json::from_str(u.get()))
+            .map_err(|_| Error::bad_database("Invalid unsigned in pdu event"))?;
+
+        let bundled = unsigned
+            .entry("m.relations".to_owned())
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        if let serde_json::Value::Object(bundled) = bundled {
+            bundled.extend(relations);
+        } else {
+            *bundled = serde_json::Value::Object(relations);
+        }
+
+        self.unsigned = Some(to_raw_value(&unsigned).expect("unsigned is valid"));
+
+        Ok(())
+    }
+
     pub fn add_age(&mut self) -> crate::Result<()> {
         let mut unsigned: BTreeMap<String, Box<RawJsonValue>> = self
             .unsigned
@@ -428,3 +542,139 @@ pub struct PduBuilder {
     pub state_key: Option<String>,
     pub redacts: Option<Arc<EventId>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use ruma::{
+        event_id,
+        serde::Base64,
+        signatures::{sign_json, Ed25519KeyPair},
+        CanonicalJsonObject, RoomVersionId,
+    };
+    use serde_json::{json, value::to_raw_value, Value};
+
+    use super::PduEvent;
+
+    fn pdu(kind: &str, content: Value, redacts: Option<&str>) -> PduEvent {
+        serde_json::from_value(json!({
+            "event_id": "$event:example.org",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 0,
+            "type": kind,
+            "content": content,
+            "state_key": "",
+            "prev_events": [],
+            "depth": 1,
+            "auth_events": [],
+            "redacts": redacts,
+            "hashes": { "sha256": "" },
+        }))
+        .expect("test PDU is valid")
+    }
+
+    fn redacted_content(mut pdu: PduEvent, room_version_id: &RoomVersionId) -> Value {
+        let reason = pdu.clone();
+        pdu.redact(room_version_id, &reason)
+            .expect("content is an object");
+        serde_json::from_str(pdu.content.get()).unwrap()
+    }
+
+    #[test]
+    fn redaction_keeps_more_since_v11() {
+        let create = pdu(
+            "m.room.create",
+            json!({ "creator": "@alice:example.org", "room_version": "10" }),
+            None,
+        );
+        assert_eq!(
+            redacted_content(create.clone(), &RoomVersionId::V10),
+            json!({ "creator": "@alice:example.org" })
+        );
+        assert_eq!(
+            redacted_content(create, &RoomVersionId::V11),
+            json!({ "creator": "@alice:example.org", "room_version": "10" })
+        );
+
+        let power_levels = pdu("m.room.power_levels", json!({ "invite": 50 }), None);
+        assert_eq!(
+            redacted_content(power_levels.clone(), &RoomVersionId::V10),
+            json!({})
+        );
+        assert_eq!(
+            redacted_content(power_levels, &RoomVersionId::V11),
+            json!({ "invite": 50 })
+        );
+    }
+
+    #[test]
+    fn redacts_moved_to_content_in_v11() {
+        let redaction = pdu(
+            "m.room.redaction",
+            json!({ "redacts": "$content:example.org" }),
+            Some("$top:example.org"),
+        );
+        assert_eq!(
+            redaction.redacts_id(&RoomVersionId::V10).as_deref(),
+            Some(event_id!("$top:example.org"))
+        );
+        assert_eq!(
+            redaction.redacts_id(&RoomVersionId::V11).as_deref(),
+            Some(event_id!("$content:example.org"))
+        );
+        assert_eq!(
+            redacted_content(redaction, &RoomVersionId::V11),
+            json!({ "redacts": "$content:example.org" })
+        );
+    }
+
+    #[test]
+    fn third_party_signed_needs_a_key_of_the_invite() {
+        let key_pair = |version: &str| {
+            let document = Ed25519KeyPair::generate().unwrap();
+            Ed25519KeyPair::from_der(&document, version.to_owned()).unwrap()
+        };
+        let identity_server_key = key_pair("0");
+        let other_key = key_pair("0");
+
+        let invite = pdu(
+            "m.room.third_party_invite",
+            json!({
+                "display_name": "a...@example.org",
+                "key_validity_url": "https://id.example.org/_matrix/identity/v2/pubkey/isvalid",
+                "public_key": Base64::new(identity_server_key.public_key().to_vec()),
+            }),
+            None,
+        );
+
+        let signed = |key: &Ed25519KeyPair| {
+            let mut signed: CanonicalJsonObject = serde_json::from_value(json!({
+                "mxid": "@bob:example.org",
+                "token": "abc",
+            }))
+            .unwrap();
+            sign_json("id.example.org", key, &mut signed).unwrap();
+            signed
+        };
+        let member_content = |signed: CanonicalJsonObject| {
+            to_raw_value(&json!({
+                "membership": "invite",
+                "third_party_invite": {
+                    "display_name": "a...@example.org",
+                    "signed": signed,
+                },
+            }))
+            .unwrap()
+        };
+
+        assert!(invite.verifies_third_party_signed(&member_content(signed(&identity_server_key))));
+        assert!(!invite.verifies_third_party_signed(&member_content(signed(&other_key))));
+
+        let mut tampered = signed(&identity_server_key);
+        tampered.insert("mxid".to_owned(), "@eve:example.org".to_owned().into());
+        assert!(!invite.verifies_third_party_signed(&member_content(tampered)));
+        assert!(!invite.verifies_third_party_signed(
+            &to_raw_value(&json!({ "membership": "invite" })).unwrap()
+        ));
+    }
+}
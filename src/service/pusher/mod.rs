@@ -13,19 +13,56 @@ use ruma::{
         },
         IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken,
     },
-    events::{room::power_levels::RoomPowerLevelsEventContent, StateEventType, TimelineEventType},
+    events::{
+        push_rules::PushRulesEvent, room::power_levels::RoomPowerLevelsEventContent,
+        GlobalAccountDataEventType, StateEventType, TimelineEventType,
+    },
     push::{Action, PushConditionRoomCtx, PushFormat, Ruleset, Tweak},
     serde::Raw,
     uint, RoomId, UInt, UserId,
 };
 
-use std::{fmt::Debug, mem};
+use std::{collections::BTreeSet, fmt::Debug, mem};
 use tracing::{info, warn};
 
 pub struct Service {
     pub db: &'static dyn Data,
 }
 
+/// What the push rules of a user want to happen for an event.
+#[derive(Debug, Default)]
+pub struct PushActions {
+    pub notify: bool,
+    pub highlight: bool,
+    /// The sound to play, usually "default"
+    pub sound: Option<String>,
+    pub tweaks: Vec<Tweak>,
+}
+
+impl PushActions {
+    fn from_actions(actions: &[Action]) -> Self {
+        let mut push_actions = Self::default();
+
+        for action in actions {
+            match action {
+                Action::Notify => push_actions.notify = true,
+                Action::SetTweak(tweak) => {
+                    match tweak {
+                        Tweak::Highlight(highlight) => push_actions.highlight = *highlight,
+                        Tweak::Sound(sound) => push_actions.sound = Some(sound.clone()),
+                        _ => {}
+                    }
+                    push_actions.tweaks.push(tweak.clone());
+                }
+                // dont_notify and coalesce don't notify
+                _ => {}
+            }
+        }
+
+        push_actions
+    }
+}
+
 impl Service {
     pub fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::PusherAction) -> Result<()> {
         self.db.set_pusher(sender, pusher)
@@ -43,6 +80,27 @@ impl Service {
         self.db.get_pushkeys(sender)
     }
 
+    /// Returns the urls of the push gateways the user has pushers on.
+    pub fn get_gateways(&self, sender: &UserId) -> Result<BTreeSet<String>> {
+        Ok(self
+            .get_pushers(sender)?
+            .into_iter()
+            .filter_map(|pusher| match pusher.kind {
+                PusherKind::Http(http) => Some(http.url),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Returns the http pushers of the user that use this push gateway.
+    pub fn get_gateway_pushers(&self, sender: &UserId, gateway: &str) -> Result<Vec<Pusher>> {
+        Ok(self
+            .get_pushers(sender)?
+            .into_iter()
+            .filter(|pusher| matches!(&pusher.kind, PusherKind::Http(http) if http.url == gateway))
+            .collect())
+    }
+
     #[tracing::instrument(skip(self, destination, request))]
     pub async fn send_request<T: OutgoingRequest>(
         &self,
@@ -128,62 +186,85 @@ impl Service {
         }
     }
 
-    #[tracing::instrument(skip(self, user, unread, pusher, ruleset, pdu))]
-    pub async fn send_push_notice(
-        &self,
-        user: &UserId,
-        unread: UInt,
-        pusher: &Pusher,
-        ruleset: Ruleset,
-        pdu: &PduEvent,
-    ) -> Result<()> {
-        let mut notify = None;
-        let mut tweaks = Vec::new();
+    /// Returns the push rules of the user, or the default rules if they never changed them.
+    pub fn ruleset(&self, user: &UserId) -> Result<Ruleset> {
+        Ok(services()
+            .account_data
+            .get(
+                None,
+                user,
+                GlobalAccountDataEventType::PushRules.to_string().into(),
+            )?
+            .map(|event| {
+                serde_json::from_str::<PushRulesEvent>(event.get())
+                    .map_err(|_| Error::bad_database("Invalid push rules event in db."))
+            })
+            .transpose()?
+            .map(|ev: PushRulesEvent| ev.content.global)
+            .unwrap_or_else(|| Ruleset::server_default(user)))
+    }
 
-        let power_levels: RoomPowerLevelsEventContent = services()
+    /// Returns the power levels push rule conditions look at.
+    pub fn power_levels(&self, room_id: &RoomId) -> Result<RoomPowerLevelsEventContent> {
+        Ok(services()
             .rooms
             .state_accessor
-            .room_state_get(&pdu.room_id, &StateEventType::RoomPowerLevels, "")?
+            .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
             .map(|ev| {
                 serde_json::from_str(ev.content.get())
                     .map_err(|_| Error::bad_database("invalid m.room.power_levels event"))
             })
             .transpose()?
-            .unwrap_or_default();
+            .unwrap_or_default())
+    }
 
-        for action in self.get_actions(
+    /// Sends the event to the pushers if the push rules of the user say so. The pushers all use
+    /// the same push gateway.
+    #[tracing::instrument(skip(self, user, unread, pushers, ruleset, pdu))]
+    pub async fn send_push_notice(
+        &self,
+        user: &UserId,
+        unread: UInt,
+        pushers: &[Pusher],
+        ruleset: Ruleset,
+        pdu: &PduEvent,
+    ) -> Result<()> {
+        let actions = self.evaluate(
             user,
             &ruleset,
-            &power_levels,
+            &self.power_levels(&pdu.room_id)?,
             &pdu.to_sync_room_event(),
             &pdu.room_id,
-        )? {
-            let n = match action {
-                Action::Notify => true,
-                Action::SetTweak(tweak) => {
-                    tweaks.push(tweak.clone());
-                    continue;
-                }
-                _ => false,
-            };
-
-            if notify.is_some() {
-                return Err(Error::bad_database(
-                    r#"Malformed pushrule contains more than one of these actions: ["dont_notify", "notify", "coalesce"]"#,
-                ));
-            }
+        )?;
 
-            notify = Some(n);
-        }
-
-        if notify == Some(true) {
-            self.send_notice(unread, pusher, tweaks, pdu).await?;
+        if actions.notify {
+            self.send_notice(user, unread, pushers, actions.tweaks, pdu)
+                .await?;
         }
         // Else the event triggered no actions
 
         Ok(())
     }
 
+    /// Finds the first rule of the user that matches the event and sums up its actions.
+    #[tracing::instrument(skip(self, user, ruleset, power_levels, pdu))]
+    pub fn evaluate(
+        &self,
+        user: &UserId,
+        ruleset: &Ruleset,
+        power_levels: &RoomPowerLevelsEventContent,
+        pdu: &Raw<AnySyncTimelineEvent>,
+        room_id: &RoomId,
+    ) -> Result<PushActions> {
+        Ok(PushActions::from_actions(self.get_actions(
+            user,
+            ruleset,
+            power_levels,
+            pdu,
+            room_id,
+        )?))
+    }
+
     #[tracing::instrument(skip(self, user, ruleset, pdu))]
     pub fn get_actions<'a>(
         &self,
@@ -199,89 +280,154 @@ impl Service {
             notifications: power_levels.notifications.clone(),
         };
 
-        let ctx = PushConditionRoomCtx {
-            room_id: room_id.to_owned(),
-            member_count: 10_u32.into(), // TODO: get member count efficiently
-            user_id: user.to_owned(),
-            user_display_name: services()
+        // contains_display_name looks for the name the user has in this room
+        let user_display_name = match services()
+            .rooms
+            .state_accessor
+            .get_member(room_id, user)?
+            .and_then(|member| member.displayname)
+        {
+            Some(displayname) => displayname,
+            None => services()
                 .users
                 .displayname(user)?
                 .unwrap_or_else(|| user.localpart().to_owned()),
+        };
+
+        let ctx = PushConditionRoomCtx {
+            room_id: room_id.to_owned(),
+            member_count: services()
+                .rooms
+                .state_cache
+                .get_member_count(room_id)?
+                .0
+                .try_into()
+                .unwrap_or(UInt::MAX),
+            user_id: user.to_owned(),
+            user_display_name,
             power_levels: Some(power_levels),
         };
 
         Ok(ruleset.get_actions(pdu, &ctx))
     }
 
-    #[tracing::instrument(skip(self, unread, pusher, tweaks, event))]
+    /// Sends one notification for all pushers with the same format. Pushers whose pushkey the
+    /// gateway rejects are removed.
+    #[tracing::instrument(skip(self, user, unread, pushers, tweaks, event))]
     async fn send_notice(
         &self,
+        user: &UserId,
         unread: UInt,
-        pusher: &Pusher,
+        pushers: &[Pusher],
         tweaks: Vec<Tweak>,
         event: &PduEvent,
     ) -> Result<()> {
         // TODO: email
-        match &pusher.kind {
-            PusherKind::Http(http) => {
-                // TODO:
-                // Two problems with this
-                // 1. if "event_id_only" is the only format kind it seems we should never add more info
-                // 2. can pusher/devices have conflicting formats
-                let event_id_only = http.format == Some(PushFormat::EventIdOnly);
-
-                let mut device = Device::new(pusher.ids.app_id.clone(), pusher.ids.pushkey.clone());
-                device.data.default_payload = http.default_payload.clone();
-                device.data.format = http.format.clone();
-
-                // Tweaks are only added if the format is NOT event_id_only
-                if !event_id_only {
-                    device.tweaks = tweaks.clone();
-                }
-
-                let d = vec![device];
-                let mut notifi = Notification::new(d);
-
-                notifi.prio = NotificationPriority::Low;
-                notifi.event_id = Some((*event.event_id).to_owned());
-                notifi.room_id = Some((*event.room_id).to_owned());
-                // TODO: missed calls
-                notifi.counts = NotificationCounts::new(unread, uint!(0));
-
-                if event.kind == TimelineEventType::RoomEncrypted
-                    || tweaks
-                        .iter()
-                        .any(|t| matches!(t, Tweak::Highlight(true) | Tweak::Sound(_)))
-                {
-                    notifi.prio = NotificationPriority::High
-                }
+        let (event_id_only, full): (Vec<_>, Vec<_>) = pushers
+            .iter()
+            .filter_map(|pusher| match &pusher.kind {
+                PusherKind::Http(http) => Some((pusher, http)),
+                _ => None,
+            })
+            .partition(|(_, http)| http.format == Some(PushFormat::EventIdOnly));
 
-                if event_id_only {
-                    self.send_request(&http.url, send_event_notification::v1::Request::new(notifi))
-                        .await?;
-                } else {
-                    notifi.sender = Some(event.sender.clone());
-                    notifi.event_type = Some(event.kind.clone());
-                    notifi.content = serde_json::value::to_raw_value(&event.content).ok();
-
-                    if event.kind == TimelineEventType::RoomMember {
-                        notifi.user_is_target =
-                            event.state_key.as_deref() == Some(event.sender.as_str());
+        for (event_id_only, pushers) in [(true, event_id_only), (false, full)] {
+            let Some((_, first)) = pushers.first() else {
+                continue;
+            };
+            let url = first.url.clone();
+
+            let devices = pushers
+                .iter()
+                .map(|(pusher, http)| {
+                    let mut device =
+                        Device::new(pusher.ids.app_id.clone(), pusher.ids.pushkey.clone());
+                    device.data.default_payload = http.default_payload.clone();
+                    device.data.format = http.format.clone();
+
+                    // Tweaks are only added if the format is NOT event_id_only
+                    if !event_id_only {
+                        device.tweaks = tweaks.clone();
                     }
 
-                    notifi.sender_display_name = services().users.displayname(&event.sender)?;
+                    device
+                })
+                .collect();
+
+            let mut notifi = Notification::new(devices);
+
+            notifi.prio = NotificationPriority::Low;
+            notifi.event_id = Some((*event.event_id).to_owned());
+            notifi.room_id = Some((*event.room_id).to_owned());
+            // TODO: missed calls
+            notifi.counts = NotificationCounts::new(unread, uint!(0));
+
+            if event.kind == TimelineEventType::RoomEncrypted
+                || tweaks
+                    .iter()
+                    .any(|t| matches!(t, Tweak::Highlight(true) | Tweak::Sound(_)))
+            {
+                notifi.prio = NotificationPriority::High
+            }
 
-                    notifi.room_name = services().rooms.state_accessor.get_name(&event.room_id)?;
+            if !event_id_only {
+                notifi.sender = Some(event.sender.clone());
+                notifi.event_type = Some(event.kind.clone());
+                notifi.content = serde_json::value::to_raw_value(&event.content).ok();
 
-                    self.send_request(&http.url, send_event_notification::v1::Request::new(notifi))
-                        .await?;
+                if event.kind == TimelineEventType::RoomMember {
+                    notifi.user_is_target =
+                        event.state_key.as_deref() == Some(event.sender.as_str());
                 }
 
-                Ok(())
+                notifi.sender_display_name = services().users.displayname(&event.sender)?;
+
+                notifi.room_name = services().rooms.state_accessor.get_name(&event.room_id)?;
+            }
+
+            let response = self
+                .send_request(&url, send_event_notification::v1::Request::new(notifi))
+                .await?;
+
+            for pushkey in response.rejected {
+                if let Some((pusher, _)) = pushers.iter().find(|(p, _)| p.ids.pushkey == pushkey) {
+                    info!(
+                        "Push gateway {} rejected pushkey of {}, removing the pusher",
+                        url, user
+                    );
+                    self.set_pusher(
+                        user,
+                        set_pusher::v3::PusherAction::Delete(pusher.ids.clone()),
+                    )?;
+                }
             }
-            // TODO: Handle email
-            PusherKind::Email(_) => Ok(()),
-            _ => Ok(()),
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::push::{Action, Tweak};
+
+    use super::PushActions;
+
+    #[test]
+    fn push_actions_from_rule_actions() {
+        let actions = PushActions::from_actions(&[
+            Action::Notify,
+            Action::SetTweak(Tweak::Sound("default".to_owned())),
+            Action::SetTweak(Tweak::Highlight(true)),
+        ]);
+        assert!(actions.notify);
+        assert!(actions.highlight);
+        assert_eq!(actions.sound.as_deref(), Some("default"));
+        assert_eq!(actions.tweaks.len(), 2);
+
+        let actions = PushActions::from_actions(&[Action::DontNotify]);
+        assert!(!actions.notify);
+        assert!(!actions.highlight);
+        assert!(actions.tweaks.is_empty());
     }
 }
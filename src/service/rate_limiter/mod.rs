@@ -0,0 +1,228 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use http::Method;
+use lru_cache::LruCache;
+use ruma::api::client::error::ErrorKind;
+
+use crate::{config::RateLimit, services, Error, Result};
+
+/// The groups of client endpoints that are limited separately.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Category {
+    Login,
+    /// Registering guest accounts, which needs nothing but a request
+    GuestRegistration,
+    Message,
+    MediaUpload,
+    General,
+}
+
+impl Category {
+    /// Sorts a client request into a category by its method, path and query string.
+    pub fn of(method: &Method, path: &str, query: Option<&str>) -> Self {
+        if path.ends_with("/login") {
+            Category::Login
+        } else if method == Method::POST
+            && path.ends_with("/register")
+            && query
+                .and_then(|query| serde_html_form::from_str::<Vec<(String, String)>>(query).ok())
+                .map_or(false, |params| {
+                    params
+                        .iter()
+                        .any(|(key, value)| key == "kind" && value == "guest")
+                })
+        {
+            Category::GuestRegistration
+        } else if path.contains("/media/") && path.contains("/upload") {
+            Category::MediaUpload
+        } else if method == Method::PUT
+            && (path.contains("/send/") || path.contains("/redact/") || path.contains("/state/"))
+        {
+            Category::Message
+        } else {
+            Category::General
+        }
+    }
+
+    fn limit(self) -> RateLimit {
+        let config = &services().globals.config.rate_limit;
+        match self {
+            Category::Login => config.login,
+            Category::GuestRegistration => config.guest_registration,
+            Category::Message => config.message,
+            Category::MediaUpload => config.media_upload,
+            Category::General => config.general,
+        }
+    }
+}
+
+pub struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst_count),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(f64::from(limit.burst_count));
+        self.updated = now;
+    }
+
+    /// Takes a token, or returns how long it takes until the next one is available.
+    fn take(&mut self, limit: RateLimit, now: Instant) -> Option<Duration> {
+        self.refill(limit, now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.per_second,
+            ))
+        }
+    }
+}
+
+/// How many requesters are remembered between cleanups. When there are more, the least recently
+/// used buckets are dropped, which only lets those requesters start with a full bucket again.
+pub const MAX_BUCKETS: usize = 100_000;
+
+pub struct Service {
+    /// Buckets by user id or IP address and category
+    pub buckets: Mutex<LruCache<(String, Category), Bucket>>,
+}
+
+impl Service {
+    /// Counts a request of the requester, which is a user id or an IP address.
+    ///
+    /// Returns a `M_LIMIT_EXCEEDED` error with the time until the next request is allowed if the
+    /// requester sent too many requests of this category.
+    pub fn check(&self, requester: String, category: Category) -> Result<()> {
+        let limit = category.limit();
+        if !limit.is_enabled() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let key = (requester, category);
+        let retry_after = match buckets.get_mut(&key) {
+            Some(bucket) => bucket.take(limit, now),
+            None => {
+                let mut bucket = Bucket::full(limit, now);
+                let retry_after = bucket.take(limit, now);
+                buckets.insert(key, bucket);
+                retry_after
+            }
+        };
+        drop(buckets);
+
+        match retry_after {
+            None => Ok(()),
+            Some(retry_after) => Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: Some(retry_after),
+                },
+                "Too many requests, please try again later.",
+            )),
+        }
+    }
+
+    /// Forgets buckets that filled up again, they behave the same as new ones.
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let refilled = buckets
+            .iter_mut()
+            .filter_map(|(key, bucket)| {
+                let limit = key.1.limit();
+                bucket.refill(limit, now);
+                (!limit.is_enabled() || bucket.tokens >= f64::from(limit.burst_count))
+                    .then(|| key.clone())
+            })
+            .collect::<Vec<_>>();
+        for key in refilled {
+            buckets.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http::Method;
+
+    use super::{Bucket, Category};
+    use crate::config::RateLimit;
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limit = RateLimit {
+            per_second: 0.5,
+            burst_count: 2,
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket::full(limit, start);
+
+        assert_eq!(bucket.take(limit, start), None);
+        assert_eq!(bucket.take(limit, start), None);
+        assert_eq!(bucket.take(limit, start), Some(Duration::from_secs(2)));
+
+        assert_eq!(bucket.take(limit, start + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn requests_are_categorized_by_path() {
+        assert_eq!(
+            Category::of(&Method::POST, "/_matrix/client/v3/login", None),
+            Category::Login
+        );
+        assert_eq!(
+            Category::of(
+                &Method::POST,
+                "/_matrix/client/v3/register",
+                Some("kind=guest")
+            ),
+            Category::GuestRegistration
+        );
+        assert_eq!(
+            Category::of(
+                &Method::POST,
+                "/_matrix/client/v3/register",
+                Some("kind=%67uest")
+            ),
+            Category::GuestRegistration
+        );
+        assert_eq!(
+            Category::of(&Method::POST, "/_matrix/client/v3/register", None),
+            Category::General
+        );
+        assert_eq!(
+            Category::of(
+                &Method::PUT,
+                "/_matrix/client/v3/rooms/!a:b/send/m.room.message/1",
+                None
+            ),
+            Category::Message
+        );
+        assert_eq!(
+            Category::of(&Method::POST, "/_matrix/media/v3/upload", None),
+            Category::MediaUpload
+        );
+        assert_eq!(
+            Category::of(&Method::GET, "/_matrix/client/v3/sync", None),
+            Category::General
+        );
+    }
+}
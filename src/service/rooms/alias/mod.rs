@@ -3,7 +3,7 @@ mod data;
 pub use data::Data;
 
 use crate::Result;
-use ruma::{OwnedRoomAliasId, OwnedRoomId, RoomAliasId, RoomId};
+use ruma::{OwnedRoomAliasId, OwnedRoomId, OwnedUserId, RoomAliasId, RoomId, UserId};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -11,8 +11,8 @@ pub struct Service {
 
 impl Service {
     #[tracing::instrument(skip(self))]
-    pub fn set_alias(&self, alias: &RoomAliasId, room_id: &RoomId) -> Result<()> {
-        self.db.set_alias(alias, room_id)
+    pub fn set_alias(&self, alias: &RoomAliasId, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+        self.db.set_alias(alias, room_id, user_id)
     }
 
     #[tracing::instrument(skip(self))]
@@ -25,6 +25,11 @@ impl Service {
         self.db.resolve_local_alias(alias)
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn who_created_alias(&self, alias: &RoomAliasId) -> Result<Option<OwnedUserId>> {
+        self.db.who_created_alias(alias)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn local_aliases_for_room<'a>(
         &'a self,
@@ -8,7 +8,7 @@ pub use data::Data;
 use ruma::{api::client::error::ErrorKind, EventId, RoomId};
 use tracing::{debug, error, warn};
 
-use crate::{services, Error, Result};
+use crate::{services, Error, PduEvent, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -24,6 +24,69 @@ impl Service {
         self.db.cache_auth_chain(key, auth_chain)
     }
 
+    /// Caches the auth chain of a new state event, so it doesn't have to be computed when it is
+    /// requested. It is the auth events and their cached auth chains, the chains of auth events
+    /// from before the cache existed are computed now.
+    ///
+    /// Only state events can be auth events of other events, so the chains of message events are
+    /// left to be computed when they are requested.
+    #[tracing::instrument(skip(self, pdu))]
+    pub fn index_auth_chain(&self, pdu: &PduEvent) -> Result<()> {
+        if pdu.state_key.is_none() {
+            return Ok(());
+        }
+
+        let auth_events = pdu
+            .auth_events
+            .iter()
+            .map(|auth_event| {
+                services()
+                    .rooms
+                    .short
+                    .get_or_create_shorteventid(auth_event)
+                    .map(|sauthevent| (sauthevent, auth_event))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let auth_chain = chain_from_auth_events(
+            auth_events.iter().map(|(sauthevent, _)| *sauthevent),
+            |sauthevent| {
+                if let Some(cached) = self.get_cached_eventid_authchain(&[sauthevent])? {
+                    return Ok(Some(cached));
+                }
+
+                let auth_event = auth_events
+                    .iter()
+                    .find(|(short, _)| *short == sauthevent)
+                    .map(|(_, auth_event)| *auth_event)
+                    .expect("short id was created from an auth event");
+
+                match self.get_auth_chain_inner(&pdu.room_id, auth_event) {
+                    Ok(inner) => {
+                        let inner = Arc::new(inner);
+                        self.cache_auth_chain(vec![sauthevent], Arc::clone(&inner))?;
+                        Ok(Some(inner))
+                    }
+                    Err(error) => {
+                        // The chain is computed again when it is requested
+                        warn!(?error, event_id = ?pdu.event_id, "Failed to index auth chain");
+                        Ok(None)
+                    }
+                }
+            },
+        )?;
+
+        let Some(auth_chain) = auth_chain else {
+            return Ok(());
+        };
+
+        let sevent_id = services()
+            .rooms
+            .short
+            .get_or_create_shorteventid(&pdu.event_id)?;
+        self.cache_auth_chain(vec![sevent_id], Arc::new(auth_chain))
+    }
+
     #[tracing::instrument(skip(self, starting_events))]
     pub async fn get_auth_chain<'a>(
         &self,
@@ -143,7 +206,15 @@ impl Service {
 
                         if !found.contains(&sauthevent) {
                             found.insert(sauthevent);
-                            todo.push(auth_event.clone());
+
+                            // Most auth events have their chain cached since they were added
+                            if let Some(cached) =
+                                self.get_cached_eventid_authchain(&[sauthevent])?
+                            {
+                                found.extend(cached.iter().copied());
+                            } else {
+                                todo.push(auth_event.clone());
+                            }
                         }
                     }
                 }
@@ -159,3 +230,127 @@ impl Service {
         Ok(found)
     }
 }
+
+/// Builds the auth chain of an event from its auth events and their auth chains. Returns `None` if
+/// the chain of one of the auth events is not known.
+fn chain_from_auth_events(
+    auth_events: impl IntoIterator<Item = u64>,
+    mut chain_of: impl FnMut(u64) -> Result<Option<Arc<HashSet<u64>>>>,
+) -> Result<Option<HashSet<u64>>> {
+    let mut auth_chain = HashSet::new();
+
+    for sauthevent in auth_events {
+        let Some(chain) = chain_of(sauthevent)? else {
+            return Ok(None);
+        };
+        auth_chain.insert(sauthevent);
+        auth_chain.extend(chain.iter().copied());
+    }
+
+    Ok(Some(auth_chain))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Instant};
+
+    use super::*;
+
+    /// Auth events of a room with the create event, the creator's join, the power levels and the
+    /// join rules, followed by users joining and then changing their display name. The power
+    /// levels are replaced every 1000 events.
+    fn room_auth_events(state_events: u64) -> HashMap<u64, Vec<u64>> {
+        let mut auth_events = HashMap::from([
+            (0, vec![]),
+            (1, vec![0]),
+            (2, vec![0, 1]),
+            (3, vec![0, 1, 2]),
+        ]);
+        let mut power_levels = 2;
+        for event in 4..state_events {
+            if event % 1000 == 0 {
+                auth_events.insert(event, vec![0, 1, power_levels]);
+                power_levels = event;
+            } else if event % 2 == 1 {
+                auth_events.insert(event, vec![0, power_levels, 3]);
+            } else {
+                auth_events.insert(event, vec![0, power_levels, event - 1]);
+            }
+        }
+        auth_events
+    }
+
+    /// Walks the auth events like the chain of an event was computed before it was indexed.
+    fn walk_auth_chain(auth_events: &HashMap<u64, Vec<u64>>, event: u64) -> HashSet<u64> {
+        let mut todo = vec![event];
+        let mut found = HashSet::new();
+        while let Some(event) = todo.pop() {
+            for &auth_event in &auth_events[&event] {
+                if found.insert(auth_event) {
+                    todo.push(auth_event);
+                }
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn chain_includes_auth_events_and_their_chains() {
+        let chains = HashMap::from([(1, Arc::new(HashSet::from([0]))), (0, Arc::default())]);
+
+        assert_eq!(
+            chain_from_auth_events([0, 1], |event| Ok(chains.get(&event).cloned())).unwrap(),
+            Some(HashSet::from([0, 1]))
+        );
+        assert_eq!(
+            chain_from_auth_events([1, 2], |event| Ok(chains.get(&event).cloned())).unwrap(),
+            None
+        );
+    }
+
+    /// Indexes the auth chains of a room with 100k state events as they are added, then compares
+    /// loading the full auth chain of the room state from the index with computing it by walking
+    /// the auth events, like `/state_ids` does.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture auth_chain_100k`.
+    #[test]
+    #[ignore]
+    fn bench_auth_chain_100k_state_events() {
+        const STATE_EVENTS: u64 = 100_000;
+
+        let auth_events = room_auth_events(STATE_EVENTS);
+
+        let start = Instant::now();
+        let mut chains = HashMap::<u64, Arc<HashSet<u64>>>::new();
+        for event in 0..STATE_EVENTS {
+            let chain = chain_from_auth_events(auth_events[&event].iter().copied(), |event| {
+                Ok(chains.get(&event).cloned())
+            })
+            .unwrap()
+            .unwrap();
+            chains.insert(event, Arc::new(chain));
+        }
+        let index_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut indexed = HashSet::new();
+        for event in 0..STATE_EVENTS {
+            indexed.extend(chains[&event].iter().copied());
+        }
+        let cached_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut walked = HashSet::new();
+        for event in 0..STATE_EVENTS {
+            walked.extend(walk_auth_chain(&auth_events, event));
+        }
+        let walk_time = start.elapsed();
+
+        assert_eq!(indexed, walked);
+        println!(
+            "Indexing {STATE_EVENTS} events: {index_time:?}, auth chain of {} events from the \
+             index: {cached_time:?}, walking the auth events: {walk_time:?}",
+            indexed.len(),
+        );
+    }
+}
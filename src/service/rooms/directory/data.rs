@@ -13,4 +13,17 @@ pub trait Data: Send + Sync {
 
     /// Returns the unsorted public room directory
     fn public_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
+
+    /// Adds the room to the directory of a third party network, e.g. of a bridge.
+    fn set_public_in_network(&self, network_id: &str, room_id: &RoomId) -> Result<()>;
+
+    /// Removes the room from the directory of a third party network.
+    fn set_not_public_in_network(&self, network_id: &str, room_id: &RoomId) -> Result<()>;
+
+    /// Returns the unsorted directory of a third party network, or of all of them if no network is
+    /// given.
+    fn network_public_rooms<'a>(
+        &'a self,
+        network_id: Option<&str>,
+    ) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
 }
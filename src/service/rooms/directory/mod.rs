@@ -1,7 +1,9 @@
 mod data;
 
+use std::collections::BTreeSet;
+
 pub use data::Data;
-use ruma::{OwnedRoomId, RoomId};
+use ruma::{directory::RoomNetwork, OwnedRoomId, RoomId};
 
 use crate::Result;
 
@@ -29,4 +31,31 @@ impl Service {
     pub fn public_rooms(&self) -> impl Iterator<Item = Result<OwnedRoomId>> + '_ {
         self.db.public_rooms()
     }
+
+    #[tracing::instrument(skip(self))]
+    pub fn set_public_in_network(&self, network_id: &str, room_id: &RoomId) -> Result<()> {
+        self.db.set_public_in_network(network_id, room_id)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn set_not_public_in_network(&self, network_id: &str, room_id: &RoomId) -> Result<()> {
+        self.db.set_not_public_in_network(network_id, room_id)
+    }
+
+    /// Returns the rooms published in the directory of the network: the Matrix directory, the one
+    /// of a third party network or all of them.
+    #[tracing::instrument(skip(self))]
+    pub fn public_rooms_in(&self, network: &RoomNetwork) -> Result<BTreeSet<OwnedRoomId>> {
+        match network {
+            RoomNetwork::ThirdParty(network_id) => {
+                self.db.network_public_rooms(Some(network_id)).collect()
+            }
+            RoomNetwork::All => self
+                .db
+                .public_rooms()
+                .chain(self.db.network_public_rooms(None))
+                .collect(),
+            _ => self.db.public_rooms().collect(),
+        }
+    }
 }
@@ -1,114 +1,280 @@
 mod data;
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
 
 pub use data::Data;
-use ruma::{events::presence::PresenceEvent, OwnedUserId, RoomId, UserId};
+use ruma::{
+    api::federation::transactions::edu::{Edu, PresenceContent, PresenceUpdate},
+    events::presence::{PresenceEvent, PresenceEventContent},
+    presence::PresenceState,
+    OwnedUserId, RoomId, UInt, UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::time::interval;
+use tracing::error;
 
-use crate::Result;
+use crate::{services, utils, Result};
+
+/// How often we look for users who were inactive for too long.
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 pub struct Service {
     pub db: &'static dyn Data,
 }
 
+/// The current presence of a user.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Presence {
+    pub state: PresenceState,
+    pub status_msg: Option<String>,
+    /// When the user was last active in millis since the unix epoch
+    pub last_active: u64,
+    pub currently_active: bool,
+}
+
+impl Presence {
+    /// The event stored in the rooms of the user. It contains the timestamp of the last activity,
+    /// which is converted to `last_active_ago` when the event is read.
+    fn to_presence_event(&self, user_id: &UserId) -> Result<PresenceEvent> {
+        Ok(PresenceEvent {
+            content: PresenceEventContent {
+                avatar_url: services().users.avatar_url(user_id)?,
+                currently_active: Some(self.currently_active),
+                displayname: services().users.displayname(user_id)?,
+                last_active_ago: Some(self.last_active.try_into().expect("time is valid")),
+                presence: self.state.clone(),
+                status_msg: self.status_msg.clone(),
+            },
+            sender: user_id.to_owned(),
+        })
+    }
+
+    fn last_active_ago(&self) -> UInt {
+        utils::millis_since_unix_epoch()
+            .saturating_sub(self.last_active)
+            .try_into()
+            .expect("time is valid")
+    }
+}
+
 impl Service {
-    /// Adds a presence event which will be saved until a new event replaces it.
-    ///
-    /// Note: This method takes a RoomId because presence updates are always bound to rooms to
-    /// make sure users outside these rooms can't see them.
-    pub fn update_presence(
+    /// Sets the presence the user chose, e.g. with `PUT /presence/{userId}/status`.
+    pub fn set_presence(
         &self,
         user_id: &UserId,
-        room_id: &RoomId,
-        presence: PresenceEvent,
+        state: PresenceState,
+        status_msg: Option<String>,
     ) -> Result<()> {
-        self.db.update_presence(user_id, room_id, presence)
+        let presence = Presence {
+            currently_active: state == PresenceState::Online,
+            state,
+            status_msg,
+            last_active: utils::millis_since_unix_epoch(),
+        };
+
+        self.db.set_presence(user_id, &presence)?;
+        self.announce(user_id, &presence)
     }
 
-    /// Resets the presence timeout, so the user will stay in their current presence state.
-    pub fn ping_presence(&self, user_id: &UserId) -> Result<()> {
-        self.db.ping_presence(user_id)
+    /// Marks the user as active, e.g. on every sync. The state is the one the client asks for,
+    /// offline keeps the current presence.
+    pub fn ping_presence(&self, user_id: &UserId, state: &PresenceState) -> Result<()> {
+        if !services().globals.allow_presence() || *state == PresenceState::Offline {
+            return Ok(());
+        }
+
+        let old = self.db.get_presence(user_id)?;
+        let presence = Presence {
+            state: state.clone(),
+            status_msg: old.as_ref().and_then(|old| old.status_msg.clone()),
+            last_active: utils::millis_since_unix_epoch(),
+            currently_active: *state == PresenceState::Online,
+        };
+
+        self.db.set_presence(user_id, &presence)?;
+
+        // Nobody needs to know about every single sync
+        if old.map_or(true, |old| {
+            old.state != presence.state || old.currently_active != presence.currently_active
+        }) {
+            self.announce(user_id, &presence)?;
+        }
+
+        Ok(())
     }
 
-    pub fn get_last_presence_event(
+    /// Sets the presence another server sent for one of its users.
+    pub fn set_remote_presence(
         &self,
         user_id: &UserId,
-        room_id: &RoomId,
-    ) -> Result<Option<PresenceEvent>> {
-        let last_update = match self.db.last_presence_update(user_id)? {
-            Some(last) => last,
-            None => return Ok(None),
+        state: PresenceState,
+        status_msg: Option<String>,
+        last_active_ago: UInt,
+        currently_active: bool,
+    ) -> Result<()> {
+        let presence = Presence {
+            state,
+            status_msg,
+            last_active: utils::millis_since_unix_epoch().saturating_sub(last_active_ago.into()),
+            currently_active,
         };
 
-        self.db.get_presence_event(room_id, user_id, last_update)
+        self.db.set_presence(user_id, &presence)?;
+        self.announce(user_id, &presence)
     }
 
-    /* TODO
-    /// Sets all users to offline who have been quiet for too long.
-    fn _presence_maintain(
-        &self,
-        rooms: &super::Rooms,
-        globals: &super::super::globals::Globals,
-    ) -> Result<()> {
-        let current_timestamp = utils::millis_since_unix_epoch();
-
-        for (user_id_bytes, last_timestamp) in self
-            .userid_lastpresenceupdate
-            .iter()
-            .filter_map(|(k, bytes)| {
-                Some((
-                    k,
-                    utils::u64_from_bytes(&bytes)
-                        .map_err(|_| {
-                            Error::bad_database("Invalid timestamp in userid_lastpresenceupdate.")
-                        })
-                        .ok()?,
-                ))
+    /// Sends the current presence again, e.g. because the displayname in it changed.
+    pub fn resend_presence(&self, user_id: &UserId) -> Result<()> {
+        if let Some(presence) = self.db.get_presence(user_id)? {
+            self.announce(user_id, &presence)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the current presence of the user, if we know it.
+    pub fn get_presence(&self, user_id: &UserId) -> Result<Option<PresenceEvent>> {
+        self.db
+            .get_presence(user_id)?
+            .map(|presence| {
+                let mut event = presence.to_presence_event(user_id)?;
+                // Online users are active now
+                event.content.last_active_ago =
+                    (presence.state != PresenceState::Online).then(|| presence.last_active_ago());
+
+                Ok(event)
             })
-            .take_while(|(_, timestamp)| current_timestamp.saturating_sub(*timestamp) > 5 * 60_000)
-        // 5 Minutes
-        {
-            // Send new presence events to set the user offline
-            let count = globals.next_count()?.to_be_bytes();
-            let user_id: Box<_> = utils::string_from_bytes(&user_id_bytes)
-                .map_err(|_| {
-                    Error::bad_database("Invalid UserId bytes in userid_lastpresenceupdate.")
-                })?
-                .try_into()
-                .map_err(|_| Error::bad_database("Invalid UserId in userid_lastpresenceupdate."))?;
-            for room_id in rooms.rooms_joined(&user_id).filter_map(|r| r.ok()) {
-                let mut presence_id = room_id.as_bytes().to_vec();
-                presence_id.push(0xff);
-                presence_id.extend_from_slice(&count);
-                presence_id.push(0xff);
-                presence_id.extend_from_slice(&user_id_bytes);
-
-                self.presenceid_presence.insert(
-                    &presence_id,
-                    &serde_json::to_vec(&PresenceEvent {
-                        content: PresenceEventContent {
-                            avatar_url: None,
-                            currently_active: None,
-                            displayname: None,
-                            last_active_ago: Some(
-                                last_timestamp.try_into().expect("time is valid"),
-                            ),
-                            presence: PresenceState::Offline,
-                            status_msg: None,
-                        },
-                        sender: user_id.to_owned(),
-                    })
-                    .expect("PresenceEvent can be serialized"),
-                )?;
+            .transpose()
+    }
+
+    /// Tells everyone who shares a room with the user about their presence. Local users see it in
+    /// the rooms, other servers get an EDU if the user is one of ours.
+    ///
+    /// Note: Presence updates are always bound to rooms to make sure users outside these rooms
+    /// can't see them.
+    fn announce(&self, user_id: &UserId, presence: &Presence) -> Result<()> {
+        if !services().globals.allow_presence() {
+            return Ok(());
+        }
+
+        let is_local = user_id.server_name() == services().globals.server_name();
+        let event = presence.to_presence_event(user_id)?;
+        let mut servers = BTreeSet::new();
+        let mut room_ids = Vec::new();
+
+        for room_id in services().rooms.state_cache.rooms_joined(user_id) {
+            let room_id = room_id?;
+            self.db.update_presence(&room_id, event.clone())?;
+
+            if is_local {
+                servers.extend(
+                    services()
+                        .rooms
+                        .state_cache
+                        .room_servers(&room_id)
+                        .filter_map(|r| r.ok()),
+                );
             }
 
-            self.userid_lastpresenceupdate.insert(
-                user_id.as_bytes(),
-                &utils::millis_since_unix_epoch().to_be_bytes(),
+            room_ids.push(room_id);
+        }
+
+        // Appservices get the event like clients do
+        let mut content = event.content;
+        content.last_active_ago =
+            (presence.state != PresenceState::Online).then(|| presence.last_active_ago());
+        services().sending.send_edu_appservices(
+            &room_ids
+                .iter()
+                .map(|room_id| &**room_id)
+                .collect::<Vec<_>>(),
+            &json!({
+                "type": "m.presence",
+                "sender": user_id,
+                "content": content,
+            }),
+        )?;
+
+        servers.remove(services().globals.server_name());
+        if servers.is_empty() {
+            return Ok(());
+        }
+
+        let edu = Edu::Presence(PresenceContent {
+            push: vec![PresenceUpdate {
+                user_id: user_id.to_owned(),
+                presence: presence.state.clone(),
+                status_msg: presence.status_msg.clone(),
+                last_active_ago: presence.last_active_ago(),
+                currently_active: presence.currently_active,
+            }],
+        });
+        let serialized = serde_json::to_vec(&edu).expect("presence EDU can be serialized");
+
+        for server in servers {
+            services().sending.send_reliable_edu(
+                &server,
+                serialized.clone(),
+                services().globals.next_count()?,
             )?;
         }
 
         Ok(())
-    }*/
+    }
+
+    pub fn start_timeout_task(&'static self) {
+        tokio::spawn(async move {
+            let mut i = interval(TIMEOUT_CHECK_INTERVAL);
+
+            loop {
+                i.tick().await;
+
+                if let Err(e) = self.time_out_presences() {
+                    error!("Failed to time out presences: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Makes local users unavailable and then offline once they were inactive for too long. The
+    /// servers of other users do this for them.
+    fn time_out_presences(&self) -> Result<()> {
+        let now = utils::millis_since_unix_epoch();
+        let config = &services().globals.config;
+        let idle_timeout = u64::from(config.presence_idle_timeout_seconds) * 1000;
+        let offline_timeout = u64::from(config.presence_offline_timeout_seconds) * 1000;
+
+        let timed_out = self
+            .db
+            .presences()
+            .filter_map(|r| r.ok())
+            .filter(|(user_id, _)| user_id.server_name() == services().globals.server_name())
+            .filter_map(|(user_id, mut presence)| {
+                let inactive = now.saturating_sub(presence.last_active);
+
+                if presence.state != PresenceState::Offline && inactive > offline_timeout {
+                    presence.state = PresenceState::Offline;
+                } else if presence.state == PresenceState::Online && inactive > idle_timeout {
+                    presence.state = PresenceState::Unavailable;
+                } else {
+                    return None;
+                }
+                presence.currently_active = false;
+
+                Some((user_id, presence))
+            })
+            .collect::<Vec<_>>();
+
+        for (user_id, presence) in timed_out {
+            self.db.set_presence(&user_id, &presence)?;
+            self.announce(&user_id, &presence)?;
+        }
+
+        Ok(())
+    }
 
     /// Returns the most recent presence updates that happened after the event with id `since`.
     #[tracing::instrument(skip(self, since, room_id))]
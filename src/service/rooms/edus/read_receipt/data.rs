@@ -2,7 +2,7 @@ use crate::Result;
 use ruma::{events::receipt::ReceiptEvent, serde::Raw, OwnedUserId, RoomId, UserId};
 
 pub trait Data: Send + Sync {
-    /// Replaces the previous read receipt.
+    /// Replaces the previous read receipt of the user with the same receipt type.
     fn readreceipt_update(
         &self,
         user_id: &UserId,
@@ -2,29 +2,109 @@ mod data;
 
 pub use data::Data;
 
-use crate::Result;
-use ruma::{events::receipt::ReceiptEvent, serde::Raw, OwnedUserId, RoomId, UserId};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{services, Result};
+use ruma::{
+    api::federation::transactions::edu::{Edu, ReceiptContent, ReceiptData, ReceiptMap},
+    events::{
+        receipt::{ReceiptEvent, ReceiptType},
+        AnySyncEphemeralRoomEvent,
+    },
+    serde::Raw,
+    OwnedUserId, RoomId, UserId,
+};
+use serde_json::json;
 
 pub struct Service {
     pub db: &'static dyn Data,
 }
 
 impl Service {
-    /// Replaces the previous read receipt.
+    /// Replaces the previous read receipt of the user with the same receipt type. Public receipts
+    /// of our users are sent to the other servers in the room.
     pub fn readreceipt_update(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
         event: ReceiptEvent,
     ) -> Result<()> {
-        self.db.readreceipt_update(user_id, room_id, event)
+        self.db
+            .readreceipt_update(user_id, room_id, event.clone())?;
+
+        services().sending.send_edu_appservices(
+            &[room_id],
+            &json!({
+                "type": "m.receipt",
+                "room_id": room_id,
+                "content": event.content,
+            }),
+        )?;
+
+        if user_id.server_name() == services().globals.server_name() {
+            self.federate(user_id, room_id, event)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the m.read receipt in the event as an EDU. Other servers only know about this
+    /// receipt type.
+    fn federate(&self, user_id: &UserId, room_id: &RoomId, event: ReceiptEvent) -> Result<()> {
+        let mut read = BTreeMap::new();
+        for (event_id, mut receipts) in event.content.0 {
+            if let Some(receipt) = receipts
+                .remove(&ReceiptType::Read)
+                .and_then(|mut receipts| receipts.remove(user_id))
+            {
+                read.insert(
+                    user_id.to_owned(),
+                    ReceiptData {
+                        data: receipt,
+                        event_ids: vec![event_id],
+                    },
+                );
+            }
+        }
+
+        if read.is_empty() {
+            return Ok(());
+        }
+
+        let mut servers = services()
+            .rooms
+            .state_cache
+            .room_servers(room_id)
+            .filter_map(|r| r.ok())
+            .collect::<BTreeSet<_>>();
+        servers.remove(services().globals.server_name());
+        if servers.is_empty() {
+            return Ok(());
+        }
+
+        let mut receipts = BTreeMap::new();
+        receipts.insert(room_id.to_owned(), ReceiptMap { read });
+        let edu = Edu::Receipt(ReceiptContent { receipts });
+        let serialized = serde_json::to_vec(&edu).expect("receipt EDU can be serialized");
+
+        for server in servers {
+            services().sending.send_reliable_edu(
+                &server,
+                serialized.clone(),
+                services().globals.next_count()?,
+            )?;
+        }
+
+        Ok(())
     }
 
-    /// Returns an iterator over the most recent read_receipts in a room that happened after the event with id `since`.
+    /// Returns an iterator over the most recent read_receipts in a room that happened after the
+    /// event with id `since`. Private receipts are only returned to their own user.
     #[tracing::instrument(skip(self))]
     pub fn readreceipts_since<'a>(
         &'a self,
         room_id: &RoomId,
+        viewer: &'a UserId,
         since: u64,
     ) -> impl Iterator<
         Item = Result<(
@@ -33,7 +113,12 @@ impl Service {
             Raw<ruma::events::AnySyncEphemeralRoomEvent>,
         )>,
     > + 'a {
-        self.db.readreceipts_since(room_id, since)
+        self.db
+            .readreceipts_since(room_id, since)
+            .filter(move |r| match r {
+                Ok((user_id, _, event)) => user_id == viewer || !is_private(event),
+                Err(_) => true,
+            })
     }
 
     /// Sets a private read marker at `count`.
@@ -53,3 +138,15 @@ impl Service {
         self.db.last_privateread_update(user_id, room_id)
     }
 }
+
+fn is_private(event: &Raw<AnySyncEphemeralRoomEvent>) -> bool {
+    match event.deserialize() {
+        Ok(AnySyncEphemeralRoomEvent::Receipt(receipt)) => receipt
+            .content
+            .0
+            .values()
+            .any(|receipts| receipts.contains_key(&ReceiptType::ReadPrivate)),
+        // Hide what we can't read
+        _ => true,
+    }
+}
@@ -1,5 +1,5 @@
 use crate::Result;
-use ruma::{OwnedUserId, RoomId, UserId};
+use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
 use std::collections::HashSet;
 
 pub trait Data: Send + Sync {
@@ -10,8 +10,13 @@ pub trait Data: Send + Sync {
     /// Removes a user from typing before the timeout is reached.
     fn typing_remove(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
 
-    /// Makes sure that typing events with old timestamps get removed.
-    fn typings_maintain(&self, room_id: &RoomId) -> Result<()>;
+    /// Makes sure that typing events with old timestamps get removed. Returns the users that
+    /// stopped typing.
+    fn typings_maintain(&self, room_id: &RoomId) -> Result<Vec<OwnedUserId>>;
+
+    /// Returns all rooms someone is typing in, including timed out typing events that were not
+    /// removed yet.
+    fn typing_rooms(&self) -> Result<HashSet<OwnedRoomId>>;
 
     /// Returns the count of the last typing update in this room.
     fn last_typing_update(&self, room_id: &RoomId) -> Result<u64>;
@@ -1,9 +1,21 @@
 mod data;
 
+use std::{collections::BTreeSet, time::Duration};
+
 pub use data::Data;
-use ruma::{events::SyncEphemeralRoomEvent, RoomId, UserId};
+use ruma::{
+    api::federation::transactions::edu::{Edu, TypingContent},
+    events::SyncEphemeralRoomEvent,
+    RoomId, UserId,
+};
+use serde_json::json;
+use tokio::time::interval;
+use tracing::error;
+
+use crate::{services, Result};
 
-use crate::Result;
+/// How often we look for typing events that timed out.
+const TYPING_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -13,17 +25,103 @@ impl Service {
     /// Sets a user as typing until the timeout timestamp is reached or roomtyping_remove is
     /// called.
     pub fn typing_add(&self, user_id: &UserId, room_id: &RoomId, timeout: u64) -> Result<()> {
-        self.db.typing_add(user_id, room_id, timeout)
+        self.db.typing_add(user_id, room_id, timeout)?;
+        self.push_to_appservices(room_id)?;
+        self.federate(user_id, room_id, true)
     }
 
     /// Removes a user from typing before the timeout is reached.
     pub fn typing_remove(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
-        self.db.typing_remove(user_id, room_id)
+        self.db.typing_remove(user_id, room_id)?;
+        self.push_to_appservices(room_id)?;
+        self.federate(user_id, room_id, false)
     }
 
     /// Makes sure that typing events with old timestamps get removed.
     fn typings_maintain(&self, room_id: &RoomId) -> Result<()> {
-        self.db.typings_maintain(room_id)
+        let timed_out = self.db.typings_maintain(room_id)?;
+        if !timed_out.is_empty() {
+            self.push_to_appservices(room_id)?;
+        }
+
+        for user_id in timed_out {
+            self.federate(&user_id, room_id, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends who is typing in the room now to the appservices that want ephemeral events.
+    fn push_to_appservices(&self, room_id: &RoomId) -> Result<()> {
+        let user_ids = self.db.typings_all(room_id)?;
+
+        services().sending.send_edu_appservices(
+            &[room_id],
+            &json!({
+                "type": "m.typing",
+                "room_id": room_id,
+                "content": { "user_ids": user_ids },
+            }),
+        )
+    }
+
+    /// Sends the typing state of our users to the other servers in the room.
+    fn federate(&self, user_id: &UserId, room_id: &RoomId, typing: bool) -> Result<()> {
+        if user_id.server_name() != services().globals.server_name() {
+            return Ok(());
+        }
+
+        let mut servers = services()
+            .rooms
+            .state_cache
+            .room_servers(room_id)
+            .filter_map(|r| r.ok())
+            .collect::<BTreeSet<_>>();
+        servers.remove(services().globals.server_name());
+        if servers.is_empty() {
+            return Ok(());
+        }
+
+        let edu = Edu::Typing(TypingContent::new(
+            room_id.to_owned(),
+            user_id.to_owned(),
+            typing,
+        ));
+        let serialized = serde_json::to_vec(&edu).expect("typing EDU can be serialized");
+
+        for server in servers {
+            services().sending.send_reliable_edu(
+                &server,
+                serialized.clone(),
+                services().globals.next_count()?,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn start_sweep_task(&'static self) {
+        tokio::spawn(async move {
+            let mut i = interval(TYPING_SWEEP_INTERVAL);
+
+            loop {
+                i.tick().await;
+
+                if let Err(e) = self.sweep() {
+                    error!("Failed to remove timed out typing events: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Removes timed out typing events in all rooms, so syncing clients see them stop without
+    /// anyone else causing an update.
+    fn sweep(&self) -> Result<()> {
+        for room_id in self.db.typing_rooms()? {
+            self.typings_maintain(&room_id)?;
+        }
+
+        Ok(())
     }
 
     /// Returns the count of the last typing update in this room.
@@ -1,20 +1,23 @@
+mod state_resolution;
+
 /// An async function that can recursively call itself.
 type AsyncRecursiveType<'a, T> = Pin<Box<dyn Future<Output = T> + 'a + Send>>;
 
 use ruma::{
     api::federation::discovery::{get_remote_server_keys, get_server_keys},
-    CanonicalJsonObject, CanonicalJsonValue, OwnedServerName, OwnedServerSigningKeyId,
-    RoomVersionId,
+    CanonicalJsonObject, CanonicalJsonValue, OwnedEventId, OwnedServerName,
+    OwnedServerSigningKeyId, RoomVersionId,
 };
 use std::{
     collections::{hash_map, BTreeMap, HashMap, HashSet},
     pin::Pin,
-    sync::{Arc, RwLock, RwLockWriteGuard},
+    sync::{Arc, Mutex, RwLock, RwLockWriteGuard},
     time::{Duration, Instant, SystemTime},
 };
 use tokio::sync::Semaphore;
 
 use futures_util::{stream::FuturesUnordered, Future, StreamExt};
+use lru_cache::LruCache;
 use ruma::{
     api::{
         client::error::ErrorKind,
@@ -24,10 +27,7 @@ use ruma::{
             membership::create_join_event,
         },
     },
-    events::{
-        room::{create::RoomCreateEventContent, server_acl::RoomServerAclEventContent},
-        StateEventType,
-    },
+    events::{room::create::RoomCreateEventContent, StateEventType},
     int,
     serde::Base64,
     state_res::{self, RoomVersion, StateMap},
@@ -40,9 +40,25 @@ use crate::{service::*, services, Error, PduEvent, Result};
 
 use super::state_compressor::CompressedStateEvent;
 
-pub struct Service;
+pub struct Service {
+    /// Events of federation transactions that were handled successfully, so retransmissions of
+    /// them can be skipped
+    pub handled_pdu_cache: Mutex<LruCache<OwnedEventId, ()>>,
+}
 
 impl Service {
+    /// Whether this event of a transaction was already handled successfully.
+    pub fn was_handled(&self, event_id: &EventId) -> bool {
+        self.handled_pdu_cache
+            .lock()
+            .unwrap()
+            .contains_key(event_id)
+    }
+
+    pub fn mark_handled(&self, event_id: OwnedEventId) {
+        self.handled_pdu_cache.lock().unwrap().insert(event_id, ());
+    }
+
     /// When receiving an event one needs to:
     /// 0. Check the server is in the room
     /// 1. Skip the PDU if we already know about it
@@ -449,10 +465,24 @@ impl Service {
                 ));
             }
 
+            let third_party_invite = incoming_pdu
+                .third_party_invite_token()
+                .and_then(|token| auth_events.get(&(StateEventType::RoomThirdPartyInvite, token)));
+
+            // Also make sure one of the keys of the identity server signed the invite
+            if third_party_invite.map_or(false, |third_party_invite| {
+                !third_party_invite.verifies_third_party_signed(&incoming_pdu.content)
+            }) {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Third party invite is not signed by the identity server",
+                ));
+            }
+
             if !state_res::event_auth::auth_check(
                 &room_version,
                 &incoming_pdu,
-                None::<PduEvent>, // TODO: third party invite
+                third_party_invite,
                 |k, s| auth_events.get(&(k.to_string().into(), s.to_owned())),
             )
             .map_err(|_e| Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed"))?
@@ -470,6 +500,10 @@ impl Service {
                 .rooms
                 .outlier
                 .add_pdu_outlier(&incoming_pdu.event_id, &val)?;
+            services()
+                .rooms
+                .auth_chain
+                .index_auth_chain(&incoming_pdu)?;
 
             debug!("Added pdu as outlier.");
 
@@ -596,7 +630,8 @@ impl Service {
 
             if okay {
                 let mut fork_states = Vec::with_capacity(extremity_sstatehashes.len());
-                let mut auth_chain_sets = Vec::with_capacity(extremity_sstatehashes.len());
+                let mut auth_chain_sets: Vec<HashSet<_>> =
+                    Vec::with_capacity(extremity_sstatehashes.len());
 
                 for (sstatehash, prev_event) in extremity_sstatehashes {
                     let mut leaf_state: HashMap<_, _> = services()
@@ -643,14 +678,18 @@ impl Service {
 
                 let lock = services().globals.stateres_mutex.lock();
 
-                let result =
-                    state_res::resolve(room_version_id, &fork_states, auth_chain_sets, |id| {
+                let result = state_resolution::resolve(
+                    room_version_id,
+                    &fork_states,
+                    &auth_chain_sets,
+                    |id| {
                         let res = services().rooms.timeline.get_pdu(id);
                         if let Err(e) = &res {
                             error!("LOOK AT ME Failed to fetch event: {}", e);
                         }
                         res.ok().flatten()
-                    });
+                    },
+                );
                 drop(lock);
 
                 state_at_incoming_event = match result {
@@ -756,20 +795,25 @@ impl Service {
 
         debug!("Starting auth check");
         // 11. Check the auth of the event passes based on the state of the event
+        let state_event_at_incoming_event = |k: &StateEventType, s: &str| {
+            services()
+                .rooms
+                .short
+                .get_shortstatekey(k, s)
+                .ok()
+                .flatten()
+                .and_then(|shortstatekey| state_at_incoming_event.get(&shortstatekey))
+                .and_then(|event_id| services().rooms.timeline.get_pdu(event_id).ok().flatten())
+        };
+        let third_party_invite = incoming_pdu.third_party_invite_token().and_then(|token| {
+            state_event_at_incoming_event(&StateEventType::RoomThirdPartyInvite, &token)
+        });
+
         let check_result = state_res::event_auth::auth_check(
             &room_version,
             &incoming_pdu,
-            None::<PduEvent>, // TODO: third party invite
-            |k, s| {
-                services()
-                    .rooms
-                    .short
-                    .get_shortstatekey(&k.to_string().into(), s)
-                    .ok()
-                    .flatten()
-                    .and_then(|shortstatekey| state_at_incoming_event.get(&shortstatekey))
-                    .and_then(|event_id| services().rooms.timeline.get_pdu(event_id).ok().flatten())
-            },
+            third_party_invite,
+            |k, s| state_event_at_incoming_event(&k.to_string().into(), s),
         )
         .map_err(|_e| Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed."))?;
 
@@ -789,10 +833,14 @@ impl Service {
             &incoming_pdu.content,
         )?;
 
+        let third_party_invite = incoming_pdu
+            .third_party_invite_token()
+            .and_then(|token| auth_events.get(&(StateEventType::RoomThirdPartyInvite, token)));
+
         let soft_fail = !state_res::event_auth::auth_check(
             &room_version,
             &incoming_pdu,
-            None::<PduEvent>,
+            third_party_invite,
             |k, s| auth_events.get(&(k.clone(), s.to_owned())),
         )
         .map_err(|_e| Error::BadRequest(ErrorKind::InvalidParam, "Auth check failed."))?;
@@ -947,9 +995,22 @@ impl Service {
             .state_full_ids(current_sstatehash)
             .await?;
 
-        let fork_states = [current_state_ids, incoming_state];
+        self.resolve_forks(
+            room_id,
+            room_version_id,
+            vec![current_state_ids, incoming_state],
+        )
+        .await
+    }
 
-        let mut auth_chain_sets = Vec::new();
+    /// Runs state resolution over the given states, keyed by shortstatekey.
+    async fn resolve_forks(
+        &self,
+        room_id: &RoomId,
+        room_version_id: &RoomVersionId,
+        fork_states: Vec<HashMap<u64, Arc<EventId>>>,
+    ) -> Result<Arc<HashSet<CompressedStateEvent>>> {
+        let mut auth_chain_sets: Vec<HashSet<_>> = Vec::new();
         for state in &fork_states {
             auth_chain_sets.push(
                 services()
@@ -990,10 +1051,10 @@ impl Service {
         };
 
         let lock = services().globals.stateres_mutex.lock();
-        let state = match state_res::resolve(
+        let state = match state_resolution::resolve(
             room_version_id,
             &fork_states,
-            auth_chain_sets,
+            &auth_chain_sets,
             fetch_event,
         ) {
             Ok(new_state) => new_state,
@@ -1023,6 +1084,116 @@ impl Service {
         Ok(Arc::new(new_room_state))
     }
 
+    /// Runs state resolution again over the states after each forward extremity of the room, e.g.
+    /// to repair a corrupted current state. The result only becomes the current state if `apply`
+    /// is set, running it again without new events changes nothing.
+    ///
+    /// Returns the state events that are added to and removed from the current state.
+    pub async fn recompute_state(
+        &self,
+        room_id: &RoomId,
+        apply: bool,
+    ) -> Result<(HashSet<CompressedStateEvent>, HashSet<CompressedStateEvent>)> {
+        let current_shortstatehash = services()
+            .rooms
+            .state
+            .get_room_shortstatehash(room_id)?
+            .ok_or(Error::BadRequest(
+                ErrorKind::NotFound,
+                "We don't know the state of this room.",
+            ))?;
+        let room_version_id = services().rooms.state.get_room_version(room_id)?;
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let mut fork_states = Vec::new();
+        for event_id in services().rooms.state.get_forward_extremities(room_id)? {
+            let Some(shortstatehash) = services()
+                .rooms
+                .state_accessor
+                .pdu_shortstatehash(&event_id)?
+            else {
+                warn!("Forward extremity {} has no state, skipping it", event_id);
+                continue;
+            };
+
+            // The state before the event, the event itself is part of the state after it
+            let mut state = services()
+                .rooms
+                .state_accessor
+                .state_full_ids(shortstatehash)
+                .await?;
+            let pdu = services()
+                .rooms
+                .timeline
+                .get_pdu(&event_id)?
+                .ok_or_else(|| Error::bad_database("Forward extremity not found."))?;
+            if let Some(state_key) = &pdu.state_key {
+                let shortstatekey = services()
+                    .rooms
+                    .short
+                    .get_or_create_shortstatekey(&pdu.kind.to_string().into(), state_key)?;
+                state.insert(shortstatekey, event_id);
+            }
+
+            fork_states.push(state);
+        }
+
+        if fork_states.is_empty() {
+            return Err(Error::bad_database(
+                "Room has no forward extremities with state.",
+            ));
+        }
+
+        let new_state = self
+            .resolve_forks(room_id, &room_version_id, fork_states)
+            .await?;
+
+        let current_state = services()
+            .rooms
+            .state_compressor
+            .load_shortstatehash_info(current_shortstatehash)?
+            .pop()
+            .expect("there is always one layer")
+            .1;
+        let added = new_state.difference(&current_state).copied().collect();
+        let removed = current_state.difference(&new_state).copied().collect();
+
+        if apply {
+            let (shortstatehash, statediffnew, statediffremoved) = services()
+                .rooms
+                .state_compressor
+                .save_state(room_id, new_state)?;
+
+            if shortstatehash != current_shortstatehash {
+                services()
+                    .rooms
+                    .state
+                    .force_state(
+                        room_id,
+                        shortstatehash,
+                        statediffnew,
+                        statediffremoved,
+                        &state_lock,
+                    )
+                    .await?;
+            }
+        }
+
+        drop(state_lock);
+
+        Ok((added, removed))
+    }
+
     /// Find the event and auth it. Once the event is validated (steps 1 - 8)
     /// it is appended to the outliers Tree.
     ///
@@ -1580,30 +1751,12 @@ impl Service {
 
     /// Returns Ok if the acl allows the server
     pub fn acl_check(&self, server_name: &ServerName, room_id: &RoomId) -> Result<()> {
-        let acl_event = match services().rooms.state_accessor.room_state_get(
-            room_id,
-            &StateEventType::RoomServerAcl,
-            "",
-        )? {
+        let acl = match services().rooms.state_accessor.server_acl(room_id)? {
             Some(acl) => acl,
             None => return Ok(()),
         };
 
-        let acl_event_content: RoomServerAclEventContent =
-            match serde_json::from_str(acl_event.content.get()) {
-                Ok(content) => content,
-                Err(_) => {
-                    warn!("Invalid ACL event");
-                    return Ok(());
-                }
-            };
-
-        if acl_event_content.allow.is_empty() {
-            // Ignore broken acl events
-            return Ok(());
-        }
-
-        if acl_event_content.is_allowed(server_name) {
+        if acl.is_allowed(server_name) {
             Ok(())
         } else {
             info!(
@@ -0,0 +1,1015 @@
+//! Version 2 of the state resolution algorithm, see
+//! <https://spec.matrix.org/v1.8/rooms/v2/#state-resolution>.
+//!
+//! The authorization rules of the single events are the ones of ruma's `state_res`, this module
+//! only decides in which order the conflicting events are checked and which of them win.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use ruma::{
+    api::client::error::ErrorKind,
+    events::{room::member::MembershipState, StateEventType, TimelineEventType},
+    state_res::{self, RoomVersion, StateMap},
+    EventId, OwnedUserId, RoomVersionId, UInt, UserId,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::{Error, PduEvent, Result};
+
+/// Resolves the conflicts between the state sets of several forks of the room.
+///
+/// `auth_chain_sets` contains the full auth chain of each state set and `fetch_event` loads
+/// events from the event store. Events of the auth difference that can't be loaded are ignored,
+/// but all events of the conflicted state need to exist.
+pub fn resolve(
+    room_version_id: &RoomVersionId,
+    state_sets: &[StateMap<Arc<EventId>>],
+    auth_chain_sets: &[HashSet<Arc<EventId>>],
+    fetch_event: impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> Result<StateMap<Arc<EventId>>> {
+    let room_version = RoomVersion::new(room_version_id).map_err(|_| {
+        Error::BadRequest(
+            ErrorKind::UnsupportedRoomVersion,
+            "State resolution does not support this room version.",
+        )
+    })?;
+
+    let (unconflicted_state, conflicted_state) = separate(state_sets);
+    if conflicted_state.is_empty() {
+        return Ok(unconflicted_state);
+    }
+    debug!("{} conflicted state keys", conflicted_state.len());
+
+    let conflicted_events = conflicted_state
+        .into_values()
+        .flatten()
+        .collect::<HashSet<_>>();
+    if conflicted_events.iter().any(|id| fetch_event(id).is_none()) {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Event of the conflicted state could not be found.",
+        ));
+    }
+
+    let full_conflicted_set = auth_difference(auth_chain_sets)
+        .into_iter()
+        .filter(|id| fetch_event(id).is_some())
+        .chain(conflicted_events)
+        .collect::<HashSet<_>>();
+
+    // The power events and the events of their auth chains are checked first, in an order where
+    // more powerful senders go first
+    let control_events = full_conflicted_set
+        .iter()
+        .filter(|id| fetch_event(id).map_or(false, |pdu| is_power_event(&pdu)))
+        .cloned()
+        .collect::<Vec<_>>();
+    let sorted_control_events =
+        reverse_topological_power_sort(control_events, &full_conflicted_set, &fetch_event);
+
+    let mut resolved_state = iterative_auth_check(
+        &room_version,
+        &sorted_control_events,
+        unconflicted_state.clone(),
+        &fetch_event,
+    );
+
+    // The other events are checked in the order of the resolved power levels' history
+    let sorted_control_events = sorted_control_events.into_iter().collect::<HashSet<_>>();
+    let other_events = full_conflicted_set
+        .into_iter()
+        .filter(|id| !sorted_control_events.contains(id))
+        .collect::<Vec<_>>();
+    let resolved_power_levels =
+        resolved_state.get(&(StateEventType::RoomPowerLevels, "".to_owned()));
+    let sorted_other_events = mainline_sort(other_events, resolved_power_levels, &fetch_event);
+
+    resolved_state = iterative_auth_check(
+        &room_version,
+        &sorted_other_events,
+        resolved_state,
+        &fetch_event,
+    );
+
+    // The unconflicted state always wins
+    resolved_state.extend(unconflicted_state);
+
+    Ok(resolved_state)
+}
+
+/// Splits the state into the keys that have the same event in every state set and the keys that
+/// don't, including the keys that are missing in some state sets.
+fn separate(
+    state_sets: &[StateMap<Arc<EventId>>],
+) -> (StateMap<Arc<EventId>>, StateMap<HashSet<Arc<EventId>>>) {
+    let mut unconflicted_state = StateMap::new();
+    let mut conflicted_state = StateMap::new();
+
+    let keys = state_sets
+        .iter()
+        .flat_map(|state| state.keys())
+        .collect::<HashSet<_>>();
+
+    for key in keys {
+        let event_ids = state_sets
+            .iter()
+            .map(|state| state.get(key))
+            .collect::<HashSet<_>>();
+
+        match event_ids.iter().next() {
+            Some(Some(event_id)) if event_ids.len() == 1 => {
+                unconflicted_state.insert(key.clone(), (*event_id).clone());
+            }
+            _ => {
+                conflicted_state.insert(
+                    key.clone(),
+                    event_ids.into_iter().flatten().cloned().collect(),
+                );
+            }
+        }
+    }
+
+    (unconflicted_state, conflicted_state)
+}
+
+/// The events that are in some, but not all auth chains.
+fn auth_difference(auth_chain_sets: &[HashSet<Arc<EventId>>]) -> HashSet<Arc<EventId>> {
+    let Some(first) = auth_chain_sets.first() else {
+        return HashSet::new();
+    };
+
+    let common = first
+        .iter()
+        .filter(|id| auth_chain_sets.iter().all(|chain| chain.contains(*id)))
+        .collect::<HashSet<_>>();
+
+    auth_chain_sets
+        .iter()
+        .flatten()
+        .filter(|id| !common.contains(id))
+        .cloned()
+        .collect()
+}
+
+/// Events that can take away permissions of other users.
+fn is_power_event(pdu: &PduEvent) -> bool {
+    match pdu.kind {
+        TimelineEventType::RoomPowerLevels
+        | TimelineEventType::RoomJoinRules
+        | TimelineEventType::RoomCreate => pdu.state_key.as_deref() == Some(""),
+        TimelineEventType::RoomMember => {
+            #[derive(Deserialize)]
+            struct ExtractMembership {
+                membership: MembershipState,
+            }
+
+            pdu.state_key.as_deref() != Some(pdu.sender.as_str())
+                && serde_json::from_str::<ExtractMembership>(pdu.content.get()).map_or(
+                    false,
+                    |content| {
+                        matches!(
+                            content.membership,
+                            MembershipState::Leave | MembershipState::Ban
+                        )
+                    },
+                )
+        }
+        _ => false,
+    }
+}
+
+/// Sorts the control events and the events of their auth chains that are also conflicted, so
+/// auth events come before the events they authorize. Ties are broken by the power level of the
+/// sender, then the timestamp and then the event id.
+fn reverse_topological_power_sort(
+    control_events: Vec<Arc<EventId>>,
+    full_conflicted_set: &HashSet<Arc<EventId>>,
+    fetch_event: &impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> Vec<Arc<EventId>> {
+    let mut graph = HashMap::new();
+    let mut todo = control_events;
+
+    while let Some(event_id) = todo.pop() {
+        if graph.contains_key(&event_id) {
+            continue;
+        }
+
+        let auth_events = fetch_event(&event_id)
+            .map(|pdu| {
+                pdu.auth_events
+                    .iter()
+                    .filter(|id| full_conflicted_set.contains(*id))
+                    .cloned()
+                    .collect::<HashSet<_>>()
+            })
+            .unwrap_or_default();
+
+        todo.extend(auth_events.iter().cloned());
+        graph.insert(event_id, auth_events);
+    }
+
+    topological_sort(&graph, |event_id| {
+        fetch_event(event_id).map_or((0, UInt::MIN), |pdu| {
+            (sender_power_level(&pdu, fetch_event), pdu.origin_server_ts)
+        })
+    })
+}
+
+/// Kahn's algorithm: an event is sorted once all events it points to are sorted. Of the events
+/// that could come next, the one with the highest power level, then the lowest timestamp, then the
+/// lowest event id goes first.
+fn topological_sort(
+    graph: &HashMap<Arc<EventId>, HashSet<Arc<EventId>>>,
+    key: impl Fn(&EventId) -> (i64, UInt),
+) -> Vec<Arc<EventId>> {
+    let mut outstanding = HashMap::new();
+    let mut pointed_to_by: HashMap<_, Vec<_>> = HashMap::new();
+
+    for (event_id, edges) in graph {
+        let edges = edges
+            .iter()
+            .filter(|id| graph.contains_key(*id))
+            .collect::<Vec<_>>();
+        outstanding.insert(event_id, edges.len());
+
+        for edge in edges {
+            pointed_to_by.entry(edge).or_default().push(event_id);
+        }
+    }
+
+    let candidate = |event_id: &Arc<EventId>| {
+        let (power_level, origin_server_ts) = key(event_id);
+        Reverse((Reverse(power_level), origin_server_ts, event_id.clone()))
+    };
+
+    let mut heap = outstanding
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(event_id, _)| candidate(event_id))
+        .collect::<BinaryHeap<_>>();
+
+    let mut sorted = Vec::with_capacity(graph.len());
+    while let Some(Reverse((_, _, event_id))) = heap.pop() {
+        for next in pointed_to_by.get(&event_id).into_iter().flatten() {
+            let count = outstanding
+                .get_mut(next)
+                .expect("every event of the graph is counted");
+            *count -= 1;
+            if *count == 0 {
+                heap.push(candidate(next));
+            }
+        }
+
+        sorted.push(event_id);
+    }
+
+    if sorted.len() != graph.len() {
+        warn!("Auth events of the conflicted state form a cycle, some events are ignored");
+    }
+
+    sorted
+}
+
+/// Sorts the events by the position of the closest event of the mainline, the history of the
+/// resolved power levels. Ties are broken by the timestamp and then the event id.
+fn mainline_sort(
+    events: Vec<Arc<EventId>>,
+    resolved_power_levels: Option<&Arc<EventId>>,
+    fetch_event: &impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> Vec<Arc<EventId>> {
+    let mut mainline = Vec::new();
+    let mut current = resolved_power_levels.cloned();
+    while let Some(event_id) = current {
+        if mainline.contains(&event_id) {
+            break;
+        }
+        current = fetch_event(&event_id).and_then(|pdu| power_levels_auth_event(&pdu, fetch_event));
+        mainline.push(event_id);
+    }
+
+    // The oldest power levels event has the lowest position
+    let positions = mainline
+        .into_iter()
+        .rev()
+        .enumerate()
+        .map(|(position, event_id)| (event_id, position))
+        .collect::<HashMap<_, _>>();
+
+    let mut keyed = events
+        .into_iter()
+        .filter_map(|event_id| {
+            let pdu = fetch_event(&event_id)?;
+            let position = mainline_position(&pdu, &positions, fetch_event);
+            Some(((position, pdu.origin_server_ts, event_id.clone()), event_id))
+        })
+        .collect::<Vec<_>>();
+    keyed.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    keyed.into_iter().map(|(_, event_id)| event_id).collect()
+}
+
+/// The position of the first mainline event found by following the power levels events in the
+/// auth events, starting with the event itself.
+fn mainline_position(
+    pdu: &PduEvent,
+    positions: &HashMap<Arc<EventId>, usize>,
+    fetch_event: &impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> usize {
+    if let Some(position) = positions.get(&pdu.event_id) {
+        return *position;
+    }
+
+    let mut seen = HashSet::new();
+    let mut current = power_levels_auth_event(pdu, fetch_event);
+    while let Some(event_id) = current {
+        if let Some(position) = positions.get(&event_id) {
+            return *position;
+        }
+        if !seen.insert(event_id.clone()) {
+            break;
+        }
+        current = fetch_event(&event_id).and_then(|pdu| power_levels_auth_event(&pdu, fetch_event));
+    }
+
+    0
+}
+
+/// Checks the events one after the other against the partial state, every accepted event is
+/// added to it. The auth events of an event are replaced by the ones in the partial state.
+fn iterative_auth_check(
+    room_version: &RoomVersion,
+    events: &[Arc<EventId>],
+    mut partial_state: StateMap<Arc<EventId>>,
+    fetch_event: &impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> StateMap<Arc<EventId>> {
+    for event_id in events {
+        let Some(pdu) = fetch_event(event_id) else {
+            warn!("Could not find event {} for state resolution", event_id);
+            continue;
+        };
+        let Some(state_key) = &pdu.state_key else {
+            continue;
+        };
+
+        let mut auth_events = StateMap::new();
+        for auth_event_id in &pdu.auth_events {
+            match fetch_event(auth_event_id) {
+                Some(auth_event) => {
+                    if let Some(auth_state_key) = &auth_event.state_key {
+                        auth_events.insert(
+                            (auth_event.kind.to_string().into(), auth_state_key.clone()),
+                            auth_event,
+                        );
+                    }
+                }
+                None => warn!("Auth event {} of {} is missing", auth_event_id, event_id),
+            }
+        }
+
+        let auth_types = match state_res::auth_types_for_event(
+            &pdu.kind,
+            &pdu.sender,
+            Some(state_key),
+            &pdu.content,
+        ) {
+            Ok(auth_types) => auth_types,
+            Err(e) => {
+                warn!("Invalid content of {}: {}", event_id, e);
+                continue;
+            }
+        };
+        for key in auth_types {
+            if let Some(auth_event) = partial_state.get(&key).and_then(|id| fetch_event(id)) {
+                auth_events.insert(key, auth_event);
+            }
+        }
+
+        let current_third_party_invite = auth_events
+            .values()
+            .find(|auth_event| auth_event.kind == TimelineEventType::RoomThirdPartyInvite);
+
+        match state_res::auth_check(
+            room_version,
+            &*pdu,
+            current_third_party_invite,
+            |kind, state_key| auth_events.get(&(kind.clone(), state_key.to_owned())),
+        ) {
+            Ok(true) => {
+                partial_state.insert(
+                    (pdu.kind.to_string().into(), state_key.clone()),
+                    event_id.clone(),
+                );
+            }
+            Ok(false) => debug!("{} was rejected by state resolution", event_id),
+            Err(e) => warn!("Auth check of {} failed: {}", event_id, e),
+        }
+    }
+
+    partial_state
+}
+
+fn power_levels_auth_event(
+    pdu: &PduEvent,
+    fetch_event: &impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> Option<Arc<EventId>> {
+    pdu.auth_events.iter().find_map(|id| {
+        let auth_event = fetch_event(id)?;
+        (auth_event.kind == TimelineEventType::RoomPowerLevels
+            && auth_event.state_key.as_deref() == Some(""))
+        .then(|| id.clone())
+    })
+}
+
+/// The power level of the sender according to the power levels in the auth events. Without power
+/// levels the creator of the room has 100.
+fn sender_power_level(
+    pdu: &PduEvent,
+    fetch_event: &impl Fn(&EventId) -> Option<Arc<PduEvent>>,
+) -> i64 {
+    let auth_events = pdu
+        .auth_events
+        .iter()
+        .filter_map(|id| fetch_event(id))
+        .collect::<Vec<_>>();
+
+    if let Some(power_levels) = auth_events.iter().find(|auth_event| {
+        auth_event.kind == TimelineEventType::RoomPowerLevels
+            && auth_event.state_key.as_deref() == Some("")
+    }) {
+        return user_power_level(power_levels, &pdu.sender);
+    }
+
+    #[derive(Deserialize)]
+    struct ExtractCreator {
+        creator: Option<OwnedUserId>,
+    }
+
+    if pdu.kind == TimelineEventType::RoomCreate {
+        return 100;
+    }
+
+    let is_creator = auth_events
+        .iter()
+        .find(|auth_event| auth_event.kind == TimelineEventType::RoomCreate)
+        .map_or(false, |create| {
+            // Since room version 11 the sender of the create event is the creator
+            let creator = serde_json::from_str::<ExtractCreator>(create.content.get())
+                .ok()
+                .and_then(|content| content.creator)
+                .unwrap_or_else(|| create.sender.clone());
+            creator == pdu.sender
+        });
+
+    if is_creator {
+        100
+    } else {
+        0
+    }
+}
+
+fn user_power_level(power_levels: &PduEvent, user_id: &UserId) -> i64 {
+    // Room versions before 10 allow power levels as strings
+    fn int(value: &Value) -> Option<i64> {
+        value
+            .as_i64()
+            .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+    }
+
+    let Ok(content) = serde_json::from_str::<Value>(power_levels.content.get()) else {
+        return 0;
+    };
+
+    content
+        .get("users")
+        .and_then(|users| users.get(user_id.as_str()))
+        .and_then(int)
+        .or_else(|| content.get("users_default").and_then(int))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    //! The cases of the state resolution tests of Synapse and ruma. Every case adds some events
+    //! after the same initial room and lists chains of prev events, the state at `END` merges them.
+
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use ruma::{
+        state_res::{self, StateMap},
+        EventId, RoomVersionId,
+    };
+    use serde_json::{json, Value};
+
+    use super::{resolve, topological_sort};
+    use crate::PduEvent;
+
+    const ALICE: &str = "@alice:foo";
+    const BOB: &str = "@bob:foo";
+    const CHARLIE: &str = "@charlie:foo";
+    const ELLA: &str = "@ella:foo";
+
+    type TestEvent = (
+        &'static str,
+        &'static str,
+        &'static str,
+        &'static str,
+        Value,
+    );
+
+    fn event_id(id: &str) -> Arc<EventId> {
+        EventId::parse_arc(format!("${id}:foo")).expect("test event ids are valid")
+    }
+
+    fn initial_events(room_version_id: &RoomVersionId) -> Vec<TestEvent> {
+        // Since room version 11 the sender of the create event is the creator
+        let create_content = match room_version_id {
+            RoomVersionId::V11 => json!({ "room_version": "11" }),
+            _ => json!({ "creator": ALICE }),
+        };
+
+        vec![
+            ("CREATE", ALICE, "m.room.create", "", create_content),
+            (
+                "IMA",
+                ALICE,
+                "m.room.member",
+                ALICE,
+                json!({ "membership": "join" }),
+            ),
+            (
+                "IPOWER",
+                ALICE,
+                "m.room.power_levels",
+                "",
+                json!({ "users": { ALICE: 100 } }),
+            ),
+            (
+                "IJR",
+                ALICE,
+                "m.room.join_rules",
+                "",
+                json!({ "join_rule": "public" }),
+            ),
+            (
+                "IMB",
+                BOB,
+                "m.room.member",
+                BOB,
+                json!({ "membership": "join" }),
+            ),
+            (
+                "IMC",
+                CHARLIE,
+                "m.room.member",
+                CHARLIE,
+                json!({ "membership": "join" }),
+            ),
+            ("START", CHARLIE, "m.room.message", "dummy", json!({})),
+        ]
+    }
+
+    fn auth_chain(
+        events: &HashMap<Arc<EventId>, Arc<PduEvent>>,
+        starting_events: impl Iterator<Item = Arc<EventId>>,
+    ) -> HashSet<Arc<EventId>> {
+        let mut chain = HashSet::new();
+        let mut todo = starting_events
+            .flat_map(|id| events[&id].auth_events.clone())
+            .collect::<Vec<_>>();
+
+        while let Some(id) = todo.pop() {
+            if chain.insert(id.clone()) {
+                todo.extend(events[&id].auth_events.iter().cloned());
+            }
+        }
+
+        chain
+    }
+
+    /// Builds the room, resolving the state wherever prev events fork, and compares the state at
+    /// `END` with the expected events where it differs from the initial state.
+    fn check(test_events: Vec<TestEvent>, edges: &[&[&str]], expected: &[&str]) {
+        check_version(&RoomVersionId::V6, test_events, edges, expected);
+    }
+
+    /// Like `check`, in a room of the given version.
+    fn check_version(
+        room_version_id: &RoomVersionId,
+        test_events: Vec<TestEvent>,
+        edges: &[&[&str]],
+        expected: &[&str],
+    ) {
+        let mut templates = HashMap::new();
+        let mut graph: HashMap<_, HashSet<_>> = HashMap::new();
+
+        let all_events = initial_events(room_version_id)
+            .into_iter()
+            .chain(test_events)
+            .chain([("END", CHARLIE, "m.room.message", "dummy", json!({}))]);
+        for (ts, (id, sender, kind, state_key, content)) in all_events.enumerate() {
+            let pdu: PduEvent = serde_json::from_value(json!({
+                "event_id": event_id(id),
+                "room_id": "!test:foo",
+                "sender": sender,
+                "origin_server_ts": ts,
+                "type": kind,
+                "content": content,
+                "state_key": state_key,
+                "prev_events": [],
+                "depth": 0,
+                "auth_events": [],
+                "hashes": { "sha256": "" },
+            }))
+            .expect("test events are valid");
+            graph.insert(pdu.event_id.clone(), HashSet::new());
+            templates.insert(pdu.event_id.clone(), pdu);
+        }
+
+        let initial_edges: &[&str] = &["START", "IMC", "IMB", "IJR", "IPOWER", "IMA", "CREATE"];
+        for chain in Some(initial_edges).iter().chain(edges) {
+            for pair in chain.windows(2) {
+                graph
+                    .get_mut(&event_id(pair[0]))
+                    .expect("edges only use known events")
+                    .insert(event_id(pair[1]));
+            }
+        }
+
+        let mut events = HashMap::new();
+        let mut state_after: HashMap<Arc<EventId>, StateMap<Arc<EventId>>> = HashMap::new();
+
+        let sorted = topological_sort(&graph, |id| (0, templates[id].origin_server_ts));
+        for id in sorted {
+            let prev_events = &graph[&id];
+            let state_before = if prev_events.len() <= 1 {
+                prev_events
+                    .iter()
+                    .next()
+                    .map(|prev| state_after[prev].clone())
+                    .unwrap_or_default()
+            } else {
+                let state_sets = prev_events
+                    .iter()
+                    .map(|prev| state_after[prev].clone())
+                    .collect::<Vec<_>>();
+                let auth_chain_sets = state_sets
+                    .iter()
+                    .map(|state| auth_chain(&events, state.values().cloned()))
+                    .collect::<Vec<_>>();
+                resolve(room_version_id, &state_sets, &auth_chain_sets, |id| {
+                    events.get(id).cloned()
+                })
+                .expect("all events exist")
+            };
+
+            let mut pdu = templates.remove(&id).expect("every event is sorted once");
+            let state_key = pdu.state_key.clone().expect("test events are state events");
+            pdu.prev_events = prev_events.iter().cloned().collect();
+            pdu.auth_events = state_res::auth_types_for_event(
+                &pdu.kind,
+                &pdu.sender,
+                Some(&state_key),
+                &pdu.content,
+            )
+            .expect("test events have valid content")
+            .into_iter()
+            .filter_map(|key| state_before.get(&key).cloned())
+            .collect();
+
+            let mut state = state_before;
+            state.insert((pdu.kind.to_string().into(), state_key), id.clone());
+            state_after.insert(id.clone(), state);
+            events.insert(id, Arc::new(pdu));
+        }
+
+        let start_state = &state_after[&event_id("START")];
+        let expected = expected
+            .iter()
+            .map(|id| {
+                let pdu = &events[&event_id(id)];
+                (
+                    (
+                        pdu.kind.to_string().into(),
+                        pdu.state_key.clone().unwrap_or_default(),
+                    ),
+                    pdu.event_id.clone(),
+                )
+            })
+            .collect::<StateMap<_>>();
+
+        let end_state = state_after[&event_id("END")]
+            .iter()
+            .filter(|(key, id)| {
+                key.0.to_string() != "m.room.message"
+                    && (expected.contains_key(*key) || start_state.get(*key) != Some(*id))
+            })
+            .map(|(key, id)| (key.clone(), id.clone()))
+            .collect::<StateMap<_>>();
+
+        assert_eq!(end_state, expected);
+    }
+
+    #[test]
+    fn ban_vs_power_level() {
+        check(
+            vec![
+                (
+                    "PA",
+                    ALICE,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, BOB: 50 } }),
+                ),
+                (
+                    "MA",
+                    ALICE,
+                    "m.room.member",
+                    ALICE,
+                    json!({ "membership": "join" }),
+                ),
+                (
+                    "MB",
+                    ALICE,
+                    "m.room.member",
+                    BOB,
+                    json!({ "membership": "ban" }),
+                ),
+                (
+                    "PB",
+                    BOB,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, BOB: 50 } }),
+                ),
+            ],
+            &[&["END", "MB", "MA", "PA", "START"], &["END", "PB", "PA"]],
+            &["PA", "MA", "MB"],
+        );
+    }
+
+    #[test]
+    fn topic_basic() {
+        check(
+            vec![
+                ("T1", ALICE, "m.room.topic", "", json!({ "topic": "1" })),
+                (
+                    "PA1",
+                    ALICE,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, BOB: 50 } }),
+                ),
+                ("T2", ALICE, "m.room.topic", "", json!({ "topic": "2" })),
+                (
+                    "PA2",
+                    ALICE,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, BOB: 0 } }),
+                ),
+                (
+                    "PB",
+                    BOB,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, BOB: 50 } }),
+                ),
+                ("T3", BOB, "m.room.topic", "", json!({ "topic": "3" })),
+            ],
+            &[
+                &["END", "PA2", "T2", "PA1", "T1", "START"],
+                &["END", "T3", "PB", "PA1"],
+            ],
+            &["PA2", "T2"],
+        );
+    }
+
+    #[test]
+    fn topic_reset() {
+        check(
+            vec![
+                ("T1", ALICE, "m.room.topic", "", json!({ "topic": "1" })),
+                (
+                    "PA",
+                    ALICE,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, BOB: 50 } }),
+                ),
+                ("T2", BOB, "m.room.topic", "", json!({ "topic": "2" })),
+                (
+                    "MB",
+                    ALICE,
+                    "m.room.member",
+                    BOB,
+                    json!({ "membership": "ban" }),
+                ),
+            ],
+            &[&["END", "MB", "T2", "PA", "T1", "START"], &["END", "T1"]],
+            &["T1", "MB", "PA"],
+        );
+    }
+
+    #[test]
+    fn join_rule_evasion() {
+        check(
+            vec![
+                (
+                    "JR",
+                    ALICE,
+                    "m.room.join_rules",
+                    "",
+                    json!({ "join_rule": "private" }),
+                ),
+                (
+                    "ME",
+                    ELLA,
+                    "m.room.member",
+                    ELLA,
+                    json!({ "membership": "join" }),
+                ),
+            ],
+            &[&["END", "JR", "START"], &["END", "ME", "START"]],
+            &["JR"],
+        );
+    }
+
+    #[test]
+    fn offtopic_power_level() {
+        check(
+            vec![
+                (
+                    "PA",
+                    ALICE,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, BOB: 50 } }),
+                ),
+                (
+                    "PB",
+                    BOB,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 50 } }),
+                ),
+                (
+                    "PC",
+                    CHARLIE,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 0 } }),
+                ),
+            ],
+            &[&["END", "PC", "PB", "PA", "START"], &["END", "PA"]],
+            &["PC"],
+        );
+    }
+
+    #[test]
+    fn unban_vs_demotion() {
+        // Charlie unbans Bob while Alice takes away Charlie's power to do so
+        check(
+            vec![
+                (
+                    "PA",
+                    ALICE,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, CHARLIE: 50 } }),
+                ),
+                (
+                    "MB",
+                    ALICE,
+                    "m.room.member",
+                    BOB,
+                    json!({ "membership": "ban" }),
+                ),
+                (
+                    "MU",
+                    CHARLIE,
+                    "m.room.member",
+                    BOB,
+                    json!({ "membership": "leave" }),
+                ),
+                (
+                    "PA2",
+                    ALICE,
+                    "m.room.power_levels",
+                    "",
+                    json!({ "users": { ALICE: 100, CHARLIE: 0 } }),
+                ),
+            ],
+            &[&["END", "MU", "MB", "PA", "START"], &["END", "PA2", "MB"]],
+            &["PA2", "MB"],
+        );
+    }
+
+    /// Bob may change the power levels, but not raise the level needed for `@room` notifications
+    /// above his own. Room versions before 6 don't check this.
+    fn notifications_events() -> Vec<TestEvent> {
+        vec![
+            (
+                "PA",
+                ALICE,
+                "m.room.power_levels",
+                "",
+                json!({ "users": { ALICE: 100, BOB: 50 } }),
+            ),
+            (
+                "PB",
+                BOB,
+                "m.room.power_levels",
+                "",
+                json!({ "users": { ALICE: 100, BOB: 50 }, "notifications": { "room": 100 } }),
+            ),
+        ]
+    }
+
+    #[test]
+    fn notifications_power_level_since_v6() {
+        let edges: &[&[&str]] = &[&["END", "PB", "PA", "START"], &["END", "PA"]];
+
+        check_version(&RoomVersionId::V5, notifications_events(), edges, &["PB"]);
+        check_version(&RoomVersionId::V6, notifications_events(), edges, &["PA"]);
+    }
+
+    /// Bob sends power levels with a string where an integer belongs, which room versions before
+    /// 10 accept.
+    fn string_power_level_events() -> Vec<TestEvent> {
+        vec![
+            (
+                "PA",
+                ALICE,
+                "m.room.power_levels",
+                "",
+                json!({ "users": { ALICE: 100, BOB: 50 } }),
+            ),
+            (
+                "PB",
+                BOB,
+                "m.room.power_levels",
+                "",
+                json!({ "users": { ALICE: 100, BOB: "50" } }),
+            ),
+        ]
+    }
+
+    #[test]
+    fn integer_power_levels_since_v10() {
+        let edges: &[&[&str]] = &[&["END", "PB", "PA", "START"], &["END", "PA"]];
+
+        check_version(
+            &RoomVersionId::V9,
+            string_power_level_events(),
+            edges,
+            &["PB"],
+        );
+        check_version(
+            &RoomVersionId::V10,
+            string_power_level_events(),
+            edges,
+            &["PA"],
+        );
+        check_version(
+            &RoomVersionId::V11,
+            string_power_level_events(),
+            edges,
+            &["PA"],
+        );
+    }
+
+    #[test]
+    fn create_without_creator_in_v11() {
+        // The create event has no creator field, the same conflicts resolve the same way
+        check_version(
+            &RoomVersionId::V11,
+            vec![
+                (
+                    "JR",
+                    ALICE,
+                    "m.room.join_rules",
+                    "",
+                    json!({ "join_rule": "private" }),
+                ),
+                (
+                    "ME",
+                    ELLA,
+                    "m.room.member",
+                    ELLA,
+                    json!({ "membership": "join" }),
+                ),
+            ],
+            &[&["END", "JR", "START"], &["END", "ME", "START"]],
+            &["JR"],
+        );
+    }
+}
@@ -6,4 +6,8 @@ pub trait Data: Send + Sync {
     fn iter_ids<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
     fn is_disabled(&self, room_id: &RoomId) -> Result<bool>;
     fn disable_room(&self, room_id: &RoomId, disabled: bool) -> Result<()>;
+    fn is_blocked(&self, room_id: &RoomId) -> Result<bool>;
+    fn block_room(&self, room_id: &RoomId, blocked: bool) -> Result<()>;
+    /// Removes everything stored about the room and returns how many bytes that freed.
+    fn purge_room(&self, room_id: &RoomId) -> Result<u64>;
 }
@@ -1,9 +1,11 @@
 mod data;
 
+use std::sync::Arc;
+
 pub use data::Data;
 use ruma::{OwnedRoomId, RoomId};
 
-use crate::Result;
+use crate::{services, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -27,4 +29,70 @@ impl Service {
     pub fn disable_room(&self, room_id: &RoomId, disabled: bool) -> Result<()> {
         self.db.disable_room(room_id, disabled)
     }
+
+    /// Checks if nobody on this server may join or be invited to the room.
+    pub fn is_blocked(&self, room_id: &RoomId) -> Result<bool> {
+        self.db.is_blocked(room_id)
+    }
+
+    pub fn block_room(&self, room_id: &RoomId, blocked: bool) -> Result<()> {
+        self.db.block_room(room_id, blocked)
+    }
+
+    /// Removes the room from the database, as if this server was never in it. Returns how many
+    /// bytes were freed.
+    ///
+    /// Local users should leave the room first, otherwise other servers still think we are in
+    /// it.
+    #[tracing::instrument(skip(self))]
+    pub async fn purge_room(&self, room_id: &RoomId) -> Result<u64> {
+        // Keeps incoming federation events and local events from re-creating parts of the room
+        let mutex_federation = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_federation
+                .write()
+                .unwrap()
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let _federation_lock = mutex_federation.lock().await;
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let _state_lock = mutex_state.lock().await;
+
+        let freed = self.db.purge_room(room_id)?;
+
+        services()
+            .rooms
+            .spaces
+            .roomid_spacechunk_cache
+            .lock()
+            .unwrap()
+            .remove(room_id);
+        services()
+            .rooms
+            .state_compressor
+            .stateinfo_cache
+            .lock()
+            .unwrap()
+            .clear();
+        services()
+            .rooms
+            .state_accessor
+            .server_acl_cache
+            .lock()
+            .unwrap()
+            .remove(room_id);
+
+        Ok(freed)
+    }
 }
@@ -5,6 +5,29 @@ use ruma::{EventId, RoomId, UserId};
 
 pub trait Data: Send + Sync {
     fn add_relation(&self, from: u64, to: u64) -> Result<()>;
+    fn add_typed_relation(
+        &self,
+        shortroomid: u64,
+        target: u64,
+        rel_type: &str,
+        from: u64,
+    ) -> Result<()>;
+    /// Returns the relation type and count of the events relating to `target` with `rel_type`,
+    /// or with any type, in chronological order.
+    fn typed_relations<'a>(
+        &'a self,
+        shortroomid: u64,
+        target: u64,
+        rel_type: Option<&str>,
+    ) -> Box<dyn Iterator<Item = Result<(String, u64)>> + 'a>;
+    /// Removes the relations to the pdu with `count` and from it to the `targets`, which are the
+    /// counts and relation types of the events it relates to.
+    fn remove_relations(
+        &self,
+        shortroomid: u64,
+        count: u64,
+        targets: &[(u64, Option<String>)],
+    ) -> Result<()>;
     #[allow(clippy::type_complexity)]
     fn relations_until<'a>(
         &'a self,
@@ -1,13 +1,14 @@
 mod data;
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 pub use data::Data;
 use ruma::{
     api::client::relations::get_relating_events,
     events::{relation::RelationType, TimelineEventType},
-    EventId, RoomId, UserId,
+    EventId, OwnedEventId, RoomId, UserId,
 };
 use serde::Deserialize;
+use serde_json::json;
 
 use crate::{services, PduEvent, Result};
 
@@ -168,6 +169,231 @@ impl Service {
         self.db.relations_until(user_id, room_id, target, until)
     }
 
+    /// Indexes `from` as relating to `target` with the given `rel_type`, for bundled
+    /// aggregations.
+    #[tracing::instrument(skip(self))]
+    pub fn add_typed_relation(
+        &self,
+        room_id: &RoomId,
+        from: PduCount,
+        target: PduCount,
+        rel_type: &str,
+    ) -> Result<()> {
+        match (from, target) {
+            (PduCount::Normal(f), PduCount::Normal(t)) => {
+                let shortroomid = services().rooms.short.get_or_create_shortroomid(room_id)?;
+                self.db.add_typed_relation(shortroomid, t, rel_type, f)
+            }
+            // TODO: Relations with backfilled pdus
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the relations from and to a pdu that is about to leave the timeline.
+    #[tracing::instrument(skip(self, pdu))]
+    pub fn remove_relations(
+        &self,
+        shortroomid: u64,
+        count: PduCount,
+        pdu: &PduEvent,
+    ) -> Result<()> {
+        #[derive(Deserialize)]
+        struct ExtractInReplyTo {
+            event_id: OwnedEventId,
+        }
+        #[derive(Deserialize)]
+        struct ExtractRelation {
+            event_id: Option<OwnedEventId>,
+            rel_type: Option<String>,
+            #[serde(rename = "m.in_reply_to")]
+            in_reply_to: Option<ExtractInReplyTo>,
+        }
+        #[derive(Deserialize)]
+        struct ExtractRelatesTo {
+            #[serde(rename = "m.relates_to")]
+            relates_to: ExtractRelation,
+        }
+
+        // Relations with backfilled pdus are never indexed
+        let PduCount::Normal(count) = count else {
+            return Ok(());
+        };
+
+        let mut targets = Vec::new();
+        if let Ok(content) = serde_json::from_str::<ExtractRelatesTo>(pdu.content.get()) {
+            let relation = content.relates_to;
+            if let Some(event_id) = relation.event_id {
+                targets.push((event_id, relation.rel_type));
+            }
+            if let Some(in_reply_to) = relation.in_reply_to {
+                targets.push((in_reply_to.event_id, None));
+            }
+        }
+
+        let mut target_counts = Vec::new();
+        for (event_id, rel_type) in targets {
+            if let Some(PduCount::Normal(target)) =
+                services().rooms.timeline.get_pdu_count(&event_id)?
+            {
+                target_counts.push((target, rel_type));
+            }
+        }
+
+        self.db.remove_relations(shortroomid, count, &target_counts)
+    }
+
+    /// Returns the events relating to `target` with the given relation type, or with any type, in
+    /// chronological order.
+    ///
+    /// Redacted relations and relations the user can't see are left out.
+    pub fn typed_relations(
+        &self,
+        user_id: &UserId,
+        target: &PduEvent,
+        rel_type: Option<&str>,
+    ) -> Result<Vec<(String, PduEvent)>> {
+        let (shortroomid, target) = match (
+            services().rooms.short.get_shortroomid(&target.room_id)?,
+            services().rooms.timeline.get_pdu_count(&target.event_id)?,
+        ) {
+            (Some(shortroomid), Some(PduCount::Normal(target))) => (shortroomid, target),
+            // TODO: Relations with backfilled pdus
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut relations = Vec::new();
+        for relation in self.db.typed_relations(shortroomid, target, rel_type) {
+            let (rel_type, from) = relation?;
+
+            let mut pdu_id = shortroomid.to_be_bytes().to_vec();
+            pdu_id.extend_from_slice(&from.to_be_bytes());
+
+            let mut pdu = match services().rooms.timeline.get_pdu_from_id(&pdu_id)? {
+                Some(pdu) if !pdu.is_redacted() => pdu,
+                _ => continue,
+            };
+            if !services().rooms.state_accessor.user_can_see_event(
+                user_id,
+                &pdu.room_id,
+                &pdu.event_id,
+            )? {
+                continue;
+            }
+            if pdu.sender != user_id {
+                pdu.remove_transaction_id()?;
+            }
+
+            relations.push((rel_type, pdu));
+        }
+
+        Ok(relations)
+    }
+
+    /// Computes the bundled aggregations of an event, which go into `unsigned.m.relations`.
+    ///
+    /// - m.annotation: The number of annotations per event type and key
+    /// - m.replace: The latest edit by the original sender
+    /// - m.reference: The ids of the referencing events
+    /// - m.thread: The thread summary, see `threads::Service::thread_summary`
+    ///
+    /// Redacted relations and relations the user can't see are not aggregated.
+    #[tracing::instrument(skip(self, pdu))]
+    pub fn bundled_aggregations(
+        &self,
+        user_id: &UserId,
+        pdu: &PduEvent,
+    ) -> Result<serde_json::Map<String, serde_json::Value>> {
+        #[derive(Deserialize)]
+        struct ExtractAnnotationKey {
+            key: String,
+        }
+        #[derive(Deserialize)]
+        struct ExtractAnnotation {
+            #[serde(rename = "m.relates_to")]
+            relates_to: ExtractAnnotationKey,
+        }
+
+        let mut bundled = serde_json::Map::new();
+
+        // Redacted events don't have relations anymore
+        if pdu.is_redacted() {
+            return Ok(bundled);
+        }
+
+        let mut annotations = BTreeMap::<(String, String), u64>::new();
+        for (_, relation) in self.typed_relations(user_id, pdu, Some("m.annotation"))? {
+            if let Ok(content) = serde_json::from_str::<ExtractAnnotation>(relation.content.get()) {
+                *annotations
+                    .entry((relation.kind.to_string(), content.relates_to.key))
+                    .or_default() += 1;
+            }
+        }
+
+        // Only the original sender may edit
+        let mut latest_edit: Option<PduEvent> = None;
+        for (_, relation) in self.typed_relations(user_id, pdu, Some("m.replace"))? {
+            if relation.sender == pdu.sender
+                && relation.state_key.is_none()
+                && latest_edit.as_ref().map_or(true, |latest| {
+                    latest.origin_server_ts <= relation.origin_server_ts
+                })
+            {
+                latest_edit = Some(relation);
+            }
+        }
+
+        let references: Vec<_> = self
+            .typed_relations(user_id, pdu, Some("m.reference"))?
+            .into_iter()
+            .map(|(_, relation)| relation.event_id)
+            .collect();
+
+        if !annotations.is_empty() {
+            let mut chunk: Vec<_> = annotations.into_iter().collect();
+            chunk.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+            let chunk: Vec<_> = chunk
+                .into_iter()
+                .map(|((kind, key), count)| json!({ "type": kind, "key": key, "count": count }))
+                .collect();
+            bundled.insert("m.annotation".to_owned(), json!({ "chunk": chunk }));
+        }
+
+        if let Some(edit) = latest_edit {
+            bundled.insert(
+                "m.replace".to_owned(),
+                serde_json::to_value(edit.to_message_like_event()).expect("event is valid json"),
+            );
+        }
+
+        if !references.is_empty() {
+            let chunk: Vec<_> = references
+                .into_iter()
+                .map(|event_id| json!({ "event_id": event_id }))
+                .collect();
+            bundled.insert("m.reference".to_owned(), json!({ "chunk": chunk }));
+        }
+
+        if let Some(thread) = services().rooms.threads.thread_summary(user_id, pdu)? {
+            bundled.insert(
+                "m.thread".to_owned(),
+                serde_json::to_value(thread).expect("to_value always works"),
+            );
+        }
+
+        Ok(bundled)
+    }
+
+    /// Adds the bundled aggregations of the event to its unsigned field.
+    pub fn add_bundled_aggregations(&self, user_id: &UserId, pdu: &mut PduEvent) -> Result<()> {
+        let bundled = self.bundled_aggregations(user_id, pdu)?;
+        if bundled.is_empty() {
+            return Ok(());
+        }
+
+        pdu.add_relations(bundled)
+    }
+
     #[tracing::instrument(skip(self, room_id, event_ids))]
     pub fn mark_as_referenced(&self, room_id: &RoomId, event_ids: &[Arc<EventId>]) -> Result<()> {
         self.db.mark_as_referenced(room_id, event_ids)
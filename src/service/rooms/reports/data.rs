@@ -0,0 +1,17 @@
+use ruma::RoomId;
+
+use crate::Result;
+
+use super::Report;
+
+pub trait Data: Send + Sync {
+    /// Stores a new report about an event in the room.
+    fn add_report(&self, report: &Report) -> Result<()>;
+
+    /// Returns all reports, or only the ones about events in this room. They are sorted by room
+    /// and then from oldest to newest.
+    fn reports<'a>(
+        &'a self,
+        room_id: Option<&RoomId>,
+    ) -> Box<dyn Iterator<Item = Result<Report>> + 'a>;
+}
@@ -0,0 +1,37 @@
+mod data;
+
+pub use data::Data;
+use ruma::{Int, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+pub struct Service {
+    pub db: &'static dyn Data,
+}
+
+/// A report of a user about an event, for the admins to review.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Report {
+    pub reporter: OwnedUserId,
+    pub room_id: OwnedRoomId,
+    pub event_id: OwnedEventId,
+    pub reason: Option<String>,
+    /// From -100 (most offensive) to 0 (inoffensive)
+    pub score: Option<Int>,
+    /// When the event was reported in millis since the unix epoch
+    pub timestamp: u64,
+}
+
+impl Service {
+    pub fn add_report(&self, report: &Report) -> Result<()> {
+        self.db.add_report(report)
+    }
+
+    pub fn reports<'a>(
+        &'a self,
+        room_id: Option<&RoomId>,
+    ) -> impl Iterator<Item = Result<Report>> + 'a {
+        self.db.reports(room_id)
+    }
+}
@@ -9,12 +9,50 @@ pub struct Service {
     pub db: &'static dyn Data,
 }
 
+/// Words too common to be useful in a search, they are not indexed.
+const STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+/// Splits a message body into lowercased words, including stopwords.
+pub fn words(body: &str) -> impl Iterator<Item = String> + '_ {
+    body.split_terminator(|c: char| !c.is_alphanumeric())
+        .filter(|s| !s.is_empty())
+        .filter(|word| word.len() <= 50)
+        .map(str::to_lowercase)
+}
+
+/// Splits a message body into the words that are indexed and searched for.
+pub fn tokenize(body: &str) -> impl Iterator<Item = String> + '_ {
+    words(body).filter(|word| !STOPWORDS.contains(&word.as_str()))
+}
+
+/// How well a message body matches the search terms: the share of its words that are terms.
+pub fn rank(terms: &[String], body: &str) -> f64 {
+    let (matches, total) = words(body).fold((0_u32, 0_u32), |(matches, total), word| {
+        (matches + u32::from(terms.contains(&word)), total + 1)
+    });
+
+    if total == 0 {
+        0.0
+    } else {
+        f64::from(matches) / f64::from(total)
+    }
+}
+
 impl Service {
     #[tracing::instrument(skip(self))]
     pub fn index_pdu<'a>(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
         self.db.index_pdu(shortroomid, pdu_id, message_body)
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn deindex_pdu(&self, shortroomid: u64, pdu_id: &[u8], message_body: &str) -> Result<()> {
+        self.db.deindex_pdu(shortroomid, pdu_id, message_body)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn search_pdus<'a>(
         &'a self,
@@ -24,3 +62,24 @@ impl Service {
         self.db.search_pdus(room_id, search_string)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{rank, tokenize};
+
+    #[test]
+    fn tokenize_skips_stopwords_and_lowercases() {
+        assert_eq!(
+            tokenize("The Server is DOWN for maintenance, again!").collect::<Vec<_>>(),
+            ["server", "down", "maintenance", "again"]
+        );
+    }
+
+    #[test]
+    fn rank_prefers_short_matches() {
+        let terms = vec!["conduit".to_owned()];
+        assert!(rank(&terms, "conduit") > rank(&terms, "I run conduit at home"));
+        assert_eq!(rank(&terms, "nothing here"), 0.0);
+        assert_eq!(rank(&terms, ""), 0.0);
+    }
+}
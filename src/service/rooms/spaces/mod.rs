@@ -1,4 +1,7 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
 use lru_cache::LruCache;
 use ruma::{
@@ -7,7 +10,7 @@ use ruma::{
             error::ErrorKind,
             space::{get_hierarchy, SpaceHierarchyRoomsChunk},
         },
-        federation,
+        federation::{self, space::SpaceHierarchyChildSummary},
     },
     events::{
         room::{
@@ -19,17 +22,18 @@ use ruma::{
             join_rules::{self, AllowRule, JoinRule, RoomJoinRulesEventContent},
             topic::RoomTopicEventContent,
         },
-        space::child::SpaceChildEventContent,
+        space::child::{HierarchySpaceChildEvent, SpaceChildEventContent},
         StateEventType,
     },
     space::SpaceRoomJoinRule,
-    OwnedRoomId, RoomId, UserId,
+    OwnedRoomId, OwnedServerName, RoomId, UserId,
 };
 
 use tracing::{debug, error, warn};
 
 use crate::{services, Error, PduEvent, Result};
 
+#[derive(Clone)]
 pub enum CachedJoinRule {
     //Simplified(SpaceRoomJoinRule),
     Full(JoinRule),
@@ -37,15 +41,25 @@ pub enum CachedJoinRule {
 
 pub struct CachedSpaceChunk {
     chunk: SpaceHierarchyRoomsChunk,
-    children: Vec<OwnedRoomId>,
     join_rule: CachedJoinRule,
 }
 
+struct SpaceStackEntry {
+    room_id: OwnedRoomId,
+    depth: usize,
+    via: Vec<OwnedServerName>,
+}
+
 pub struct Service {
     pub roomid_spacechunk_cache: Mutex<LruCache<OwnedRoomId, Option<CachedSpaceChunk>>>,
 }
 
 impl Service {
+    /// Walks the space tree below `room_id` depth first and returns the rooms the user may see.
+    ///
+    /// Rooms reachable through several parents are only returned once, which also stops the walk
+    /// at cycles. Rooms this server is not in are requested over federation, from the servers in
+    /// the `via` of the child event.
     pub async fn get_hierarchy(
         &self,
         sender_user: &UserId,
@@ -57,251 +71,348 @@ impl Service {
     ) -> Result<get_hierarchy::v1::Response> {
         let mut left_to_skip = skip;
 
-        let mut rooms_in_path = Vec::new();
-        let mut stack = vec![vec![room_id.to_owned()]];
+        let mut seen = HashSet::new();
+        // Summaries of children returned by remote servers, used when we can't ask for the
+        // child itself
+        let mut remote_summaries = HashMap::new();
+        let mut stack = vec![SpaceStackEntry {
+            room_id: room_id.to_owned(),
+            depth: 1,
+            via: Vec::new(),
+        }];
         let mut results = Vec::new();
 
-        while let Some(current_room) = {
-            while stack.last().map_or(false, |s| s.is_empty()) {
-                stack.pop();
-            }
-            if !stack.is_empty() {
-                stack.last_mut().and_then(|s| s.pop())
-            } else {
-                None
+        while let Some(current) = stack.pop() {
+            if seen.contains(&current.room_id) {
+                continue;
             }
-        } {
-            rooms_in_path.push(current_room.clone());
             if results.len() >= limit {
+                stack.push(current);
                 break;
             }
 
-            if let Some(cached) = self
+            let cached = self
                 .roomid_spacechunk_cache
                 .lock()
                 .unwrap()
-                .get_mut(&current_room.to_owned())
-                .as_ref()
-            {
-                if let Some(cached) = cached {
-                    let allowed = match &cached.join_rule {
-                        //CachedJoinRule::Simplified(s) => {
-                        //self.handle_simplified_join_rule(s, sender_user, &current_room)?
-                        //}
-                        CachedJoinRule::Full(f) => {
-                            self.handle_join_rule(f, sender_user, &current_room)?
-                        }
-                    };
-                    if allowed {
-                        if left_to_skip > 0 {
-                            left_to_skip -= 1;
-                        } else {
-                            results.push(cached.chunk.clone());
-                        }
-                        if rooms_in_path.len() < max_depth {
-                            stack.push(cached.children.clone());
+                .get_mut(&current.room_id)
+                .map(|cached| {
+                    cached
+                        .as_ref()
+                        .map(|c| (c.chunk.clone(), c.join_rule.clone()))
+                });
+
+            let chunk = match cached {
+                Some(cached) => cached,
+                None => {
+                    if services()
+                        .rooms
+                        .state
+                        .get_room_shortstatehash(&current.room_id)?
+                        .is_some()
+                    {
+                        self.local_chunk(sender_user, &current.room_id).await?
+                    } else if current.room_id.server_name()
+                        == Some(services().globals.server_name())
+                    {
+                        None
+                    } else {
+                        if !results.is_empty() {
+                            // Early return so the client can see some data already
+                            stack.push(current);
+                            break;
                         }
+                        self.remote_chunk(&current, suggested_only, &mut remote_summaries)
+                            .await?
                     }
                 }
+            };
+
+            seen.insert(current.room_id.clone());
+
+            let Some((chunk, join_rule)) = chunk else {
                 continue;
+            };
+
+            let CachedJoinRule::Full(join_rule) = &join_rule;
+            if !self.handle_join_rule(join_rule, sender_user, &current.room_id)? {
+                continue;
+            }
+
+            if current.depth < max_depth {
+                // Reversed so the first child is visited first
+                for (child, via) in Self::children(&chunk, suggested_only).into_iter().rev() {
+                    if !seen.contains(&child) {
+                        stack.push(SpaceStackEntry {
+                            room_id: child,
+                            depth: current.depth + 1,
+                            via,
+                        });
+                    }
+                }
             }
 
-            if let Some(current_shortstatehash) = services()
+            if left_to_skip > 0 {
+                left_to_skip -= 1;
+            } else {
+                results.push(chunk);
+            }
+        }
+
+        let has_more = stack.iter().any(|entry| !seen.contains(&entry.room_id));
+
+        Ok(get_hierarchy::v1::Response {
+            next_batch: has_more.then(|| (skip + results.len()).to_string()),
+            rooms: results,
+        })
+    }
+
+    /// Builds the chunk of a room this server is in and caches it.
+    async fn local_chunk(
+        &self,
+        sender_user: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<(SpaceHierarchyRoomsChunk, CachedJoinRule)>> {
+        let shortstatehash = services()
+            .rooms
+            .state
+            .get_room_shortstatehash(room_id)?
+            .expect("checked by the caller");
+
+        let state = services()
+            .rooms
+            .state_accessor
+            .state_full_ids(shortstatehash)
+            .await?;
+
+        let mut children_pdus = Vec::new();
+        for (key, id) in state {
+            let (event_type, _) = services().rooms.short.get_statekey_from_short(key)?;
+            if event_type != StateEventType::SpaceChild {
+                continue;
+            }
+
+            let pdu = services()
                 .rooms
-                .state
-                .get_room_shortstatehash(&current_room)?
+                .timeline
+                .get_pdu(&id)?
+                .ok_or_else(|| Error::bad_database("Event in space state not found"))?;
+
+            // Children without via are removed
+            if serde_json::from_str::<SpaceChildEventContent>(pdu.content.get())
+                .ok()
+                .map(|c| c.via)
+                .map_or(true, |v| v.is_empty())
             {
-                let state = services()
-                    .rooms
-                    .state_accessor
-                    .state_full_ids(current_shortstatehash)
-                    .await?;
-
-                let mut children_ids = Vec::new();
-                let mut children_pdus = Vec::new();
-                for (key, id) in state {
-                    let (event_type, state_key) =
-                        services().rooms.short.get_statekey_from_short(key)?;
-                    if event_type != StateEventType::SpaceChild {
-                        continue;
-                    }
+                continue;
+            }
 
-                    let pdu = services()
-                        .rooms
-                        .timeline
-                        .get_pdu(&id)?
-                        .ok_or_else(|| Error::bad_database("Event in space state not found"))?;
-
-                    if serde_json::from_str::<SpaceChildEventContent>(pdu.content.get())
-                        .ok()
-                        .map(|c| c.via)
-                        .map_or(true, |v| v.is_empty())
-                    {
-                        continue;
-                    }
+            children_pdus.push(pdu);
+        }
 
-                    if let Ok(room_id) = OwnedRoomId::try_from(state_key) {
-                        children_ids.push(room_id);
-                        children_pdus.push(pdu);
-                    }
-                }
+        let chunk = match self.get_room_chunk(sender_user, room_id, children_pdus) {
+            Ok(chunk) => chunk,
+            // The user may not see the room, so it is not cached either
+            Err(_) => return Ok(None),
+        };
 
-                // TODO: Sort children
-                children_ids.reverse();
+        let join_rule = services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+            .map(|s| {
+                serde_json::from_str(s.content.get())
+                    .map(|c: RoomJoinRulesEventContent| c.join_rule)
+                    .map_err(|e| {
+                        error!("Invalid room join rule event in database: {}", e);
+                        Error::BadDatabase("Invalid room join rule event in database.")
+                    })
+            })
+            .transpose()?
+            .unwrap_or(JoinRule::Invite);
 
-                let chunk = self.get_room_chunk(sender_user, &current_room, children_pdus);
-                if let Ok(chunk) = chunk {
-                    if left_to_skip > 0 {
-                        left_to_skip -= 1;
-                    } else {
-                        results.push(chunk.clone());
-                    }
-                    let join_rule = services()
-                        .rooms
-                        .state_accessor
-                        .room_state_get(&current_room, &StateEventType::RoomJoinRules, "")?
-                        .map(|s| {
-                            serde_json::from_str(s.content.get())
-                                .map(|c: RoomJoinRulesEventContent| c.join_rule)
-                                .map_err(|e| {
-                                    error!("Invalid room join rule event in database: {}", e);
-                                    Error::BadDatabase("Invalid room join rule event in database.")
-                                })
-                        })
-                        .transpose()?
-                        .unwrap_or(JoinRule::Invite);
-
-                    self.roomid_spacechunk_cache.lock().unwrap().insert(
-                        current_room.clone(),
-                        Some(CachedSpaceChunk {
-                            chunk,
-                            children: children_ids.clone(),
-                            join_rule: CachedJoinRule::Full(join_rule),
-                        }),
-                    );
-                }
+        let join_rule = CachedJoinRule::Full(join_rule);
 
-                if rooms_in_path.len() < max_depth {
-                    stack.push(children_ids);
-                }
-            } else {
-                let server = current_room
-                    .server_name()
-                    .expect("Room IDs should always have a server name");
-                if server == services().globals.server_name() {
-                    continue;
-                }
-                if !results.is_empty() {
-                    // Early return so the client can see some data already
-                    break;
-                }
-                debug!("Asking {server} for /hierarchy");
-                if let Ok(response) = services()
-                    .sending
-                    .send_federation_request(
-                        server,
-                        federation::space::get_hierarchy::v1::Request {
-                            room_id: current_room.to_owned(),
-                            suggested_only,
-                        },
-                    )
-                    .await
-                {
-                    warn!("Got response from {server} for /hierarchy\n{response:?}");
-                    let chunk = SpaceHierarchyRoomsChunk {
-                        canonical_alias: response.room.canonical_alias,
-                        name: response.room.name,
-                        num_joined_members: response.room.num_joined_members,
-                        room_id: response.room.room_id,
-                        topic: response.room.topic,
-                        world_readable: response.room.world_readable,
-                        guest_can_join: response.room.guest_can_join,
-                        avatar_url: response.room.avatar_url,
-                        join_rule: response.room.join_rule.clone(),
-                        room_type: response.room.room_type,
-                        children_state: response.room.children_state,
-                    };
-                    let children = response
-                        .children
-                        .iter()
-                        .map(|c| c.room_id.clone())
-                        .collect::<Vec<_>>();
-
-                    let join_rule = match response.room.join_rule {
-                        SpaceRoomJoinRule::Invite => JoinRule::Invite,
-                        SpaceRoomJoinRule::Knock => JoinRule::Knock,
-                        SpaceRoomJoinRule::Private => JoinRule::Private,
-                        SpaceRoomJoinRule::Restricted => {
-                            JoinRule::Restricted(join_rules::Restricted {
-                                allow: response
-                                    .room
-                                    .allowed_room_ids
-                                    .into_iter()
-                                    .map(AllowRule::room_membership)
-                                    .collect(),
-                            })
-                        }
-                        SpaceRoomJoinRule::KnockRestricted => {
-                            JoinRule::KnockRestricted(join_rules::Restricted {
-                                allow: response
-                                    .room
-                                    .allowed_room_ids
-                                    .into_iter()
-                                    .map(AllowRule::room_membership)
-                                    .collect(),
-                            })
-                        }
-                        SpaceRoomJoinRule::Public => JoinRule::Public,
-                        _ => return Err(Error::BadServerResponse("Unknown join rule")),
-                    };
-                    if self.handle_join_rule(&join_rule, sender_user, &current_room)? {
-                        if left_to_skip > 0 {
-                            left_to_skip -= 1;
-                        } else {
-                            results.push(chunk.clone());
-                        }
-                        if rooms_in_path.len() < max_depth {
-                            stack.push(children.clone());
-                        }
-                    }
+        self.roomid_spacechunk_cache.lock().unwrap().insert(
+            room_id.to_owned(),
+            Some(CachedSpaceChunk {
+                chunk: chunk.clone(),
+                join_rule: join_rule.clone(),
+            }),
+        );
 
-                    self.roomid_spacechunk_cache.lock().unwrap().insert(
-                        current_room.clone(),
-                        Some(CachedSpaceChunk {
-                            chunk,
-                            children,
-                            join_rule: CachedJoinRule::Full(join_rule),
-                        }),
-                    );
+        Ok(Some((chunk, join_rule)))
+    }
 
-                    /* TODO:
-                    for child in response.children {
-                        roomid_spacechunk_cache.insert(
-                            current_room.clone(),
-                            CachedSpaceChunk {
-                                chunk: child.chunk,
-                                children,
-                                join_rule,
-                            },
-                        );
-                    }
-                    */
-                } else {
-                    self.roomid_spacechunk_cache
-                        .lock()
-                        .unwrap()
-                        .insert(current_room.clone(), None);
+    /// Asks the servers in `via`, and then the server of the room id, for the chunk of a room
+    /// this server is not in.
+    ///
+    /// The children in the response are remembered, so they can still be returned if their own
+    /// servers can't be reached.
+    async fn remote_chunk(
+        &self,
+        entry: &SpaceStackEntry,
+        suggested_only: bool,
+        remote_summaries: &mut HashMap<OwnedRoomId, SpaceHierarchyChildSummary>,
+    ) -> Result<Option<(SpaceHierarchyRoomsChunk, CachedJoinRule)>> {
+        let mut servers = entry.via.clone();
+        if let Some(server) = entry.room_id.server_name() {
+            if !servers.iter().any(|s| s.as_str() == server.as_str()) {
+                servers.push(server.to_owned());
+            }
+        }
+
+        for server in servers
+            .iter()
+            .filter(|server| server.as_str() != services().globals.server_name().as_str())
+        {
+            debug!("Asking {server} for /hierarchy");
+            let response = match services()
+                .sending
+                .send_federation_request(
+                    server,
+                    federation::space::get_hierarchy::v1::Request {
+                        room_id: entry.room_id.clone(),
+                        suggested_only,
+                    },
+                )
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!(
+                        "Could not get /hierarchy of {} from {server}: {e}",
+                        entry.room_id
+                    );
+                    continue;
                 }
+            };
+
+            let chunk = SpaceHierarchyRoomsChunk {
+                canonical_alias: response.room.canonical_alias,
+                name: response.room.name,
+                num_joined_members: response.room.num_joined_members,
+                room_id: response.room.room_id,
+                topic: response.room.topic,
+                world_readable: response.room.world_readable,
+                guest_can_join: response.room.guest_can_join,
+                avatar_url: response.room.avatar_url,
+                join_rule: response.room.join_rule.clone(),
+                room_type: response.room.room_type,
+                children_state: response.room.children_state,
+            };
+
+            let join_rule = CachedJoinRule::Full(Self::summary_join_rule(
+                &response.room.join_rule,
+                response.room.allowed_room_ids,
+            )?);
+
+            for child in response.children {
+                remote_summaries.insert(child.room_id.clone(), child);
             }
+
+            self.roomid_spacechunk_cache.lock().unwrap().insert(
+                entry.room_id.clone(),
+                Some(CachedSpaceChunk {
+                    chunk: chunk.clone(),
+                    join_rule: join_rule.clone(),
+                }),
+            );
+
+            return Ok(Some((chunk, join_rule)));
         }
 
-        Ok(get_hierarchy::v1::Response {
-            next_batch: if results.is_empty() {
-                None
-            } else {
-                Some((skip + results.len()).to_string())
-            },
-            rooms: results,
+        if let Some(summary) = remote_summaries.remove(&entry.room_id) {
+            // We don't know the children of this room, so the walk stops here
+            let join_rule = CachedJoinRule::Full(Self::summary_join_rule(
+                &summary.join_rule,
+                summary.allowed_room_ids,
+            )?);
+
+            let chunk = SpaceHierarchyRoomsChunk {
+                canonical_alias: summary.canonical_alias,
+                name: summary.name,
+                num_joined_members: summary.num_joined_members,
+                room_id: summary.room_id,
+                topic: summary.topic,
+                world_readable: summary.world_readable,
+                guest_can_join: summary.guest_can_join,
+                avatar_url: summary.avatar_url,
+                join_rule: summary.join_rule,
+                room_type: summary.room_type,
+                children_state: Vec::new(),
+            };
+
+            return Ok(Some((chunk, join_rule)));
+        }
+
+        self.roomid_spacechunk_cache
+            .lock()
+            .unwrap()
+            .insert(entry.room_id.clone(), None);
+
+        Ok(None)
+    }
+
+    /// Returns the children of a space chunk and their via servers, in the order of the spec:
+    /// by `order`, then by timestamp and then by room id.
+    fn children(
+        chunk: &SpaceHierarchyRoomsChunk,
+        suggested_only: bool,
+    ) -> Vec<(OwnedRoomId, Vec<OwnedServerName>)> {
+        let mut children: Vec<_> = chunk
+            .children_state
+            .iter()
+            .filter_map(|raw| raw.deserialize().ok())
+            .filter(|event: &HierarchySpaceChildEvent| {
+                !event.content.via.is_empty() && (!suggested_only || event.content.suggested)
+            })
+            .filter_map(|event| {
+                let room_id = OwnedRoomId::try_from(event.state_key).ok()?;
+                Some((
+                    event.content.order,
+                    event.origin_server_ts,
+                    room_id,
+                    event.content.via,
+                ))
+            })
+            .collect();
+
+        children.sort_by(|a, b| {
+            // Children with an order come first
+            (a.0.is_none(), &a.0, a.1, &a.2).cmp(&(b.0.is_none(), &b.0, b.1, &b.2))
+        });
+
+        children
+            .into_iter()
+            .map(|(_, _, room_id, via)| (room_id, via))
+            .collect()
+    }
+
+    fn summary_join_rule(
+        join_rule: &SpaceRoomJoinRule,
+        allowed_room_ids: Vec<OwnedRoomId>,
+    ) -> Result<JoinRule> {
+        Ok(match join_rule {
+            SpaceRoomJoinRule::Invite => JoinRule::Invite,
+            SpaceRoomJoinRule::Knock => JoinRule::Knock,
+            SpaceRoomJoinRule::Private => JoinRule::Private,
+            SpaceRoomJoinRule::Restricted => JoinRule::Restricted(join_rules::Restricted {
+                allow: allowed_room_ids
+                    .into_iter()
+                    .map(AllowRule::room_membership)
+                    .collect(),
+            }),
+            SpaceRoomJoinRule::KnockRestricted => {
+                JoinRule::KnockRestricted(join_rules::Restricted {
+                    allow: allowed_room_ids
+                        .into_iter()
+                        .map(AllowRule::room_membership)
+                        .collect(),
+                })
+            }
+            SpaceRoomJoinRule::Public => JoinRule::Public,
+            _ => return Err(Error::BadServerResponse("Unknown join rule")),
         })
     }
 
@@ -80,14 +80,10 @@ impl Service {
                         Err(_) => continue,
                     };
 
-                    services().rooms.state_cache.update_membership(
-                        room_id,
-                        &user_id,
-                        membership,
-                        &pdu.sender,
-                        None,
-                        false,
-                    )?;
+                    services()
+                        .rooms
+                        .state_cache
+                        .update_membership(room_id, &user_id, membership, None, false)?;
                 }
                 TimelineEventType::SpaceChild => {
                     services()
@@ -104,8 +100,7 @@ impl Service {
 
         services().rooms.state_cache.update_joined_count(room_id)?;
 
-        self.db
-            .set_room_state(room_id, shortstatehash, state_lock)?;
+        self.set_room_state(room_id, shortstatehash, state_lock)?;
 
         Ok(())
     }
@@ -320,7 +315,18 @@ impl Service {
         shortstatehash: u64,
         mutex_lock: &MutexGuard<'_, ()>, // Take mutex guard to make sure users get the room state mutex
     ) -> Result<()> {
-        self.db.set_room_state(room_id, shortstatehash, mutex_lock)
+        self.db
+            .set_room_state(room_id, shortstatehash, mutex_lock)?;
+
+        services()
+            .rooms
+            .state_accessor
+            .server_acl_cache
+            .lock()
+            .unwrap()
+            .remove(room_id);
+
+        Ok(())
     }
 
     /// Returns the room's version.
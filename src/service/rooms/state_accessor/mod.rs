@@ -1,31 +1,92 @@
 mod data;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
 };
 
 pub use data::Data;
 use lru_cache::LruCache;
+use regex::RegexSet;
 use ruma::{
+    api::client::error::ErrorKind,
     events::{
         room::{
             avatar::RoomAvatarEventContent,
             history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
+            join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent},
             member::{MembershipState, RoomMemberEventContent},
             name::RoomNameEventContent,
+            power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
+            server_acl::RoomServerAclEventContent,
         },
-        StateEventType,
+        StateEventType, TimelineEventType,
     },
-    EventId, JsOption, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
+    DeviceId, EventId, JsOption, OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName,
+    UserId,
 };
-use tracing::error;
+use tracing::{error, warn};
 
-use crate::{services, Error, PduEvent, Result};
+use crate::{service::rooms::timeline::PduCount, services, Error, PduEvent, Result};
 
 pub struct Service {
     pub db: &'static dyn Data,
     pub server_visibility_cache: Mutex<LruCache<(OwnedServerName, u64), bool>>,
-    pub user_visibility_cache: Mutex<LruCache<(OwnedUserId, u64), bool>>,
+    pub user_visibility_cache:
+        Mutex<LruCache<(OwnedUserId, u64), (HistoryVisibility, MembershipState)>>,
+    pub server_acl_cache: Mutex<LruCache<OwnedRoomId, Option<Arc<ServerAcl>>>>,
+}
+
+/// The server ACL of a room, with the globs compiled once so checking all servers of a room
+/// stays cheap.
+pub struct ServerAcl {
+    content: RoomServerAclEventContent,
+    /// The deny and allow globs, `None` if they were too big to compile
+    compiled: Option<(RegexSet, RegexSet)>,
+}
+
+impl ServerAcl {
+    /// Returns `None` for ACLs without allowed servers, these are ignored.
+    fn new(content: RoomServerAclEventContent) -> Option<Self> {
+        if content.allow.is_empty() {
+            return None;
+        }
+
+        let compile =
+            |globs: &[String]| RegexSet::new(globs.iter().map(|glob| glob_to_regex(glob)));
+        let compiled = compile(&content.deny)
+            .and_then(|deny| Ok((deny, compile(&content.allow)?)))
+            .ok();
+
+        Some(Self { content, compiled })
+    }
+
+    pub fn is_allowed(&self, server_name: &ServerName) -> bool {
+        let Some((deny, allow)) = &self.compiled else {
+            return self.content.is_allowed(server_name);
+        };
+
+        if !self.content.allow_ip_literals && server_name.is_ip_literal() {
+            return false;
+        }
+
+        let host = server_name.host();
+        !deny.is_match(host) && allow.is_match(host)
+    }
+}
+
+/// `*` matches any number of characters and `?` exactly one, like in the spec.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+
+    regex
 }
 
 impl Service {
@@ -64,6 +125,18 @@ impl Service {
         self.db.state_get(shortstatehash, event_type, state_key)
     }
 
+    /// Get the history visibility in state, `shared` if none is set
+    fn history_visibility(&self, shortstatehash: u64) -> Result<HistoryVisibility> {
+        self.state_get(shortstatehash, &StateEventType::RoomHistoryVisibility, "")?
+            .map_or(Ok(HistoryVisibility::Shared), |s| {
+                serde_json::from_str(s.content.get())
+                    .map(|c: RoomHistoryVisibilityEventContent| c.history_visibility)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid history visibility event in database.")
+                    })
+            })
+    }
+
     /// Get membership for given user in state
     fn user_membership(&self, shortstatehash: u64, user_id: &UserId) -> Result<MembershipState> {
         self.state_get(
@@ -116,15 +189,7 @@ impl Service {
             return Ok(*visibility);
         }
 
-        let history_visibility = self
-            .state_get(shortstatehash, &StateEventType::RoomHistoryVisibility, "")?
-            .map_or(Ok(HistoryVisibility::Shared), |s| {
-                serde_json::from_str(s.content.get())
-                    .map(|c: RoomHistoryVisibilityEventContent| c.history_visibility)
-                    .map_err(|_| {
-                        Error::bad_database("Invalid history visibility event in database.")
-                    })
-            })?;
+        let history_visibility = self.history_visibility(shortstatehash)?;
 
         let mut current_server_members = services()
             .rooms
@@ -157,8 +222,10 @@ impl Service {
         Ok(visibility)
     }
 
-    /// Whether a user is allowed to see an event, based on
-    /// the room's history_visibility at that event's state.
+    /// Whether a user is allowed to see an event, based on the room's history_visibility and the
+    /// membership of the user at that event's state.
+    ///
+    /// Every client API that returns events has to use this.
     #[tracing::instrument(skip(self, user_id, room_id, event_id))]
     pub fn user_can_see_event(
         &self,
@@ -168,53 +235,97 @@ impl Service {
     ) -> Result<bool> {
         let shortstatehash = match self.pdu_shortstatehash(event_id)? {
             Some(shortstatehash) => shortstatehash,
-            None => return Ok(true),
+            // Only outliers have no state, and they are not part of the timeline
+            None => return Ok(false),
         };
 
-        if let Some(visibility) = self
+        // Both only depend on the state at the event, so they can be cached
+        let cached = self
             .user_visibility_cache
             .lock()
             .unwrap()
             .get_mut(&(user_id.to_owned(), shortstatehash))
-        {
-            return Ok(*visibility);
+            .cloned();
+        let (history_visibility, membership) = match cached {
+            Some(cached) => cached,
+            None => {
+                let history_visibility = self.history_visibility(shortstatehash)?;
+                let membership = self.user_membership(shortstatehash, user_id)?;
+
+                self.user_visibility_cache.lock().unwrap().insert(
+                    (user_id.to_owned(), shortstatehash),
+                    (history_visibility.clone(), membership.clone()),
+                );
+
+                (history_visibility, membership)
+            }
+        };
+
+        if history_visibility_allows(&history_visibility, &membership, false) {
+            return Ok(true);
         }
 
-        let currently_member = services().rooms.state_cache.is_joined(user_id, room_id)?;
+        // Users always see their own membership events, e.g. their join in a room with joined
+        // history visibility or the kick that removed them
+        if let Some(pdu) = services().rooms.timeline.get_pdu(event_id)? {
+            if pdu.kind == TimelineEventType::RoomMember
+                && pdu.state_key.as_deref() == Some(user_id.as_str())
+            {
+                return Ok(true);
+            }
+        }
 
-        let history_visibility = self
-            .state_get(shortstatehash, &StateEventType::RoomHistoryVisibility, "")?
-            .map_or(Ok(HistoryVisibility::Shared), |s| {
-                serde_json::from_str(s.content.get())
-                    .map(|c: RoomHistoryVisibilityEventContent| c.history_visibility)
-                    .map_err(|_| {
-                        Error::bad_database("Invalid history visibility event in database.")
-                    })
-            })?;
+        let joined_later = history_visibility == HistoryVisibility::Shared
+            && match services().rooms.timeline.get_pdu_count(event_id)? {
+                Some(count) => self.user_joined_after(user_id, room_id, count)?,
+                None => false,
+            };
+
+        Ok(history_visibility_allows(
+            &history_visibility,
+            &membership,
+            joined_later,
+        ))
+    }
 
-        let visibility = match history_visibility {
-            HistoryVisibility::WorldReadable => true,
-            HistoryVisibility::Shared => currently_member,
-            HistoryVisibility::Invited => {
-                // Allow if any member on requesting server was AT LEAST invited, else deny
-                self.user_was_invited(shortstatehash, user_id)
-            }
-            HistoryVisibility::Joined => {
-                // Allow if any member on requested server was joined, else deny
-                self.user_was_joined(shortstatehash, user_id)
+    /// Whether the user joined the room at some point after the event with the given count, even
+    /// if they left again since.
+    ///
+    /// Walks back through the membership events of the user, each found in the state before the
+    /// next one.
+    fn user_joined_after(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        count: PduCount,
+    ) -> Result<bool> {
+        let mut member =
+            self.room_state_get(room_id, &StateEventType::RoomMember, user_id.as_str())?;
+
+        while let Some(pdu) = member {
+            match services().rooms.timeline.get_pdu_count(&pdu.event_id)? {
+                Some(member_count) if member_count > count => {}
+                _ => return Ok(false),
             }
-            _ => {
-                error!("Unknown history visibility {history_visibility}");
-                false
+
+            let membership = serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+                .map_err(|_| Error::bad_database("Invalid room membership event in database."))?
+                .membership;
+            if membership == MembershipState::Join {
+                return Ok(true);
             }
-        };
 
-        self.user_visibility_cache
-            .lock()
-            .unwrap()
-            .insert((user_id.to_owned(), shortstatehash), visibility);
+            member = match self.pdu_shortstatehash(&pdu.event_id)? {
+                Some(shortstatehash) => self.state_get(
+                    shortstatehash,
+                    &StateEventType::RoomMember,
+                    user_id.as_str(),
+                )?,
+                None => None,
+            };
+        }
 
-        Ok(visibility)
+        Ok(false)
     }
 
     /// Whether a user is allowed to see an event, based on
@@ -241,6 +352,81 @@ impl Service {
         self.db.pdu_shortstatehash(event_id)
     }
 
+    /// Returns the state of the room at a position of the timeline, e.g. the count of a sync
+    /// token. This is the state before the first event after the position.
+    ///
+    /// The user has to be allowed to see that event, so users can't look at the room before
+    /// they could see it.
+    #[tracing::instrument(skip(self))]
+    pub fn shortstatehash_at(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        count: PduCount,
+    ) -> Result<Option<u64>> {
+        match services()
+            .rooms
+            .timeline
+            .pdus_after(user_id, room_id, count)?
+            .next()
+            .transpose()?
+        {
+            Some((_, pdu)) => {
+                if !self.user_can_see_event(user_id, room_id, &pdu.event_id)? {
+                    return Err(Error::BadRequest(
+                        ErrorKind::Forbidden,
+                        "You don't have permission to view the room at this point.",
+                    ));
+                }
+
+                self.pdu_shortstatehash(&pdu.event_id)
+            }
+            None => services().rooms.state.get_room_shortstatehash(room_id),
+        }
+    }
+
+    /// Returns the state of the room before the event, like the federation `/state` endpoint.
+    #[tracing::instrument(skip(self))]
+    pub fn state_at_event(&self, room_id: &RoomId, event_id: &EventId) -> Result<Option<u64>> {
+        match services().rooms.timeline.get_pdu(event_id)? {
+            Some(pdu) if &*pdu.room_id == room_id => self.pdu_shortstatehash(event_id),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the state of the room at an `at` parameter of a client, which is either an event
+    /// id or a sync token. The user has to be allowed to see the room at that point.
+    #[tracing::instrument(skip(self))]
+    pub fn shortstatehash_at_token(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        at: &str,
+    ) -> Result<u64> {
+        let shortstatehash = if at.starts_with('$') {
+            let event_id = EventId::parse(at)
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid event id."))?;
+            let shortstatehash = self.state_at_event(room_id, &event_id)?;
+
+            if shortstatehash.is_some() && !self.user_can_see_event(user_id, room_id, &event_id)? {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "You don't have permission to view the room at this point.",
+                ));
+            }
+
+            shortstatehash
+        } else {
+            let count = PduCount::try_from_string(at)?;
+            self.shortstatehash_at(user_id, room_id, count)?
+        };
+
+        shortstatehash.ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room state at this point not found.",
+        ))
+    }
+
     /// Returns the full room state.
     #[tracing::instrument(skip(self))]
     pub async fn room_state_full(
@@ -272,6 +458,73 @@ impl Service {
         self.db.room_state_get(room_id, event_type, state_key)
     }
 
+    /// Returns the current server ACL of the room, `None` if there is none or it is ignored.
+    #[tracing::instrument(skip(self))]
+    pub fn server_acl(&self, room_id: &RoomId) -> Result<Option<Arc<ServerAcl>>> {
+        // Keep the cache locked, so an ACL that changes meanwhile can't be cached after the
+        // state change removed the old one
+        let mut cache = self.server_acl_cache.lock().unwrap();
+        if let Some(acl) = cache.get_mut(room_id) {
+            return Ok(acl.clone());
+        }
+
+        let acl = match self.room_state_get(room_id, &StateEventType::RoomServerAcl, "")? {
+            Some(event) => match serde_json::from_str(event.content.get()) {
+                Ok(content) => ServerAcl::new(content).map(Arc::new),
+                Err(_) => {
+                    warn!("Invalid ACL event in {}", room_id);
+                    None
+                }
+            },
+            None => None,
+        };
+        cache.insert(room_id.to_owned(), acl.clone());
+
+        Ok(acl)
+    }
+
+    /// Returns the member events a client using lazy loading needs to display a batch of events:
+    /// the current member event of every sender that is not in `already_sent` and, unless
+    /// `include_redundant_members` is set, was not sent to the device before.
+    #[tracing::instrument(skip(self, events, already_sent))]
+    pub fn lazy_loading_members<'a>(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+        events: impl IntoIterator<Item = &'a PduEvent>,
+        already_sent: &HashSet<OwnedUserId>,
+        include_redundant_members: bool,
+    ) -> Result<Vec<(OwnedUserId, Arc<PduEvent>)>> {
+        let mut senders = HashSet::new();
+        let mut members = Vec::new();
+
+        for event in events {
+            if already_sent.contains(&event.sender) || !senders.insert(&event.sender) {
+                continue;
+            }
+
+            if !include_redundant_members
+                && services().rooms.lazy_loading.lazy_load_was_sent_before(
+                    user_id,
+                    device_id,
+                    room_id,
+                    &event.sender,
+                )?
+            {
+                continue;
+            }
+
+            if let Some(member_event) =
+                self.room_state_get(room_id, &StateEventType::RoomMember, event.sender.as_str())?
+            {
+                members.push((event.sender.clone(), member_event));
+            }
+        }
+
+        Ok(members)
+    }
+
     pub fn get_name(&self, room_id: &RoomId) -> Result<Option<String>> {
         services()
             .rooms
@@ -315,4 +568,184 @@ impl Service {
                     .map_err(|_| Error::bad_database("Invalid room member event in database."))
             })
     }
+
+    /// Returns the rooms whose members may join this room without an invite, or `None` if the
+    /// join rule of the room is not restricted.
+    pub fn join_restriction_rooms(&self, room_id: &RoomId) -> Result<Option<Vec<OwnedRoomId>>> {
+        let join_rule = self
+            .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+            .map(|s| {
+                serde_json::from_str(s.content.get())
+                    .map(|c: RoomJoinRulesEventContent| c.join_rule)
+                    .map_err(|_| Error::bad_database("Invalid join rules event in database."))
+            })
+            .transpose()?;
+
+        Ok(match join_rule {
+            Some(JoinRule::Restricted(restricted) | JoinRule::KnockRestricted(restricted)) => Some(
+                restricted
+                    .allow
+                    .into_iter()
+                    .filter_map(|rule| match rule {
+                        AllowRule::RoomMembership(membership) => Some(membership.room_id),
+                        _ => None,
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        })
+    }
+
+    /// Whether the power levels in the current state of the room allow the user to send the state
+    /// event.
+    pub fn user_can_send_state(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        event_type: StateEventType,
+    ) -> Result<bool> {
+        let power_levels: RoomPowerLevelsEventContent = self
+            .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+            .map(|s| {
+                serde_json::from_str(s.content.get())
+                    .map_err(|_| Error::bad_database("Invalid power levels event in database."))
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(RoomPowerLevels::from(power_levels).user_can_send_state(user_id, event_type))
+    }
+
+    /// Returns a local user in the room that may invite, to authorise a restricted join in its
+    /// name. The user with the highest power level is preferred.
+    pub fn restricted_join_authorizer(&self, room_id: &RoomId) -> Result<Option<OwnedUserId>> {
+        let power_levels: RoomPowerLevelsEventContent = self
+            .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+            .map(|s| {
+                serde_json::from_str(s.content.get())
+                    .map_err(|_| Error::bad_database("Invalid power levels event in database."))
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(services()
+            .rooms
+            .state_cache
+            .get_our_real_users(room_id)?
+            .iter()
+            .map(|user_id| {
+                let level = power_levels
+                    .users
+                    .get(user_id)
+                    .copied()
+                    .unwrap_or(power_levels.users_default);
+                (user_id, level)
+            })
+            .filter(|(_, level)| *level >= power_levels.invite)
+            .max_by_key(|(_, level)| *level)
+            .map(|(user_id, _)| user_id.to_owned()))
+    }
+}
+
+/// Whether the history visibility at an event allows a user to see it.
+///
+/// `membership` is the membership of the user at the event and `joined_later` whether the user
+/// joined the room at some point after the event.
+fn history_visibility_allows(
+    history_visibility: &HistoryVisibility,
+    membership: &MembershipState,
+    joined_later: bool,
+) -> bool {
+    match history_visibility {
+        HistoryVisibility::WorldReadable => true,
+        _ if membership == &MembershipState::Join => true,
+        HistoryVisibility::Shared => joined_later,
+        HistoryVisibility::Invited => membership == &MembershipState::Invite,
+        HistoryVisibility::Joined => false,
+        _ => {
+            error!("Unknown history visibility {history_visibility}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{
+        events::room::{
+            history_visibility::HistoryVisibility::{self, *},
+            member::MembershipState::{self, *},
+            server_acl::RoomServerAclEventContent,
+        },
+        ServerName,
+    };
+
+    use super::{history_visibility_allows, ServerAcl};
+
+    fn allows(
+        history_visibility: HistoryVisibility,
+        membership: MembershipState,
+        joined_later: bool,
+    ) -> bool {
+        history_visibility_allows(&history_visibility, &membership, joined_later)
+    }
+
+    #[test]
+    fn world_readable_is_visible_to_everyone() {
+        assert!(allows(WorldReadable, Leave, false));
+        assert!(allows(WorldReadable, Ban, false));
+        assert!(allows(WorldReadable, Join, true));
+    }
+
+    #[test]
+    fn shared_is_visible_to_current_and_former_members() {
+        // Joined later
+        assert!(allows(Shared, Leave, true));
+        assert!(allows(Shared, Invite, true));
+        // Left later
+        assert!(allows(Shared, Join, false));
+        // Never joined
+        assert!(!allows(Shared, Leave, false));
+        assert!(!allows(Shared, Invite, false));
+    }
+
+    #[test]
+    fn invited_is_visible_from_the_invite_on() {
+        assert!(allows(Invited, Invite, false));
+        assert!(allows(Invited, Join, false));
+        // Events before the invite stay hidden after joining
+        assert!(!allows(Invited, Leave, true));
+        assert!(!allows(Invited, Knock, true));
+        assert!(!allows(Invited, Ban, false));
+    }
+
+    #[test]
+    fn joined_is_visible_only_while_joined() {
+        assert!(allows(Joined, Join, false));
+        assert!(allows(Joined, Join, true));
+        // Being invited is not enough
+        assert!(!allows(Joined, Invite, true));
+        // Events before the join stay hidden after joining
+        assert!(!allows(Joined, Leave, true));
+    }
+
+    #[test]
+    fn server_acl_globs() {
+        let acl = ServerAcl::new(RoomServerAclEventContent::new(
+            false,
+            vec!["*.example.org".to_owned(), "matrix.org".to_owned()],
+            vec!["evil?.example.org".to_owned()],
+        ))
+        .unwrap();
+
+        let allowed = |server: &str| acl.is_allowed(&ServerName::parse(server).unwrap());
+        assert!(allowed("chat.example.org"));
+        assert!(allowed("chat.example.org:8448"));
+        assert!(allowed("matrix.org"));
+        assert!(!allowed("matrixxorg"));
+        assert!(!allowed("evil1.example.org"));
+        assert!(allowed("evil12.example.org"));
+        assert!(!allowed("example.com"));
+        assert!(!allowed("127.0.0.1"));
+    }
 }
@@ -6,7 +6,6 @@ pub use data::Data;
 use ruma::{
     events::{
         direct::DirectEvent,
-        ignored_user_list::IgnoredUserListEvent,
         room::{create::RoomCreateEventContent, member::MembershipState},
         AnyStrippedStateEvent, AnySyncStateEvent, GlobalAccountDataEventType,
         RoomAccountDataEventType, StateEventType,
@@ -16,7 +15,10 @@ use ruma::{
 };
 use tracing::warn;
 
-use crate::{services, Error, Result};
+use crate::{
+    service::{appservice::RegistrationInfo, users::DeviceListChange},
+    services, Error, Result,
+};
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -30,7 +32,6 @@ impl Service {
         room_id: &RoomId,
         user_id: &UserId,
         membership: MembershipState,
-        sender: &UserId,
         last_state: Option<Vec<Raw<AnyStrippedStateEvent>>>,
         update_joined_count: bool,
     ) -> Result<()> {
@@ -144,42 +145,38 @@ impl Service {
                     }
                 }
 
+                let was_joined = self.is_joined(user_id, room_id)?;
+
                 self.db.mark_as_joined(user_id, room_id)?;
+
+                if !was_joined {
+                    services().users.update_device_lists_for_membership(
+                        room_id,
+                        user_id,
+                        DeviceListChange::Changed,
+                    )?;
+                }
             }
             MembershipState::Invite => {
-                // We want to know if the sender is ignored by the receiver
-                let is_ignored = services()
-                    .account_data
-                    .get(
-                        None,    // Ignored users are in global account data
-                        user_id, // Receiver
-                        GlobalAccountDataEventType::IgnoredUserList
-                            .to_string()
-                            .into(),
-                    )?
-                    .map(|event| {
-                        serde_json::from_str::<IgnoredUserListEvent>(event.get()).map_err(|e| {
-                            warn!("Invalid account data event in db: {e:?}");
-                            Error::BadDatabase("Invalid account data event in db.")
-                        })
-                    })
-                    .transpose()?
-                    .map_or(false, |ignored| {
-                        ignored
-                            .content
-                            .ignored_users
-                            .iter()
-                            .any(|(user, _details)| user == sender)
-                    });
-
-                if is_ignored {
-                    return Ok(());
-                }
-
+                // Invites of ignored users are stored anyway and hidden when the user syncs,
+                // so they show up if the sender is unignored
                 self.db.mark_as_invited(user_id, room_id, last_state)?;
             }
+            MembershipState::Knock => {
+                self.db.mark_as_knocked(user_id, room_id, last_state)?;
+            }
             MembershipState::Leave | MembershipState::Ban => {
+                let was_joined = self.is_joined(user_id, room_id)?;
+
                 self.db.mark_as_left(user_id, room_id)?;
+
+                if was_joined {
+                    services().users.update_device_lists_for_membership(
+                        room_id,
+                        user_id,
+                        DeviceListChange::Left,
+                    )?;
+                }
             }
             _ => {}
         }
@@ -205,7 +202,7 @@ impl Service {
     pub fn appservice_in_room(
         &self,
         room_id: &RoomId,
-        appservice: &(String, serde_yaml::Value),
+        appservice: &RegistrationInfo,
     ) -> Result<bool> {
         self.db.appservice_in_room(room_id, appservice)
     }
@@ -258,6 +255,19 @@ impl Service {
         self.db.room_invited_count(room_id)
     }
 
+    /// Returns the number of joined and invited members. The counts are stored on every
+    /// membership change, so this doesn't look at the members.
+    #[tracing::instrument(skip(self))]
+    pub fn get_member_count(&self, room_id: &RoomId) -> Result<(u64, u64)> {
+        let joined = self.db.room_joined_count(room_id)?.unwrap_or_else(|| {
+            warn!("Room {} has no member count", room_id);
+            0
+        });
+        let invited = self.db.room_invited_count(room_id)?.unwrap_or(0);
+
+        Ok((joined, invited))
+    }
+
     /// Returns an iterator over all User IDs who ever joined a room.
     #[tracing::instrument(skip(self))]
     pub fn room_useroncejoined<'a>(
@@ -281,6 +291,11 @@ impl Service {
         self.db.get_invite_count(room_id, user_id)
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn get_knock_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
+        self.db.get_knock_count(room_id, user_id)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn get_left_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
         self.db.get_left_count(room_id, user_id)
@@ -313,6 +328,24 @@ impl Service {
         self.db.invite_state(user_id, room_id)
     }
 
+    /// Returns an iterator over all rooms a user knocked on.
+    #[tracing::instrument(skip(self))]
+    pub fn rooms_knocked<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<(OwnedRoomId, Vec<Raw<AnyStrippedStateEvent>>)>> + 'a {
+        self.db.rooms_knocked(user_id)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn knock_state(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>> {
+        self.db.knock_state(user_id, room_id)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn left_state(
         &self,
@@ -346,6 +379,11 @@ impl Service {
         self.db.is_invited(user_id, room_id)
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn is_knocked(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
+        self.db.is_knocked(user_id, room_id)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn is_left(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
         self.db.is_left(user_id, room_id)
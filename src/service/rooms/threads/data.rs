@@ -13,4 +13,5 @@ pub trait Data: Send + Sync {
 
     fn update_participants(&self, root_id: &[u8], participants: &[OwnedUserId]) -> Result<()>;
     fn get_participants(&self, root_id: &[u8]) -> Result<Option<Vec<OwnedUserId>>>;
+    fn remove_thread(&self, root_id: &[u8]) -> Result<()>;
 }
@@ -4,11 +4,9 @@ pub use data::Data;
 use ruma::{
     api::client::{error::ErrorKind, threads::get_threads::v1::IncludeThreads},
     events::relation::BundledThread,
-    uint, CanonicalJsonValue, EventId, RoomId, UserId,
+    EventId, RoomId, UInt, UserId,
 };
 
-use serde_json::json;
-
 use crate::{services, Error, PduEvent, Result};
 
 pub struct Service {
@@ -26,6 +24,35 @@ impl Service {
         self.db.threads_until(user_id, room_id, until, include)
     }
 
+    /// Computes the `m.thread` bundled aggregation of a thread root from the relations index.
+    ///
+    /// Returns None if the event is not the root of a thread.
+    pub fn thread_summary(
+        &self,
+        user_id: &UserId,
+        root: &PduEvent,
+    ) -> Result<Option<BundledThread>> {
+        let events =
+            services()
+                .rooms
+                .pdu_metadata
+                .typed_relations(user_id, root, Some("m.thread"))?;
+
+        let current_user_participated =
+            root.sender == user_id || events.iter().any(|(_, pdu)| pdu.sender == user_id);
+
+        Ok(events.last().map(|(_, latest)| BundledThread {
+            latest_event: latest.to_message_like_event(),
+            count: UInt::new_saturating(events.len() as u64),
+            current_user_participated,
+        }))
+    }
+
+    /// Forgets the thread of a root that is about to leave the timeline.
+    pub fn remove_thread(&self, root_id: &[u8]) -> Result<()> {
+        self.db.remove_thread(root_id)
+    }
+
     pub fn add_to_thread(&self, root_event_id: &EventId, pdu: &PduEvent) -> Result<()> {
         let root_id = &services()
             .rooms
@@ -46,62 +73,7 @@ impl Service {
                 Error::BadRequest(ErrorKind::InvalidParam, "Thread root pdu not found")
             })?;
 
-        let mut root_pdu_json = services()
-            .rooms
-            .timeline
-            .get_pdu_json_from_id(root_id)?
-            .ok_or_else(|| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Thread root pdu not found")
-            })?;
-
-        if let CanonicalJsonValue::Object(unsigned) = root_pdu_json
-            .entry("unsigned".to_owned())
-            .or_insert_with(|| CanonicalJsonValue::Object(Default::default()))
-        {
-            if let Some(mut relations) = unsigned
-                .get("m.relations")
-                .and_then(|r| r.as_object())
-                .and_then(|r| r.get("m.thread"))
-                .and_then(|relations| {
-                    serde_json::from_value::<BundledThread>(relations.clone().into()).ok()
-                })
-            {
-                // Thread already existed
-                relations.count += uint!(1);
-                relations.latest_event = pdu.to_message_like_event();
-
-                let content = serde_json::to_value(relations).expect("to_value always works");
-
-                unsigned.insert(
-                    "m.relations".to_owned(),
-                    json!({ "m.thread": content })
-                        .try_into()
-                        .expect("thread is valid json"),
-                );
-            } else {
-                // New thread
-                let relations = BundledThread {
-                    latest_event: pdu.to_message_like_event(),
-                    count: uint!(1),
-                    current_user_participated: true,
-                };
-
-                let content = serde_json::to_value(relations).expect("to_value always works");
-
-                unsigned.insert(
-                    "m.relations".to_owned(),
-                    json!({ "m.thread": content })
-                        .try_into()
-                        .expect("thread is valid json"),
-                );
-            }
-
-            services()
-                .rooms
-                .timeline
-                .replace_pdu(root_id, &root_pdu_json, &root_pdu)?;
-        }
-
+        // The summary is not stored in the root, see `thread_summary`
         let mut users = Vec::new();
         if let Some(userids) = self.db.get_participants(root_id)? {
             users.extend_from_slice(&userids);
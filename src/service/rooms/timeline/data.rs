@@ -64,6 +64,21 @@ pub trait Data: Send + Sync {
         pdu: &PduEvent,
     ) -> Result<()>;
 
+    /// Removes a pdu from the timeline. The redacted json is kept as an outlier, so events
+    /// referencing it can still be authorized.
+    fn purge_pdu(
+        &self,
+        pdu_id: &[u8],
+        event_id: &EventId,
+        redacted_json: &CanonicalJsonObject,
+    ) -> Result<()>;
+
+    /// Remembers that the global count was at `count` at the time `ts`.
+    fn add_count_checkpoint(&self, ts: u64, count: u64) -> Result<()>;
+
+    /// Returns the count of the latest checkpoint at or before `ts`.
+    fn count_at(&self, ts: u64) -> Result<Option<u64>>;
+
     /// Returns an iterator over all events and their tokens in a room that happened before the
     /// event with id `until` in reverse-chronological order.
     #[allow(clippy::type_complexity)]
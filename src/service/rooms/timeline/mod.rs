@@ -11,19 +11,16 @@ use std::{
 };
 
 pub use data::Data;
-use regex::Regex;
 use ruma::{
     api::{client::error::ErrorKind, federation},
     canonical_json::to_canonical_value,
     events::{
-        push_rules::PushRulesEvent,
         room::{
             create::RoomCreateEventContent, encrypted::Relation, member::MembershipState,
             power_levels::RoomPowerLevelsEventContent,
         },
-        GlobalAccountDataEventType, StateEventType, TimelineEventType,
+        StateEventType, TimelineEventType,
     },
-    push::{Action, Ruleset, Tweak},
     serde::Base64,
     state_res,
     state_res::{Event, RoomVersion},
@@ -43,6 +40,10 @@ use crate::{
 
 use super::state_compressor::CompressedStateEvent;
 
+/// How many events after the read marker are looked at when counting unread notifications.
+/// Clients show bigger counts as "99+" anyway.
+const MAX_UNREAD_EVENTS_COUNTED: usize = 1000;
+
 #[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
 pub enum PduCount {
     Backfilled(u64),
@@ -291,17 +292,10 @@ impl Service {
 
         drop(insert_lock);
 
+        services().rooms.auth_chain.index_auth_chain(pdu)?;
+
         // See if the event matches any known pushers
-        let power_levels: RoomPowerLevelsEventContent = services()
-            .rooms
-            .state_accessor
-            .room_state_get(&pdu.room_id, &StateEventType::RoomPowerLevels, "")?
-            .map(|ev| {
-                serde_json::from_str(ev.content.get())
-                    .map_err(|_| Error::bad_database("invalid m.room.power_levels event"))
-            })
-            .transpose()?
-            .unwrap_or_default();
+        let power_levels = services().pusher.power_levels(&pdu.room_id)?;
 
         let sync_pdu = pdu.to_sync_room_event();
 
@@ -327,55 +321,39 @@ impl Service {
         }
 
         for user in push_target.iter() {
-            // Don't notify the user of their own events
-            if user == &pdu.sender {
+            // Don't notify the user of their own events or events of users they ignore
+            if user == &pdu.sender || services().users.is_ignored(&pdu.sender, user)? {
                 continue;
             }
 
-            let rules_for_user = services()
-                .account_data
-                .get(
-                    None,
-                    user,
-                    GlobalAccountDataEventType::PushRules.to_string().into(),
-                )?
-                .map(|event| {
-                    serde_json::from_str::<PushRulesEvent>(event.get())
-                        .map_err(|_| Error::bad_database("Invalid push rules event in db."))
-                })
-                .transpose()?
-                .map(|ev: PushRulesEvent| ev.content.global)
-                .unwrap_or_else(|| Ruleset::server_default(user));
-
-            let mut highlight = false;
-            let mut notify = false;
+            // A broken ruleset of one user must not keep the others from being notified
+            let ruleset = match services().pusher.ruleset(user) {
+                Ok(ruleset) => ruleset,
+                Err(e) => {
+                    warn!("Skipping notifications for {}: {}", user, e);
+                    continue;
+                }
+            };
 
-            for action in services().pusher.get_actions(
+            let actions = services().pusher.evaluate(
                 user,
-                &rules_for_user,
+                &ruleset,
                 &power_levels,
                 &sync_pdu,
                 &pdu.room_id,
-            )? {
-                match action {
-                    Action::Notify => notify = true,
-                    Action::SetTweak(Tweak::Highlight(true)) => {
-                        highlight = true;
-                    }
-                    _ => {}
-                };
-            }
+            )?;
 
-            if notify {
+            if actions.notify {
                 notifies.push(user.clone());
-            }
 
-            if highlight {
-                highlights.push(user.clone());
+                // One request per push gateway contains all pushers of the user on it
+                for gateway in services().pusher.get_gateways(user)? {
+                    services().sending.send_push_pdu(&pdu_id, user, gateway)?;
+                }
             }
 
-            for push_key in services().pusher.get_pushkeys(user) {
-                services().sending.send_push_pdu(&pdu_id, user, push_key?)?;
+            if actions.highlight {
+                highlights.push(user.clone());
             }
         }
 
@@ -384,8 +362,24 @@ impl Service {
 
         match pdu.kind {
             TimelineEventType::RoomRedaction => {
-                if let Some(redact_id) = &pdu.redacts {
-                    self.redact_pdu(redact_id, pdu)?;
+                let room_version_id = services().rooms.state.get_room_version(&pdu.room_id)?;
+                if let Some(redact_id) = pdu.redacts_id(&room_version_id) {
+                    self.redact_pdu(&redact_id, pdu)?;
+                }
+            }
+            TimelineEventType::RoomEncryption => {
+                if pdu.state_key.as_deref() == Some("") {
+                    // Members now need each other's device lists. This walks all members, so it
+                    // doesn't run under the state lock of the room.
+                    let room_id = pdu.room_id.clone();
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = services()
+                            .users
+                            .update_device_lists_for_encryption(&room_id)
+                        {
+                            error!("Failed to update device lists of {}: {}", room_id, e);
+                        }
+                    });
                 }
             }
             TimelineEventType::SpaceChild => {
@@ -414,7 +408,7 @@ impl Service {
                         .map_err(|_| Error::bad_database("Invalid content in pdu."))?;
 
                     let invite_state = match content.membership {
-                        MembershipState::Invite => {
+                        MembershipState::Invite | MembershipState::Knock => {
                             let state = services().rooms.state.calculate_invite_state(pdu)?;
                             Some(state)
                         }
@@ -427,7 +421,6 @@ impl Service {
                         &pdu.room_id,
                         &target_user_id,
                         content.membership,
-                        &pdu.sender,
                         invite_state,
                         true,
                     )?;
@@ -484,6 +477,7 @@ impl Service {
         #[derive(Clone, Debug, Deserialize)]
         struct ExtractEventId {
             event_id: OwnedEventId,
+            rel_type: Option<String>,
         }
         #[derive(Clone, Debug, Deserialize)]
         struct ExtractRelatesToEventId {
@@ -501,6 +495,15 @@ impl Service {
                     .rooms
                     .pdu_metadata
                     .add_relation(PduCount::Normal(count2), related_pducount)?;
+
+                if let Some(rel_type) = &content.relates_to.rel_type {
+                    services().rooms.pdu_metadata.add_typed_relation(
+                        &pdu.room_id,
+                        PduCount::Normal(count2),
+                        related_pducount,
+                        rel_type,
+                    )?;
+                }
             }
         }
 
@@ -530,7 +533,7 @@ impl Service {
             }
         }
 
-        for appservice in services().appservice.all()? {
+        for appservice in services().appservice.all_info() {
             if services()
                 .rooms
                 .state_cache
@@ -538,7 +541,7 @@ impl Service {
             {
                 services()
                     .sending
-                    .send_pdu_appservice(appservice.0, pdu_id.clone())?;
+                    .send_pdu_appservice(appservice.id.clone(), pdu_id.clone())?;
                 continue;
             }
 
@@ -550,73 +553,36 @@ impl Service {
                     .as_ref()
                     .and_then(|state_key| UserId::parse(state_key.as_str()).ok())
                 {
-                    if let Some(appservice_uid) = appservice
-                        .1
-                        .get("sender_localpart")
-                        .and_then(|string| string.as_str())
-                        .and_then(|string| {
-                            UserId::parse_with_server_name(string, services().globals.server_name())
-                                .ok()
-                        })
-                    {
-                        if state_key_uid == &appservice_uid {
-                            services()
-                                .sending
-                                .send_pdu_appservice(appservice.0, pdu_id.clone())?;
-                            continue;
-                        }
+                    if appservice.is_sender(state_key_uid) {
+                        services()
+                            .sending
+                            .send_pdu_appservice(appservice.id.clone(), pdu_id.clone())?;
+                        continue;
                     }
                 }
             }
 
-            if let Some(namespaces) = appservice.1.get("namespaces") {
-                let users = namespaces
-                    .get("users")
-                    .and_then(|users| users.as_sequence())
-                    .map_or_else(Vec::new, |users| {
-                        users
-                            .iter()
-                            .filter_map(|users| Regex::new(users.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-                let aliases = namespaces
-                    .get("aliases")
-                    .and_then(|aliases| aliases.as_sequence())
-                    .map_or_else(Vec::new, |aliases| {
-                        aliases
-                            .iter()
-                            .filter_map(|aliases| Regex::new(aliases.get("regex")?.as_str()?).ok())
-                            .collect::<Vec<_>>()
-                    });
-                let rooms = namespaces
-                    .get("rooms")
-                    .and_then(|rooms| rooms.as_sequence());
-
-                let matching_users = |users: &Regex| {
-                    users.is_match(pdu.sender.as_str())
-                        || pdu.kind == TimelineEventType::RoomMember
-                            && pdu
-                                .state_key
-                                .as_ref()
-                                .map_or(false, |state_key| users.is_match(state_key))
-                };
-                let matching_aliases = |aliases: &Regex| {
-                    services()
-                        .rooms
-                        .alias
-                        .local_aliases_for_room(&pdu.room_id)
-                        .filter_map(|r| r.ok())
-                        .any(|room_alias| aliases.is_match(room_alias.as_str()))
-                };
+            let matching_users = || {
+                appservice.users.is_match(pdu.sender.as_str())
+                    || pdu.kind == TimelineEventType::RoomMember
+                        && pdu
+                            .state_key
+                            .as_ref()
+                            .map_or(false, |state_key| appservice.users.is_match(state_key))
+            };
+            let matching_aliases = || {
+                services()
+                    .rooms
+                    .alias
+                    .local_aliases_for_room(&pdu.room_id)
+                    .filter_map(|r| r.ok())
+                    .any(|room_alias| appservice.is_alias_match(&room_alias))
+            };
 
-                if aliases.iter().any(matching_aliases)
-                    || rooms.map_or(false, |rooms| rooms.contains(&pdu.room_id.as_str().into()))
-                    || users.iter().any(matching_users)
-                {
-                    services()
-                        .sending
-                        .send_pdu_appservice(appservice.0, pdu_id.clone())?;
-                }
+            if appservice.is_room_match(&pdu.room_id) || matching_users() || matching_aliases() {
+                services()
+                    .sending
+                    .send_pdu_appservice(appservice.id.clone(), pdu_id.clone())?;
             }
         }
 
@@ -733,12 +699,13 @@ impl Service {
             signatures: None,
         };
 
-        let auth_check = state_res::auth_check(
-            &room_version,
-            &pdu,
-            None::<PduEvent>, // TODO: third_party_invite
-            |k, s| auth_events.get(&(k.clone(), s.to_owned())),
-        )
+        let third_party_invite = pdu
+            .third_party_invite_token()
+            .and_then(|token| auth_events.get(&(StateEventType::RoomThirdPartyInvite, token)));
+
+        let auth_check = state_res::auth_check(&room_version, &pdu, third_party_invite, |k, s| {
+            auth_events.get(&(k.clone(), s.to_owned()))
+        })
         .map_err(|e| {
             error!("{:?}", e);
             Error::bad_database("Auth check failed.")
@@ -947,7 +914,9 @@ impl Service {
         // Remove our server from the server list since it will be added to it by room_servers() and/or the if statement above
         servers.remove(services().globals.server_name());
 
-        services().sending.send_pdu(servers.into_iter(), &pdu_id)?;
+        services()
+            .sending
+            .send_pdu(&pdu.room_id, servers.into_iter(), &pdu_id)?;
 
         Ok(pdu.event_id)
     }
@@ -1027,6 +996,90 @@ impl Service {
         self.db.pdus_after(user_id, room_id, from)
     }
 
+    /// Counts the events after `since` that notify or highlight the user according to their push
+    /// rules.
+    pub fn count_unread(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        since: PduCount,
+    ) -> Result<(u64, u64)> {
+        let ruleset = services().pusher.ruleset(user_id)?;
+        let power_levels = services().pusher.power_levels(room_id)?;
+
+        let ignored_users = services().users.ignored_users(user_id)?;
+
+        let mut notifications = 0;
+        let mut highlights = 0;
+
+        for (_, pdu) in self
+            .pdus_after(user_id, room_id, since)?
+            .take(MAX_UNREAD_EVENTS_COUNTED)
+            .filter_map(|r| r.ok())
+        {
+            // Users are not notified of their own events or events of users they ignore
+            if pdu.sender == user_id || ignored_users.contains(&pdu.sender) {
+                continue;
+            }
+
+            let actions = services().pusher.evaluate(
+                user_id,
+                &ruleset,
+                &power_levels,
+                &pdu.to_sync_room_event(),
+                room_id,
+            )?;
+
+            if actions.notify {
+                notifications += 1;
+            }
+            if actions.highlight {
+                highlights += 1;
+            }
+        }
+
+        Ok((notifications, highlights))
+    }
+
+    /// Updates the unread counts of the user after they read up to `read_up_to`. The stored counts
+    /// grow with every new event, this recounts them from the new position.
+    ///
+    /// The private read marker remembers the furthest position of the user, so a receipt for an
+    /// older event leaves the counts alone. New events are counted under the state lock, so none
+    /// is lost or counted twice while recounting.
+    pub fn update_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        read_up_to: PduCount,
+        _state_lock: &MutexGuard<'_, ()>, // Take mutex guard to make sure users get the room state mutex
+    ) -> Result<()> {
+        let private_read = services()
+            .rooms
+            .edus
+            .read_receipt
+            .private_read_get(room_id, user_id)?;
+        let since = match notification_read_position(private_read, read_up_to) {
+            Some(since) => since,
+            None => return Ok(()),
+        };
+
+        if let PduCount::Normal(count) = since {
+            services()
+                .rooms
+                .edus
+                .read_receipt
+                .private_read_set(room_id, user_id, count)?;
+        }
+
+        let (notifications, highlights) = self.count_unread(user_id, room_id, since)?;
+
+        services()
+            .rooms
+            .user
+            .set_notification_counts(user_id, room_id, notifications, highlights)
+    }
+
     /// Replace a PDU with the redacted form.
     #[tracing::instrument(skip(self, reason))]
     pub fn redact_pdu(&self, event_id: &EventId, reason: &PduEvent) -> Result<()> {
@@ -1035,7 +1088,15 @@ impl Service {
             let mut pdu = self
                 .get_pdu_from_id(&pdu_id)?
                 .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
-            pdu.redact(reason)?;
+            let shortroomid = services()
+                .rooms
+                .short
+                .get_shortroomid(&pdu.room_id)?
+                .expect("room exists");
+            Self::deindex_message(shortroomid, &pdu_id, &pdu)?;
+
+            let room_version_id = services().rooms.state.get_room_version(&pdu.room_id)?;
+            pdu.redact(&room_version_id, reason)?;
             self.replace_pdu(
                 &pdu_id,
                 &utils::to_canonical_object(&pdu).expect("PDU is an object"),
@@ -1046,15 +1107,175 @@ impl Service {
         Ok(())
     }
 
+    /// Removes the body of a message from the search index.
+    fn deindex_message(shortroomid: u64, pdu_id: &[u8], pdu: &PduEvent) -> Result<()> {
+        #[derive(Deserialize)]
+        struct ExtractBody {
+            body: Option<String>,
+        }
+
+        if pdu.kind != TimelineEventType::RoomMessage {
+            return Ok(());
+        }
+
+        if let Some(body) = serde_json::from_str::<ExtractBody>(pdu.content.get())
+            .ok()
+            .and_then(|content| content.body)
+        {
+            services()
+                .rooms
+                .search
+                .deindex_pdu(shortroomid, pdu_id, &body)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns how long events in this room are kept in milliseconds, or None if they are kept
+    /// forever.
+    ///
+    /// Uses the `max_lifetime` of the `m.room.retention` state event, falling back to the
+    /// configured default, and caps it to the configured maximum.
+    pub fn retention_max_lifetime(&self, room_id: &RoomId) -> Result<Option<u64>> {
+        #[derive(Deserialize)]
+        struct ExtractMaxLifetime {
+            max_lifetime: Option<u64>,
+        }
+
+        let days_to_ms = |days: u32| u64::from(days) * 24 * 60 * 60 * 1000;
+
+        let room_lifetime = services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::from("m.room.retention"), "")?
+            .and_then(|pdu| {
+                serde_json::from_str::<ExtractMaxLifetime>(pdu.content.get())
+                    .ok()?
+                    .max_lifetime
+            })
+            .filter(|&lifetime| lifetime > 0);
+
+        let default_days = services().globals.room_retention_default_days();
+        let lifetime = room_lifetime.or((default_days > 0).then(|| days_to_ms(default_days)));
+
+        let max_days = services().globals.room_retention_max_days();
+        Ok(if max_days > 0 {
+            Some(lifetime.map_or(days_to_ms(max_days), |l| l.min(days_to_ms(max_days))))
+        } else {
+            lifetime
+        })
+    }
+
+    /// Remembers the current global count, so `count_at` can later tell which events were
+    /// received before now.
+    pub fn add_count_checkpoint(&self) -> Result<()> {
+        self.db.add_count_checkpoint(
+            utils::millis_since_unix_epoch(),
+            services().globals.current_count()?,
+        )
+    }
+
+    /// Returns the global count at the latest checkpoint at or before `ts`, or None if there
+    /// is no checkpoint that old.
+    pub fn count_at(&self, ts: u64) -> Result<Option<u64>> {
+        self.db.count_at(ts)
+    }
+
+    /// Removes all non-state events this server received before the global count was at
+    /// `count` from the timeline of a room.
+    ///
+    /// The count is used instead of `origin_server_ts`, which other servers choose freely.
+    /// Purged events are kept in their redacted form as outliers, so the event graph stays
+    /// intact. Returns the number of purged events.
+    #[tracing::instrument(skip(self))]
+    pub fn purge_pdus_received_before(&self, room_id: &RoomId, count: u64) -> Result<usize> {
+        let shortroomid = services()
+            .rooms
+            .short
+            .get_shortroomid(room_id)?
+            .expect("room exists");
+        let room_version_id = services().rooms.state.get_room_version(room_id)?;
+        let server_user = UserId::parse(format!("@conduit:{}", services().globals.server_name()))
+            .expect("@conduit:server_name is valid");
+
+        // Backfilled events come first in the timeline, but their counts were handed out in
+        // reverse, so only normal events can end the scan
+        let received_before = |pdu_count: &PduCount| match *pdu_count {
+            PduCount::Normal(c) | PduCount::Backfilled(c) => c < count,
+        };
+
+        let mut purged = 0;
+        loop {
+            // Collect a batch first, we can't remove entries while iterating over the tree
+            let batch = self
+                .all_pdus(&server_user, room_id)?
+                .filter_map(|r| r.ok())
+                .take_while(|(pdu_count, _)| {
+                    matches!(pdu_count, PduCount::Backfilled(_)) || received_before(pdu_count)
+                })
+                .filter(|(pdu_count, pdu)| received_before(pdu_count) && pdu.state_key.is_none())
+                .take(1000)
+                .collect::<Vec<_>>();
+
+            if batch.is_empty() {
+                return Ok(purged);
+            }
+
+            for (pdu_count, pdu) in batch {
+                let pdu_id = match self.get_pdu_id(&pdu.event_id)? {
+                    Some(pdu_id) => pdu_id,
+                    None => return Err(Error::bad_database("PDU in timeline has no PDU ID.")),
+                };
+                let pdu_json = self
+                    .get_pdu_json_from_id(&pdu_id)?
+                    .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
+                let redacted_json = ruma::canonical_json::redact(pdu_json, &room_version_id, None)
+                    .map_err(|_| Error::bad_database("Failed to redact PDU in the database."))?;
+
+                Self::deindex_message(shortroomid, &pdu_id, &pdu)?;
+                services()
+                    .rooms
+                    .pdu_metadata
+                    .remove_relations(shortroomid, pdu_count, &pdu)?;
+                services().rooms.threads.remove_thread(&pdu_id)?;
+
+                self.db.purge_pdu(&pdu_id, &pdu.event_id, &redacted_json)?;
+                purged += 1;
+            }
+        }
+    }
+
+    /// Asks other servers in the room for older events if there are less than `limit` events
+    /// before `from` locally, so backward pagination can continue past the start of our history.
+    ///
+    /// Failing to get events from any server is not an error, the caller just has fewer events.
     #[tracing::instrument(skip(self, room_id))]
-    pub async fn backfill_if_required(&self, room_id: &RoomId, from: PduCount) -> Result<()> {
-        let first_pdu = self
+    pub async fn backfill_if_required(
+        &self,
+        room_id: &RoomId,
+        from: PduCount,
+        limit: usize,
+    ) -> Result<()> {
+        let local_events = self
+            .pdus_until(user_id!("@doesntmatter:conduit.rs"), room_id, from)?
+            .take(limit)
+            .count();
+
+        if local_events >= limit {
+            // No backfill required, there are enough events before `from`
+            return Ok(());
+        }
+
+        let first_pdu = match self
             .all_pdus(user_id!("@doesntmatter:conduit.rs"), room_id)?
             .next()
-            .expect("Room is not empty")?;
+        {
+            Some(first_pdu) => first_pdu?,
+            None => return Ok(()),
+        };
 
-        if first_pdu.0 < from {
-            // No backfill required, there are still events between them
+        if first_pdu.1.kind == TimelineEventType::RoomCreate {
+            // We already have the start of the room
             return Ok(());
         }
 
@@ -1068,16 +1289,36 @@ impl Service {
             })
             .transpose()?
             .unwrap_or_default();
-        let mut admin_servers = power_levels
+
+        // Servers of room admins are most likely to have the full history, try them first
+        let mut backfill_servers: Vec<OwnedServerName> = Vec::new();
+        for server in power_levels
             .users
             .iter()
             .filter(|(_, level)| **level > power_levels.users_default)
-            .map(|(user_id, _)| user_id.server_name())
-            .collect::<HashSet<_>>();
-        admin_servers.remove(services().globals.server_name());
+            .map(|(user_id, _)| user_id.server_name().to_owned())
+            .chain(
+                services()
+                    .rooms
+                    .state_cache
+                    .room_servers(room_id)
+                    .filter_map(|r| r.ok()),
+            )
+        {
+            if &*server != services().globals.server_name()
+                && !backfill_servers.contains(&server)
+                && services()
+                    .rooms
+                    .state_cache
+                    .server_in_room(&server, room_id)
+                    .unwrap_or(false)
+            {
+                backfill_servers.push(server);
+            }
+        }
 
         // Request backfill
-        for backfill_server in admin_servers {
+        for backfill_server in &backfill_servers {
             info!("Asking {backfill_server} for backfill");
             let response = services()
                 .sending
@@ -1197,6 +1438,15 @@ impl Service {
     }
 }
 
+/// Returns the position to recount the unread events from after a receipt for `read_up_to`, or
+/// None if the private read marker `private_read` is already further.
+fn notification_read_position(private_read: Option<u64>, read_up_to: PduCount) -> Option<PduCount> {
+    match private_read {
+        Some(private_read) if PduCount::Normal(private_read) >= read_up_to => None,
+        _ => Some(read_up_to),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1208,4 +1458,31 @@ mod tests {
         assert!(PduCount::Normal(1) > PduCount::Backfilled(1));
         assert!(PduCount::Backfilled(1) < PduCount::Normal(1));
     }
+
+    #[test]
+    fn notification_read_position_only_moves_forward() {
+        // The first receipt counts from where it points
+        assert_eq!(
+            notification_read_position(None, PduCount::Normal(5)),
+            Some(PduCount::Normal(5))
+        );
+        // A newer receipt resets the counts up to it
+        assert_eq!(
+            notification_read_position(Some(5), PduCount::Normal(8)),
+            Some(PduCount::Normal(8))
+        );
+        // Older receipts and the same receipt again change nothing
+        assert_eq!(
+            notification_read_position(Some(8), PduCount::Normal(5)),
+            None
+        );
+        assert_eq!(
+            notification_read_position(Some(8), PduCount::Normal(8)),
+            None
+        );
+        assert_eq!(
+            notification_read_position(Some(8), PduCount::Backfilled(1)),
+            None
+        );
+    }
 }
@@ -2,13 +2,20 @@ use crate::Result;
 use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
 
 pub trait Data: Send + Sync {
-    fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
+    /// Stores the unread counts of the user and marks them as changed.
+    fn set_notification_counts(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        notification_count: u64,
+        highlight_count: u64,
+    ) -> Result<()>;
 
     fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
 
     fn highlight_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
 
-    // Returns the count at which the last reset_notification_counts was called
+    // Returns the count at which the notification counts were last set
     fn last_notification_read(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64>;
 
     fn associate_token_shortstatehash(
@@ -1,8 +1,6 @@
-use ruma::ServerName;
-
 use crate::Result;
 
-use super::{OutgoingKind, SendingEventType};
+use super::{Backoff, OutgoingKind, SendingEventType};
 
 pub trait Data: Send + Sync {
     #[allow(clippy::type_complexity)]
@@ -15,6 +13,8 @@ pub trait Data: Send + Sync {
     ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, SendingEventType)>> + 'a>;
     fn delete_active_request(&self, key: Vec<u8>) -> Result<()>;
     fn delete_all_active_requests_for(&self, outgoing_kind: &OutgoingKind) -> Result<()>;
+    /// Checks if PDUs of the room are still waiting to be sent.
+    fn has_queued_pdus(&self, shortroomid: u64) -> Result<bool>;
     fn delete_all_requests_for(&self, outgoing_kind: &OutgoingKind) -> Result<()>;
     fn queue_requests(
         &self,
@@ -25,6 +25,9 @@ pub trait Data: Send + Sync {
         outgoing_kind: &OutgoingKind,
     ) -> Box<dyn Iterator<Item = Result<(SendingEventType, Vec<u8>)>> + 'a>;
     fn mark_as_active(&self, events: &[(SendingEventType, Vec<u8>)]) -> Result<()>;
-    fn set_latest_educount(&self, server_name: &ServerName, educount: u64) -> Result<()>;
-    fn get_latest_educount(&self, server_name: &ServerName) -> Result<u64>;
+
+    /// Returns an iterator over all destinations we failed to send to recently.
+    fn backoffs<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(OutgoingKind, Backoff)>> + 'a>;
+    fn set_backoff(&self, outgoing_kind: &OutgoingKind, backoff: &Backoff) -> Result<()>;
+    fn remove_backoff(&self, outgoing_kind: &OutgoingKind) -> Result<()>;
 }
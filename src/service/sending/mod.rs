@@ -3,51 +3,47 @@ mod data;
 pub use data::Data;
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::HashMap,
     fmt::Debug,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
 };
 
 use crate::{
     api::{appservice_server, server_server},
     services,
-    utils::calculate_hash,
+    utils::{self, calculate_hash},
     Config, Error, PduEvent, Result,
 };
 use federation::transactions::send_transaction_message;
 use futures_util::{stream::FuturesUnordered, StreamExt};
+use rand::Rng;
 
 use base64::{engine::general_purpose, Engine as _};
 
 use ruma::{
-    api::{
-        appservice,
-        federation::{
-            self,
-            transactions::edu::{
-                DeviceListUpdateContent, Edu, ReceiptContent, ReceiptData, ReceiptMap,
-            },
-        },
-        OutgoingRequest,
-    },
-    device_id,
-    events::{
-        push_rules::PushRulesEvent, receipt::ReceiptType, AnySyncEphemeralRoomEvent,
-        GlobalAccountDataEventType,
-    },
-    push, uint, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId, ServerName, UInt, UserId,
+    api::{appservice, federation, OutgoingRequest},
+    MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId, RoomId, ServerName, UInt, UserId,
 };
 use tokio::{
     select,
-    sync::{mpsc, Mutex, Semaphore},
+    sync::{mpsc, Mutex, Notify, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
 };
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
+
+/// How long we wait after the first failure, it doubles with every further failure.
+const FIRST_BACKOFF: Duration = Duration::from_secs(30);
+/// How often we check for destinations whose backoff is over.
+const RETRY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum OutgoingKind {
     Appservice(String),
-    Push(OwnedUserId, String), // user and pushkey
+    Push(OwnedUserId, String), // user and push gateway url
     Normal(OwnedServerName),
 }
 
@@ -60,11 +56,11 @@ impl OutgoingKind {
                 p.extend_from_slice(server.as_bytes());
                 p
             }
-            OutgoingKind::Push(user, pushkey) => {
+            OutgoingKind::Push(user, gateway) => {
                 let mut p = b"$".to_vec();
                 p.extend_from_slice(user.as_bytes());
                 p.push(0xff);
-                p.extend_from_slice(pushkey.as_bytes());
+                p.extend_from_slice(gateway.as_bytes());
                 p
             }
             OutgoingKind::Normal(server) => {
@@ -90,14 +86,109 @@ pub struct Service {
 
     /// The state for a given state hash.
     pub(super) maximum_requests: Arc<Semaphore>,
+    max_concurrent_requests: usize,
+    destination_queues: RwLock<HashMap<OwnedServerName, Arc<DestinationQueue>>>,
+    max_requests_per_destination: usize,
     pub sender: mpsc::UnboundedSender<(OutgoingKind, SendingEventType, Vec<u8>)>,
     receiver: Mutex<mpsc::UnboundedReceiver<(OutgoingKind, SendingEventType, Vec<u8>)>>,
+    /// Tells the handler to stop starting transactions
+    stopping: Notify,
+    handler_task: std::sync::Mutex<Option<JoinHandle<Drained>>>,
+}
+
+/// What the handler did after it was told to stop.
+#[derive(Debug, Default)]
+pub struct Drained {
+    /// Transactions that were in flight and went through
+    pub finished: usize,
+    /// Transactions that were in flight and failed, they are sent again at the next start
+    pub failed: usize,
+    /// Events that were not sent yet and are sent at the next start
+    pub kept: usize,
+}
+
+/// The requests to one server that are being sent or wait for their turn.
+struct DestinationQueue {
+    permits: Arc<Semaphore>,
+    waiting: AtomicUsize,
+}
+
+/// Counts a request as waiting until it is dropped, even if the request is cancelled.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(waiting: &'a AtomicUsize) -> Self {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        Self(waiting)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 enum TransactionStatus {
     Running,
-    Failed(u32, Instant), // number of times failed, time of last failure
-    Retrying(u32),        // number of times failed
+    Failed(Backoff),
+    Retrying(u32), // number of times failed
+}
+
+/// Why and until when we don't send to a destination. It is persisted, so a restart doesn't make
+/// us retry everything at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// Failed requests in a row
+    pub failures: u32,
+    /// Milliseconds since the unix epoch
+    pub last_failure: u64,
+    /// Milliseconds since the unix epoch
+    pub next_attempt: u64,
+}
+
+impl Backoff {
+    /// The backoff after the given number of failures in a row, ending at the latest failure.
+    ///
+    /// The delay doubles with every failure up to `sending_backoff_max_seconds` and is made up to
+    /// a quarter longer at random, so destinations that failed together are not retried together.
+    /// Dead destinations wait for the probe interval instead.
+    fn after_failures(failures: u32) -> Self {
+        let config = &services().globals.config;
+        let now = utils::millis_since_unix_epoch();
+
+        let delay = if failures >= config.sending_dead_after_failures {
+            Duration::from_secs(config.sending_dead_probe_second_interval.into())
+        } else {
+            FIRST_BACKOFF
+                .saturating_mul(2_u32.saturating_pow(failures.saturating_sub(1)))
+                .min(Duration::from_secs(
+                    config.sending_backoff_max_seconds.into(),
+                ))
+        };
+        let delay = delay.as_millis() as u64;
+        let jitter = rand::thread_rng().gen_range(0..=delay / 4);
+
+        Self {
+            failures,
+            last_failure: now,
+            next_attempt: now.saturating_add(delay).saturating_add(jitter),
+        }
+    }
+
+    /// Dead destinations are only tried again on new events or every probe interval.
+    pub fn is_dead(&self) -> bool {
+        self.failures >= services().globals.config.sending_dead_after_failures
+    }
+
+    fn may_retry(&self, now: u64) -> bool {
+        now >= self.next_attempt
+    }
+
+    /// New events wake up dead destinations, but not more often than the first backoff.
+    fn may_revive(&self, now: u64) -> bool {
+        self.is_dead() && now.saturating_sub(self.last_failure) >= FIRST_BACKOFF.as_millis() as u64
+    }
 }
 
 impl Service {
@@ -108,27 +199,52 @@ impl Service {
             sender,
             receiver: Mutex::new(receiver),
             maximum_requests: Arc::new(Semaphore::new(config.max_concurrent_requests as usize)),
+            max_concurrent_requests: config.max_concurrent_requests as usize,
+            destination_queues: RwLock::new(HashMap::new()),
+            max_requests_per_destination: config.max_concurrent_requests_per_destination as usize,
+            stopping: Notify::new(),
+            handler_task: std::sync::Mutex::new(None),
         })
     }
 
     pub fn start_handler(self: &Arc<Self>) {
         let self2 = Arc::clone(self);
-        tokio::spawn(async move {
-            self2.handler().await.unwrap();
-        });
+        let task = tokio::spawn(async move { self2.handler().await.unwrap() });
+        *self.handler_task.lock().unwrap() = Some(task);
     }
 
-    async fn handler(&self) -> Result<()> {
+    /// Stops sending new transactions and waits for the ones in flight. Everything that was not
+    /// sent stays in the database and is sent at the next start.
+    pub async fn drain(&self) -> Option<Drained> {
+        self.stopping.notify_one();
+
+        let task = self.handler_task.lock().unwrap().take()?;
+        task.await.ok()
+    }
+
+    async fn handler(&self) -> Result<Drained> {
         let mut receiver = self.receiver.lock().await;
 
         let mut futures = FuturesUnordered::new();
 
         let mut current_transaction_status = HashMap::<OutgoingKind, TransactionStatus>::new();
 
+        // Keep backing off from destinations that failed before the restart
+        for (outgoing_kind, backoff) in self.db.backoffs().filter_map(|r| r.ok()) {
+            current_transaction_status.insert(outgoing_kind, TransactionStatus::Failed(backoff));
+        }
+
+        let mut retry_check = tokio::time::interval(RETRY_CHECK_INTERVAL);
+
         // Retry requests we could not finish yet
         let mut initial_transactions = HashMap::<OutgoingKind, Vec<SendingEventType>>::new();
 
         for (key, outgoing_kind, event) in self.db.active_requests().filter_map(|r| r.ok()) {
+            if current_transaction_status.contains_key(&outgoing_kind) {
+                // Retried when its backoff is over
+                continue;
+            }
+
             let entry = initial_transactions
                 .entry(outgoing_kind.clone())
                 .or_default();
@@ -155,8 +271,15 @@ impl Service {
                 Some(response) = futures.next() => {
                     match response {
                         Ok(outgoing_kind) => {
+                            services().metrics.record_transaction(&outgoing_kind, true);
                             self.db.delete_all_active_requests_for(&outgoing_kind)?;
 
+                            if let Some(&TransactionStatus::Retrying(failures)) = current_transaction_status.get(&outgoing_kind) {
+                                info!("Sending to {:?} works again after {} failures", outgoing_kind, failures);
+                                self.db.remove_backoff(&outgoing_kind)?;
+                                current_transaction_status.insert(outgoing_kind.clone(), TransactionStatus::Running);
+                            }
+
                             // Find events that have been added since starting the last request
                             let new_events = self.db.queued_requests(&outgoing_kind).filter_map(|r| r.ok()).take(30).collect::<Vec<_>>();
 
@@ -174,18 +297,48 @@ impl Service {
                                 current_transaction_status.remove(&outgoing_kind);
                             }
                         }
-                        Err((outgoing_kind, _)) => {
-                            current_transaction_status.entry(outgoing_kind).and_modify(|e| *e = match e {
-                                TransactionStatus::Running => TransactionStatus::Failed(1, Instant::now()),
-                                TransactionStatus::Retrying(n) => TransactionStatus::Failed(*n+1, Instant::now()),
-                                TransactionStatus::Failed(_, _) => {
+                        Err((outgoing_kind, e)) => {
+                            services().metrics.record_transaction(&outgoing_kind, false);
+                            let failures = match current_transaction_status.get(&outgoing_kind) {
+                                Some(TransactionStatus::Running) => 1,
+                                Some(TransactionStatus::Retrying(n)) => n + 1,
+                                Some(TransactionStatus::Failed(_)) | None => {
                                     error!("Request that was not even running failed?!");
-                                    return
-                                },
-                            });
+                                    continue;
+                                }
+                            };
+
+                            let backoff = Backoff::after_failures(failures);
+                            if backoff.is_dead() {
+                                warn!("Sending to {:?} failed {} times in a row, considering it dead: {}", outgoing_kind, failures, e);
+                            } else {
+                                debug!("Sending to {:?} failed {} times in a row: {}", outgoing_kind, failures, e);
+                            }
+
+                            self.db.set_backoff(&outgoing_kind, &backoff)?;
+                            current_transaction_status.insert(outgoing_kind, TransactionStatus::Failed(backoff));
                         }
                     };
                 },
+                _ = retry_check.tick() => {
+                    let now = utils::millis_since_unix_epoch();
+
+                    for (outgoing_kind, status) in current_transaction_status.iter_mut() {
+                        if let TransactionStatus::Failed(backoff) = status {
+                            if backoff.may_retry(now) {
+                                *status = TransactionStatus::Retrying(backoff.failures);
+
+                                let events = self
+                                    .db
+                                    .active_requests_for(outgoing_kind)
+                                    .filter_map(|r| r.ok())
+                                    .map(|(_, e)| e)
+                                    .collect();
+                                futures.push(Self::handle_events(outgoing_kind.clone(), events));
+                            }
+                        }
+                    }
+                }
                 Some((outgoing_kind, event, key)) = receiver.recv() => {
                     if let Ok(Some(events)) = self.select_events(
                         &outgoing_kind,
@@ -195,8 +348,67 @@ impl Service {
                         futures.push(Self::handle_events(outgoing_kind, events));
                     }
                 }
+                _ = self.stopping.notified() => {
+                    break;
+                }
+            }
+        }
+
+        let mut drained = Drained::default();
+        let mut kept = HashMap::<OutgoingKind, usize>::new();
+
+        // Only active requests are sent at startup. Queued requests of other destinations are
+        // only picked up after one of their transactions went through, so the events that were
+        // not handled yet become active requests, as many as fit into one transaction.
+        while let Ok((outgoing_kind, event, key)) = receiver.try_recv() {
+            if current_transaction_status.contains_key(&outgoing_kind) {
+                continue;
+            }
+
+            let count = kept.entry(outgoing_kind).or_default();
+            if *count < 30 {
+                self.db.mark_as_active(&[(event, key)])?;
+                *count += 1;
+                drained.kept += 1;
             }
         }
+
+        while let Some(response) = futures.next().await {
+            match response {
+                Ok(outgoing_kind) => {
+                    services().metrics.record_transaction(&outgoing_kind, true);
+                    self.db.delete_all_active_requests_for(&outgoing_kind)?;
+
+                    if let Some(TransactionStatus::Retrying(_)) =
+                        current_transaction_status.get(&outgoing_kind)
+                    {
+                        self.db.remove_backoff(&outgoing_kind)?;
+                    }
+
+                    let new_events = self
+                        .db
+                        .queued_requests(&outgoing_kind)
+                        .filter_map(|r| r.ok())
+                        .take(30)
+                        .collect::<Vec<_>>();
+                    self.db.mark_as_active(&new_events)?;
+
+                    drained.finished += 1;
+                    drained.kept += new_events.len();
+                }
+                Err((outgoing_kind, e)) => {
+                    services().metrics.record_transaction(&outgoing_kind, false);
+                    debug!(
+                        "Sending to {:?} failed while shutting down: {}",
+                        outgoing_kind, e
+                    );
+
+                    drained.failed += 1;
+                }
+            }
+        }
+
+        Ok(drained)
     }
 
     #[tracing::instrument(skip(self, outgoing_kind, new_events, current_transaction_status))]
@@ -216,18 +428,14 @@ impl Service {
                 TransactionStatus::Running | TransactionStatus::Retrying(_) => {
                     allow = false; // already running
                 }
-                TransactionStatus::Failed(tries, time) => {
-                    // Fail if a request has failed recently (exponential backoff)
-                    let mut min_elapsed_duration = Duration::from_secs(30) * (*tries) * (*tries);
-                    if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
-                        min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
-                    }
-
-                    if time.elapsed() < min_elapsed_duration {
-                        allow = false;
-                    } else {
+                TransactionStatus::Failed(backoff) => {
+                    // The new event waits in the queue until the backoff is over
+                    let now = utils::millis_since_unix_epoch();
+                    if backoff.may_retry(now) || backoff.may_revive(now) {
                         retry = true;
-                        *e = TransactionStatus::Retrying(*tries);
+                        *e = TransactionStatus::Retrying(backoff.failures);
+                    } else {
+                        allow = false;
                     }
                 }
             })
@@ -253,125 +461,14 @@ impl Service {
             for (e, _) in new_events {
                 events.push(e);
             }
-
-            if let OutgoingKind::Normal(server_name) = outgoing_kind {
-                if let Ok((select_edus, last_count)) = self.select_edus(server_name) {
-                    events.extend(select_edus.into_iter().map(SendingEventType::Edu));
-
-                    self.db.set_latest_educount(server_name, last_count)?;
-                }
-            }
         }
 
         Ok(Some(events))
     }
 
-    #[tracing::instrument(skip(self, server_name))]
-    pub fn select_edus(&self, server_name: &ServerName) -> Result<(Vec<Vec<u8>>, u64)> {
-        // u64: count of last edu
-        let since = self.db.get_latest_educount(server_name)?;
-        let mut events = Vec::new();
-        let mut max_edu_count = since;
-        let mut device_list_changes = HashSet::new();
-
-        'outer: for room_id in services().rooms.state_cache.server_rooms(server_name) {
-            let room_id = room_id?;
-            // Look for device list updates in this room
-            device_list_changes.extend(
-                services()
-                    .users
-                    .keys_changed(room_id.as_ref(), since, None)
-                    .filter_map(|r| r.ok())
-                    .filter(|user_id| user_id.server_name() == services().globals.server_name()),
-            );
-
-            // Look for read receipts in this room
-            for r in services()
-                .rooms
-                .edus
-                .read_receipt
-                .readreceipts_since(&room_id, since)
-            {
-                let (user_id, count, read_receipt) = r?;
-
-                if count > max_edu_count {
-                    max_edu_count = count;
-                }
-
-                if user_id.server_name() != services().globals.server_name() {
-                    continue;
-                }
-
-                let event: AnySyncEphemeralRoomEvent =
-                    serde_json::from_str(read_receipt.json().get())
-                        .map_err(|_| Error::bad_database("Invalid edu event in read_receipts."))?;
-                let federation_event = match event {
-                    AnySyncEphemeralRoomEvent::Receipt(r) => {
-                        let mut read = BTreeMap::new();
-
-                        let (event_id, mut receipt) = r
-                            .content
-                            .0
-                            .into_iter()
-                            .next()
-                            .expect("we only use one event per read receipt");
-                        let receipt = receipt
-                            .remove(&ReceiptType::Read)
-                            .expect("our read receipts always set this")
-                            .remove(&user_id)
-                            .expect("our read receipts always have the user here");
-
-                        read.insert(
-                            user_id,
-                            ReceiptData {
-                                data: receipt.clone(),
-                                event_ids: vec![event_id.clone()],
-                            },
-                        );
-
-                        let receipt_map = ReceiptMap { read };
-
-                        let mut receipts = BTreeMap::new();
-                        receipts.insert(room_id.clone(), receipt_map);
-
-                        Edu::Receipt(ReceiptContent { receipts })
-                    }
-                    _ => {
-                        Error::bad_database("Invalid event type in read_receipts");
-                        continue;
-                    }
-                };
-
-                events.push(serde_json::to_vec(&federation_event).expect("json can be serialized"));
-
-                if events.len() >= 20 {
-                    break 'outer;
-                }
-            }
-        }
-
-        for user_id in device_list_changes {
-            // Empty prev id forces synapse to resync: https://github.com/matrix-org/synapse/blob/98aec1cc9da2bd6b8e34ffb282c85abf9b8b42ca/synapse/handlers/device.py#L767
-            // Because synapse resyncs, we can just insert dummy data
-            let edu = Edu::DeviceListUpdate(DeviceListUpdateContent {
-                user_id,
-                device_id: device_id!("dummy").to_owned(),
-                device_display_name: Some("Dummy".to_owned()),
-                stream_id: uint!(1),
-                prev_id: Vec::new(),
-                deleted: None,
-                keys: None,
-            });
-
-            events.push(serde_json::to_vec(&edu).expect("json can be serialized"));
-        }
-
-        Ok((events, max_edu_count))
-    }
-
-    #[tracing::instrument(skip(self, pdu_id, user, pushkey))]
-    pub fn send_push_pdu(&self, pdu_id: &[u8], user: &UserId, pushkey: String) -> Result<()> {
-        let outgoing_kind = OutgoingKind::Push(user.to_owned(), pushkey);
+    #[tracing::instrument(skip(self, pdu_id, user, gateway))]
+    pub fn send_push_pdu(&self, pdu_id: &[u8], user: &UserId, gateway: String) -> Result<()> {
+        let outgoing_kind = OutgoingKind::Push(user.to_owned(), gateway);
         let event = SendingEventType::Pdu(pdu_id.to_owned());
         let keys = self.db.queue_requests(&[(&outgoing_kind, event.clone())])?;
         self.sender
@@ -381,14 +478,27 @@ impl Service {
         Ok(())
     }
 
+    /// Checks if PDUs of the room still wait to be sent, e.g. before the room is purged.
+    pub fn has_queued_pdus(&self, room_id: &RoomId) -> Result<bool> {
+        match services().rooms.short.get_shortroomid(room_id)? {
+            Some(shortroomid) => self.db.has_queued_pdus(shortroomid),
+            None => Ok(false),
+        }
+    }
+
+    /// Queues the PDU for the servers. Servers the room's ACL denies don't get it.
     #[tracing::instrument(skip(self, servers, pdu_id))]
     pub fn send_pdu<I: Iterator<Item = OwnedServerName>>(
         &self,
+        room_id: &RoomId,
         servers: I,
         pdu_id: &[u8],
     ) -> Result<()> {
+        let acl = services().rooms.state_accessor.server_acl(room_id)?;
+
         let requests = servers
             .into_iter()
+            .filter(|server| acl.as_ref().map_or(true, |acl| acl.is_allowed(server)))
             .map(|server| {
                 (
                     OutgoingKind::Normal(server),
@@ -440,6 +550,124 @@ impl Service {
         Ok(())
     }
 
+    /// Queues an ephemeral event like typing, a receipt or presence for the appservices that asked
+    /// for them and are interested in one of the rooms. The event is in the client format, with
+    /// the `room_id` of room events.
+    #[tracing::instrument(skip(self, room_ids, event))]
+    pub fn send_edu_appservices(
+        &self,
+        room_ids: &[&RoomId],
+        event: &serde_json::Value,
+    ) -> Result<()> {
+        let mut requests = Vec::new();
+        for appservice in services().appservice.all_info() {
+            if !appservice.push_ephemeral {
+                continue;
+            }
+
+            for room_id in room_ids {
+                if appservice.is_room_match(room_id)
+                    || services()
+                        .rooms
+                        .state_cache
+                        .appservice_in_room(room_id, &appservice)?
+                {
+                    requests.push((
+                        OutgoingKind::Appservice(appservice.id.clone()),
+                        SendingEventType::Edu(
+                            serde_json::to_vec(event).expect("JSON value can be serialized"),
+                        ),
+                    ));
+                    break;
+                }
+            }
+        }
+
+        if requests.is_empty() {
+            return Ok(());
+        }
+
+        let keys = self.db.queue_requests(
+            &requests
+                .iter()
+                .map(|(o, e)| (o, e.clone()))
+                .collect::<Vec<_>>(),
+        )?;
+        for ((outgoing_kind, event), key) in requests.into_iter().zip(keys) {
+            self.sender.send((outgoing_kind, event, key)).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Waits until fewer than `max_concurrent_requests_per_destination` requests to the server
+    /// are in flight, so one slow server can't take up all of the global permits.
+    async fn destination_permit(&self, destination: &ServerName) -> OwnedSemaphorePermit {
+        let queue = self
+            .destination_queues
+            .read()
+            .unwrap()
+            .get(destination)
+            .map(Arc::clone);
+
+        let queue = match queue {
+            Some(queue) => queue,
+            None => Arc::clone(
+                self.destination_queues
+                    .write()
+                    .unwrap()
+                    .entry(destination.to_owned())
+                    .or_insert_with(|| {
+                        Arc::new(DestinationQueue {
+                            permits: Arc::new(Semaphore::new(self.max_requests_per_destination)),
+                            waiting: AtomicUsize::new(0),
+                        })
+                    }),
+            ),
+        };
+
+        let waiting = Waiting::new(&queue.waiting);
+        let permit = Arc::clone(&queue.permits)
+            .acquire_owned()
+            .await
+            .expect("we never close the semaphore");
+        drop(waiting);
+
+        permit
+    }
+
+    /// Returns how many requests to any destination are in flight.
+    pub fn requests_in_flight(&self) -> usize {
+        self.max_concurrent_requests - self.maximum_requests.available_permits()
+    }
+
+    /// Returns the servers we are sending requests to with the number of requests in flight and
+    /// waiting in the queue, longest queue first.
+    pub fn destination_queues(&self) -> Vec<(OwnedServerName, usize, usize)> {
+        let mut queues = self
+            .destination_queues
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(server, queue)| {
+                (
+                    server.clone(),
+                    self.max_requests_per_destination - queue.permits.available_permits(),
+                    queue.waiting.load(Ordering::Relaxed),
+                )
+            })
+            .filter(|(_, in_flight, waiting)| *in_flight > 0 || *waiting > 0)
+            .collect::<Vec<_>>();
+        queues.sort_unstable_by(|a, b| (b.2, b.1).cmp(&(a.2, a.1)));
+
+        queues
+    }
+
+    /// Returns all destinations we are backing off from because sending to them failed.
+    pub fn backoffs(&self) -> impl Iterator<Item = Result<(OutgoingKind, Backoff)>> + '_ {
+        self.db.backoffs()
+    }
+
     /// Cleanup event data
     /// Used for instance after we remove an appservice registration
     ///
@@ -459,6 +687,7 @@ impl Service {
         match &kind {
             OutgoingKind::Appservice(id) => {
                 let mut pdu_jsons = Vec::new();
+                let mut ephemeral = Vec::new();
 
                 for event in &events {
                     match event {
@@ -476,15 +705,23 @@ impl Service {
                                 })?
                                 .to_room_event())
                         }
-                        SendingEventType::Edu(_) => {
-                            // Appservices don't need EDUs (?)
+                        SendingEventType::Edu(edu) => {
+                            match serde_json::from_slice::<serde_json::Value>(edu) {
+                                Ok(edu) => ephemeral.push(edu),
+                                Err(_) => warn!("[Appservice] Invalid ephemeral event in db."),
+                            }
                         }
                     }
                 }
 
+                let mut fields = serde_json::Map::new();
+                if !ephemeral.is_empty() {
+                    fields.insert("de.sorunome.msc2409.ephemeral".to_owned(), ephemeral.into());
+                }
+
                 let permit = services().sending.maximum_requests.acquire().await;
 
-                let response = appservice_server::send_request(
+                let response = appservice_server::send_request_with_fields(
                     services()
                         .appservice
                         .get_registration(id)
@@ -509,6 +746,7 @@ impl Service {
                         )))
                             .into(),
                     },
+                    fields,
                 )
                 .await
                 .map(|_response| kind.clone())
@@ -518,7 +756,7 @@ impl Service {
 
                 response
             }
-            OutgoingKind::Push(userid, pushkey) => {
+            OutgoingKind::Push(userid, gateway) => {
                 let mut pdus = Vec::new();
 
                 for event in &events {
@@ -557,26 +795,23 @@ impl Service {
                         }
                     }
 
-                    let pusher = match services()
+                    // Pushers that were removed since the event was queued are skipped
+                    let pushers = services()
                         .pusher
-                        .get_pusher(userid, pushkey)
-                        .map_err(|e| (OutgoingKind::Push(userid.clone(), pushkey.clone()), e))?
-                    {
-                        Some(pusher) => pusher,
-                        None => continue,
-                    };
+                        .get_gateway_pushers(userid, gateway)
+                        .map_err(|e| (kind.clone(), e))?;
+                    if pushers.is_empty() {
+                        continue;
+                    }
 
-                    let rules_for_user = services()
-                        .account_data
-                        .get(
-                            None,
-                            userid,
-                            GlobalAccountDataEventType::PushRules.to_string().into(),
-                        )
-                        .unwrap_or_default()
-                        .and_then(|event| serde_json::from_str::<PushRulesEvent>(event.get()).ok())
-                        .map(|ev: PushRulesEvent| ev.content.global)
-                        .unwrap_or_else(|| push::Ruleset::server_default(userid));
+                    // Retrying can't fix a broken ruleset
+                    let rules_for_user = match services().pusher.ruleset(userid) {
+                        Ok(ruleset) => ruleset,
+                        Err(e) => {
+                            warn!("Skipping push notifications for {}: {}", userid, e);
+                            break;
+                        }
+                    };
 
                     let unread: UInt = services()
                         .rooms
@@ -588,16 +823,18 @@ impl Service {
 
                     let permit = services().sending.maximum_requests.acquire().await;
 
-                    let _response = services()
+                    // Retrying would send the notifications of the other events again
+                    if let Err(e) = services()
                         .pusher
-                        .send_push_notice(userid, unread, &pusher, rules_for_user, &pdu)
+                        .send_push_notice(userid, unread, &pushers, rules_for_user, &pdu)
                         .await
-                        .map(|_response| kind.clone())
-                        .map_err(|e| (kind.clone(), e));
+                    {
+                        warn!("Failed to send push notification to {}: {}", gateway, e);
+                    }
 
                     drop(permit);
                 }
-                Ok(OutgoingKind::Push(userid.clone(), pushkey.clone()))
+                Ok(OutgoingKind::Push(userid.clone(), gateway.clone()))
             }
             OutgoingKind::Normal(server) => {
                 let mut edu_jsons = Vec::new();
@@ -632,6 +869,7 @@ impl Service {
                     }
                 }
 
+                let destination_permit = services().sending.destination_permit(server).await;
                 let permit = services().sending.maximum_requests.acquire().await;
 
                 let response = server_server::send_request(
@@ -666,6 +904,7 @@ impl Service {
                 .map_err(|e| (kind, e));
 
                 drop(permit);
+                drop(destination_permit);
 
                 response
             }
@@ -682,18 +921,13 @@ impl Service {
         T: Debug,
     {
         debug!("Waiting for permit");
+        let destination_permit = self.destination_permit(destination).await;
         let permit = self.maximum_requests.acquire().await;
         debug!("Got permit");
-        let response = tokio::time::timeout(
-            Duration::from_secs(2 * 60),
-            server_server::send_request(destination, request),
-        )
-        .await
-        .map_err(|_| {
-            warn!("Timeout waiting for server response of {destination}");
-            Error::BadServerResponse("Timeout waiting for server response")
-        })?;
+        // The timeout depends on the kind of request, see server_server::send_request
+        let response = server_server::send_request(destination, request).await;
         drop(permit);
+        drop(destination_permit);
 
         response
     }
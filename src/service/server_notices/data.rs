@@ -0,0 +1,10 @@
+use crate::Result;
+use ruma::{OwnedRoomId, RoomId, UserId};
+
+pub trait Data: Send + Sync {
+    /// Remembers the room server notices are sent to for this user.
+    fn set_notice_room(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
+
+    /// Returns the room server notices are sent to for this user, if one was created.
+    fn notice_room(&self, user_id: &UserId) -> Result<Option<OwnedRoomId>>;
+}
@@ -0,0 +1,241 @@
+mod data;
+
+use std::{collections::BTreeMap, sync::Arc};
+
+pub use data::Data;
+use ruma::{
+    events::{
+        room::{
+            create::RoomCreateEventContent,
+            join_rules::{JoinRule, RoomJoinRulesEventContent},
+            member::{MembershipState, RoomMemberEventContent},
+            message::RoomMessageEventContent,
+            name::RoomNameEventContent,
+            power_levels::RoomPowerLevelsEventContent,
+        },
+        tag::{TagEvent, TagEventContent, TagInfo},
+        RoomAccountDataEventType, TimelineEventType,
+    },
+    EventId, OwnedRoomId, RoomId, UserId,
+};
+use serde_json::value::to_raw_value;
+use tokio::sync::MutexGuard;
+
+use crate::{service::pdu::PduBuilder, services, utils, Error, Result};
+
+pub struct Service {
+    pub db: &'static dyn Data,
+}
+
+impl Service {
+    /// Sends a message to a local user in their server notice room.
+    ///
+    /// The room is created on the first notice and reused afterwards. The user is invited and
+    /// joined again if they left it.
+    #[tracing::instrument(skip(self, content))]
+    pub async fn send_notice(
+        &self,
+        user_id: &UserId,
+        content: &RoomMessageEventContent,
+    ) -> Result<Arc<EventId>> {
+        let conduit_user =
+            UserId::parse_with_server_name("conduit", services().globals.server_name())
+                .expect("@conduit:server_name is valid");
+
+        let room_id = match self.db.notice_room(user_id)? {
+            Some(room_id) if services().rooms.metadata.exists(&room_id)? => room_id,
+            _ => {
+                let room_id = self.create_notice_room(&conduit_user).await?;
+                self.db.set_notice_room(user_id, &room_id)?;
+                self.tag_notice_room(user_id, &room_id)?;
+                room_id
+            }
+        };
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        if !services().rooms.state_cache.is_joined(user_id, &room_id)? {
+            if !services().rooms.state_cache.is_invited(user_id, &room_id)? {
+                Self::send_membership(
+                    &conduit_user,
+                    user_id,
+                    MembershipState::Invite,
+                    &room_id,
+                    &state_lock,
+                )?;
+            }
+            Self::send_membership(
+                user_id,
+                user_id,
+                MembershipState::Join,
+                &room_id,
+                &state_lock,
+            )?;
+        }
+
+        let event_id = services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomMessage,
+                content: to_raw_value(content).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: None,
+                redacts: None,
+            },
+            &conduit_user,
+            &room_id,
+            &state_lock,
+        )?;
+
+        drop(state_lock);
+
+        Ok(event_id)
+    }
+
+    /// Creates a room only the server user can talk in.
+    async fn create_notice_room(&self, conduit_user: &UserId) -> Result<OwnedRoomId> {
+        let room_id = RoomId::new(services().globals.server_name());
+
+        services().rooms.short.get_or_create_shortroomid(&room_id)?;
+
+        let mutex_state = Arc::clone(
+            services()
+                .globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let room_version = services().globals.default_room_version();
+        let mut content = if utils::has_creator_field(&room_version) {
+            RoomCreateEventContent::new_v1(conduit_user.to_owned())
+        } else {
+            RoomCreateEventContent::new_v11()
+        };
+        content.federate = false;
+        content.room_version = room_version;
+
+        let mut users = BTreeMap::new();
+        users.insert(conduit_user.to_owned(), 100.into());
+
+        let state_events = [
+            (
+                TimelineEventType::RoomCreate,
+                to_raw_value(&content),
+                "".to_owned(),
+            ),
+            (
+                TimelineEventType::RoomMember,
+                to_raw_value(&RoomMemberEventContent::new(MembershipState::Join)),
+                conduit_user.to_string(),
+            ),
+            (
+                TimelineEventType::RoomPowerLevels,
+                to_raw_value(&RoomPowerLevelsEventContent {
+                    users,
+                    events_default: 100.into(),
+                    ..Default::default()
+                }),
+                "".to_owned(),
+            ),
+            (
+                TimelineEventType::RoomJoinRules,
+                to_raw_value(&RoomJoinRulesEventContent::new(JoinRule::Invite)),
+                "".to_owned(),
+            ),
+            (
+                TimelineEventType::RoomName,
+                to_raw_value(&RoomNameEventContent::new(format!(
+                    "{} Server Notices",
+                    services().globals.server_name()
+                ))),
+                "".to_owned(),
+            ),
+        ];
+
+        for (event_type, content, state_key) in state_events {
+            services().rooms.timeline.build_and_append_pdu(
+                PduBuilder {
+                    event_type,
+                    content: content.expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some(state_key),
+                    redacts: None,
+                },
+                conduit_user,
+                &room_id,
+                &state_lock,
+            )?;
+        }
+
+        Ok(room_id)
+    }
+
+    /// Tags the room as `m.server_notice` for the user, so clients can show it specially.
+    fn tag_notice_room(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        let mut tags_event = services()
+            .account_data
+            .get(Some(room_id), user_id, RoomAccountDataEventType::Tag)?
+            .map(|e| {
+                serde_json::from_str(e.get())
+                    .map_err(|_| Error::bad_database("Invalid account data event in db."))
+            })
+            .unwrap_or_else(|| {
+                Ok(TagEvent {
+                    content: TagEventContent {
+                        tags: BTreeMap::new(),
+                    },
+                })
+            })?;
+
+        tags_event
+            .content
+            .tags
+            .insert("m.server_notice".to_owned().into(), TagInfo::new());
+
+        services().account_data.update(
+            Some(room_id),
+            user_id,
+            RoomAccountDataEventType::Tag,
+            &serde_json::to_value(tags_event).expect("to json value always works"),
+        )
+    }
+
+    fn send_membership(
+        sender: &UserId,
+        user_id: &UserId,
+        membership: MembershipState,
+        room_id: &RoomId,
+        state_lock: &MutexGuard<'_, ()>,
+    ) -> Result<()> {
+        let mut content = RoomMemberEventContent::new(membership);
+        content.displayname = services().users.displayname(user_id)?;
+        content.avatar_url = services().users.avatar_url(user_id)?;
+
+        services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomMember,
+                content: to_raw_value(&content).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            },
+            sender,
+            room_id,
+            state_lock,
+        )?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,9 @@
+use crate::Result;
+use ruma::{OwnedUserId, UserId};
+
+pub trait Data: Send + Sync {
+    /// Remembers which local user the subject of the identity provider is.
+    fn set_subject_user(&self, idp_id: &str, subject: &str, user_id: &UserId) -> Result<()>;
+
+    fn find_from_subject(&self, idp_id: &str, subject: &str) -> Result<Option<OwnedUserId>>;
+}
@@ -0,0 +1,487 @@
+mod data;
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose, Engine as _};
+pub use data::Data;
+use reqwest::Url;
+use ring::digest;
+use ruma::{
+    api::client::error::ErrorKind,
+    events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
+    push,
+    thirdparty::Medium,
+    OwnedUserId, UserId,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    api::client_server::AUTO_GEN_PASSWORD_LENGTH, config::OidcProvider, services, utils, Error,
+    Result,
+};
+
+/// Where identity providers send users back to after they logged in.
+pub const CALLBACK_PATH: &str = "/_conduit/client/oidc/callback";
+/// The cookie that ties a started login to the browser it was started in.
+pub const SESSION_COOKIE: &str = "conduit_sso_session";
+
+/// How long users have to log in at the identity provider.
+const SESSION_LIFETIME: Duration = Duration::from_secs(10 * 60);
+/// How long clients have to redeem the login token after they were redirected back.
+const LOGIN_TOKEN_LIFETIME: Duration = Duration::from_secs(2 * 60);
+const STATE_LENGTH: usize = 32;
+const BROWSER_SECRET_LENGTH: usize = 32;
+const CODE_VERIFIER_LENGTH: usize = 64;
+const LOGIN_TOKEN_LENGTH: usize = 32;
+const RANDOM_LOCALPART_LENGTH: usize = 10;
+
+/// The endpoints of an identity provider, from its discovery document.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProviderMetadata {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+}
+
+/// A login the client started at an identity provider.
+pub struct SsoSession {
+    pub idp_id: String,
+    /// Where the client wants to get the login token
+    pub redirect_url: String,
+    /// Stored in a cookie, so only the browser that started the login can finish it
+    pub browser_secret: String,
+    /// The PKCE secret the code can only be exchanged with
+    pub code_verifier: String,
+    pub started: Instant,
+}
+
+/// Where the browser goes after the login finished.
+pub enum SsoRedirect {
+    /// Straight back to the client with the login token, the client is whitelisted
+    Client(String),
+    /// The user has to confirm sending the login token to the host first
+    Confirm { url: String, host: String },
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+pub struct Service {
+    pub db: &'static dyn Data,
+    pub provider_metadata: RwLock<HashMap<String, ProviderMetadata>>,
+    /// Started logins by their state parameter
+    pub sessions: Mutex<HashMap<String, SsoSession>>,
+    pub login_tokens: Mutex<HashMap<String, (OwnedUserId, Instant)>>,
+}
+
+impl Service {
+    pub fn providers(&self) -> &'static [OidcProvider] {
+        &services().globals.config.oidc_providers
+    }
+
+    /// Returns the provider with the id, or the first one if no id is given.
+    fn provider(&self, idp_id: Option<&str>) -> Result<&'static OidcProvider> {
+        let providers = self.providers();
+        match idp_id {
+            Some(idp_id) => providers.iter().find(|provider| provider.id == idp_id),
+            None => providers.first(),
+        }
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Unknown identity provider.",
+        ))
+    }
+
+    async fn metadata(&self, provider: &OidcProvider) -> Result<ProviderMetadata> {
+        let cached = self
+            .provider_metadata
+            .read()
+            .unwrap()
+            .get(&provider.id)
+            .cloned();
+        if let Some(metadata) = cached {
+            return Ok(metadata);
+        }
+
+        let response = services()
+            .globals
+            .default_client()
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                provider.issuer.trim_end_matches('/')
+            ))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            warn!(
+                "Discovery of identity provider {} returned {}",
+                provider.id,
+                response.status()
+            );
+            return Err(Error::BadServerResponse(
+                "Identity provider discovery failed.",
+            ));
+        }
+
+        let metadata: ProviderMetadata =
+            serde_json::from_slice(&response.bytes().await?).map_err(|_| {
+                Error::BadServerResponse("Identity provider returned invalid metadata.")
+            })?;
+        self.provider_metadata
+            .write()
+            .unwrap()
+            .insert(provider.id.clone(), metadata.clone());
+
+        Ok(metadata)
+    }
+
+    fn callback_url(&self) -> String {
+        format!(
+            "{}{CALLBACK_PATH}",
+            services()
+                .globals
+                .config
+                .sso_base_url
+                .as_deref()
+                .expect("checked at startup when there are providers")
+                .trim_end_matches('/')
+        )
+    }
+
+    /// Starts a login at the identity provider. Returns the URL the user logs in at and the
+    /// cookie the browser has to send back when the identity provider redirects it.
+    pub async fn authorization_url(
+        &self,
+        idp_id: Option<&str>,
+        redirect_url: &str,
+    ) -> Result<(String, String)> {
+        let provider = self.provider(idp_id)?;
+        Url::parse(redirect_url)
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Redirect URL is invalid."))?;
+        let metadata = self.metadata(provider).await?;
+
+        let state = utils::random_string(STATE_LENGTH);
+        let browser_secret = utils::random_string(BROWSER_SECRET_LENGTH);
+        let code_verifier = utils::random_string(CODE_VERIFIER_LENGTH);
+        let code_challenge = general_purpose::URL_SAFE_NO_PAD
+            .encode(digest::digest(&digest::SHA256, code_verifier.as_bytes()));
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|_, session| session.started.elapsed() < SESSION_LIFETIME);
+            sessions.insert(
+                state.clone(),
+                SsoSession {
+                    idp_id: provider.id.clone(),
+                    redirect_url: redirect_url.to_owned(),
+                    browser_secret: browser_secret.clone(),
+                    code_verifier,
+                    started: Instant::now(),
+                },
+            );
+        }
+
+        let url = Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", provider.client_id.as_str()),
+                ("redirect_uri", self.callback_url().as_str()),
+                ("scope", provider.scopes.join(" ").as_str()),
+                ("state", state.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|_| {
+            Error::BadServerResponse("Identity provider has an invalid authorization endpoint.")
+        })?;
+
+        let cookie = format!(
+            "{SESSION_COOKIE}={browser_secret}; Path={CALLBACK_PATH}; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+            SESSION_LIFETIME.as_secs()
+        );
+
+        Ok((url.to_string(), cookie))
+    }
+
+    /// Finishes the login after the identity provider sent the user back: exchanges the code,
+    /// finds or creates the user and returns where the client gets the login token.
+    ///
+    /// The browser secret is the value of the session cookie. Logins started in another browser,
+    /// e.g. from a link someone was sent, are refused.
+    pub async fn finish_login(
+        &self,
+        code: &str,
+        state: &str,
+        browser_secret: Option<&str>,
+    ) -> Result<SsoRedirect> {
+        let session = self
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|session| session.started.elapsed() < SESSION_LIFETIME)
+            .filter(|session| Some(session.browser_secret.as_str()) == browser_secret)
+            .ok_or(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Unknown or expired login session.",
+            ))?;
+        let provider = self.provider(Some(&session.idp_id))?;
+        let metadata = self.metadata(provider).await?;
+
+        let response = services()
+            .globals
+            .default_client()
+            .post(&metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", self.callback_url().as_str()),
+                ("client_id", provider.client_id.as_str()),
+                ("client_secret", provider.client_secret.as_str()),
+                ("code_verifier", session.code_verifier.as_str()),
+            ])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            warn!(
+                "Identity provider {} returned {} for the code exchange",
+                provider.id,
+                response.status()
+            );
+            return Err(Error::BadServerResponse(
+                "Identity provider rejected the login.",
+            ));
+        }
+        let token: TokenResponse =
+            serde_json::from_slice(&response.bytes().await?).map_err(|_| {
+                Error::BadServerResponse("Identity provider returned an invalid token.")
+            })?;
+
+        // The endpoint is reached over TLS, so the claims don't need the signature of an ID token
+        let response = services()
+            .globals
+            .default_client()
+            .get(&metadata.userinfo_endpoint)
+            .bearer_auth(&token.access_token)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            warn!(
+                "Identity provider {} returned {} for the userinfo",
+                provider.id,
+                response.status()
+            );
+            return Err(Error::BadServerResponse(
+                "Identity provider returned no userinfo.",
+            ));
+        }
+        let userinfo: serde_json::Value = serde_json::from_slice(&response.bytes().await?)
+            .map_err(|_| {
+                Error::BadServerResponse("Identity provider returned invalid userinfo.")
+            })?;
+
+        let user_id = self.map_user(provider, &userinfo)?;
+
+        let login_token = utils::random_string(LOGIN_TOKEN_LENGTH);
+        {
+            let mut login_tokens = self.login_tokens.lock().unwrap();
+            login_tokens.retain(|_, (_, created)| created.elapsed() < LOGIN_TOKEN_LIFETIME);
+            login_tokens.insert(login_token.clone(), (user_id, Instant::now()));
+        }
+
+        let mut redirect_url =
+            Url::parse(&session.redirect_url).expect("checked when the login started");
+        redirect_url
+            .query_pairs_mut()
+            .append_pair("loginToken", &login_token);
+
+        if services()
+            .globals
+            .config
+            .sso_client_whitelist
+            .iter()
+            .any(|prefix| session.redirect_url.starts_with(prefix.as_str()))
+        {
+            return Ok(SsoRedirect::Client(redirect_url.to_string()));
+        }
+
+        Ok(SsoRedirect::Confirm {
+            host: redirect_url.host_str().unwrap_or_default().to_owned(),
+            url: redirect_url.to_string(),
+        })
+    }
+
+    /// Returns the user of the login token. Every token can only be used once.
+    pub fn redeem_login_token(&self, token: &str) -> Option<OwnedUserId> {
+        self.login_tokens
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|(_, created)| created.elapsed() < LOGIN_TOKEN_LIFETIME)
+            .map(|(user_id, _)| user_id)
+    }
+
+    /// Finds the local user of the subject, by an earlier login or, if the provider is trusted
+    /// with it, the verified email address, or creates one.
+    fn map_user(
+        &self,
+        provider: &OidcProvider,
+        userinfo: &serde_json::Value,
+    ) -> Result<OwnedUserId> {
+        let subject = userinfo
+            .get("sub")
+            .and_then(|subject| subject.as_str())
+            .ok_or(Error::BadServerResponse(
+                "Identity provider returned no subject.",
+            ))?;
+
+        if let Some(user_id) = self.db.find_from_subject(&provider.id, subject)? {
+            return Ok(user_id);
+        }
+
+        let verified_email = userinfo
+            .get("email")
+            .and_then(|email| email.as_str())
+            .filter(|_| userinfo.get("email_verified") == Some(&serde_json::Value::Bool(true)));
+
+        if let Some(email) = verified_email.filter(|_| provider.link_by_email) {
+            if let Some(user_id) = services().users.find_from_threepid(&Medium::Email, email)? {
+                self.db.set_subject_user(&provider.id, subject, &user_id)?;
+                info!(
+                    "Linked {} to {} of identity provider {} by email address",
+                    user_id, subject, provider.id
+                );
+                return Ok(user_id);
+            }
+        }
+
+        if !provider.auto_provision {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "There is no account for this identity.",
+            ));
+        }
+
+        let user_id = self.provision_user(provider, subject, userinfo, verified_email)?;
+        self.db.set_subject_user(&provider.id, subject, &user_id)?;
+
+        Ok(user_id)
+    }
+
+    fn provision_user(
+        &self,
+        provider: &OidcProvider,
+        subject: &str,
+        userinfo: &serde_json::Value,
+        verified_email: Option<&str>,
+    ) -> Result<OwnedUserId> {
+        let claimed = userinfo
+            .get(&provider.localpart_claim)
+            .and_then(|claimed| claimed.as_str())
+            .unwrap_or(subject);
+        let mut localpart = claimed
+            .to_lowercase()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || "._=-/".contains(*c))
+            .collect::<String>();
+        if localpart.is_empty() {
+            localpart = utils::random_string(RANDOM_LOCALPART_LENGTH).to_lowercase();
+        }
+
+        // Someone else might already have the name, names of appservices get a random one
+        let mut suffix = 0;
+        let user_id = loop {
+            let candidate = if suffix == 0 {
+                localpart.clone()
+            } else {
+                format!("{localpart}{suffix}")
+            };
+            let candidate =
+                UserId::parse_with_server_name(candidate, services().globals.server_name())
+                    .map_err(|_| {
+                        Error::BadRequest(
+                            ErrorKind::InvalidUsername,
+                            "Username from the identity provider is invalid.",
+                        )
+                    })?;
+            if services().appservice.is_exclusive_user_id(&candidate) {
+                localpart = utils::random_string(RANDOM_LOCALPART_LENGTH).to_lowercase();
+                suffix = 0;
+                continue;
+            }
+            if !services().users.exists(&candidate)? {
+                break candidate;
+            }
+            suffix += 1;
+        };
+
+        // Nobody knows the password, an empty one would mark the account as deactivated
+        services().users.create(
+            &user_id,
+            Some(&utils::random_string(AUTO_GEN_PASSWORD_LENGTH)),
+        )?;
+
+        let mut displayname = userinfo
+            .get("name")
+            .and_then(|name| name.as_str())
+            .unwrap_or(user_id.localpart())
+            .to_owned();
+
+        // If enabled append lightning bolt to display name (default true)
+        if services().globals.enable_lightning_bolt() {
+            displayname.push_str(" ⚡️");
+        }
+
+        services()
+            .users
+            .set_displayname(&user_id, Some(displayname))?;
+
+        // Initial account data
+        services().account_data.update(
+            None,
+            &user_id,
+            GlobalAccountDataEventType::PushRules.to_string().into(),
+            &serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+                content: ruma::events::push_rules::PushRulesEventContent {
+                    global: push::Ruleset::server_default(&user_id),
+                },
+            })
+            .expect("to json always works"),
+        )?;
+
+        if let Some(email) = verified_email {
+            if !services().users.add_threepid(
+                &user_id,
+                &Medium::Email,
+                email,
+                utils::millis_since_unix_epoch(),
+            )? {
+                warn!(
+                    "Email address of new user {} already belongs to another user",
+                    user_id
+                );
+            }
+        }
+
+        info!(
+            "New user {} registered with identity provider {}",
+            user_id, provider.id
+        );
+        services()
+            .admin
+            .send_message(RoomMessageEventContent::notice_plain(format!(
+                "New user {user_id} registered with {}.",
+                provider.name
+            )));
+
+        Ok(user_id)
+    }
+}
@@ -16,4 +16,8 @@ pub trait Data: Send + Sync {
         device_id: Option<&DeviceId>,
         txn_id: &TransactionId,
     ) -> Result<Option<Vec<u8>>>;
+
+    /// Forgets the transaction ids that were added before the timestamp. Returns how many were
+    /// removed.
+    fn remove_txnids_older_than(&self, ts: u64) -> Result<u64>;
 }
@@ -1,9 +1,16 @@
 mod data;
 
+use std::time::Duration;
+
 pub use data::Data;
 
-use crate::Result;
-use ruma::{DeviceId, TransactionId, UserId};
+use crate::{services, utils, Error, Result};
+use ruma::{api::client::error::ErrorKind, DeviceId, OwnedEventId, TransactionId, UserId};
+use tokio::time::interval;
+use tracing::{debug, error};
+
+/// How often transaction ids older than the retention are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 pub struct Service {
     pub db: &'static dyn Data,
@@ -28,4 +35,55 @@ impl Service {
     ) -> Result<Option<Vec<u8>>> {
         self.db.existing_txnid(user_id, device_id, txn_id)
     }
+
+    /// Returns the id of the event that was sent with this transaction id before, so a retried
+    /// request doesn't send the event again.
+    pub fn existing_event_id(
+        &self,
+        user_id: &UserId,
+        device_id: Option<&DeviceId>,
+        txn_id: &TransactionId,
+    ) -> Result<Option<OwnedEventId>> {
+        let Some(response) = self.db.existing_txnid(user_id, device_id, txn_id)? else {
+            return Ok(None);
+        };
+
+        // The client might have sent a txnid of the /sendToDevice endpoint
+        // This txnid has no response associated with it
+        if response.is_empty() {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Tried to use txn id already used for an incompatible endpoint.",
+            ));
+        }
+
+        utils::string_from_bytes(&response)
+            .map_err(|_| Error::bad_database("Invalid txnid bytes in database."))?
+            .try_into()
+            .map(Some)
+            .map_err(|_| Error::bad_database("Invalid event id in txnid data."))
+    }
+
+    pub fn start_prune_task(&'static self) {
+        tokio::spawn(async move {
+            let mut i = interval(PRUNE_INTERVAL);
+
+            loop {
+                i.tick().await;
+
+                let retention = u64::from(services().globals.config.transaction_id_retention_days)
+                    * 24
+                    * 60
+                    * 60
+                    * 1000;
+                let ts = utils::millis_since_unix_epoch().saturating_sub(retention);
+
+                match self.db.remove_txnids_older_than(ts) {
+                    Ok(0) => {}
+                    Ok(count) => debug!("Removed {} old transaction ids", count),
+                    Err(e) => error!("Failed to remove old transaction ids: {}", e),
+                }
+            }
+        });
+    }
 }
@@ -1,3 +1,4 @@
+use super::RegistrationTokenInfo;
 use crate::Result;
 use ruma::{api::client::uiaa::UiaaInfo, CanonicalJsonValue, DeviceId, UserId};
 
@@ -31,4 +32,14 @@ pub trait Data: Send + Sync {
         device_id: &DeviceId,
         session: &str,
     ) -> Result<UiaaInfo>;
+
+    fn set_registration_token(&self, token: &str, info: &RegistrationTokenInfo) -> Result<()>;
+
+    fn registration_token(&self, token: &str) -> Result<Option<RegistrationTokenInfo>>;
+
+    fn remove_registration_token(&self, token: &str) -> Result<()>;
+
+    fn registration_tokens<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<(String, RegistrationTokenInfo)>> + 'a>;
 }
@@ -1,20 +1,62 @@
 mod data;
 
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
 pub use data::Data;
 
 use ruma::{
     api::client::{
         error::ErrorKind,
-        uiaa::{AuthData, AuthType, Password, UiaaInfo, UserIdentifier},
+        uiaa::{AuthData, AuthType, Password, ReCaptcha, UiaaInfo, UserIdentifier},
     },
     CanonicalJsonValue, DeviceId, UserId,
 };
-use tracing::error;
+use serde::{Deserialize, Serialize};
+use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
+use tracing::{error, warn};
+
+use crate::{
+    api::client_server::SESSION_ID_LENGTH, config::RegistrationStage, services, utils, Error,
+    Result,
+};
+
+const RECAPTCHA_VERIFY_URL: &str = "https://www.google.com/recaptcha/api/siteverify";
 
-use crate::{api::client_server::SESSION_ID_LENGTH, services, utils, Error, Result};
+/// How long a session that completed the token stage has to finish the registration.
+const REGISTRATION_TOKEN_SESSION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// How many sessions that completed the token stage are remembered at most, so clients can't
+/// fill the memory.
+const MAX_REGISTRATION_TOKEN_SESSIONS: usize = 10_000;
+
+/// A token admins hand out so people can register while registration is closed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RegistrationTokenInfo {
+    /// How many more registrations may use the token, unlimited if None
+    pub uses_remaining: Option<u64>,
+    /// When the token expires in millis since the unix epoch, never if None
+    pub expires_at: Option<u64>,
+}
+
+impl RegistrationTokenInfo {
+    pub fn is_valid(&self) -> bool {
+        self.uses_remaining != Some(0)
+            && self.expires_at.map_or(true, |expires_at| {
+                expires_at > utils::millis_since_unix_epoch()
+            })
+    }
+}
 
 pub struct Service {
     pub db: &'static dyn Data,
+    /// Held while a registration token is checked and used, so it can't be used too often
+    pub registration_token_lock: Mutex<()>,
+    /// The registration tokens of sessions that completed the token stage and when they did, to
+    /// be spent once the account is created
+    pub session_registration_tokens: Mutex<HashMap<String, (String, Instant)>>,
 }
 
 impl Service {
@@ -40,7 +82,7 @@ impl Service {
         )
     }
 
-    pub fn try_auth(
+    pub async fn try_auth(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
@@ -97,7 +139,12 @@ impl Service {
                 uiaainfo.completed.push(AuthType::Password);
             }
             AuthData::RegistrationToken(t) => {
-                if Some(t.token.trim()) == services().globals.config.registration_token.as_deref() {
+                let token = t.token.trim();
+                if self.is_valid_registration_token(token)? {
+                    self.add_session_registration_token(
+                        uiaainfo.session.clone().expect("session is always set"),
+                        token.to_owned(),
+                    )?;
                     uiaainfo.completed.push(AuthType::RegistrationToken);
                 } else {
                     uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
@@ -107,6 +154,17 @@ impl Service {
                     return Ok((false, uiaainfo));
                 }
             }
+            AuthData::ReCaptcha(ReCaptcha { response, .. }) => {
+                if self.verify_recaptcha(response).await? {
+                    uiaainfo.completed.push(AuthType::ReCaptcha);
+                } else {
+                    uiaainfo.auth_error = Some(ruma::api::client::error::StandardErrorBody {
+                        kind: ErrorKind::Forbidden,
+                        message: "Invalid reCAPTCHA response.".to_owned(),
+                    });
+                    return Ok((false, uiaainfo));
+                }
+            }
             AuthData::Dummy(_) => {
                 uiaainfo.completed.push(AuthType::Dummy);
             }
@@ -145,6 +203,191 @@ impl Service {
         Ok((true, uiaainfo))
     }
 
+    /// The stages of the registration flow, as configured or from what is set up. The token
+    /// stage is always part of it when a token is required, also if the configured stages don't
+    /// include it.
+    pub fn registration_stages(&self, registration_token_required: bool) -> Vec<AuthType> {
+        let config = &services().globals.config;
+
+        let mut stages = if config.registration_stages.is_empty() {
+            let mut stages = Vec::new();
+            if config.recaptcha_public_key.is_some() {
+                stages.push(RegistrationStage::Recaptcha);
+            }
+            stages
+        } else {
+            config.registration_stages.clone()
+        };
+
+        if registration_token_required && !stages.contains(&RegistrationStage::Token) {
+            stages.insert(0, RegistrationStage::Token);
+        }
+
+        if stages.is_empty() {
+            return vec![AuthType::Dummy];
+        }
+
+        stages
+            .into_iter()
+            .map(|stage| match stage {
+                RegistrationStage::Recaptcha => AuthType::ReCaptcha,
+                RegistrationStage::Token => AuthType::RegistrationToken,
+            })
+            .collect()
+    }
+
+    /// The parameters clients need for the stages, e.g. the public reCAPTCHA key.
+    pub fn registration_params(&self, stages: &[AuthType]) -> Box<RawJsonValue> {
+        let mut params = serde_json::Map::new();
+        if stages.contains(&AuthType::ReCaptcha) {
+            params.insert(
+                AuthType::ReCaptcha.to_string(),
+                serde_json::json!({
+                    "public_key": services().globals.config.recaptcha_public_key,
+                }),
+            );
+        }
+
+        to_raw_value(&params).expect("json is valid raw value")
+    }
+
+    /// Asks Google whether the client solved the reCAPTCHA.
+    async fn verify_recaptcha(&self, response: &str) -> Result<bool> {
+        let Some(secret) = &services().globals.config.recaptcha_private_key else {
+            return Ok(false);
+        };
+
+        let verification = services()
+            .globals
+            .default_client()
+            .post(RECAPTCHA_VERIFY_URL)
+            .form(&[("secret", secret.as_str()), ("response", response)])
+            .send()
+            .await?;
+        if !verification.status().is_success() {
+            warn!("reCAPTCHA verification returned {}", verification.status());
+            return Err(Error::BadServerResponse("reCAPTCHA verification failed."));
+        }
+
+        let verification: serde_json::Value = serde_json::from_slice(&verification.bytes().await?)
+            .map_err(|_| {
+                Error::BadServerResponse("reCAPTCHA verification returned invalid JSON.")
+            })?;
+
+        Ok(verification.get("success") == Some(&serde_json::Value::Bool(true)))
+    }
+
+    /// Whether registration requires a token, because one is configured, or because registration
+    /// is closed and an admin created tokens.
+    pub fn registration_token_required(&self) -> Result<bool> {
+        if services().globals.config.registration_token.is_some() {
+            return Ok(true);
+        }
+
+        if services().globals.allow_registration() {
+            return Ok(false);
+        }
+
+        self.registration_token_available()
+    }
+
+    /// Whether any registration token can be used, the configured one or one created by an admin.
+    pub fn registration_token_available(&self) -> Result<bool> {
+        if services().globals.config.registration_token.is_some() {
+            return Ok(true);
+        }
+
+        for token in self.db.registration_tokens() {
+            if token?.1.is_valid() {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Remembers the token the session completed the token stage with. Sessions that didn't
+    /// finish the registration in time are forgotten.
+    fn add_session_registration_token(&self, session: String, token: String) -> Result<()> {
+        let mut sessions = self.session_registration_tokens.lock().unwrap();
+        sessions
+            .retain(|_, (_, completed)| completed.elapsed() < REGISTRATION_TOKEN_SESSION_LIFETIME);
+
+        if sessions.len() >= MAX_REGISTRATION_TOKEN_SESSIONS {
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: None,
+                },
+                "Too many registrations in progress, try again later.",
+            ));
+        }
+
+        sessions.insert(session, (token, Instant::now()));
+        Ok(())
+    }
+
+    /// Returns the registration token the session completed the token stage with, and forgets it.
+    pub fn take_session_registration_token(&self, session: &str) -> Option<String> {
+        self.session_registration_tokens
+            .lock()
+            .unwrap()
+            .remove(session)
+            .filter(|(_, completed)| completed.elapsed() < REGISTRATION_TOKEN_SESSION_LIFETIME)
+            .map(|(token, _)| token)
+    }
+
+    /// Returns whether the token can be used to register, without using it.
+    pub fn is_valid_registration_token(&self, token: &str) -> Result<bool> {
+        if Some(token) == services().globals.config.registration_token.as_deref() {
+            return Ok(true);
+        }
+
+        Ok(self
+            .db
+            .registration_token(token)?
+            .map_or(false, |info| info.is_valid()))
+    }
+
+    /// Counts one registration against the token. The configured token can be used any number of
+    /// times.
+    ///
+    /// Hold `registration_token_lock` from checking the token until it is spent, so it can't be
+    /// used too often.
+    pub fn spend_registration_token(&self, token: &str) -> Result<()> {
+        if Some(token) == services().globals.config.registration_token.as_deref() {
+            return Ok(());
+        }
+
+        let Some(mut info) = self.db.registration_token(token)? else {
+            return Ok(());
+        };
+
+        if let Some(uses_remaining) = &mut info.uses_remaining {
+            *uses_remaining = uses_remaining.saturating_sub(1);
+            self.db.set_registration_token(token, &info)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn create_registration_token(
+        &self,
+        token: &str,
+        info: &RegistrationTokenInfo,
+    ) -> Result<()> {
+        self.db.set_registration_token(token, info)
+    }
+
+    pub fn remove_registration_token(&self, token: &str) -> Result<()> {
+        self.db.remove_registration_token(token)
+    }
+
+    pub fn registration_tokens(
+        &self,
+    ) -> impl Iterator<Item = Result<(String, RegistrationTokenInfo)>> + '_ {
+        self.db.registration_tokens()
+    }
+
     pub fn get_uiaa_request(
         &self,
         user_id: &UserId,
@@ -1,9 +1,13 @@
-use crate::Result;
+use crate::{
+    service::users::{DeviceLastSeen, DeviceListChange},
+    Result,
+};
 use ruma::{
     api::client::{device::Device, filter::FilterDefinition},
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::AnyToDeviceEvent,
     serde::Raw,
+    thirdparty::{Medium, ThirdPartyIdentifier},
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri,
     OwnedUserId, UInt, UserId,
 };
@@ -36,12 +40,34 @@ pub trait Data: Send + Sync {
     /// Hash and set the user's password to the Argon2 hash
     fn set_password(&self, user_id: &UserId, password: Option<&str>) -> Result<()>;
 
+    /// Marks the user as a guest or as a regular user.
+    fn set_guest(&self, user_id: &UserId, is_guest: bool) -> Result<()>;
+
+    /// Whether the user registered as a guest.
+    fn is_guest(&self, user_id: &UserId) -> Result<bool>;
+
+    /// Adds the user to the block list or removes them from it.
+    fn block_user(&self, user_id: &UserId, blocked: bool) -> Result<()>;
+
+    /// Whether the user may not join, invite or send events over federation anymore.
+    fn is_blocked(&self, user_id: &UserId) -> Result<bool>;
+
     /// Returns the displayname of a user on this homeserver.
     fn displayname(&self, user_id: &UserId) -> Result<Option<String>>;
 
     /// Sets a new displayname or removes it if displayname is None. You still need to nofify all rooms of this change.
     fn set_displayname(&self, user_id: &UserId, displayname: Option<String>) -> Result<()>;
 
+    /// Removes all user directory entries of the user.
+    fn remove_from_user_directory(&self, user_id: &UserId) -> Result<()>;
+
+    /// Returns the users with a localpart or displayname word containing the given lowercase word,
+    /// together with the `DirectoryMatch` flags of each index entry.
+    fn search_directory<'a>(
+        &'a self,
+        word: &str,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, u8)>> + 'a>;
+
     /// Get the avatar_url of a user.
     fn avatar_url(&self, user_id: &UserId) -> Result<Option<OwnedMxcUri>>;
 
@@ -75,6 +101,24 @@ pub trait Data: Send + Sync {
     /// Replaces the access token of one device.
     fn set_token(&self, user_id: &UserId, device_id: &DeviceId, token: &str) -> Result<()>;
 
+    /// Marks the access token of a device as soft logged out until a new one is set.
+    fn soft_logout_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()>;
+
+    fn is_soft_logged_out(&self, user_id: &UserId, device_id: &DeviceId) -> Result<bool>;
+
+    fn set_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        last_seen: &DeviceLastSeen,
+    ) -> Result<()>;
+
+    fn device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<DeviceLastSeen>>;
+
     fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -98,6 +142,29 @@ pub trait Data: Send + Sync {
         device_id: &DeviceId,
     ) -> Result<BTreeMap<DeviceKeyAlgorithm, UInt>>;
 
+    /// Replaces the fallback key of the device for the algorithm of the key id.
+    fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        fallback_key_key: &DeviceKeyId,
+        fallback_key_value: &Raw<OneTimeKey>,
+    ) -> Result<()>;
+
+    /// Returns the fallback key and marks it as used. It is kept until it is replaced.
+    fn take_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        key_algorithm: &DeviceKeyAlgorithm,
+    ) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>>;
+
+    fn unused_fallback_key_types(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Vec<DeviceKeyAlgorithm>>;
+
     fn add_device_keys(
         &self,
         user_id: &UserId,
@@ -105,10 +172,12 @@ pub trait Data: Send + Sync {
         device_keys: &Raw<DeviceKeys>,
     ) -> Result<()>;
 
+    /// Stores the given cross-signing keys. The master key can only be left out if the user
+    /// already has one.
     fn add_cross_signing_keys(
         &self,
         user_id: &UserId,
-        master_key: &Raw<CrossSigningKey>,
+        master_key: &Option<Raw<CrossSigningKey>>,
         self_signing_key: &Option<Raw<CrossSigningKey>>,
         user_signing_key: &Option<Raw<CrossSigningKey>>,
         notify: bool,
@@ -131,6 +200,35 @@ pub trait Data: Send + Sync {
 
     fn mark_device_key_update(&self, user_id: &UserId) -> Result<()>;
 
+    /// Tells `observer` about a change of `user_id`'s device list in its next sync.
+    fn add_device_list_change(
+        &self,
+        observer: &UserId,
+        user_id: &UserId,
+        change: DeviceListChange,
+    ) -> Result<()>;
+
+    /// Returns the device list changes `observer` has to be told about after `since`, oldest
+    /// first.
+    fn device_list_changes<'a>(
+        &'a self,
+        observer: &UserId,
+        since: u64,
+    ) -> Box<dyn Iterator<Item = Result<(OwnedUserId, DeviceListChange)>> + 'a>;
+
+    /// Removes the device list changes of `observer` up to and including `until`.
+    fn prune_device_list_changes(&self, observer: &UserId, until: u64) -> Result<()>;
+
+    /// Remembers that the device has seen all device list changes up to `count`.
+    fn set_device_list_since(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        count: u64,
+    ) -> Result<()>;
+
+    fn device_list_since(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<u64>>;
+
     fn get_device_keys(
         &self,
         user_id: &UserId,
@@ -176,10 +274,12 @@ pub trait Data: Send + Sync {
         content: serde_json::Value,
     ) -> Result<()>;
 
+    /// Returns the queued to-device events of the device up to the count `until`.
     fn get_to_device_events(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
+        until: u64,
     ) -> Result<Vec<Raw<AnyToDeviceEvent>>>;
 
     fn remove_to_device_events(
@@ -202,6 +302,11 @@ pub trait Data: Send + Sync {
 
     fn get_devicelist_version(&self, user_id: &UserId) -> Result<Option<u64>>;
 
+    /// The stream id of the last device list update EDU we sent for this user.
+    fn last_device_list_update(&self, user_id: &UserId) -> Result<Option<u64>>;
+
+    fn set_last_device_list_update(&self, user_id: &UserId, stream_id: u64) -> Result<()>;
+
     fn all_devices_metadata<'a>(
         &'a self,
         user_id: &UserId,
@@ -211,4 +316,35 @@ pub trait Data: Send + Sync {
     fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String>;
 
     fn get_filter(&self, user_id: &UserId, filter_id: &str) -> Result<Option<FilterDefinition>>;
+
+    /// Associates the validated email address or phone number with the user.
+    fn add_threepid(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+        validated_at: u64,
+        added_at: u64,
+    ) -> Result<()>;
+
+    fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()>;
+
+    /// Returns the user the email address or phone number belongs to.
+    fn find_from_threepid(&self, medium: &Medium, address: &str) -> Result<Option<OwnedUserId>>;
+
+    fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> Box<dyn Iterator<Item = Result<ThirdPartyIdentifier>> + 'a>;
+
+    /// Stores a token other services can use to find out who the user is.
+    fn create_openid_token(&self, user_id: &UserId, token: &str, expires_at: u64) -> Result<()>;
+
+    /// Returns the user of the token and when it expires.
+    fn find_from_openid_token(&self, token: &str) -> Result<Option<(OwnedUserId, u64)>>;
+
+    fn remove_openid_token(&self, token: &str) -> Result<()>;
+
+    /// Removes the tokens that expired before the timestamp. Returns how many were removed.
+    fn remove_expired_openid_tokens(&self, ts: u64) -> Result<u64>;
 }
@@ -1,29 +1,53 @@
 mod data;
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     mem,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 pub use data::Data;
+use lru_cache::LruCache;
 use ruma::{
-    api::client::{
-        device::Device,
-        error::ErrorKind,
-        filter::FilterDefinition,
-        sync::sync_events::{
-            self,
-            v4::{ExtensionsConfig, SyncRequestList},
+    api::{
+        client::{
+            device::Device,
+            error::ErrorKind,
+            filter::FilterDefinition,
+            sync::sync_events::{
+                self,
+                v4::{ExtensionsConfig, SyncRequestList},
+            },
         },
+        federation::transactions::edu::{DeviceListUpdateContent, Edu, SigningKeyUpdateContent},
     },
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
-    events::AnyToDeviceEvent,
+    events::{
+        ignored_user_list::IgnoredUserListEvent, room::redaction::RoomRedactionEventContent,
+        AnyToDeviceEvent, GlobalAccountDataEventType, StateEventType, TimelineEventType,
+    },
     serde::Raw,
-    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri,
-    OwnedRoomId, OwnedUserId, RoomAliasId, UInt, UserId,
+    thirdparty::{Medium, ThirdPartyIdentifier},
+    ClientSecret, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch,
+    OwnedClientSecret, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri, OwnedRoomId, OwnedServerName,
+    OwnedSessionId, OwnedUserId, RoomAliasId, RoomId, SessionId, UInt, UserId,
 };
 
-use crate::{services, Error, Result};
+use serde_json::value::to_raw_value;
+use tokio::time::interval;
+use tracing::{debug, error, warn};
+
+use crate::{api::client_server::leave_all_rooms, services, utils, Error, Result};
+
+use super::pdu::PduBuilder;
+
+/// How long OpenID tokens can be used.
+pub const OPENID_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+const OPENID_TOKEN_LENGTH: usize = 32;
+/// How long a validation session for an email address or phone number is remembered.
+pub const THREEPID_SESSION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// How many validation sessions are remembered at most, so clients can't fill the memory.
+const MAX_THREEPID_SESSIONS: usize = 10_000;
 
 pub struct SlidingSyncCache {
     lists: BTreeMap<String, SyncRequestList>,
@@ -32,11 +56,88 @@ pub struct SlidingSyncCache {
     extensions: ExtensionsConfig,
 }
 
+/// How a user matched a user directory search, better matches first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DirectoryMatch {
+    LocalpartPrefix,
+    DisplaynamePrefix,
+    LocalpartSubstring,
+    DisplaynameSubstring,
+}
+
+impl DirectoryMatch {
+    const ALL: [Self; 4] = [
+        Self::LocalpartPrefix,
+        Self::DisplaynamePrefix,
+        Self::LocalpartSubstring,
+        Self::DisplaynameSubstring,
+    ];
+
+    /// The bit representing this kind of match in the user directory index.
+    pub fn flag(self) -> u8 {
+        1 << self as u8
+    }
+
+    /// Returns the best match contained in the flags.
+    pub fn best(flags: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| flags & m.flag() != 0)
+    }
+}
+
+/// Where and when a device was last used.
+pub struct DeviceLastSeen {
+    pub ts: MilliSecondsSinceUnixEpoch,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Why a user has to be told about another user's device list in /sync.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceListChange {
+    /// The devices or keys changed, or the users started sharing an encrypted room
+    Changed = 0,
+    /// The users don't share an encrypted room anymore
+    Left = 1,
+}
+
+impl DeviceListChange {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Changed),
+            1 => Some(Self::Left),
+            _ => None,
+        }
+    }
+}
+
+/// Splits a localpart, displayname or search term into lowercase words for the user directory.
+pub fn directory_words(s: &str) -> impl Iterator<Item = String> + '_ {
+    s.split_terminator(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .filter(|word| word.len() <= 50)
+        .map(str::to_lowercase)
+}
+
+/// A validation session for an email address or phone number, started at an identity server.
+pub struct ThreepidSession {
+    pub client_secret: OwnedClientSecret,
+    pub id_server: String,
+    pub id_access_token: Option<String>,
+    pub created: Instant,
+}
+
 pub struct Service {
     pub db: &'static dyn Data,
     #[allow(clippy::type_complexity)]
     pub connections:
         Mutex<BTreeMap<(OwnedUserId, OwnedDeviceId, String), Arc<Mutex<SlidingSyncCache>>>>,
+    pub last_seen_updates: Mutex<LruCache<(OwnedUserId, OwnedDeviceId), Instant>>,
+    pub threepid_sessions: Mutex<HashMap<OwnedSessionId, ThreepidSession>>,
+    /// Held while an email address or phone number is checked and added, so it can't be added
+    /// to two accounts
+    pub threepid_lock: Mutex<()>,
+    /// Held while an OpenID token is looked up and removed, so it can only be used once
+    pub openid_token_lock: Mutex<()>,
 }
 
 impl Service {
@@ -281,6 +382,60 @@ impl Service {
         Ok(())
     }
 
+    /// Creates a guest account. Guests have no password, so they can't log in again.
+    pub fn create_guest(&self, user_id: &UserId) -> Result<()> {
+        self.db.set_password(user_id, None)?;
+        self.db.set_guest(user_id, true)
+    }
+
+    /// Whether the user registered as a guest.
+    pub fn is_guest(&self, user_id: &UserId) -> Result<bool> {
+        self.db.is_guest(user_id)
+    }
+
+    /// Blocked users can't join rooms or invite anyone, local or remote. Their room events from
+    /// other servers are still accepted, so the room stays the same as on the other servers.
+    pub fn block_user(&self, user_id: &UserId, blocked: bool) -> Result<()> {
+        self.db.block_user(user_id, blocked)
+    }
+
+    /// Whether the user is on the block list.
+    pub fn is_blocked(&self, user_id: &UserId) -> Result<bool> {
+        self.db.is_blocked(user_id)
+    }
+
+    /// Returns the users in the `m.ignored_user_list` of the user. Their events and invites are
+    /// not shown to the user, which is checked whenever events are read, so changes of the list
+    /// apply to the next sync. A list that can't be parsed counts as empty.
+    pub fn ignored_users(&self, user_id: &UserId) -> Result<HashSet<OwnedUserId>> {
+        Ok(services()
+            .account_data
+            .get(
+                None, // Ignored users are in global account data
+                user_id,
+                GlobalAccountDataEventType::IgnoredUserList
+                    .to_string()
+                    .into(),
+            )?
+            // Account data isn't validated, a broken list must not break sending events
+            .and_then(
+                |event| match serde_json::from_str::<IgnoredUserListEvent>(event.get()) {
+                    Ok(ignored) => Some(ignored),
+                    Err(e) => {
+                        warn!("Ignoring invalid m.ignored_user_list of {user_id}: {e:?}");
+                        None
+                    }
+                },
+            )
+            .map(|ignored| ignored.content.ignored_users.into_keys().collect())
+            .unwrap_or_default())
+    }
+
+    /// Whether the sender is in the `m.ignored_user_list` of the user.
+    pub fn is_ignored(&self, sender: &UserId, user_id: &UserId) -> Result<bool> {
+        Ok(self.ignored_users(user_id)?.contains(sender))
+    }
+
     /// Returns the number of users registered on this server.
     pub fn count(&self) -> Result<usize> {
         self.db.count()
@@ -323,6 +478,51 @@ impl Service {
         self.db.set_displayname(user_id, displayname)
     }
 
+    /// Searches the user directory for users whose localpart or displayname contains every word
+    /// of the search term, best matches first.
+    ///
+    /// This does not check if the sender is allowed to see the users.
+    pub fn search_directory(
+        &self,
+        search_term: &str,
+    ) -> Result<Vec<(OwnedUserId, DirectoryMatch)>> {
+        // A full or partial user id only matches on the localpart, the server name is not indexed
+        let search_term = match search_term.strip_prefix('@') {
+            Some(user_id) => user_id.split(':').next().unwrap_or_default(),
+            None => search_term,
+        };
+
+        let mut matches: Option<HashMap<OwnedUserId, u8>> = None;
+        for word in directory_words(search_term) {
+            let mut word_matches = HashMap::new();
+            for r in self.db.search_directory(&word) {
+                let (user_id, flags) = r?;
+                *word_matches.entry(user_id).or_default() |= flags;
+            }
+
+            // Every word has to match, the match kinds of all words are combined
+            matches = Some(match matches {
+                None => word_matches,
+                Some(previous) => previous
+                    .into_iter()
+                    .filter_map(|(user_id, flags)| {
+                        let word_flags = word_matches.get(&user_id)?;
+                        Some((user_id, flags | word_flags))
+                    })
+                    .collect(),
+            });
+        }
+
+        let mut results: Vec<_> = matches
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(user_id, flags)| Some((user_id, DirectoryMatch::best(flags)?)))
+            .collect();
+        results.sort_unstable_by(|(a_id, a), (b_id, b)| a.cmp(b).then_with(|| a_id.cmp(b_id)));
+
+        Ok(results)
+    }
+
     /// Get the avatar_url of a user.
     pub fn avatar_url(&self, user_id: &UserId) -> Result<Option<OwnedMxcUri>> {
         self.db.avatar_url(user_id)
@@ -352,12 +552,27 @@ impl Service {
         initial_device_display_name: Option<String>,
     ) -> Result<()> {
         self.db
-            .create_device(user_id, device_id, token, initial_device_display_name)
+            .create_device(user_id, device_id, token, initial_device_display_name)?;
+        self.federate_device_list_update(user_id, device_id, false)
     }
 
     /// Removes a device from a user.
     pub fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
-        self.db.remove_device(user_id, device_id)
+        self.db.remove_device(user_id, device_id)?;
+        self.federate_device_list_update(user_id, device_id, true)
+    }
+
+    /// Removes all devices of a user except the given one, which invalidates their access tokens.
+    /// The access token of the kept device stays valid.
+    pub fn remove_other_devices(&self, user_id: &UserId, keep: &DeviceId) -> Result<()> {
+        // Collect first, removing devices while iterating over them skips some
+        let device_ids = self.all_device_ids(user_id).collect::<Result<Vec<_>>>()?;
+
+        for device_id in device_ids.iter().filter(|&id| id != keep) {
+            self.remove_device(user_id, device_id)?;
+        }
+
+        Ok(())
     }
 
     /// Returns an iterator over all device ids of this user.
@@ -373,6 +588,113 @@ impl Service {
         self.db.set_token(user_id, device_id, token)
     }
 
+    /// Soft logout a device: its access token is rejected with `soft_logout: true`, so the client
+    /// can log in again with the same device id without losing its local state.
+    pub fn soft_logout_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        self.db.soft_logout_device(user_id, device_id)
+    }
+
+    /// Soft logout all devices of a user, returns the number of devices.
+    pub fn soft_logout_user(&self, user_id: &UserId) -> Result<usize> {
+        let mut count = 0;
+        for device_id in self.all_device_ids(user_id) {
+            self.soft_logout_device(user_id, &device_id?)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Records a request of a device, at most once every `device_last_seen_second_interval`.
+    pub fn update_device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        ip: Option<String>,
+        user_agent: Option<String>,
+    ) -> Result<()> {
+        let interval =
+            Duration::from_secs(services().globals.device_last_seen_second_interval().into());
+
+        {
+            let mut last_seen_updates = self.last_seen_updates.lock().unwrap();
+            let key = (user_id.to_owned(), device_id.to_owned());
+
+            if last_seen_updates
+                .get_mut(&key)
+                .map_or(false, |last_update| last_update.elapsed() < interval)
+            {
+                return Ok(());
+            }
+            last_seen_updates.insert(key, Instant::now());
+        }
+
+        self.db.set_device_last_seen(
+            user_id,
+            device_id,
+            &DeviceLastSeen {
+                ts: MilliSecondsSinceUnixEpoch::now(),
+                ip,
+                user_agent,
+            },
+        )
+    }
+
+    /// Returns where and when a device was last used, if it was used since it was created.
+    pub fn device_last_seen(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<DeviceLastSeen>> {
+        self.db.device_last_seen(user_id, device_id)
+    }
+
+    /// Returns when the device was last used, or when it was created if it wasn't used since.
+    pub fn device_last_active(
+        &self,
+        user_id: &UserId,
+        device: &Device,
+    ) -> Result<Option<MilliSecondsSinceUnixEpoch>> {
+        Ok(self
+            .device_last_seen(user_id, &device.device_id)?
+            .map(|last_seen| last_seen.ts)
+            .or(device.last_seen_ts))
+    }
+
+    /// Logs out all devices of local users that were not used since `before`. Returns how many
+    /// devices, and with them access tokens, were removed.
+    pub fn remove_inactive_devices(&self, before: MilliSecondsSinceUnixEpoch) -> Result<usize> {
+        let mut removed = 0;
+
+        for user_id in self.iter().collect::<Vec<_>>() {
+            let user_id = user_id?;
+
+            // Collect first, removing devices while iterating over them skips some
+            let mut inactive = Vec::new();
+            for device in self.all_devices_metadata(&user_id) {
+                let device = device?;
+                if self
+                    .device_last_active(&user_id, &device)?
+                    .map_or(false, |last_active| last_active < before)
+                {
+                    inactive.push(device.device_id);
+                }
+            }
+
+            for device_id in inactive {
+                self.remove_device(&user_id, &device_id)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Check if the access token of a device was soft logged out.
+    pub fn is_soft_logged_out(&self, user_id: &UserId, device_id: &DeviceId) -> Result<bool> {
+        self.db.is_soft_logged_out(user_id, device_id)
+    }
+
     pub fn add_one_time_key(
         &self,
         user_id: &UserId,
@@ -388,13 +710,20 @@ impl Service {
         self.db.last_one_time_keys_update(user_id)
     }
 
+    /// Claims a one-time key of the device. If there are none left, the fallback key is used.
     pub fn take_one_time_key(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
         key_algorithm: &DeviceKeyAlgorithm,
     ) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>> {
-        self.db.take_one_time_key(user_id, device_id, key_algorithm)
+        match self
+            .db
+            .take_one_time_key(user_id, device_id, key_algorithm)?
+        {
+            Some(one_time_key) => Ok(Some(one_time_key)),
+            None => self.db.take_fallback_key(user_id, device_id, key_algorithm),
+        }
     }
 
     pub fn count_one_time_keys(
@@ -405,19 +734,40 @@ impl Service {
         self.db.count_one_time_keys(user_id, device_id)
     }
 
+    pub fn add_fallback_key(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        fallback_key_key: &DeviceKeyId,
+        fallback_key_value: &Raw<OneTimeKey>,
+    ) -> Result<()> {
+        self.db
+            .add_fallback_key(user_id, device_id, fallback_key_key, fallback_key_value)
+    }
+
+    /// Returns the algorithms of the fallback keys that were not claimed yet.
+    pub fn unused_fallback_key_types(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Vec<DeviceKeyAlgorithm>> {
+        self.db.unused_fallback_key_types(user_id, device_id)
+    }
+
     pub fn add_device_keys(
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
         device_keys: &Raw<DeviceKeys>,
     ) -> Result<()> {
-        self.db.add_device_keys(user_id, device_id, device_keys)
+        self.db.add_device_keys(user_id, device_id, device_keys)?;
+        self.federate_device_list_update(user_id, device_id, false)
     }
 
     pub fn add_cross_signing_keys(
         &self,
         user_id: &UserId,
-        master_key: &Raw<CrossSigningKey>,
+        master_key: &Option<Raw<CrossSigningKey>>,
         self_signing_key: &Option<Raw<CrossSigningKey>>,
         user_signing_key: &Option<Raw<CrossSigningKey>>,
         notify: bool,
@@ -438,7 +788,109 @@ impl Service {
         signature: (String, String),
         sender_id: &UserId,
     ) -> Result<()> {
-        self.db.sign_key(target_id, key_id, signature, sender_id)
+        self.db.sign_key(target_id, key_id, signature, sender_id)?;
+
+        // Signatures of cross-signing keys are sent with them, device keys changed
+        let device_id = <&DeviceId>::from(key_id);
+        if self.get_device_metadata(target_id, device_id)?.is_some() {
+            self.federate_device_list_update(target_id, device_id, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the master and self-signing key of one of our users to the servers that share a room
+    /// with them. The user-signing key is private.
+    pub fn federate_cross_signing_keys(&self, user_id: &UserId) -> Result<()> {
+        if user_id.server_name() != services().globals.server_name() {
+            return Ok(());
+        }
+
+        let servers = servers_sharing_rooms(user_id)?;
+        if servers.is_empty() {
+            return Ok(());
+        }
+
+        // Only the signatures of the user itself are public
+        let edu = Edu::SigningKeyUpdate(SigningKeyUpdateContent {
+            user_id: user_id.to_owned(),
+            master_key: self.get_master_key(None, user_id, &|_| false)?,
+            self_signing_key: self.get_self_signing_key(None, user_id, &|_| false)?,
+        });
+        let serialized = serde_json::to_vec(&edu).expect("signing key EDU can be serialized");
+
+        for server in servers {
+            services().sending.send_reliable_edu(
+                &server,
+                serialized.clone(),
+                services().globals.next_count()?,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Tells the servers that share a room with one of our users that a device was added, changed
+    /// or deleted. Every change increments the device list version, which is the stream id,
+    /// `prev_id` is the stream id of the update we sent before.
+    fn federate_device_list_update(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        deleted: bool,
+    ) -> Result<()> {
+        if user_id.server_name() != services().globals.server_name() {
+            return Ok(());
+        }
+
+        let servers = servers_sharing_rooms(user_id)?;
+        if servers.is_empty() {
+            return Ok(());
+        }
+
+        let stream_id = self.db.get_devicelist_version(user_id)?.unwrap_or(0);
+        let prev_id: Option<UInt> = self
+            .db
+            .last_device_list_update(user_id)?
+            .filter(|prev_id| *prev_id < stream_id)
+            .map(|prev_id| {
+                prev_id
+                    .try_into()
+                    .expect("version will not grow that large")
+            });
+
+        let (device_display_name, keys) = if deleted {
+            (None, None)
+        } else {
+            (
+                self.get_device_metadata(user_id, device_id)?
+                    .and_then(|device| device.display_name),
+                self.get_device_keys(user_id, device_id)?,
+            )
+        };
+
+        let edu = Edu::DeviceListUpdate(DeviceListUpdateContent {
+            user_id: user_id.to_owned(),
+            device_id: device_id.to_owned(),
+            device_display_name,
+            stream_id: stream_id
+                .try_into()
+                .expect("version will not grow that large"),
+            prev_id: prev_id.into_iter().collect(),
+            deleted: deleted.then_some(true),
+            keys,
+        });
+        let serialized = serde_json::to_vec(&edu).expect("device list EDU can be serialized");
+
+        for server in servers {
+            services().sending.send_reliable_edu(
+                &server,
+                serialized.clone(),
+                services().globals.next_count()?,
+            )?;
+        }
+
+        self.db.set_last_device_list_update(user_id, stream_id)
     }
 
     pub fn keys_changed<'a>(
@@ -454,6 +906,199 @@ impl Service {
         self.db.mark_device_key_update(user_id)
     }
 
+    /// Returns the users whose device lists changed after `since` and the users that don't share
+    /// an encrypted room with `user_id` anymore.
+    pub fn device_list_changes(
+        &self,
+        user_id: &UserId,
+        since: u64,
+    ) -> Result<(HashSet<OwnedUserId>, HashSet<OwnedUserId>)> {
+        let mut changed = HashSet::new();
+        let mut left = HashSet::new();
+
+        // Later changes win, a user can leave and come back between two syncs
+        for r in self.db.device_list_changes(user_id, since) {
+            let (other_user, change) = r?;
+            match change {
+                DeviceListChange::Changed => {
+                    left.remove(&other_user);
+                    changed.insert(other_user);
+                }
+                DeviceListChange::Left => {
+                    changed.remove(&other_user);
+                    left.insert(other_user);
+                }
+            }
+        }
+
+        Ok((changed, left))
+    }
+
+    /// Remembers that a device has seen all device list changes up to `count` and forgets the
+    /// changes every device of the user has seen.
+    pub fn acknowledge_device_list_changes(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        count: u64,
+    ) -> Result<()> {
+        self.db.set_device_list_since(user_id, device_id, count)?;
+
+        // Devices that never synced don't hold anything back, they start with an initial sync
+        let mut oldest = count;
+        for other_device in self.all_device_ids(user_id) {
+            if let Some(since) = self.db.device_list_since(user_id, &other_device?)? {
+                oldest = oldest.min(since);
+            }
+        }
+
+        self.db.prune_device_list_changes(user_id, oldest)
+    }
+
+    /// Records the device list changes caused by a user joining or leaving an encrypted room: users
+    /// that start sharing an encrypted room need each other's device lists, users that stop
+    /// sharing their last encrypted room can stop tracking them.
+    pub fn update_device_lists_for_membership(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        change: DeviceListChange,
+    ) -> Result<()> {
+        if services()
+            .rooms
+            .state_accessor
+            .room_state_get(room_id, &StateEventType::RoomEncryption, "")?
+            .is_none()
+        {
+            return Ok(());
+        }
+
+        let user_is_local = user_id.server_name() == services().globals.server_name();
+
+        for member in services()
+            .rooms
+            .state_cache
+            .room_members(room_id)
+            .filter_map(|r| r.ok())
+            .filter(|member| member != user_id)
+            .filter(|member| {
+                user_is_local || member.server_name() == services().globals.server_name()
+            })
+        {
+            if self.share_encrypted_room(user_id, &member, room_id)? {
+                continue;
+            }
+
+            self.add_device_list_change(&member, user_id, change)?;
+            self.add_device_list_change(user_id, &member, change)?;
+        }
+
+        Ok(())
+    }
+
+    /// Records device list changes for all members of a room that just enabled encryption. Only
+    /// local users are told, remote users don't sync with us.
+    pub fn update_device_lists_for_encryption(&self, room_id: &RoomId) -> Result<()> {
+        let members = services()
+            .rooms
+            .state_cache
+            .room_members(room_id)
+            .filter_map(|r| r.ok())
+            .collect::<HashSet<_>>();
+
+        for member in members
+            .iter()
+            .filter(|member| member.server_name() == services().globals.server_name())
+        {
+            // Looked up once per user instead of once per pair of members
+            let known = self.encrypted_room_members(member, room_id)?;
+
+            for other_member in &members {
+                if other_member == member || known.contains(other_member) {
+                    continue;
+                }
+
+                self.add_device_list_change(member, other_member, DeviceListChange::Changed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the members of all encrypted rooms the user is in, other than `ignore_room`.
+    fn encrypted_room_members(
+        &self,
+        user_id: &UserId,
+        ignore_room: &RoomId,
+    ) -> Result<HashSet<OwnedUserId>> {
+        let mut members = HashSet::new();
+
+        for room_id in services()
+            .rooms
+            .state_cache
+            .rooms_joined(user_id)
+            .filter_map(|r| r.ok())
+            .filter(|room_id| room_id != ignore_room)
+        {
+            if services()
+                .rooms
+                .state_accessor
+                .room_state_get(&room_id, &StateEventType::RoomEncryption, "")?
+                .is_some()
+            {
+                members.extend(
+                    services()
+                        .rooms
+                        .state_cache
+                        .room_members(&room_id)
+                        .filter_map(|r| r.ok()),
+                );
+            }
+        }
+
+        Ok(members)
+    }
+
+    /// Only local users sync, so changes for remote observers are not stored.
+    fn add_device_list_change(
+        &self,
+        observer: &UserId,
+        user_id: &UserId,
+        change: DeviceListChange,
+    ) -> Result<()> {
+        if observer.server_name() != services().globals.server_name() {
+            return Ok(());
+        }
+
+        self.db.add_device_list_change(observer, user_id, change)
+    }
+
+    /// Checks if two users share an encrypted room other than `ignore_room`.
+    pub fn share_encrypted_room(
+        &self,
+        user_a: &UserId,
+        user_b: &UserId,
+        ignore_room: &RoomId,
+    ) -> Result<bool> {
+        Ok(services()
+            .rooms
+            .user
+            .get_shared_rooms(vec![user_a.to_owned(), user_b.to_owned()])?
+            .filter_map(|r| r.ok())
+            .filter(|room_id| room_id != ignore_room)
+            .filter_map(|other_room_id| {
+                Some(
+                    services()
+                        .rooms
+                        .state_accessor
+                        .room_state_get(&other_room_id, &StateEventType::RoomEncryption, "")
+                        .ok()?
+                        .is_some(),
+                )
+            })
+            .any(|encrypted| encrypted))
+    }
+
     pub fn get_device_keys(
         &self,
         user_id: &UserId,
@@ -526,8 +1171,9 @@ impl Service {
         &self,
         user_id: &UserId,
         device_id: &DeviceId,
+        until: u64,
     ) -> Result<Vec<Raw<AnyToDeviceEvent>>> {
-        self.db.get_to_device_events(user_id, device_id)
+        self.db.get_to_device_events(user_id, device_id, until)
     }
 
     pub fn remove_to_device_events(
@@ -545,7 +1191,8 @@ impl Service {
         device_id: &DeviceId,
         device: &Device,
     ) -> Result<()> {
-        self.db.update_device_metadata(user_id, device_id, device)
+        self.db.update_device_metadata(user_id, device_id, device)?;
+        self.federate_device_list_update(user_id, device_id, false)
     }
 
     /// Get device metadata.
@@ -580,10 +1227,66 @@ impl Service {
         // password without logging in should check if the account is deactivated.
         self.db.set_password(user_id, None)?;
 
-        // TODO: Unhook 3PID
+        // Deactivated users can't be found anymore
+        self.db.remove_from_user_directory(user_id)?;
+        self.db.set_guest(user_id, false)?;
+
+        for threepid in self.threepids(user_id).collect::<Vec<_>>() {
+            let threepid = threepid?;
+            self.db
+                .remove_threepid(user_id, &threepid.medium, &threepid.address)?;
+        }
+
         Ok(())
     }
 
+    /// Deactivates the account and makes the user leave all rooms. If `erase` is set, the
+    /// messages of the user are redacted and their profile is removed as well.
+    ///
+    /// This can be called again for an account that is already deactivated, e.g. to finish after
+    /// a failure. Returns the rooms that could not be left or erased.
+    pub async fn deactivate(
+        &self,
+        user_id: &UserId,
+        erase: bool,
+    ) -> Result<Vec<(OwnedRoomId, Error)>> {
+        // Logging in is not possible anymore after this, even if something below fails
+        self.deactivate_account(user_id)?;
+
+        let mut failed = Vec::new();
+
+        // Redactions are sent as the user, so this has to happen before they leave
+        if erase {
+            for room_id in services()
+                .rooms
+                .state_cache
+                .rooms_joined(user_id)
+                .collect::<Vec<_>>()
+            {
+                let room_id = room_id?;
+                if let Err(e) = erase_messages(user_id, &room_id).await {
+                    warn!(
+                        "Failed to erase messages of {} in {}: {}",
+                        user_id, room_id, e
+                    );
+                    failed.push((room_id, e));
+                }
+            }
+        }
+
+        failed.extend(leave_all_rooms(user_id).await?);
+
+        if erase {
+            self.set_displayname(user_id, None)?;
+            self.set_avatar_url(user_id, None)?;
+            self.set_blurhash(user_id, None)?;
+            // Setting the displayname indexes the localpart again
+            self.db.remove_from_user_directory(user_id)?;
+        }
+
+        Ok(failed)
+    }
+
     /// Creates a new sync filter. Returns the filter id.
     pub fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String> {
         self.db.create_filter(user_id, filter)
@@ -596,6 +1299,214 @@ impl Service {
     ) -> Result<Option<FilterDefinition>> {
         self.db.get_filter(user_id, filter_id)
     }
+
+    /// Remembers a validation session started at an identity server. Sessions are forgotten
+    /// after an hour, and new ones are refused while too many are remembered.
+    pub fn add_threepid_session(
+        &self,
+        sid: OwnedSessionId,
+        session: ThreepidSession,
+    ) -> Result<()> {
+        let mut sessions = self.threepid_sessions.lock().unwrap();
+        sessions.retain(|_, session| session.created.elapsed() < THREEPID_SESSION_LIFETIME);
+
+        if sessions.len() >= MAX_THREEPID_SESSIONS {
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: None,
+                },
+                "Too many validation sessions, try again later.",
+            ));
+        }
+
+        sessions.insert(sid, session);
+        Ok(())
+    }
+
+    /// Returns the identity server and access token of the validation session, if it hasn't
+    /// expired and the client secret matches.
+    pub fn threepid_session(
+        &self,
+        sid: &SessionId,
+        client_secret: &ClientSecret,
+    ) -> Option<(String, Option<String>)> {
+        self.threepid_sessions
+            .lock()
+            .unwrap()
+            .get(sid)
+            .filter(|session| session.created.elapsed() < THREEPID_SESSION_LIFETIME)
+            .filter(|session| &*session.client_secret == client_secret)
+            .map(|session| (session.id_server.clone(), session.id_access_token.clone()))
+    }
+
+    /// Associates the validated email address or phone number with the user. Returns false if
+    /// it belongs to another user.
+    pub fn add_threepid(
+        &self,
+        user_id: &UserId,
+        medium: &Medium,
+        address: &str,
+        validated_at: u64,
+    ) -> Result<bool> {
+        let _guard = self.threepid_lock.lock().unwrap();
+
+        if let Some(owner) = self.find_from_threepid(medium, address)? {
+            if owner != user_id {
+                return Ok(false);
+            }
+        }
+
+        self.db.add_threepid(
+            user_id,
+            medium,
+            &normalize_threepid(medium, address),
+            validated_at,
+            utils::millis_since_unix_epoch(),
+        )?;
+        Ok(true)
+    }
+
+    pub fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+        self.db
+            .remove_threepid(user_id, medium, &normalize_threepid(medium, address))
+    }
+
+    /// Returns the local user the email address or phone number belongs to.
+    pub fn find_from_threepid(
+        &self,
+        medium: &Medium,
+        address: &str,
+    ) -> Result<Option<OwnedUserId>> {
+        self.db
+            .find_from_threepid(medium, &normalize_threepid(medium, address))
+    }
+
+    pub fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<ThirdPartyIdentifier>> + 'a {
+        self.db.threepids(user_id)
+    }
+
+    /// Creates a token that lets other services, like integration managers, find out who the
+    /// user is. Returns the token.
+    pub fn create_openid_token(&self, user_id: &UserId) -> Result<String> {
+        let token = utils::random_string(OPENID_TOKEN_LENGTH);
+        let expires_at =
+            utils::millis_since_unix_epoch() + OPENID_TOKEN_LIFETIME.as_millis() as u64;
+
+        self.db.create_openid_token(user_id, &token, expires_at)?;
+
+        Ok(token)
+    }
+
+    /// Returns the user of the OpenID token. Every token can only be looked up once, so a token
+    /// that leaks after it was used is worthless.
+    pub fn find_from_openid_token(&self, token: &str) -> Result<Option<OwnedUserId>> {
+        let _guard = self.openid_token_lock.lock().unwrap();
+
+        let Some((user_id, expires_at)) = self.db.find_from_openid_token(token)? else {
+            return Ok(None);
+        };
+        self.db.remove_openid_token(token)?;
+
+        Ok((expires_at > utils::millis_since_unix_epoch()).then_some(user_id))
+    }
+
+    /// Removes expired OpenID tokens that were never used, every hour.
+    pub fn start_openid_token_sweep_task(&'static self) {
+        tokio::spawn(async move {
+            let mut i = interval(OPENID_TOKEN_LIFETIME);
+
+            loop {
+                i.tick().await;
+
+                match self
+                    .db
+                    .remove_expired_openid_tokens(utils::millis_since_unix_epoch())
+                {
+                    Ok(0) => {}
+                    Ok(count) => debug!("Removed {} expired OpenID tokens", count),
+                    Err(e) => error!("Failed to remove expired OpenID tokens: {}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Email addresses are case insensitive, so they are stored in lowercase.
+fn normalize_threepid(medium: &Medium, address: &str) -> String {
+    match medium {
+        Medium::Email => address.to_lowercase(),
+        _ => address.to_owned(),
+    }
+}
+
+/// The other servers in the rooms the user joined.
+fn servers_sharing_rooms(user_id: &UserId) -> Result<BTreeSet<OwnedServerName>> {
+    let mut servers = BTreeSet::new();
+    for room_id in services().rooms.state_cache.rooms_joined(user_id) {
+        servers.extend(
+            services()
+                .rooms
+                .state_cache
+                .room_servers(&room_id?)
+                .filter_map(|r| r.ok()),
+        );
+    }
+    servers.remove(services().globals.server_name());
+
+    Ok(servers)
+}
+
+/// Redacts all messages of the user in the room that are not redacted yet.
+async fn erase_messages(user_id: &UserId, room_id: &RoomId) -> Result<()> {
+    let event_ids = services()
+        .rooms
+        .timeline
+        .all_pdus(user_id, room_id)?
+        .filter_map(|r| r.ok())
+        .map(|(_, pdu)| pdu)
+        .filter(|pdu| {
+            pdu.sender == user_id
+                && pdu.state_key.is_none()
+                && pdu.kind != TimelineEventType::RoomRedaction
+                && !pdu.is_redacted()
+        })
+        .map(|pdu| pdu.event_id)
+        .collect::<Vec<_>>();
+
+    let mutex_state = Arc::clone(
+        services()
+            .globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    for event_id in event_ids {
+        services().rooms.timeline.build_and_append_pdu(
+            PduBuilder {
+                event_type: TimelineEventType::RoomRedaction,
+                content: to_raw_value(&RoomRedactionEventContent {
+                    redacts: Some((*event_id).to_owned()),
+                    reason: Some("Account erased".to_owned()),
+                })
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: None,
+                redacts: Some(event_id),
+            },
+            user_id,
+            room_id,
+            &state_lock,
+        )?;
+    }
+
+    Ok(())
 }
 
 /// Ensure that a user only sees signatures from themselves and the target user
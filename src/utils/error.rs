@@ -44,6 +44,12 @@ pub enum Error {
         #[from]
         source: rocksdb::Error,
     },
+    #[cfg(feature = "postgres")]
+    #[error("There was a problem with the connection to the postgres database: {source}")]
+    PostgresError {
+        #[from]
+        source: tokio_postgres::Error,
+    },
     #[error("Could not generate an image.")]
     ImageError {
         #[from]
@@ -72,6 +78,8 @@ pub enum Error {
     Uiaa(UiaaInfo),
     #[error("{0}: {1}")]
     BadRequest(ErrorKind, &'static str),
+    #[error("{0}: {1}")]
+    BadRequestString(ErrorKind, String),
     #[error("{0}")]
     Conflict(&'static str), // This is only needed for when a room alias already exists
     #[cfg(feature = "conduit_bin")]
@@ -113,7 +121,7 @@ impl Error {
 
         use ErrorKind::*;
         let (kind, status_code) = match self {
-            Self::BadRequest(kind, _) => (
+            Self::BadRequest(kind, _) | Self::BadRequestString(kind, _) => (
                 kind.clone(),
                 match kind {
                     WrongRoomKeysVersion { .. }
@@ -156,6 +164,8 @@ impl Error {
             Self::HeedError => db_error,
             #[cfg(feature = "rocksdb")]
             Self::RocksDbError { .. } => db_error,
+            #[cfg(feature = "postgres")]
+            Self::PostgresError { .. } => db_error,
             Self::IoError { .. } => db_error,
             Self::BadConfig { .. } => db_error,
             Self::BadDatabase { .. } => db_error,
@@ -4,7 +4,9 @@ use argon2::{Config, Variant};
 use cmp::Ordering;
 use rand::prelude::*;
 use ring::digest;
-use ruma::{canonical_json::try_from_json_map, CanonicalJsonError, CanonicalJsonObject};
+use ruma::{
+    canonical_json::try_from_json_map, CanonicalJsonError, CanonicalJsonObject, RoomVersionId,
+};
 use std::{
     cmp, fmt,
     str::FromStr,
@@ -144,6 +146,17 @@ pub fn deserialize_from_str<
     deserializer.deserialize_str(Visitor(std::marker::PhantomData))
 }
 
+/// Whether create events of this room version have a `creator` field. Since room version 11 the
+/// sender of the create event is the creator.
+pub fn has_creator_field(room_version: &RoomVersionId) -> bool {
+    use RoomVersionId::*;
+
+    matches!(
+        room_version,
+        V1 | V2 | V3 | V4 | V5 | V6 | V7 | V8 | V9 | V10
+    )
+}
+
 // Copied from librustdoc:
 // https://github.com/rust-lang/rust/blob/cbaeec14f90b59a91a6b0f17fc046c66fa811892/src/librustdoc/html/escape.rs
 